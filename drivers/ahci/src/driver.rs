@@ -14,6 +14,21 @@ pub(crate) mod kernel_if;
 
 pub(crate) type HbaInfoRef = alloc::sync::Arc<HbaInfo>;
 
+/// Time a command may remain outstanding before it is aborted.
+const CMD_TIMEOUT_MSEC: u64 = 10_000;
+/// Timeout for commands which may legitimately take a long time, such as cache flushes.
+const LONG_CMD_TIMEOUT_MSEC: u64 = 60_000;
+/// Interval at which [Port::watchdog] checks for expired commands.
+const WATCHDOG_MSEC: u64 = 500;
+/// The HBA must halt the command list engine within 500ms of it being stopped.
+const ENGINE_STOP_MSEC: u64 = 500;
+/// Time given to the device to respond to the NOP used to abort its queue.
+const NOP_TIMEOUT_MSEC: u64 = 1000;
+/// Time given to re-establish communication with the device after COMRESET.
+const COMRESET_MSEC: u64 = 1000;
+/// Interval at which the SCT status is polled while a background SCT command executes.
+const SCT_POLL_MSEC: u64 = 100;
+
 const NEXT_ID: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
 ///
 fn new_instance_id() -> usize {
@@ -205,6 +220,9 @@ pub struct Port {
     cmd_lock: CmdLock,
     cmd_queue: spin::Mutex<alloc::collections::VecDeque<CmdFuture>>,
     err_chk: PortErrChk,
+    streams: ata::stream::StreamIds,
+    // Set while the port is recovering from a command timeout, see [Self::recover]
+    recovering: core::sync::atomic::AtomicBool,
 }
 
 impl Port {
@@ -227,6 +245,8 @@ impl Port {
             cmd_lock: CmdLock::new(),
             cmd_queue: spin::Mutex::new(alloc::collections::VecDeque::new()),
             err_chk: PortErrChk::new(),
+            streams: ata::stream::StreamIds::new(),
+            recovering: core::sync::atomic::AtomicBool::new(false),
         }
     }
 
@@ -242,22 +262,14 @@ impl Port {
             if let Some(i) = oi {
                 i
             } else {
-                // min alignment for alloc is 8. This should be u16
-                let mut buffer = alloc::boxed::Box::new([0u8; 512]);
-
-                // SAFETY: IdentifyDevice returns a 512 byte buff
-                unsafe {
-                    self.issue_cmd(
-                        ata::command::constructor::NoArgCmd::IdentifyDevice.compose(),
-                        Some(&mut buffer[..]),
-                    )
-                    .await
-                    .unwrap(); // todo handle this
+                let id_raw = self.read_identity().await.unwrap(); // todo handle this
+                log::info!(
+                    "AHCI: {self} interface {:?}",
+                    id_raw.interface_properties()
+                );
+                if let Some(wrv) = id_raw.write_read_verify() {
+                    log::info!("AHCI: {self} write-read-verify {wrv:?}");
                 }
-
-                let id_raw: alloc::boxed::Box<ata::structures::identification::DeviceIdentity> =
-                    unsafe { core::mem::transmute(buffer) };
-
                 let id = DevIdentity::from(*id_raw);
 
                 if id.lba_count == 0 {
@@ -271,6 +283,359 @@ impl Port {
         .boxed()
     }
 
+    /// Issues [ata::command::AtaCommand::IDENTIFY_DEVICE] and returns the raw result.
+    ///
+    /// This does not update the cached identity returned by [Self::get_identity].
+    pub async fn read_identity(
+        &self,
+    ) -> Result<alloc::boxed::Box<ata::structures::identification::DeviceIdentity>, CmdErr> {
+        // min alignment for alloc is 8. This should be u16
+        let mut buffer = alloc::boxed::Box::new([0u8; 512]);
+
+        // SAFETY: IdentifyDevice returns a 512 byte buff
+        unsafe {
+            self.issue_cmd(
+                ata::command::constructor::NoArgCmd::IdentifyDevice.compose(),
+                Some(&mut buffer[..]),
+            )
+            .await?;
+        }
+
+        // SAFETY: DeviceIdentity is 512 bytes and all bit patterns are valid
+        Ok(unsafe { core::mem::transmute(buffer) })
+    }
+
+    /// Applies `cfg` to the device using [ata::command::AtaCommand::SET_FEATURES].
+    ///
+    /// The device identity is read before issuing any commands to check that all the features are
+    /// supported and again afterwards to verify the changes were applied.
+    /// If a command fails the remaining commands are not issued.
+    pub async fn configure(&self, cfg: &ata::config::DeviceConfig) -> Result<(), ConfigErr> {
+        let id = self.read_identity().await?;
+        cfg.check_supported(&id)?;
+
+        for cmd in cfg.commands() {
+            // SAFETY: SET FEATURES does not transfer data
+            unsafe { self.issue_cmd(cmd, None) }.await?;
+        }
+
+        let id = self.read_identity().await?;
+        *self.identity.lock() = Some(DevIdentity::from(*id));
+        cfg.verify(&id)?;
+        Ok(())
+    }
+
+    /// Downloads `image` to the device using [ata::command::AtaCommand::DOWNLOAD_MICROCODE] and
+    /// activates it, see [ata::microcode] for how the image is transferred.
+    ///
+    /// `progress` is called with the number of bytes transferred after each command completes.
+    /// The cached identity is refreshed once the new microcode is active.
+    pub async fn download_microcode(
+        &self,
+        image: &[u8],
+        mut progress: impl FnMut(usize),
+    ) -> Result<(), CmdErr> {
+        let id = self.read_identity().await?;
+        let download =
+            ata::microcode::MicrocodeDownload::new(&id, image.len()).map_err(CmdErr::MicrocodeErr)?;
+        log::debug!(
+            "AHCI: {self} downloading microcode using {:?} in {} segments",
+            download.subcommand(),
+            download.segments()
+        );
+
+        for (range, cmd) in download {
+            let len = range.len();
+            let seg = &image[range];
+            // buffer must be word aligned
+            let copy;
+            let seg = if seg.as_ptr() as usize % 2 == 0 {
+                seg
+            } else {
+                copy = alloc::boxed::Box::<[u8]>::from(seg);
+                &copy[..]
+            };
+            // SAFETY: The buffer is the size given in the command
+            unsafe { self.issue_cmd(cmd, Some(seg)) }.await?;
+            progress(len);
+        }
+
+        let id = self.read_identity().await?;
+        *self.identity.lock() = Some(DevIdentity::from(*id));
+        Ok(())
+    }
+
+    /// Reads the Device Statistics log and decodes it, see [ata::structures::device_stats].
+    ///
+    /// Returns `Err(Unsupported)` if the device does not support the General Purpose Logging
+    /// feature set or the log.
+    pub async fn read_device_statistics(
+        &self,
+    ) -> Result<ata::structures::device_stats::DeviceStatistics, CmdErr> {
+        use ata::structures::device_stats::{self, page, DeviceStatistics};
+        use ata::structures::identification::{Features119, Features84};
+
+        let id = self.read_identity().await?;
+        if !id.features.features_84.contains(Features84::GPL_FEATURES) {
+            return Err(CmdErr::Unsupported);
+        }
+        let dma = id.features119.contains(Features119::LOG_DMA_EXT);
+
+        let read_page = |n: u8| async move {
+            let mut buff = alloc::boxed::Box::new([0u8; device_stats::PAGE_SIZE]);
+            // Never returns None, the page count is 1
+            let cmd = ata::command::constructor::ReadLogCmd::new(
+                device_stats::DEVICE_STATISTICS_LOG,
+                n as u16,
+                1,
+                dma,
+            )
+            .unwrap();
+            // SAFETY: The buffer is the size of one log page
+            unsafe { self.issue_cmd(cmd.compose(), Some(&mut buff[..])) }.await?;
+            Ok::<_, CmdErr>(buff)
+        };
+
+        // The device aborts the command if it does not support the log
+        let supported = match read_page(page::SUPPORTED).await {
+            Err(CmdErr::DevErr(_)) => return Err(CmdErr::Unsupported),
+            r => r?,
+        };
+
+        let mut stats = DeviceStatistics::default();
+        for p in device_stats::supported_pages(&supported) {
+            if matches!(p, page::GENERAL | page::TEMPERATURE | page::SOLID_STATE) {
+                let data = read_page(p).await?;
+                if !stats.apply_page(&data) {
+                    log::warn!("AHCI: {self} returned invalid device statistics page {p:#x}");
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Checks that the device supports `feature`, returns whether the SCT logs may be accessed
+    /// using DMA.
+    ///
+    /// Returns `Err(Unsupported)` if the device does not support `feature`.
+    async fn sct_support(&self, feature: ata::sct::SctFeature) -> Result<bool, CmdErr> {
+        let id = self.read_identity().await?;
+        if !feature.is_supported(&id) {
+            return Err(CmdErr::Unsupported);
+        }
+        Ok(id
+            .features119
+            .contains(ata::structures::identification::Features119::LOG_DMA_EXT))
+    }
+
+    /// Issues the SCT command `key` and returns the resulting status, see [ata::sct].
+    async fn sct_command(
+        &self,
+        key: &ata::sct::SctKey,
+        dma: bool,
+    ) -> Result<ata::sct::SctStatus, CmdErr> {
+        // SAFETY: The key is one log page
+        unsafe { self.issue_cmd(key.command(dma), Some(&key.as_bytes()[..])) }.await?;
+        self.sct_status(dma).await
+    }
+
+    /// Reads the SCT status from the SCT command log.
+    async fn sct_status(&self, dma: bool) -> Result<ata::sct::SctStatus, CmdErr> {
+        let mut buff = alloc::boxed::Box::new([0u8; ata::sct::KEY_SIZE]);
+        // Never returns None, the page count is 1
+        let cmd = ata::command::constructor::ReadLogCmd::new(ata::sct::SCT_COMMAND_LOG, 0, 1, dma)
+            .unwrap();
+        // SAFETY: The buffer is the size of one log page
+        unsafe { self.issue_cmd(cmd.compose(), Some(&mut buff[..])) }.await?;
+        Ok(ata::sct::SctStatus::from_raw(&buff))
+    }
+
+    /// Sets the read and write error recovery time limits in units of 100ms, `0` disables the
+    /// limit. See [ata::sct::SctKey::set_error_recovery].
+    ///
+    /// The limits are volatile and are lost when the device is reset.
+    pub async fn set_error_recovery(&self, read: u16, write: u16) -> Result<(), CmdErr> {
+        use ata::sct::{RecoveryTimer, SctFeature, SctKey};
+        let dma = self.sct_support(SctFeature::ErrorRecovery).await?;
+        for (timer, limit) in [(RecoveryTimer::Read, read), (RecoveryTimer::Write, write)] {
+            let key = SctKey::set_error_recovery(timer, limit);
+            let status = self.sct_command(&key, dma).await?;
+            if status.extended_status != 0 {
+                log::warn!(
+                    "AHCI: {self} failed to set {timer:?} recovery time: status {:#x}",
+                    status.extended_status
+                );
+                return Err(CmdErr::DevErr(0));
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the contents of the device's volatile write cache to non-volatile media, completes
+    /// once all data written before it was issued is stored.
+    pub async fn flush_cache(&self) -> Result<(), CmdErr> {
+        use ata::command::constructor::NoArgCmd;
+        let cmd = if self.get_identity().await.support_48_bit {
+            NoArgCmd::FlushCacheExt
+        } else {
+            NoArgCmd::FlushCache
+        };
+        // SAFETY: FLUSH CACHE does not transfer data
+        unsafe { self.issue_cmd(cmd.compose(), None) }.await?;
+        Ok(())
+    }
+
+    /// Fills `count` logical sectors starting at `lba` with `pattern` using SCT WRITE SAME.
+    ///
+    /// The device performs the write in the background, this polls the SCT status until it
+    /// completes.
+    pub async fn write_same(&self, lba: u64, count: u64, pattern: u32) -> Result<(), CmdErr> {
+        // A count of 0 means "to the end of the device"
+        if count == 0 {
+            return Err(CmdErr::BadArgs);
+        }
+        let dma = self.sct_support(ata::sct::SctFeature::WriteSame).await?;
+        let key = ata::sct::SctKey::write_same(lba, count, pattern);
+        let mut status = self.sct_command(&key, dma).await?;
+        while status.in_progress() {
+            hootux::task::util::sleep(SCT_POLL_MSEC).await;
+            status = self.sct_status(dma).await?;
+        }
+        if status.extended_status != 0 {
+            log::warn!(
+                "AHCI: {self} SCT write same failed: status {:#x}",
+                status.extended_status
+            );
+            return Err(CmdErr::DevErr(0));
+        }
+        Ok(())
+    }
+
+    /// Opens a stream for latency bounded I/O, see [ata::stream].
+    ///
+    /// `latency_us` is the default time limit for commands on the stream in microseconds. If this
+    /// is `None` or the device does not report a time limit granularity the device default is used.
+    pub async fn open_stream(&self, latency_us: Option<u32>) -> Result<Stream, CmdErr> {
+        let id = self.read_identity().await?;
+        if !id
+            .features
+            .features_84
+            .contains(ata::structures::identification::Features84::STREAMING)
+        {
+            return Err(CmdErr::Unsupported);
+        }
+        let params = id.streaming;
+        let cctl = latency_us.and_then(|l| params.cctl(l)).unwrap_or(0);
+        let stream = self.streams.alloc().ok_or(CmdErr::Exhausted)?;
+
+        let cmd = ata::command::constructor::ConfigureStreamCmd::add(
+            stream,
+            cctl,
+            params.min_req_size(),
+        );
+        // SAFETY: CONFIGURE STREAM does not transfer data
+        if let Err(e) = unsafe { self.issue_cmd(cmd.compose(), None) }.await {
+            self.streams.free(stream);
+            return Err(e);
+        }
+        Ok(Stream { id: stream, params })
+    }
+
+    /// Removes the stream from the device and releases its ID.
+    pub async fn close_stream(&self, stream: Stream) -> Result<(), CmdErr> {
+        let cmd = ata::command::constructor::ConfigureStreamCmd::remove(stream.id);
+        // SAFETY: CONFIGURE STREAM does not transfer data
+        let r = unsafe { self.issue_cmd(cmd.compose(), None) }.await;
+        self.streams.free(stream.id);
+        r.map(|_| ())
+    }
+
+    /// Reads from `lba` into `buff` using [ata::command::AtaCommand::READ_STREAM_DMA_EXT].
+    ///
+    /// The request must be aligned to [ata::structures::identification::Streaming::min_req_size]
+    /// otherwise `Err(BadArgs)` is returned, see [Stream::align_request].
+    /// When `continuous` is set device errors do not fail the request and
+    /// [StreamStatus::Degraded] is returned instead, the data read may contain errors.
+    pub async fn stream_read(
+        &self,
+        stream: &Stream,
+        lba: SectorAddress,
+        buff: &mut [u8],
+        continuous: bool,
+    ) -> Result<StreamStatus, CmdErr> {
+        let count = self.stream_count(stream, lba, buff.len()).await?;
+        let cmd = ata::command::constructor::StreamCmd::new(
+            ata::command::constructor::SpanningCmdType::Read,
+            stream.id,
+            lba.raw(),
+            count,
+            0,
+        )
+        .map_err(CmdErr::AddrErr)?
+        .continuous(continuous);
+        // SAFETY: The count was calculated from the size of the buffer
+        let r = unsafe { self.issue_cmd(cmd.compose(), Some(&mut *buff)) }.await;
+        Self::stream_status(r, continuous)
+    }
+
+    /// Writes `buff` to `lba` using [ata::command::AtaCommand::WRITE_STREAM_DMA_EXT].
+    ///
+    /// The same requirements as [Self::stream_read] apply. When `flush` is set the data is
+    /// written to the media before the command completes.
+    pub async fn stream_write(
+        &self,
+        stream: &Stream,
+        lba: SectorAddress,
+        buff: &[u8],
+        continuous: bool,
+        flush: bool,
+    ) -> Result<StreamStatus, CmdErr> {
+        let count = self.stream_count(stream, lba, buff.len()).await?;
+        let cmd = ata::command::constructor::StreamCmd::new(
+            ata::command::constructor::SpanningCmdType::Write,
+            stream.id,
+            lba.raw(),
+            count,
+            0,
+        )
+        .map_err(CmdErr::AddrErr)?
+        .continuous(continuous)
+        .flag(flush);
+        // SAFETY: The count was calculated from the size of the buffer
+        let r = unsafe { self.issue_cmd(cmd.compose(), Some(buff)) }.await;
+        Self::stream_status(r, continuous)
+    }
+
+    /// Checks that a stream request is aligned and returns the number of sectors in it.
+    async fn stream_count(
+        &self,
+        stream: &Stream,
+        lba: SectorAddress,
+        len: usize,
+    ) -> Result<u32, CmdErr> {
+        let id = self.get_identity().await;
+        if len as u64 % id.lba_size != 0 || len == 0 {
+            return Err(CmdErr::BadArgs);
+        }
+        let count: u32 = (len as u64 / id.lba_size)
+            .try_into()
+            .map_err(|_| CmdErr::BadArgs)?;
+        if stream.align_request(lba.raw(), count) != (lba.raw(), count)
+            || id.exceeds_dev(lba, SectorCount::new(count).ok_or(CmdErr::BadArgs)?)
+        {
+            return Err(CmdErr::BadArgs);
+        }
+        Ok(count)
+    }
+
+    fn stream_status<T>(r: Result<T, CmdErr>, continuous: bool) -> Result<StreamStatus, CmdErr> {
+        match r {
+            Ok(_) => Ok(StreamStatus::Complete),
+            Err(CmdErr::DevErr(e)) if continuous => Ok(StreamStatus::Degraded(e)),
+            Err(e) => Err(e),
+        }
+    }
+
     fn enable(&self, state: bool) {
         self.port.lock().cmd_status.update(|t| {
             t.set(crate::hba::port_control::CommStatus::START, state);
@@ -304,7 +669,7 @@ impl Port {
     /// The caller must ensure that the buffer is correctly sized for the given command.
     async unsafe fn exec_cmd(&self, cmd: CmdFuture) -> Option<u8> {
         // not allowed to run commands while in error state
-        if self.err_chk.is_err() {
+        if self.err_chk.is_err() || self.recovering.load(atomic::Ordering::Relaxed) {
             return None;
         }
 
@@ -325,6 +690,7 @@ impl Port {
         // fixme errors here should be handled
         table.send_fis(fis, b.map(|d| d.cast_mut())).expect("fixme");
         *self.active_cmd_fut[slot as usize].lock() = Some(cmd.clone());
+        cmd.data.start_deadline();
 
         self.port.lock().tfd_wait();
 
@@ -357,6 +723,10 @@ impl Port {
     /// Checks for completed commands, wakes them and attempts to issue new commands from the queue.
     /// This may require waiting for [Self::get_identity]. This fn does not wait for command completion.
     async fn refresh_exec(&self) {
+        // Commands are reissued once recovery has completed
+        if self.recovering.load(atomic::Ordering::Relaxed) {
+            return;
+        }
         // check if the port is currently in the err state
         if !self.err_chk.is_err() {
             let tfd;
@@ -426,21 +796,260 @@ impl Port {
 
         let n = self.err_chk.next();
 
+        let nqc = {
+            let l = self.active_cmd_fut[n as usize].lock();
+            let data = &l.as_ref().unwrap().data;
+            data.start_deadline();
+            data.nqc.load(atomic::Ordering::Relaxed)
+        };
+
         // SAFETY: The safety guarantees must have been checked to reach this point.
-        if self.active_cmd_fut[n as usize]
-            .lock()
-            .as_ref()
-            .unwrap()
-            .data
-            .nqc
-            .load(atomic::Ordering::Relaxed)
-        {
+        if nqc {
             unsafe { self.port.lock().exec_nqc(n) }
         } else {
             unsafe { self.port.lock().exec_cmd(n) }
         }
     }
 
+    /// Periodically checks the port for commands which have exceeded their deadline.
+    /// Exits when the port is dropped.
+    async fn watchdog(port: alloc::sync::Weak<Self>) -> hootux::task::TaskResult {
+        loop {
+            hootux::task::util::sleep(WATCHDOG_MSEC).await;
+            let Some(port) = port.upgrade() else {
+                return hootux::task::TaskResult::ExitedNormally;
+            };
+            port.chk_timeouts().await;
+        }
+    }
+
+    /// Returns the running commands which have exceeded their deadline.
+    fn expired(&self) -> u32 {
+        let now = hootux::time::get_sys_time();
+        let outstanding = {
+            let l = self.port.lock();
+            l.get_ci() | l.get_sact()
+        };
+        let mut ret = 0;
+        for i in 0..self.info.queue_depth() {
+            let mask = 1 << i;
+            if self.cmd_lock.cmd[i as usize].load(atomic::Ordering::Relaxed) != CmdLockState::Running
+                || outstanding & mask == 0
+            {
+                continue;
+            }
+            if let Some(c) = self.active_cmd_fut[i as usize].lock().as_ref() {
+                let deadline = c.data.deadline.load(atomic::Ordering::Relaxed);
+                if deadline != 0 && deadline < now {
+                    ret |= mask;
+                }
+            }
+        }
+        ret
+    }
+
+    /// Checks for commands which have exceeded their deadline and recovers the port if any are found.
+    async fn chk_timeouts(&self) {
+        if self.recovering.load(atomic::Ordering::Relaxed) || self.expired() == 0 {
+            return;
+        }
+        // Completions may not have been handled yet, i.e. when an interrupt was missed
+        self.refresh_exec().await;
+        let expired = self.expired();
+        if expired != 0 {
+            self.recover(expired).await;
+        }
+    }
+
+    /// Recovers the port after the commands in `expired` exceeded their deadline.
+    ///
+    /// The command list engine is stopped, which aborts every outstanding command. The expired
+    /// commands are failed with [CmdErr::Timeout] and every other outstanding command is returned
+    /// to the front of the queue to be reissued. The device is then sent a NOP which causes it to
+    /// discard any NCQ commands it still holds, if the device does not respond to the NOP, or
+    /// remains busy after the engine is stopped, it is reset using COMRESET.
+    ///
+    /// SERVICE is not used because it is only defined for the legacy TCQ feature set.
+    async fn recover(&self, expired: u32) {
+        self.recovering.store(true, atomic::Ordering::Relaxed);
+        log::warn!("{self}: Command timeout on slots {expired:#x}, aborting");
+
+        // Clearing START also clears PxCI and PxSACT
+        let outstanding = {
+            let l = self.port.lock();
+            l.get_ci() | l.get_sact()
+        };
+
+        let mut reset = false;
+        if !self.stop_engine().await {
+            log::warn!("{self}: Command list engine did not halt, resetting device");
+            reset = true;
+            if !self.comreset().await {
+                return self.recovery_failed();
+            }
+        }
+
+        // The HBA is halted so command buffers are no longer accessed
+        let mut requeue = alloc::vec::Vec::new();
+        for i in 0..self.info.queue_depth() {
+            let mask = 1 << i;
+            if self.cmd_lock.cmd[i as usize].load(atomic::Ordering::Relaxed) != CmdLockState::Running {
+                continue;
+            }
+            if let Some(c) = self.active_cmd_fut[i as usize].lock().take() {
+                if expired & mask != 0 {
+                    c.err(CmdErr::Timeout);
+                } else if outstanding & mask != 0 {
+                    requeue.push(c);
+                } else {
+                    c.ready();
+                }
+            }
+            self.cmd_lock.free(i);
+        }
+        self.err_chk.reset();
+        {
+            let mut q = self.cmd_queue.lock();
+            for c in requeue.into_iter().rev() {
+                q.push_front(c);
+            }
+        }
+
+        if !reset && self.port.lock().dev_busy() {
+            log::warn!("{self}: Device busy after abort, resetting device");
+            reset = true;
+            if !self.comreset().await {
+                return self.recovery_failed();
+            }
+        }
+
+        self.clear_errors();
+        self.enable(true);
+
+        if !reset && !self.abort_queue().await {
+            log::warn!("{self}: Device did not respond to NOP, resetting device");
+            self.stop_engine().await;
+            if !self.comreset().await {
+                return self.recovery_failed();
+            }
+            self.clear_errors();
+            self.enable(true);
+        }
+
+        self.recovering.store(false, atomic::Ordering::Relaxed);
+        self.drain_queue().await;
+    }
+
+    /// Abandons every command when the device does not respond to recovery.
+    fn recovery_failed(&self) {
+        log::error!("{self}: Device did not respond to COMRESET");
+        self.abandon_cmd();
+        for i in 0..self.info.queue_depth() {
+            self.cmd_lock.free(i);
+        }
+        self.err_chk.reset();
+        self.recovering.store(false, atomic::Ordering::Relaxed);
+    }
+
+    /// Sends a NOP to the device which aborts any commands still queued within the device.
+    /// The command engine must be running. Returns whether the device responded.
+    ///
+    /// The NOP is always aborted by the device, the command engine is stopped and errors are
+    /// cleared before this fn returns.
+    async fn abort_queue(&self) -> bool {
+        let Some(slot) = self.cmd_lock.get_cmd() else {
+            return false;
+        };
+        // NOP is not an opaque command so this never returns None
+        let (fis, _) = self
+            .compile_fis(&ata::command::constructor::NoArgCmd::Nop.compose())
+            .unwrap();
+        self.cmd_tables
+            .table(slot)
+            .unwrap()
+            .send_fis(fis, None)
+            .expect("fixme");
+
+        // SAFETY: The FIS is valid and NOP does not transfer data
+        unsafe { self.port.lock().exec_cmd(slot) };
+        self.cmd_lock.full_lock(slot);
+
+        let mask = 1 << slot;
+        let ok = self
+            .wait_for(NOP_TIMEOUT_MSEC, |p| {
+                !p.dev_busy() && (p.get_ci() & mask == 0 || p.task_file_data.read().get_status() & 1 != 0)
+            })
+            .await;
+
+        // The error returned by the NOP halts the command engine
+        self.stop_engine().await;
+        self.cmd_lock.free(slot);
+        self.clear_errors();
+        self.enable(true);
+        ok
+    }
+
+    /// Clears START and waits for the command list engine to halt. Returns whether it halted.
+    async fn stop_engine(&self) -> bool {
+        self.enable(false);
+        self.wait_for(ENGINE_STOP_MSEC, |p| !p.engine_running()).await
+    }
+
+    /// Resets the device using COMRESET, the command engine must be stopped.
+    /// Returns whether the device became ready again.
+    async fn comreset(&self) -> bool {
+        self.port.lock().set_interface_reset(true);
+        hootux::task::util::sleep(1).await;
+        self.port.lock().set_interface_reset(false);
+
+        if !self.wait_for(COMRESET_MSEC, |p| p.dev_present()).await {
+            return false;
+        }
+        let ready = self.wait_for(CMD_TIMEOUT_MSEC, |p| !p.dev_busy()).await;
+        self.port.lock().clear_sata_err();
+        ready
+    }
+
+    /// Clears PxSERR and the port interrupt status.
+    fn clear_errors(&self) {
+        self.port.lock().clear_sata_err();
+        self.int_clear(InterruptStatus::all());
+    }
+
+    /// Polls `cond` until it returns true or `msec` milliseconds elapse.
+    /// Returns whether `cond` returned true.
+    async fn wait_for(
+        &self,
+        msec: u64,
+        cond: impl Fn(&crate::hba::port_control::PortControl) -> bool,
+    ) -> bool {
+        let deadline = hootux::time::get_sys_time() + msec * 1_000_000;
+        loop {
+            let done = cond(&self.port.lock());
+            if done {
+                return true;
+            }
+            if hootux::time::get_sys_time() > deadline {
+                return false;
+            }
+            hootux::task::util::sleep(1).await;
+        }
+    }
+
+    /// Issues queued commands until the queue is empty or no command slots are free.
+    async fn drain_queue(&self) {
+        loop {
+            let Some(c) = self.cmd_queue.lock().pop_front() else {
+                return;
+            };
+            // SAFETY: Commands are checked before they are queued
+            if unsafe { self.exec_cmd(c.clone()) }.await.is_none() {
+                self.cmd_queue.lock().push_front(c);
+                return;
+            }
+        }
+    }
+
     /// Returns which commands are completed
     fn complete(&self) -> u32 {
         let mut ret = 0;
@@ -477,6 +1086,7 @@ impl Port {
                 buff: atomic::Atomic::new(buff.map(|b| b as *const [u8])),
                 waker: Default::default(),
                 nqc: atomic::Atomic::new(false),
+                deadline: atomic::Atomic::new(0),
             }),
         }
     }
@@ -563,23 +1173,55 @@ impl Port {
         b.resize(id.lba_size as usize * count.count_ext() as usize, 0);
         let mut b = b.into_boxed_slice();
 
-        let c = constructor::SpanningCmd::new(
+        self.read_into(lba, &mut b).await?;
+        Ok(b)
+    }
+
+    /// Reads from the device at `lba` into `buff`. The size of `buff` must be aligned to the
+    /// logical sector size of the device.
+    ///
+    /// This fn will return Err(BadArgs) under the same conditions as [Self::write]. Reads are
+    /// issued using 28-bit commands where possible, see [ata::command::constructor::Addressing::select].
+    pub async fn read_into(&self, lba: SectorAddress, buff: &mut [u8]) -> Result<(), CmdErr> {
+        use ata::command::constructor;
+
+        let id = self.get_identity().await;
+        if buff.len() as u64 % id.lba_size != 0 {
+            return Err(CmdErr::BadArgs);
+        }
+
+        let count = SectorCount::new(
+            (buff.len() as u64 / id.lba_size)
+                .try_into()
+                .ok()
+                .ok_or(CmdErr::BadArgs)?,
+        )
+        .ok_or(CmdErr::BadArgs)?;
+
+        if id.exceeds_dev(lba, count) || buff.len() == 0 {
+            return Err(CmdErr::BadArgs);
+        }
+
+        let c = constructor::DmaCmd::new(
             constructor::SpanningCmdType::Read,
             lba.raw(),
-            count.count_ext(),
+            count.0.get(),
+            id.support_48_bit,
         )
-        .ok_or(CmdErr::BadArgs)?;
+        .map_err(CmdErr::AddrErr)?;
 
         // SAFETY: This is safe because the command take a buffer and the buffer size is equal to
         // the size of the expected data.
-        unsafe { self.issue_cmd(c.compose(), Some(&mut *b)) }.await?;
-        Ok(b)
+        unsafe { self.issue_cmd(c.compose(), Some(&mut *buff)) }.await?;
+        Ok(())
     }
 
     /// This fn writes the given buffer to the device at starting at `lba`.
     ///
     /// This fn will return Err(BadArgs) if the size of given buffer is not aligned to the logical
     /// sector size of the device or the buffer + `lba` exceeds the size of the device.
+    /// `Err(AddrErr(Requires48Bit))` is returned if the write requires 48-bit addressing and the
+    /// device does not support it.
     pub async fn write(&self, lba: SectorAddress, buff: &[u8]) -> Result<(), CmdErr> {
         use ata::command::constructor;
 
@@ -600,12 +1242,13 @@ impl Port {
             return Err(CmdErr::BadArgs);
         }
 
-        let c = constructor::SpanningCmd::new(
+        let c = constructor::DmaCmd::new(
             constructor::SpanningCmdType::Write,
             lba.raw(),
-            count.count_ext(),
+            count.0.get(),
+            id.support_48_bit,
         )
-        .ok_or(CmdErr::BadArgs)?;
+        .map_err(CmdErr::AddrErr)?;
         // SAFETY: This is safe because the the count has been calculated from the size of the buffer.
         unsafe { self.issue_cmd(c.compose(), Some(buff)) }.await?;
         Ok(())
@@ -774,6 +1417,10 @@ impl Port {
     }
 
     async fn update(&self) {
+        // Interrupts raised during recovery are cleared when it completes
+        if self.recovering.load(atomic::Ordering::Relaxed) {
+            return;
+        }
         if self.handle_int() {
             self.refresh_exec().await
         }
@@ -950,6 +1597,8 @@ struct CmdDataInner {
     waker: futures::task::AtomicWaker,
     // contains whether this command used NQC. This is here exclusively for error checking
     nqc: atomic::Atomic<bool>,
+    // System time in nanoseconds after which the command has timed out. 0 until the command is issued.
+    deadline: atomic::Atomic<u64>,
 }
 
 impl CmdDataInner {
@@ -961,6 +1610,22 @@ impl CmdDataInner {
     fn get_buff(&self) -> Option<*const [u8]> {
         self.buff.load(atomic::Ordering::Relaxed)
     }
+
+    /// Sets the deadline for the command, this should be called when the command is issued.
+    fn start_deadline(&self) {
+        use ata::command::AtaCommand;
+        let timeout = match self.cmd.command {
+            MaybeOpaqueCommand::Concrete(
+                AtaCommand::FLUSH_CACHE
+                | AtaCommand::FLUSH_CACHE_EXT
+                | AtaCommand::DOWNLOAD_MICROCODE
+                | AtaCommand::DOWNLOAD_MICROCODE_DMA,
+            ) => LONG_CMD_TIMEOUT_MSEC,
+            _ => CMD_TIMEOUT_MSEC,
+        };
+        let deadline = hootux::time::get_sys_time() + timeout * 1_000_000;
+        self.deadline.store(deadline, atomic::Ordering::Relaxed);
+    }
 }
 
 unsafe impl Send for CmdDataInner {}
@@ -992,6 +1657,69 @@ pub enum CmdErr {
     /// An error was encountered while building the command. This differs from [Self::AtaErr] because
     /// the command itself was logically correct but an issue was encountered while building the command.
     BuildErr(cmd_ctl::CommandError),
+    /// The requested sectors cannot be addressed by any command supported by the device.
+    AddrErr(ata::command::constructor::AddressError),
+    /// The microcode image cannot be downloaded to the device.
+    MicrocodeErr(ata::microcode::MicrocodeError),
+    /// The device does not support the requested feature.
+    Unsupported,
+    /// All of the resources required for the request, such as stream IDs, are in use.
+    Exhausted,
+    /// The command did not complete before its deadline and was aborted.
+    Timeout,
+}
+
+/// A stream opened using [Port::open_stream].
+#[derive(Debug)]
+pub struct Stream {
+    id: ata::stream::StreamId,
+    params: ata::structures::identification::Streaming,
+}
+
+impl Stream {
+    pub fn id(&self) -> ata::stream::StreamId {
+        self.id
+    }
+
+    /// Returns the streaming parameters reported by the device.
+    pub fn params(&self) -> &ata::structures::identification::Streaming {
+        &self.params
+    }
+
+    /// See [ata::structures::identification::Streaming::align_request].
+    pub fn align_request(&self, lba: u64, count: u32) -> (u64, u32) {
+        self.params.align_request(lba, count)
+    }
+}
+
+/// The result of a successful stream request.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StreamStatus {
+    Complete,
+    /// A continuous request completed within its time limit but the device encountered errors.
+    /// Contains the error bits from the command, details are recorded in the streaming error log.
+    Degraded(u8),
+}
+
+/// Errors returned by [Port::configure].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ConfigErr {
+    /// A command failed.
+    Cmd(CmdErr),
+    /// The device does not support the config or did not apply it.
+    Config(ata::config::ConfigError),
+}
+
+impl From<CmdErr> for ConfigErr {
+    fn from(value: CmdErr) -> Self {
+        Self::Cmd(value)
+    }
+}
+
+impl From<ata::config::ConfigError> for ConfigErr {
+    fn from(value: ata::config::ConfigError) -> Self {
+        Self::Config(value)
+    }
 }
 
 #[derive(Clone)]
@@ -1127,6 +1855,14 @@ impl PortErrChk {
         }
     }
 
+    /// Leaves the error checking state. Used when all commands have been aborted.
+    fn reset(&self) {
+        let mut l = self.inner.lock();
+        l.waiting = false;
+        l.err_cmd = 0;
+        l.err_chk = None;
+    }
+
     fn is_err(&self) -> bool {
         let l = self.inner.lock();
         l.err_chk.is_some() || l.waiting
@@ -3,6 +3,7 @@ use crate::driver::CmdErr;
 use alloc::boxed::Box;
 use core::any::Any;
 use futures::FutureExt;
+use hootux::mem::dma::DmaTarget;
 use hootux::system::sysfs::block;
 
 #[derive(Clone)]
@@ -24,6 +25,36 @@ impl AhciBlockDev {
     }
 }
 
+fn map_cmd_err(e: CmdErr) -> block::BlockDevIoErr {
+    match e {
+        CmdErr::AtaErr => block::BlockDevIoErr::InternalDriverErr,
+        CmdErr::DevErr(e) => {
+            // todo log this earlier when more context is available
+            log::error!("SATA Device returned Error {}", e);
+            block::BlockDevIoErr::HardwareError
+        }
+        CmdErr::Disowned => block::BlockDevIoErr::DeviceOffline,
+        CmdErr::Timeout => {
+            log::error!("SATA Device timed out");
+            block::BlockDevIoErr::HardwareError
+        }
+        CmdErr::BadArgs => block::BlockDevIoErr::OutOfRange,
+        CmdErr::BuildErr(_) => block::BlockDevIoErr::InternalDriverErr,
+        CmdErr::AddrErr(_) => block::BlockDevIoErr::OutOfRange,
+        CmdErr::Unsupported | CmdErr::Exhausted => block::BlockDevIoErr::NotSupported,
+        CmdErr::MicrocodeErr(e) => {
+            use ata::microcode::MicrocodeError;
+            match e {
+                MicrocodeError::Unsupported => block::BlockDevIoErr::NotSupported,
+                MicrocodeError::Misaligned => block::BlockDevIoErr::Misaligned,
+                MicrocodeError::TooSmall | MicrocodeError::TooLarge => {
+                    block::BlockDevIoErr::GeomError
+                }
+            }
+        }
+    }
+}
+
 impl block::BlockDev for AhciBlockDev {
     fn read(&self, seek: block::BlockDevGeomIntegral, size: usize) -> block::IoFut<Box<[u8]>> {
         async move {
@@ -44,21 +75,7 @@ impl block::BlockDev for AhciBlockDev {
                     .ok_or(block::BlockDevIoErr::GeomError)?,
                 )
                 .await;
-            return if r.is_ok() {
-                Ok(r.unwrap())
-            } else {
-                match r.unwrap_err() {
-                    CmdErr::AtaErr => Err(block::BlockDevIoErr::InternalDriverErr),
-                    CmdErr::DevErr(e) => {
-                        // todo log this earlier when more context is available
-                        log::error!("SATA Device returned Error {}", e);
-                        Err(block::BlockDevIoErr::HardwareError)
-                    }
-                    CmdErr::Disowned => Err(block::BlockDevIoErr::DeviceOffline),
-                    CmdErr::BadArgs => Err(block::BlockDevIoErr::OutOfRange),
-                    CmdErr::BuildErr(_) => Err(block::BlockDevIoErr::InternalDriverErr),
-                }
-            };
+            r.map_err(map_cmd_err)
         }
         .boxed()
     }
@@ -91,21 +108,163 @@ impl block::BlockDev for AhciBlockDev {
                 )
                 .await;
 
-            if u.is_err() {
-                match u.unwrap_err() {
-                    CmdErr::AtaErr => Err(block::BlockDevIoErr::InternalDriverErr),
-                    CmdErr::DevErr(e) => {
-                        // todo log this earlier when more context is available
-                        log::error!("SATA Device returned Error {}", e);
-                        Err(block::BlockDevIoErr::HardwareError)
-                    }
-                    CmdErr::Disowned => Err(block::BlockDevIoErr::DeviceOffline),
-                    CmdErr::BadArgs => Err(block::BlockDevIoErr::OutOfRange),
-                    CmdErr::BuildErr(_) => Err(block::BlockDevIoErr::InternalDriverErr),
-                }
-            } else {
-                Ok(buff)
+            u.map(|_| buff).map_err(map_cmd_err)
+        }
+        .boxed()
+    }
+
+    fn read_into<'a>(
+        &'a self,
+        seek: block::BlockDevGeomIntegral,
+        buff: &'a mut dyn DmaTarget,
+    ) -> block::IoFut<'a, ()> {
+        async move {
+            let port = self
+                .port
+                .upgrade()
+                .ok_or(block::BlockDevIoErr::DeviceOffline)?;
+
+            // SAFETY: as_mut guarantees that this is safe.
+            let buff = unsafe { &mut *buff.as_mut() };
+            // buffer must be word aligned
+            if buff.len() % 2 != 0 || buff.as_ptr() as usize % 2 != 0 {
+                return Err(block::BlockDevIoErr::Misaligned);
             }
+
+            port.read_into(
+                super::SectorAddress::new(seek).ok_or(block::BlockDevIoErr::OutOfRange)?,
+                buff,
+            )
+            .await
+            .map_err(map_cmd_err)
+        }
+        .boxed()
+    }
+
+    fn write_from<'a>(
+        &'a self,
+        seek: block::BlockDevGeomIntegral,
+        buff: &'a mut dyn DmaTarget,
+    ) -> block::IoFut<'a, ()> {
+        async move {
+            let port = self
+                .port
+                .upgrade()
+                .ok_or(block::BlockDevIoErr::DeviceOffline)?;
+
+            // SAFETY: as_mut guarantees that this is safe.
+            let buff = unsafe { &*buff.as_mut() };
+            // buffer must be word aligned
+            if buff.len() % 2 != 0 || buff.as_ptr() as usize % 2 != 0 {
+                return Err(block::BlockDevIoErr::Misaligned);
+            }
+
+            port.write(
+                super::SectorAddress::new(seek).ok_or(block::BlockDevIoErr::OutOfRange)?,
+                buff,
+            )
+            .await
+            .map_err(map_cmd_err)
+        }
+        .boxed()
+    }
+
+    fn update_firmware<'a>(
+        &'a self,
+        image: &'a [u8],
+        progress: &'a block::FirmwareProgress,
+    ) -> block::IoFut<'a, ()> {
+        async move {
+            let port = self
+                .port
+                .upgrade()
+                .ok_or(block::BlockDevIoErr::DeviceOffline)?;
+
+            let r = port
+                .download_microcode(image, |n| progress.advance(n as u64))
+                .await;
+            // The new firmware may report a different geometry
+            *self.geom.write() = None;
+            r.map_err(map_cmd_err)
+        }
+        .boxed()
+    }
+
+    fn device_statistics(&self) -> block::IoFut<block::DeviceStatistics> {
+        async {
+            let port = self
+                .port
+                .upgrade()
+                .ok_or(block::BlockDevIoErr::DeviceOffline)?;
+
+            let lba_size = port.get_identity().await.lba_size;
+            let s = port.read_device_statistics().await.map_err(map_cmd_err)?;
+            Ok(block::DeviceStatistics {
+                power_on_hours: s.power_on_hours,
+                power_cycles: s.power_on_resets,
+                bytes_written: s.logical_sectors_written.map(|n| n * lba_size),
+                bytes_read: s.logical_sectors_read.map(|n| n * lba_size),
+                temperature: s.temperature.map(Into::into),
+                highest_temperature: s.highest_temperature.map(Into::into),
+                lowest_temperature: s.lowest_temperature.map(Into::into),
+                max_operating_temperature: s.max_operating_temperature.map(Into::into),
+                endurance_used: s.endurance_used,
+            })
+        }
+        .boxed()
+    }
+
+    fn set_error_recovery(&self, read_ms: u32, write_ms: u32) -> block::IoFut<()> {
+        async move {
+            let port = self
+                .port
+                .upgrade()
+                .ok_or(block::BlockDevIoErr::DeviceOffline)?;
+
+            // SCT uses units of 100ms
+            let to_units = |ms: u32| {
+                u16::try_from(ms.div_ceil(100)).map_err(|_| block::BlockDevIoErr::OutOfRange)
+            };
+            port.set_error_recovery(to_units(read_ms)?, to_units(write_ms)?)
+                .await
+                .map_err(map_cmd_err)
+        }
+        .boxed()
+    }
+
+    fn write_zeroes(
+        &self,
+        seek: block::BlockDevGeomIntegral,
+        count: block::BlockDevGeomIntegral,
+    ) -> block::IoFut<()> {
+        async move {
+            let port = self
+                .port
+                .upgrade()
+                .ok_or(block::BlockDevIoErr::DeviceOffline)?;
+
+            let lba_count = port.get_identity().await.lba_count;
+            if seek.checked_add(count).map_or(true, |end| end > lba_count) {
+                return Err(block::BlockDevIoErr::OutOfRange);
+            }
+            if count == 0 {
+                return Ok(());
+            }
+            match port.write_same(seek, count, 0).await {
+                Err(CmdErr::Unsupported) => block::zero_fill(self, seek, count).await,
+                r => r.map_err(map_cmd_err),
+            }
+        }
+        .boxed()
+    }
+
+    fn flush(&self) -> block::IoFut<()> {
+        async {
+            let port = self
+                .port
+                .upgrade()
+                .ok_or(block::BlockDevIoErr::DeviceOffline)?;
+            port.flush_cache().await.map_err(map_cmd_err)
         }
         .boxed()
     }
@@ -195,6 +195,12 @@ impl AhciDriver {
             s.hba.int_enable(false);
         }
 
+        for p in s.hba.ports.iter().flatten() {
+            hootux::task::run_task(Box::pin(super::Port::watchdog(
+                alloc::sync::Arc::downgrade(p),
+            )));
+        }
+
         hootux::task::run_task(Box::pin(Box::new(s).run()));
 
         Ok(())
@@ -25,7 +25,7 @@ pub(crate) struct PortControl {
     /// PxSCTL
     sata_ctl: Register<SataControl>,
     /// PxSERR
-    sata_err: Register<SataErr>,
+    sata_err: Register<SataErr, ReadWriteClear<SataErr>>,
     /// PxSCAT
     sata_active: CmdIssue,
     /// PxCI
@@ -109,6 +109,43 @@ impl PortControl {
     pub(crate) fn set_ci(&self, cmds: u32) {
         self.command_issue.0.set(cmds);
     }
+
+    /// Returns the NCQ tags which have not been completed by the device.
+    pub(crate) fn get_sact(&self) -> u32 {
+        self.sata_active.0.get()
+    }
+
+    /// Returns whether the command list engine is running. After [CommStatus::START] is cleared
+    /// this must be polled until it returns false before the port can be reset or restarted.
+    pub(crate) fn engine_running(&self) -> bool {
+        self.cmd_status.read().contains(CommStatus::COMMAND_LIST_RUNNING)
+    }
+
+    /// Returns whether the device is busy or is requesting a data transfer.
+    pub(crate) fn dev_busy(&self) -> bool {
+        self.task_file_data.read().status & 0x88 != 0
+    }
+
+    /// Returns whether communication with the device is established.
+    pub(crate) fn dev_present(&self) -> bool {
+        self.sata_status.read().dev_detect() == DeviceDetection::InComm
+    }
+
+    /// Asserts or de-asserts COMRESET. COMRESET must remain asserted for at least 1ms and
+    /// [CommStatus::START] must be clear while it is asserted.
+    pub(crate) fn set_interface_reset(&mut self, reset: bool) {
+        let action = if reset {
+            DeviceDetectionInit::ResetInterface
+        } else {
+            DeviceDetectionInit::NoAction
+        };
+        self.sata_ctl.update(|c| c.set_dev_detect_init(action));
+    }
+
+    /// Clears all errors recorded in PxSERR.
+    pub(crate) fn clear_sata_err(&mut self) {
+        self.sata_err.clear(SataErr::all());
+    }
 }
 
 bitflags::bitflags! {
@@ -793,6 +830,15 @@ bitflags::bitflags! {
     }
 }
 
+unsafe impl Acknowledge<SataErr> for SataErr {
+    fn ack(self) -> SataErr
+    where
+        Self: Sized,
+    {
+        Self::from_bits_truncate(self.bits())
+    }
+}
+
 #[derive(Debug)]
 #[repr(C)]
 struct SataNotification {
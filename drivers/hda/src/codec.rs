@@ -0,0 +1,165 @@
+use crate::controller::Controller;
+use crate::HdaError;
+use alloc::vec::Vec;
+
+// Parameter IDs for GET_PARAMETER
+const PARAM_VENDOR_ID: u8 = 0x00;
+const PARAM_SUB_NODES: u8 = 0x04;
+const PARAM_FG_TYPE: u8 = 0x05;
+const PARAM_WIDGET_CAPS: u8 = 0x09;
+const PARAM_PIN_CAPS: u8 = 0x0C;
+const PARAM_OUT_AMP_CAPS: u8 = 0x12;
+
+const VERB_GET_PARAMETER: u16 = 0xF00;
+const VERB_SET_CONNECTION: u16 = 0x701;
+const VERB_SET_POWER_STATE: u16 = 0x705;
+const VERB_SET_STREAM: u16 = 0x706;
+const VERB_SET_PIN_CTL: u16 = 0x707;
+const VERB_SET_EAPD: u16 = 0x70C;
+const VERB_GET_CONFIG_DEFAULT: u16 = 0xF1C;
+// 4-bit verbs
+const VERB_SET_FORMAT: u8 = 0x2;
+const VERB_SET_AMP_GAIN: u8 = 0x3;
+
+const FG_AUDIO: u32 = 1;
+const WIDGET_OUTPUT: u32 = 0;
+const WIDGET_PIN: u32 = 4;
+const WIDGET_CAPS_OUT_AMP: u32 = 1 << 2;
+const PIN_CAPS_OUTPUT: u32 = 1 << 4;
+/// Port connectivity value indicating nothing is connected to the pin.
+const PIN_NO_CONNECTION: u32 = 1;
+const PIN_CTL_OUT_ENABLE: u8 = 1 << 6;
+const EAPD_ENABLE: u8 = 1 << 1;
+/// Set output amp, left and right, unmuted. The gain is OR'd into the low bits.
+const AMP_OUT_LR: u16 = 0xB000;
+
+/// A command sent to a codec.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Verb(u32);
+
+impl Verb {
+    /// Constructs a verb with a 12-bit identifier and 8-bit payload
+    fn new(codec: u8, node: u8, verb: u16, payload: u8) -> Self {
+        Self((codec as u32) << 28 | (node as u32) << 20 | (verb as u32 & 0xfff) << 8 | payload as u32)
+    }
+
+    /// Constructs a verb with a 4-bit identifier and 16-bit payload
+    fn new_long(codec: u8, node: u8, verb: u8, payload: u16) -> Self {
+        Self((codec as u32) << 28 | (node as u32) << 20 | (verb as u32 & 0xf) << 16 | payload as u32)
+    }
+
+    pub(crate) fn raw(&self) -> u32 {
+        self.0
+    }
+}
+
+#[derive(Debug)]
+struct Widget {
+    node: u8,
+    caps: u32,
+    pin_caps: u32,
+    config_default: u32,
+}
+
+impl Widget {
+    fn widget_type(&self) -> u32 {
+        (self.caps >> 20) & 0xf
+    }
+}
+
+pub(crate) struct Codec {
+    address: u8,
+    vendor: u32,
+    /// Audio function group node
+    afg: Option<u8>,
+    widgets: Vec<Widget>,
+}
+
+impl core::fmt::Display for Codec {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}: {:04x}:{:04x} with {} widgets", self.address, self.vendor >> 16, self.vendor & 0xffff, self.widgets.len())
+    }
+}
+
+impl Codec {
+    fn param(ctl: &mut Controller, codec: u8, node: u8, param: u8) -> Result<u32, HdaError> {
+        ctl.command(Verb::new(codec, node, VERB_GET_PARAMETER, param))
+    }
+
+    /// Returns the range of sub nodes of `node`
+    fn sub_nodes(ctl: &mut Controller, codec: u8, node: u8) -> Result<core::ops::Range<u8>, HdaError> {
+        let r = Self::param(ctl, codec, node, PARAM_SUB_NODES)?;
+        let start = (r >> 16) as u8;
+        Ok(start..start.saturating_add(r as u8))
+    }
+
+    /// Reads the codec at `address` and locates its audio widgets
+    pub(crate) fn probe(ctl: &mut Controller, address: u8) -> Result<Self, HdaError> {
+        let mut codec = Self {
+            address,
+            vendor: Self::param(ctl, address, 0, PARAM_VENDOR_ID)?,
+            afg: None,
+            widgets: Vec::new(),
+        };
+
+        for fg in Self::sub_nodes(ctl, address, 0)? {
+            if Self::param(ctl, address, fg, PARAM_FG_TYPE)? & 0xff == FG_AUDIO {
+                codec.afg = Some(fg);
+                break;
+            }
+        }
+        let Some(afg) = codec.afg else { return Ok(codec) };
+
+        for node in Self::sub_nodes(ctl, address, afg)? {
+            let caps = Self::param(ctl, address, node, PARAM_WIDGET_CAPS)?;
+            let mut w = Widget { node, caps, pin_caps: 0, config_default: 0 };
+            if w.widget_type() == WIDGET_PIN {
+                w.pin_caps = Self::param(ctl, address, node, PARAM_PIN_CAPS)?;
+                w.config_default = ctl.command(Verb::new(address, node, VERB_GET_CONFIG_DEFAULT, 0))?;
+            }
+            codec.widgets.push(w);
+        }
+        Ok(codec)
+    }
+
+    /// Returns the first output converter and output pin.
+    ///
+    /// This assumes that the first connection of the pin is routed to the converter, which is
+    /// true for simple codecs but not in general.
+    pub(crate) fn output_path(&self) -> Option<(u8, u8)> {
+        let dac = self.widgets.iter().find(|w| w.widget_type() == WIDGET_OUTPUT)?;
+        let pin = self.widgets.iter().find(|w| {
+            w.widget_type() == WIDGET_PIN && w.pin_caps & PIN_CAPS_OUTPUT != 0 && w.config_default >> 30 != PIN_NO_CONNECTION
+        })?;
+        Some((dac.node, pin.node))
+    }
+
+    /// Configures the output path to receive data from the stream `tag` using `format`
+    pub(crate) fn configure_output(&self, ctl: &mut Controller, tag: u8, format: u16) -> Result<(), HdaError> {
+        let (dac, pin) = self.output_path().ok_or(HdaError::NoOutput)?;
+        let afg = self.afg.ok_or(HdaError::NoOutput)?;
+        let a = self.address;
+
+        for node in [afg, dac, pin] {
+            ctl.command(Verb::new(a, node, VERB_SET_POWER_STATE, 0))?;
+        }
+
+        ctl.command(Verb::new(a, dac, VERB_SET_STREAM, tag << 4))?;
+        ctl.command(Verb::new_long(a, dac, VERB_SET_FORMAT, format))?;
+
+        for node in [dac, pin] {
+            let w = self.widgets.iter().find(|w| w.node == node).unwrap();
+            if w.caps & WIDGET_CAPS_OUT_AMP != 0 {
+                // the offset is the gain step representing 0dB
+                let offset = Self::param(ctl, a, node, PARAM_OUT_AMP_CAPS)? & 0x7f;
+                ctl.command(Verb::new_long(a, node, VERB_SET_AMP_GAIN, AMP_OUT_LR | offset as u16))?;
+            }
+        }
+
+        ctl.command(Verb::new(a, pin, VERB_SET_CONNECTION, 0))?;
+        ctl.command(Verb::new(a, pin, VERB_SET_PIN_CTL, PIN_CTL_OUT_ENABLE))?;
+        // Not all pins support EAPD, this is ignored when it is unsupported
+        ctl.command(Verb::new(a, pin, VERB_SET_EAPD, EAPD_ENABLE))?;
+        Ok(())
+    }
+}
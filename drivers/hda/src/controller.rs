@@ -0,0 +1,212 @@
+use crate::codec::{Codec, Verb};
+use crate::HdaError;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use hootux::alloc_interface::DmaAlloc;
+use hootux::task::util::sleep;
+
+// Register offsets, see Intel HDA specification rev 1.0a section 3.3
+const GCAP: usize = 0x00;
+const GCTL: usize = 0x08;
+const STATESTS: usize = 0x0E;
+const ICOI: usize = 0x60;
+const ICII: usize = 0x64;
+const ICIS: usize = 0x68;
+const SD_BASE: usize = 0x80;
+const SD_LEN: usize = 0x20;
+
+const GCAP_64OK: u16 = 1;
+const GCTL_CRST: u32 = 1;
+const ICIS_ICB: u16 = 1;
+const ICIS_IRV: u16 = 1 << 1;
+
+// Stream descriptor register offsets
+const SD_CTL: usize = 0x00;
+const SD_CTL_STREAM: usize = 0x02;
+const SD_CBL: usize = 0x08;
+const SD_LVI: usize = 0x0C;
+const SD_FMT: usize = 0x12;
+const SD_BDPL: usize = 0x18;
+const SD_BDPU: usize = 0x1C;
+
+const SD_CTL_SRST: u8 = 1;
+const SD_CTL_RUN: u8 = 1 << 1;
+
+/// 48kHz, 16 bits per sample, 2 channels
+const STREAM_FORMAT: u16 = 0x0011;
+const SAMPLE_RATE: usize = 48_000;
+const CHANNELS: usize = 2;
+/// Stream tag used for the test tone, `0` is reserved.
+const STREAM_TAG: u8 = 1;
+
+const TONE_FREQ: usize = 440;
+const TONE_MS: u64 = 500;
+const TONE_AMPLITUDE: i16 = 0x1000;
+
+/// Number of milliseconds to wait for the controller to change state
+const WAIT_MS: u64 = 100;
+/// Number of polls to wait for a codec to respond to a command
+const CMD_TIMEOUT: usize = 100_000;
+
+/// Buffer descriptor list entry
+#[repr(C)]
+struct BdlEntry {
+    addr: u64,
+    len: u32,
+    /// Bit 0 requests an interrupt on completion
+    flags: u32,
+}
+
+pub(crate) struct Controller {
+    regs: &'static mut [u8],
+    // kept so the device is not released while the driver is running
+    _pci_dev: alloc::sync::Arc<spin::Mutex<hootux::system::pci::DeviceControl>>,
+}
+
+impl Controller {
+    pub(crate) fn new(regs: &'static mut [u8], pci_dev: alloc::sync::Arc<spin::Mutex<hootux::system::pci::DeviceControl>>) -> Self {
+        Self { regs, _pci_dev: pci_dev }
+    }
+
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + size_of::<T>() <= self.regs.len());
+        // SAFETY: The offset is within the register region, registers are naturally aligned
+        unsafe { core::ptr::read_volatile(self.regs.as_ptr().add(offset) as *const T) }
+    }
+
+    fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        assert!(offset + size_of::<T>() <= self.regs.len());
+        // SAFETY: See Self::read
+        unsafe { core::ptr::write_volatile(self.regs.as_mut_ptr().add(offset) as *mut T, value) }
+    }
+
+    /// Polls `f` every millisecond until it returns `true`
+    async fn wait<F: Fn(&Self) -> bool>(&self, f: F) -> Result<(), HdaError> {
+        for _ in 0..WAIT_MS {
+            if f(self) {
+                return Ok(());
+            }
+            sleep(1).await;
+        }
+        Err(HdaError::Timeout)
+    }
+
+    fn dma_alloc(&self) -> DmaAlloc {
+        if self.read::<u16>(GCAP) & GCAP_64OK != 0 {
+            DmaAlloc::new(hootux::mem::MemRegion::Mem64, 128)
+        } else {
+            DmaAlloc::new(hootux::mem::MemRegion::Mem32, 128)
+        }
+    }
+
+    async fn reset(&mut self) -> Result<(), HdaError> {
+        let gctl: u32 = self.read(GCTL);
+        self.write(GCTL, gctl & !GCTL_CRST);
+        self.wait(|s| s.read::<u32>(GCTL) & GCTL_CRST == 0).await?;
+        self.write(GCTL, gctl | GCTL_CRST);
+        self.wait(|s| s.read::<u32>(GCTL) & GCTL_CRST != 0).await?;
+
+        // Codecs must request a state change within 521µs of reset.
+        sleep(1).await;
+        Ok(())
+    }
+
+    /// Sends `verb` using the immediate command interface and returns the response.
+    pub(crate) fn command(&mut self, verb: Verb) -> Result<u32, HdaError> {
+        let poll = |s: &Self, f: fn(u16) -> bool| (0..CMD_TIMEOUT).any(|_| f(s.read::<u16>(ICIS)));
+
+        if !poll(self, |i| i & ICIS_ICB == 0) {
+            return Err(HdaError::Timeout);
+        }
+        self.write(ICOI, verb.raw());
+        // IRV is cleared by writing 1
+        self.write(ICIS, ICIS_IRV | ICIS_ICB);
+        if !poll(self, |i| i & ICIS_IRV != 0) {
+            return Err(HdaError::NoResponse);
+        }
+        let response = self.read(ICII);
+        self.write(ICIS, ICIS_IRV);
+        Ok(response)
+    }
+
+    pub(crate) async fn run(mut self) -> hootux::task::TaskResult {
+        if let Err(e) = self.reset().await {
+            log::error!("{}: Controller reset failed: {e:?}", crate::CRATE_NAME);
+            return hootux::task::TaskResult::Error;
+        }
+
+        let present: u16 = self.read(STATESTS);
+        let mut codecs = Vec::new();
+        for addr in (0..15).filter(|a| present & (1 << a) != 0) {
+            match Codec::probe(&mut self, addr) {
+                Ok(c) => {
+                    log::info!("{}: Found codec {c}", crate::CRATE_NAME);
+                    codecs.push(c);
+                }
+                Err(e) => log::warn!("{}: Failed to probe codec {addr}: {e:?}", crate::CRATE_NAME),
+            }
+        }
+
+        let Some(codec) = codecs.iter().find(|c| c.output_path().is_some()) else {
+            log::info!("{}: No output found, unable to play test tone", crate::CRATE_NAME);
+            return hootux::task::TaskResult::ExitedNormally;
+        };
+        match self.test_tone(codec).await {
+            Ok(()) => hootux::task::TaskResult::ExitedNormally,
+            Err(e) => {
+                log::error!("{}: Failed to play test tone: {e:?}", crate::CRATE_NAME);
+                hootux::task::TaskResult::Error
+            }
+        }
+    }
+
+    /// Plays a short square wave through the first output stream.
+    async fn test_tone(&mut self, codec: &Codec) -> Result<(), HdaError> {
+        let alloc = self.dma_alloc();
+        let frames = SAMPLE_RATE * TONE_MS as usize / 1000;
+        let mut samples: Vec<u8, DmaAlloc> = Vec::try_with_capacity_in(frames * CHANNELS * 2, alloc).map_err(|_| HdaError::OutOfMemory)?;
+        for i in 0..frames {
+            let v = if (i * 2 * TONE_FREQ / SAMPLE_RATE) % 2 == 0 { TONE_AMPLITUDE } else { -TONE_AMPLITUDE };
+            for _ in 0..CHANNELS {
+                samples.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        let samples = samples.into_boxed_slice();
+        let samples_addr = hootux::mem::mem_map::translate(samples.as_ptr() as usize).ok_or(HdaError::OutOfMemory)?;
+
+        // The BDL must contain at least 2 entries
+        let half = (samples.len() / 2) as u32;
+        let bdl = [
+            BdlEntry { addr: samples_addr, len: half, flags: 0 },
+            BdlEntry { addr: samples_addr + half as u64, len: samples.len() as u32 - half, flags: 0 },
+        ];
+        let bdl = Box::try_new_in(bdl, self.dma_alloc()).map_err(|_| HdaError::OutOfMemory)?;
+        let bdl_addr = hootux::mem::mem_map::translate(&*bdl as *const _ as usize).ok_or(HdaError::OutOfMemory)?;
+
+        // Output streams follow the input streams
+        let iss = (self.read::<u16>(GCAP) >> 8) & 0xf;
+        let sd = SD_BASE + SD_LEN * iss as usize;
+
+        self.write(sd + SD_CTL, SD_CTL_SRST);
+        self.wait(|s| s.read::<u8>(sd + SD_CTL) & SD_CTL_SRST != 0).await?;
+        self.write(sd + SD_CTL, 0u8);
+        self.wait(|s| s.read::<u8>(sd + SD_CTL) & SD_CTL_SRST == 0).await?;
+
+        self.write(sd + SD_CBL, samples.len() as u32);
+        self.write(sd + SD_LVI, (bdl.len() - 1) as u16);
+        self.write(sd + SD_FMT, STREAM_FORMAT);
+        self.write(sd + SD_BDPL, bdl_addr as u32);
+        self.write(sd + SD_BDPU, (bdl_addr >> 32) as u32);
+        self.write(sd + SD_CTL_STREAM, STREAM_TAG << 4);
+
+        codec.configure_output(self, STREAM_TAG, STREAM_FORMAT)?;
+
+        self.write(sd + SD_CTL, SD_CTL_RUN);
+        sleep(TONE_MS).await;
+        self.write(sd + SD_CTL, 0u8);
+        self.wait(|s| s.read::<u8>(sd + SD_CTL) & SD_CTL_RUN == 0).await?;
+
+        // `samples` and `bdl` are dropped here after the stream has stopped.
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+use alloc::boxed::Box;
+use core::alloc::Allocator;
+use hootux::system::driver_if::{MatchState, ResourceId};
+
+// note: this is 4,3 (multimedia, HD audio) the programming interface is ignored
+const HDA_CLASS: u32 = 0x04030000;
+const HDA_CLASS_MASK: u32 = !0xffff;
+const HDA_BAR: u8 = 0;
+
+pub struct HdaPciProfile;
+impl hootux::system::driver_if::DriverProfile for HdaPciProfile {
+    fn try_start(&self, resource: Box<dyn ResourceId>) -> (MatchState, Option<Box<dyn ResourceId>>) {
+        if hootux::system::driver_if::WhoIsResource::whois(&*resource)
+            == core::any::TypeId::of::<hootux::system::pci::PciResourceContainer>()
+        {
+            let pci_dev: Box<hootux::system::pci::PciResourceContainer> = match resource.as_any().downcast() {
+                Ok(res) => res,
+                Err(_) => unreachable!(), // TypeId checked above, this branch is impossible
+            };
+
+            if pci_dev.class() & HDA_CLASS_MASK != HDA_CLASS {
+                return (MatchState::NoMatch, Some(pci_dev));
+            }
+
+            return match start(pci_dev.get_inner()) {
+                Ok(()) => (MatchState::Success, None),
+                Err(e) => {
+                    log::error!("Failed to start {} for {}: {e}; Poisoning device", crate::CRATE_NAME, pci_dev.addr());
+                    (MatchState::MatchRejected, None)
+                }
+            };
+        }
+        (MatchState::WrongBus, Some(resource))
+    }
+
+    fn bus_name(&self) -> &str {
+        "pci"
+    }
+}
+
+#[cold]
+fn start(pci_dev: alloc::sync::Arc<spin::Mutex<hootux::system::pci::DeviceControl>>) -> Result<(), &'static str> {
+    let mut lock = pci_dev.lock();
+    let b = lock.get_bar(HDA_BAR).ok_or("BAR 0 not implemented")?;
+
+    // SAFETY: The address is given by the PCI bar and is marked as reserved
+    let regs = unsafe {
+        &mut *hootux::alloc_interface::MmioAlloc::new(b.addr() as usize)
+            .allocate(b.layout())
+            .map_err(|_| "System ran out of memory")?
+            .as_ptr()
+    };
+
+    // SAFETY: The controller only accesses memory allocated by the driver
+    unsafe { lock.set_bus_master(true) };
+    log::trace!("Attempting to start {} for {}", crate::CRATE_NAME, lock.address());
+    drop(lock);
+
+    let ctl = crate::controller::Controller::new(regs, pci_dev);
+    hootux::task::run_task(Box::pin(ctl.run()));
+    Ok(())
+}
@@ -0,0 +1,34 @@
+//! Intel High Definition Audio controller driver.
+//!
+//! This driver is currently only capable of detecting codecs and playing a test tone on the first
+//! output it can locate. Codecs are accessed using the immediate command interface, CORB/RIRB
+//! and interrupts are not used.
+
+#![feature(allocator_api)]
+#![no_std]
+extern crate alloc;
+
+static CRATE_NAME: &str = env!("CARGO_CRATE_NAME");
+
+mod codec;
+mod controller;
+mod kernel_if;
+
+#[no_mangle]
+pub extern "C" fn init() {
+    hootux::system::sysfs::get_sysfs()
+        .get_discovery()
+        .register_driver(alloc::boxed::Box::new(kernel_if::HdaPciProfile))
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum HdaError {
+    /// The controller did not respond within the expected time.
+    Timeout,
+    /// A codec did not respond to a command.
+    NoResponse,
+    /// No output path could be located.
+    NoOutput,
+    /// Failed to allocate DMA memory
+    OutOfMemory,
+}
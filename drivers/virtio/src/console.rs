@@ -0,0 +1,466 @@
+//! Virtio console and virtio-serial, see virtio 1.2 section 5.3.
+//!
+//! Console ports are registered as `/dev/hvc{n}`. Other ports are registered using the name given
+//! by the host, or as `/dev/vport{n}` when the host does not name them. Under QEMU ports are added
+//! with `-device virtconsole,chardev=<id>` and `-device virtserialport,chardev=<id>,name=<name>`.
+//!
+//! Data is only received while the port has room for it, so the host is stalled instead of data
+//! being dropped when the guest does not read it. Writes complete once the host has consumed them.
+
+use crate::queue::{dma_buffer, Buffer, Virtqueue};
+use crate::transport::Transport;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeSet, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use futures_util::future::BoxFuture;
+use futures_util::task::AtomicWaker;
+use futures_util::FutureExt;
+use hootux::alloc_interface::DmaAlloc;
+use hootux::fs::devfs::naming::DeviceClass;
+use hootux::fs::device::{DeviceFile, Fifo, FileSystem, OpenMode};
+use hootux::fs::file::*;
+use hootux::fs::vfs::{DevID, MajorNum};
+use hootux::fs::{IoError, IoResult};
+use hootux::mem::dma::{DmaBuff, DmaTarget};
+use hootux::mem::PAGE_SIZE;
+use hootux::task::util::sleep;
+
+const F_MULTIPORT: u64 = 1 << 1;
+
+/// Offset of `max_nr_ports` within the device configuration.
+const CFG_MAX_PORTS: usize = 4;
+/// Maximum number of ports used for each device.
+const MAX_PORTS: u32 = 16;
+
+const CONTROL_RX_QUEUE: u16 = 2;
+const CONTROL_TX_QUEUE: u16 = 3;
+
+// Control events
+const DEVICE_READY: u16 = 0;
+const DEVICE_ADD: u16 = 1;
+const DEVICE_REMOVE: u16 = 2;
+const PORT_READY: u16 = 3;
+const CONSOLE_PORT: u16 = 4;
+const RESIZE: u16 = 5;
+const PORT_OPEN: u16 = 6;
+const PORT_NAME: u16 = 7;
+
+/// Size of a control message header.
+const CONTROL_LEN: usize = 8;
+/// Number of buffers given to the device for control messages. Messages are lost when the device
+/// has no buffer available.
+const CONTROL_BUFFERS: usize = 8;
+/// Number of unread bytes buffered for each port, no further data is received until it is read.
+const INPUT_LIMIT: usize = PAGE_SIZE * 4;
+
+static MAJOR: spin::Lazy<MajorNum> = spin::Lazy::new(|| MajorNum::register("virtio-console").unwrap());
+static MINOR: AtomicUsize = AtomicUsize::new(0);
+
+/// Port names given by hosts, names are leaked once so they can be used as [DeviceClass::Fixed].
+static NAMES: spin::Mutex<BTreeSet<&'static str>> = spin::Mutex::new(BTreeSet::new());
+
+fn intern(name: &str) -> &'static str {
+    let mut l = NAMES.lock();
+    if let Some(name) = l.get(name) {
+        return name;
+    }
+    let name = String::from(name).leak();
+    l.insert(name);
+    name
+}
+
+/// Returns the receive and transmit queue indices of `port`.
+fn port_queues(port: u32) -> (u16, u16) {
+    match port {
+        0 => (0, 1),
+        n => (2 * n as u16 + 2, 2 * n as u16 + 3),
+    }
+}
+
+pub(crate) fn start(transport: Transport) -> Result<(), &'static str> {
+    let features = transport.init(F_MULTIPORT)?;
+    let multiport = features & F_MULTIPORT != 0;
+    let count = match multiport {
+        true => {
+            let cfg = transport.config().ok_or("Device configuration not present")?;
+            let max = u32::from_le_bytes(cfg.get(CFG_MAX_PORTS..CFG_MAX_PORTS + 4).ok_or("Device configuration too short")?.try_into().unwrap());
+            max.clamp(1, MAX_PORTS)
+        }
+        false => 1,
+    };
+
+    let mut ports = Vec::new();
+    for id in 0..count {
+        let (rx, tx) = port_queues(id);
+        ports.push(Arc::new(Port::new(id, transport.setup_queue(rx)?, transport.setup_queue(tx)?)?));
+    }
+    let control = match multiport {
+        true => Some(Control {
+            rx: transport.setup_queue(CONTROL_RX_QUEUE)?,
+            tx: transport.setup_queue(CONTROL_TX_QUEUE)?,
+            tx_buff: async_lock::Mutex::new(dma_buffer(PAGE_SIZE).ok_or("System ran out of memory")?),
+        }),
+        false => None,
+    };
+    transport.driver_ok();
+
+    let dev = Arc::new(Console { _transport: transport, ports, control });
+    hootux::task::run_task(Box::pin(dev.run()));
+    Ok(())
+}
+
+struct Control {
+    rx: Virtqueue,
+    tx: Virtqueue,
+    tx_buff: async_lock::Mutex<Vec<u8, DmaAlloc>>,
+}
+
+struct Console {
+    // kept so the device is not released while the driver is running
+    _transport: Transport,
+    ports: Vec<Arc<Port>>,
+    /// Control queues, only present when the device supports multiple ports.
+    control: Option<Control>,
+}
+
+/// State of a port which is only accessed by [Console::run].
+#[derive(Default)]
+struct PortState {
+    /// Outstanding receive buffer
+    rx_head: Option<u16>,
+    present: bool,
+    console: bool,
+    registered: bool,
+    /// Whether the host has been told the port is open.
+    open_sent: bool,
+}
+
+impl Console {
+    async fn send_control(&self, id: u32, event: u16, value: u16) {
+        let Some(control) = &self.control else { return };
+        let mut b = control.tx_buff.lock().await;
+        b[..4].copy_from_slice(&id.to_le_bytes());
+        b[4..6].copy_from_slice(&event.to_le_bytes());
+        b[6..8].copy_from_slice(&value.to_le_bytes());
+        control.tx.transfer(&[Buffer::new(&b[..CONTROL_LEN], false)]).await;
+    }
+
+    /// Registers `port` with the devfs as `class`, replacing its previous node.
+    async fn register(&self, port: &Arc<Port>, state: &mut PortState, class: DeviceClass) {
+        if state.registered {
+            let _ = hootux::fs::devfs::unregister(port.dev).await;
+            state.registered = false;
+        }
+        port.input.lock().clear();
+        match hootux::fs::devfs::register(Box::new(PortFile::new(port.clone())), class).await {
+            Ok(name) => {
+                log::info!("{}: Port {} registered as {name}", crate::CRATE_NAME, port.id);
+                state.registered = true;
+            }
+            Err(e) => log::error!("{}: Failed to register port {}: {e:?}", crate::CRATE_NAME, port.id),
+        }
+    }
+
+    async fn unregister(&self, port: &Port, state: &mut PortState) {
+        if state.registered {
+            let _ = hootux::fs::devfs::unregister(port.dev).await;
+            state.registered = false;
+        }
+    }
+
+    async fn control_message(&self, msg: &[u8], states: &mut [PortState]) {
+        let Some(header) = msg.get(..CONTROL_LEN) else { return };
+        let id = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let event = u16::from_le_bytes(header[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(header[6..8].try_into().unwrap());
+        let (Some(port), Some(state)) = (self.ports.get(id as usize), states.get_mut(id as usize)) else {
+            log::warn!("{}: Control message {event} for unknown port {id}", crate::CRATE_NAME);
+            return;
+        };
+
+        match event {
+            DEVICE_ADD => {
+                state.present = true;
+                self.register(port, state, DeviceClass::Channel).await;
+                self.send_control(id, PORT_READY, 1).await;
+            }
+            DEVICE_REMOVE => {
+                self.unregister(port, state).await;
+                *state = PortState { rx_head: state.rx_head, ..Default::default() };
+                port.host_connected.store(false, Ordering::Relaxed);
+            }
+            CONSOLE_PORT => {
+                state.console = true;
+                self.register(port, state, DeviceClass::Hypervisor).await;
+            }
+            PORT_NAME => match core::str::from_utf8(&msg[CONTROL_LEN..]).map(|s| s.trim_end_matches('\0')) {
+                Ok(name) if !name.is_empty() && !name.contains(hootux::fs::PATH_SEPARATOR) && !state.console => {
+                    self.register(port, state, DeviceClass::Fixed(intern(name))).await;
+                }
+                _ => log::warn!("{}: Invalid name for port {id}", crate::CRATE_NAME),
+            },
+            PORT_OPEN => port.host_connected.store(value != 0, Ordering::Relaxed),
+            RESIZE => {}
+            _ => log::debug!("{}: Unknown control event {event} for port {id}", crate::CRATE_NAME),
+        }
+    }
+
+    async fn run(self: Arc<Self>) -> hootux::task::TaskResult {
+        let buffers = |n: usize| (0..n).map(|_| dma_buffer(PAGE_SIZE)).collect::<Option<Vec<_>>>();
+        let (Some(rx_buffs), Some(control_buffs)) = (buffers(self.ports.len()), buffers(CONTROL_BUFFERS)) else {
+            log::error!("{}: Failed to allocate receive buffers", crate::CRATE_NAME);
+            return hootux::task::TaskResult::Error;
+        };
+        let mut states: Vec<PortState> = self.ports.iter().map(|_| PortState::default()).collect();
+        let mut control_heads = [None; CONTROL_BUFFERS];
+
+        match &self.control {
+            Some(control) => {
+                for (head, buff) in control_heads.iter_mut().zip(&control_buffs) {
+                    *head = control.rx.submit(&[Buffer::new(buff, true)]);
+                }
+                self.send_control(0, DEVICE_READY, 1).await;
+            }
+            None => {
+                // Without multiport support the only port is a console
+                states[0] = PortState { present: true, console: true, ..Default::default() };
+                self.register(&self.ports[0], &mut states[0], DeviceClass::Hypervisor).await;
+            }
+        }
+
+        loop {
+            if let Some(control) = &self.control {
+                for (head, buff) in control_heads.iter_mut().zip(&control_buffs) {
+                    if let Some(len) = head.and_then(|h| control.rx.poll(h)) {
+                        let msg: Vec<u8> = buff[..(len as usize).min(buff.len())].to_vec();
+                        *head = None;
+                        self.control_message(&msg, &mut states).await;
+                    }
+                    if head.is_none() {
+                        *head = control.rx.submit(&[Buffer::new(buff, true)]);
+                    }
+                }
+            }
+
+            for ((port, state), buff) in self.ports.iter().zip(states.iter_mut()).zip(&rx_buffs) {
+                if let Some(len) = state.rx_head.and_then(|h| port.rx.poll(h)) {
+                    state.rx_head = None;
+                    port.input.lock().extend(&buff[..(len as usize).min(buff.len())]);
+                    port.waker.wake();
+                }
+                if state.rx_head.is_none() && port.input.lock().len() + buff.len() <= INPUT_LIMIT {
+                    state.rx_head = port.rx.submit(&[Buffer::new(buff, true)]);
+                }
+
+                // Consoles are always open, the host is only told that the port is open while
+                // the port is present.
+                let open = state.present && (state.console || port.opened.load(Ordering::Relaxed) > 0);
+                if self.control.is_some() && open != state.open_sent {
+                    self.send_control(port.id, PORT_OPEN, open as u16).await;
+                    state.open_sent = open;
+                }
+            }
+            sleep(1).await;
+        }
+    }
+}
+
+pub(crate) struct Port {
+    id: u32,
+    dev: DevID,
+    rx: Virtqueue,
+    tx: Virtqueue,
+    tx_buff: async_lock::Mutex<Vec<u8, DmaAlloc>>,
+    input: spin::Mutex<VecDeque<u8>>,
+    waker: AtomicWaker,
+    /// Only one reader is allowed for each port.
+    reader: AtomicBool,
+    /// Number of open file objects.
+    opened: AtomicUsize,
+    host_connected: AtomicBool,
+}
+
+impl Port {
+    fn new(id: u32, rx: Virtqueue, tx: Virtqueue) -> Result<Self, &'static str> {
+        Ok(Self {
+            id,
+            dev: DevID::new(*MAJOR, MINOR.fetch_add(1, Ordering::Relaxed)),
+            rx,
+            tx,
+            tx_buff: async_lock::Mutex::new(dma_buffer(PAGE_SIZE).ok_or("System ran out of memory")?),
+            input: spin::Mutex::new(VecDeque::new()),
+            waker: AtomicWaker::new(),
+            reader: AtomicBool::new(false),
+            opened: AtomicUsize::new(0),
+            host_connected: AtomicBool::new(false),
+        })
+    }
+
+    /// Sends `data` to the host, completes once the host has consumed it.
+    async fn send(&self, data: &[u8]) {
+        let mut b = self.tx_buff.lock().await;
+        for chunk in data.chunks(b.len()) {
+            b[..chunk.len()].copy_from_slice(chunk);
+            self.tx.transfer(&[Buffer::new(&b[..chunk.len()], false)]).await;
+        }
+    }
+}
+
+/// File object for a port.
+///
+/// Reads will wait until at least one byte is available. Data written while the host is not
+/// connected is discarded by the host.
+pub struct PortFile {
+    port: Arc<Port>,
+    fifo_lock: OpenMode,
+}
+
+impl PortFile {
+    fn new(port: Arc<Port>) -> Self {
+        Self { port, fifo_lock: OpenMode::Locked }
+    }
+
+    /// Returns whether the host side of the port is connected.
+    pub fn host_connected(&self) -> bool {
+        self.port.host_connected.load(Ordering::Relaxed)
+    }
+}
+
+#[cast_trait_object::dyn_upcast]
+#[cast_trait_object::dyn_cast(NormalFile<u8>, Directory, FileSystem, Fifo<u8>, DeviceFile)]
+impl File for PortFile {
+    fn file_type(&self) -> FileType {
+        FileType::CharDev
+    }
+
+    fn block_size(&self) -> u64 {
+        1
+    }
+
+    fn device(&self) -> DevID {
+        self.port.dev
+    }
+
+    fn clone_file(&self) -> Box<dyn File> {
+        Box::new(Self::new(self.port.clone()))
+    }
+
+    fn id(&self) -> u64 {
+        0
+    }
+
+    fn len(&self) -> IoResult<u64> {
+        async { Ok(self.port.input.lock().len() as u64) }.boxed()
+    }
+}
+
+impl Drop for PortFile {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+impl DeviceFile for PortFile {}
+
+impl Fifo<u8> for PortFile {
+    fn open(&mut self, mode: OpenMode) -> Result<(), IoError> {
+        if self.fifo_lock != OpenMode::Locked {
+            return Err(IoError::DeviceError);
+        }
+        if mode.is_read() {
+            if let Err(_) = self.port.reader.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed) {
+                return Err(IoError::Busy);
+            }
+        }
+        self.port.opened.fetch_add(1, Ordering::Relaxed);
+        self.fifo_lock = mode;
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), IoError> {
+        if self.fifo_lock == OpenMode::Locked {
+            return Err(IoError::NotReady);
+        }
+        if self.fifo_lock.is_read() {
+            self.port.reader.store(false, Ordering::Release);
+        }
+        self.port.opened.fetch_sub(1, Ordering::Relaxed);
+        self.fifo_lock = OpenMode::Locked;
+        Ok(())
+    }
+
+    fn locks_remain(&self, mode: OpenMode) -> usize {
+        if mode.is_read() {
+            (!self.port.reader.load(Ordering::Relaxed)) as usize
+        } else {
+            usize::MAX
+        }
+    }
+
+    fn is_master(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl Read<u8> for PortFile {
+    fn read<'f, 'a: 'f, 'b: 'f>(&'a self, _: u64, mut dbuff: DmaBuff<'b>) -> BoxFuture<'f, Result<(DmaBuff<'b>, usize), (IoError, DmaBuff<'b>, usize)>> {
+        async {
+            if !self.fifo_lock.is_read() {
+                return Err((IoError::NotReady, dbuff, 0));
+            }
+            InputFut { port: &self.port }.await;
+
+            // SAFETY: as_mut guarantees that this is safe.
+            let buff = unsafe { &mut *DmaTarget::as_mut(&mut *dbuff) };
+            let mut l = self.port.input.lock();
+            let count = l.len().min(buff.len());
+            for (i, b) in l.drain(..count).enumerate() {
+                buff[i] = b;
+            }
+            drop(l);
+            Ok((dbuff, count))
+        }
+        .boxed()
+    }
+}
+
+impl Write<u8> for PortFile {
+    fn write<'f, 'a: 'f, 'b: 'f>(&'a self, _: u64, mut dbuff: DmaBuff<'b>) -> BoxFuture<'f, Result<(DmaBuff<'b>, usize), (IoError, DmaBuff<'b>, usize)>> {
+        async {
+            if !self.fifo_lock.is_write() {
+                return Err((IoError::NotReady, dbuff, 0));
+            }
+            // SAFETY: as_mut guarantees that this is safe.
+            let buff = unsafe { &*DmaTarget::as_mut(&mut *dbuff) };
+            self.port.send(buff).await;
+            let len = buff.len();
+            Ok((dbuff, len))
+        }
+        .boxed()
+    }
+}
+
+/// Completes when input is available.
+struct InputFut<'a> {
+    port: &'a Port,
+}
+
+impl core::future::Future for InputFut<'_> {
+    type Output = ();
+
+    fn poll(self: core::pin::Pin<&mut Self>, cx: &mut core::task::Context<'_>) -> core::task::Poll<Self::Output> {
+        let ready = || !self.port.input.lock().is_empty();
+        if ready() {
+            return core::task::Poll::Ready(());
+        }
+        self.port.waker.register(cx.waker());
+        if ready() {
+            core::task::Poll::Ready(())
+        } else {
+            core::task::Poll::Pending
+        }
+    }
+}
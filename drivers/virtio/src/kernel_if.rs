@@ -0,0 +1,66 @@
+use crate::DeviceType;
+use alloc::boxed::Box;
+use hootux::system::driver_if::{MatchState, ResourceId};
+
+const VIRTIO_VENDOR: u16 = 0x1af4;
+/// Modern devices use `0x1040 + device type`.
+const MODERN_BASE: u16 = 0x1040;
+const MODERN_END: u16 = 0x107f;
+
+/// Returns the device type of a transitional device, these use the legacy device IDs.
+fn transitional_type(device: u16) -> Option<u16> {
+    match device {
+        0x1003 => Some(3),
+        0x1009 => Some(9),
+        _ => None,
+    }
+}
+
+pub struct VirtioPciProfile;
+impl hootux::system::driver_if::DriverProfile for VirtioPciProfile {
+    fn try_start(&self, resource: Box<dyn ResourceId>) -> (MatchState, Option<Box<dyn ResourceId>>) {
+        if hootux::system::driver_if::WhoIsResource::whois(&*resource)
+            == core::any::TypeId::of::<hootux::system::pci::PciResourceContainer>()
+        {
+            let pci_dev: Box<hootux::system::pci::PciResourceContainer> = match resource.as_any().downcast() {
+                Ok(res) => res,
+                Err(_) => unreachable!(), // TypeId checked above, this branch is impossible
+            };
+
+            let (vendor, device) = pci_dev.dev_id();
+            if vendor != VIRTIO_VENDOR {
+                return (MatchState::NoMatch, Some(pci_dev));
+            }
+            let id = match device {
+                MODERN_BASE..=MODERN_END => Some(device - MODERN_BASE),
+                _ => transitional_type(device),
+            };
+            let Some(ty) = id.and_then(DeviceType::from_id) else {
+                return (MatchState::NoMatch, Some(pci_dev));
+            };
+
+            return match start(ty, pci_dev.get_inner()) {
+                Ok(()) => (MatchState::Success, None),
+                Err(e) => {
+                    log::error!("Failed to start {} for {}: {e}; Poisoning device", crate::CRATE_NAME, pci_dev.addr());
+                    (MatchState::MatchRejected, None)
+                }
+            };
+        }
+        (MatchState::WrongBus, Some(resource))
+    }
+
+    fn bus_name(&self) -> &str {
+        "pci"
+    }
+}
+
+#[cold]
+fn start(ty: DeviceType, pci_dev: alloc::sync::Arc<spin::Mutex<hootux::system::pci::DeviceControl>>) -> Result<(), &'static str> {
+    log::trace!("Attempting to start {} {ty:?} for {}", crate::CRATE_NAME, pci_dev.lock().address());
+    let transport = crate::transport::Transport::new(pci_dev)?;
+    match ty {
+        DeviceType::Console => crate::console::start(transport),
+        DeviceType::P9 => crate::p9::start(transport),
+    }
+}
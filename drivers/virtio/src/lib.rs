@@ -0,0 +1,44 @@
+//! Virtio device drivers.
+//!
+//! Devices are accessed using the virtio 1.x PCI transport, legacy-only devices are not supported.
+//! Queue completion is polled, device interrupts are not used.
+//!
+//! Supported devices are
+//! - Console: Console ports are registered as `/dev/hvc{n}`, other ports as `/dev/<name>`.
+//! - 9P transport (virtfs): The exported directory is mounted at `/mnt/<tag>`.
+
+#![feature(allocator_api)]
+#![no_std]
+extern crate alloc;
+
+static CRATE_NAME: &str = env!("CARGO_CRATE_NAME");
+
+mod console;
+mod kernel_if;
+mod p9;
+mod queue;
+mod transport;
+
+#[no_mangle]
+pub extern "C" fn init() {
+    hootux::system::sysfs::get_sysfs()
+        .get_discovery()
+        .register_driver(alloc::boxed::Box::new(kernel_if::VirtioPciProfile))
+}
+
+/// Virtio device type, see virtio 1.2 section 5.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum DeviceType {
+    Console,
+    P9,
+}
+
+impl DeviceType {
+    fn from_id(id: u16) -> Option<Self> {
+        match id {
+            3 => Some(Self::Console),
+            9 => Some(Self::P9),
+            _ => None,
+        }
+    }
+}
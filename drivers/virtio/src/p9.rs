@@ -0,0 +1,86 @@
+//! Virtio 9P transport (virtfs).
+//!
+//! Shares a directory from the host using the 9P2000.L protocol. Under QEMU a directory is
+//! exported with `-virtfs local,path=<dir>,mount_tag=<tag>,security_model=none`, the export is
+//! mounted at `/mnt/<tag>` once the device is started.
+
+use crate::transport::Transport;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+
+mod fs;
+mod proto;
+
+/// The device configuration contains the mount tag.
+const F_MOUNT_TAG: u64 = 1;
+const REQUEST_QUEUE: u16 = 0;
+/// Directory which exports are mounted into.
+const MOUNT_DIR: &str = "/mnt";
+/// User name given when attaching, `security_model=none` performs all accesses as the QEMU user.
+const UNAME: &str = "root";
+
+static COUNT: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+/// Reads the mount tag from the device configuration.
+fn mount_tag(transport: &Transport) -> Option<String> {
+    let cfg = transport.config()?;
+    let len = u16::from_le_bytes(cfg.get(..2)?.try_into().unwrap()) as usize;
+    let tag = core::str::from_utf8(cfg.get(2..2 + len)?).ok()?;
+    // The tag is used as a file name
+    if tag.is_empty() || tag.contains(hootux::fs::PATH_SEPARATOR) || tag == "." || tag == ".." {
+        return None;
+    }
+    Some(String::from(tag))
+}
+
+pub(crate) fn start(transport: Transport) -> Result<(), &'static str> {
+    let features = transport.init(F_MOUNT_TAG)?;
+    let queue = transport.setup_queue(REQUEST_QUEUE)?;
+    let client = proto::Client::new(queue).ok_or("System ran out of memory")?;
+    transport.driver_ok();
+
+    let n = COUNT.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    let tag = match mount_tag(&transport).filter(|_| features & F_MOUNT_TAG != 0) {
+        Some(tag) => tag,
+        None => {
+            log::warn!("{}: No valid mount tag, using 9p{n}", crate::CRATE_NAME);
+            format!("9p{n}")
+        }
+    };
+
+    hootux::task::run_task(Box::pin(mount(transport, Arc::new(client), tag)));
+    Ok(())
+}
+
+async fn mount(transport: Transport, client: Arc<proto::Client>, tag: String) -> hootux::task::TaskResult {
+    if let Err(e) = client.version().await {
+        log::error!("{}: Failed to negotiate 9p version for {tag}: {e:?}", crate::CRATE_NAME);
+        transport.fail();
+        return hootux::task::TaskResult::Error;
+    }
+    let (fid, qid) = match client.attach(UNAME, "").await {
+        Ok(root) => root,
+        Err(e) => {
+            log::error!("{}: Failed to attach to {tag}: {e:?}", crate::CRATE_NAME);
+            return hootux::task::TaskResult::Error;
+        }
+    };
+
+    let vfs = hootux::fs::get_vfs();
+    // Fails if the directory already exists
+    let _ = vfs.mkdir(MOUNT_DIR).await;
+    let path = format!("{MOUNT_DIR}/{tag}");
+    let fs = fs::P9Fs::new(transport, client, fid, qid, tag);
+    match vfs.mount(Box::new(fs), &path, hootux::fs::vfs::MountFlags::empty(), "").await {
+        Ok(()) => {
+            log::info!("{}: Mounted host directory at {path}", crate::CRATE_NAME);
+            hootux::task::TaskResult::ExitedNormally
+        }
+        Err(e) => {
+            log::error!("{}: Failed to mount {path}: {e:?}", crate::CRATE_NAME);
+            hootux::task::TaskResult::Error
+        }
+    }
+}
@@ -0,0 +1,513 @@
+//! VFS interface for a 9P export.
+//!
+//! Each file object holds a fid walked to the file. IO is performed through a second fid which is
+//! opened on first use, both are clunked when the last file object referring to them is dropped.
+//! The server may be modified by the host at any time so nothing is cached.
+
+use super::proto::{self, Client, Qid, SetAttr};
+use crate::transport::Transport;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use hootux::fs::device::{DeviceFile, Fifo, FileSystem};
+use hootux::fs::file::*;
+use hootux::fs::statfs::FsStat;
+use hootux::fs::vfs::{DevID, MajorNum, MountFlags};
+use hootux::fs::{IoError, IoResult, PARENT_DIR, THIS_DIR};
+use hootux::mem::dma::{DmaBuff, DmaTarget};
+
+static MAJOR: spin::Lazy<MajorNum> = spin::Lazy::new(|| MajorNum::register("9p").unwrap());
+static MINOR: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+const NEW_FILE_MODE: u32 = 0o644;
+const NEW_DIR_MODE: u32 = 0o755;
+
+struct FsInner {
+    client: Arc<Client>,
+    dev: DevID,
+    opts: spin::RwLock<FsOpts>,
+    root: Arc<Node>,
+    tag: String,
+    // kept so the device is not released while the filesystem is in use
+    _transport: Transport,
+}
+
+impl FsInner {
+    /// Constructs a file object for `node`.
+    fn file(self: &Arc<Self>, node: Node) -> Box<dyn File> {
+        let node = Arc::new(node);
+        if node.qid.is_dir() {
+            Box::new(P9Dir { fs: self.clone(), node })
+        } else {
+            Box::new(P9File { fs: self.clone(), node })
+        }
+    }
+
+    async fn metadata(&self, node: &Node, file_type: FileType) -> Result<FileMetadata, IoError> {
+        let attr = self.client.getattr(node.fid).await?;
+        Ok(FileMetadata::new(attr.size, attr.block_size.max(1), attr.qid.path, self.dev, file_type)
+            .with_owner(FileMode::from_bits_truncate(attr.mode as u16), attr.uid, attr.gid)
+            .with_times(attr.atime, attr.mtime, attr.ctime)
+            .with_nlink(attr.nlink))
+    }
+
+    fn set_mode<'f>(&'f self, node: &'f Node, mode: FileMode) -> IoResult<'f, ()> {
+        let attr = SetAttr { valid: proto::SETATTR_MODE, mode: mode.bits() as u32, ..Default::default() };
+        async move { self.client.setattr(node.fid, &attr).await }.boxed()
+    }
+
+    fn set_owner<'f>(&'f self, node: &'f Node, uid: u32, gid: u32) -> IoResult<'f, ()> {
+        let attr = SetAttr { valid: proto::SETATTR_UID | proto::SETATTR_GID, uid, gid, ..Default::default() };
+        async move { self.client.setattr(node.fid, &attr).await }.boxed()
+    }
+
+    fn set_times<'f>(&'f self, node: &'f Node, atime: Option<u64>, mtime: Option<u64>) -> IoResult<'f, ()> {
+        let mut attr = SetAttr::default();
+        if let Some(atime) = atime {
+            attr.valid |= proto::SETATTR_ATIME | proto::SETATTR_ATIME_SET;
+            attr.atime = atime;
+        }
+        if let Some(mtime) = mtime {
+            attr.valid |= proto::SETATTR_MTIME | proto::SETATTR_MTIME_SET;
+            attr.mtime = mtime;
+        }
+        async move { self.client.setattr(node.fid, &attr).await }.boxed()
+    }
+}
+
+/// A file on the server.
+struct Node {
+    client: Arc<Client>,
+    fid: u32,
+    qid: Qid,
+    /// Fid opened for IO and whether it is writable.
+    io: async_lock::Mutex<Option<(u32, bool)>>,
+}
+
+impl Node {
+    fn new(client: Arc<Client>, fid: u32, qid: Qid) -> Self {
+        Self { client, fid, qid, io: async_lock::Mutex::new(None) }
+    }
+
+    /// Returns the fid opened for IO and whether it is writable. The file is opened for reading
+    /// and writing if permitted, otherwise it is opened read only.
+    async fn io_fid(&self) -> Result<(u32, bool), IoError> {
+        let mut io = self.io.lock().await;
+        if let Some(open) = *io {
+            return Ok(open);
+        }
+        let (fid, _) = self.client.walk(self.fid, &[]).await?;
+        let writable = match self.client.lopen(fid, proto::O_RDWR).await {
+            Ok(_) => true,
+            Err(IoError::PermissionDenied | IoError::ReadOnly) => match self.client.lopen(fid, proto::O_RDONLY).await {
+                Ok(_) => false,
+                Err(e) => {
+                    let _ = self.client.clunk(fid).await;
+                    return Err(e);
+                }
+            },
+            Err(e) => {
+                let _ = self.client.clunk(fid).await;
+                return Err(e);
+            }
+        };
+        *io = Some((fid, writable));
+        Ok((fid, writable))
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let client = self.client.clone();
+        let fids = [Some(self.fid), self.io.get_mut().take().map(|(fid, _)| fid)];
+        hootux::task::run_task(Box::pin(async move {
+            for fid in fids.into_iter().flatten() {
+                let _ = client.clunk(fid).await;
+            }
+            hootux::task::TaskResult::ExitedNormally
+        }));
+    }
+}
+
+#[derive(Clone)]
+#[cast_trait_object::dyn_cast(File => NormalFile<u8>, Directory, FileSystem, Fifo<u8>, DeviceFile)]
+#[cast_trait_object::dyn_upcast(File)]
+pub(super) struct P9Fs {
+    inner: Arc<FsInner>,
+}
+
+impl P9Fs {
+    /// Constructs a filesystem for the export attached to `root_fid`.
+    pub(super) fn new(transport: Transport, client: Arc<Client>, root_fid: u32, root_qid: Qid, tag: String) -> Self {
+        Self {
+            inner: Arc::new(FsInner {
+                root: Arc::new(Node::new(client.clone(), root_fid, root_qid)),
+                client,
+                dev: DevID::new(*MAJOR, MINOR.fetch_add(1, core::sync::atomic::Ordering::Relaxed)),
+                opts: spin::RwLock::new(FsOpts::new(false, false)),
+                tag,
+                _transport: transport,
+            }),
+        }
+    }
+
+    fn root_dir(&self) -> P9Dir {
+        P9Dir { fs: self.inner.clone(), node: self.inner.root.clone() }
+    }
+}
+
+impl File for P9Fs {
+    fn file_type(&self) -> FileType {
+        FileType::Directory
+    }
+
+    fn block_size(&self) -> u64 {
+        hootux::mem::PAGE_SIZE as u64
+    }
+
+    fn device(&self) -> DevID {
+        self.inner.dev
+    }
+
+    fn clone_file(&self) -> Box<dyn File> {
+        Box::new(self.clone())
+    }
+
+    fn id(&self) -> u64 {
+        self.inner.root.qid.path
+    }
+
+    fn len(&self) -> IoResult<u64> {
+        async { self.root_dir().len().await }.boxed()
+    }
+}
+
+impl DeviceFile for P9Fs {}
+
+impl FileSystem for P9Fs {
+    fn root(&self) -> Box<dyn Directory> {
+        Box::new(self.root_dir())
+    }
+
+    fn get_opt(&self, option: &str) -> Option<FsOptionVariant> {
+        self.inner.opts.read().get(option)
+    }
+
+    fn set_opts(&mut self, options: &str) {
+        // The directory cache is never enabled, the host may modify the export at any time
+        let mut new_opts = FsOpts::new(false, false);
+        for i in options.split_whitespace() {
+            match i {
+                "DEV" => {
+                    new_opts.set(FsOpts::DEV_ALLOWED.to_string(), FsOpts::TRUE.to_string());
+                }
+                "NOCACHE" => {}
+                e => log::warn!(r#"Unknown option "{e}" will be ignored"#),
+            }
+        }
+        *self.inner.opts.write() = new_opts;
+    }
+
+    fn driver_name(&self) -> &'static str {
+        "9p"
+    }
+
+    fn raw_file(&self) -> Option<&str> {
+        Some(self.inner.tag.as_str())
+    }
+
+    fn statfs(&self) -> IoResult<FsStat> {
+        async {
+            let s = self.inner.client.statfs(self.inner.root.fid).await?;
+            Ok(FsStat {
+                block_size: s.block_size.max(1) as u64,
+                blocks: s.blocks,
+                blocks_free: s.blocks_free,
+                blocks_avail: s.blocks_avail,
+                inodes: s.files,
+                inodes_free: s.files_free,
+                flags: MountFlags::empty(),
+            })
+        }
+        .boxed()
+    }
+}
+
+#[derive(Clone)]
+#[cast_trait_object::dyn_cast(File => NormalFile<u8>, Directory, FileSystem, Fifo<u8>, DeviceFile)]
+#[cast_trait_object::dyn_upcast(File)]
+struct P9Dir {
+    fs: Arc<FsInner>,
+    node: Arc<Node>,
+}
+
+impl P9Dir {
+    fn is_root(&self) -> bool {
+        Arc::ptr_eq(&self.node, &self.fs.root)
+    }
+
+    /// Walks to the entry `name`.
+    async fn walk(&self, name: &str) -> Result<Node, IoError> {
+        let (fid, qid) = self.fs.client.walk(self.node.fid, &[name]).await?;
+        Ok(Node::new(self.fs.client.clone(), fid, qid.unwrap())) // a qid is always returned for each name
+    }
+}
+
+impl File for P9Dir {
+    fn file_type(&self) -> FileType {
+        FileType::Directory
+    }
+
+    fn block_size(&self) -> u64 {
+        hootux::mem::PAGE_SIZE as u64
+    }
+
+    fn device(&self) -> DevID {
+        self.fs.dev
+    }
+
+    fn clone_file(&self) -> Box<dyn File> {
+        Box::new(self.clone())
+    }
+
+    fn id(&self) -> u64 {
+        self.node.qid.path
+    }
+
+    fn len(&self) -> IoResult<u64> {
+        async { Ok(self.file_list().await?.len() as u64) }.boxed()
+    }
+
+    fn metadata(&self) -> IoResult<FileMetadata> {
+        async { self.fs.metadata(&self.node, FileType::Directory).await }.boxed()
+    }
+
+    fn set_mode(&self, mode: FileMode) -> IoResult<()> {
+        self.fs.set_mode(&self.node, mode)
+    }
+
+    fn set_owner(&self, uid: u32, gid: u32) -> IoResult<()> {
+        self.fs.set_owner(&self.node, uid, gid)
+    }
+
+    fn set_times(&self, atime: Option<u64>, mtime: Option<u64>) -> IoResult<()> {
+        self.fs.set_times(&self.node, atime, mtime)
+    }
+}
+
+impl Directory for P9Dir {
+    fn entries(&self) -> IoResult<usize> {
+        async { Ok(self.file_list().await?.len()) }.boxed()
+    }
+
+    fn new_file<'f, 'b: 'f, 'a: 'f>(&'a self, name: &'b str, file: Option<&'b mut dyn NormalFile<u8>>) -> BoxFuture<'f, Result<(), (Option<IoError>, Option<IoError>)>> {
+        async move {
+            let client = &self.fs.client;
+            // lcreate changes the fid to refer to the new file so a clone of the directory is used
+            let (fid, _) = client.walk(self.node.fid, &[]).await.map_err(|e| (Some(e), None))?;
+            let r = client.lcreate(fid, name, proto::O_RDWR | proto::O_CREAT | proto::O_EXCL, NEW_FILE_MODE).await;
+            let _ = client.clunk(fid).await;
+            r.map_err(|e| (Some(e), None))?;
+
+            if let Some(src) = file {
+                let len = src.len_chars().await.map_err(|e| (None, Some(e)))?;
+                let dst = self.fs.file(self.walk(name).await.map_err(|e| (Some(e), None))?);
+                let dst = cast_file!(NormalFile<u8>: dst).map_err(|_| (Some(IoError::NotPresent), None))?;
+                hootux::fs::splice::splice(&*src, 0, &*dst, 0, len as usize)
+                    .await
+                    .map_err(|(e, _)| (None, Some(e)))?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn new_dir<'f, 'a: 'f, 'b: 'f>(&'a self, name: &'b str) -> IoResult<'f, Box<dyn Directory>> {
+        async move {
+            self.fs.client.mkdir(self.node.fid, name, NEW_DIR_MODE).await?;
+            let file = self.fs.file(self.walk(name).await?);
+            cast_file!(Directory: file).map_err(|_| IoError::NotPresent) // replaced by a normal file by the host
+        }
+        .boxed()
+    }
+
+    fn get_file<'f, 'a: 'f, 'b: 'f>(&'a self, name: &'b str) -> IoResult<'f, Box<dyn File>> {
+        async move {
+            if name == THIS_DIR {
+                return Ok(self.clone_file());
+            } else if name == PARENT_DIR && self.is_root() {
+                return Err(IoError::IsDevice);
+            }
+            Ok(self.fs.file(self.walk(name).await?))
+        }
+        .boxed()
+    }
+
+    fn get_file_with_meta<'f, 'a: 'f, 'b: 'f>(&'a self, name: &'b str) -> IoResult<'f, FileHandle> {
+        async move {
+            if name == PARENT_DIR && self.is_root() {
+                return Ok(FileHandle::new_dev(FileMetadata::new_unknown()));
+            }
+            let file = self.get_file(name).await?;
+            let meta = file.metadata().await?;
+            Ok(FileHandle::new(file, false, meta))
+        }
+        .boxed()
+    }
+
+    fn file_list(&self) -> IoResult<Vec<String>> {
+        async {
+            let client = &self.fs.client;
+            let (fid, _) = client.walk(self.node.fid, &[]).await?;
+            let r = match client.lopen(fid, proto::O_RDONLY).await {
+                Ok(_) => client.readdir(fid).await,
+                Err(e) => Err(e),
+            };
+            let _ = client.clunk(fid).await;
+            let mut list = r?;
+            for name in [THIS_DIR, PARENT_DIR] {
+                if !list.iter().any(|n| n == name) {
+                    list.push(name.to_string());
+                }
+            }
+            Ok(list)
+        }
+        .boxed()
+    }
+
+    fn remove<'f, 'a: 'f, 'b: 'f>(&'a self, name: &'b str) -> IoResult<'f, ()> {
+        async move {
+            let is_dir = self.walk(name).await?.qid.is_dir();
+            let flags = if is_dir { proto::AT_REMOVEDIR } else { 0 };
+            self.fs.client.unlinkat(self.node.fid, name, flags).await
+        }
+        .boxed()
+    }
+}
+
+#[derive(Clone)]
+#[cast_trait_object::dyn_cast(File => NormalFile<u8>, Directory, FileSystem, Fifo<u8>, DeviceFile)]
+#[cast_trait_object::dyn_upcast(File)]
+struct P9File {
+    fs: Arc<FsInner>,
+    node: Arc<Node>,
+}
+
+impl File for P9File {
+    fn file_type(&self) -> FileType {
+        FileType::NormalFile
+    }
+
+    fn block_size(&self) -> u64 {
+        hootux::mem::PAGE_SIZE as u64
+    }
+
+    fn device(&self) -> DevID {
+        self.fs.dev
+    }
+
+    fn clone_file(&self) -> Box<dyn File> {
+        Box::new(self.clone())
+    }
+
+    fn id(&self) -> u64 {
+        self.node.qid.path
+    }
+
+    fn len(&self) -> IoResult<u64> {
+        async { Ok(self.fs.client.getattr(self.node.fid).await?.size) }.boxed()
+    }
+
+    fn metadata(&self) -> IoResult<FileMetadata> {
+        async { self.fs.metadata(&self.node, FileType::NormalFile).await }.boxed()
+    }
+
+    fn set_mode(&self, mode: FileMode) -> IoResult<()> {
+        self.fs.set_mode(&self.node, mode)
+    }
+
+    fn set_owner(&self, uid: u32, gid: u32) -> IoResult<()> {
+        self.fs.set_owner(&self.node, uid, gid)
+    }
+
+    fn set_times(&self, atime: Option<u64>, mtime: Option<u64>) -> IoResult<()> {
+        self.fs.set_times(&self.node, atime, mtime)
+    }
+}
+
+impl NormalFile<u8> for P9File {
+    fn len_chars(&self) -> IoResult<u64> {
+        self.len()
+    }
+
+    /// Locking is not supported, the host may access the file at any time.
+    fn file_lock<'a>(self: Box<Self>) -> BoxFuture<'a, Result<LockedFile<u8>, (IoError, Box<dyn NormalFile<u8>>)>> {
+        async { Err((IoError::NotSupported, self as Box<dyn NormalFile<u8>>)) }.boxed()
+    }
+
+    unsafe fn unlock_unsafe(&self) -> IoResult<()> {
+        async { Err(IoError::Exclusive) }.boxed()
+    }
+
+    /// Sends Tfsync, the host syncs the file to its own storage.
+    fn sync(&self, data_only: bool) -> IoResult<()> {
+        async move {
+            let (fid, _) = self.node.io_fid().await?;
+            self.fs.client.fsync(fid, data_only).await
+        }
+        .boxed()
+    }
+}
+
+impl Read<u8> for P9File {
+    fn read<'f, 'a: 'f, 'b: 'f>(&'a self, pos: u64, mut dbuff: DmaBuff<'b>) -> BoxFuture<'f, Result<(DmaBuff<'b>, usize), (IoError, DmaBuff<'b>, usize)>> {
+        async move {
+            // SAFETY: The buffer is not accessed after dbuff is returned
+            let buff = unsafe { &mut *DmaTarget::as_mut(&mut *dbuff) };
+            let fid = match self.node.io_fid().await {
+                Ok((fid, _)) => fid,
+                Err(e) => return Err((e, dbuff, 0)),
+            };
+            let mut done = 0;
+            while done < buff.len() {
+                match self.fs.client.read(fid, pos + done as u64, &mut buff[done..]).await {
+                    Ok(0) => break,
+                    Ok(n) => done += n,
+                    Err(e) => return Err((e, dbuff, done)),
+                }
+            }
+            if done == 0 && !buff.is_empty() {
+                return Err((IoError::EndOfFile, dbuff, 0));
+            }
+            Ok((dbuff, done))
+        }
+        .boxed()
+    }
+}
+
+impl Write<u8> for P9File {
+    fn write<'f, 'a: 'f, 'b: 'f>(&'a self, pos: u64, mut dbuff: DmaBuff<'b>) -> BoxFuture<'f, Result<(DmaBuff<'b>, usize), (IoError, DmaBuff<'b>, usize)>> {
+        async move {
+            // SAFETY: See P9File::read
+            let buff = unsafe { &*DmaTarget::as_mut(&mut *dbuff) };
+            let fid = match self.node.io_fid().await {
+                Ok((fid, true)) => fid,
+                Ok((_, false)) => return Err((IoError::ReadOnly, dbuff, 0)),
+                Err(e) => return Err((e, dbuff, 0)),
+            };
+            let mut done = 0;
+            while done < buff.len() {
+                match self.fs.client.write(fid, pos + done as u64, &buff[done..]).await {
+                    Ok(0) => return Err((IoError::EndOfFile, dbuff, done)),
+                    Ok(n) => done += n,
+                    Err(e) => return Err((e, dbuff, done)),
+                }
+            }
+            Ok((dbuff, done))
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,481 @@
+//! 9P2000.L client.
+//!
+//! Only a single request is in flight at any time, requests are serialized by [Client::rpc].
+//! Each request is given to the device as a two buffer chain, the request and a response buffer
+//! of `msize` bytes.
+
+use crate::queue::{dma_buffer, Buffer, Virtqueue};
+use alloc::string::String;
+use alloc::vec::Vec;
+use hootux::alloc_interface::DmaAlloc;
+use hootux::fs::IoError;
+
+/// Maximum message size requested from the server.
+pub(super) const MSIZE: u32 = 64 * 1024;
+const VERSION: &str = "9P2000.L";
+/// Size of the header of Tread, Rread and Twrite. Used to limit IO sizes to fit into a message.
+pub(super) const IO_HEADER: u32 = 4 + 1 + 2 + 4 + 8 + 4;
+const HEADER: usize = 4 + 1 + 2;
+const NOTAG: u16 = !0;
+const NOFID: u32 = !0;
+
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TMKDIR: u8 = 72;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+// Linux open flags used by Tlopen and Tlcreate
+pub(super) const O_RDONLY: u32 = 0;
+pub(super) const O_RDWR: u32 = 2;
+pub(super) const O_CREAT: u32 = 0o100;
+pub(super) const O_EXCL: u32 = 0o200;
+
+/// Tunlinkat flag, removes a directory.
+pub(super) const AT_REMOVEDIR: u32 = 0x200;
+
+/// Requests the fields of `struct stat` from Tgetattr.
+const GETATTR_BASIC: u64 = 0x7ff;
+
+pub(super) const SETATTR_MODE: u32 = 1;
+pub(super) const SETATTR_UID: u32 = 1 << 1;
+pub(super) const SETATTR_GID: u32 = 1 << 2;
+pub(super) const SETATTR_ATIME: u32 = 1 << 4;
+pub(super) const SETATTR_MTIME: u32 = 1 << 5;
+pub(super) const SETATTR_ATIME_SET: u32 = 1 << 7;
+pub(super) const SETATTR_MTIME_SET: u32 = 1 << 8;
+
+const QTDIR: u8 = 0x80;
+
+/// Maps an errno returned by the server.
+fn errno(code: u32) -> IoError {
+    match code {
+        2 => IoError::NotPresent,
+        1 | 13 => IoError::PermissionDenied,
+        16 => IoError::Busy,
+        17 => IoError::AlreadyExists,
+        20 => IoError::NotPresent, // ENOTDIR
+        21 => IoError::NotSupported, // EISDIR
+        28 => IoError::EndOfFile, // ENOSPC
+        30 => IoError::ReadOnly,
+        39 => IoError::NotEmpty,
+        _ => {
+            log::debug!("{}: 9p server returned errno {code}", crate::CRATE_NAME);
+            IoError::DeviceError
+        }
+    }
+}
+
+/// Unique identifier of a file on the server.
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Qid {
+    pub ty: u8,
+    pub path: u64,
+}
+
+impl Qid {
+    pub fn is_dir(&self) -> bool {
+        self.ty & QTDIR != 0
+    }
+}
+
+/// Response to Tgetattr.
+pub(super) struct Attr {
+    pub qid: Qid,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u64,
+    pub size: u64,
+    pub block_size: u64,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+}
+
+/// Response to Tstatfs.
+pub(super) struct StatFs {
+    pub block_size: u32,
+    pub blocks: u64,
+    pub blocks_free: u64,
+    pub blocks_avail: u64,
+    pub files: u64,
+    pub files_free: u64,
+}
+
+/// Fields updated by Tsetattr, fields are only modified when their bit is set within `valid`.
+#[derive(Default)]
+pub(super) struct SetAttr {
+    pub valid: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: u64,
+    pub mtime: u64,
+}
+
+struct Writer<'a> {
+    buff: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, b: &[u8]) -> &mut Self {
+        self.buff[self.len..self.len + b.len()].copy_from_slice(b);
+        self.len += b.len();
+        self
+    }
+
+    fn u8(&mut self, v: u8) -> &mut Self {
+        self.bytes(&[v])
+    }
+
+    fn u16(&mut self, v: u16) -> &mut Self {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn u32(&mut self, v: u32) -> &mut Self {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn u64(&mut self, v: u64) -> &mut Self {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn str(&mut self, s: &str) -> &mut Self {
+        self.u16(s.len() as u16).bytes(s.as_bytes())
+    }
+}
+
+struct Reader<'a> {
+    buff: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], IoError> {
+        if len > self.buff.len() {
+            return Err(IoError::InvalidData);
+        }
+        let (b, rest) = self.buff.split_at(len);
+        self.buff = rest;
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> Result<u8, IoError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, IoError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, IoError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, IoError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&'a str, IoError> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.bytes(len)?).map_err(|_| IoError::InvalidData)
+    }
+
+    fn qid(&mut self) -> Result<Qid, IoError> {
+        let ty = self.u8()?;
+        let _version = self.u32()?;
+        Ok(Qid { ty, path: self.u64()? })
+    }
+}
+
+struct Buffers {
+    request: Vec<u8, DmaAlloc>,
+    response: Vec<u8, DmaAlloc>,
+}
+
+pub(super) struct Client {
+    queue: Virtqueue,
+    buffers: async_lock::Mutex<Buffers>,
+    msize: core::sync::atomic::AtomicU32,
+    fids: spin::Mutex<(u32, Vec<u32>)>,
+}
+
+impl Client {
+    pub(super) fn new(queue: Virtqueue) -> Option<Self> {
+        Some(Self {
+            queue,
+            buffers: async_lock::Mutex::new(Buffers { request: dma_buffer(MSIZE as usize)?, response: dma_buffer(MSIZE as usize)? }),
+            msize: core::sync::atomic::AtomicU32::new(MSIZE),
+            fids: spin::Mutex::new((0, Vec::new())),
+        })
+    }
+
+    /// Returns the message size negotiated with the server.
+    pub(super) fn msize(&self) -> u32 {
+        self.msize.load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Returns the largest number of bytes which can be read or written by a single request.
+    pub(super) fn io_size(&self) -> usize {
+        (self.msize() - IO_HEADER) as usize
+    }
+
+    fn alloc_fid(&self) -> u32 {
+        let mut l = self.fids.lock();
+        l.1.pop().unwrap_or_else(|| {
+            l.0 += 1;
+            l.0
+        })
+    }
+
+    fn free_fid(&self, fid: u32) {
+        self.fids.lock().1.push(fid);
+    }
+
+    /// Sends a request of type `ty` with the body built by `build`, the body of the response is
+    /// passed to `parse`.
+    async fn rpc<R>(&self, ty: u8, build: impl FnOnce(&mut Writer), parse: impl FnOnce(&mut Reader) -> Result<R, IoError>) -> Result<R, IoError> {
+        let mut buffers = self.buffers.lock().await;
+        let Buffers { request, response } = &mut *buffers;
+
+        let mut w = Writer { buff: &mut request[..], len: HEADER };
+        build(&mut w);
+        let len = w.len;
+        request[..4].copy_from_slice(&(len as u32).to_le_bytes());
+        request[4] = ty;
+        let tag = if ty == TVERSION { NOTAG } else { 0 };
+        request[5..7].copy_from_slice(&tag.to_le_bytes());
+
+        let chain = [Buffer::new(&request[..len], false), Buffer::new(&response[..self.msize() as usize], true)];
+        let written = self.queue.transfer(&chain).await as usize;
+
+        let mut r = Reader { buff: &response[..written.min(response.len())] };
+        let size = r.u32()? as usize;
+        if size > written {
+            return Err(IoError::InvalidData);
+        }
+        let mut r = Reader { buff: &response[..size] };
+        r.bytes(4)?;
+        let rtype = r.u8()?;
+        let _tag = r.u16()?;
+        match rtype {
+            RLERROR => Err(errno(r.u32()?)),
+            t if t == ty + 1 => parse(&mut r),
+            _ => Err(IoError::InvalidData),
+        }
+    }
+
+    /// Negotiates the protocol version and message size.
+    pub(super) async fn version(&self) -> Result<(), IoError> {
+        let (msize, version) = self
+            .rpc(TVERSION, |w| { w.u32(MSIZE).str(VERSION); }, |r| Ok((r.u32()?, String::from(r.str()?))))
+            .await?;
+        if version != VERSION {
+            log::error!("{}: 9p server does not support {VERSION}, offered {version}", crate::CRATE_NAME);
+            return Err(IoError::NotSupported);
+        }
+        if msize < 4096 {
+            return Err(IoError::NotSupported);
+        }
+        self.msize.store(msize.min(MSIZE), core::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Allocates a fid and attaches it to the root of the export `aname`.
+    pub(super) async fn attach(&self, uname: &str, aname: &str) -> Result<(u32, Qid), IoError> {
+        let fid = self.alloc_fid();
+        match self.rpc(TATTACH, |w| { w.u32(fid).u32(NOFID).str(uname).str(aname).u32(0); }, |r| r.qid()).await {
+            Ok(qid) => Ok((fid, qid)),
+            Err(e) => {
+                self.free_fid(fid);
+                Err(e)
+            }
+        }
+    }
+
+    /// Walks from `fid` through `names` and returns a new fid referring to the file walked to
+    /// along with its qid. An empty walk clones `fid`.
+    pub(super) async fn walk(&self, fid: u32, names: &[&str]) -> Result<(u32, Option<Qid>), IoError> {
+        let newfid = self.alloc_fid();
+        let r = self.walk_inner(fid, newfid, names).await;
+        if r.is_err() {
+            // newfid is not assigned when the walk fails
+            self.free_fid(newfid);
+        }
+        r.map(|qid| (newfid, qid))
+    }
+
+    async fn walk_inner(&self, fid: u32, newfid: u32, names: &[&str]) -> Result<Option<Qid>, IoError> {
+        let qids = self
+            .rpc(
+                TWALK,
+                |w| {
+                    w.u32(fid).u32(newfid).u16(names.len() as u16);
+                    for n in names {
+                        w.str(n);
+                    }
+                },
+                |r| {
+                    let n = r.u16()?;
+                    (0..n).map(|_| r.qid()).collect::<Result<Vec<_>, _>>()
+                },
+            )
+            .await?;
+        // When the walk fails part way through the server returns the successful prefix
+        if qids.len() != names.len() {
+            return Err(IoError::NotPresent);
+        }
+        Ok(qids.last().copied())
+    }
+
+    /// Opens `fid` for IO, returns the iounit which may be `0` if the server does not report it.
+    pub(super) async fn lopen(&self, fid: u32, flags: u32) -> Result<u32, IoError> {
+        self.rpc(TLOPEN, |w| { w.u32(fid).u32(flags); }, |r| { r.qid()?; r.u32() }).await
+    }
+
+    /// Creates the file `name` within the directory `fid`, `fid` is changed to refer to the new file.
+    pub(super) async fn lcreate(&self, fid: u32, name: &str, flags: u32, mode: u32) -> Result<Qid, IoError> {
+        self.rpc(TLCREATE, |w| { w.u32(fid).str(name).u32(flags).u32(mode).u32(0); }, |r| r.qid()).await
+    }
+
+    pub(super) async fn mkdir(&self, dfid: u32, name: &str, mode: u32) -> Result<Qid, IoError> {
+        self.rpc(TMKDIR, |w| { w.u32(dfid).str(name).u32(mode).u32(0); }, |r| r.qid()).await
+    }
+
+    pub(super) async fn unlinkat(&self, dfid: u32, name: &str, flags: u32) -> Result<(), IoError> {
+        self.rpc(TUNLINKAT, |w| { w.u32(dfid).str(name).u32(flags); }, |_| Ok(())).await
+    }
+
+    pub(super) async fn statfs(&self, fid: u32) -> Result<StatFs, IoError> {
+        self.rpc(
+            TSTATFS,
+            |w| { w.u32(fid); },
+            |r| {
+                let _ty = r.u32()?;
+                let block_size = r.u32()?;
+                let (blocks, blocks_free, blocks_avail) = (r.u64()?, r.u64()?, r.u64()?);
+                let (files, files_free) = (r.u64()?, r.u64()?);
+                let _fsid = r.u64()?;
+                let _namelen = r.u32()?;
+                Ok(StatFs { block_size, blocks, blocks_free, blocks_avail, files, files_free })
+            },
+        )
+        .await
+    }
+
+    pub(super) async fn getattr(&self, fid: u32) -> Result<Attr, IoError> {
+        self.rpc(
+            TGETATTR,
+            |w| { w.u32(fid).u64(GETATTR_BASIC); },
+            |r| {
+                let _valid = r.u64()?;
+                let qid = r.qid()?;
+                let (mode, uid, gid) = (r.u32()?, r.u32()?, r.u32()?);
+                let nlink = r.u64()?;
+                let _rdev = r.u64()?;
+                let size = r.u64()?;
+                let block_size = r.u64()?;
+                let _blocks = r.u64()?;
+                let mut time = || -> Result<u64, IoError> {
+                    let sec = r.u64()?;
+                    let _nsec = r.u64()?;
+                    Ok(sec)
+                };
+                let (atime, mtime, ctime) = (time()?, time()?, time()?);
+                Ok(Attr { qid, mode, uid, gid, nlink, size, block_size, atime, mtime, ctime })
+            },
+        )
+        .await
+    }
+
+    pub(super) async fn setattr(&self, fid: u32, attr: &SetAttr) -> Result<(), IoError> {
+        self.rpc(
+            TSETATTR,
+            |w| {
+                w.u32(fid).u32(attr.valid).u32(attr.mode).u32(attr.uid).u32(attr.gid).u64(0);
+                w.u64(attr.atime).u64(0).u64(attr.mtime).u64(0);
+            },
+            |_| Ok(()),
+        )
+        .await
+    }
+
+    /// Reads the entries of the open directory `fid`, returns the names of each entry.
+    pub(super) async fn readdir(&self, fid: u32) -> Result<Vec<String>, IoError> {
+        let mut names = Vec::new();
+        let mut offset = 0;
+        loop {
+            let count = self.io_size() as u32;
+            let last = self
+                .rpc(
+                    TREADDIR,
+                    |w| { w.u32(fid).u64(offset).u32(count); },
+                    |r| {
+                        let len = r.u32()? as usize;
+                        let mut r = Reader { buff: r.bytes(len)? };
+                        let mut last = None;
+                        while !r.buff.is_empty() {
+                            r.qid()?;
+                            last = Some(r.u64()?);
+                            r.u8()?;
+                            names.push(String::from(r.str()?));
+                        }
+                        Ok(last)
+                    },
+                )
+                .await?;
+            match last {
+                Some(o) => offset = o,
+                None => return Ok(names),
+            }
+        }
+    }
+
+    /// Reads from `fid` into `buff`, returns the number of bytes read.
+    pub(super) async fn read(&self, fid: u32, offset: u64, buff: &mut [u8]) -> Result<usize, IoError> {
+        let count = buff.len().min(self.io_size()) as u32;
+        self.rpc(
+            TREAD,
+            |w| { w.u32(fid).u64(offset).u32(count); },
+            |r| {
+                let len = r.u32()? as usize;
+                let data = r.bytes(len)?;
+                let b = buff.get_mut(..len).ok_or(IoError::InvalidData)?;
+                b.copy_from_slice(data);
+                Ok(len)
+            },
+        )
+        .await
+    }
+
+    /// Writes `buff` into `fid`, returns the number of bytes written.
+    pub(super) async fn write(&self, fid: u32, offset: u64, buff: &[u8]) -> Result<usize, IoError> {
+        let buff = &buff[..buff.len().min(self.io_size())];
+        self.rpc(TWRITE, |w| { w.u32(fid).u64(offset).u32(buff.len() as u32).bytes(buff); }, |r| Ok(r.u32()? as usize)).await
+    }
+
+    /// Makes the data written to the open file `fid` durable, if `data_only` is set metadata which
+    /// is not required to read the data back may not be written.
+    pub(super) async fn fsync(&self, fid: u32, data_only: bool) -> Result<(), IoError> {
+        self.rpc(TFSYNC, |w| { w.u32(fid).u32(data_only as u32); }, |_| Ok(())).await
+    }
+
+    /// Releases `fid`, the fid is returned to the allocator even if the request fails.
+    pub(super) async fn clunk(&self, fid: u32) -> Result<(), IoError> {
+        let r = self.rpc(TCLUNK, |w| { w.u32(fid); }, |_| Ok(())).await;
+        self.free_fid(fid);
+        r
+    }
+}
@@ -0,0 +1,222 @@
+//! Split virtqueues, see virtio 1.2 section 2.7.
+//!
+//! The descriptor table and both rings are stored within a single page. Completion is detected by
+//! polling the used ring, multiple chains may be outstanding at once using [Virtqueue::submit] and
+//! [Virtqueue::poll].
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use hootux::alloc_interface::DmaAlloc;
+use hootux::mem::PAGE_SIZE;
+
+/// Largest queue size used by the driver, this allows the descriptor table and rings to fit
+/// within a single page.
+pub(crate) const MAX_QUEUE_SIZE: u16 = 128;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 1 << 1;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// Allocates a zeroed buffer of `len` bytes which is physically contiguous.
+pub(crate) fn dma_buffer(len: usize) -> Option<Vec<u8, DmaAlloc>> {
+    let mut v = Vec::new_in(DmaAlloc::new(hootux::mem::MemRegion::Mem64, PAGE_SIZE));
+    v.try_reserve_exact(len).ok()?;
+    v.resize(len, 0);
+    Some(v)
+}
+
+/// A buffer given to the device.
+pub(crate) struct Buffer {
+    /// Physical address of the buffer
+    pub addr: u64,
+    pub len: u32,
+    /// Whether the device writes to the buffer, device writable buffers must follow the readable
+    /// buffers within a chain.
+    pub writable: bool,
+}
+
+impl Buffer {
+    pub(crate) fn new(buff: &[u8], writable: bool) -> Self {
+        Self {
+            addr: hootux::mem::mem_map::translate(buff.as_ptr() as usize).unwrap(), // buffers are allocated using dma_buffer
+            len: buff.len() as u32,
+            writable,
+        }
+    }
+}
+
+struct State {
+    free: Vec<u16>,
+    avail_idx: u16,
+    last_used: u16,
+    /// Completed chains which have not been collected, maps the head descriptor to the number of
+    /// bytes written by the device.
+    completed: BTreeMap<u16, u32>,
+}
+
+pub(crate) struct Virtqueue {
+    index: u16,
+    size: u16,
+    mem: Box<[u8; PAGE_SIZE], DmaAlloc>,
+    notify: *mut u16,
+    state: spin::Mutex<State>,
+}
+
+// SAFETY: `notify` points to MMIO owned by the driver, the rings are only modified while `state` is locked.
+unsafe impl Send for Virtqueue {}
+unsafe impl Sync for Virtqueue {}
+
+impl Virtqueue {
+    /// Allocates a queue of `size` entries. `notify` is the address the queue index is written
+    /// to when buffers are made available.
+    pub(crate) fn new(index: u16, size: u16, notify: *mut u16) -> Option<Self> {
+        assert!(size <= MAX_QUEUE_SIZE);
+        let mem = Box::try_new_in([0u8; PAGE_SIZE], DmaAlloc::new(hootux::mem::MemRegion::Mem64, PAGE_SIZE)).ok()?;
+        Some(Self {
+            index,
+            size,
+            mem,
+            notify,
+            state: spin::Mutex::new(State {
+                free: (0..size).rev().collect(),
+                avail_idx: 0,
+                last_used: 0,
+                completed: BTreeMap::new(),
+            }),
+        })
+    }
+
+    fn avail_offset(&self) -> usize {
+        size_of::<Descriptor>() * self.size as usize
+    }
+
+    fn used_offset(&self) -> usize {
+        // flags, idx, ring, used_event
+        let end = self.avail_offset() + 6 + 2 * self.size as usize;
+        (end + 3) & !3
+    }
+
+    /// Returns the physical addresses of the descriptor table, the available ring and the used ring.
+    pub(crate) fn addresses(&self) -> (u64, u64, u64) {
+        let base = hootux::mem::mem_map::translate(self.mem.as_ptr() as usize).unwrap(); // memory is allocated
+        (base, base + self.avail_offset() as u64, base + self.used_offset() as u64)
+    }
+
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        debug_assert!(offset + size_of::<T>() <= PAGE_SIZE);
+        // SAFETY: The offset is within the page
+        unsafe { (self.mem.as_ptr() as *mut u8).add(offset) as *mut T }
+    }
+
+    /// Makes `buffers` available to the device as a single chain, returns the head descriptor.
+    ///
+    /// Returns `None` if there are not enough free descriptors.
+    pub(crate) fn submit(&self, buffers: &[Buffer]) -> Option<u16> {
+        assert!(!buffers.is_empty());
+        let mut state = self.state.lock();
+        if state.free.len() < buffers.len() {
+            return None;
+        }
+        let ids: Vec<u16> = (0..buffers.len()).map(|_| state.free.pop().unwrap()).collect();
+        for (i, (buff, id)) in buffers.iter().zip(&ids).enumerate() {
+            let next = ids.get(i + 1);
+            let mut flags = if buff.writable { DESC_F_WRITE } else { 0 };
+            if next.is_some() {
+                flags |= DESC_F_NEXT;
+            }
+            let desc = Descriptor { addr: buff.addr, len: buff.len, flags, next: next.copied().unwrap_or(0) };
+            // SAFETY: The descriptor is free so the device does not access it
+            unsafe { self.ptr::<Descriptor>(*id as usize * size_of::<Descriptor>()).write_volatile(desc) };
+        }
+
+        let slot = state.avail_idx % self.size;
+        // SAFETY: Ring entries are only written while the state is locked
+        unsafe { self.ptr::<u16>(self.avail_offset() + 4 + slot as usize * 2).write_volatile(ids[0]) };
+        state.avail_idx = state.avail_idx.wrapping_add(1);
+        // The descriptors must be visible before the index is updated
+        fence(Ordering::SeqCst);
+        unsafe { self.ptr::<u16>(self.avail_offset() + 2).write_volatile(state.avail_idx) };
+        fence(Ordering::SeqCst);
+        // SAFETY: The notification address is given by the device
+        unsafe { self.notify.write_volatile(self.index) };
+        Some(ids[0])
+    }
+
+    /// Collects completed chains from the used ring and returns the length written to `head` if
+    /// it has been completed.
+    pub(crate) fn poll(&self, head: u16) -> Option<u32> {
+        let mut state = self.state.lock();
+        // SAFETY: The index is written by the device
+        let used_idx = unsafe { self.ptr::<u16>(self.used_offset() + 2).read_volatile() };
+        fence(Ordering::SeqCst);
+        while state.last_used != used_idx {
+            let slot = state.last_used % self.size;
+            let elem = self.used_offset() + 4 + slot as usize * 8;
+            // SAFETY: The element was published by the device
+            let (id, len) = unsafe { (self.ptr::<u32>(elem).read_volatile() as u16, self.ptr::<u32>(elem + 4).read_volatile()) };
+            state.last_used = state.last_used.wrapping_add(1);
+
+            // Return the chain to the free list
+            let mut desc = id;
+            loop {
+                state.free.push(desc);
+                // SAFETY: The chain was returned by the device
+                let d = unsafe { self.ptr::<Descriptor>(desc as usize * size_of::<Descriptor>()).read_volatile() };
+                if d.flags & DESC_F_NEXT == 0 {
+                    break;
+                }
+                desc = d.next;
+            }
+            state.completed.insert(id, len);
+        }
+        state.completed.remove(&head)
+    }
+
+    /// Gives `buffers` to the device and waits for it to complete them.
+    ///
+    /// Returns the number of bytes written by the device. If the future is dropped after the
+    /// buffers were submitted, dropping it blocks until the device has returned them.
+    pub(crate) async fn transfer(&self, buffers: &[Buffer]) -> u32 {
+        let head = loop {
+            match self.submit(buffers) {
+                Some(head) => break head,
+                None => hootux::task::util::sleep(1).await,
+            }
+        };
+        let mut chain = InFlight { queue: self, head, done: false };
+        loop {
+            if let Some(len) = self.poll(head) {
+                chain.done = true;
+                return len;
+            }
+            hootux::task::util::sleep(1).await;
+        }
+    }
+}
+
+/// A chain submitted by [Virtqueue::transfer].
+struct InFlight<'a> {
+    queue: &'a Virtqueue,
+    head: u16,
+    /// Set once the chain has been collected from the used ring.
+    done: bool,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        // The buffers are owned by the caller and may be reused or freed once this returns
+        while !self.done {
+            self.done = self.queue.poll(self.head).is_some();
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Virtio PCI transport, see virtio 1.2 section 4.1.
+//!
+//! The device registers are located using the vendor specific PCI capabilities, each of which
+//! describes a region within one of the device's BARs.
+
+use crate::queue::Virtqueue;
+use alloc::collections::BTreeMap;
+use core::alloc::Allocator;
+use hootux::system::pci::capabilities::CapabilityId;
+use hootux::system::pci::DeviceControl;
+
+// Vendor specific capability fields
+const CAP_CFG_TYPE: usize = 3;
+const CAP_BAR: usize = 4;
+const CAP_OFFSET: usize = 8;
+const CAP_LENGTH: usize = 12;
+const CAP_NOTIFY_MULTIPLIER: usize = 16;
+
+const CFG_TYPE_COMMON: u8 = 1;
+const CFG_TYPE_NOTIFY: u8 = 2;
+const CFG_TYPE_DEVICE: u8 = 4;
+
+// Common configuration register offsets
+const DEVICE_FEATURE_SELECT: usize = 0;
+const DEVICE_FEATURE: usize = 4;
+const DRIVER_FEATURE_SELECT: usize = 8;
+const DRIVER_FEATURE: usize = 12;
+const DEVICE_STATUS: usize = 20;
+const CONFIG_GENERATION: usize = 21;
+const QUEUE_SELECT: usize = 22;
+const QUEUE_SIZE: usize = 24;
+const QUEUE_ENABLE: usize = 28;
+const QUEUE_NOTIFY_OFF: usize = 30;
+const QUEUE_DESC: usize = 32;
+const QUEUE_DRIVER: usize = 40;
+const QUEUE_DEVICE: usize = 48;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 1 << 1;
+const STATUS_DRIVER_OK: u8 = 1 << 2;
+const STATUS_FEATURES_OK: u8 = 1 << 3;
+const STATUS_FAILED: u8 = 1 << 7;
+
+/// Indicates the device is not a legacy device, this must be accepted by the driver.
+pub(crate) const F_VERSION_1: u64 = 1 << 32;
+
+/// Number of polls to wait for the device to complete a reset
+const RESET_TIMEOUT: usize = 100_000;
+
+/// A register region within a BAR.
+struct Region {
+    base: *mut u8,
+    len: usize,
+}
+
+impl Region {
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + size_of::<T>() <= self.len);
+        // SAFETY: The offset is within the region, fields are naturally aligned
+        unsafe { core::ptr::read_volatile(self.base.add(offset) as *const T) }
+    }
+
+    fn write<T: Copy>(&self, offset: usize, value: T) {
+        assert!(offset + size_of::<T>() <= self.len);
+        // SAFETY: See Self::read
+        unsafe { core::ptr::write_volatile(self.base.add(offset) as *mut T, value) }
+    }
+}
+
+pub(crate) struct Transport {
+    common: Region,
+    notify: Region,
+    notify_multiplier: u32,
+    device: Option<Region>,
+    // kept so the device is not released while the driver is running
+    _pci_dev: alloc::sync::Arc<spin::Mutex<DeviceControl>>,
+}
+
+// SAFETY: Regions point to MMIO which is owned by the driver.
+unsafe impl Send for Transport {}
+unsafe impl Sync for Transport {}
+
+impl Transport {
+    pub(crate) fn new(pci_dev: alloc::sync::Arc<spin::Mutex<DeviceControl>>) -> Result<Self, &'static str> {
+        let mut lock = pci_dev.lock();
+        let mut bars: BTreeMap<u8, *mut u8> = BTreeMap::new();
+        let mut common = None;
+        let mut notify = None;
+        let mut device = None;
+
+        for cap in lock.capability_regions(CapabilityId::VendorSpecific) {
+            let field = |offset: usize| u32::from_le_bytes(cap[offset..offset + 4].try_into().unwrap());
+            let bar = cap[CAP_BAR];
+            let cfg_type = cap[CAP_CFG_TYPE];
+            // The first capability of each type is preferred
+            let slot = match cfg_type {
+                CFG_TYPE_COMMON if common.is_none() => &mut common,
+                CFG_TYPE_NOTIFY if notify.is_none() => &mut notify,
+                CFG_TYPE_DEVICE if device.is_none() => &mut device,
+                _ => continue,
+            };
+            let Some(info) = lock.get_bar(bar) else { continue };
+            let (offset, len) = (field(CAP_OFFSET) as usize, field(CAP_LENGTH) as usize);
+            if offset + len > info.layout().size() {
+                return Err("Virtio capability exceeds BAR");
+            }
+            let base = match bars.get(&bar) {
+                Some(base) => *base,
+                None => {
+                    // SAFETY: The address is given by the PCI bar and is marked as reserved
+                    let base = unsafe {
+                        hootux::alloc_interface::MmioAlloc::new(info.addr() as usize)
+                            .allocate(info.layout())
+                            .map_err(|_| "System ran out of memory")?
+                            .as_ptr() as *mut u8
+                    };
+                    bars.insert(bar, base);
+                    base
+                }
+            };
+            // SAFETY: Checked above that the region is within the BAR
+            let region = Region { base: unsafe { base.add(offset) }, len };
+            *slot = Some((region, field(CAP_NOTIFY_MULTIPLIER)));
+        }
+
+        let (common, _) = common.ok_or("Common configuration capability not present")?;
+        let (notify, notify_multiplier) = notify.ok_or("Notification capability not present")?;
+
+        // SAFETY: The device only accesses memory allocated by the driver
+        unsafe { lock.set_bus_master(true) };
+        drop(lock);
+
+        Ok(Self {
+            common,
+            notify,
+            notify_multiplier,
+            device: device.map(|(region, _)| region),
+            _pci_dev: pci_dev,
+        })
+    }
+
+    /// Resets the device and negotiates features. `features` are the optional features supported
+    /// by the driver, returns the features accepted by both the driver and the device.
+    pub(crate) fn init(&self, features: u64) -> Result<u64, &'static str> {
+        self.common.write(DEVICE_STATUS, 0u8);
+        let mut timeout = RESET_TIMEOUT;
+        while self.common.read::<u8>(DEVICE_STATUS) != 0 {
+            timeout = timeout.checked_sub(1).ok_or("Device did not reset")?;
+            core::hint::spin_loop();
+        }
+        self.common.write(DEVICE_STATUS, STATUS_ACKNOWLEDGE);
+        self.common.write(DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let mut offered = 0u64;
+        for i in 0..2 {
+            self.common.write(DEVICE_FEATURE_SELECT, i as u32);
+            offered |= (self.common.read::<u32>(DEVICE_FEATURE) as u64) << (i * 32);
+        }
+        if offered & F_VERSION_1 == 0 {
+            self.fail();
+            return Err("Legacy devices are not supported");
+        }
+        let accepted = offered & (features | F_VERSION_1);
+        for i in 0..2 {
+            self.common.write(DRIVER_FEATURE_SELECT, i as u32);
+            self.common.write(DRIVER_FEATURE, (accepted >> (i * 32)) as u32);
+        }
+
+        self.common.write(DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+        if self.common.read::<u8>(DEVICE_STATUS) & STATUS_FEATURES_OK == 0 {
+            self.fail();
+            return Err("Device rejected features");
+        }
+        Ok(accepted)
+    }
+
+    /// Configures and enables the queue `index`.
+    pub(crate) fn setup_queue(&self, index: u16) -> Result<Virtqueue, &'static str> {
+        self.common.write(QUEUE_SELECT, index);
+        let size = self.common.read::<u16>(QUEUE_SIZE);
+        if size == 0 {
+            return Err("Queue not available");
+        }
+        let size = size.min(crate::queue::MAX_QUEUE_SIZE);
+        self.common.write(QUEUE_SIZE, size);
+
+        let notify_off = self.common.read::<u16>(QUEUE_NOTIFY_OFF) as usize * self.notify_multiplier as usize;
+        if notify_off + size_of::<u16>() > self.notify.len {
+            return Err("Queue notification address exceeds BAR");
+        }
+        // SAFETY: Checked above that the address is within the region
+        let notify = unsafe { self.notify.base.add(notify_off) } as *mut u16;
+        let queue = Virtqueue::new(index, size, notify).ok_or("System ran out of memory")?;
+
+        let (desc, driver, device) = queue.addresses();
+        self.common.write(QUEUE_DESC, desc);
+        self.common.write(QUEUE_DRIVER, driver);
+        self.common.write(QUEUE_DEVICE, device);
+        self.common.write(QUEUE_ENABLE, 1u16);
+        Ok(queue)
+    }
+
+    /// Indicates that the driver has finished configuring the device.
+    pub(crate) fn driver_ok(&self) {
+        let status = self.common.read::<u8>(DEVICE_STATUS);
+        self.common.write(DEVICE_STATUS, status | STATUS_DRIVER_OK);
+    }
+
+    /// Indicates that the driver has given up on the device.
+    pub(crate) fn fail(&self) {
+        let status = self.common.read::<u8>(DEVICE_STATUS);
+        self.common.write(DEVICE_STATUS, status | STATUS_FAILED);
+    }
+
+    /// Returns a copy of the device specific configuration.
+    ///
+    /// The copy is retried if the device modifies the configuration while it is being read.
+    pub(crate) fn config(&self) -> Option<alloc::vec::Vec<u8>> {
+        let device = self.device.as_ref()?;
+        loop {
+            let generation = self.common.read::<u8>(CONFIG_GENERATION);
+            let cfg = (0..device.len).map(|offset| device.read::<u8>(offset)).collect();
+            if generation == self.common.read::<u8>(CONFIG_GENERATION) {
+                return Some(cfg);
+            }
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Intel 6300ESB watchdog timer, see the Intel 6300ESB I/O Controller Hub datasheet section 11.
+//!
+//! The timer counts down the first stage and then the second stage, the system is reset when the
+//! second stage expires. Both stages are loaded with half of the timeout.
+
+use alloc::sync::Arc;
+use core::alloc::Allocator;
+use hootux::system::pci::DeviceControl;
+
+const WDT_BAR: u8 = 0;
+
+// Memory mapped registers
+const TIMER1: usize = 0x00;
+const TIMER2: usize = 0x04;
+const RELOAD: usize = 0x0c;
+
+// Configuration space registers
+const CONFIG: u16 = 0x60;
+const LOCK: u16 = 0x68;
+
+/// Configuration, disables the first stage interrupt. Reboot is enabled while bit 5 is clear.
+const CONFIG_INT_NONE: u16 = 3;
+const LOCK_LOCKED: u8 = 1;
+const LOCK_ENABLE: u8 = 1 << 1;
+const RELOAD_RELOAD: u16 = 1 << 8;
+/// Set when the previous reset was caused by the watchdog.
+const RELOAD_TIMEOUT: u16 = 1 << 9;
+
+/// Each stage is 20 bits, with the 1kHz prescaler one second is approximately `1 << 10` ticks.
+const MAX_TIMEOUT: u32 = 2046;
+
+pub(crate) struct Esb {
+    regs: spin::Mutex<&'static mut [u8]>,
+    pci_dev: Arc<spin::Mutex<DeviceControl>>,
+    caused_reset: bool,
+}
+
+impl Esb {
+    pub(crate) fn new(pci_dev: Arc<spin::Mutex<DeviceControl>>) -> Result<Self, &'static str> {
+        let mut lock = pci_dev.lock();
+        let b = lock.get_bar(WDT_BAR).ok_or("BAR 0 not implemented")?;
+
+        // SAFETY: The address is given by the PCI bar and is marked as reserved
+        let regs = unsafe {
+            &mut *hootux::alloc_interface::MmioAlloc::new(b.addr() as usize)
+                .allocate(b.layout())
+                .map_err(|_| "System ran out of memory")?
+                .as_ptr()
+        };
+
+        if lock.read_cfg::<u8>(LOCK) & LOCK_LOCKED != 0 {
+            return Err("Watchdog is locked by firmware");
+        }
+        // SAFETY: The watchdog does not access memory
+        unsafe {
+            lock.write_cfg(CONFIG, CONFIG_INT_NONE);
+            lock.write_cfg(LOCK, 0u8);
+        }
+        drop(lock);
+
+        let mut wdt = Self {
+            regs: spin::Mutex::new(regs),
+            pci_dev,
+            caused_reset: false,
+        };
+        wdt.caused_reset = wdt.read(RELOAD) & RELOAD_TIMEOUT != 0;
+        wdt.write(RELOAD, RELOAD_TIMEOUT | RELOAD_RELOAD);
+        Ok(wdt)
+    }
+
+    fn read(&self, offset: usize) -> u16 {
+        let regs = self.regs.lock();
+        // SAFETY: The offset is within the register region
+        unsafe { core::ptr::read_volatile(regs.as_ptr().add(offset) as *const u16) }
+    }
+
+    /// Writes a register, registers must be unlocked before each write.
+    fn write<T: Copy>(&self, offset: usize, value: T) {
+        let mut regs = self.regs.lock();
+        // SAFETY: The offset is within the register region, the unlock sequence is written to the reload register
+        unsafe {
+            let base = regs.as_mut_ptr();
+            core::ptr::write_volatile(base.add(RELOAD) as *mut u16, 0x80);
+            core::ptr::write_volatile(base.add(RELOAD) as *mut u16, 0x86);
+            core::ptr::write_volatile(base.add(offset) as *mut T, value);
+        }
+    }
+}
+
+impl hootux::system::watchdog::HardwareWatchdog for Esb {
+    fn name(&self) -> &str {
+        "i6300esb"
+    }
+
+    fn start(&self, timeout: u32) -> u32 {
+        let timeout = timeout.clamp(1, MAX_TIMEOUT);
+        self.write(TIMER1, timeout << 9);
+        self.write(TIMER2, timeout << 9);
+        self.write(RELOAD, RELOAD_RELOAD);
+        // SAFETY: The watchdog does not access memory
+        unsafe { self.pci_dev.lock().write_cfg(LOCK, LOCK_ENABLE) };
+        timeout
+    }
+
+    fn pet(&self) {
+        self.write(RELOAD, RELOAD_RELOAD);
+    }
+
+    fn stop(&self) {
+        self.write(RELOAD, RELOAD_RELOAD);
+        // SAFETY: The watchdog does not access memory
+        unsafe { self.pci_dev.lock().write_cfg(LOCK, 0u8) };
+    }
+
+    fn caused_reset(&self) -> bool {
+        self.caused_reset
+    }
+}
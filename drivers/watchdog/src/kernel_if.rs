@@ -0,0 +1,52 @@
+use alloc::boxed::Box;
+use hootux::system::driver_if::{MatchState, ResourceId};
+use hootux::system::watchdog::HardwareWatchdog;
+
+const INTEL: u16 = 0x8086;
+/// 6300ESB watchdog timer
+const ESB_WDT: u16 = 0x25ab;
+/// ICH9 LPC interface controllers
+const ICH9_LPC: core::ops::RangeInclusive<u16> = 0x2910..=0x291f;
+
+pub struct WatchdogPciProfile;
+impl hootux::system::driver_if::DriverProfile for WatchdogPciProfile {
+    fn try_start(&self, resource: Box<dyn ResourceId>) -> (MatchState, Option<Box<dyn ResourceId>>) {
+        if hootux::system::driver_if::WhoIsResource::whois(&*resource)
+            == core::any::TypeId::of::<hootux::system::pci::PciResourceContainer>()
+        {
+            let pci_dev: Box<hootux::system::pci::PciResourceContainer> = match resource.as_any().downcast() {
+                Ok(res) => res,
+                Err(_) => unreachable!(), // TypeId checked above, this branch is impossible
+            };
+
+            let wdt: Result<Box<dyn HardwareWatchdog>, &str> = match pci_dev.dev_id() {
+                (INTEL, ESB_WDT) => crate::esb::Esb::new(pci_dev.get_inner()).map(|w| Box::new(w) as _),
+                (INTEL, dev) if ICH9_LPC.contains(&dev) => crate::tco::Tco::new(pci_dev.get_inner()).map(|w| Box::new(w) as _),
+                _ => return (MatchState::NoMatch, Some(pci_dev)),
+            };
+
+            return match wdt.map(hootux::system::watchdog::register) {
+                Ok(Ok(())) => (MatchState::Success, None),
+                Ok(Err(wdt)) => {
+                    log::info!("{} already in use, not starting {}", crate::CRATE_NAME, wdt.name());
+                    (MatchState::MatchRejected, Some(pci_dev))
+                }
+                Err(e) => {
+                    // The LPC controller is not owned by this driver, it is returned to the system
+                    log::error!("Failed to start {} for {}: {e}", crate::CRATE_NAME, pci_dev.addr());
+                    (MatchState::MatchRejected, Some(pci_dev))
+                }
+            };
+        }
+        (MatchState::WrongBus, Some(resource))
+    }
+
+    fn bus_name(&self) -> &str {
+        "pci"
+    }
+
+    /// The watchdog is stopped and restarted by the kernel around a suspend.
+    fn suspend(&self) -> Result<(), ()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,25 @@
+//! Hardware watchdog timer drivers.
+//!
+//! Watchdogs are registered with [hootux::system::watchdog], which starts the watchdog and resets
+//! it while all CPUs are responding.
+//!
+//! Supported devices are
+//! - Intel 6300ESB watchdog, which is emulated by QEMU's `i6300esb` device.
+//! - Intel ICH9 TCO timer, which is present on QEMU's `q35` machine.
+
+#![feature(allocator_api)]
+#![no_std]
+extern crate alloc;
+
+static CRATE_NAME: &str = env!("CARGO_CRATE_NAME");
+
+mod esb;
+mod kernel_if;
+mod tco;
+
+#[no_mangle]
+pub extern "C" fn init() {
+    hootux::system::sysfs::get_sysfs()
+        .get_discovery()
+        .register_driver(alloc::boxed::Box::new(kernel_if::WatchdogPciProfile))
+}
@@ -0,0 +1,138 @@
+//! Intel ICH9 TCO timer, see the Intel I/O Controller Hub 9 datasheet sections 5.14 and 13.9.
+//!
+//! The TCO registers are located at `PMBASE + 0x60` in I/O space. When the timer first expires
+//! it is reloaded, the system is reset when it expires a second time. The timer counts in ticks
+//! of 0.6 seconds.
+//!
+//! The system is only reset when `NO_REBOOT` is clear in the General Control and Status register,
+//! which is located in the chipset configuration registers at `RCBA + 0x3410`.
+
+use alloc::sync::Arc;
+use core::alloc::{Allocator, Layout};
+use hootux::system::pci::DeviceControl;
+use x86_64::instructions::port::Port;
+
+// LPC configuration space registers
+const PMBASE: u16 = 0x40;
+const ACPI_CNTL: u16 = 0x44;
+const RCBA: u16 = 0xf0;
+
+const PMBASE_MASK: u32 = 0xff80;
+const ACPI_EN: u8 = 1 << 7;
+const RCBA_EN: u32 = 1;
+const RCBA_MASK: u32 = !0x3fff;
+
+/// Offset of the TCO registers from PMBASE
+const TCO_OFFSET: u16 = 0x60;
+const TCO_RLD: u16 = 0x00;
+const TCO1_STS: u16 = 0x04;
+const TCO2_STS: u16 = 0x06;
+const TCO1_CNT: u16 = 0x08;
+const TCO_TMR: u16 = 0x12;
+
+const TCO1_STS_TIMEOUT: u16 = 1 << 3;
+/// Set when the previous reset was caused by the second timeout.
+const TCO2_STS_SECOND_TO: u16 = 1 << 1;
+const TCO2_STS_BOOT: u16 = 1 << 2;
+const TCO1_CNT_TMR_HLT: u16 = 1 << 11;
+
+/// Offset of the General Control and Status register within the page containing it.
+const GCS: usize = 0x410;
+const GCS_PAGE: u32 = 0x3000;
+const GCS_NO_REBOOT: u32 = 1 << 5;
+
+const TMR_MIN: u16 = 4;
+const TMR_MAX: u16 = 0x3ff;
+
+pub(crate) struct Tco {
+    base: u16,
+    gcs: spin::Mutex<&'static mut u32>,
+    caused_reset: bool,
+    // kept so the device is not released while the driver is running
+    _pci_dev: Arc<spin::Mutex<DeviceControl>>,
+}
+
+impl Tco {
+    pub(crate) fn new(pci_dev: Arc<spin::Mutex<DeviceControl>>) -> Result<Self, &'static str> {
+        let lock = pci_dev.lock();
+        if lock.read_cfg::<u8>(ACPI_CNTL) & ACPI_EN == 0 {
+            return Err("ACPI I/O decoding is disabled");
+        }
+        let base = (lock.read_cfg::<u32>(PMBASE) & PMBASE_MASK) as u16 + TCO_OFFSET;
+        let rcba = lock.read_cfg::<u32>(RCBA);
+        if rcba & RCBA_EN == 0 {
+            return Err("Chipset configuration registers are disabled");
+        }
+        drop(lock);
+
+        // SAFETY: The address is given by the LPC controller, the page only contains chipset registers
+        let gcs = unsafe {
+            let page = hootux::alloc_interface::MmioAlloc::new(((rcba & RCBA_MASK) + GCS_PAGE) as usize)
+                .allocate(Layout::from_size_align(hootux::mem::PAGE_SIZE, hootux::mem::PAGE_SIZE).unwrap())
+                .map_err(|_| "System ran out of memory")?;
+            &mut *((page.as_ptr() as *mut u8).add(GCS) as *mut u32)
+        };
+
+        let mut wdt = Self {
+            base,
+            gcs: spin::Mutex::new(gcs),
+            caused_reset: false,
+            _pci_dev: pci_dev,
+        };
+        wdt.caused_reset = wdt.read(TCO2_STS) & TCO2_STS_SECOND_TO != 0;
+        // Status bits are cleared by writing 1
+        wdt.write(TCO1_STS, TCO1_STS_TIMEOUT);
+        wdt.write(TCO2_STS, TCO2_STS_SECOND_TO | TCO2_STS_BOOT);
+        Ok(wdt)
+    }
+
+    fn read(&self, offset: u16) -> u16 {
+        // SAFETY: The port is within the TCO register block
+        unsafe { Port::new(self.base + offset).read() }
+    }
+
+    fn write(&self, offset: u16, value: u16) {
+        // SAFETY: See Self::read
+        unsafe { Port::new(self.base + offset).write(value) }
+    }
+
+    fn set_no_reboot(&self, no_reboot: bool) {
+        let mut gcs = self.gcs.lock();
+        // SAFETY: The register is mapped
+        unsafe {
+            let v = core::ptr::read_volatile(&**gcs);
+            let v = if no_reboot { v | GCS_NO_REBOOT } else { v & !GCS_NO_REBOOT };
+            core::ptr::write_volatile(&mut **gcs, v);
+        }
+    }
+}
+
+impl hootux::system::watchdog::HardwareWatchdog for Tco {
+    fn name(&self) -> &str {
+        "ICH9 TCO"
+    }
+
+    fn start(&self, timeout: u32) -> u32 {
+        // The timer expires twice before the system is reset
+        let ticks = (timeout * 10 / 6 / 2).clamp(TMR_MIN as u32, TMR_MAX as u32) as u16;
+        self.set_no_reboot(false);
+        self.write(TCO_TMR, (self.read(TCO_TMR) & !TMR_MAX) | ticks);
+        self.pet();
+        self.write(TCO1_CNT, self.read(TCO1_CNT) & !TCO1_CNT_TMR_HLT);
+        ticks as u32 * 2 * 6 / 10
+    }
+
+    fn pet(&self) {
+        self.write(TCO_RLD, 1);
+        self.write(TCO1_STS, TCO1_STS_TIMEOUT);
+    }
+
+    fn stop(&self) {
+        self.write(TCO1_CNT, self.read(TCO1_CNT) | TCO1_CNT_TMR_HLT);
+        self.set_no_reboot(true);
+    }
+
+    fn caused_reset(&self) -> bool {
+        self.caused_reset
+    }
+}
@@ -12,7 +12,6 @@ use alloc::boxed::Box;
 use alloc::vec::Vec;
 use core::ptr::NonNull;
 use hootux::exit_qemu;
-use hootux::graphics::basic_output::BasicTTY;
 use hootux::interrupts::apic::Apic;
 use hootux::task::keyboard;
 use hootux::time::kernel_init_timer;
@@ -79,6 +78,9 @@ fn kernel_main(b: *mut libboot::boot_info::BootInfo) -> ! {
     }
 
     mem::init_mm_subsys();
+    interrupts::stats::register_cpu();
+    system::watchdog::register_cpu();
+    time::vdso::register_cpu();
 
     interrupts::apic::load_apic();
     // SAFETY: prob safe but i dont want to think rn
@@ -99,8 +101,10 @@ fn kernel_main(b: *mut libboot::boot_info::BootInfo) -> ! {
         // SAFETY: This is safe, we need a NonNull above and are just casting it back.
         graphics::KERNEL_FRAMEBUFFER.init(graphics::FrameBuffer::new(buff.width as usize ,buff.height as usize, buff.stride as usize ,unsafe { fb.as_mut() }, pxmode));
         graphics::KERNEL_FRAMEBUFFER.get().clear();
-        *graphics::basic_output::WRITER.lock() = Some(BasicTTY::new(&graphics::KERNEL_FRAMEBUFFER));
-    };
+        graphics::basic_output::init(&graphics::KERNEL_FRAMEBUFFER);
+    } else {
+        sound::pc_speaker::fault_code(sound::pc_speaker::FaultCode::NoFramebuffer);
+    }
 
     let acpi_tables = unsafe {
         let t = if let Some(acpi) = b.rsdp_ptr() {
@@ -112,18 +116,32 @@ fn kernel_main(b: *mut libboot::boot_info::BootInfo) -> ! {
             todo!(); // try alternate ways to locate rsdp
         };
         let fadt = acpi::PlatformInfo::new(&t).unwrap();
-        let pmtimer = fadt.pm_timer.expect("No PmTimer found");
-        let timer = Box::new(time::acpi_pm_timer::AcpiTimer::locate(pmtimer));
-        kernel_init_timer(timer);
+        // kvmclock does not cause VM-exits when read, prefer it when running under KVM
+        if let Some(kvmclock) = time::kvmclock::KvmClock::locate() {
+            kernel_init_timer(Box::new(kvmclock));
+        } else {
+            let pmtimer = fadt.pm_timer.expect("No PmTimer found");
+            let timer = Box::new(time::acpi_pm_timer::AcpiTimer::locate(pmtimer));
+            kernel_init_timer(timer);
+        }
 
         t
     };
     // temporary, until thread local segment is set up
     interrupts::apic::cal_and_run(0x20000);
 
+    pstore::init();
     init_logger();
+    if let Some(cmdline) = b.cmdline() {
+        config::parse_cmdline(cmdline);
+        logger::parse_cmdline(cmdline);
+        net::fetch::parse_cmdline(cmdline);
+        task::affinity::parse_cmdline(cmdline);
+        selftest::parse_cmdline(cmdline);
+    }
 
     say_hi();
+    system::hypervisor::log_hypervisor();
 
     debug!("Successfully initialized Kernel");
 
@@ -144,11 +162,15 @@ fn kernel_main(b: *mut libboot::boot_info::BootInfo) -> ! {
 
     // SAFETY: MP not initialized, race conditions are impossible.gugui
     unsafe { system::sysfs::get_sysfs().firmware().cfg_acpi(acpi_tables) }
+    // SAFETY: Firmware tables are configured above and MP is not initialized
+    unsafe { mem::numa::init() }
 
     #[cfg(test)]
     test_main();
 
     init_static_drivers();
+    system::cpufreq::init();
+    system::thermal::init();
 
     // Should this be started before or after init_static_drivers()?
     {
@@ -162,6 +184,7 @@ fn kernel_main(b: *mut libboot::boot_info::BootInfo) -> ! {
         }
     }
 
+    task::timer_wheel::init();
     task::run_task(Box::pin(keyboard::print_key()));
     task::run_exec(); //executor.run();
 }
@@ -188,12 +211,45 @@ fn say_hi() {
 
 fn init_static_drivers() {
     serial::init_rt_serial();
+    graphics::vconsole::init();
+    task::top::spawn();
+    time::clock_dev::init();
+    time::vdso::init();
+    mp::ipi::init();
+    interrupts::stats::init();
+    interrupts::affinity::init();
+    system::sysfs::block::stats::init();
+    system::sysfs::block::dev_file::init();
+    config::init();
+    logger::init();
+    klog::init();
+    ksyms::init();
+    pstore::mount();
+    memdbg::init();
+    mem::leak::init();
+    task::rlimit::init();
+    task::affinity::init();
+    task::job::init();
+    net::neighbor::init();
+    hootux::input::ps2_mouse::init();
     ahci::init();
+    hda::init();
+    virtio::init();
+    watchdog::init();
+    system::shutdown::init();
+    system::suspend::init();
+    fs::writeback::init();
+    fs::statfs::init();
+    fs::overlay::init();
+    selftest::init();
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic_handler(info: &core::panic::PanicInfo) -> ! {
+    // The serial driver may not be able to make progress
+    serial::early::takeover();
+    mp::ipi::halt_others();
     unsafe {
         panic_unlock!();
     }
@@ -203,6 +259,7 @@ fn panic_handler(info: &core::panic::PanicInfo) -> ! {
     }
     serial_println!("KERNEL PANIC\nInfo: {}", info);
     log::error!("KERNEL PANIC\nInfo: {}", info);
+    sound::pc_speaker::fault_code(sound::pc_speaker::FaultCode::Panic);
 
     stop()
 }
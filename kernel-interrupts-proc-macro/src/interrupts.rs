@@ -33,14 +33,16 @@ impl InterruptConfig {
                 #r
 
                 extern "x86-interrupt" fn #f_name(_sf: ::x86_64::structures::idt::InterruptStackFrame) {
-                    unsafe {
-                        crate::interrupts::vector_tables::INT_LOG.log(#byte);
-                    }
+                    crate::interrupts::vector_tables::INT_LOG.log(#byte);
+                    let start = crate::interrupts::vector_tables::INT_LOG.start();
+                    crate::interrupts::vector_tables::irq_enter(#byte);
                     if let Some(f) = vector_tables::IHR.get(#byte).read().callable() {
                         f.call();
                     } else {
-                        ::log::warn!("Unhandled interrupt at vector {}", #byte)
+                        crate::interrupts::spurious::unhandled(#byte)
                     }
+                    crate::interrupts::vector_tables::irq_exit();
+                    crate::interrupts::vector_tables::INT_LOG.log_latency(#byte, start);
                 }
 
                 idt[ #byte ].set_handler_fn( #f_name );
@@ -46,7 +46,7 @@ fn build_fonts(resources: &PathBuf) {
 fn assemble() {
     let out_dir = std::env::var("OUT_DIR").expect("$OUT_DIR not specified");
     // Need something assembled? Wang it in the assemble list.
-    let assemble = vec!["mp/x86_trampoline.asm"];
+    let assemble = vec!["mp/x86_trampoline.asm", "system/x86_wakeup.asm"];
     println!("cargo:rustc-link-search=native={}",&out_dir);
 
     // todo parallelize this?
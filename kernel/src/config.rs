@@ -0,0 +1,186 @@
+//! Kernel configuration registry.
+//!
+//! Subsystems declare their tunables as a [Tunable] static, the default value may be selected
+//! using cargo features. Each tunable must be listed in [PARAMS] so that it can be overridden by
+//! the kernel command line and queried or modified at runtime via `/config`.
+//!
+//! The command line consists of whitespace separated `name=value` pairs, arguments which do not
+//! name a tunable are ignored.
+//!
+//! Tunables are generally only read when a subsystem initializes a resource, modifying a tunable
+//! will not affect resources which have already been initialized.
+
+use crate::fs::report::ReportFile;
+use crate::fs::vfs::{DevID, MajorNum};
+use crate::fs::IoError;
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::fmt::Write as _;
+
+const FS_NAME: &str = "/config";
+
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("config").unwrap(););
+
+/// Default log level.
+pub static LOG_LEVEL: Tunable<log::LevelFilter> = Tunable::with_hook(
+    "log.level",
+    "Maximum level of log messages",
+    if cfg!(feature = "log-level-warn") {
+        log::LevelFilter::Warn
+    } else if cfg!(feature = "log-level-info") {
+        log::LevelFilter::Info
+    } else {
+        log::LevelFilter::Trace
+    },
+    crate::logger::default_level_changed,
+);
+
+/// All tunables which can be configured.
+static PARAMS: &[&dyn Param] = &[
+    &LOG_LEVEL,
+    &crate::serial::QUOTA_SIZE,
+    &crate::input::EVENT_QUEUE_LEN,
+    &crate::graphics::vconsole::INPUT_LIMIT,
+    &crate::interrupts::spurious::UNHANDLED_THRESHOLD,
+    &crate::interrupts::affinity::BALANCE_MS,
+    &crate::time::tickless::TICKLESS,
+    &crate::time::tickless::MAX_IDLE_MS,
+    &crate::mem::allocator::HEAP_CEILING,
+    &crate::system::sysfs::block::dev_file::CHECKSUM_PAGES,
+    &crate::system::sysfs::block::dev_file::READAHEAD_PAGES,
+    &crate::time::sntp::SERVER,
+    &crate::time::sntp::POLL_INTERVAL,
+    &crate::time::vdso::UPDATE_MS,
+    &crate::net::neighbor::REACHABLE_TIME,
+    &crate::net::neighbor::RETRANS_TIME,
+    &crate::net::neighbor::MAX_PROBES,
+    &crate::net::neighbor::GC_STALE_TIME,
+    &crate::net::capture::BUFFER_SIZE,
+    &crate::system::cpufreq::GOVERNOR,
+    &crate::system::cpufreq::SAMPLE_MS,
+    &crate::system::thermal::POLL_MS,
+    &crate::system::thermal::LOG_INTERVAL,
+    &crate::system::thermal::THROTTLE_MARGIN,
+    &crate::system::thermal::CRITICAL_MARGIN,
+    &crate::system::thermal::CRITICAL_MS,
+    &crate::system::watchdog::TIMEOUT,
+    &crate::system::watchdog::PET_MS,
+    &crate::system::watchdog::STALL_MS,
+    &crate::task::top::INTERVAL_MS,
+    &crate::task::rlimit::DEFAULT_HEAP,
+    &crate::task::rlimit::DEFAULT_OPEN_FILES,
+    &crate::task::rlimit::DEFAULT_PENDING_OPS,
+    &crate::fault_inject::HEAP.spec,
+    &crate::fault_inject::FRAME.spec,
+    &crate::fault_inject::BLOCK.spec,
+    &crate::fault_inject::NET_TX.spec,
+    &crate::fault_inject::NET_RX.spec,
+    &crate::mem::leak::TRACK,
+    &crate::system::shutdown::TIMEOUT_MS,
+];
+
+/// A configurable value.
+pub struct Tunable<T: Copy> {
+    name: &'static str,
+    description: &'static str,
+    value: atomic::Atomic<T>,
+    on_set: Option<fn(T)>,
+}
+
+impl<T: Copy> Tunable<T> {
+    pub const fn new(name: &'static str, description: &'static str, default: T) -> Self {
+        Self { name, description, value: atomic::Atomic::new(default), on_set: None }
+    }
+
+    /// Constructs a tunable which calls `on_set` when its value is changed.
+    pub const fn with_hook(name: &'static str, description: &'static str, default: T, on_set: fn(T)) -> Self {
+        Self { name, description, value: atomic::Atomic::new(default), on_set: Some(on_set) }
+    }
+
+    pub fn get(&self) -> T {
+        self.value.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn set(&self, value: T) {
+        self.value.store(value, atomic::Ordering::Relaxed);
+        if let Some(f) = self.on_set {
+            f(value)
+        }
+    }
+}
+
+/// Type erased interface to a [Tunable].
+pub trait Param: Sync {
+    fn name(&self) -> &'static str;
+    fn description(&self) -> &'static str;
+    /// Parses `value` and sets the parameter to it.
+    fn set_str(&self, value: &str) -> Result<(), ()>;
+    fn fmt_value(&self, f: &mut dyn core::fmt::Write) -> core::fmt::Result;
+}
+
+impl<T> Param for Tunable<T>
+where
+    T: Copy + Send + core::str::FromStr + core::fmt::Display,
+{
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn description(&self) -> &'static str {
+        self.description
+    }
+
+    fn set_str(&self, value: &str) -> Result<(), ()> {
+        self.set(value.parse().map_err(|_| ())?);
+        Ok(())
+    }
+
+    fn fmt_value(&self, f: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        write!(f, "{}", self.get())
+    }
+}
+
+/// Returns the parameter named `name`.
+pub fn lookup(name: &str) -> Option<&'static dyn Param> {
+    PARAMS.iter().find(|p| p.name() == name).copied()
+}
+
+/// Sets a parameter from a `name=value` string.
+pub fn set_arg(arg: &str) -> Result<(), ()> {
+    let (name, value) = arg.split_once('=').ok_or(())?;
+    lookup(name.trim()).ok_or(())?.set_str(value.trim())
+}
+
+/// Applies the parameters given on the kernel command line.
+pub fn parse_cmdline(cmdline: &str) {
+    for arg in cmdline.split_whitespace() {
+        let Some((name, _)) = arg.split_once('=') else { continue };
+        if lookup(name).is_none() {
+            continue;
+        }
+        match set_arg(arg) {
+            Ok(()) => log::info!("Config: {arg}"),
+            Err(()) => log::warn!("Config: Invalid value for {name}: {arg}"),
+        }
+    }
+}
+
+/// Formats all parameters as `name=value` lines, followed by their description.
+pub fn report() -> String {
+    let mut s = String::new();
+    for p in PARAMS {
+        let _ = write!(s, "{}=", p.name());
+        let _ = p.fmt_value(&mut s);
+        let _ = writeln!(s, "\t# {}", p.description());
+    }
+    s
+}
+
+/// Mounts the configuration file.
+///
+/// Reading the file returns the output of [report], writing `name=value` lines sets parameters.
+pub fn init() {
+    let file = ReportFile::with_commands(DevID::new(*MAJOR, 0), report, |line| set_arg(line).map_err(|()| IoError::InvalidData));
+    crate::task::util::block_on!(crate::fs::get_vfs().mount_dev(Box::new(file), FS_NAME))
+        .expect("Failed to mount config to VFS");
+}
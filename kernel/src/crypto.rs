@@ -0,0 +1,183 @@
+//! Cryptographic primitives.
+//!
+//! Algorithms are provided by engines which are registered with the kernel by name. Users look up
+//! an algorithm such as `"sha256"` or `"aes"` using [hash] or [cipher] and receive an instance
+//! from the highest priority engine which is available on this CPU, so accelerated
+//! implementations are picked up by all users without them needing to know which exist. An engine
+//! may also be requested by its driver name, e.g. `"aes-generic"`.
+//!
+//! The following engines are built in
+//!
+//! | Algorithm | Driver             | Priority |
+//! |-----------|--------------------|----------|
+//! | `sha256`  | `sha256-generic`   | 100      |
+//! | `crc32c`  | `crc32c-sse4.2`    | 200      |
+//! | `crc32c`  | `crc32c-generic`   | 100      |
+//! | `aes`     | `aes-ni`           | 300      |
+//! | `aes`     | `aes-generic`      | 100      |
+//!
+//! Constructions built on these, such as [hmac::Hmac] and [xts::Xts], take their primitives from
+//! the registry. Where the algorithm is fixed and performance matters the typed interfaces in
+//! each module may be used directly, e.g. [crc::crc32c].
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+pub mod aes;
+pub mod crc;
+pub mod hmac;
+pub mod sha256;
+pub mod xts;
+
+/// An incremental hash function.
+pub trait Hash: Send + Sync {
+    fn update(&mut self, data: &[u8]);
+
+    /// Writes the digest to `out`, which must be [Self::output_size] bytes long.
+    fn finish(self: Box<Self>, out: &mut [u8]);
+
+    /// Returns the size of the digest in bytes.
+    fn output_size(&self) -> usize;
+
+    /// Returns the size of the blocks which the hash operates on in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Clones the current state of the hash.
+    fn box_clone(&self) -> Box<dyn Hash>;
+}
+
+/// A keyed block cipher.
+pub trait BlockCipher: Send + Sync {
+    /// Returns the size of the blocks which the cipher operates on in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Encrypts each block of `data` in place, the length of `data` must be a multiple of the
+    /// block size.
+    ///
+    /// Implementations may have a significant setup cost, so callers should pass as many blocks
+    /// as possible in a single call.
+    fn encrypt_blocks(&self, data: &mut [u8]);
+
+    /// Decrypts each block of `data` in place, see [Self::encrypt_blocks].
+    fn decrypt_blocks(&self, data: &mut [u8]);
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CryptoError {
+    /// No available engine implements the requested algorithm.
+    NotFound,
+    /// The key is not a valid size for the algorithm.
+    InvalidKey,
+    /// An engine with the same driver name is already registered.
+    AlreadyExists,
+}
+
+/// Describes an implementation of a hash function.
+#[derive(Copy, Clone)]
+pub struct HashEngine {
+    /// Name of the algorithm which this engine implements.
+    pub algorithm: &'static str,
+    /// Unique name of this implementation.
+    pub driver: &'static str,
+    /// When multiple engines implement an algorithm the highest priority available one is used.
+    pub priority: u32,
+    /// Returns whether the engine can be used on this system.
+    pub available: fn() -> bool,
+    pub new: fn() -> Box<dyn Hash>,
+}
+
+/// Describes an implementation of a block cipher.
+#[derive(Copy, Clone)]
+pub struct CipherEngine {
+    /// Name of the algorithm which this engine implements.
+    pub algorithm: &'static str,
+    /// Unique name of this implementation.
+    pub driver: &'static str,
+    /// When multiple engines implement an algorithm the highest priority available one is used.
+    pub priority: u32,
+    /// Returns whether the engine can be used on this system.
+    pub available: fn() -> bool,
+    /// Key sizes in bytes accepted by this engine.
+    pub key_sizes: &'static [usize],
+    /// Constructs an instance using `key`, the key length is one of [Self::key_sizes].
+    pub new: fn(&[u8]) -> Box<dyn BlockCipher>,
+}
+
+struct Registry {
+    hashes: Vec<HashEngine>,
+    ciphers: Vec<CipherEngine>,
+}
+
+impl Registry {
+    fn contains(&self, driver: &str) -> bool {
+        self.hashes.iter().any(|e| e.driver == driver) || self.ciphers.iter().any(|e| e.driver == driver)
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: spin::RwLock<Registry> = spin::RwLock::new(Registry {
+        hashes: alloc::vec![sha256::ENGINE, crc::ENGINE_HW, crc::ENGINE_GENERIC],
+        ciphers: alloc::vec![aes::ENGINE_HW, aes::ENGINE_GENERIC],
+    });
+}
+
+/// Registers a new hash engine.
+pub fn register_hash(engine: HashEngine) -> Result<(), CryptoError> {
+    let mut l = REGISTRY.write();
+    if l.contains(engine.driver) {
+        return Err(CryptoError::AlreadyExists);
+    }
+    log::debug!("crypto: registered {} ({})", engine.driver, engine.algorithm);
+    l.hashes.push(engine);
+    Ok(())
+}
+
+/// Registers a new block cipher engine.
+pub fn register_cipher(engine: CipherEngine) -> Result<(), CryptoError> {
+    let mut l = REGISTRY.write();
+    if l.contains(engine.driver) {
+        return Err(CryptoError::AlreadyExists);
+    }
+    log::debug!("crypto: registered {} ({})", engine.driver, engine.algorithm);
+    l.ciphers.push(engine);
+    Ok(())
+}
+
+/// Returns the highest priority available engine from `engines` matching `name` which is either
+/// an algorithm or driver name. Engines are only selected by driver name when available.
+fn select<'a, E>(engines: &'a [E], name: &str, f: impl Fn(&E) -> (&'static str, &'static str, u32, fn() -> bool)) -> Option<&'a E> {
+    engines
+        .iter()
+        .filter(|e| {
+            let (alg, driver, _, available) = f(e);
+            (alg == name || driver == name) && available()
+        })
+        .max_by_key(|e| f(e).2)
+}
+
+/// Constructs a new instance of the hash `name`.
+pub fn hash(name: &str) -> Result<Box<dyn Hash>, CryptoError> {
+    let l = REGISTRY.read();
+    let e = select(&l.hashes, name, |e| (e.algorithm, e.driver, e.priority, e.available)).ok_or(CryptoError::NotFound)?;
+    Ok((e.new)())
+}
+
+/// Constructs a new instance of the block cipher `name` using `key`.
+///
+/// Returns [CryptoError::InvalidKey] if the selected engine does not accept keys of this length.
+pub fn cipher(name: &str, key: &[u8]) -> Result<Box<dyn BlockCipher>, CryptoError> {
+    let l = REGISTRY.read();
+    let e = select(&l.ciphers, name, |e| (e.algorithm, e.driver, e.priority, e.available)).ok_or(CryptoError::NotFound)?;
+    if !e.key_sizes.contains(&key.len()) {
+        return Err(CryptoError::InvalidKey);
+    }
+    Ok((e.new)(key))
+}
+
+/// Returns `(algorithm, driver, priority, available)` for each registered engine.
+pub fn engines() -> Vec<(&'static str, &'static str, u32, bool)> {
+    let l = REGISTRY.read();
+    let hashes = l.hashes.iter().map(|e| (e.algorithm, e.driver, e.priority, (e.available)()));
+    let ciphers = l.ciphers.iter().map(|e| (e.algorithm, e.driver, e.priority, (e.available)()));
+    hashes.chain(ciphers).collect()
+}
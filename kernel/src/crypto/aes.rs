@@ -0,0 +1,362 @@
+//! AES block cipher.
+//!
+//! This uses AES-NI when the CPU supports it and SSE has been enabled, otherwise the cipher is
+//! computed in software. These are registered as the `aes-ni` and `aes-generic` engines.
+//!
+//! The kernel does not use SSE registers, so the SSE state of the interrupted context is saved and
+//! interrupts are disabled while AES-NI is in use.
+//!
+//! The software implementation uses table lookups and is not constant time.
+
+use super::{BlockCipher, CipherEngine};
+use alloc::boxed::Box;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+lazy_static::lazy_static! {
+    /// Whether AES-NI can be used.
+    static ref HW_AES: bool = raw_cpuid::CpuId::new().get_feature_info().is_some_and(|f| f.has_aesni())
+        && Cr4::read().contains(Cr4Flags::OSFXSR)
+        && !Cr0::read().intersects(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+}
+
+pub const BLOCK_SIZE: usize = 16;
+
+pub(super) type Block = [u8; BLOCK_SIZE];
+
+const SBOX: [u8; 256] = sbox();
+const INV_SBOX: [u8; 256] = inv_sbox(&SBOX);
+const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+const fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut p = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            p ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 };
+        b >>= 1;
+    }
+    p
+}
+
+const fn sbox() -> [u8; 256] {
+    // Multiplicative inverses are found using logarithms to the generator 3
+    let mut exp = [0u8; 255];
+    let mut log = [0u8; 256];
+    let mut x = 1u8;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x;
+        log[x as usize] = i as u8;
+        x = mul(x, 3);
+        i += 1;
+    }
+
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let inv = if i == 0 { 0 } else { exp[(255 - log[i] as usize) % 255] };
+        table[i] = inv ^ inv.rotate_left(1) ^ inv.rotate_left(2) ^ inv.rotate_left(3) ^ inv.rotate_left(4) ^ 0x63;
+        i += 1;
+    }
+    table
+}
+
+const fn inv_sbox(sbox: &[u8; 256]) -> [u8; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[sbox[i] as usize] = i as u8;
+        i += 1;
+    }
+    table
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KeyError {
+    /// The key is not 16 or 32 bytes long.
+    InvalidLength,
+}
+
+/// An expanded AES-128 or AES-256 key.
+#[derive(Clone)]
+pub struct Aes {
+    /// Whether to use AES-NI.
+    hw: bool,
+    rounds: usize,
+    enc: [Block; 15],
+    /// Round keys for the equivalent inverse cipher used by AES-NI.
+    dec: [Block; 15],
+}
+
+impl Aes {
+    /// Expands `key`, AES-NI will be used when it is available.
+    pub fn new(key: &[u8]) -> Result<Self, KeyError> {
+        Self::expand(key, *HW_AES)
+    }
+
+    /// Expands `key`, the cipher will always be computed in software.
+    pub fn new_generic(key: &[u8]) -> Result<Self, KeyError> {
+        Self::expand(key, false)
+    }
+
+    fn expand(key: &[u8], hw: bool) -> Result<Self, KeyError> {
+        let nk = match key.len() {
+            16 => 4,
+            32 => 8,
+            _ => return Err(KeyError::InvalidLength),
+        };
+        let rounds = nk + 6;
+        let mut w = [[0u8; 4]; 60];
+        for (i, word) in key.chunks_exact(4).enumerate() {
+            w[i].copy_from_slice(word);
+        }
+        for i in nk..4 * (rounds + 1) {
+            let mut t = w[i - 1];
+            if i % nk == 0 {
+                t = [SBOX[t[1] as usize] ^ RCON[i / nk - 1], SBOX[t[2] as usize], SBOX[t[3] as usize], SBOX[t[0] as usize]];
+            } else if nk > 6 && i % nk == 4 {
+                t = t.map(|b| SBOX[b as usize]);
+            }
+            for j in 0..4 {
+                w[i][j] = w[i - nk][j] ^ t[j];
+            }
+        }
+
+        let mut enc = [[0; BLOCK_SIZE]; 15];
+        for (r, k) in enc.iter_mut().enumerate().take(rounds + 1) {
+            for c in 0..4 {
+                k[c * 4..c * 4 + 4].copy_from_slice(&w[r * 4 + c]);
+            }
+        }
+        let mut dec = [[0; BLOCK_SIZE]; 15];
+        dec[0] = enc[rounds];
+        dec[rounds] = enc[0];
+        for r in 1..rounds {
+            dec[r] = enc[rounds - r];
+            inv_mix_columns(&mut dec[r]);
+        }
+        Ok(Self { hw, rounds, enc, dec })
+    }
+
+    /// Encrypts each block of `data`, the length of `data` must be a multiple of [BLOCK_SIZE].
+    pub fn encrypt_blocks(&self, data: &mut [u8]) {
+        self.with_cipher(false, |f| data.chunks_exact_mut(BLOCK_SIZE).for_each(|b| f(b.try_into().unwrap())))
+    }
+
+    /// Decrypts each block of `data`, the length of `data` must be a multiple of [BLOCK_SIZE].
+    pub fn decrypt_blocks(&self, data: &mut [u8]) {
+        self.with_cipher(true, |f| data.chunks_exact_mut(BLOCK_SIZE).for_each(|b| f(b.try_into().unwrap())))
+    }
+
+    /// Calls `f` with a function which encrypts or decrypts a single block.
+    ///
+    /// Saving the SSE state is expensive so when AES-NI is used it is saved once for all blocks
+    /// processed by `f`, interrupts are disabled while `f` runs.
+    fn with_cipher<R>(&self, decrypt: bool, f: impl FnOnce(&dyn Fn(&mut Block)) -> R) -> R {
+        if self.hw {
+            // SAFETY: AES-NI is supported and SSE is enabled, the SSE state is saved
+            unsafe {
+                with_simd(|| {
+                    if decrypt {
+                        f(&|b| aesni_decrypt(&self.dec, self.rounds, b))
+                    } else {
+                        f(&|b| aesni_encrypt(&self.enc, self.rounds, b))
+                    }
+                })
+            }
+        } else if decrypt {
+            f(&|b| self.decrypt_sw(b))
+        } else {
+            f(&|b| self.encrypt_sw(b))
+        }
+    }
+
+    fn encrypt_sw(&self, s: &mut Block) {
+        xor(s, &self.enc[0]);
+        for r in 1..=self.rounds {
+            for b in s.iter_mut() {
+                *b = SBOX[*b as usize];
+            }
+            shift_rows(s);
+            if r != self.rounds {
+                mix_columns(s);
+            }
+            xor(s, &self.enc[r]);
+        }
+    }
+
+    fn decrypt_sw(&self, s: &mut Block) {
+        xor(s, &self.enc[self.rounds]);
+        for r in (0..self.rounds).rev() {
+            inv_shift_rows(s);
+            for b in s.iter_mut() {
+                *b = INV_SBOX[*b as usize];
+            }
+            xor(s, &self.enc[r]);
+            if r != 0 {
+                inv_mix_columns(s);
+            }
+        }
+    }
+}
+
+impl Drop for Aes {
+    fn drop(&mut self) {
+        // Clear the key schedule so that the key does not remain in freed memory
+        for b in self.enc.iter_mut().chain(self.dec.iter_mut()).flatten() {
+            // SAFETY: b is a valid reference
+            unsafe { core::ptr::write_volatile(b, 0) };
+        }
+    }
+}
+
+pub(super) fn xor(a: &mut Block, b: &Block) {
+    for (a, b) in a.iter_mut().zip(b) {
+        *a ^= b;
+    }
+}
+
+/// The state is stored column major, byte `r + 4c` is row `r` of column `c`.
+fn shift_rows(s: &mut Block) {
+    let old = *s;
+    for r in 1..4 {
+        for c in 0..4 {
+            s[r + 4 * c] = old[r + 4 * ((c + r) % 4)];
+        }
+    }
+}
+
+fn inv_shift_rows(s: &mut Block) {
+    let old = *s;
+    for r in 1..4 {
+        for c in 0..4 {
+            s[r + 4 * c] = old[r + 4 * ((c + 4 - r) % 4)];
+        }
+    }
+}
+
+fn mix_columns(s: &mut Block) {
+    for c in s.chunks_exact_mut(4) {
+        let [a0, a1, a2, a3] = [c[0], c[1], c[2], c[3]];
+        c[0] = mul(a0, 2) ^ mul(a1, 3) ^ a2 ^ a3;
+        c[1] = a0 ^ mul(a1, 2) ^ mul(a2, 3) ^ a3;
+        c[2] = a0 ^ a1 ^ mul(a2, 2) ^ mul(a3, 3);
+        c[3] = mul(a0, 3) ^ a1 ^ a2 ^ mul(a3, 2);
+    }
+}
+
+fn inv_mix_columns(s: &mut Block) {
+    for c in s.chunks_exact_mut(4) {
+        let [a0, a1, a2, a3] = [c[0], c[1], c[2], c[3]];
+        c[0] = mul(a0, 14) ^ mul(a1, 11) ^ mul(a2, 13) ^ mul(a3, 9);
+        c[1] = mul(a0, 9) ^ mul(a1, 14) ^ mul(a2, 11) ^ mul(a3, 13);
+        c[2] = mul(a0, 13) ^ mul(a1, 9) ^ mul(a2, 14) ^ mul(a3, 11);
+        c[3] = mul(a0, 11) ^ mul(a1, 13) ^ mul(a2, 9) ^ mul(a3, 14);
+    }
+}
+
+#[repr(C, align(16))]
+struct FxArea([u8; 512]);
+
+/// Runs `f` with the SSE state of the interrupted context saved.
+///
+/// # Safety
+///
+/// SSE must be enabled.
+unsafe fn with_simd<R>(f: impl FnOnce() -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut area = FxArea([0; 512]);
+        core::arch::asm!("fxsave64 [{}]", in(reg) &mut area, options(nostack));
+        let r = f();
+        core::arch::asm!("fxrstor64 [{}]", in(reg) &area, options(nostack));
+        r
+    })
+}
+
+/// The compiler does not use SSE registers so they are not declared as clobbered, the caller
+/// must save them using [with_simd].
+///
+/// # Safety
+///
+/// AES-NI must be supported and SSE must be enabled.
+unsafe fn aesni_encrypt(keys: &[Block; 15], rounds: usize, b: &mut Block) {
+    core::arch::asm!(
+        "movdqu xmm0, [{b}]",
+        "movdqu xmm1, [{k}]",
+        "pxor xmm0, xmm1",
+        "2:",
+        "add {k}, 16",
+        "movdqu xmm1, [{k}]",
+        "aesenc xmm0, xmm1",
+        "dec {r}",
+        "jnz 2b",
+        "movdqu xmm1, [{k} + 16]",
+        "aesenclast xmm0, xmm1",
+        "movdqu [{b}], xmm0",
+        b = in(reg) b.as_mut_ptr(),
+        k = inout(reg) keys.as_ptr() => _,
+        r = inout(reg) rounds - 1 => _,
+        options(nostack),
+    );
+}
+
+/// See [aesni_encrypt], `keys` are the round keys for the equivalent inverse cipher.
+///
+/// # Safety
+///
+/// AES-NI must be supported and SSE must be enabled.
+unsafe fn aesni_decrypt(keys: &[Block; 15], rounds: usize, b: &mut Block) {
+    core::arch::asm!(
+        "movdqu xmm0, [{b}]",
+        "movdqu xmm1, [{k}]",
+        "pxor xmm0, xmm1",
+        "2:",
+        "add {k}, 16",
+        "movdqu xmm1, [{k}]",
+        "aesdec xmm0, xmm1",
+        "dec {r}",
+        "jnz 2b",
+        "movdqu xmm1, [{k} + 16]",
+        "aesdeclast xmm0, xmm1",
+        "movdqu [{b}], xmm0",
+        b = in(reg) b.as_mut_ptr(),
+        k = inout(reg) keys.as_ptr() => _,
+        r = inout(reg) rounds - 1 => _,
+        options(nostack),
+    );
+}
+
+impl BlockCipher for Aes {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn encrypt_blocks(&self, data: &mut [u8]) {
+        Aes::encrypt_blocks(self, data)
+    }
+
+    fn decrypt_blocks(&self, data: &mut [u8]) {
+        Aes::decrypt_blocks(self, data)
+    }
+}
+
+const KEY_SIZES: &[usize] = &[16, 32];
+
+pub(super) const ENGINE_HW: CipherEngine = CipherEngine {
+    algorithm: "aes",
+    driver: "aes-ni",
+    priority: 300,
+    available: || *HW_AES,
+    key_sizes: KEY_SIZES,
+    new: |key| Box::new(Aes::expand(key, true).unwrap()), // key size checked by the registry
+};
+
+pub(super) const ENGINE_GENERIC: CipherEngine = CipherEngine {
+    algorithm: "aes",
+    driver: "aes-generic",
+    priority: 100,
+    available: || true,
+    key_sizes: KEY_SIZES,
+    new: |key| Box::new(Aes::new_generic(key).unwrap()), // key size checked by the registry
+};
@@ -0,0 +1,133 @@
+//! Cyclic redundancy checks.
+
+use super::{Hash, HashEngine};
+use alloc::boxed::Box;
+
+lazy_static::lazy_static! {
+    /// Whether the CPU supports the SSE4.2 `crc32` instruction.
+    static ref HW_CRC32C: bool = raw_cpuid::CpuId::new().get_feature_info().is_some_and(|f| f.has_sse42());
+}
+
+const CRC32C_POLY: u32 = 0x82F6_3B78;
+static CRC32C_TABLE: [u32; 256] = crc_table(CRC32C_POLY);
+
+const fn crc_table(poly: u32) -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ poly } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 (IEEE 802.3, reflected) as used by GPT.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// CRC-32C (Castagnoli).
+///
+/// This uses the SSE4.2 `crc32` instruction when the CPU supports it. The instruction only
+/// operates on general purpose registers so it may be used even though the kernel does not use
+/// SSE registers.
+pub fn crc32c(data: &[u8]) -> u32 {
+    !crc32c_append(!0, data)
+}
+
+/// Continues the CRC-32C `crc` over `data`. `crc` is the un-inverted intermediate value, start
+/// with `!0` and invert the result when finished.
+pub fn crc32c_append(crc: u32, data: &[u8]) -> u32 {
+    if *HW_CRC32C {
+        // SAFETY: The instruction is supported
+        unsafe { crc32c_hw(crc, data) }
+    } else {
+        crc32c_sw(crc, data)
+    }
+}
+
+fn crc32c_sw(mut crc: u32, data: &[u8]) -> u32 {
+    for b in data {
+        crc = CRC32C_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// # Safety
+///
+/// The caller must ensure that the CPU supports SSE4.2
+unsafe fn crc32c_hw(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = crc as u64;
+    let mut chunks = data.chunks_exact(8);
+    for c in &mut chunks {
+        let v = u64::from_le_bytes(c.try_into().unwrap());
+        core::arch::asm!("crc32 {crc}, {v}", crc = inout(reg) crc, v = in(reg) v, options(pure, nomem, nostack));
+    }
+    for b in chunks.remainder() {
+        core::arch::asm!("crc32 {crc:e}, {v}", crc = inout(reg) crc, v = in(reg_byte) *b, options(pure, nomem, nostack));
+    }
+    crc as u32
+}
+
+/// CRC-32C as a [Hash], the digest is the little endian CRC.
+#[derive(Clone)]
+struct Crc32c {
+    crc: u32,
+    hw: bool,
+}
+
+impl Hash for Crc32c {
+    fn update(&mut self, data: &[u8]) {
+        self.crc = if self.hw {
+            // SAFETY: Only constructed with `hw` when the instruction is supported
+            unsafe { crc32c_hw(self.crc, data) }
+        } else {
+            crc32c_sw(self.crc, data)
+        }
+    }
+
+    fn finish(self: Box<Self>, out: &mut [u8]) {
+        out.copy_from_slice(&(!self.crc).to_le_bytes())
+    }
+
+    fn output_size(&self) -> usize {
+        4
+    }
+
+    fn block_size(&self) -> usize {
+        1
+    }
+
+    fn box_clone(&self) -> Box<dyn Hash> {
+        Box::new(self.clone())
+    }
+}
+
+pub(super) const ENGINE_HW: HashEngine = HashEngine {
+    algorithm: "crc32c",
+    driver: "crc32c-sse4.2",
+    priority: 200,
+    available: || *HW_CRC32C,
+    new: || Box::new(Crc32c { crc: !0, hw: true }),
+};
+
+pub(super) const ENGINE_GENERIC: HashEngine = HashEngine {
+    algorithm: "crc32c",
+    driver: "crc32c-generic",
+    priority: 100,
+    available: || true,
+    new: || Box::new(Crc32c { crc: !0, hw: false }),
+};
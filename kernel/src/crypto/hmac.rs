@@ -0,0 +1,102 @@
+//! HMAC (RFC 2104) and PBKDF2 (RFC 8018) over any registered hash.
+
+use super::sha256::DIGEST_SIZE;
+use super::Hash;
+use alloc::boxed::Box;
+use alloc::vec;
+
+/// HMAC keyed with `key`.
+pub struct Hmac {
+    inner: Box<dyn Hash>,
+    outer: Box<dyn Hash>,
+}
+
+impl Clone for Hmac {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.box_clone(), outer: self.outer.box_clone() }
+    }
+}
+
+impl Hmac {
+    /// Constructs an HMAC using the hash `hash`, which must not have been updated.
+    pub fn new(hash: Box<dyn Hash>, key: &[u8]) -> Self {
+        let mut k = vec![0u8; hash.block_size()];
+        if key.len() > k.len() {
+            let mut h = hash.box_clone();
+            h.update(key);
+            let n = h.output_size();
+            h.finish(&mut k[..n]);
+        } else {
+            k[..key.len()].copy_from_slice(key);
+        }
+        let mut inner = hash.box_clone();
+        let mut outer = hash;
+        k.iter_mut().for_each(|b| *b ^= 0x36);
+        inner.update(&k);
+        k.iter_mut().for_each(|b| *b ^= 0x36 ^ 0x5c);
+        outer.update(&k);
+        k.fill(0);
+        Self { inner, outer }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    /// Returns the size of the MAC in bytes.
+    pub fn output_size(&self) -> usize {
+        self.outer.output_size()
+    }
+
+    /// Writes the MAC to `out`, which must be [Self::output_size] bytes long.
+    pub fn finish(self, out: &mut [u8]) {
+        let mut outer = self.outer;
+        self.inner.finish(out);
+        outer.update(out);
+        outer.finish(out);
+    }
+}
+
+fn sha256_engine() -> Box<dyn Hash> {
+    super::hash("sha256").unwrap() // built in
+}
+
+/// Returns the HMAC-SHA256 of `data` keyed with `key`.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; DIGEST_SIZE] {
+    let mut h = Hmac::new(sha256_engine(), key);
+    h.update(data);
+    let mut out = [0; DIGEST_SIZE];
+    h.finish(&mut out);
+    out
+}
+
+/// Derives `out.len()` bytes of key material from the password which `prf` is keyed with using
+/// PBKDF2.
+pub fn pbkdf2(prf: &Hmac, salt: &[u8], iterations: u32, out: &mut [u8]) {
+    let len = prf.output_size();
+    let mut u = vec![0u8; len];
+    let mut t = vec![0u8; len];
+    for (i, block) in out.chunks_mut(len).enumerate() {
+        let mut h = prf.clone();
+        h.update(salt);
+        h.update(&(i as u32 + 1).to_be_bytes());
+        h.finish(&mut u);
+        t.copy_from_slice(&u);
+        for _ in 1..iterations {
+            let mut h = prf.clone();
+            h.update(&u);
+            h.finish(&mut u);
+            for (t, u) in t.iter_mut().zip(&u) {
+                *t ^= u;
+            }
+        }
+        block.copy_from_slice(&t[..block.len()]);
+    }
+    t.fill(0);
+    u.fill(0);
+}
+
+/// Derives `out.len()` bytes of key material from `password` using PBKDF2-HMAC-SHA256.
+pub fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+    pbkdf2(&Hmac::new(sha256_engine(), password), salt, iterations, out)
+}
@@ -0,0 +1,71 @@
+//! XTS mode (IEEE 1619) as used for disk encryption.
+
+use super::aes::{xor, Block, BLOCK_SIZE};
+use super::{BlockCipher, CryptoError};
+use alloc::boxed::Box;
+
+/// AES in XTS mode.
+///
+/// Each sector is encrypted using a tweak derived from its sector number so that identical
+/// sectors encrypt differently. Sectors must be a multiple of [BLOCK_SIZE], ciphertext stealing
+/// is not implemented.
+pub struct Xts {
+    data: Box<dyn BlockCipher>,
+    tweak: Box<dyn BlockCipher>,
+}
+
+impl Xts {
+    /// `key` contains the data key followed by the tweak key, it must be 32 bytes for AES-128 or
+    /// 64 bytes for AES-256.
+    pub fn new(key: &[u8]) -> Result<Self, CryptoError> {
+        let (data, tweak) = key.split_at(key.len() / 2);
+        Ok(Self { data: super::cipher("aes", data)?, tweak: super::cipher("aes", tweak)? })
+    }
+
+    fn initial_tweak(&self, sector: u64) -> Block {
+        let mut t = [0; BLOCK_SIZE];
+        t[..8].copy_from_slice(&sector.to_le_bytes());
+        self.tweak.encrypt_blocks(&mut t);
+        t
+    }
+
+    /// Multiplies the tweak by the primitive element of GF(2^128).
+    fn next_tweak(t: &mut Block) {
+        let carry = t[15] >> 7;
+        for i in (1..BLOCK_SIZE).rev() {
+            t[i] = (t[i] << 1) | (t[i - 1] >> 7);
+        }
+        t[0] = (t[0] << 1) ^ (carry * 0x87);
+    }
+
+    /// XORs each block of `data` with its tweak starting from `t`.
+    fn apply_tweaks(mut t: Block, data: &mut [u8]) {
+        for b in data.chunks_exact_mut(BLOCK_SIZE) {
+            xor(b.try_into().unwrap(), &t);
+            Self::next_tweak(&mut t);
+        }
+    }
+
+    /// Encrypts `data` in place as sector number `sector`.
+    pub fn encrypt_sector(&self, sector: u64, data: &mut [u8]) {
+        self.sector(sector, data, false)
+    }
+
+    /// Decrypts `data` in place as sector number `sector`.
+    pub fn decrypt_sector(&self, sector: u64, data: &mut [u8]) {
+        self.sector(sector, data, true)
+    }
+
+    fn sector(&self, sector: u64, data: &mut [u8], decrypt: bool) {
+        // The tweaks are applied in separate passes so the whole sector is given to the cipher at
+        // once, the tweaks are cheap to recompute.
+        let t = self.initial_tweak(sector);
+        Self::apply_tweaks(t, data);
+        if decrypt {
+            self.data.decrypt_blocks(data);
+        } else {
+            self.data.encrypt_blocks(data);
+        }
+        Self::apply_tweaks(t, data);
+    }
+}
@@ -0,0 +1,326 @@
+//! Kernel error type.
+//!
+//! [KernelError] records where an error originated so that failures can be diagnosed from the log.
+//! Each error carries an [Errno], the subsystem which returned it, optionally the device and a
+//! description of the operation which failed, and the error which caused it. Layers which receive
+//! an error from a lower layer should wrap it using [ErrorContext::context] rather than replacing
+//! it, the [Display](core::fmt::Display) implementation prints the whole chain.
+//!
+//! Traits such as [crate::fs::file::Read] return [IoError], a [KernelError] is converted into an
+//! [IoError] using its errno at these boundaries. Each [IoError] variant has its own errno so this
+//! conversion is lossless, the remaining context is lost and should be logged before the boundary
+//! if it is needed.
+
+use crate::fs::vfs::VfsError;
+use crate::fs::IoError;
+use crate::system::sysfs::block::BlockDevIoErr;
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+
+/// Error codes, the values are the same as Linux on x86_64.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[repr(i32)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Errno {
+    /// Not present, [IoError::NotPresent].
+    ENOENT = 2,
+    /// I/O error, [IoError::MediaError].
+    EIO = 5,
+    /// Device error, [IoError::DeviceError].
+    ENXIO = 6,
+    /// File is not open, [IoError::NotReady].
+    EBADF = 9,
+    /// Operation would block, [IoError::WouldBlock].
+    EAGAIN = 11,
+    /// Permission denied, [IoError::PermissionDenied].
+    EACCES = 13,
+    /// Resource busy, [IoError::Busy].
+    EBUSY = 16,
+    /// File exists, [IoError::AlreadyExists].
+    EEXIST = 17,
+    /// Link across filesystems, [IoError::CrossDevice].
+    EXDEV = 18,
+    /// Operation not supported by device file, [IoError::IsDevice].
+    ENODEV = 19,
+    /// Not a directory, converted into [IoError::NotPresent].
+    ENOTDIR = 20,
+    /// Is a directory, [IoError::IsDirectory].
+    EISDIR = 21,
+    /// Invalid argument, [IoError::InvalidData].
+    EINVAL = 22,
+    /// Resource limit reached, [IoError::LimitExceeded].
+    EMFILE = 24,
+    /// Read only, [IoError::ReadOnly].
+    EROFS = 30,
+    /// Deadlock, [IoError::Deadlock].
+    EDEADLK = 35,
+    /// File is locked, [IoError::Exclusive].
+    ENOLCK = 37,
+    /// Directory not empty, [IoError::NotEmpty].
+    ENOTEMPTY = 39,
+    /// End of file, [IoError::EndOfFile].
+    ENODATA = 61,
+    /// Not supported, [IoError::NotSupported].
+    EOPNOTSUPP = 95,
+    /// Quota exceeded, [IoError::QuotaExceeded].
+    EDQUOT = 122,
+}
+
+impl Errno {
+    /// Returns a short description of the error.
+    pub fn description(&self) -> &'static str {
+        match self {
+            Errno::ENOENT => "Not present",
+            Errno::EIO => "I/O error",
+            Errno::ENXIO => "Device error",
+            Errno::EBADF => "File not open",
+            Errno::EAGAIN => "Operation would block",
+            Errno::EACCES => "Permission denied",
+            Errno::EBUSY => "Resource busy",
+            Errno::EEXIST => "Already exists",
+            Errno::EXDEV => "Cross-device link",
+            Errno::ENODEV => "Not supported by device file",
+            Errno::ENOTDIR => "Not a directory",
+            Errno::EISDIR => "Is a directory",
+            Errno::EINVAL => "Invalid argument",
+            Errno::EROFS => "Read only",
+            Errno::EDEADLK => "Deadlock",
+            Errno::ENOLCK => "Locked",
+            Errno::ENOTEMPTY => "Directory not empty",
+            Errno::ENODATA => "End of file",
+            Errno::EMFILE => "Resource limit reached",
+            Errno::EOPNOTSUPP => "Not supported",
+            Errno::EDQUOT => "Quota exceeded",
+        }
+    }
+}
+
+impl core::fmt::Display for Errno {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} ({self:?})", self.description())
+    }
+}
+
+impl From<IoError> for Errno {
+    fn from(value: IoError) -> Self {
+        value.errno()
+    }
+}
+
+impl From<Errno> for IoError {
+    fn from(value: Errno) -> Self {
+        match value {
+            Errno::ENOENT | Errno::ENOTDIR => IoError::NotPresent,
+            Errno::EIO => IoError::MediaError,
+            Errno::ENXIO => IoError::DeviceError,
+            Errno::EOPNOTSUPP => IoError::NotSupported,
+            Errno::ENOLCK => IoError::Exclusive,
+            Errno::ENODATA => IoError::EndOfFile,
+            Errno::EEXIST => IoError::AlreadyExists,
+            Errno::ENOTEMPTY => IoError::NotEmpty,
+            Errno::ENODEV => IoError::IsDevice,
+            Errno::EROFS => IoError::ReadOnly,
+            Errno::EBUSY => IoError::Busy,
+            Errno::EBADF => IoError::NotReady,
+            Errno::EINVAL => IoError::InvalidData,
+            Errno::EAGAIN => IoError::WouldBlock,
+            Errno::EACCES => IoError::PermissionDenied,
+            Errno::EDEADLK => IoError::Deadlock,
+            Errno::EMFILE => IoError::LimitExceeded,
+            Errno::EXDEV => IoError::CrossDevice,
+            Errno::EISDIR => IoError::IsDirectory,
+            Errno::EDQUOT => IoError::QuotaExceeded,
+        }
+    }
+}
+
+impl IoError {
+    /// Returns the errno for this error.
+    pub fn errno(&self) -> Errno {
+        match self {
+            IoError::NotPresent => Errno::ENOENT,
+            IoError::MediaError => Errno::EIO,
+            IoError::DeviceError => Errno::ENXIO,
+            IoError::NotSupported => Errno::EOPNOTSUPP,
+            IoError::Exclusive => Errno::ENOLCK,
+            IoError::EndOfFile => Errno::ENODATA,
+            IoError::AlreadyExists => Errno::EEXIST,
+            IoError::NotEmpty => Errno::ENOTEMPTY,
+            IoError::IsDevice => Errno::ENODEV,
+            IoError::ReadOnly => Errno::EROFS,
+            IoError::Busy => Errno::EBUSY,
+            IoError::NotReady => Errno::EBADF,
+            IoError::InvalidData => Errno::EINVAL,
+            IoError::WouldBlock => Errno::EAGAIN,
+            IoError::PermissionDenied => Errno::EACCES,
+            IoError::Deadlock => Errno::EDEADLK,
+            IoError::LimitExceeded => Errno::EMFILE,
+            IoError::CrossDevice => Errno::EXDEV,
+            IoError::IsDirectory => Errno::EISDIR,
+            IoError::QuotaExceeded => Errno::EDQUOT,
+        }
+    }
+}
+
+/// An error with the context in which it occurred.
+#[derive(Debug, Clone)]
+pub struct KernelError {
+    errno: Errno,
+    subsystem: &'static str,
+    device: Option<String>,
+    context: Option<Cow<'static, str>>,
+    source: Option<Box<KernelError>>,
+}
+
+impl KernelError {
+    /// Constructs an error which originated in `subsystem`.
+    pub fn new(subsystem: &'static str, errno: impl Into<Errno>) -> Self {
+        Self {
+            errno: errno.into(),
+            subsystem,
+            device: None,
+            context: None,
+            source: None,
+        }
+    }
+
+    /// Sets the device which the error occurred on.
+    pub fn with_device(mut self, device: impl core::fmt::Display) -> Self {
+        self.device = Some(device.to_string());
+        self
+    }
+
+    /// Describes the operation which failed.
+    pub fn with_context(mut self, context: impl Into<Cow<'static, str>>) -> Self {
+        self.context = Some(context.into());
+        self
+    }
+
+    /// Sets the error which caused `self`.
+    pub fn caused_by(mut self, source: KernelError) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// Returns a new error with the same errno which was caused by `self`.
+    pub fn wrap(self, subsystem: &'static str, context: impl Into<Cow<'static, str>>) -> KernelError {
+        KernelError::new(subsystem, self.errno).with_context(context).caused_by(self)
+    }
+
+    pub fn errno(&self) -> Errno {
+        self.errno
+    }
+
+    pub fn subsystem(&self) -> &'static str {
+        self.subsystem
+    }
+
+    pub fn device(&self) -> Option<&str> {
+        self.device.as_deref()
+    }
+
+    pub fn source(&self) -> Option<&KernelError> {
+        self.source.as_deref()
+    }
+
+    /// Returns `self` followed by each error which caused it.
+    pub fn chain(&self) -> impl Iterator<Item = &KernelError> {
+        core::iter::successors(Some(self), |e| e.source())
+    }
+
+    /// Returns the error at the end of the chain.
+    pub fn root_cause(&self) -> &KernelError {
+        self.chain().last().unwrap() // chain always contains self
+    }
+
+    fn fmt_one(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.subsystem)?;
+        if let Some(dev) = &self.device {
+            write!(f, " {dev}")?;
+        }
+        write!(f, ": ")?;
+        if let Some(context) = &self.context {
+            write!(f, "{context}: ")?;
+        }
+        write!(f, "{}", self.errno)
+    }
+}
+
+/// Formats the error followed by each error which caused it, e.g.
+/// `fetch: tftp://10.0.2.2/init -> /init: Not present (ENOENT), caused by tftp: ...`
+impl core::fmt::Display for KernelError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.fmt_one(f)?;
+        for e in self.chain().skip(1) {
+            write!(f, ", caused by ")?;
+            e.fmt_one(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<KernelError> for IoError {
+    fn from(value: KernelError) -> Self {
+        value.errno.into()
+    }
+}
+
+impl From<VfsError> for KernelError {
+    fn from(value: VfsError) -> Self {
+        let errno = match value {
+            VfsError::NotADirectory(_) => Errno::ENOTDIR,
+            VfsError::DoesNotExist(_) => Errno::ENOENT,
+            VfsError::InvalidArg | VfsError::PathFrameError => Errno::EINVAL,
+            VfsError::LowerLevel(e) => e.errno(),
+        };
+        KernelError::new("vfs", errno)
+    }
+}
+
+impl From<BlockDevIoErr> for KernelError {
+    fn from(value: BlockDevIoErr) -> Self {
+        let context = match value {
+            BlockDevIoErr::Misaligned => "Misaligned access",
+            BlockDevIoErr::OutOfRange => "Out of range",
+            BlockDevIoErr::GeomError => "Geometry error",
+            BlockDevIoErr::HardwareError => "Hardware error",
+            BlockDevIoErr::InternalDriverErr => "Driver error",
+            BlockDevIoErr::DeviceOffline => "Device offline",
+            BlockDevIoErr::ReadOnly => "Read only",
+            BlockDevIoErr::NotSupported => "Not supported",
+        };
+        KernelError::new("block", IoError::from(value)).with_context(context)
+    }
+}
+
+/// Adds context to the error in a [Result].
+pub trait ErrorContext<T> {
+    /// Converts the error into a [KernelError] which occurred in `subsystem` while performing `context`.
+    ///
+    /// If the error is already a [KernelError] it becomes the cause of a new error with the same errno.
+    fn context(self, subsystem: &'static str, context: impl Into<Cow<'static, str>>) -> Result<T, KernelError>;
+
+    /// Like [Self::context] but `context` is only evaluated when `self` is an error.
+    fn with_context<C: Into<Cow<'static, str>>>(self, subsystem: &'static str, context: impl FnOnce() -> C) -> Result<T, KernelError>;
+}
+
+impl<T> ErrorContext<T> for Result<T, IoError> {
+    fn context(self, subsystem: &'static str, context: impl Into<Cow<'static, str>>) -> Result<T, KernelError> {
+        self.map_err(|e| KernelError::new(subsystem, e).with_context(context))
+    }
+
+    fn with_context<C: Into<Cow<'static, str>>>(self, subsystem: &'static str, context: impl FnOnce() -> C) -> Result<T, KernelError> {
+        self.map_err(|e| KernelError::new(subsystem, e).with_context(context()))
+    }
+}
+
+impl<T> ErrorContext<T> for Result<T, KernelError> {
+    fn context(self, subsystem: &'static str, context: impl Into<Cow<'static, str>>) -> Result<T, KernelError> {
+        self.map_err(|e| e.wrap(subsystem, context))
+    }
+
+    fn with_context<C: Into<Cow<'static, str>>>(self, subsystem: &'static str, context: impl FnOnce() -> C) -> Result<T, KernelError> {
+        self.map_err(|e| e.wrap(subsystem, context()))
+    }
+}
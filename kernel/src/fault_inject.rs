@@ -0,0 +1,154 @@
+//! Fault injection.
+//!
+//! Fault points are placed where failures are rare in practice so that the code handling them can
+//! be exercised. Each point is controlled by a [Tunable] which may be set on the kernel command line
+//! or at runtime, see [crate::config].
+//!
+//! - `fault.heap`: Kernel heap allocations return null.
+//! - `fault.frame`: Physical frame allocations fail, this only applies after the frame allocator
+//!   has been fully initialized.
+//! - `fault.block`: Block device reads and writes fail with [BlockDevIoErr::HardwareError].
+//! - `fault.net_tx`: Packet transmission fails with [crate::fs::IoError::DeviceError].
+//! - `fault.net_rx`: Received packets are dropped.
+//!
+//! A point is set to `off`, to a probability such as `0.5%`, or to `#N` which fails only the Nth
+//! call after the point was set. Probabilities are drawn from a generator with a fixed seed, so a
+//! boot with the same configuration fails roughly the same calls.
+//!
+//! Most heap allocations are infallible and a failure calls the allocation error handler, which
+//! panics, so `fault.heap` is mostly useful with `#N` to target a known fallible allocation.
+//!
+//! [BlockDevIoErr::HardwareError]: crate::system::sysfs::block::BlockDevIoErr::HardwareError
+
+use crate::config::Tunable;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Probabilities are given in parts per million.
+const PPM: u32 = 1_000_000;
+
+/// When a fault point fails.
+///
+/// This is aligned so that its tunable is lock free, fault points are checked by the allocators
+/// and from interrupt handlers.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[repr(align(8))]
+pub enum FaultSpec {
+    #[default]
+    Off,
+    /// Fails each call with this probability in parts per million.
+    Probability(u32),
+    /// Fails only the Nth call.
+    Nth(u32),
+}
+
+impl core::str::FromStr for FaultSpec {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "off" {
+            return Ok(Self::Off);
+        }
+        if let Some(n) = s.strip_prefix('#') {
+            return match n.parse() {
+                Ok(0) | Err(_) => Err(()),
+                Ok(n) => Ok(Self::Nth(n)),
+            };
+        }
+
+        let percent = s.strip_suffix('%').ok_or(())?;
+        let (whole, frac) = percent.split_once('.').unwrap_or((percent, ""));
+        if frac.len() > 4 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(());
+        }
+        let whole: u32 = whole.parse().map_err(|_| ())?;
+        // Pads the fraction to 4 digits, 1% is 10_000ppm
+        let frac = frac.bytes().chain(core::iter::repeat(b'0')).take(4).fold(0, |acc, b| acc * 10 + (b - b'0') as u32);
+        let ppm = whole.checked_mul(10_000).and_then(|w| w.checked_add(frac)).filter(|p| *p <= PPM).ok_or(())?;
+        Ok(if ppm == 0 { Self::Off } else { Self::Probability(ppm) })
+    }
+}
+
+impl core::fmt::Display for FaultSpec {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Off => f.write_str("off"),
+            Self::Probability(ppm) => {
+                write!(f, "{}", ppm / 10_000)?;
+                let (mut frac, mut width) = (ppm % 10_000, 4);
+                if frac != 0 {
+                    while frac % 10 == 0 {
+                        frac /= 10;
+                        width -= 1;
+                    }
+                    write!(f, ".{frac:0width$}")?;
+                }
+                f.write_str("%")
+            }
+            Self::Nth(n) => write!(f, "#{n}"),
+        }
+    }
+}
+
+/// A location where faults may be injected.
+pub struct FaultPoint {
+    pub spec: Tunable<FaultSpec>,
+    /// Calls since `spec` was last set.
+    calls: AtomicU64,
+    injected: AtomicU64,
+}
+
+impl FaultPoint {
+    const fn new(name: &'static str, description: &'static str, on_set: fn(FaultSpec)) -> Self {
+        Self {
+            spec: Tunable::with_hook(name, description, FaultSpec::Off, on_set),
+            calls: AtomicU64::new(0),
+            injected: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns whether the caller should fail this call.
+    ///
+    /// This does not allocate or lock, so it may be called from allocators and interrupt handlers.
+    #[inline]
+    pub fn should_fail(&self) -> bool {
+        let fail = match self.spec.get() {
+            FaultSpec::Off => return false,
+            FaultSpec::Probability(ppm) => random() % PPM < ppm,
+            FaultSpec::Nth(n) => self.calls.fetch_add(1, Ordering::Relaxed) + 1 == n as u64,
+        };
+        if fail {
+            self.injected.fetch_add(1, Ordering::Relaxed);
+        }
+        fail
+    }
+
+    /// Returns the number of faults injected at this point.
+    pub fn injected(&self) -> u64 {
+        self.injected.load(Ordering::Relaxed)
+    }
+
+    fn reset(&self) {
+        self.calls.store(0, Ordering::Relaxed);
+    }
+}
+
+pub static HEAP: FaultPoint = FaultPoint::new("fault.heap", "Fail kernel heap allocations", |_| HEAP.reset());
+pub static FRAME: FaultPoint = FaultPoint::new("fault.frame", "Fail physical frame allocations", |_| FRAME.reset());
+pub static BLOCK: FaultPoint = FaultPoint::new("fault.block", "Fail block device requests", |_| BLOCK.reset());
+pub static NET_TX: FaultPoint = FaultPoint::new("fault.net_tx", "Fail packet transmission", |_| NET_TX.reset());
+pub static NET_RX: FaultPoint = FaultPoint::new("fault.net_rx", "Drop received packets", |_| NET_RX.reset());
+
+static SEED: AtomicU64 = AtomicU64::new(0x9e37_79b9_7f4a_7c15);
+
+/// Xorshift generator shared by all fault points.
+fn random() -> u32 {
+    let next = |mut x: u64| {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        x
+    };
+    // The closure always returns `Some`
+    let old = SEED.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(next(x))).unwrap();
+    (next(old) >> 32) as u32
+}
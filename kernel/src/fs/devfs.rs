@@ -0,0 +1,105 @@
+//! Device file system.
+//!
+//! The devfs is a [tmpfs](super::tmpfs) instance mounted at [DEV_PATH] when the VFS is initialized.
+//! Drivers register their device files with [register] which creates a node named according to
+//! the [naming] policy, so drivers do not need to choose their own paths.
+//!
+//! Devices which are removed from the system, e.g. by hot-unplug, must be removed using
+//! [unregister], this removes the node and releases its name for the next device of the same class.
+
+pub mod naming;
+
+use super::device::DeviceFile;
+use super::vfs::{DevID, MountFlags, VfsError};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use naming::DeviceClass;
+
+pub const DEV_PATH: &str = "/dev";
+
+static STATE: spin::Mutex<DevFsState> = spin::Mutex::new(DevFsState {
+    indices: naming::Indices::new(),
+    nodes: BTreeMap::new(),
+});
+
+struct DevFsState {
+    indices: naming::Indices,
+    nodes: BTreeMap<DevID, Node>,
+}
+
+struct Node {
+    name: String,
+    class: DeviceClass,
+    index: usize,
+}
+
+/// Mounts the devfs.
+pub(super) fn init() {
+    crate::task::util::block_on!(super::get_vfs().mount(super::tmpfs::TmpFsRoot::new(), DEV_PATH, MountFlags::empty(), ""))
+        .expect("Failed to mount devfs");
+}
+
+/// Creates a node for `dev` in the devfs named according to `class`, returns the name of the node.
+///
+/// # Errors
+///
+/// - Returns [VfsError::InvalidArg] when `class` is a [DeviceClass::Partition] whose disk is not registered.
+/// - Any error returned by [super::vfs::VirtualFileSystem::mount_dev].
+pub async fn register(dev: Box<dyn DeviceFile>, class: DeviceClass) -> Result<String, VfsError> {
+    let id = dev.device();
+    let (name, index) = {
+        let mut state = STATE.lock();
+        let parent = match class {
+            DeviceClass::Partition { disk, .. } => Some(state.nodes.get(&disk).ok_or(VfsError::InvalidArg)?.name.clone()),
+            _ => None,
+        };
+        let index = if class.is_indexed() { state.indices.alloc(&class) } else { 0 };
+        (class.name(index, parent.as_deref()), index)
+    };
+
+    if let Err(e) = super::get_vfs().mount_dev(dev, &alloc::format!("{DEV_PATH}/{name}")).await {
+        if class.is_indexed() {
+            STATE.lock().indices.release(&class, index);
+        }
+        return Err(e);
+    }
+    STATE.lock().nodes.insert(id, Node { name: name.clone(), class, index });
+    Ok(name)
+}
+
+/// Removes the node for the device `id` and returns its device file.
+///
+/// If `id` is a disk the nodes for its partitions are also removed.
+pub async fn unregister(id: DevID) -> Result<Box<dyn DeviceFile>, VfsError> {
+    let partitions: Vec<DevID> = STATE
+        .lock()
+        .nodes
+        .iter()
+        .filter(|(_, n)| matches!(n.class, DeviceClass::Partition { disk, .. } if disk == id))
+        .map(|(id, _)| *id)
+        .collect();
+    for p in partitions {
+        remove_node(p).await?;
+    }
+    remove_node(id).await
+}
+
+async fn remove_node(id: DevID) -> Result<Box<dyn DeviceFile>, VfsError> {
+    let path = alloc::format!("{DEV_PATH}/{}", name_of(id).ok_or(VfsError::DoesNotExist(1))?);
+    let dev = super::get_vfs().remove_dev(&path).await?;
+
+    let mut state = STATE.lock();
+    if let Some(node) = state.nodes.remove(&id) {
+        if node.class.is_indexed() {
+            state.indices.release(&node.class, node.index);
+        }
+    }
+    Ok(dev)
+}
+
+/// Returns the name of the node for the device `id`.
+pub fn name_of(id: DevID) -> Option<String> {
+    STATE.lock().nodes.get(&id).map(|n| n.name.clone())
+}
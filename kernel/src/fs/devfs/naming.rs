@@ -0,0 +1,138 @@
+//! Device node naming policy.
+//!
+//! Nodes are named by their [DeviceClass] followed by an index, the lowest free index for a class
+//! is allocated when a device is registered and released when it is removed. Disks are named using
+//! letters instead of numbers, partitions are named after the disk they belong to.
+//!
+//! | Class                      | Names                |
+//! |----------------------------|----------------------|
+//! | [DeviceClass::Serial]      | `ttyS0`, `ttyS1`     |
+//! | [DeviceClass::Console]     | `tty0`, `tty1`       |
+//! | [DeviceClass::Disk]        | `sda`, `sdz`, `sdaa` |
+//! | [DeviceClass::Partition]   | `sda1`, `nvme0n1p1`  |
+//! | [DeviceClass::Loop]        | `loop0`, `loop1`     |
+//! | [DeviceClass::Md]          | `md0`, `md1`         |
+//! | [DeviceClass::Mapped]      | `dm-0`, `dm-1`       |
+//! | [DeviceClass::Framebuffer] | `fb0`                |
+//! | [DeviceClass::Input]       | `input0`             |
+//! | [DeviceClass::Network]     | `eth0`, `eth1`       |
+//! | [DeviceClass::Capture]     | `pcap0`, `pcap1`     |
+//! | [DeviceClass::Hypervisor]  | `hvc0`, `hvc1`       |
+//! | [DeviceClass::Channel]     | `vport0`, `vport1`   |
+//! | [DeviceClass::Fixed]       | The given name       |
+
+use crate::fs::vfs::DevID;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::{String, ToString};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DeviceClass {
+    Serial,
+    Console,
+    Disk,
+    /// Partition `number` of the disk registered as `disk`. Partitions are numbered from 1.
+    Partition { disk: DevID, number: usize },
+    /// A block device backed by a file, see [crate::system::sysfs::block::loop_dev].
+    Loop,
+    /// A software RAID array, see [crate::system::sysfs::block::md].
+    Md,
+    /// A device composed from a table of targets, see [crate::system::sysfs::block::dm].
+    Mapped,
+    Framebuffer,
+    Input,
+    /// A network interface, see [crate::net::interface].
+    Network,
+    /// A packet capture, see [crate::net::capture].
+    Capture,
+    /// A console provided by the hypervisor.
+    Hypervisor,
+    /// An unnamed host communication channel, such as a virtio-serial port.
+    Channel,
+    /// A device of which there is only ever one instance, which is named as given.
+    Fixed(&'static str),
+}
+
+impl DeviceClass {
+    fn prefix(&self) -> &'static str {
+        match self {
+            DeviceClass::Serial => "ttyS",
+            DeviceClass::Console => "tty",
+            DeviceClass::Disk => "sd",
+            DeviceClass::Loop => "loop",
+            DeviceClass::Md => "md",
+            DeviceClass::Mapped => "dm-",
+            DeviceClass::Framebuffer => "fb",
+            DeviceClass::Input => "input",
+            DeviceClass::Network => "eth",
+            DeviceClass::Capture => "pcap",
+            DeviceClass::Hypervisor => "hvc",
+            DeviceClass::Channel => "vport",
+            DeviceClass::Partition { .. } | DeviceClass::Fixed(_) => "",
+        }
+    }
+
+    /// Returns whether nodes of this class are allocated an index.
+    pub fn is_indexed(&self) -> bool {
+        !matches!(self, DeviceClass::Partition { .. } | DeviceClass::Fixed(_))
+    }
+
+    /// Returns the node name for the device with the index `index`.
+    ///
+    /// `parent` is the node name of the parent device, this is only used by [DeviceClass::Partition]
+    /// and must be given for it.
+    pub fn name(&self, index: usize, parent: Option<&str>) -> String {
+        match self {
+            DeviceClass::Disk => alloc::format!("{}{}", self.prefix(), disk_letters(index)),
+            DeviceClass::Partition { number, .. } => partition_name(parent.expect("Partition requires a parent name"), *number),
+            DeviceClass::Fixed(name) => name.to_string(),
+            _ => alloc::format!("{}{index}", self.prefix()),
+        }
+    }
+}
+
+/// Converts a disk index into its letters, 0 is `a`, 25 is `z`, 26 is `aa`.
+fn disk_letters(mut index: usize) -> String {
+    let mut letters = String::new();
+    loop {
+        letters.insert(0, (b'a' + (index % 26) as u8) as char);
+        if index < 26 {
+            break;
+        }
+        index = index / 26 - 1;
+    }
+    letters
+}
+
+/// Disks whose names end in a digit separate the partition number with a `p`.
+fn partition_name(disk: &str, number: usize) -> String {
+    if disk.ends_with(|c: char| c.is_ascii_digit()) {
+        alloc::format!("{disk}p{number}")
+    } else {
+        alloc::format!("{disk}{number}")
+    }
+}
+
+/// Tracks the indices in use by each device class.
+pub(super) struct Indices {
+    used: BTreeMap<&'static str, BTreeSet<usize>>,
+}
+
+impl Indices {
+    pub(super) const fn new() -> Self {
+        Self { used: BTreeMap::new() }
+    }
+
+    /// Allocates the lowest free index for `class`.
+    pub(super) fn alloc(&mut self, class: &DeviceClass) -> usize {
+        let used = self.used.entry(class.prefix()).or_default();
+        let index = (0..).find(|i| !used.contains(i)).unwrap(); // the set is finite
+        used.insert(index);
+        index
+    }
+
+    pub(super) fn release(&mut self, class: &DeviceClass, index: usize) {
+        if let Some(used) = self.used.get_mut(class.prefix()) {
+            used.remove(&index);
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use core::fmt::Formatter;
+use futures_util::FutureExt;
 use super::file::*;
 
 /// This is a marker trait for files which act as special device (character/block) files.
@@ -17,7 +18,137 @@ use super::file::*;
 ///
 /// Because a device file may need to be accessed from within the kernel it may not be locked.
 /// Attempting to call [NormalFile::file_lock] on a device file must return [super::IoError::NotSupported].
-pub trait DeviceFile: File {}
+pub trait DeviceFile: File {
+    /// Performs a structured control operation on the device.
+    ///
+    /// Drivers should use this instead of B-side files for requests which do not map cleanly to a
+    /// text format. Requests which the driver does not recognise must return
+    /// [super::IoError::NotSupported], which is what the default implementation does.
+    ///
+    /// Control requests may modify the device, callers acting on behalf of a user must check that
+    /// the user has write access to the file before calling this.
+    fn control<'f, 'a: 'f>(&'a mut self, _request: DeviceCtl) -> super::IoResult<'f, CtlResponse> {
+        async { Err(super::IoError::NotSupported) }.boxed()
+    }
+}
+
+/// A request for [DeviceFile::control]. Requests are grouped by the type of device they apply to.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum DeviceCtl {
+    Block(BlockCtl),
+    Serial(crate::serial::SerialCtl),
+}
+
+#[derive(Debug, Clone)]
+pub enum BlockCtl {
+    /// Returns [CtlResponse::Geometry].
+    GetGeometry,
+    /// Indicates that the blocks `start..start + count` no longer contain useful data.
+    Trim { start: u64, count: u64 },
+    /// Sets whether accesses by this file object bypass the page cache.
+    SetDirect(bool),
+    /// Replaces the device firmware with `image`, this completes once the new firmware is active.
+    ///
+    /// This requires privileged credentials regardless of the file permissions, `cred` must be
+    /// the credentials of the caller.
+    UpdateFirmware {
+        image: alloc::sync::Arc<[u8]>,
+        cred: super::perm::Credentials,
+    },
+    /// Returns [CtlResponse::Progress] for the current or most recent firmware update.
+    FirmwareProgress,
+}
+
+#[derive(Debug, Clone)]
+pub enum CtlResponse {
+    None,
+    Value(u64),
+    Geometry(crate::system::sysfs::block::BlockDevGeom),
+    /// `done` out of `total` bytes have been processed.
+    Progress { done: u64, total: u64, active: bool },
+}
+
+impl DeviceCtl {
+    const GROUP_BLOCK: u16 = 1;
+    const GROUP_SERIAL: u16 = 2;
+
+    /// Decodes a request given through the system call interface.
+    ///
+    /// The upper 16 bits of `request` select the group and the lower 16 bits select the request
+    /// within the group. `arg` contains the arguments of the request as little-endian `u64`s.
+    ///
+    /// | Group | Request | Arguments      | Decodes to                      |
+    /// |-------|---------|----------------|---------------------------------|
+    /// | 1     | 0       |                | [BlockCtl::GetGeometry]         |
+    /// | 1     | 1       | `start, count` | [BlockCtl::Trim]                |
+    /// | 1     | 2       | `enable`       | [BlockCtl::SetDirect]           |
+    /// | 1     | 3       |                | [BlockCtl::FirmwareProgress]    |
+    /// | 2     | 0       | `enable`       | [crate::serial::SerialCtl::SetBreak] |
+    /// | 2     | 1       |                | [crate::serial::SerialCtl::GetBaud]  |
+    /// | 2     | 2       | `baud`         | [crate::serial::SerialCtl::SetBaud]  |
+    ///
+    /// [BlockCtl::UpdateFirmware] cannot be decoded because it must carry the caller's credentials,
+    /// the caller must construct it itself, see `/bin/fwupdate` in
+    /// [crate::system::sysfs::block::dev_file].
+    ///
+    /// Returns [super::IoError::NotSupported] if the request is unknown and [super::IoError::InvalidData]
+    /// if `arg` is too short or contains an invalid value.
+    pub fn from_raw(request: u32, arg: &[u8]) -> Result<Self, super::IoError> {
+        use crate::serial::SerialCtl;
+        let arg = |n: usize| -> Result<u64, super::IoError> {
+            let b = arg.get(n * 8..(n + 1) * 8).ok_or(super::IoError::InvalidData)?;
+            Ok(u64::from_le_bytes(b.try_into().unwrap()))
+        };
+
+        let r = match ((request >> 16) as u16, request as u16) {
+            (Self::GROUP_BLOCK, 0) => Self::Block(BlockCtl::GetGeometry),
+            (Self::GROUP_BLOCK, 1) => Self::Block(BlockCtl::Trim { start: arg(0)?, count: arg(1)? }),
+            (Self::GROUP_BLOCK, 2) => Self::Block(BlockCtl::SetDirect(arg(0)? != 0)),
+            (Self::GROUP_BLOCK, 3) => Self::Block(BlockCtl::FirmwareProgress),
+            (Self::GROUP_SERIAL, 0) => Self::Serial(SerialCtl::SetBreak(arg(0)? != 0)),
+            (Self::GROUP_SERIAL, 1) => Self::Serial(SerialCtl::GetBaud),
+            (Self::GROUP_SERIAL, 2) => Self::Serial(SerialCtl::SetBaud(arg(0)?.try_into().map_err(|_| super::IoError::InvalidData)?)),
+            _ => return Err(super::IoError::NotSupported),
+        };
+        Ok(r)
+    }
+}
+
+impl CtlResponse {
+    /// Encodes `self` as little-endian `u64`s into `buff` for returning through the system call
+    /// interface. Returns the number of bytes written.
+    ///
+    /// [CtlResponse::Geometry] is encoded as the fields of [crate::system::sysfs::block::BlockDevGeom] in declaration order.
+    /// [CtlResponse::Progress] is encoded as `done, total, active`.
+    ///
+    /// Returns [super::IoError::EndOfFile] if `buff` is too small.
+    pub fn encode(&self, buff: &mut [u8]) -> Result<usize, super::IoError> {
+        let mut values = alloc::vec::Vec::new();
+        match self {
+            CtlResponse::None => {}
+            CtlResponse::Value(v) => values.push(*v),
+            CtlResponse::Geometry(g) => values.extend_from_slice(&[
+                g.blocks,
+                g.block_size,
+                g.optimal_block_size,
+                g.optimal_alignment,
+                g.max_blocks_per_transfer,
+                g.req_data_alignment as u64,
+            ]),
+            CtlResponse::Progress { done, total, active } => values.extend_from_slice(&[*done, *total, *active as u64]),
+        }
+
+        let len = values.len() * 8;
+        if buff.len() < len {
+            return Err(super::IoError::EndOfFile);
+        }
+        for (chunk, v) in buff.chunks_exact_mut(8).zip(values) {
+            chunk.copy_from_slice(&v.to_le_bytes());
+        }
+        Ok(len)
+    }
+}
 
 file_derive_debug!(DeviceFile);
 
@@ -144,6 +275,31 @@ pub trait FileSystem: DeviceFile {
     /// If this filesystem is created from a file, return the file path.
     fn raw_file(&self) -> Option<&str>;
 
+    /// Returns the usage accounting of the filesystem if it supports quotas, see [super::quota].
+    ///
+    /// The default implementation returns `None`.
+    fn quota(&self) -> Option<&super::quota::Quota> {
+        None
+    }
+
+    /// Returns the capacity and usage of the filesystem.
+    ///
+    /// [FsStat::flags](super::statfs::FsStat::flags) should be empty, the VFS sets it to the flags
+    /// the filesystem is mounted with.
+    fn statfs(&self) -> super::IoResult<super::statfs::FsStat>;
+
+    /// Copies the directory with the ID `dir` and everything below it into a new filesystem,
+    /// which may then be mounted. Modifications of either filesystem are not visible in the other.
+    ///
+    /// The copy must be atomic, it must not contain part of an operation which was running while
+    /// it was taken. This allows tests to capture the state of a filesystem and to roll back to it
+    /// by snapshotting the snapshot.
+    ///
+    /// The default implementation returns [IoError::NotSupported](super::IoError::NotSupported).
+    fn snapshot(&self, _dir: u64) -> super::IoResult<alloc::boxed::Box<dyn FileSystem>> {
+        async { Err(super::IoError::NotSupported) }.boxed()
+    }
+
     /// This fn is called when the filesystem is unmounted from the VFS.
     /// Its purpose is to performs a clean-up before handing it off, so it may be used detached within the kernel.
     /// This fn will **not** be called when the filesystem is "remounted".
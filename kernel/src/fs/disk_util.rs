@@ -0,0 +1,244 @@
+//! Utilities for provisioning disks.
+//!
+//! These operate on the raw contents of a disk through a [NormalFile], normally the block device
+//! file created in the devfs by [crate::system::sysfs::block::dev_file]. A [Disk] may be limited to
+//! a region of the file so that partitions created by [gpt] can be formatted before they have
+//! their own device files.
+//!
+//! - [gpt] creates and reads GUID partition tables.
+//! - [fat] formats and checks FAT32 filesystems.
+//! - [ext2] formats and checks ext2 filesystems.
+//! - [buffer_cache] caches filesystem metadata blocks, [fat] and [ext2] access their metadata
+//!   through it. Formatting writes the boot sector or superblock last so that an interrupted
+//!   format never leaves a disk which appears to contain a valid filesystem.
+//!
+//! The checkers do not repair filesystems, they return a [FsckReport] describing any problems found.
+//!
+//! Errors are returned as a [KernelError] naming the disk and the operation which failed.
+//!
+//! [read_exact] and [write_all] repeat file reads and writes until a whole buffer is transferred,
+//! they are also used outside of provisioning wherever a short transfer is not useful.
+
+pub mod gpt;
+pub mod fat;
+pub mod ext2;
+pub mod buffer_cache;
+
+use super::file::{NormalFile, Read, Write};
+use super::IoError;
+use crate::error::{ErrorContext, Errno, KernelError};
+use crate::mem::dma::{DmaBuff, DmaGuard, DmaSlice, DmaTarget};
+use alloc::boxed::Box;
+use alloc::borrow::Cow;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// Size of buffers used when filling large regions of a disk.
+const FILL_CHUNK: usize = crate::mem::PAGE_SIZE * 16;
+
+/// Number of blocks cached while formatting or checking a filesystem.
+const METADATA_CACHE_BLOCKS: usize = 64;
+
+/// A region of a file which is accessed as a disk.
+#[derive(Clone)]
+pub struct Disk<'a> {
+    file: &'a dyn NormalFile<u8>,
+    offset: u64,
+    len: u64,
+    sector_size: u64,
+}
+
+impl<'a> Disk<'a> {
+    /// Uses the entirety of `file` as a disk with the logical sector size `sector_size`.
+    pub async fn new(file: &'a dyn NormalFile<u8>, sector_size: u64) -> Result<Self, KernelError> {
+        assert!(sector_size.is_power_of_two(), "Sector size must be a power of two");
+        let len = file.len().await.context("disk", "Failed to get the size of the disk").map_err(|e| e.with_device(file.device()))?;
+        Ok(Self { file, offset: 0, len, sector_size })
+    }
+
+    /// Constructs an error which occurred on this disk.
+    fn error(&self, errno: impl Into<Errno>, context: impl Into<Cow<'static, str>>) -> KernelError {
+        KernelError::new("disk", errno).with_device(self.file.device()).with_context(context)
+    }
+
+    /// Returns a disk for the byte range `range` of `self`.
+    ///
+    /// # Panics
+    ///
+    /// This fn will panic if `range` extends beyond the end of `self`.
+    pub fn region(&self, range: Range<u64>) -> Self {
+        assert!(range.start <= range.end && range.end <= self.len, "Region out of bounds");
+        Self { file: self.file, offset: self.offset + range.start, len: range.end - range.start, sector_size: self.sector_size }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    /// Reads `len` bytes at `pos`. Returns [Errno::ENODATA] if the range is not entirely within the disk.
+    pub async fn read_at(&self, pos: u64, len: usize) -> Result<Vec<u8>, KernelError> {
+        if pos + len as u64 > self.len {
+            return Err(self.error(Errno::ENODATA, alloc::format!("Read of {len} bytes at {pos:#x} is beyond the end of the disk")));
+        }
+        read_exact(self.file, self.offset + pos, len).await.map_err(|e| match e {
+            IoError::EndOfFile => self.error(Errno::ENODATA, alloc::format!("Read of {len} bytes at {pos:#x} ended early")),
+            e => self.error(e, alloc::format!("Read of {len} bytes at {pos:#x} failed")),
+        })
+    }
+
+    /// Writes `data` at `pos`. Returns [Errno::ENODATA] if the range is not entirely within the disk.
+    pub async fn write_at(&self, pos: u64, data: &[u8]) -> Result<(), KernelError> {
+        let len = data.len();
+        if pos + len as u64 > self.len {
+            return Err(self.error(Errno::ENODATA, alloc::format!("Write of {len} bytes at {pos:#x} is beyond the end of the disk")));
+        }
+        let buff: DmaBuff<'static> = Box::new(DmaGuard::from(Vec::from(data)));
+        write_all(self.file, self.offset + pos, buff).await.map_err(|(e, _)| match e {
+            IoError::EndOfFile => self.error(Errno::ENODATA, alloc::format!("Write of {len} bytes at {pos:#x} ended early")),
+            e => self.error(e, alloc::format!("Write of {len} bytes at {pos:#x} failed")),
+        })?;
+        Ok(())
+    }
+
+    /// Makes everything written to the disk durable, see [NormalFile::sync].
+    pub async fn sync(&self) -> Result<(), KernelError> {
+        self.file.sync(false).await.map_err(|e| self.error(e, "Sync failed"))
+    }
+
+    /// Fills `range` with zeros.
+    pub async fn zero(&self, range: Range<u64>) -> Result<(), KernelError> {
+        let zeros = alloc::vec![0u8; FILL_CHUNK];
+        let mut pos = range.start;
+        while pos < range.end {
+            let n = (range.end - pos).min(FILL_CHUNK as u64) as usize;
+            self.write_at(pos, &zeros[..n]).await?;
+            pos += n as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Reads `len` bytes from `file` at `pos`.
+///
+/// Returns [IoError::EndOfFile] if the end of the file is reached before `len` bytes were read.
+pub async fn read_exact<F: Read<u8> + ?Sized>(file: &F, pos: u64, len: usize) -> Result<Vec<u8>, IoError> {
+    let mut buff: DmaBuff<'static> = Box::new(DmaGuard::from(alloc::vec![0u8; len]));
+    let mut out = Vec::with_capacity(len);
+    while out.len() < len {
+        let (b, count) = file.read(pos + out.len() as u64, buff).await.map_err(|(e, _, _)| e)?;
+        if count == 0 {
+            return Err(IoError::EndOfFile);
+        }
+        buff = b;
+        // SAFETY: as_mut guarantees that this is safe.
+        let data = unsafe { &*DmaTarget::as_mut(&mut *buff) };
+        out.extend_from_slice(&data[..count]);
+        buff = Box::new(DmaSlice::new(buff, 0..len - out.len()));
+    }
+    Ok(out)
+}
+
+/// Writes the entirety of `buff` to `file` at `pos`, returns the number of bytes written.
+///
+/// Returns [IoError::EndOfFile] if a write makes no progress. On error the number of bytes written
+/// before the error occurred is returned.
+pub async fn write_all<F: Write<u8> + ?Sized>(file: &F, pos: u64, mut buff: DmaBuff<'_>) -> Result<usize, (IoError, usize)> {
+    let len = buff.as_mut().len();
+    let mut done = 0;
+    while done < len {
+        let (b, count) = file.write(pos + done as u64, buff).await.map_err(|(e, _, n)| (e, done + n))?;
+        done += count;
+        if count == 0 {
+            return Err((IoError::EndOfFile, done));
+        }
+        buff = Box::new(DmaSlice::new(b, count..len - done + count));
+    }
+    Ok(done)
+}
+
+/// Problems found by a filesystem checker.
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// Inconsistencies which may cause data loss or prevent the filesystem from being used.
+    pub errors: Vec<String>,
+    /// Inconsistencies which are harmless or can be corrected by the filesystem driver, such as
+    /// incorrect free space counts.
+    pub warnings: Vec<String>,
+}
+
+impl FsckReport {
+    const MAX_MESSAGES: usize = 64;
+
+    pub fn is_clean(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    fn error(&mut self, args: core::fmt::Arguments) {
+        Self::push(&mut self.errors, args)
+    }
+
+    fn warn(&mut self, args: core::fmt::Arguments) {
+        Self::push(&mut self.warnings, args)
+    }
+
+    /// A badly damaged filesystem may produce an error for every cluster, only the first
+    /// [Self::MAX_MESSAGES] are kept.
+    fn push(list: &mut Vec<String>, args: core::fmt::Arguments) {
+        match list.len() {
+            n if n < Self::MAX_MESSAGES => list.push(alloc::fmt::format(args)),
+            n if n == Self::MAX_MESSAGES => list.push(String::from("Further messages omitted")),
+            _ => {}
+        }
+    }
+}
+
+/// Fills `buff` with random bytes for use as identifiers.
+///
+/// Uses `RDRAND` when it is available, otherwise falls back to a generator seeded with the system time.
+/// This is not suitable for cryptographic use.
+pub(crate) fn random_bytes(buff: &mut [u8]) {
+    let rdrand = x86_64::instructions::random::RdRand::new();
+    let mut state = crate::time::get_sys_time() ^ crate::time::rtc::realtime().rotate_left(32);
+    for chunk in buff.chunks_mut(8) {
+        let v = match rdrand.and_then(|r| r.get_u64()) {
+            Some(v) => v,
+            None => {
+                // xorshift64
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state
+            }
+        };
+        chunk.copy_from_slice(&v.to_le_bytes()[..chunk.len()]);
+    }
+}
+
+fn read_u16(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes(b[off..off + 2].try_into().unwrap())
+}
+
+fn read_u32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+fn read_u64(b: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(b[off..off + 8].try_into().unwrap())
+}
+
+fn put_u16(b: &mut [u8], off: usize, v: u16) {
+    b[off..off + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(b: &mut [u8], off: usize, v: u32) {
+    b[off..off + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put_u64(b: &mut [u8], off: usize, v: u64) {
+    b[off..off + 8].copy_from_slice(&v.to_le_bytes());
+}
@@ -0,0 +1,459 @@
+//! Block-granular cache for filesystem metadata.
+//!
+//! The block device page cache (see [crate::system::sysfs::block::dev_file]) caches whole pages
+//! of the device and is write-through, so it can't defer or order writes. A [BufferCache] caches
+//! individual filesystem blocks of a [Disk] and holds modified blocks until they are written back
+//! by [BufferCache::sync] or evicted. Filesystem code should access its metadata (superblocks,
+//! allocation tables, bitmaps, directory blocks) through a buffer cache rather than reading and
+//! writing the disk directly.
+//!
+//! - Buffers may be pinned using [BufferCache::pin], a pinned buffer is never evicted.
+//! - [BufferCache::order] records that one dirty block must reach the disk before another, e.g.
+//!   that the allocation bitmap must be written before the inode which uses the allocated block.
+//!   When a block is written back all the blocks it depends on are written first. Constraints
+//!   which would form a cycle are rejected with [Errno::EDEADLK].
+//! - A [Journal] may be attached, it is given every dirty block before any of them are written in
+//!   place. While a journal is attached dirty buffers are only written back by [BufferCache::sync],
+//!   eviction of a dirty buffer syncs the whole cache.
+//! - [BufferCache::sync] flushes the disk once the buffers have been written back, when it returns
+//!   the metadata is durable. Other write-back only writes the blocks.
+//!
+//! A cache delays [crate::system::shutdown] until it is dropped, so an operation which syncs its
+//! cache before returning is not interrupted by a shutdown.
+//!
+//! The cache is not coherent with other users of the disk, blocks written through the [Disk] or
+//! another cache are not seen by blocks which are already cached.
+//!
+//! Dirty buffers are charged to the disk's [DirtyAccount]. When the dirty limits are exceeded a
+//! task accessing the cache first writes back the least recently used dirty buffers, see
+//! [crate::fs::writeback].
+//!
+//! Buffers are [Page]s, blocks read from the disk are merged with identical cached pages by
+//! [page::merge] and copied when they are first modified.
+
+use super::Disk;
+use crate::error::{Errno, ErrorContext, KernelError};
+use crate::fs::writeback::{self, DirtyAccount};
+use crate::mem::page::{self, Page, PageFlags};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use futures_util::future::BoxFuture;
+
+/// Write-ahead hooks called by [BufferCache::sync].
+pub trait Journal: Send + Sync {
+    /// Called with the block number and contents of every dirty block, in write order, before any
+    /// of them are written in place. When this returns the blocks must be recoverable after a crash.
+    fn commit<'a>(&'a self, blocks: &'a [(u64, Box<[u8]>)]) -> BoxFuture<'a, Result<(), KernelError>>;
+
+    /// Called once every block given to [Self::commit] has been written in place.
+    fn checkpoint(&self) -> BoxFuture<'_, Result<(), KernelError>>;
+}
+
+pub struct BufferCache<'a> {
+    disk: Disk<'a>,
+    block_size: u64,
+    capacity: usize,
+    journal: Option<Box<dyn Journal + 'a>>,
+    inner: spin::Mutex<Inner>,
+    account: Arc<DirtyAccount>,
+    /// Delays shutdown until the cache is dropped, owners sync the cache before dropping it.
+    _inhibit: Option<crate::system::shutdown::Inhibitor>,
+}
+
+#[derive(Default)]
+struct Inner {
+    buffers: BTreeMap<u64, Buffer>,
+    /// Maps blocks to the dirty blocks which must be written before them.
+    after: BTreeMap<u64, BTreeSet<u64>>,
+    clock: u64,
+}
+
+struct Buffer {
+    page: Arc<Page>,
+    pins: usize,
+    /// Incremented on each modification, used to detect modifications during write-back.
+    generation: u64,
+    last_used: u64,
+}
+
+impl Buffer {
+    fn is_dirty(&self) -> bool {
+        self.page.flags().contains(PageFlags::DIRTY)
+    }
+}
+
+impl Inner {
+    /// Pins `block` if it is cached.
+    fn try_pin(&mut self, block: u64) -> bool {
+        self.clock += 1;
+        match self.buffers.get_mut(&block) {
+            Some(b) => {
+                b.pins += 1;
+                b.last_used = self.clock;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn is_dirty(&self, block: u64) -> bool {
+        self.buffers.get(&block).is_some_and(Buffer::is_dirty)
+    }
+
+    /// Returns whether `block` must be written after `other`, directly or indirectly.
+    fn depends_on(&self, block: u64, other: u64) -> bool {
+        let mut stack = alloc::vec![block];
+        let mut seen = BTreeSet::new();
+        while let Some(b) = stack.pop() {
+            for &d in self.after.get(&b).into_iter().flatten() {
+                if d == other {
+                    return true;
+                }
+                if seen.insert(d) {
+                    stack.push(d);
+                }
+            }
+        }
+        false
+    }
+
+    /// Appends `block` to `order` after the dirty blocks it depends on.
+    fn visit(&self, block: u64, seen: &mut BTreeSet<u64>, order: &mut Vec<u64>) {
+        if !seen.insert(block) {
+            return;
+        }
+        for &d in self.after.get(&block).into_iter().flatten() {
+            self.visit(d, seen, order);
+        }
+        if self.is_dirty(block) {
+            order.push(block);
+        }
+    }
+
+    /// Returns a copy of each dirty block in the order they must be written along with its
+    /// generation. When `block` is given only it and the blocks it depends on are returned.
+    fn write_order(&self, block: Option<u64>) -> Vec<(u64, Box<[u8]>, u64)> {
+        let mut seen = BTreeSet::new();
+        let mut order = Vec::new();
+        match block {
+            Some(b) => self.visit(b, &mut seen, &mut order),
+            None => {
+                for (&b, _) in self.buffers.iter().filter(|(_, b)| b.is_dirty()) {
+                    self.visit(b, &mut seen, &mut order);
+                }
+            }
+        }
+        order.into_iter().map(|b| (b, self.buffers[&b].page.read(|d| d.into()), self.buffers[&b].generation)).collect()
+    }
+
+    /// Marks `block` as clean if it was not modified since `generation`, and releases the blocks
+    /// which were waiting for it. Returns the number of bytes which were cleaned.
+    fn written(&mut self, block: u64, generation: u64) -> usize {
+        let Some(b) = self.buffers.get_mut(&block) else { return 0 };
+        if b.generation != generation || !b.is_dirty() {
+            return 0;
+        }
+        b.page.clean();
+        let len = b.page.len();
+        self.after.remove(&block);
+        self.after.retain(|_, deps| {
+            deps.remove(&block);
+            !deps.is_empty()
+        });
+        len
+    }
+
+    /// Returns the least recently used dirty buffer.
+    fn oldest_dirty(&self) -> Option<u64> {
+        self.buffers.iter().filter(|(_, b)| b.is_dirty()).min_by_key(|(_, b)| b.last_used).map(|(n, _)| *n)
+    }
+
+    /// Returns the least recently used unpinned buffer, preferring clean buffers.
+    fn victim(&self) -> Option<(u64, bool)> {
+        let unpinned = || self.buffers.iter().filter(|(_, b)| b.pins == 0);
+        unpinned()
+            .filter(|(_, b)| !b.is_dirty())
+            .min_by_key(|(_, b)| b.last_used)
+            .or_else(|| unpinned().min_by_key(|(_, b)| b.last_used))
+            .map(|(n, b)| (*n, b.is_dirty()))
+    }
+}
+
+impl<'a> BufferCache<'a> {
+    /// Constructs a cache of at most `capacity` blocks of `block_size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// This fn will panic if `block_size` is not a power of two or `capacity` is 0.
+    pub fn new(disk: Disk<'a>, block_size: u64, capacity: usize) -> Self {
+        assert!(block_size.is_power_of_two(), "Block size must be a power of two");
+        assert_ne!(capacity, 0, "Buffer cache capacity must not be 0");
+        Self {
+            disk,
+            block_size,
+            capacity,
+            journal: None,
+            inner: spin::Mutex::new(Inner::default()),
+            account: writeback::account(disk.file.device()),
+            _inhibit: crate::system::shutdown::inhibit("buffer cache"),
+        }
+    }
+
+    /// Attaches a journal, see [Journal].
+    pub fn with_journal(mut self, journal: Box<dyn Journal + 'a>) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// Returns the number of blocks in the disk, the last may be shorter than the block size.
+    pub fn blocks(&self) -> u64 {
+        self.disk.len().div_ceil(self.block_size)
+    }
+
+    fn block_len(&self, block: u64) -> usize {
+        (self.disk.len() - block * self.block_size).min(self.block_size) as usize
+    }
+
+    /// Pins `block`, reading it from the disk if it is not cached.
+    pub async fn pin(&self, block: u64) -> Result<Pinned<'_>, KernelError> {
+        self.load(block, true).await
+    }
+
+    /// Like [Self::pin] but a block which is not cached is zeroed instead of read. This should
+    /// be used when the whole block will be overwritten.
+    pub async fn pin_new(&self, block: u64) -> Result<Pinned<'_>, KernelError> {
+        self.load(block, false).await
+    }
+
+    async fn load(&self, block: u64, read: bool) -> Result<Pinned<'_>, KernelError> {
+        self.balance().await?;
+        if self.inner.lock().try_pin(block) {
+            return Ok(Pinned { inner: &self.inner, account: &self.account, block });
+        }
+        if block >= self.blocks() {
+            return Err(self.disk.error(Errno::ENODATA, alloc::format!("Block {block} is beyond the end of the disk")));
+        }
+        let len = self.block_len(block);
+        let page = match read {
+            true => page::merge(Page::from_data(self.disk.read_at(block * self.block_size, len).await?.into_boxed_slice())),
+            false => page::merge(Page::from_data(alloc::vec![0u8; len].into_boxed_slice())),
+        };
+        self.make_room().await?;
+
+        let mut l = self.inner.lock();
+        // Another task may have loaded the block while this one was reading it.
+        if !l.try_pin(block) {
+            let last_used = l.clock;
+            l.buffers.insert(block, Buffer { page, pins: 1, generation: 0, last_used });
+        }
+        Ok(Pinned { inner: &self.inner, account: &self.account, block })
+    }
+
+    /// Throttles the caller while the dirty limits are exceeded, see [crate::fs::writeback].
+    async fn balance(&self) -> Result<(), KernelError> {
+        if !self.account.over_high() {
+            return Ok(());
+        }
+        while self.account.over_low() {
+            let Some(block) = self.inner.lock().oldest_dirty() else { break };
+            // With a journal attached this syncs the whole cache
+            self.flush_block(block).await?;
+        }
+        Ok(())
+    }
+
+    /// Evicts buffers until there is room for another. If every buffer is pinned the cache is
+    /// allowed to grow beyond its capacity.
+    async fn make_room(&self) -> Result<(), KernelError> {
+        loop {
+            let victim = {
+                let mut l = self.inner.lock();
+                if l.buffers.len() < self.capacity {
+                    return Ok(());
+                }
+                match l.victim() {
+                    None => return Ok(()),
+                    Some((n, false)) => {
+                        l.buffers.remove(&n);
+                        return Ok(());
+                    }
+                    Some((n, true)) => n,
+                }
+            };
+            match self.journal {
+                Some(_) => self.sync().await?,
+                None => self.flush_block(victim).await?,
+            }
+        }
+    }
+
+    /// Writes `block` and each block it depends on to the disk.
+    ///
+    /// When a journal is attached this syncs the whole cache.
+    pub async fn flush_block(&self, block: u64) -> Result<(), KernelError> {
+        if self.journal.is_some() {
+            return self.sync().await;
+        }
+        let order = self.inner.lock().write_order(Some(block));
+        self.write_back(&order).await
+    }
+
+    /// Writes all dirty buffers to the disk in an order which satisfies every constraint given to
+    /// [Self::order] and flushes the disk. If a journal is attached the buffers are committed to it
+    /// and the commit is flushed before they are written in place.
+    pub async fn sync(&self) -> Result<(), KernelError> {
+        let order = self.inner.lock().write_order(None);
+        match &self.journal {
+            Some(journal) if !order.is_empty() => {
+                let blocks: Vec<(u64, Box<[u8]>)> = order.iter().map(|(n, data, _)| (*n, data.clone())).collect();
+                journal.commit(&blocks).await.context("bcache", "Journal commit failed")?;
+                self.disk.sync().await?;
+                self.write_back(&order).await?;
+                self.disk.sync().await?;
+                journal.checkpoint().await.context("bcache", "Journal checkpoint failed")
+            }
+            _ => {
+                self.write_back(&order).await?;
+                self.disk.sync().await
+            }
+        }
+    }
+
+    async fn write_back(&self, order: &[(u64, Box<[u8]>, u64)]) -> Result<(), KernelError> {
+        for (block, data, generation) in order {
+            self.disk
+                .write_at(block * self.block_size, data)
+                .await
+                .with_context("bcache", || alloc::format!("Write-back of block {block} failed"))?;
+            let cleaned = self.inner.lock().written(*block, *generation);
+            self.account.cleaned(cleaned);
+        }
+        Ok(())
+    }
+
+    /// Requires that `first` is written to the disk before `then`.
+    ///
+    /// The constraint lasts until `first` is next written, if `first` is not dirty this does
+    /// nothing. Returns [Errno::EDEADLK] if `first` must already be written after `then`.
+    pub fn order(&self, first: u64, then: u64) -> Result<(), KernelError> {
+        let mut l = self.inner.lock();
+        if first == then {
+            return Err(self.disk.error(Errno::EINVAL, alloc::format!("Block {first} can't be ordered before itself")));
+        }
+        if !l.is_dirty(first) {
+            return Ok(());
+        }
+        if l.depends_on(first, then) {
+            return Err(self.disk.error(Errno::EDEADLK, alloc::format!("Ordering block {first} before {then} would form a cycle")));
+        }
+        l.after.entry(then).or_default().insert(first);
+        Ok(())
+    }
+
+    /// Requires that every block which is currently dirty is written before `then`. This is
+    /// intended for structures such as superblocks which make the rest of the metadata valid.
+    pub fn order_after_all(&self, then: u64) -> Result<(), KernelError> {
+        let dirty: Vec<u64> = self.inner.lock().buffers.iter().filter(|(n, b)| b.is_dirty() && **n != then).map(|(n, _)| *n).collect();
+        dirty.into_iter().try_for_each(|b| self.order(b, then))
+    }
+
+    /// Reads `len` bytes at `pos` through the cache.
+    pub async fn read(&self, pos: u64, len: usize) -> Result<Vec<u8>, KernelError> {
+        let mut out = Vec::with_capacity(len);
+        let end = pos + len as u64;
+        let mut p = pos;
+        while p < end {
+            let (block, off) = (p / self.block_size, (p % self.block_size) as usize);
+            let buf = self.pin(block).await?;
+            let n = buf.read(|data| {
+                let n = (data.len() - off).min((end - p) as usize);
+                out.extend_from_slice(&data[off..off + n]);
+                n
+            });
+            p += n as u64;
+        }
+        Ok(out)
+    }
+
+    /// Writes `data` at `pos` into the cache, the modified buffers are marked dirty.
+    pub async fn write(&self, pos: u64, data: &[u8]) -> Result<(), KernelError> {
+        let end = pos + data.len() as u64;
+        let mut p = pos;
+        while p < end {
+            let (block, off) = (p / self.block_size, (p % self.block_size) as usize);
+            let whole = off == 0 && block < self.blocks() && end - p >= self.block_len(block) as u64;
+            let buf = match whole {
+                true => self.pin_new(block).await?,
+                false => self.pin(block).await?,
+            };
+            let n = buf.modify(|b| {
+                let n = (b.len() - off).min((end - p) as usize);
+                let src = (p - pos) as usize;
+                b[off..off + n].copy_from_slice(&data[src..src + n]);
+                n
+            });
+            p += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of cached and dirty buffers.
+    pub fn stats(&self) -> (usize, usize) {
+        let l = self.inner.lock();
+        (l.buffers.len(), l.buffers.values().filter(|b| b.is_dirty()).count())
+    }
+}
+
+impl Drop for BufferCache<'_> {
+    fn drop(&mut self) {
+        let dirty = self.inner.get_mut().buffers.values().filter(|b| b.is_dirty()).count();
+        let bytes = self.inner.get_mut().buffers.values().filter(|b| b.is_dirty()).map(|b| b.page.len()).sum();
+        self.account.cleaned(bytes);
+        if dirty != 0 {
+            log::warn!("Buffer cache for {} dropped with {dirty} dirty buffers", self.disk.file.device());
+        }
+    }
+}
+
+/// A pinned buffer, the buffer can't be evicted until this is dropped.
+pub struct Pinned<'c> {
+    inner: &'c spin::Mutex<Inner>,
+    account: &'c DirtyAccount,
+    block: u64,
+}
+
+impl Pinned<'_> {
+    pub fn block(&self) -> u64 {
+        self.block
+    }
+
+    /// Calls `f` with the contents of the buffer.
+    pub fn read<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
+        self.inner.lock().buffers[&self.block].page.read(f)
+    }
+
+    /// Calls `f` with the contents of the buffer and marks it dirty.
+    pub fn modify<R>(&self, f: impl FnOnce(&mut [u8]) -> R) -> R {
+        let mut l = self.inner.lock();
+        let b = l.buffers.get_mut(&self.block).unwrap(); // pinned buffers are never removed
+        b.generation += 1;
+        if !b.is_dirty() {
+            self.account.dirtied(b.page.len());
+        }
+        Page::make_mut(&mut b.page).modify(f)
+    }
+}
+
+impl Drop for Pinned<'_> {
+    fn drop(&mut self) {
+        if let Some(b) = self.inner.lock().buffers.get_mut(&self.block) {
+            b.pins -= 1;
+        }
+    }
+}
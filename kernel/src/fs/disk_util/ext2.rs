@@ -0,0 +1,368 @@
+//! ext2 formatting and checking.
+//!
+//! [mkfs] creates a revision 1 filesystem with 4KiB blocks and the `filetype` feature. Sparse
+//! superblocks are not used, every group contains a copy of the superblock and group descriptors.
+
+use super::buffer_cache::BufferCache;
+use super::{put_u16, put_u32, read_u16, read_u32, Disk, FsckReport};
+use crate::error::{Errno, KernelError};
+use alloc::vec::Vec;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+
+const BLOCK_SIZE: u64 = 4096;
+const LOG_BLOCK_SIZE: u32 = 2; // 1024 << 2
+const BLOCKS_PER_GROUP: u32 = 32768;
+const INODES_PER_GROUP: u32 = 8192;
+const INODE_SIZE: u16 = 128;
+const DESCRIPTOR_SIZE: usize = 32;
+const FIRST_INO: u32 = 11;
+const ROOT_INO: u32 = 2;
+const LOST_FOUND_INO: u32 = 11;
+/// Groups with fewer data blocks than this are not worth keeping.
+const MIN_GROUP_DATA: u32 = 64;
+
+const STATE_CLEAN: u16 = 1;
+const ERRORS_CONTINUE: u16 = 1;
+const REV_DYNAMIC: u32 = 1;
+const FEATURE_INCOMPAT_FILETYPE: u32 = 0x2;
+const FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+
+const S_IFMT: u16 = 0xF000;
+const S_IFDIR: u16 = 0x4000;
+const FT_DIR: u8 = 2;
+
+/// Formats `disk` as ext2 with the volume name `label`. Labels longer than 16 bytes are truncated.
+pub async fn mkfs(disk: &Disk<'_>, label: &str) -> Result<(), KernelError> {
+    if disk.sector_size() > BLOCK_SIZE {
+        return Err(disk.error(Errno::EOPNOTSUPP, "ext2: Sector size is larger than the block size"));
+    }
+    let mut blocks = (disk.len() / BLOCK_SIZE).min(u32::MAX as u64) as u32;
+    let mut groups = blocks.div_ceil(BLOCKS_PER_GROUP);
+    if groups == 0 {
+        return Err(disk.error(Errno::ENODATA, "ext2: Disk is too small"));
+    }
+    let gdt_blocks = (groups as u64 * DESCRIPTOR_SIZE as u64).div_ceil(BLOCK_SIZE) as u32;
+    let itable_blocks = (INODES_PER_GROUP as u64 * INODE_SIZE as u64 / BLOCK_SIZE) as u32;
+    // superblock, descriptors, block bitmap, inode bitmap, inode table
+    let overhead = 1 + gdt_blocks + 2 + itable_blocks;
+
+    let last = blocks - (groups - 1) * BLOCKS_PER_GROUP;
+    if last < overhead + MIN_GROUP_DATA {
+        groups -= 1;
+        blocks = groups * BLOCKS_PER_GROUP;
+    }
+    if groups == 0 {
+        return Err(disk.error(Errno::ENODATA, "ext2: Disk is too small"));
+    }
+    let group_len = |g: u32| (blocks - g * BLOCKS_PER_GROUP).min(BLOCKS_PER_GROUP);
+
+    // The root directory and lost+found each use one block from the start of group 0's data
+    let root_block = overhead;
+    let lost_found_block = overhead + 1;
+
+    let mut gdt = alloc::vec![0u8; (gdt_blocks as u64 * BLOCK_SIZE) as usize];
+    let mut free_blocks = 0;
+    for g in 0..groups {
+        let base = g * BLOCKS_PER_GROUP;
+        let d = &mut gdt[g as usize * DESCRIPTOR_SIZE..][..DESCRIPTOR_SIZE];
+        let used = if g == 0 { overhead + 2 } else { overhead };
+        put_u32(d, 0, base + 1 + gdt_blocks);
+        put_u32(d, 4, base + 2 + gdt_blocks);
+        put_u32(d, 8, base + 3 + gdt_blocks);
+        put_u16(d, 12, (group_len(g) - used) as u16);
+        put_u16(d, 14, if g == 0 { (INODES_PER_GROUP - FIRST_INO) as u16 } else { INODES_PER_GROUP as u16 });
+        put_u16(d, 16, if g == 0 { 2 } else { 0 });
+        free_blocks += group_len(g) - used;
+    }
+
+    let mut uuid = [0u8; 16];
+    super::random_bytes(&mut uuid);
+    let now = crate::time::rtc::realtime() as u32;
+
+    let mut sb = alloc::vec![0u8; SUPERBLOCK_SIZE];
+    put_u32(&mut sb, 0, groups * INODES_PER_GROUP);
+    put_u32(&mut sb, 4, blocks);
+    put_u32(&mut sb, 8, blocks / 20); // 5% reserved for root
+    put_u32(&mut sb, 12, free_blocks);
+    put_u32(&mut sb, 16, groups * INODES_PER_GROUP - FIRST_INO);
+    put_u32(&mut sb, 20, 0); // first data block, always 0 with blocks larger than 1KiB
+    put_u32(&mut sb, 24, LOG_BLOCK_SIZE);
+    put_u32(&mut sb, 28, LOG_BLOCK_SIZE);
+    put_u32(&mut sb, 32, BLOCKS_PER_GROUP);
+    put_u32(&mut sb, 36, BLOCKS_PER_GROUP);
+    put_u32(&mut sb, 40, INODES_PER_GROUP);
+    put_u32(&mut sb, 48, now);
+    put_u16(&mut sb, 54, u16::MAX); // max mount count, -1 disables checks
+    put_u16(&mut sb, 56, MAGIC);
+    put_u16(&mut sb, 58, STATE_CLEAN);
+    put_u16(&mut sb, 60, ERRORS_CONTINUE);
+    put_u32(&mut sb, 64, now);
+    put_u32(&mut sb, 76, REV_DYNAMIC);
+    put_u32(&mut sb, 84, FIRST_INO);
+    put_u16(&mut sb, 88, INODE_SIZE);
+    put_u32(&mut sb, 96, FEATURE_INCOMPAT_FILETYPE);
+    sb[104..120].copy_from_slice(&uuid);
+    let name = label.as_bytes();
+    let n = name.len().min(16);
+    sb[120..120 + n].copy_from_slice(&name[..n]);
+
+    // The primary superblock is written last, clearing it first means an interrupted format
+    // doesn't leave the previous filesystem's superblock describing the new metadata.
+    disk.zero(0..BLOCK_SIZE).await?;
+    let cache = BufferCache::new(disk.clone(), BLOCK_SIZE, super::METADATA_CACHE_BLOCKS);
+    let zeros = alloc::vec![0u8; BLOCK_SIZE as usize];
+    for g in 0..groups {
+        let base = (g * BLOCKS_PER_GROUP) as u64 * BLOCK_SIZE;
+        let len = group_len(g);
+        // Backup superblocks are at the start of their group
+        if g != 0 {
+            put_u16(&mut sb, 90, g as u16);
+            cache.write(base, &zeros).await?;
+            cache.write(base, &sb).await?;
+        }
+        cache.write(base + BLOCK_SIZE, &gdt).await?;
+
+        let used = if g == 0 { overhead + 2 } else { overhead };
+        let mut bitmap = alloc::vec![0u8; BLOCK_SIZE as usize];
+        for b in (0..used).chain(len..BLOCK_SIZE as u32 * 8) {
+            bitmap[b as usize / 8] |= 1 << (b % 8);
+        }
+        cache.write(base + (1 + gdt_blocks) as u64 * BLOCK_SIZE, &bitmap).await?;
+
+        bitmap.fill(0);
+        let used_inodes = if g == 0 { FIRST_INO } else { 0 };
+        for i in (0..used_inodes).chain(INODES_PER_GROUP..BLOCK_SIZE as u32 * 8) {
+            bitmap[i as usize / 8] |= 1 << (i % 8);
+        }
+        cache.write(base + (2 + gdt_blocks) as u64 * BLOCK_SIZE, &bitmap).await?;
+
+        let itable = base + (3 + gdt_blocks) as u64 * BLOCK_SIZE;
+        disk.zero(itable..itable + itable_blocks as u64 * BLOCK_SIZE).await?;
+    }
+
+    // Root and lost+found
+    let mut root = alloc::vec![0u8; BLOCK_SIZE as usize];
+    let mut off = dir_entry(&mut root, 0, ROOT_INO, b".", 12);
+    off = dir_entry(&mut root, off, ROOT_INO, b"..", 12);
+    dir_entry(&mut root, off, LOST_FOUND_INO, b"lost+found", BLOCK_SIZE as usize - off);
+    cache.write(root_block as u64 * BLOCK_SIZE, &root).await?;
+
+    let mut lost_found = alloc::vec![0u8; BLOCK_SIZE as usize];
+    let off = dir_entry(&mut lost_found, 0, LOST_FOUND_INO, b".", 12);
+    dir_entry(&mut lost_found, off, ROOT_INO, b"..", BLOCK_SIZE as usize - off);
+    cache.write(lost_found_block as u64 * BLOCK_SIZE, &lost_found).await?;
+
+    let itable = (3 + gdt_blocks) as u64 * BLOCK_SIZE;
+    let root_inode = inode(S_IFDIR | 0o755, 3, root_block, now);
+    cache.write(itable + (ROOT_INO - 1) as u64 * INODE_SIZE as u64, &root_inode).await?;
+    let lf_inode = inode(S_IFDIR | 0o700, 2, lost_found_block, now);
+    cache.write(itable + (LOST_FOUND_INO - 1) as u64 * INODE_SIZE as u64, &lf_inode).await?;
+
+    put_u16(&mut sb, 90, 0);
+    cache.write(SUPERBLOCK_OFFSET, &sb).await?;
+    cache.order_after_all(0)?;
+    cache.sync().await
+}
+
+/// Writes a directory entry at `off` and returns the offset of the next entry.
+fn dir_entry(block: &mut [u8], off: usize, ino: u32, name: &[u8], rec_len: usize) -> usize {
+    put_u32(block, off, ino);
+    put_u16(block, off + 4, rec_len as u16);
+    block[off + 6] = name.len() as u8;
+    block[off + 7] = FT_DIR;
+    block[off + 8..off + 8 + name.len()].copy_from_slice(name);
+    off + rec_len
+}
+
+/// Returns a directory inode using the single block `block`.
+fn inode(mode: u16, links: u16, block: u32, time: u32) -> [u8; INODE_SIZE as usize] {
+    let mut i = [0u8; INODE_SIZE as usize];
+    put_u16(&mut i, 0, mode);
+    put_u32(&mut i, 4, BLOCK_SIZE as u32);
+    put_u32(&mut i, 8, time);
+    put_u32(&mut i, 12, time);
+    put_u32(&mut i, 16, time);
+    put_u16(&mut i, 26, links);
+    put_u32(&mut i, 28, (BLOCK_SIZE / 512) as u32);
+    put_u32(&mut i, 40, block);
+    i
+}
+
+fn bit(bitmap: &[u8], n: u32) -> bool {
+    bitmap[n as usize / 8] & (1 << (n % 8)) != 0
+}
+
+/// Checks the ext2 filesystem on `disk`.
+///
+/// This checks the superblock, that the group descriptors and bitmaps agree with each other and
+/// with the superblock, and that the root directory is intact.
+pub async fn fsck(disk: &Disk<'_>) -> Result<FsckReport, KernelError> {
+    let mut report = FsckReport::default();
+    let sb = disk.read_at(SUPERBLOCK_OFFSET, SUPERBLOCK_SIZE).await?;
+    if read_u16(&sb, 56) != MAGIC {
+        report.error(format_args!("Superblock magic is invalid"));
+        return Ok(report);
+    }
+
+    let inodes = read_u32(&sb, 0);
+    let blocks = read_u32(&sb, 4);
+    let first_data = read_u32(&sb, 20);
+    let log_bs = read_u32(&sb, 24);
+    let bpg = read_u32(&sb, 32);
+    let ipg = read_u32(&sb, 40);
+    let rev = read_u32(&sb, 76);
+    if log_bs > 6 {
+        report.error(format_args!("Invalid block size 1024 << {log_bs}"));
+        return Ok(report);
+    }
+    let bs = 1024u64 << log_bs;
+    let inode_size = if rev == 0 { INODE_SIZE as u64 } else { read_u16(&sb, 88) as u64 };
+    if bpg == 0 || bpg as u64 > bs * 8 || ipg == 0 || ipg as u64 > bs * 8 {
+        report.error(format_args!("Invalid blocks or inodes per group"));
+        return Ok(report);
+    }
+    if inode_size < INODE_SIZE as u64 || !inode_size.is_power_of_two() || inode_size > bs {
+        report.error(format_args!("Invalid inode size {inode_size}"));
+        return Ok(report);
+    }
+    if first_data != (bs == 1024) as u32 {
+        report.error(format_args!("First data block is {first_data}, expected {}", (bs == 1024) as u32));
+        return Ok(report);
+    }
+    if blocks as u64 * bs > disk.len() {
+        report.error(format_args!("Filesystem is larger than the disk"));
+        return Ok(report);
+    }
+    if rev >= REV_DYNAMIC {
+        let incompat = read_u32(&sb, 96);
+        if incompat & !FEATURE_INCOMPAT_FILETYPE != 0 {
+            report.error(format_args!("Unsupported incompatible features {incompat:#x}"));
+            return Ok(report);
+        }
+    }
+    if read_u16(&sb, 58) & STATE_CLEAN == 0 {
+        report.warn(format_args!("Filesystem was not cleanly unmounted"));
+    }
+
+    let groups = match blocks.checked_sub(first_data) {
+        Some(data_blocks) if data_blocks > 0 => data_blocks.div_ceil(bpg),
+        _ => return Err(disk.error(Errno::EINVAL, alloc::format!("ext2: Block count {blocks} does not extend past the first data block"))),
+    };
+    if groups as u64 * ipg as u64 != inodes as u64 {
+        report.error(format_args!("Inode count {inodes} does not match {groups} groups of {ipg}"));
+    }
+    let gdt_blocks = (groups as u64 * DESCRIPTOR_SIZE as u64).div_ceil(bs);
+    let cache = BufferCache::new(disk.clone(), bs, super::METADATA_CACHE_BLOCKS);
+    let gdt = cache.read((first_data as u64 + 1) * bs, (gdt_blocks * bs) as usize).await?;
+    let itable_blocks = (ipg as u64 * inode_size).div_ceil(bs);
+
+    let mut free_blocks = 0u64;
+    let mut free_inodes = 0u64;
+    let mut descriptors = Vec::with_capacity(groups as usize);
+    for g in 0..groups {
+        let d = &gdt[g as usize * DESCRIPTOR_SIZE..][..DESCRIPTOR_SIZE];
+        let (block_bitmap, inode_bitmap, itable) = (read_u32(d, 0), read_u32(d, 4), read_u32(d, 8));
+        descriptors.push(itable);
+        let start = first_data + g * bpg;
+        let len = (blocks - start).min(bpg);
+        let range = start as u64..start as u64 + len as u64;
+        if !range.contains(&(block_bitmap as u64))
+            || !range.contains(&(inode_bitmap as u64))
+            || !range.contains(&(itable as u64))
+            || !range.contains(&(itable as u64 + itable_blocks - 1))
+        {
+            report.error(format_args!("Group {g} metadata is outside of the group"));
+            continue;
+        }
+
+        let bitmap = cache.read(block_bitmap as u64 * bs, bs as usize).await?;
+        for b in [block_bitmap, inode_bitmap].into_iter().chain(itable..itable + itable_blocks as u32) {
+            if !bit(&bitmap, b - start) {
+                report.error(format_args!("Group {g} metadata block {b} is marked free"));
+            }
+        }
+        let free = (0..len).filter(|&b| !bit(&bitmap, b)).count() as u64;
+        if free != read_u16(d, 12) as u64 {
+            report.warn(format_args!("Group {g} free block count is {}, counted {free}", read_u16(d, 12)));
+        }
+        free_blocks += free;
+
+        let bitmap = cache.read(inode_bitmap as u64 * bs, bs as usize).await?;
+        let free = (0..ipg).filter(|&i| !bit(&bitmap, i)).count() as u64;
+        if free != read_u16(d, 14) as u64 {
+            report.warn(format_args!("Group {g} free inode count is {}, counted {free}", read_u16(d, 14)));
+        }
+        free_inodes += free;
+    }
+    if free_blocks != read_u32(&sb, 12) as u64 {
+        report.warn(format_args!("Superblock free block count is {}, counted {free_blocks}", read_u32(&sb, 12)));
+    }
+    if free_inodes != read_u32(&sb, 16) as u64 {
+        report.warn(format_args!("Superblock free inode count is {}, counted {free_inodes}", read_u32(&sb, 16)));
+    }
+
+    // Root directory
+    let ino_pos = descriptors[0] as u64 * bs + (ROOT_INO - 1) as u64 * inode_size;
+    let root = cache.read(ino_pos, INODE_SIZE as usize).await?;
+    if read_u16(&root, 0) & S_IFMT != S_IFDIR {
+        report.error(format_args!("Root inode is not a directory"));
+    } else if read_u16(&root, 26) < 2 {
+        report.error(format_args!("Root directory link count is {}", read_u16(&root, 26)));
+    } else {
+        check_root(&cache, &root, bs, blocks, &mut report).await?;
+    }
+
+    if groups > 1 {
+        let backup = cache.read(bpg as u64 * bs + first_data as u64 * bs, SUPERBLOCK_SIZE).await?;
+        // Fields which change while the filesystem is in use are excluded
+        let fields = [0..12, 20..48, 56..58, 76..90, 92..136];
+        if fields.into_iter().any(|r| backup[r.clone()] != sb[r]) {
+            report.warn(format_args!("Backup superblock in group 1 differs from the primary superblock"));
+        }
+    } else if rev >= REV_DYNAMIC && read_u32(&sb, 100) & FEATURE_RO_COMPAT_SPARSE_SUPER == 0 {
+        report.warn(format_args!("Filesystem has no backup superblock"));
+    }
+    Ok(report)
+}
+
+/// Checks the directory entries in the direct blocks of the root directory.
+async fn check_root(cache: &BufferCache<'_>, root: &[u8], bs: u64, blocks: u32, report: &mut FsckReport) -> Result<(), KernelError> {
+    let size = read_u32(root, 4) as u64;
+    if size == 0 || size % bs != 0 {
+        report.error(format_args!("Root directory size {size} is not a multiple of the block size"));
+        return Ok(());
+    }
+    let mut index = 0;
+    for n in 0..(size / bs).min(12) as usize {
+        let block = read_u32(root, 40 + n * 4);
+        if block == 0 || block >= blocks {
+            report.error(format_args!("Root directory block {n} is invalid ({block})"));
+            return Ok(());
+        }
+        let data = cache.read(block as u64 * bs, bs as usize).await?;
+        let mut off = 0;
+        while off < data.len() {
+            let ino = read_u32(&data, off);
+            let rec_len = read_u16(&data, off + 4) as usize;
+            let name_len = data[off + 6] as usize;
+            if rec_len < 8 || rec_len % 4 != 0 || off + rec_len > data.len() || (ino != 0 && 8 + name_len > rec_len) {
+                report.error(format_args!("Root directory entry at {} in block {n} is corrupt", off));
+                return Ok(());
+            }
+            let name = &data[off + 8..off + 8 + name_len];
+            match index {
+                0 if name != b"." || ino != ROOT_INO => report.error(format_args!("Root directory is missing \".\"")),
+                1 if name != b".." || ino != ROOT_INO => report.error(format_args!("Root directory is missing \"..\"")),
+                _ => {}
+            }
+            if ino != 0 {
+                index += 1;
+            }
+            off += rec_len;
+        }
+    }
+    Ok(())
+}
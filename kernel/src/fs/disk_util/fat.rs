@@ -0,0 +1,327 @@
+//! FAT32 formatting and checking.
+//!
+//! Only FAT32 is supported, [mkfs] returns [Errno::ENODATA] when the disk is too small to
+//! contain the minimum number of clusters for FAT32 (roughly 32MiB with 512 byte sectors).
+
+use super::buffer_cache::BufferCache;
+use super::{put_u16, put_u32, read_u16, read_u32, Disk, FsckReport};
+use crate::error::{Errno, KernelError};
+use alloc::vec::Vec;
+
+const RESERVED_SECTORS: u16 = 32;
+const FAT_COUNT: u8 = 2;
+const MEDIA: u8 = 0xF8;
+const ROOT_CLUSTER: u32 = 2;
+const FSINFO_SECTOR: u16 = 1;
+const BACKUP_BOOT_SECTOR: u16 = 6;
+/// Volumes with fewer clusters than this are FAT12 or FAT16.
+const MIN_CLUSTERS: u32 = 65525;
+const MAX_CLUSTERS: u32 = 0x0FFF_FFF5;
+
+const ENTRY_MASK: u32 = 0x0FFF_FFFF;
+const BAD_CLUSTER: u32 = 0x0FFF_FFF7;
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIG: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIG: u32 = 0xAA55_0000;
+const ATTR_VOLUME_ID: u8 = 0x08;
+
+/// Filesystem parameters which are read from the boot sector.
+struct Bpb {
+    bytes_per_sector: u64,
+    sectors_per_cluster: u64,
+    reserved: u64,
+    fats: u64,
+    fat_size: u64,
+    total_sectors: u64,
+    root_cluster: u32,
+    fsinfo: u64,
+    backup_boot: u64,
+}
+
+impl Bpb {
+    fn data_start(&self) -> u64 {
+        self.reserved + self.fats * self.fat_size
+    }
+
+    fn clusters(&self) -> u32 {
+        ((self.total_sectors - self.data_start()) / self.sectors_per_cluster) as u32
+    }
+
+    fn cluster_pos(&self, cluster: u32) -> u64 {
+        (self.data_start() + (cluster - 2) as u64 * self.sectors_per_cluster) * self.bytes_per_sector
+    }
+
+    fn fat_pos(&self, fat: u64) -> u64 {
+        (self.reserved + fat * self.fat_size) * self.bytes_per_sector
+    }
+
+    fn encode(&self, label: &[u8; 11], volume_id: u32, hidden: u32) -> Vec<u8> {
+        let mut b = alloc::vec![0u8; self.bytes_per_sector as usize];
+        b[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+        b[3..11].copy_from_slice(b"HOOTUX  ");
+        put_u16(&mut b, 11, self.bytes_per_sector as u16);
+        b[13] = self.sectors_per_cluster as u8;
+        put_u16(&mut b, 14, self.reserved as u16);
+        b[16] = self.fats as u8;
+        b[21] = MEDIA;
+        put_u16(&mut b, 24, 63); // sectors per track
+        put_u16(&mut b, 26, 255); // heads
+        put_u32(&mut b, 28, hidden);
+        put_u32(&mut b, 32, self.total_sectors as u32);
+        put_u32(&mut b, 36, self.fat_size as u32);
+        put_u32(&mut b, 44, self.root_cluster);
+        put_u16(&mut b, 48, self.fsinfo as u16);
+        put_u16(&mut b, 50, self.backup_boot as u16);
+        b[64] = 0x80; // drive number
+        b[66] = 0x29; // extended boot signature
+        put_u32(&mut b, 67, volume_id);
+        b[71..82].copy_from_slice(label);
+        b[82..90].copy_from_slice(b"FAT32   ");
+        b[510] = 0x55;
+        b[511] = 0xAA;
+        b
+    }
+
+    /// Parses the boot sector, errors are added to `report`.
+    fn decode(b: &[u8], report: &mut FsckReport) -> Option<Self> {
+        if b[510..512] != [0x55, 0xAA] {
+            report.error(format_args!("Boot sector signature is missing"));
+            return None;
+        }
+        let bpb = Self {
+            bytes_per_sector: read_u16(b, 11) as u64,
+            sectors_per_cluster: b[13] as u64,
+            reserved: read_u16(b, 14) as u64,
+            fats: b[16] as u64,
+            fat_size: read_u32(b, 36) as u64,
+            total_sectors: read_u32(b, 32) as u64,
+            root_cluster: read_u32(b, 44),
+            fsinfo: read_u16(b, 48) as u64,
+            backup_boot: read_u16(b, 50) as u64,
+        };
+
+        if !(512..=4096).contains(&bpb.bytes_per_sector) || !bpb.bytes_per_sector.is_power_of_two() {
+            report.error(format_args!("Invalid bytes per sector {}", bpb.bytes_per_sector));
+            return None;
+        }
+        if bpb.sectors_per_cluster == 0 || !bpb.sectors_per_cluster.is_power_of_two() {
+            report.error(format_args!("Invalid sectors per cluster {}", bpb.sectors_per_cluster));
+            return None;
+        }
+        if bpb.reserved == 0 || bpb.fats == 0 {
+            report.error(format_args!("Invalid reserved sector count or FAT count"));
+            return None;
+        }
+        if read_u16(b, 17) != 0 || read_u16(b, 22) != 0 || bpb.fat_size == 0 {
+            report.error(format_args!("Not a FAT32 filesystem, FAT12 and FAT16 are not supported"));
+            return None;
+        }
+        if bpb.data_start() >= bpb.total_sectors {
+            report.error(format_args!("FATs extend beyond the end of the filesystem"));
+            return None;
+        }
+        if bpb.clusters() < MIN_CLUSTERS {
+            report.error(format_args!("Cluster count {} is too small for FAT32", bpb.clusters()));
+            return None;
+        }
+        if (bpb.clusters() as u64 + 2) * 4 > bpb.fat_size * bpb.bytes_per_sector {
+            report.error(format_args!("FAT is too small for {} clusters", bpb.clusters()));
+            return None;
+        }
+        Some(bpb)
+    }
+}
+
+/// Formats `disk` as FAT32 with the volume label `label`.
+///
+/// The label is converted to upper case and truncated to 11 characters, non-ascii characters are replaced with `_`.
+pub async fn mkfs(disk: &Disk<'_>, label: &str) -> Result<(), KernelError> {
+    let bps = disk.sector_size();
+    if !(512..=4096).contains(&bps) {
+        return Err(disk.error(Errno::EOPNOTSUPP, "fat: Unsupported sector size"));
+    }
+    let total_sectors = (disk.len() / bps).min(u32::MAX as u64);
+
+    // Cluster sizes recommended by the FAT specification, limited to 32KiB
+    let size = total_sectors * bps;
+    let cluster_bytes: u64 = match size >> 20 {
+        0..=260 => 512,
+        261..=8192 => 4096,
+        8193..=16384 => 8192,
+        16385..=32768 => 16384,
+        _ => 32768,
+    };
+    let spc = (cluster_bytes / bps).max(1);
+
+    // This overestimates the FAT size slightly, the FAT may not be smaller than the cluster count
+    let Some(data_sectors) = total_sectors.checked_sub(RESERVED_SECTORS as u64) else {
+        return Err(disk.error(Errno::ENODATA, "fat: Disk is too small for FAT32"));
+    };
+    let fat_size = ((data_sectors / spc + 2) * 4).div_ceil(bps);
+    let bpb = Bpb {
+        bytes_per_sector: bps,
+        sectors_per_cluster: spc,
+        reserved: RESERVED_SECTORS as u64,
+        fats: FAT_COUNT as u64,
+        fat_size,
+        total_sectors,
+        root_cluster: ROOT_CLUSTER,
+        fsinfo: FSINFO_SECTOR as u64,
+        backup_boot: BACKUP_BOOT_SECTOR as u64,
+    };
+    if bpb.data_start() >= total_sectors || bpb.clusters() < MIN_CLUSTERS {
+        return Err(disk.error(Errno::ENODATA, "fat: Disk is too small for FAT32"));
+    }
+    if bpb.clusters() > MAX_CLUSTERS {
+        return Err(disk.error(Errno::EOPNOTSUPP, "fat: Disk is too large for FAT32"));
+    }
+
+    let mut name = [b' '; 11];
+    for (d, c) in name.iter_mut().zip(label.chars()) {
+        *d = if c.is_ascii_graphic() || c == ' ' { c.to_ascii_uppercase() as u8 } else { b'_' };
+    }
+    if label.is_empty() {
+        name = *b"NO NAME    ";
+    }
+    let mut id = [0u8; 4];
+    super::random_bytes(&mut id);
+
+    // Clear the reserved region, FATs and root directory. This also destroys any existing boot sector.
+    disk.zero(0..bpb.cluster_pos(ROOT_CLUSTER) + spc * bps).await?;
+    let cache = BufferCache::new(disk.clone(), bps, super::METADATA_CACHE_BLOCKS);
+
+    let boot = bpb.encode(&name, u32::from_le_bytes(id), (disk.offset / bps) as u32);
+    let mut fsinfo = alloc::vec![0u8; bps as usize];
+    put_u32(&mut fsinfo, 0, FSINFO_LEAD_SIG);
+    put_u32(&mut fsinfo, 484, FSINFO_STRUCT_SIG);
+    put_u32(&mut fsinfo, 488, bpb.clusters() - 1); // the root directory is allocated
+    put_u32(&mut fsinfo, 492, ROOT_CLUSTER + 1);
+    put_u32(&mut fsinfo, 508, FSINFO_TRAIL_SIG);
+    cache.write(BACKUP_BOOT_SECTOR as u64 * bps, &boot).await?;
+    for base in [0, BACKUP_BOOT_SECTOR as u64] {
+        cache.write((base + FSINFO_SECTOR as u64) * bps, &fsinfo).await?;
+    }
+
+    let mut fat = [0u8; 12];
+    put_u32(&mut fat, 0, 0x0FFF_FF00 | MEDIA as u32);
+    put_u32(&mut fat, 4, ENTRY_MASK);
+    put_u32(&mut fat, 8, ENTRY_MASK); // root directory
+    for n in 0..bpb.fats {
+        cache.write(bpb.fat_pos(n), &fat).await?;
+    }
+
+    let mut label_entry = [0u8; 32];
+    label_entry[0..11].copy_from_slice(&name);
+    label_entry[11] = ATTR_VOLUME_ID;
+    cache.write(bpb.cluster_pos(ROOT_CLUSTER), &label_entry).await?;
+
+    // The boot sector makes the filesystem valid so it is written last.
+    cache.write(0, &boot).await?;
+    cache.order_after_all(0)?;
+    cache.sync().await
+}
+
+/// Checks the FAT32 filesystem on `disk`.
+///
+/// This checks the boot sector, that all copies of the FAT are identical, that all cluster
+/// chains are valid and not cross-linked and that the root directory chain terminates.
+pub async fn fsck(disk: &Disk<'_>) -> Result<FsckReport, KernelError> {
+    let mut report = FsckReport::default();
+    let boot = disk.read_at(0, disk.sector_size().max(512) as usize).await?;
+    let Some(bpb) = Bpb::decode(&boot, &mut report) else {
+        return Ok(report);
+    };
+    let bps = bpb.bytes_per_sector;
+    if bpb.total_sectors * bps > disk.len() {
+        report.error(format_args!("Filesystem is larger than the disk"));
+        return Ok(report);
+    }
+
+    let cache = BufferCache::new(disk.clone(), bps, super::METADATA_CACHE_BLOCKS);
+
+    if bpb.backup_boot != 0 && bpb.backup_boot < bpb.reserved {
+        let backup = cache.read(bpb.backup_boot * bps, bps as usize).await?;
+        if backup[..90] != boot[..90] {
+            report.warn(format_args!("Backup boot sector differs from the boot sector"));
+        }
+    }
+
+    let clusters = bpb.clusters();
+    let end = clusters + 2;
+    let mut referenced = alloc::vec![0u8; (end as usize).div_ceil(8)];
+    let mut free = 0u32;
+    let fat_len = end as u64 * 4;
+    // Each FAT is only scanned once so this bypasses the cache
+    let mut pos = 0;
+    while pos < fat_len {
+        let n = (fat_len - pos).min(super::FILL_CHUNK as u64) as usize;
+        let chunk = disk.read_at(bpb.fat_pos(0) + pos, n).await?;
+        for copy in 1..bpb.fats {
+            if disk.read_at(bpb.fat_pos(copy) + pos, n).await? != chunk {
+                report.error(format_args!("FAT {} differs from FAT 0 at byte {pos}", copy));
+            }
+        }
+
+        for (i, e) in chunk.chunks_exact(4).enumerate() {
+            let cluster = (pos / 4) as u32 + i as u32;
+            let v = read_u32(e, 0) & ENTRY_MASK;
+            if cluster < 2 {
+                if cluster == 0 && v & 0xFF != boot[21] as u32 {
+                    report.warn(format_args!("FAT media byte does not match the boot sector"));
+                }
+                continue;
+            }
+            match v {
+                0 => free += 1,
+                BAD_CLUSTER => {}
+                v if v >= END_OF_CHAIN => {}
+                v if v < 2 || v >= end => report.error(format_args!("Cluster {cluster} points to invalid cluster {v:#x}")),
+                v => {
+                    let (byte, bit) = (v as usize / 8, v % 8);
+                    if referenced[byte] & (1 << bit) != 0 {
+                        report.error(format_args!("Cluster {v} is cross-linked"));
+                    }
+                    referenced[byte] |= 1 << bit;
+                }
+            }
+        }
+        pos += n as u64;
+    }
+
+    // Follow the root directory chain
+    let mut c = bpb.root_cluster;
+    let mut len = 0;
+    loop {
+        if c < 2 || c >= end {
+            report.error(format_args!("Root directory chain contains invalid cluster {c:#x}"));
+            break;
+        }
+        let next = read_u32(&cache.read(bpb.fat_pos(0) + c as u64 * 4, 4).await?, 0) & ENTRY_MASK;
+        len += 1;
+        if next == 0 {
+            report.error(format_args!("Root directory chain contains free cluster {c}"));
+            break;
+        } else if next >= END_OF_CHAIN {
+            break;
+        } else if len > clusters {
+            report.error(format_args!("Root directory chain contains a loop"));
+            break;
+        }
+        c = next;
+    }
+
+    if bpb.fsinfo != 0 && bpb.fsinfo < bpb.reserved {
+        let info = cache.read(bpb.fsinfo * bps, bps as usize).await?;
+        if read_u32(&info, 0) != FSINFO_LEAD_SIG || read_u32(&info, 484) != FSINFO_STRUCT_SIG || read_u32(&info, 508) != FSINFO_TRAIL_SIG {
+            report.warn(format_args!("FSInfo sector signatures are invalid"));
+        } else {
+            let count = read_u32(&info, 488);
+            if count != u32::MAX && count != free {
+                report.warn(format_args!("FSInfo free cluster count is {count}, counted {free}"));
+            }
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,368 @@
+//! GUID partition tables.
+//!
+//! [create] writes a protective MBR and both the primary and backup partition tables, partitions
+//! are aligned to 1MiB. [read] returns the partitions from the primary table, falling back to the
+//! backup table if the primary is damaged. [check] verifies that both tables are intact and agree.
+
+use super::{put_u32, put_u64, read_u32, read_u64, Disk, FsckReport};
+use crate::error::{Errno, KernelError};
+use crate::crypto::crc::crc32;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const REVISION: u32 = 0x0001_0000;
+const HEADER_SIZE: u32 = 92;
+const ENTRY_COUNT: u32 = 128;
+const ENTRY_SIZE: u32 = 128;
+const ALIGNMENT: u64 = 1024 * 1024;
+/// Number of UTF-16 code units in a partition name.
+const NAME_LEN: usize = 36;
+
+/// A GUID, stored in the mixed-endian on-disk format.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    pub const UNUSED: Self = Self([0; 16]);
+    pub const EFI_SYSTEM: Self = Self::new(0xC12A7328, 0xF81F, 0x11D2, [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B]);
+    pub const BASIC_DATA: Self = Self::new(0xEBD0A0A2, 0xB9E5, 0x4433, [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7]);
+    pub const LINUX_FS: Self = Self::new(0x0FC63DAF, 0x8483, 0x4772, [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4]);
+
+    /// Constructs a GUID from the fields of its canonical form, `a-b-c-d[0..2]-d[2..8]`.
+    pub const fn new(a: u32, b: u16, c: u16, d: [u8; 8]) -> Self {
+        let a = a.to_le_bytes();
+        let b = b.to_le_bytes();
+        let c = c.to_le_bytes();
+        Self([a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]])
+    }
+
+    /// Returns a new random (version 4) GUID.
+    pub fn random() -> Self {
+        let mut b = [0; 16];
+        super::random_bytes(&mut b);
+        b[7] = (b[7] & 0x0f) | 0x40;
+        b[8] = (b[8] & 0x3f) | 0x80;
+        Self(b)
+    }
+
+    /// Constructs a GUID from its on-disk format, `b` must be at least 16 bytes long.
+    pub fn from_bytes(b: &[u8]) -> Self {
+        Self(b[..16].try_into().unwrap())
+    }
+
+    /// Returns the GUID in its on-disk format.
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl core::fmt::Display for Guid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            read_u32(b, 0),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+        for i in &b[10..] {
+            write!(f, "{i:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A partition to be created by [create].
+pub struct PartitionSpec<'a> {
+    pub type_guid: Guid,
+    pub name: &'a str,
+    /// Size of the partition in bytes, rounded up to the alignment. `None` uses the remainder of the disk.
+    pub len: Option<u64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Partition {
+    pub type_guid: Guid,
+    pub guid: Guid,
+    pub first_lba: u64,
+    /// The last LBA in the partition, inclusive.
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub name: String,
+}
+
+impl Partition {
+    /// Returns the byte range of the partition, for use with [Disk::region].
+    pub fn byte_range(&self, sector_size: u64) -> Range<u64> {
+        self.first_lba * sector_size..(self.last_lba + 1) * sector_size
+    }
+
+    fn encode(&self, b: &mut [u8]) {
+        b[0..16].copy_from_slice(&self.type_guid.0);
+        b[16..32].copy_from_slice(&self.guid.0);
+        put_u64(b, 32, self.first_lba);
+        put_u64(b, 40, self.last_lba);
+        put_u64(b, 48, self.attributes);
+        for (i, c) in self.name.encode_utf16().take(NAME_LEN).enumerate() {
+            b[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+    }
+
+    fn decode(b: &[u8]) -> Option<Self> {
+        let type_guid = Guid::from_bytes(&b[0..16]);
+        if type_guid == Guid::UNUSED {
+            return None;
+        }
+        let name = b[56..56 + NAME_LEN * 2].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).take_while(|c| *c != 0);
+        Some(Self {
+            type_guid,
+            guid: Guid::from_bytes(&b[16..32]),
+            first_lba: read_u64(b, 32),
+            last_lba: read_u64(b, 40),
+            attributes: read_u64(b, 48),
+            name: char::decode_utf16(name).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect(),
+        })
+    }
+}
+
+struct Header {
+    my_lba: u64,
+    alternate_lba: u64,
+    first_usable: u64,
+    last_usable: u64,
+    disk_guid: Guid,
+    entries_lba: u64,
+    entry_count: u32,
+    entry_size: u32,
+    entries_crc: u32,
+}
+
+impl Header {
+    fn encode(&self, sector_size: u64) -> Vec<u8> {
+        let mut b = alloc::vec![0u8; sector_size as usize];
+        b[0..8].copy_from_slice(SIGNATURE);
+        put_u32(&mut b, 8, REVISION);
+        put_u32(&mut b, 12, HEADER_SIZE);
+        put_u64(&mut b, 24, self.my_lba);
+        put_u64(&mut b, 32, self.alternate_lba);
+        put_u64(&mut b, 40, self.first_usable);
+        put_u64(&mut b, 48, self.last_usable);
+        b[56..72].copy_from_slice(&self.disk_guid.0);
+        put_u64(&mut b, 72, self.entries_lba);
+        put_u32(&mut b, 80, self.entry_count);
+        put_u32(&mut b, 84, self.entry_size);
+        put_u32(&mut b, 88, self.entries_crc);
+        let crc = crc32(&b[..HEADER_SIZE as usize]);
+        put_u32(&mut b, 16, crc);
+        b
+    }
+
+    /// Parses and validates the header, returns `None` if the header is not valid.
+    fn decode(b: &[u8]) -> Option<Self> {
+        if &b[0..8] != SIGNATURE {
+            return None;
+        }
+        let size = read_u32(b, 12) as usize;
+        if size < HEADER_SIZE as usize || size > b.len() {
+            return None;
+        }
+        let mut h = Vec::from(&b[..size]);
+        put_u32(&mut h, 16, 0);
+        if crc32(&h) != read_u32(b, 16) {
+            return None;
+        }
+        let entry_size = read_u32(b, 84);
+        if entry_size < ENTRY_SIZE || !entry_size.is_power_of_two() {
+            return None;
+        }
+        Some(Self {
+            my_lba: read_u64(b, 24),
+            alternate_lba: read_u64(b, 32),
+            first_usable: read_u64(b, 40),
+            last_usable: read_u64(b, 48),
+            disk_guid: Guid::from_bytes(&b[56..72]),
+            entries_lba: read_u64(b, 72),
+            entry_count: read_u32(b, 80),
+            entry_size,
+            entries_crc: read_u32(b, 88),
+        })
+    }
+
+    fn entries_len(&self) -> usize {
+        self.entry_count as usize * self.entry_size as usize
+    }
+}
+
+/// Writes a new partition table containing `partitions` onto `disk`, replacing any existing table.
+/// Returns the partitions which were created.
+///
+/// Returns [Errno::ENODATA] if the partitions do not fit on the disk.
+pub async fn create(disk: &Disk<'_>, partitions: &[PartitionSpec<'_>]) -> Result<Vec<Partition>, KernelError> {
+    let ss = disk.sector_size();
+    let sectors = disk.len() / ss;
+    let entry_sectors = (ENTRY_COUNT * ENTRY_SIZE) as u64 / ss;
+    if partitions.len() > ENTRY_COUNT as usize || sectors < 2 * (entry_sectors + 2) {
+        return Err(disk.error(Errno::ENODATA, "gpt: Disk is too small for a partition table"));
+    }
+    let last_lba = sectors - 1;
+    let first_usable = 2 + entry_sectors;
+    let last_usable = last_lba - entry_sectors - 1;
+    let align = (ALIGNMENT / ss).max(1);
+
+    let mut created = Vec::new();
+    let mut next = first_usable.next_multiple_of(align);
+    for p in partitions {
+        let last = match p.len {
+            Some(len) => next + len.div_ceil(ss).next_multiple_of(align) - 1,
+            None => last_usable,
+        };
+        if next > last_usable || last > last_usable {
+            return Err(disk.error(Errno::ENODATA, "gpt: Partitions do not fit on the disk"));
+        }
+        created.push(Partition {
+            type_guid: p.type_guid,
+            guid: Guid::random(),
+            first_lba: next,
+            last_lba: last,
+            attributes: 0,
+            name: String::from(p.name),
+        });
+        next = (last + 1).next_multiple_of(align);
+    }
+
+    let mut entries = alloc::vec![0u8; (ENTRY_COUNT * ENTRY_SIZE) as usize];
+    for (p, b) in created.iter().zip(entries.chunks_exact_mut(ENTRY_SIZE as usize)) {
+        p.encode(b);
+    }
+
+    let mut primary = Header {
+        my_lba: 1,
+        alternate_lba: last_lba,
+        first_usable,
+        last_usable,
+        disk_guid: Guid::random(),
+        entries_lba: 2,
+        entry_count: ENTRY_COUNT,
+        entry_size: ENTRY_SIZE,
+        entries_crc: crc32(&entries),
+    };
+
+    disk.write_at(0, &protective_mbr(sectors, ss)).await?;
+    disk.write_at(2 * ss, &entries).await?;
+    disk.write_at(ss, &primary.encode(ss)).await?;
+
+    // backup
+    primary.my_lba = last_lba;
+    primary.alternate_lba = 1;
+    primary.entries_lba = last_lba - entry_sectors;
+    disk.write_at(primary.entries_lba * ss, &entries).await?;
+    disk.write_at(last_lba * ss, &primary.encode(ss)).await?;
+
+    Ok(created)
+}
+
+fn protective_mbr(sectors: u64, sector_size: u64) -> Vec<u8> {
+    let mut b = alloc::vec![0u8; sector_size as usize];
+    let e = &mut b[446..462];
+    e[1..4].copy_from_slice(&[0x00, 0x02, 0x00]); // CHS of LBA 1
+    e[4] = 0xEE;
+    e[5..8].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+    put_u32(e, 8, 1);
+    put_u32(e, 12, (sectors - 1).min(u32::MAX as u64) as u32);
+    b[510] = 0x55;
+    b[511] = 0xAA;
+    b
+}
+
+/// Reads and validates the header at `lba` and its partition entries.
+async fn read_table(disk: &Disk<'_>, lba: u64) -> Result<Option<(Header, Vec<u8>)>, KernelError> {
+    let ss = disk.sector_size();
+    let Some(header) = Header::decode(&disk.read_at(lba * ss, ss as usize).await?) else {
+        return Ok(None);
+    };
+    if header.my_lba != lba || header.entries_lba.saturating_mul(ss).saturating_add(header.entries_len() as u64) > disk.len() {
+        return Ok(None);
+    }
+    let entries = disk.read_at(header.entries_lba * ss, header.entries_len()).await?;
+    if crc32(&entries) != header.entries_crc {
+        return Ok(None);
+    }
+    Ok(Some((header, entries)))
+}
+
+fn decode_entries(header: &Header, entries: &[u8]) -> Vec<Partition> {
+    entries.chunks_exact(header.entry_size as usize).filter_map(Partition::decode).collect()
+}
+
+/// Returns the address of the last sector of `disk`, where the backup header is stored.
+fn last_lba(disk: &Disk<'_>) -> Result<u64, KernelError> {
+    (disk.len() / disk.sector_size()).checked_sub(1).ok_or_else(|| disk.error(Errno::EINVAL, "gpt: Disk is empty"))
+}
+
+/// Reads the partitions from the partition table on `disk`.
+///
+/// Returns [Errno::EINVAL] if neither the primary nor the backup table is valid.
+pub async fn read(disk: &Disk<'_>) -> Result<Vec<Partition>, KernelError> {
+    let last_lba = last_lba(disk)?;
+    let (header, entries) = match read_table(disk, 1).await? {
+        Some(t) => t,
+        None => read_table(disk, last_lba).await?.ok_or_else(|| disk.error(Errno::EINVAL, "gpt: No valid partition table"))?,
+    };
+    Ok(decode_entries(&header, &entries))
+}
+
+/// Checks the partition table on `disk`.
+pub async fn check(disk: &Disk<'_>) -> Result<FsckReport, KernelError> {
+    let mut report = FsckReport::default();
+    let ss = disk.sector_size();
+    let last_lba = last_lba(disk)?;
+
+    let mbr = disk.read_at(0, ss as usize).await?;
+    if mbr[510..512] != [0x55, 0xAA] || mbr[446 + 4] != 0xEE {
+        report.warn(format_args!("Protective MBR is missing"));
+    }
+
+    let primary = read_table(disk, 1).await?;
+    let backup = read_table(disk, last_lba).await?;
+    let (header, entries) = match (&primary, &backup) {
+        (Some(p), Some(b)) => {
+            if p.0.disk_guid != b.0.disk_guid || p.1 != b.1 {
+                report.error(format_args!("Primary and backup partition tables differ"));
+            }
+            if p.0.alternate_lba != last_lba {
+                report.warn(format_args!("Primary header does not locate the backup header at the end of the disk"));
+            }
+            p
+        }
+        (Some(p), None) => {
+            report.error(format_args!("Backup partition table is damaged or missing"));
+            p
+        }
+        (None, Some(b)) => {
+            report.error(format_args!("Primary partition table is damaged or missing"));
+            b
+        }
+        (None, None) => {
+            report.error(format_args!("No valid partition table found"));
+            return Ok(report);
+        }
+    };
+
+    let parts = decode_entries(header, entries);
+    for (i, p) in parts.iter().enumerate() {
+        if p.first_lba > p.last_lba || p.first_lba < header.first_usable || p.last_lba > header.last_usable {
+            report.error(format_args!("Partition {} \"{}\" lies outside the usable area", i + 1, p.name));
+        }
+        for (j, q) in parts.iter().enumerate().skip(i + 1) {
+            if p.first_lba <= q.last_lba && q.first_lba <= p.last_lba {
+                report.error(format_args!("Partitions {} and {} overlap", i + 1, j + 1));
+            }
+        }
+    }
+    Ok(report)
+}
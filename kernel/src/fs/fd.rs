@@ -0,0 +1,209 @@
+//! File descriptor tables.
+//!
+//! An [FdTable] maps small integers to open files. Each file in a table is charged to the
+//! [Resource::OpenFiles] limit of the task which created the table, when the limit is reached
+//! [FdTable::insert] fails with [IoError::LimitExceeded].
+//!
+//! Descriptors may be marked close-on-spawn, these are not inherited by tasks started using
+//! [crate::task::spawn::spawn].
+//!
+//! [fsync] and [fdatasync] sync a descriptor of the running task, see [NormalFile::sync].
+//!
+//! [lock] and [unlock] take advisory locks on a descriptor of the running task, see [super::lock].
+
+use super::file::{File, NormalFile};
+use super::lock::{LockKind, LockOwner};
+use super::IoError;
+use crate::task::rlimit::{Resource, Resources};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use core::ops::Range;
+
+pub type Fd = usize;
+
+pub struct FdTable {
+    files: BTreeMap<Fd, Box<dyn File>>,
+    /// Descriptors which are not inherited by spawned tasks.
+    cloexec: BTreeSet<Fd>,
+    /// `None` when the table was created outside a task, in which case it is not limited.
+    owner: Option<Arc<Resources>>,
+}
+
+impl FdTable {
+    /// Creates an empty table charged to the current task.
+    pub fn new() -> Self {
+        Self {
+            files: BTreeMap::new(),
+            cloexec: BTreeSet::new(),
+            owner: crate::task::rlimit::current(),
+        }
+    }
+
+    /// Creates an empty table charged to `owner`.
+    pub(crate) fn with_owner(owner: Arc<Resources>) -> Self {
+        Self {
+            files: BTreeMap::new(),
+            cloexec: BTreeSet::new(),
+            owner: Some(owner),
+        }
+    }
+
+    /// Inserts `file` using the lowest free descriptor.
+    ///
+    /// Returns `file` with [IoError::LimitExceeded] if the owner has reached its open file limit.
+    pub fn insert(&mut self, file: Box<dyn File>) -> Result<Fd, (IoError, Box<dyn File>)> {
+        if let Some(owner) = &self.owner {
+            if !owner.try_charge(Resource::OpenFiles, 1) {
+                return Err((IoError::LimitExceeded, file));
+            }
+        }
+        let fd = self.files.keys().zip(0..).find(|(fd, i)| **fd != *i).map_or(self.files.len(), |(_, i)| i);
+        self.files.insert(fd, file);
+        Ok(fd)
+    }
+
+    /// Inserts `file` as `fd`, closing the file previously using `fd`.
+    pub fn insert_at(&mut self, fd: Fd, file: Box<dyn File>) -> Result<(), (IoError, Box<dyn File>)> {
+        if self.files.contains_key(&fd) {
+            self.remove(fd);
+        }
+        if let Some(owner) = &self.owner {
+            if !owner.try_charge(Resource::OpenFiles, 1) {
+                return Err((IoError::LimitExceeded, file));
+            }
+        }
+        self.files.insert(fd, file);
+        Ok(())
+    }
+
+    pub fn get(&self, fd: Fd) -> Option<&dyn File> {
+        self.files.get(&fd).map(|f| &**f)
+    }
+
+    /// Removes `fd` from the table and returns its file.
+    pub fn remove(&mut self, fd: Fd) -> Option<Box<dyn File>> {
+        let file = self.files.remove(&fd)?;
+        self.cloexec.remove(&fd);
+        if let Some(owner) = &self.owner {
+            owner.uncharge(Resource::OpenFiles, 1);
+        }
+        Some(file)
+    }
+
+    /// Sets whether `fd` is closed in spawned tasks. Returns [IoError::NotPresent] if `fd` is not open.
+    pub fn set_cloexec(&mut self, fd: Fd, cloexec: bool) -> Result<(), IoError> {
+        if !self.files.contains_key(&fd) {
+            return Err(IoError::NotPresent);
+        }
+        match cloexec {
+            true => self.cloexec.insert(fd),
+            false => self.cloexec.remove(&fd),
+        };
+        Ok(())
+    }
+
+    pub fn is_cloexec(&self, fd: Fd) -> bool {
+        self.cloexec.contains(&fd)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Fd, &dyn File)> {
+        self.files.iter().map(|(fd, f)| (*fd, &**f))
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for FdTable {
+    fn drop(&mut self) {
+        if let Some(owner) = &self.owner {
+            owner.uncharge(Resource::OpenFiles, self.files.len());
+        }
+    }
+}
+
+/// Makes all data written to `fd` of the running task durable, see [NormalFile::sync].
+///
+/// Returns [IoError::NotPresent] if `fd` is not open, or [IoError::NotSupported] if it is not a
+/// normal file.
+pub async fn fsync(fd: Fd) -> Result<(), IoError> {
+    sync_fd(fd, false).await
+}
+
+/// Like [fsync] but metadata which is not required to read the data back may not be written.
+pub async fn fdatasync(fd: Fd) -> Result<(), IoError> {
+    sync_fd(fd, true).await
+}
+
+/// Returns the capacity of the filesystem containing `fd` of the running task, see
+/// [super::device::FileSystem::statfs].
+///
+/// Returns [IoError::NotPresent] if `fd` is not open, or [IoError::NotSupported] if the file is
+/// not within a filesystem.
+pub async fn fstatfs(fd: Fd) -> Result<super::statfs::FsStat, IoError> {
+    let proc = crate::task::job::current().ok_or(IoError::NotPresent)?;
+    let dev = proc.files().get(fd).ok_or(IoError::NotPresent)?.device();
+    super::get_vfs().statfs_dev(dev).await.map_err(|e| match e {
+        super::vfs::VfsError::LowerLevel(e) => e,
+        _ => IoError::NotSupported,
+    })
+}
+
+/// Closes `fd` of the running task. Like POSIX record locks, this releases all locks held by the
+/// task on the file even when they were taken through another descriptor.
+///
+/// Returns [IoError::NotPresent] if `fd` is not open.
+pub fn close(fd: Fd) -> Result<(), IoError> {
+    let proc = crate::task::job::current().ok_or(IoError::NotPresent)?;
+    let file = proc.files().remove(fd).ok_or(IoError::NotPresent)?;
+    super::get_vfs().locks().unlock(&*file, proc.id().into(), 0..u64::MAX);
+    Ok(())
+}
+
+/// Acquires a `kind` lock over `range` of `fd` for the running task, see
+/// [super::lock::LockManager::lock]. When `wait` is `false` this returns [IoError::WouldBlock]
+/// instead of waiting for conflicting locks.
+///
+/// Returns [IoError::NotPresent] if `fd` is not open.
+pub async fn lock(fd: Fd, kind: LockKind, range: Range<u64>, wait: bool) -> Result<(), IoError> {
+    let proc = crate::task::job::current().ok_or(IoError::NotPresent)?;
+    // Cloned so that the table is not locked while waiting
+    let file = proc.files().get(fd).ok_or(IoError::NotPresent)?.clone_file();
+    let owner = LockOwner::from(proc.id());
+    let locks = super::get_vfs().locks();
+    match wait {
+        true => locks.lock(&*file, owner, kind, range).await,
+        false => locks.try_lock(&*file, owner, kind, range),
+    }
+}
+
+/// Releases `range` from the locks held by the running task on `fd`.
+///
+/// Returns [IoError::NotPresent] if `fd` is not open.
+pub fn unlock(fd: Fd, range: Range<u64>) -> Result<(), IoError> {
+    let proc = crate::task::job::current().ok_or(IoError::NotPresent)?;
+    let files = proc.files();
+    let file = files.get(fd).ok_or(IoError::NotPresent)?;
+    super::get_vfs().locks().unlock(file, proc.id().into(), range);
+    Ok(())
+}
+
+async fn sync_fd(fd: Fd, data_only: bool) -> Result<(), IoError> {
+    let proc = crate::task::job::current().ok_or(IoError::NotPresent)?;
+    // Cloned so that the table is not locked while syncing
+    let file = proc.files().get(fd).ok_or(IoError::NotPresent)?.clone_file();
+    let file = super::file::cast_file!(NormalFile<u8>: file).map_err(|_| IoError::NotSupported)?;
+    file.sync(data_only).await
+}
@@ -123,10 +123,39 @@ pub trait File: Send + Sync {
     /// transparent to the operation of the file. All other effects are not permitted,
     ///
     /// Calling [File::b_file] on a B-side file is not permitted
+    ///
+    /// B-side files do not have permissions of their own, callers acting on behalf of a user must
+    /// use [super::perm::b_file] which checks the permissions of the A-side file.
     // todo distinguish b-files, maybe add UUID for file types to distinguish them?
     fn b_file(&self, _id: u64) -> Option<alloc::boxed::Box<dyn File>> {
         None
     }
+
+    /// Returns the metadata for this file.
+    ///
+    /// The default implementation constructs the metadata using the other methods of this trait,
+    /// this is sufficient for files which do not store ownership or timestamps.
+    fn metadata(&self) -> IoResult<FileMetadata> {
+        async {
+            Ok(FileMetadata::new(self.len().await?, self.block_size(), self.id(), self.device(), self.file_type()))
+        }.boxed()
+    }
+
+    /// Sets the permission bits of the file.
+    fn set_mode(&self, _mode: FileMode) -> IoResult<()> {
+        async { Err(IoError::NotSupported) }.boxed()
+    }
+
+    /// Sets the owning user and group of the file.
+    fn set_owner(&self, _uid: u32, _gid: u32) -> IoResult<()> {
+        async { Err(IoError::NotSupported) }.boxed()
+    }
+
+    /// Sets the access and modification times of the file in seconds since the Unix epoch.
+    /// Times which are `None` are not modified.
+    fn set_times(&self, _atime: Option<u64>, _mtime: Option<u64>) -> IoResult<()> {
+        async { Err(IoError::NotSupported) }.boxed()
+    }
 }
 
 self::file_derive_debug!(NormalFile<u8>);
@@ -176,6 +205,22 @@ pub trait NormalFile<T = u8>: Read<T> + Write<T> + File + Send + Sync {
     ///
     /// Note: The file may not be deleted from the filesystem while it is locked.
     unsafe fn unlock_unsafe(&self) -> IoResult<()>;
+
+    /// Makes all data written to the file durable (`fsync`). When this returns without error the
+    /// data can be read back after the system loses power.
+    ///
+    /// Implementations must first write back the file's dirty cached data, then the metadata
+    /// required to find it, then issue [BlockDev::flush] on the device holding it. When
+    /// `data_only` is `true` (`fdatasync`) metadata which is not required to read the data back,
+    /// e.g. timestamps, may be skipped.
+    ///
+    /// The default implementation returns `Ok(())`, this is correct for files which have no backing
+    /// storage, e.g. files in a tmpfs.
+    ///
+    /// [BlockDev::flush]: crate::system::sysfs::block::BlockDev::flush
+    fn sync(&self, _data_only: bool) -> IoResult<()> {
+        async { Ok(()) }.boxed()
+    }
 }
 
 /// This trait's methods may have side effects. Any side effects should be documented at the implementation level.
@@ -192,6 +237,20 @@ pub trait Read<T> {
     ///
     /// [IoError::NotPresent] - Will be returned when the file no longer exists.
     fn read<'f, 'a: 'f,'b: 'f>(&'a self, pos: u64, buff: DmaBuff<'b>) -> futures_util::future::BoxFuture<'f, Result<(DmaBuff<'b>, usize),(IoError,DmaBuff<'b>,usize)>>;
+
+    /// Lends the file's own storage containing up to `len` bytes from `pos`. This allows
+    /// [super::splice::splice] to pass data to a writer without copying it into an intermediate buffer.
+    ///
+    /// The returned buffer must not be written to. The file may block writers until the buffer is dropped.
+    ///
+    /// Files which do not keep their data in memory return `None`, the caller should use [Self::read] instead.
+    ///
+    /// # Errors
+    ///
+    /// [IoError::EndOfFile] - Will be returned when `pos` is at or beyond the end of the file.
+    fn lend<'f, 'a: 'f>(&'a self, _pos: u64, _len: usize) -> Option<futures_util::future::BoxFuture<'f, Result<DmaBuff<'a>, IoError>>> {
+        None
+    }
 }
 
 /// This trait's methods may have side effects. Any side effects should be documented at the implementation level.
@@ -324,6 +383,51 @@ pub trait Directory: File {
     /// If `name` is a VFS-managed device file then this fn must remove the entry from the directory
     /// and return [IoError::IsDevice] to inform the VFS to remove the device entry from the device-override list.
     fn remove<'f, 'a: 'f, 'b: 'f>(&'a self, name: &'b str) -> IoResult<'f, ()>;
+
+    /// Adds the entry `name` referring to `file` (a hard link) and increments the file's link count.
+    ///
+    /// `file` must be a file in the same filesystem as `self`, otherwise [IoError::CrossDevice] is
+    /// returned. Returns [IoError::AlreadyExists] if `name` exists and [IoError::PermissionDenied]
+    /// if `file` is a directory, directories can't be linked.
+    ///
+    /// The default implementation returns [IoError::NotSupported].
+    #[allow(unused_variables)]
+    fn link<'f, 'a: 'f, 'b: 'f>(&'a self, name: &'b str, file: &'b dyn File) -> IoResult<'f, ()> {
+        async { Err(IoError::NotSupported) }.boxed()
+    }
+
+    /// Removes the entry `name` and decrements the link count of the file it refers to, the file is
+    /// deleted once its last link is removed. Unlike [Self::remove] this returns
+    /// [IoError::IsDirectory] if `name` is a directory.
+    ///
+    /// The default implementation checks the type of the file before calling [Self::remove], this
+    /// races with renames of `name`. Implementations should override it.
+    fn unlink<'f, 'a: 'f, 'b: 'f>(&'a self, name: &'b str) -> IoResult<'f, ()> {
+        async move {
+            if self.get_file_meta(name).await?.file_type() == FileType::Directory {
+                return Err(IoError::IsDirectory);
+            }
+            self.remove(name).await
+        }.boxed()
+    }
+
+    /// Atomically moves the entry `old` to the entry `new` in `new_dir`, which may be `self`. If `new`
+    /// exists it is replaced, at no point is `new` absent. The link count of the file is unchanged.
+    ///
+    /// # Errors
+    ///
+    /// - [IoError::CrossDevice]: `new_dir` is in another filesystem.
+    /// - [IoError::NotPresent]: `old` does not exist.
+    /// - [IoError::IsDirectory]: `new` is a directory and `old` is not.
+    /// - [IoError::NotEmpty]: `new` is a directory which is not empty.
+    /// - [IoError::InvalidData]: `old` is a directory and `new` is not, or `new_dir` is within `old`.
+    ///
+    /// If `old` and `new` refer to the same file this does nothing. The default implementation
+    /// returns [IoError::NotSupported].
+    #[allow(unused_variables)]
+    fn rename<'f, 'a: 'f, 'b: 'f>(&'a self, old: &'b str, new_dir: &'b dyn Directory, new: &'b str) -> IoResult<'f, ()> {
+        async { Err(IoError::NotSupported) }.boxed()
+    }
 }
 
 #[macro_export]
@@ -406,6 +510,16 @@ pub struct FileMetadata {
     /// if the file is a mountpoint or special file this may not be the case.
     device: super::vfs::DevID,
     f_type: FileType,
+
+    mode: FileMode,
+    uid: u32,
+    gid: u32,
+    nlink: u64,
+
+    // Timestamps are given in seconds since the Unix epoch
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
 }
 
 impl FileMetadata {
@@ -423,20 +537,41 @@ impl FileMetadata {
             id,
             device,
             f_type: file_type,
+            mode: FileMode::default_for(file_type),
+            uid: 0,
+            gid: 0,
+            nlink: 1,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
         }
     }
 
 
     /// Constructs an instance of self using the information provided by `file`
     pub async fn new_from_file(file: &dyn File) -> Result<Self,IoError> {
-        Ok(Self {
-            unknown: false,
-            size: file.len().await?,
-            block_size: file.block_size().try_into().unwrap(),
-            id: file.id(),
-            device: file.device(),
-            f_type: file.file_type(),
-        })
+        file.metadata().await
+    }
+
+    /// Sets the ownership and permissions.
+    pub fn with_owner(mut self, mode: FileMode, uid: u32, gid: u32) -> Self {
+        self.mode = mode;
+        self.uid = uid;
+        self.gid = gid;
+        self
+    }
+
+    /// Sets the access, modification and status change times.
+    pub fn with_times(mut self, atime: u64, mtime: u64, ctime: u64) -> Self {
+        self.atime = atime;
+        self.mtime = mtime;
+        self.ctime = ctime;
+        self
+    }
+
+    pub fn with_nlink(mut self, nlink: u64) -> Self {
+        self.nlink = nlink;
+        self
     }
 
     /// File size in bytes
@@ -472,6 +607,38 @@ impl FileMetadata {
         self.device
     }
 
+    pub fn mode(&self) -> FileMode {
+        self.mode
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    /// Number of hard links to the file.
+    pub fn nlink(&self) -> u64 {
+        self.nlink
+    }
+
+    /// Time of the last access.
+    pub fn atime(&self) -> u64 {
+        self.atime
+    }
+
+    /// Time of the last modification to the file's contents.
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    /// Time of the last change to the file's contents or metadata.
+    pub fn ctime(&self) -> u64 {
+        self.ctime
+    }
+
     pub fn new_unknown() -> Self {
         Self {
             unknown: true,
@@ -480,6 +647,13 @@ impl FileMetadata {
             id: 0,
             device: DevID::NULL,
             f_type: FileType::NormalFile,
+            mode: FileMode::empty(),
+            uid: 0,
+            gid: 0,
+            nlink: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
         }
     }
 
@@ -489,6 +663,73 @@ impl FileMetadata {
 }
 
 
+/// Formats the metadata in the style of `ls -l`, excluding the file name.
+impl core::fmt::Display for FileMetadata {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let t = match self.f_type {
+            FileType::NormalFile => '-',
+            FileType::Directory | FileType::MountPoint => 'd',
+            FileType::CharDev => 'c',
+            FileType::BlkDev => 'b',
+        };
+        write!(f, "{t}{} {} {} {} {:>10} {}", self.mode, self.nlink, self.uid, self.gid, self.size, crate::time::rtc::DateTime::from_unix(self.mtime))
+    }
+}
+
+bitflags::bitflags! {
+    /// Permission bits of a file.
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub struct FileMode: u16 {
+        const OTHER_EXEC = 1;
+        const OTHER_WRITE = 1 << 1;
+        const OTHER_READ = 1 << 2;
+        const GROUP_EXEC = 1 << 3;
+        const GROUP_WRITE = 1 << 4;
+        const GROUP_READ = 1 << 5;
+        const OWNER_EXEC = 1 << 6;
+        const OWNER_WRITE = 1 << 7;
+        const OWNER_READ = 1 << 8;
+        const STICKY = 1 << 9;
+        const SET_GID = 1 << 10;
+        const SET_UID = 1 << 11;
+    }
+}
+
+impl FileMode {
+    /// Returns the default mode for files of type `file_type`.
+    ///
+    /// Device files are only accessible by their owner, directories are searchable by everyone and
+    /// all other files are readable by everyone.
+    pub const fn default_for(file_type: FileType) -> Self {
+        match file_type {
+            FileType::Directory | FileType::MountPoint => Self::from_bits_retain(0o755),
+            FileType::CharDev | FileType::BlkDev => Self::from_bits_retain(0o600),
+            FileType::NormalFile => Self::from_bits_retain(0o644),
+        }
+    }
+}
+
+/// Formats the mode as `rwxrwxrwx`
+impl core::fmt::Display for FileMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let special = [FileMode::SET_UID, FileMode::SET_GID, FileMode::STICKY];
+        for (i, c) in ['s', 's', 't'].into_iter().enumerate() {
+            let shift = 6 - (i * 3);
+            let r = self.bits() & (0o4 << shift) != 0;
+            let w = self.bits() & (0o2 << shift) != 0;
+            let x = self.bits() & (0o1 << shift) != 0;
+            let x = match (x, self.contains(special[i])) {
+                (true, true) => c,
+                (false, true) => c.to_ascii_uppercase(),
+                (true, false) => 'x',
+                (false, false) => '-',
+            };
+            write!(f, "{}{}{x}", if r { 'r' } else { '-' }, if w { 'w' } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum FileType {
     NormalFile,
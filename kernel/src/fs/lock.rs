@@ -0,0 +1,225 @@
+//! Advisory file locks.
+//!
+//! Locks are held by a [LockOwner] over a byte range of a file, a lock over the whole file is a
+//! lock over `0..u64::MAX`. Shared locks may overlap other shared locks, exclusive locks may not
+//! overlap any lock held by another owner. Locks held by the same owner never conflict, locking a
+//! range which overlaps the owner's existing locks replaces them within that range.
+//!
+//! These locks are advisory, they do not prevent file operations. Files are identified by their
+//! device and [File::id] so all file objects for the same file share locks.
+//!
+//! [LockManager::lock] waits for conflicting locks to be released. Before waiting it checks
+//! whether the owners it would wait for are themselves waiting on the caller, in which case it
+//! returns [IoError::Deadlock] instead.
+//!
+//! Tasks lock their descriptors using [super::fd::lock]. A task's locks on a file are released
+//! when it closes any descriptor for the file using [super::fd::close] and all of its locks are
+//! released when it exits.
+
+use super::file::File;
+use super::vfs::DevID;
+use super::IoError;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::task::{Poll, Waker};
+
+type FileId = (DevID, u64);
+
+/// Identifies the holder of a lock, locks taken through [super::fd] are held by the task.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct LockOwner(pub u64);
+
+impl From<crate::task::TaskId> for LockOwner {
+    fn from(id: crate::task::TaskId) -> Self {
+        Self(id.into())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LockKind {
+    Shared,
+    Exclusive,
+}
+
+/// A lock held on a file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileLock {
+    pub owner: LockOwner,
+    pub kind: LockKind,
+    pub range: Range<u64>,
+}
+
+impl FileLock {
+    /// Returns whether `self` prevents `owner` from acquiring a `kind` lock over `range`.
+    fn conflicts(&self, owner: LockOwner, kind: LockKind, range: &Range<u64>) -> bool {
+        self.owner != owner
+            && (self.kind == LockKind::Exclusive || kind == LockKind::Exclusive)
+            && self.range.start < range.end
+            && range.start < self.range.end
+    }
+}
+
+/// A request which is waiting for conflicting locks to be released.
+struct Waiter {
+    file: FileId,
+    kind: LockKind,
+    range: Range<u64>,
+}
+
+#[derive(Default)]
+struct LockState {
+    files: BTreeMap<FileId, Vec<FileLock>>,
+    waiting: BTreeMap<LockOwner, Waiter>,
+    wakers: BTreeMap<FileId, Vec<Waker>>,
+}
+
+impl LockState {
+    /// Returns the owners of locks which conflict with the request.
+    fn blockers(&self, file: FileId, owner: LockOwner, kind: LockKind, range: &Range<u64>) -> Vec<LockOwner> {
+        self.files
+            .get(&file)
+            .map(|l| l.iter().filter(|l| l.conflicts(owner, kind, range)).map(|l| l.owner).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns whether `owner` waiting on the request would complete a cycle in the wait-for graph.
+    fn would_deadlock(&self, file: FileId, owner: LockOwner, kind: LockKind, range: &Range<u64>) -> bool {
+        let mut visited = Vec::new();
+        let mut stack = self.blockers(file, owner, kind, range);
+        while let Some(o) = stack.pop() {
+            if o == owner {
+                return true;
+            }
+            if visited.contains(&o) {
+                continue;
+            }
+            visited.push(o);
+            if let Some(w) = self.waiting.get(&o) {
+                stack.extend(self.blockers(w.file, o, w.kind, &w.range));
+            }
+        }
+        false
+    }
+
+    /// Removes `range` from all locks held by `owner` on `file`, splitting locks where necessary.
+    fn release(&mut self, file: FileId, owner: LockOwner, range: &Range<u64>) {
+        let Some(locks) = self.files.get_mut(&file) else { return };
+        let mut new = Vec::with_capacity(locks.len());
+        for l in locks.drain(..) {
+            if l.owner != owner || l.range.end <= range.start || range.end <= l.range.start {
+                new.push(l);
+                continue;
+            }
+            if l.range.start < range.start {
+                new.push(FileLock { range: l.range.start..range.start, ..l.clone() });
+            }
+            if range.end < l.range.end {
+                new.push(FileLock { range: range.end..l.range.end, ..l });
+            }
+        }
+        *locks = new;
+        if locks.is_empty() {
+            self.files.remove(&file);
+        }
+        self.wake(file);
+    }
+
+    fn wake(&mut self, file: FileId) {
+        for w in self.wakers.remove(&file).unwrap_or_default() {
+            w.wake();
+        }
+    }
+}
+
+/// Tracks advisory locks for all files. See the module level documentation for details.
+pub struct LockManager {
+    state: spin::Mutex<LockState>,
+}
+
+impl LockManager {
+    pub(crate) fn new() -> Self {
+        Self { state: spin::Mutex::new(LockState::default()) }
+    }
+
+    fn file_id(file: &dyn File) -> FileId {
+        (file.device(), file.id())
+    }
+
+    /// Attempts to acquire a lock without waiting. Returns [IoError::WouldBlock] if a conflicting lock is held.
+    pub fn try_lock(&self, file: &dyn File, owner: LockOwner, kind: LockKind, range: Range<u64>) -> Result<(), IoError> {
+        let file = Self::file_id(file);
+        let mut state = self.state.lock();
+        if !state.blockers(file, owner, kind, &range).is_empty() {
+            return Err(IoError::WouldBlock);
+        }
+        state.release(file, owner, &range);
+        state.files.entry(file).or_default().push(FileLock { owner, kind, range });
+        Ok(())
+    }
+
+    /// Acquires a lock, waiting for conflicting locks to be released.
+    ///
+    /// Returns [IoError::Deadlock] if waiting for the lock would deadlock. `owner` may only wait
+    /// on one lock at a time.
+    pub async fn lock(&self, file: &dyn File, owner: LockOwner, kind: LockKind, range: Range<u64>) -> Result<(), IoError> {
+        let id = Self::file_id(file);
+        let _guard = WaitGuard { manager: self, owner };
+        core::future::poll_fn(|cx| {
+            let mut state = self.state.lock();
+            if state.blockers(id, owner, kind, &range).is_empty() {
+                state.waiting.remove(&owner);
+                state.release(id, owner, &range);
+                state.files.entry(id).or_default().push(FileLock { owner, kind, range: range.clone() });
+                return Poll::Ready(Ok(()));
+            }
+            // Other owners may have started waiting on `owner` since the last poll
+            if state.would_deadlock(id, owner, kind, &range) {
+                state.waiting.remove(&owner);
+                return Poll::Ready(Err(IoError::Deadlock));
+            }
+            state.waiting.insert(owner, Waiter { file: id, kind, range: range.clone() });
+            state.wakers.entry(id).or_default().push(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Releases `range` from all locks held by `owner` on `file`.
+    pub fn unlock(&self, file: &dyn File, owner: LockOwner, range: Range<u64>) {
+        self.state.lock().release(Self::file_id(file), owner, &range);
+    }
+
+    /// Releases all locks held by `owner` on all files.
+    pub fn unlock_all(&self, owner: LockOwner) {
+        let mut state = self.state.lock();
+        state.waiting.remove(&owner);
+        let files: Vec<FileId> = state.files.keys().copied().collect();
+        for f in files {
+            state.release(f, owner, &(0..u64::MAX));
+        }
+    }
+
+    /// Returns a lock which would prevent `owner` from acquiring the requested lock, if there is one.
+    pub fn query(&self, file: &dyn File, owner: LockOwner, kind: LockKind, range: Range<u64>) -> Option<FileLock> {
+        let state = self.state.lock();
+        state.files.get(&Self::file_id(file))?.iter().find(|l| l.conflicts(owner, kind, &range)).cloned()
+    }
+
+    /// Returns all locks held on `file`.
+    pub fn locks(&self, file: &dyn File) -> Vec<FileLock> {
+        self.state.lock().files.get(&Self::file_id(file)).cloned().unwrap_or_default()
+    }
+}
+
+/// Removes the owner from the wait-for graph when [LockManager::lock] completes or is cancelled.
+struct WaitGuard<'a> {
+    manager: &'a LockManager,
+    owner: LockOwner,
+}
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        self.manager.state.lock().waiting.remove(&self.owner);
+    }
+}
@@ -7,6 +7,18 @@ pub mod vfs;
 pub mod file;
 pub mod device;
 pub mod tmpfs;
+pub mod report;
+pub mod devfs;
+pub mod splice;
+pub mod ring;
+pub mod perm;
+pub mod lock;
+pub mod disk_util;
+pub mod fd;
+pub mod writeback;
+pub mod quota;
+pub mod statfs;
+pub mod overlay;
 
 /// Contains the systems VFS. It may not be constructed until a root filesystem can be acquired.
 ///
@@ -25,6 +37,7 @@ pub fn init_fs(vfs: alloc::boxed::Box<dyn device::FileSystem>) {
     assert!(unsafe { VIRTUAL_FILE_SYSTEM.is_none() });
     log::debug!("Initializing VFS with: {} type: {}",vfs.device(), vfs.driver_name());
     unsafe { VIRTUAL_FILE_SYSTEM = Some(alloc::boxed::Box::new(vfs::VirtualFileSystem::new(vfs))); }
+    devfs::init();
 }
 
 /// Returns a reference to the VFS.
@@ -91,7 +104,30 @@ pub enum IoError {
     NotReady,
 
     /// Returned by [file::Write::write] when the input is an invalid variant of the character
-    InvalidData
+    InvalidData,
+
+    /// Returned by a file in non-blocking mode when the operation could not be completed without waiting.
+    /// No data was transferred and the operation may be retried later.
+    WouldBlock,
+
+    /// The caller's credentials do not permit the requested access. See [perm].
+    PermissionDenied,
+
+    /// Waiting for the requested resource would never complete because the waiter holds a
+    /// resource required to release it.
+    Deadlock,
+
+    /// The caller has reached a resource limit. See [crate::task::rlimit].
+    LimitExceeded,
+
+    /// Attempted to link or rename a file onto another filesystem.
+    CrossDevice,
+
+    /// Attempted an operation on a directory which requires a non-directory file.
+    IsDirectory,
+
+    /// The filesystem's quota does not permit the allocation. See [quota].
+    QuotaExceeded,
 }
 
 /// Generic test for a FileSystem implementation.
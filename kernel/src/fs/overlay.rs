@@ -0,0 +1,753 @@
+//! Union mount of a read-only lower filesystem and a writable upper filesystem.
+//!
+//! [OverlayFs] presents the merged tree of both layers. A name present in the upper layer is
+//! looked up there, otherwise it is looked up in the lower layer. Directories which exist in both
+//! layers are merged, their entries are the union of both directories and upper entries shadow
+//! lower entries with the same name. Files of other types shadow lower files completely.
+//!
+//! The lower layer is never modified, e.g. an initramfs or a read only disk image. All
+//! modifications are made to the upper layer, which is normally a [TmpFsRoot] (see
+//! [OverlayFs::with_tmpfs]) so that a live image appears writable while its writes are kept in
+//! memory.
+//!
+//! # Copy-up
+//!
+//! A lower file is copied into the upper layer before it is modified, this includes writes and
+//! changes to its attributes. Its parent directories are copied up first, copying a directory only
+//! creates it, its entries remain in the lower layer. Special files are not copied up and are
+//! accessed in the layer which contains them.
+//!
+//! # Whiteouts
+//!
+//! Removing a file which exists in the lower layer creates an empty file named `.wh.<name>` in
+//! the upper directory, which hides `name` in the lower directory. Creating `name` again removes
+//! the whiteout. When a directory is created in place of a whited out entry it is made opaque by
+//! creating `.wh..wh..opq` inside it, which hides the entries of the lower directory. Names
+//! starting with `.wh.` are reserved and are never visible through the overlay.
+//!
+//! Hard links and renames are not supported.
+//!
+//! Overlays are mounted using [VirtualFileSystem::mount_overlay](super::vfs::VirtualFileSystem::mount_overlay)
+//! or the `/bin/overlay LOWER MOUNTPOINT [UPPER]` program, where `LOWER` and `UPPER` are the
+//! mountpoints of the layers. Without `UPPER` modifications are kept in a tmpfs.
+
+use super::device::{DeviceFile, Fifo, FileSystem};
+use super::file::*;
+use super::statfs::FsStat;
+use super::tmpfs::TmpFsRoot;
+use super::vfs::{DevID, MajorNum};
+use super::{IoError, IoResult, PARENT_DIR, THIS_DIR};
+use crate::error::KernelError;
+use crate::mem::dma::DmaBuff;
+use crate::task::spawn::Args;
+use crate::task::TaskResult;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+
+static MAJOR: spin::Lazy<MajorNum> = spin::Lazy::new(|| MajorNum::register("overlay").unwrap());
+static MINOR: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Marks a directory as opaque, see the module documentation.
+const OPAQUE: &str = ".wh..wh..opq";
+
+fn whiteout(name: &str) -> String {
+    alloc::format!("{WHITEOUT_PREFIX}{name}")
+}
+
+fn as_dir(file: &dyn File) -> Option<Box<dyn Directory>> {
+    cast_file!(Directory: file.clone_file()).ok()
+}
+
+fn new_file_err((e, src): (Option<IoError>, Option<IoError>)) -> IoError {
+    e.or(src).unwrap_or(IoError::DeviceError)
+}
+
+/// A file in the merged tree. Nodes are shared by all file objects referring to the same path so
+/// that a copy-up is seen by all of them.
+struct Node {
+    fs: Weak<OverlayInner>,
+    /// The path from the root of the overlay, empty for the root.
+    path: String,
+    name: String,
+    id: u64,
+    /// `None` for the root.
+    parent: Option<Arc<Node>>,
+    ty: FileType,
+    /// Set when the file is copied up.
+    upper: spin::RwLock<Option<Box<dyn File>>>,
+    lower: Option<Box<dyn File>>,
+    /// Entries of the lower directory are hidden.
+    opaque: bool,
+}
+
+impl Node {
+    fn upper(&self) -> Option<Box<dyn File>> {
+        self.upper.read().as_ref().map(|f| f.clone_file())
+    }
+
+    /// Returns the file in the topmost layer containing `self`.
+    fn top(&self) -> Box<dyn File> {
+        // A node which is not in the upper layer is in the lower one
+        self.upper().unwrap_or_else(|| self.lower.as_ref().unwrap().clone_file())
+    }
+
+    fn upper_dir(&self) -> Option<Box<dyn Directory>> {
+        as_dir(&*self.upper()?)
+    }
+
+    fn lower_dir(&self) -> Option<Box<dyn Directory>> {
+        if self.opaque {
+            return None;
+        }
+        as_dir(&**self.lower.as_ref()?)
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let Some(fs) = self.fs.upgrade() else { return };
+        let mut l = fs.nodes.lock();
+        if l.get(&self.path).is_some_and(|n| n.strong_count() == 0) {
+            l.remove(&self.path);
+        }
+        drop(l);
+    }
+}
+
+struct OverlayInner {
+    lower: Box<dyn FileSystem>,
+    upper: Box<dyn FileSystem>,
+    dev: DevID,
+    opts: spin::RwLock<FsOpts>,
+    root: Arc<Node>,
+    nodes: spin::Mutex<BTreeMap<String, Weak<Node>>>,
+    /// IDs are kept after their node is dropped so that the ID of a path never changes.
+    ids: spin::Mutex<BTreeMap<String, u64>>,
+    /// Serializes copy-ups so that each file is copied once.
+    copy_lock: async_lock::Mutex<()>,
+}
+
+impl OverlayInner {
+    fn id(&self, path: &str) -> u64 {
+        let mut l = self.ids.lock();
+        let next = l.len() as u64 + 1; // the root is 0
+        *l.entry(path.to_string()).or_insert(next)
+    }
+
+    /// Constructs a file object for `node`.
+    fn file(self: &Arc<Self>, node: Arc<Node>) -> Box<dyn File> {
+        match node.ty {
+            FileType::Directory => Box::new(OvlDir { fs: self.clone(), node }),
+            FileType::NormalFile => Box::new(OvlFile { fs: self.clone(), node }),
+            _ => node.top(),
+        }
+    }
+
+    /// Looks up `name` in the merged directory `parent`.
+    async fn lookup(self: &Arc<Self>, parent: &Arc<Node>, name: &str) -> Result<Arc<Node>, IoError> {
+        if name.starts_with(WHITEOUT_PREFIX) {
+            return Err(IoError::NotPresent);
+        }
+        let path = alloc::format!("{}/{name}", parent.path);
+        if let Some(n) = self.nodes.lock().get(&path).and_then(Weak::upgrade) {
+            return Ok(n);
+        }
+
+        let mut upper = None;
+        if let Some(dir) = parent.upper_dir() {
+            match dir.get_file(name).await {
+                Ok(f) => upper = Some(f),
+                Err(IoError::NotPresent) => {
+                    if dir.get_file(&whiteout(name)).await.is_ok() {
+                        return Err(IoError::NotPresent);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let upper_is_dir = upper.as_ref().map(|f| f.file_type() == FileType::Directory);
+        let lower = match parent.lower_dir() {
+            Some(dir) if upper_is_dir != Some(false) => match dir.get_file(name).await {
+                // A directory is only merged with another directory
+                Ok(f) if upper_is_dir.is_none() || f.file_type() == FileType::Directory => Some(f),
+                Ok(_) | Err(IoError::NotPresent) => None,
+                Err(e) => return Err(e),
+            },
+            _ => None,
+        };
+
+        let ty = upper.as_ref().or(lower.as_ref()).ok_or(IoError::NotPresent)?.file_type();
+        let opaque = match upper.as_deref().and_then(as_dir) {
+            Some(dir) if lower.is_some() => dir.get_file(OPAQUE).await.is_ok(),
+            _ => false,
+        };
+        let node = Arc::new(Node {
+            fs: Arc::downgrade(self),
+            id: self.id(&path),
+            path: path.clone(),
+            name: name.to_string(),
+            parent: Some(parent.clone()),
+            ty,
+            upper: spin::RwLock::new(upper),
+            lower,
+            opaque,
+        });
+
+        // Another task may have looked up the same path, the first node is used.
+        // `node` must be dropped after unlocking because its drop locks the node list
+        let existing = {
+            let mut l = self.nodes.lock();
+            match l.get(&path).and_then(Weak::upgrade) {
+                Some(n) => Some(n),
+                None => {
+                    l.insert(path, Arc::downgrade(&node));
+                    None
+                }
+            }
+        };
+        Ok(existing.unwrap_or(node))
+    }
+
+    /// Removes `node` and all nodes beneath it from the node list, later lookups of their paths
+    /// will look them up again.
+    fn forget(&self, node: &Node) {
+        let prefix = alloc::format!("{}/", node.path);
+        let mut l = self.nodes.lock();
+        if l.get(&node.path).is_some_and(|n| core::ptr::eq(n.as_ptr(), node)) {
+            l.remove(&node.path);
+        }
+        l.retain(|path, _| !path.starts_with(&prefix));
+    }
+
+    /// Copies `node` into the upper layer, see the module documentation. Returns the upper file.
+    async fn copy_up(&self, node: &Arc<Node>) -> Result<Box<dyn File>, IoError> {
+        if let Some(f) = node.upper() {
+            return Ok(f);
+        }
+        let _l = self.copy_lock.lock().await;
+
+        let mut chain = Vec::new();
+        let mut cur = node.clone();
+        while cur.upper().is_none() {
+            let parent = cur.parent.clone().unwrap(); // The root is always in the upper layer
+            chain.push(cur);
+            cur = parent;
+        }
+
+        for n in chain.into_iter().rev() {
+            let dir = n.parent.as_ref().unwrap().upper_dir().ok_or(IoError::NotPresent)?;
+            let lower = n.lower.as_ref().unwrap();
+            match n.ty {
+                FileType::Directory => {
+                    dir.new_dir(&n.name).await?;
+                }
+                FileType::NormalFile => {
+                    let mut src = cast_file!(NormalFile<u8>: lower.clone_file()).map_err(|_| IoError::NotSupported)?;
+                    dir.new_file(&n.name, Some(&mut *src)).await.map_err(new_file_err)?;
+                }
+                _ => return Err(IoError::NotSupported),
+            }
+            let upper = dir.get_file(&n.name).await?;
+
+            // Attributes which the upper layer does not support are not copied
+            let meta = lower.metadata().await?;
+            let _ = upper.set_mode(meta.mode()).await;
+            let _ = upper.set_owner(meta.uid(), meta.gid()).await;
+            let _ = upper.set_times(Some(meta.atime()), Some(meta.mtime())).await;
+            *n.upper.write() = Some(upper);
+        }
+        Ok(node.upper().unwrap())
+    }
+
+    /// Copies up the directory `node` and returns it.
+    async fn upper_dir(&self, node: &Arc<Node>) -> Result<Box<dyn Directory>, IoError> {
+        as_dir(&*self.copy_up(node).await?).ok_or(IoError::NotPresent)
+    }
+
+    async fn metadata(&self, node: &Node) -> Result<FileMetadata, IoError> {
+        let m = node.top().metadata().await?;
+        Ok(FileMetadata::new(m.size(), m.block_size(), node.id, self.dev, node.ty)
+            .with_owner(m.mode(), m.uid(), m.gid())
+            .with_times(m.atime(), m.mtime(), m.ctime())
+            .with_nlink(m.nlink()))
+    }
+
+    fn set_mode<'f>(&'f self, node: &'f Arc<Node>, mode: FileMode) -> IoResult<'f, ()> {
+        async move { self.copy_up(node).await?.set_mode(mode).await }.boxed()
+    }
+
+    fn set_owner<'f>(&'f self, node: &'f Arc<Node>, uid: u32, gid: u32) -> IoResult<'f, ()> {
+        async move { self.copy_up(node).await?.set_owner(uid, gid).await }.boxed()
+    }
+
+    fn set_times<'f>(&'f self, node: &'f Arc<Node>, atime: Option<u64>, mtime: Option<u64>) -> IoResult<'f, ()> {
+        async move { self.copy_up(node).await?.set_times(atime, mtime).await }.boxed()
+    }
+}
+
+#[derive(Clone)]
+#[cast_trait_object::dyn_cast(File => NormalFile<u8>, Directory, FileSystem, Fifo<u8>, DeviceFile)]
+#[cast_trait_object::dyn_upcast(File)]
+pub struct OverlayFs {
+    inner: Arc<OverlayInner>,
+}
+
+impl OverlayFs {
+    /// Constructs an overlay of `upper` over `lower`, see the module documentation.
+    ///
+    /// `upper` must be writable and should be empty or a previous upper layer of `lower`.
+    pub fn new(lower: Box<dyn FileSystem>, upper: Box<dyn FileSystem>) -> Box<dyn FileSystem> {
+        let inner = Arc::new_cyclic(|fs| OverlayInner {
+            root: Arc::new(Node {
+                fs: fs.clone(),
+                path: String::new(),
+                name: String::new(),
+                id: 0,
+                parent: None,
+                ty: FileType::Directory,
+                upper: spin::RwLock::new(Some(upper.root().clone_file())),
+                lower: Some(lower.root().clone_file()),
+                opaque: false,
+            }),
+            lower,
+            upper,
+            dev: DevID::new(*MAJOR, MINOR.fetch_add(1, core::sync::atomic::Ordering::Relaxed)),
+            opts: spin::RwLock::new(FsOpts::new(false, true)),
+            nodes: spin::Mutex::new(BTreeMap::new()),
+            ids: spin::Mutex::new(BTreeMap::new()),
+            copy_lock: async_lock::Mutex::new(()),
+        });
+        Box::new(Self { inner })
+    }
+
+    /// Constructs an overlay of an empty tmpfs over `lower`, modifications are kept in memory.
+    pub fn with_tmpfs(lower: Box<dyn FileSystem>) -> Box<dyn FileSystem> {
+        Self::new(lower, TmpFsRoot::new())
+    }
+
+    fn root_dir(&self) -> OvlDir {
+        OvlDir { fs: self.inner.clone(), node: self.inner.root.clone() }
+    }
+}
+
+impl File for OverlayFs {
+    fn file_type(&self) -> FileType {
+        FileType::Directory
+    }
+
+    fn block_size(&self) -> u64 {
+        crate::mem::PAGE_SIZE as u64
+    }
+
+    fn device(&self) -> DevID {
+        self.inner.dev
+    }
+
+    fn clone_file(&self) -> Box<dyn File> {
+        Box::new(self.clone())
+    }
+
+    fn id(&self) -> u64 {
+        0
+    }
+
+    fn len(&self) -> IoResult<u64> {
+        async { self.root_dir().len().await }.boxed()
+    }
+}
+
+impl DeviceFile for OverlayFs {}
+
+impl FileSystem for OverlayFs {
+    fn root(&self) -> Box<dyn Directory> {
+        Box::new(self.root_dir())
+    }
+
+    fn get_opt(&self, option: &str) -> Option<FsOptionVariant> {
+        self.inner.opts.read().get(option)
+    }
+
+    /// Options are not passed to the layers, they must be configured before they are given to
+    /// [OverlayFs::new].
+    fn set_opts(&mut self, options: &str) {
+        let mut new_opts = FsOpts::new(false, true);
+        for i in options.split_whitespace() {
+            match i {
+                "NODEV" => {
+                    new_opts.set(FsOpts::DEV_ALLOWED.to_string(), FsOpts::FALSE.to_string());
+                }
+                "NOCACHE" => {}
+                e => log::warn!(r#"Unknown option "{e}" will be ignored"#),
+            }
+        }
+        *self.inner.opts.write() = new_opts;
+    }
+
+    fn driver_name(&self) -> &'static str {
+        "overlay"
+    }
+
+    fn raw_file(&self) -> Option<&str> {
+        None
+    }
+
+    fn quota(&self) -> Option<&super::quota::Quota> {
+        self.inner.upper.quota()
+    }
+
+    /// Returns the capacity of the upper layer, which holds all modifications.
+    fn statfs(&self) -> IoResult<FsStat> {
+        self.inner.upper.statfs()
+    }
+}
+
+#[derive(Clone)]
+#[cast_trait_object::dyn_cast(File => NormalFile<u8>, Directory, FileSystem, Fifo<u8>, DeviceFile)]
+#[cast_trait_object::dyn_upcast(File)]
+struct OvlDir {
+    fs: Arc<OverlayInner>,
+    node: Arc<Node>,
+}
+
+impl OvlDir {
+    /// Returns an error if `name` is already visible in `self`.
+    async fn check_vacant(&self, name: &str) -> Result<(), IoError> {
+        if name.starts_with(WHITEOUT_PREFIX) || name == THIS_DIR || name == PARENT_DIR {
+            return Err(IoError::InvalidData);
+        }
+        match self.fs.lookup(&self.node, name).await {
+            Ok(_) => Err(IoError::AlreadyExists),
+            Err(IoError::NotPresent) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl File for OvlDir {
+    fn file_type(&self) -> FileType {
+        FileType::Directory
+    }
+
+    fn block_size(&self) -> u64 {
+        crate::mem::PAGE_SIZE as u64
+    }
+
+    fn device(&self) -> DevID {
+        self.fs.dev
+    }
+
+    fn clone_file(&self) -> Box<dyn File> {
+        Box::new(self.clone())
+    }
+
+    fn id(&self) -> u64 {
+        self.node.id
+    }
+
+    fn len(&self) -> IoResult<u64> {
+        async { Ok(self.file_list().await?.len() as u64) }.boxed()
+    }
+
+    fn metadata(&self) -> IoResult<FileMetadata> {
+        async { self.fs.metadata(&self.node).await }.boxed()
+    }
+
+    fn set_mode(&self, mode: FileMode) -> IoResult<()> {
+        self.fs.set_mode(&self.node, mode)
+    }
+
+    fn set_owner(&self, uid: u32, gid: u32) -> IoResult<()> {
+        self.fs.set_owner(&self.node, uid, gid)
+    }
+
+    fn set_times(&self, atime: Option<u64>, mtime: Option<u64>) -> IoResult<()> {
+        self.fs.set_times(&self.node, atime, mtime)
+    }
+}
+
+impl Directory for OvlDir {
+    fn entries(&self) -> IoResult<usize> {
+        async { Ok(self.file_list().await?.len()) }.boxed()
+    }
+
+    fn new_file<'f, 'b: 'f, 'a: 'f>(&'a self, name: &'b str, file: Option<&'b mut dyn NormalFile<u8>>) -> BoxFuture<'f, Result<(), (Option<IoError>, Option<IoError>)>> {
+        async move {
+            self.check_vacant(name).await.map_err(|e| (Some(e), None))?;
+            let dir = self.fs.upper_dir(&self.node).await.map_err(|e| (Some(e), None))?;
+            dir.new_file(name, file).await?;
+            // The new file already shadows the whiteout
+            match dir.remove(&whiteout(name)).await {
+                Ok(()) | Err(IoError::NotPresent) => Ok(()),
+                Err(e) => Err((Some(e), None)),
+            }
+        }
+        .boxed()
+    }
+
+    fn new_dir<'f, 'a: 'f, 'b: 'f>(&'a self, name: &'b str) -> IoResult<'f, Box<dyn Directory>> {
+        async move {
+            self.check_vacant(name).await?;
+            let dir = self.fs.upper_dir(&self.node).await?;
+            let whiteout = whiteout(name);
+            let replaces = dir.get_file(&whiteout).await.is_ok();
+            let new = dir.new_dir(name).await?;
+            if replaces {
+                // Made opaque before the whiteout is removed so the lower entries are never visible
+                new.new_file(OPAQUE, None).await.map_err(new_file_err)?;
+                dir.remove(&whiteout).await?;
+            }
+            let node = self.fs.lookup(&self.node, name).await?;
+            cast_file!(Directory: self.fs.file(node)).map_err(|_| IoError::NotPresent)
+        }
+        .boxed()
+    }
+
+    /// Device files are stored in the upper layer.
+    fn store<'f, 'a: 'f, 'b: 'f>(&'a self, name: &'b str, file: Box<dyn File>) -> IoResult<'f, ()> {
+        async move { self.fs.upper_dir(&self.node).await?.store(name, file).await }.boxed()
+    }
+
+    fn get_file<'f, 'a: 'f, 'b: 'f>(&'a self, name: &'b str) -> IoResult<'f, Box<dyn File>> {
+        async move {
+            if name == THIS_DIR {
+                return Ok(self.clone_file());
+            } else if name == PARENT_DIR {
+                return match &self.node.parent {
+                    Some(p) => Ok(self.fs.file(p.clone())),
+                    None => Err(IoError::IsDevice),
+                };
+            }
+            Ok(self.fs.file(self.fs.lookup(&self.node, name).await?))
+        }
+        .boxed()
+    }
+
+    fn get_file_with_meta<'f, 'a: 'f, 'b: 'f>(&'a self, name: &'b str) -> IoResult<'f, FileHandle> {
+        async move {
+            if name == PARENT_DIR && self.node.parent.is_none() {
+                return Ok(FileHandle::new_dev(FileMetadata::new_unknown()));
+            }
+            let file = self.get_file(name).await?;
+            let meta = file.metadata().await?;
+            Ok(FileHandle::new(file, false, meta))
+        }
+        .boxed()
+    }
+
+    fn file_list(&self) -> IoResult<Vec<String>> {
+        async {
+            let mut names = BTreeSet::new();
+            let mut hidden = BTreeSet::new();
+            if let Some(dir) = self.node.upper_dir() {
+                for name in dir.file_list().await? {
+                    match name.strip_prefix(WHITEOUT_PREFIX) {
+                        Some(n) => {
+                            hidden.insert(n.to_string());
+                        }
+                        None => {
+                            names.insert(name);
+                        }
+                    }
+                }
+            }
+            if let Some(dir) = self.node.lower_dir() {
+                for name in dir.file_list().await? {
+                    if !hidden.contains(&name) && !name.starts_with(WHITEOUT_PREFIX) {
+                        names.insert(name);
+                    }
+                }
+            }
+            names.insert(THIS_DIR.to_string());
+            names.insert(PARENT_DIR.to_string());
+            Ok(names.into_iter().collect())
+        }
+        .boxed()
+    }
+
+    fn remove<'f, 'a: 'f, 'b: 'f>(&'a self, name: &'b str) -> IoResult<'f, ()> {
+        async move {
+            if name == THIS_DIR || name == PARENT_DIR {
+                return Err(IoError::InvalidData);
+            }
+            let node = self.fs.lookup(&self.node, name).await?;
+            if let Some(dir) = node.upper_dir() {
+                let merged = OvlDir { fs: self.fs.clone(), node: node.clone() };
+                if merged.file_list().await?.len() > 2 {
+                    return Err(IoError::NotEmpty);
+                }
+                // The upper directory may still contain whiteouts
+                for n in dir.file_list().await? {
+                    if n.starts_with(WHITEOUT_PREFIX) {
+                        dir.remove(&n).await?;
+                    }
+                }
+            } else if node.ty == FileType::Directory && node.lower_dir().is_some() {
+                let merged = OvlDir { fs: self.fs.clone(), node: node.clone() };
+                if merged.file_list().await?.len() > 2 {
+                    return Err(IoError::NotEmpty);
+                }
+            }
+
+            let dir = self.fs.upper_dir(&self.node).await?;
+            let mut r = Ok(());
+            if node.upper().is_some() {
+                match dir.remove(name).await {
+                    Ok(()) => {}
+                    // The VFS must still remove the device from its list
+                    Err(IoError::IsDevice) => r = Err(IoError::IsDevice),
+                    Err(e) => return Err(e),
+                }
+            }
+            if node.lower.is_some() {
+                dir.new_file(&whiteout(name), None).await.map_err(new_file_err)?;
+            }
+            self.fs.forget(&node);
+            r
+        }
+        .boxed()
+    }
+}
+
+#[derive(Clone)]
+#[cast_trait_object::dyn_cast(File => NormalFile<u8>, Directory, FileSystem, Fifo<u8>, DeviceFile)]
+#[cast_trait_object::dyn_upcast(File)]
+struct OvlFile {
+    fs: Arc<OverlayInner>,
+    node: Arc<Node>,
+}
+
+impl OvlFile {
+    fn top(&self) -> Result<Box<dyn NormalFile<u8>>, IoError> {
+        cast_file!(NormalFile<u8>: self.node.top()).map_err(|_| IoError::NotSupported)
+    }
+
+    async fn upper(&self) -> Result<Box<dyn NormalFile<u8>>, IoError> {
+        cast_file!(NormalFile<u8>: self.fs.copy_up(&self.node).await?).map_err(|_| IoError::NotSupported)
+    }
+}
+
+impl File for OvlFile {
+    fn file_type(&self) -> FileType {
+        FileType::NormalFile
+    }
+
+    fn block_size(&self) -> u64 {
+        self.node.top().block_size()
+    }
+
+    fn device(&self) -> DevID {
+        self.fs.dev
+    }
+
+    fn clone_file(&self) -> Box<dyn File> {
+        Box::new(self.clone())
+    }
+
+    fn id(&self) -> u64 {
+        self.node.id
+    }
+
+    fn len(&self) -> IoResult<u64> {
+        async { self.node.top().len().await }.boxed()
+    }
+
+    fn metadata(&self) -> IoResult<FileMetadata> {
+        async { self.fs.metadata(&self.node).await }.boxed()
+    }
+
+    fn set_mode(&self, mode: FileMode) -> IoResult<()> {
+        self.fs.set_mode(&self.node, mode)
+    }
+
+    fn set_owner(&self, uid: u32, gid: u32) -> IoResult<()> {
+        self.fs.set_owner(&self.node, uid, gid)
+    }
+
+    fn set_times(&self, atime: Option<u64>, mtime: Option<u64>) -> IoResult<()> {
+        self.fs.set_times(&self.node, atime, mtime)
+    }
+}
+
+impl NormalFile<u8> for OvlFile {
+    fn len_chars(&self) -> IoResult<u64> {
+        self.len()
+    }
+
+    /// Locking is not supported, the file accessed changes when the file is copied up.
+    fn file_lock<'a>(self: Box<Self>) -> BoxFuture<'a, Result<LockedFile<u8>, (IoError, Box<dyn NormalFile<u8>>)>> {
+        async { Err((IoError::NotSupported, self as Box<dyn NormalFile<u8>>)) }.boxed()
+    }
+
+    unsafe fn unlock_unsafe(&self) -> IoResult<()> {
+        async { Err(IoError::Exclusive) }.boxed()
+    }
+
+    /// Files which have not been copied up have not been modified.
+    fn sync(&self, data_only: bool) -> IoResult<()> {
+        async move {
+            match self.node.upper() {
+                Some(f) => cast_file!(NormalFile<u8>: f).map_err(|_| IoError::NotSupported)?.sync(data_only).await,
+                None => Ok(()),
+            }
+        }
+        .boxed()
+    }
+}
+
+impl Read<u8> for OvlFile {
+    fn read<'f, 'a: 'f, 'b: 'f>(&'a self, pos: u64, dbuff: DmaBuff<'b>) -> BoxFuture<'f, Result<(DmaBuff<'b>, usize), (IoError, DmaBuff<'b>, usize)>> {
+        async move {
+            match self.top() {
+                Ok(file) => file.read(pos, dbuff).await,
+                Err(e) => Err((e, dbuff, 0)),
+            }
+        }
+        .boxed()
+    }
+}
+
+impl Write<u8> for OvlFile {
+    /// Copies the file up before the first write.
+    fn write<'f, 'a: 'f, 'b: 'f>(&'a self, pos: u64, dbuff: DmaBuff<'b>) -> BoxFuture<'f, Result<(DmaBuff<'b>, usize), (IoError, DmaBuff<'b>, usize)>> {
+        async move {
+            match self.upper().await {
+                Ok(file) => file.write(pos, dbuff).await,
+                Err(e) => Err((e, dbuff, 0)),
+            }
+        }
+        .boxed()
+    }
+}
+
+fn program(args: Args) -> Pin<Box<dyn Future<Output = TaskResult> + Send>> {
+    Box::pin(async move {
+        let (lower, mountpoint, upper) = match args.argv.get(1..).unwrap_or_default() {
+            [lower, mountpoint] => (lower, mountpoint, None),
+            [lower, mountpoint, upper] => (lower, mountpoint, Some(upper.as_str())),
+            _ => {
+                crate::serial_println!("Usage: overlay LOWER MOUNTPOINT [UPPER]");
+                return TaskResult::Error;
+            }
+        };
+        match super::get_vfs().mount_overlay(lower, upper, mountpoint, "").await {
+            Ok(()) => TaskResult::ExitedNormally,
+            Err(e) => {
+                crate::serial_println!("overlay: {}", KernelError::from(e).with_context(alloc::format!("Failed to mount {mountpoint}")));
+                TaskResult::Error
+            }
+        }
+    })
+}
+
+/// Registers the `/bin/overlay` program.
+pub fn init() {
+    crate::task::spawn::register("/bin/overlay", program).expect("Failed to register overlay");
+}
@@ -0,0 +1,95 @@
+//! File access control.
+//!
+//! Accesses are made on behalf of a set of [Credentials]. The kernel is always permitted access,
+//! all other callers are checked against the permission bits of the file's [FileMetadata] using
+//! the usual owner/group/other rules. The superuser (uid 0) may read and write any file and may
+//! execute any file which has at least one execute bit set.
+//!
+//! Device files default to mode `0o600` owned by the superuser, see [FileMode::default_for].
+//! B-side files do not have their own permissions, they are checked against their A-side file by [b_file].
+
+use super::file::{File, FileMetadata, FileMode, FileType};
+use super::IoError;
+use alloc::boxed::Box;
+
+/// The identity that a file access is performed on behalf of.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Credentials {
+    /// Accesses made by the kernel itself, these are never checked.
+    Kernel,
+    User { uid: u32, gid: u32 },
+}
+
+impl Credentials {
+    pub const ROOT: Self = Self::User { uid: 0, gid: 0 };
+
+    /// Returns whether these credentials bypass read and write permission checks.
+    pub fn is_privileged(&self) -> bool {
+        match self {
+            Credentials::Kernel => true,
+            Credentials::User { uid, .. } => *uid == 0,
+        }
+    }
+}
+
+bitflags::bitflags! {
+    /// Requested access to a file. For directories [Access::EXEC] permits searching the directory.
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub struct Access: u8 {
+        const READ = 1 << 2;
+        const WRITE = 1 << 1;
+        const EXEC = 1;
+    }
+}
+
+/// Checks whether `cred` may access a file with the metadata `meta`.
+///
+/// Returns [IoError::PermissionDenied] when access is not permitted.
+pub fn check(meta: &FileMetadata, cred: Credentials, access: Access) -> Result<(), IoError> {
+    let (uid, gid) = match cred {
+        Credentials::Kernel => return Ok(()),
+        Credentials::User { uid, gid } => (uid, gid),
+    };
+    let mode = meta.mode().bits();
+
+    if uid == 0 {
+        let any_exec = FileMode::OWNER_EXEC | FileMode::GROUP_EXEC | FileMode::OTHER_EXEC;
+        let is_dir = matches!(meta.file_type(), FileType::Directory | FileType::MountPoint);
+        return if access.contains(Access::EXEC) && !is_dir && !meta.mode().intersects(any_exec) {
+            Err(IoError::PermissionDenied)
+        } else {
+            Ok(())
+        };
+    }
+
+    let shift = if uid == meta.uid() {
+        6
+    } else if gid == meta.gid() {
+        3
+    } else {
+        0
+    };
+
+    if Access::from_bits_truncate((mode >> shift) as u8).contains(access) {
+        Ok(())
+    } else {
+        Err(IoError::PermissionDenied)
+    }
+}
+
+/// Fetches the metadata of `file` and checks whether `cred` may access it.
+pub async fn check_file(file: &dyn File, cred: Credentials, access: Access) -> Result<(), IoError> {
+    if cred == Credentials::Kernel {
+        return Ok(());
+    }
+    check(&file.metadata().await?, cred, access)
+}
+
+/// Returns the B-side file `id` of `file` if `cred` may access `file` with `access`.
+///
+/// B-side files may control the device directly, so they are only accessible to callers which
+/// could access the A-side file.
+pub async fn b_file(file: &dyn File, id: u64, cred: Credentials, access: Access) -> Result<Box<dyn File>, IoError> {
+    check_file(file, cred, access).await?;
+    file.b_file(id).ok_or(IoError::NotPresent)
+}
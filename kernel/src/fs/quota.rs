@@ -0,0 +1,210 @@
+//! Filesystem usage accounting and quotas.
+//!
+//! Each filesystem which supports quotas owns a [Quota] counting the blocks and inodes it has
+//! allocated. Until users own files the quota applies to the whole filesystem, so it limits each
+//! mount rather than each user.
+//!
+//! Both counts have a soft and a hard limit. Allocations which would exceed the hard limit fail
+//! with [IoError::QuotaExceeded]. Exceeding the soft limit is allowed but logs a warning, so the
+//! administrator is told before writers start failing. Limits are unset by default and are
+//! configured using the mount options parsed by [Quota::set_opt].
+//!
+//! Allocations are charged through a [Charge], which holds an inode and the blocks of its data.
+//! Both are released when the charge is dropped, so a filesystem only needs to keep the charge
+//! alive as long as the file exists.
+
+use super::vfs::DevID;
+use super::IoError;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Usage and limits of one resource.
+pub struct Limit {
+    used: AtomicU64,
+    soft: AtomicU64,
+    hard: AtomicU64,
+    /// Set while the soft limit is exceeded, so the warning is only logged once.
+    warned: AtomicBool,
+}
+
+impl Limit {
+    const fn new() -> Self {
+        Self {
+            used: AtomicU64::new(0),
+            soft: AtomicU64::new(u64::MAX),
+            hard: AtomicU64::new(u64::MAX),
+            warned: AtomicBool::new(false),
+        }
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Returns the soft limit, [u64::MAX] if it is unset.
+    pub fn soft(&self) -> u64 {
+        self.soft.load(Ordering::Relaxed)
+    }
+
+    /// Returns the hard limit, [u64::MAX] if it is unset.
+    pub fn hard(&self) -> u64 {
+        self.hard.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number which may still be allocated before the hard limit is reached.
+    pub fn available(&self) -> u64 {
+        self.hard().saturating_sub(self.used())
+    }
+
+    /// Charges `n`, returns whether the soft limit was newly exceeded.
+    fn charge(&self, n: u64) -> Result<bool, IoError> {
+        let hard = self.hard();
+        let used = self
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |u| u.checked_add(n).filter(|u| *u <= hard))
+            .map_err(|_| IoError::QuotaExceeded)?
+            + n;
+        Ok(used > self.soft() && !self.warned.swap(true, Ordering::Relaxed))
+    }
+
+    fn release(&self, n: u64) {
+        let used = self.used.fetch_sub(n, Ordering::Relaxed) - n;
+        if used <= self.soft() {
+            self.warned.store(false, Ordering::Relaxed);
+        }
+    }
+
+    fn set_limits(&self, soft: u64, hard: u64) {
+        self.soft.store(soft, Ordering::Relaxed);
+        self.hard.store(hard, Ordering::Relaxed);
+        self.warned.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Usage accounting of a filesystem, see the module documentation.
+pub struct Quota {
+    dev: DevID,
+    block_size: u64,
+    blocks: Limit,
+    inodes: Limit,
+}
+
+impl Quota {
+    /// Constructs a quota without limits for the filesystem `dev`, which allocates blocks of
+    /// `block_size` bytes.
+    pub fn new(dev: DevID, block_size: u64) -> Arc<Self> {
+        Arc::new(Self {
+            dev,
+            block_size,
+            blocks: Limit::new(),
+            inodes: Limit::new(),
+        })
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn blocks(&self) -> &Limit {
+        &self.blocks
+    }
+
+    pub fn inodes(&self) -> &Limit {
+        &self.inodes
+    }
+
+    /// Returns the number of blocks needed to store `bytes`.
+    pub fn blocks_for(&self, bytes: u64) -> u64 {
+        bytes.div_ceil(self.block_size)
+    }
+
+    /// Allocates an inode, returns a charge holding it.
+    pub fn alloc_inode(self: &Arc<Self>) -> Result<Charge, IoError> {
+        if self.inodes.charge(1)? {
+            log::warn!("{}: Inode quota soft limit of {} exceeded", self.dev, self.inodes.soft());
+        }
+        Ok(Charge { quota: self.clone(), blocks: AtomicU64::new(0) })
+    }
+
+    fn charge_blocks(&self, n: u64) -> Result<(), IoError> {
+        if self.blocks.charge(n)? {
+            log::warn!("{}: Block quota soft limit of {} exceeded", self.dev, self.blocks.soft());
+        }
+        Ok(())
+    }
+
+    /// Removes all limits, used when the filesystem's options are replaced.
+    pub fn clear_limits(&self) {
+        self.blocks.set_limits(u64::MAX, u64::MAX);
+        self.inodes.set_limits(u64::MAX, u64::MAX);
+    }
+
+    /// Parses a quota mount option, returns `false` if `opt` is not a quota option.
+    ///
+    /// | Option        | Effect                         |
+    /// |---------------|--------------------------------|
+    /// | `bsoft=N`     | Sets the block soft limit to N |
+    /// | `bhard=N`     | Sets the block hard limit to N |
+    /// | `isoft=N`     | Sets the inode soft limit to N |
+    /// | `ihard=N`     | Sets the inode hard limit to N |
+    ///
+    /// Limits which are lower than the current usage only prevent further allocations. Invalid
+    /// values are logged and ignored.
+    pub fn set_opt(&self, opt: &str) -> bool {
+        let Some((key, value)) = opt.split_once('=') else { return false };
+        let (limit, soft) = match key {
+            "bsoft" => (&self.blocks, true),
+            "bhard" => (&self.blocks, false),
+            "isoft" => (&self.inodes, true),
+            "ihard" => (&self.inodes, false),
+            _ => return false,
+        };
+        let Ok(value) = value.parse::<u64>() else {
+            log::warn!(r#"{}: Invalid quota option "{opt}" will be ignored"#, self.dev);
+            return true;
+        };
+        if soft {
+            limit.set_limits(value, limit.hard());
+        } else {
+            limit.set_limits(limit.soft(), value);
+        }
+        true
+    }
+}
+
+/// An inode and the blocks charged to it, both are released when this is dropped.
+pub struct Charge {
+    quota: Arc<Quota>,
+    blocks: AtomicU64,
+}
+
+impl Charge {
+    /// Returns the number of blocks charged.
+    pub fn blocks(&self) -> u64 {
+        self.blocks.load(Ordering::Relaxed)
+    }
+
+    /// Changes the charge to cover `bytes` of data. Growing fails with
+    /// [IoError::QuotaExceeded] if the quota's hard limit would be exceeded, the charge is then
+    /// unchanged.
+    ///
+    /// The caller must serialize resizes of the same charge, i.e. by holding the file's data lock.
+    pub fn resize(&self, bytes: u64) -> Result<(), IoError> {
+        let new = self.quota.blocks_for(bytes);
+        let old = self.blocks();
+        if new > old {
+            self.quota.charge_blocks(new - old)?;
+        } else {
+            self.quota.blocks.release(old - new);
+        }
+        self.blocks.store(new, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.quota.blocks.release(*self.blocks.get_mut());
+        self.quota.inodes.release(1);
+    }
+}
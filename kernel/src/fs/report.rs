@@ -0,0 +1,276 @@
+//! Character devices which present a generated text report.
+//!
+//! Subsystems which export their state as text implement [Report], or provide a function returning
+//! the text, and mount a [ReportFile] instead of implementing the file traits themselves.
+//!
+//! The report is regenerated on each read and `pos` is an offset into it, so a reader which reads
+//! the report in multiple pieces may see a torn report if it changes between reads.
+
+use super::device::{Fifo, OpenMode};
+use super::file::*;
+use super::vfs::DevID;
+use super::{IoError, IoResult};
+use crate::mem::dma::DmaBuff;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+
+/// Contents of a [ReportFile].
+pub trait Report: Send + Sync {
+    /// Returns the current contents of the file.
+    fn report(&self) -> String;
+
+    /// Returns whether the file may be opened for writing.
+    fn writable(&self) -> bool {
+        false
+    }
+
+    /// Handles `data` written to the file. This is only called when [Self::writable] returns `true`.
+    fn write<'a>(&'a self, data: &'a str) -> BoxFuture<'a, Result<(), IoError>> {
+        let _ = data;
+        async { Err(IoError::ReadOnly) }.boxed()
+    }
+
+    /// Permissions of the file, the file is owned by the superuser.
+    fn mode(&self) -> u16 {
+        if self.writable() { 0o644 } else { 0o444 }
+    }
+
+    /// Returns the length of the file.
+    fn len(&self) -> u64 {
+        self.report().len() as u64
+    }
+
+    /// Reads the file at `pos` into `buff`, returning the number of bytes read.
+    ///
+    /// Reports which are streamed rather than regenerated may override this and ignore `pos`.
+    fn read<'a>(&'a self, pos: u64, buff: &'a mut [u8]) -> BoxFuture<'a, Result<usize, IoError>> {
+        let rc = copy_at(self.report().as_bytes(), pos, buff);
+        async { rc }.boxed()
+    }
+
+    /// Called when the file is opened with `mode`, the open fails if this returns an error.
+    fn open(&self, mode: OpenMode) -> Result<(), IoError> {
+        let _ = mode;
+        Ok(())
+    }
+
+    /// Called when a file which was successfully opened is closed.
+    fn close(&self) {}
+
+    /// See [Fifo::locks_remain], this is only called for modes which [Self::writable] permits.
+    fn locks_remain(&self, mode: OpenMode) -> usize {
+        let _ = mode;
+        usize::MAX
+    }
+}
+
+/// Copies `data` starting at `pos` into `buff`.
+fn copy_at(data: &[u8], pos: u64, buff: &mut [u8]) -> Result<usize, IoError> {
+    let pos = pos as usize;
+    if pos >= data.len() {
+        return Err(IoError::EndOfFile);
+    }
+    let count = buff.len().min(data.len() - pos);
+    buff[..count].copy_from_slice(&data[pos..pos + count]);
+    Ok(count)
+}
+
+impl Report for fn() -> String {
+    fn report(&self) -> String {
+        self()
+    }
+}
+
+impl Report for fn() -> &'static [u8] {
+    fn report(&self) -> String {
+        String::from_utf8_lossy(self()).into_owned()
+    }
+
+    fn len(&self) -> u64 {
+        self().len() as u64
+    }
+
+    fn read<'a>(&'a self, pos: u64, buff: &'a mut [u8]) -> BoxFuture<'a, Result<usize, IoError>> {
+        let rc = copy_at(self(), pos, buff);
+        async { rc }.boxed()
+    }
+}
+
+/// A report which passes each line written to it to `command`, see [ReportFile::with_commands].
+struct Commands {
+    report: fn() -> String,
+    command: fn(&str) -> Result<(), IoError>,
+}
+
+impl Report for Commands {
+    fn report(&self) -> String {
+        (self.report)()
+    }
+
+    fn writable(&self) -> bool {
+        true
+    }
+
+    fn write<'a>(&'a self, data: &'a str) -> BoxFuture<'a, Result<(), IoError>> {
+        let rc = data.lines().map(str::trim).filter(|l| !l.is_empty()).try_for_each(self.command);
+        async { rc }.boxed()
+    }
+}
+
+/// File object for a [Report].
+///
+/// Writes which are not valid UTF-8 are rejected with [IoError::InvalidData], otherwise the
+/// entire buffer is passed to [Report::write].
+#[derive(Clone)]
+pub struct ReportFile {
+    report: Arc<dyn Report>,
+    fifo_lock: OpenMode,
+    id: DevID,
+}
+
+impl ReportFile {
+    /// Constructs a read-only file containing the output of `report`.
+    pub fn new(id: DevID, report: fn() -> String) -> Self {
+        Self::with_report(id, Arc::new(report))
+    }
+
+    /// Constructs a read-only file containing the data returned by `data`, unlike [Self::new] the
+    /// data is not copied on each read.
+    pub fn new_static(id: DevID, data: fn() -> &'static [u8]) -> Self {
+        Self::with_report(id, Arc::new(data))
+    }
+
+    /// Constructs a file containing the output of `report`, each non-empty line written to the
+    /// file is passed to `command`.
+    ///
+    /// Writes are rejected with the error returned by `command` if any line fails, lines before
+    /// the failed line will have been applied.
+    pub fn with_commands(id: DevID, report: fn() -> String, command: fn(&str) -> Result<(), IoError>) -> Self {
+        Self::with_report(id, Arc::new(Commands { report, command }))
+    }
+
+    /// Constructs a file for `report`.
+    pub fn with_report(id: DevID, report: Arc<dyn Report>) -> Self {
+        Self {
+            report,
+            fifo_lock: OpenMode::Locked,
+            id,
+        }
+    }
+}
+
+#[cast_trait_object::dyn_upcast]
+#[cast_trait_object::dyn_cast(NormalFile<u8>, Directory, super::device::FileSystem, super::device::Fifo<u8>, super::device::DeviceFile )]
+impl File for ReportFile {
+    fn file_type(&self) -> FileType {
+        FileType::CharDev
+    }
+
+    fn block_size(&self) -> u64 {
+        1
+    }
+
+    fn device(&self) -> DevID {
+        self.id
+    }
+
+    fn clone_file(&self) -> Box<dyn File> {
+        Box::new(self.clone())
+    }
+
+    fn id(&self) -> u64 {
+        0
+    }
+
+    fn len(&self) -> IoResult<u64> {
+        async { Ok(self.report.len()) }.boxed()
+    }
+
+    fn metadata(&self) -> IoResult<FileMetadata> {
+        async {
+            Ok(FileMetadata::new(self.len().await?, 1, 0, self.id, FileType::CharDev).with_owner(FileMode::from_bits_retain(self.report.mode()), 0, 0))
+        }.boxed()
+    }
+}
+
+impl Drop for ReportFile {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+impl super::device::DeviceFile for ReportFile {}
+
+impl Fifo<u8> for ReportFile {
+    fn open(&mut self, mode: OpenMode) -> Result<(), IoError> {
+        if self.fifo_lock != OpenMode::Locked {
+            return Err(IoError::DeviceError);
+        }
+        if mode.is_write() && !self.report.writable() {
+            return Err(IoError::ReadOnly);
+        }
+        self.report.open(mode)?;
+        self.fifo_lock = mode;
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), IoError> {
+        if self.fifo_lock == OpenMode::Locked {
+            return Err(IoError::NotReady);
+        }
+        self.fifo_lock = OpenMode::Locked;
+        self.report.close();
+        Ok(())
+    }
+
+    fn locks_remain(&self, mode: OpenMode) -> usize {
+        if mode.is_write() && !self.report.writable() {
+            0
+        } else {
+            self.report.locks_remain(mode)
+        }
+    }
+
+    fn is_master(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl Read<u8> for ReportFile {
+    fn read<'f, 'a: 'f, 'b: 'f>(&'a self, pos: u64, mut dbuff: DmaBuff<'b>) -> BoxFuture<'f, Result<(DmaBuff<'b>, usize), (IoError, DmaBuff<'b>, usize)>> {
+        async move {
+            if !self.fifo_lock.is_read() {
+                return Err((IoError::NotReady, dbuff, 0));
+            }
+            // SAFETY: as_mut guarantees that this is safe.
+            let buff = unsafe { &mut *crate::mem::dma::DmaTarget::as_mut(&mut *dbuff) };
+            match self.report.read(pos, buff).await {
+                Ok(count) => Ok((dbuff, count)),
+                Err(e) => Err((e, dbuff, 0)),
+            }
+        }.boxed()
+    }
+}
+
+impl Write<u8> for ReportFile {
+    fn write<'f, 'a: 'f, 'b: 'f>(&'a self, _: u64, mut dbuff: DmaBuff<'b>) -> BoxFuture<'f, Result<(DmaBuff<'b>, usize), (IoError, DmaBuff<'b>, usize)>> {
+        async {
+            if !self.fifo_lock.is_write() {
+                return Err((IoError::NotReady, dbuff, 0));
+            }
+            // SAFETY: as_mut guarantees that this is safe.
+            let buff = unsafe { &mut *crate::mem::dma::DmaTarget::as_mut(&mut *dbuff) };
+            let len = buff.len();
+            let Ok(data) = core::str::from_utf8(buff) else {
+                return Err((IoError::InvalidData, dbuff, 0));
+            };
+            match self.report.write(data).await {
+                Ok(()) => Ok((dbuff, len)),
+                Err(e) => Err((e, dbuff, 0)),
+            }
+        }.boxed()
+    }
+}
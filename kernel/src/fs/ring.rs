@@ -0,0 +1,447 @@
+//! Asynchronous I/O submission and completion rings.
+//!
+//! An [IoRing] is a pair of single-producer single-consumer rings in a shared memory region. The
+//! submitter posts [Sqe]s to the submission ring and calls [IoRing::enter], the kernel then executes
+//! each operation using the file traits and posts a [Cqe] to the completion ring when it completes.
+//! Operations may complete in any order, [Sqe::user_data] is copied into the completion to identify
+//! the operation.
+//!
+//! The region is page aligned and laid out as `[SQ header][CQ header][SQ entries][CQ entries]` so
+//! that it may be mapped into a process. Until processes exist the submitter side is used by the kernel.
+//!
+//! Files are referenced by their index in the ring's file table, see [IoRing::register_file].
+
+use super::device::Fifo;
+use super::file::{File, NormalFile};
+use super::IoError;
+use crate::mem::dma::{DmaBuff, DmaTarget};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use core::sync::atomic::AtomicU32;
+use core::task::Poll;
+use futures_util::future::BoxFuture;
+use futures_util::task::AtomicWaker;
+
+/// Operation requested by a [Sqe].
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Opcode {
+    /// Completes immediately with a result of `0`.
+    Nop = 0,
+    /// Reads `len` bytes at `pos` into `addr`.
+    Read,
+    /// Writes `len` bytes from `addr` to `pos`.
+    Write,
+    /// Flushes the file to its backing storage, see [NormalFile::sync]. If [Sqe::flags] contains
+    /// [FSYNC_DATASYNC] only the data is synced. Only normal files can be synced.
+    Fsync,
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Nop),
+            1 => Ok(Self::Read),
+            2 => Ok(Self::Write),
+            3 => Ok(Self::Fsync),
+            e => Err(e),
+        }
+    }
+}
+
+/// [Sqe::flags] for [Opcode::Fsync], metadata which is not required to read the data back may not
+/// be written.
+pub const FSYNC_DATASYNC: u8 = 1;
+
+/// Submission queue entry.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Sqe {
+    /// See [Opcode].
+    pub opcode: u8,
+    /// Flags specific to the opcode.
+    pub flags: u8,
+    _reserved: [u8; 2],
+    /// Index into the file table.
+    pub file: u32,
+    pub pos: u64,
+    /// Address of the buffer.
+    pub addr: u64,
+    /// Length of the buffer in bytes.
+    pub len: u64,
+    /// Copied into the [Cqe] for this operation.
+    pub user_data: u64,
+}
+
+impl Sqe {
+    pub fn new(opcode: Opcode, file: u32, pos: u64, buff: *mut [u8], user_data: u64) -> Self {
+        Self {
+            opcode: opcode as u8,
+            flags: 0,
+            _reserved: [0; 2],
+            file,
+            pos,
+            addr: buff as *mut u8 as u64,
+            len: buff.len() as u64,
+            user_data,
+        }
+    }
+}
+
+/// Completion queue entry.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Cqe {
+    /// [Sqe::user_data] of the completed operation.
+    pub user_data: u64,
+    /// The number of bytes transferred, or a negative error code. See [error_code].
+    pub result: i64,
+}
+
+/// Returns the error code used in [Cqe::result] for `err`.
+pub fn error_code(err: IoError) -> i64 {
+    -1 - err as i64
+}
+
+#[repr(C)]
+struct RingHeader {
+    /// Index of the next entry to be consumed.
+    head: AtomicU32,
+    /// Index of the next entry to be produced.
+    tail: AtomicU32,
+    mask: u32,
+    /// Number of completions which were not posted immediately because the ring was full.
+    overflow: AtomicU32,
+}
+
+/// The shared memory containing both rings.
+struct Region {
+    ptr: NonNull<u8>,
+    layout: Layout,
+    entries: u32,
+}
+
+// SAFETY: The region is only accessed through atomics and the ring protocol.
+unsafe impl Send for Region {}
+unsafe impl Sync for Region {}
+
+impl Region {
+    const HEADER_SIZE: usize = size_of::<RingHeader>();
+    const SQ_OFFSET: usize = Self::HEADER_SIZE * 2;
+
+    fn new(entries: u32) -> Self {
+        let size = Self::SQ_OFFSET + entries as usize * size_of::<Sqe>() + Self::cq_entries(entries) as usize * size_of::<Cqe>();
+        let layout = Layout::from_size_align(size, crate::mem::PAGE_SIZE).unwrap();
+        // SAFETY: `layout` has a non-zero size
+        let ptr = NonNull::new(unsafe { alloc::alloc::alloc_zeroed(layout) }).unwrap_or_else(|| alloc::alloc::handle_alloc_error(layout));
+        let s = Self { ptr, layout, entries };
+        // Headers are all zeroes apart from the mask, which is never modified after this
+        // SAFETY: The headers are within the region and no references to them exist yet.
+        unsafe {
+            (*(s.ptr.as_ptr() as *mut RingHeader)).mask = entries - 1;
+            (*(s.ptr.as_ptr().add(Self::HEADER_SIZE) as *mut RingHeader)).mask = Self::cq_entries(entries) - 1;
+        }
+        s
+    }
+
+    const fn cq_entries(entries: u32) -> u32 {
+        entries * 2
+    }
+
+    fn sq(&self) -> &RingHeader {
+        // SAFETY: The header is initialized and lives as long as `self`
+        unsafe { &*(self.ptr.as_ptr() as *const RingHeader) }
+    }
+
+    fn cq(&self) -> &RingHeader {
+        // SAFETY: See Self::sq
+        unsafe { &*(self.ptr.as_ptr().add(Self::HEADER_SIZE) as *const RingHeader) }
+    }
+
+    fn sqe(&self, index: u32) -> *mut Sqe {
+        let i = (index & self.sq().mask) as usize;
+        // SAFETY: The index is masked to the number of entries.
+        unsafe { (self.ptr.as_ptr().add(Self::SQ_OFFSET) as *mut Sqe).add(i) }
+    }
+
+    fn cqe(&self, index: u32) -> *mut Cqe {
+        let i = (index & self.cq().mask) as usize;
+        let offset = Self::SQ_OFFSET + self.entries as usize * size_of::<Sqe>();
+        // SAFETY: The index is masked to the number of entries.
+        unsafe { (self.ptr.as_ptr().add(offset) as *mut Cqe).add(i) }
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        // SAFETY: Allocated in Region::new with the same layout
+        unsafe { alloc::alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+/// A file registered to an [IoRing].
+enum RingFile {
+    Normal(Box<dyn NormalFile<u8>>),
+    Fifo(Box<dyn Fifo<u8>>),
+}
+
+impl RingFile {
+    fn read<'a>(&'a self, pos: u64, buff: DmaBuff<'a>) -> BoxFuture<'a, Result<(DmaBuff<'a>, usize), (IoError, DmaBuff<'a>, usize)>> {
+        match self {
+            RingFile::Normal(f) => f.read(pos, buff),
+            RingFile::Fifo(f) => f.read(pos, buff),
+        }
+    }
+
+    fn write<'a>(&'a self, pos: u64, buff: DmaBuff<'a>) -> BoxFuture<'a, Result<(DmaBuff<'a>, usize), (IoError, DmaBuff<'a>, usize)>> {
+        match self {
+            RingFile::Normal(f) => f.write(pos, buff),
+            RingFile::Fifo(f) => f.write(pos, buff),
+        }
+    }
+
+    async fn sync(&self, data_only: bool) -> Result<(), IoError> {
+        match self {
+            RingFile::Normal(f) => f.sync(data_only).await,
+            RingFile::Fifo(_) => Err(IoError::NotSupported),
+        }
+    }
+}
+
+/// Buffer described by a [Sqe].
+struct RawBuff {
+    data: *mut [u8],
+}
+
+// SAFETY: The submitter guarantees the buffer is not accessed until the operation completes.
+unsafe impl Send for RawBuff {}
+
+unsafe impl DmaTarget for RawBuff {
+    fn as_mut(&mut self) -> *mut [u8] {
+        self.data
+    }
+}
+
+struct RingInner {
+    region: Region,
+    files: spin::RwLock<Vec<Option<Arc<RingFile>>>>,
+    /// Serializes producers of the completion ring.
+    cq_lock: spin::Mutex<VecDeque<Cqe>>,
+    /// Wakes the worker when submissions are available.
+    doorbell: AtomicWaker,
+    /// Wakes tasks waiting for completions.
+    completion: AtomicWaker,
+    closed: atomic::Atomic<bool>,
+}
+
+impl RingInner {
+    /// Posts a completion, completions which do not fit in the ring are held until there is space.
+    fn complete(&self, cqe: Cqe) {
+        let mut overflow = self.cq_lock.lock();
+        overflow.push_back(cqe);
+        self.flush_locked(&mut overflow);
+        drop(overflow);
+        self.completion.wake();
+    }
+
+    fn flush_locked(&self, overflow: &mut VecDeque<Cqe>) {
+        let cq = self.region.cq();
+        let tail = cq.tail.load(atomic::Ordering::Relaxed);
+        let head = cq.head.load(atomic::Ordering::Acquire);
+        let free = (cq.mask + 1) - tail.wrapping_sub(head);
+
+        let mut posted = 0;
+        while posted < free {
+            let Some(cqe) = overflow.pop_front() else { break };
+            // SAFETY: The consumer will not read this entry until the tail is advanced.
+            unsafe { self.region.cqe(tail.wrapping_add(posted)).write_volatile(cqe) };
+            posted += 1;
+        }
+        cq.tail.store(tail.wrapping_add(posted), atomic::Ordering::Release);
+        cq.overflow.store(overflow.len() as u32, atomic::Ordering::Relaxed);
+    }
+
+    async fn execute(&self, sqe: Sqe) -> Result<usize, IoError> {
+        let op = Opcode::try_from(sqe.opcode).map_err(|_| IoError::NotSupported)?;
+        if op == Opcode::Nop {
+            return Ok(0);
+        }
+        let file = self.files.read().get(sqe.file as usize).cloned().flatten().ok_or(IoError::NotPresent)?;
+        let buff: DmaBuff = Box::new(RawBuff {
+            data: core::ptr::slice_from_raw_parts_mut(sqe.addr as *mut u8, sqe.len as usize),
+        });
+
+        match op {
+            Opcode::Nop => unreachable!(),
+            Opcode::Read => file.read(sqe.pos, buff).await.map(|(_, n)| n).map_err(|(e, _, _)| e),
+            Opcode::Write => file.write(sqe.pos, buff).await.map(|(_, n)| n).map_err(|(e, _, _)| e),
+            Opcode::Fsync => file.sync(sqe.flags & FSYNC_DATASYNC != 0).await.map(|()| 0),
+        }
+    }
+}
+
+/// A submission and completion ring pair. See the module level documentation for details.
+///
+/// Dropping the ring does not cancel operations which have been submitted, their completions are discarded.
+pub struct IoRing {
+    inner: Arc<RingInner>,
+}
+
+impl IoRing {
+    /// Creates a new ring with `entries` submission entries and twice as many completion entries.
+    ///
+    /// # Panics
+    ///
+    /// `entries` must be a power of two.
+    pub fn new(entries: u32) -> Self {
+        assert!(entries.is_power_of_two(), "IoRing entries must be a power of two");
+        let inner = Arc::new(RingInner {
+            region: Region::new(entries),
+            files: spin::RwLock::new(Vec::new()),
+            cq_lock: spin::Mutex::new(VecDeque::new()),
+            doorbell: AtomicWaker::new(),
+            completion: AtomicWaker::new(),
+            closed: atomic::Atomic::new(false),
+        });
+        crate::task::run_task(Box::pin(worker(inner.clone())));
+        Self { inner }
+    }
+
+    /// Returns the address and size of the shared region.
+    pub fn region(&self) -> (*mut u8, usize) {
+        (self.inner.region.ptr.as_ptr(), self.inner.region.layout.size())
+    }
+
+    /// Adds `file` to the file table and returns its index.
+    ///
+    /// Only normal files and FIFOs are supported, FIFOs must already be opened.
+    pub fn register_file(&self, file: Box<dyn File>) -> Result<u32, Box<dyn File>> {
+        let file = match super::file::cast_file!(NormalFile<u8>: file) {
+            Ok(f) => RingFile::Normal(f),
+            Err(file) => RingFile::Fifo(super::file::cast_file!(Fifo<u8>: file)?),
+        };
+
+        let mut files = self.inner.files.write();
+        let file = Some(Arc::new(file));
+        if let Some(i) = files.iter().position(Option::is_none) {
+            files[i] = file;
+            Ok(i as u32)
+        } else {
+            files.push(file);
+            Ok(files.len() as u32 - 1)
+        }
+    }
+
+    /// Removes the file at `index` from the file table. Operations already using it will complete normally.
+    pub fn unregister_file(&self, index: u32) -> Result<(), IoError> {
+        self.inner.files.write().get_mut(index as usize).and_then(Option::take).map(|_| ()).ok_or(IoError::NotPresent)
+    }
+
+    /// Posts `sqe` to the submission ring. The operation will not start until [Self::enter] is called.
+    ///
+    /// Returns `sqe` if the ring is full.
+    ///
+    /// # Safety
+    ///
+    /// The buffer described by `sqe` must remain valid and must not be accessed until the
+    /// completion for this operation has been reaped.
+    pub unsafe fn submit(&self, sqe: Sqe) -> Result<(), Sqe> {
+        let sq = self.inner.region.sq();
+        let tail = sq.tail.load(atomic::Ordering::Relaxed);
+        if tail.wrapping_sub(sq.head.load(atomic::Ordering::Acquire)) > sq.mask {
+            return Err(sqe);
+        }
+        self.inner.region.sqe(tail).write_volatile(sqe);
+        sq.tail.store(tail.wrapping_add(1), atomic::Ordering::Release);
+        Ok(())
+    }
+
+    /// Notifies the kernel that submissions are available. This is the only system call required
+    /// to operate the ring.
+    pub fn enter(&self) {
+        // completions may have been reaped since the overflow was last flushed
+        self.inner.flush_locked(&mut self.inner.cq_lock.lock());
+        self.inner.doorbell.wake();
+    }
+
+    /// Takes the next completion from the completion ring.
+    pub fn reap(&self) -> Option<Cqe> {
+        let cq = self.inner.region.cq();
+        let head = cq.head.load(atomic::Ordering::Relaxed);
+        if head == cq.tail.load(atomic::Ordering::Acquire) {
+            return None;
+        }
+        // SAFETY: The entry was posted before the tail was advanced.
+        let cqe = unsafe { self.inner.region.cqe(head).read_volatile() };
+        cq.head.store(head.wrapping_add(1), atomic::Ordering::Release);
+        Some(cqe)
+    }
+
+    /// Waits for the next completion.
+    pub async fn wait(&self) -> Cqe {
+        core::future::poll_fn(|cx| {
+            if let Some(cqe) = self.reap() {
+                return Poll::Ready(cqe);
+            }
+            self.inner.completion.register(cx.waker());
+            self.enter();
+            match self.reap() {
+                Some(cqe) => Poll::Ready(cqe),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for IoRing {
+    fn drop(&mut self) {
+        self.inner.closed.store(true, atomic::Ordering::Relaxed);
+        self.inner.doorbell.wake();
+    }
+}
+
+/// Consumes the submission ring, each operation is run as its own task.
+async fn worker(inner: Arc<RingInner>) -> crate::task::TaskResult {
+    loop {
+        core::future::poll_fn(|cx| {
+            inner.doorbell.register(cx.waker());
+            let sq = inner.region.sq();
+            if inner.closed.load(atomic::Ordering::Relaxed) || sq.head.load(atomic::Ordering::Relaxed) != sq.tail.load(atomic::Ordering::Acquire) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+
+        if inner.closed.load(atomic::Ordering::Relaxed) {
+            return crate::task::TaskResult::ExitedNormally;
+        }
+
+        let sq = inner.region.sq();
+        let mut head = sq.head.load(atomic::Ordering::Relaxed);
+        let tail = sq.tail.load(atomic::Ordering::Acquire);
+        while head != tail {
+            // SAFETY: The entry was posted before the tail was advanced.
+            let sqe = unsafe { inner.region.sqe(head).read_volatile() };
+            head = head.wrapping_add(1);
+            let inner = inner.clone();
+            crate::task::run_task(Box::pin(async move {
+                let result = match inner.execute(sqe).await {
+                    Ok(n) => n as i64,
+                    Err(e) => error_code(e),
+                };
+                inner.complete(Cqe { user_data: sqe.user_data, result });
+                crate::task::TaskResult::ExitedNormally
+            }));
+        }
+        sq.head.store(head, atomic::Ordering::Release);
+    }
+}
@@ -0,0 +1,61 @@
+//! Transfers data between file objects.
+//!
+//! When the source file can lend its own storage via [Read::lend] the lent buffer is handed
+//! directly to the writer. Otherwise, data is read into an intermediate buffer which is then handed
+//! to the writer, the data is never copied between the read and the write.
+
+use super::disk_util::write_all;
+use super::file::{Read, Write};
+use super::IoError;
+use crate::mem::dma::{DmaBuff, DmaGuard, DmaSlice};
+use alloc::boxed::Box;
+
+/// Maximum number of bytes transferred by each read/write pair.
+const CHUNK_SIZE: usize = crate::mem::PAGE_SIZE * 16;
+
+/// Transfers up to `len` bytes from `src` at `src_pos` into `dst` at `dst_pos`.
+///
+/// Returns the number of bytes transferred, which may be less than `len` if the end of `src` was reached.
+/// On error the number of bytes transferred before the error occurred is returned.
+///
+/// `src` and `dst` must not refer to the same file, when `src` lends its storage it may block
+/// writes to itself until the transfer completes.
+pub async fn splice(src: &dyn Read<u8>, src_pos: u64, dst: &dyn Write<u8>, dst_pos: u64, len: usize) -> Result<usize, (IoError, usize)> {
+    let mut done = 0;
+
+    while done < len {
+        let chunk = (len - done).min(CHUNK_SIZE);
+        let pos = src_pos + done as u64;
+
+        let buff = match src.lend(pos, chunk) {
+            Some(lent) => match lent.await {
+                Ok(buff) => buff,
+                Err(IoError::EndOfFile) => break,
+                Err(e) => return Err((e, done)),
+            },
+            None => match read_bounce(src, pos, chunk).await {
+                Ok(Some(buff)) => buff,
+                Ok(None) => break,
+                Err(e) => return Err((e, done)),
+            },
+        };
+
+        let count = write_all(dst, dst_pos + done as u64, buff).await.map_err(|(e, n)| (e, done + n))?;
+        done += count;
+        if count < chunk {
+            // short read, the end of `src` was reached
+            break;
+        }
+    }
+    Ok(done)
+}
+
+/// Reads up to `len` bytes into a newly allocated buffer. Returns `None` at the end of the file.
+async fn read_bounce(src: &dyn Read<u8>, pos: u64, len: usize) -> Result<Option<DmaBuff<'static>>, IoError> {
+    let buff: DmaBuff<'static> = Box::new(DmaGuard::from(alloc::vec![0u8; len]));
+    match src.read(pos, buff).await {
+        Ok((_, 0)) | Err((IoError::EndOfFile, _, 0)) => Ok(None),
+        Ok((buff, count)) | Err((IoError::EndOfFile, buff, count)) => Ok(Some(Box::new(DmaSlice::new(buff, 0..count)))),
+        Err((e, _, _)) => Err(e),
+    }
+}
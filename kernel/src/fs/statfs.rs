@@ -0,0 +1,119 @@
+//! Filesystem capacity reporting.
+//!
+//! Each [FileSystem](super::device::FileSystem) reports its capacity and usage as an [FsStat]
+//! through [statfs](super::device::FileSystem::statfs). The VFS adds the flags the filesystem was
+//! mounted with, see [VirtualFileSystem::statfs](super::vfs::VirtualFileSystem::statfs).
+//!
+//! The `/bin/df [-i] [PATH]...` program prints the usage of the filesystems containing each `PATH`,
+//! or of every mounted filesystem. Sizes are printed in KiB, `-i` prints inode counts instead.
+
+use super::vfs::MountFlags;
+use crate::error::KernelError;
+use crate::task::spawn::Args;
+use crate::task::TaskResult;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+
+/// Capacity and usage of a filesystem.
+///
+/// Counts which a filesystem can't determine are [u64::MAX], e.g. the number of inodes of a
+/// filesystem which allocates them dynamically.
+#[derive(Debug, Copy, Clone)]
+pub struct FsStat {
+    /// The size in bytes of the blocks counted by this struct.
+    pub block_size: u64,
+    pub blocks: u64,
+    pub blocks_free: u64,
+    /// Free blocks which may be allocated. This is lower than `blocks_free` when blocks are
+    /// reserved or limited by a quota.
+    pub blocks_avail: u64,
+    pub inodes: u64,
+    pub inodes_free: u64,
+    /// Set by the VFS, filesystems should return [MountFlags::empty].
+    pub flags: MountFlags,
+}
+
+/// Formats a count which may be unknown.
+fn count(n: u64) -> String {
+    if n == u64::MAX { "-".to_string() } else { n.to_string() }
+}
+
+/// Returns the percentage of `used + avail` which is used, rounded up like `df`.
+fn percent(used: u64, avail: u64) -> String {
+    let total = used.saturating_add(avail);
+    if avail == u64::MAX || total == 0 {
+        return "-".to_string();
+    }
+    alloc::format!("{}%", (used as u128 * 100).div_ceil(total as u128))
+}
+
+fn print(source: &str, stat: &FsStat, location: &str, inodes: bool) {
+    let row = if inodes {
+        let used = stat.inodes.saturating_sub(stat.inodes_free);
+        let used = if stat.inodes == u64::MAX { u64::MAX } else { used };
+        [count(stat.inodes), count(used), count(stat.inodes_free), percent(used, stat.inodes_free)]
+    } else {
+        let kib = |blocks: u64| if blocks == u64::MAX { u64::MAX } else { blocks.saturating_mul(stat.block_size) / 1024 };
+        let used = stat.blocks.saturating_sub(stat.blocks_free);
+        [count(kib(stat.blocks)), count(kib(used)), count(kib(stat.blocks_avail)), percent(used, stat.blocks_avail)]
+    };
+    let [total, used, avail, pct] = row;
+    let ro = if stat.flags.contains(MountFlags::READ_ONLY) { " (ro)" } else { "" };
+    crate::serial_println!("{source:<16} {total:>12} {used:>12} {avail:>12} {pct:>5} {location}{ro}");
+}
+
+fn program(args: Args) -> Pin<Box<dyn Future<Output = TaskResult> + Send>> {
+    Box::pin(async move {
+        let mut inodes = false;
+        let mut paths: Vec<String> = Vec::new();
+        for a in args.argv.iter().skip(1) {
+            match a.as_str() {
+                "-i" => inodes = true,
+                s if s.starts_with('-') => {
+                    crate::serial_println!("Usage: df [-i] [PATH]...");
+                    return TaskResult::Error;
+                }
+                _ => paths.push(a.clone()),
+            }
+        }
+
+        let vfs = crate::fs::get_vfs();
+        let mounts = vfs.filesystems();
+        if paths.is_empty() {
+            paths = mounts.iter().map(|m| m.location.clone()).collect();
+        }
+
+        let header = if inodes { ["Inodes", "IUsed", "IFree", "IUse%"] } else { ["1K-blocks", "Used", "Available", "Use%"] };
+        let [total, used, avail, pct] = header;
+        crate::serial_println!("{:<16} {total:>12} {used:>12} {avail:>12} {pct:>5} Mounted on", "Filesystem");
+
+        let mut ok = true;
+        for path in paths {
+            let r = async {
+                let dev = vfs.open(&path).await?.device();
+                let stat = vfs.statfs_dev(dev).await?;
+                Ok::<_, crate::fs::vfs::VfsError>((dev, stat))
+            };
+            match r.await {
+                Ok((dev, stat)) => {
+                    let m = mounts.iter().find(|m| m.device == dev);
+                    let source = m.map_or(String::new(), |m| m.source.clone().unwrap_or_else(|| m.fs_type.clone()));
+                    print(&source, &stat, m.map_or(&*path, |m| &*m.location), inodes);
+                }
+                Err(e) => {
+                    crate::serial_println!("df: {}", KernelError::from(e).with_context(alloc::format!("Failed to stat {path}")));
+                    ok = false;
+                }
+            }
+        }
+        if ok { TaskResult::ExitedNormally } else { TaskResult::Error }
+    })
+}
+
+/// Registers the `/bin/df` program.
+pub fn init() {
+    crate::task::spawn::register("/bin/df", program).expect("Failed to register df");
+}
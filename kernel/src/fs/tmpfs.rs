@@ -17,9 +17,11 @@ use futures_util::FutureExt;
 use lazy_static::lazy_static;
 use crate::fs::IoError::NotPresent;
 use crate::mem::dma::{DmaBuff, DmaClaimable, DmaTarget};
+use crate::task::wait::{Annotate, WaitPoint};
+use super::quota::{Charge, Quota};
 
 lazy_static! {
-    pub static ref DRIVER_MAJOR: MajorNum = MajorNum::new();
+    pub static ref DRIVER_MAJOR: MajorNum = MajorNum::register("tmpfs").unwrap();
 }
 
 static MINOR: atomic::Atomic<usize> = atomic::Atomic::new(0);
@@ -34,6 +36,71 @@ trait TmpFsFile: Sync + Send {
     fn set_link(&self, _count: u64) {}
 
     fn type_id(&self) -> core::any::TypeId;
+
+    /// Returns `self` if it is a directory.
+    fn as_dir(self: Arc<Self>) -> Option<Arc<DirAccessor>> {
+        None
+    }
+
+    /// Returns `self` if it is a normal file.
+    fn as_file(self: Arc<Self>) -> Option<Arc<FileAccessor>> {
+        None
+    }
+}
+
+/// Ownership, permissions and timestamps of a tmpfs file.
+#[derive(Clone)]
+struct Attributes {
+    mode: FileMode,
+    uid: u32,
+    gid: u32,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+}
+
+impl Attributes {
+    fn new(file_type: FileType) -> spin::Mutex<Self> {
+        let now = crate::time::rtc::realtime();
+        spin::Mutex::new(Self {
+            mode: FileMode::default_for(file_type),
+            uid: 0,
+            gid: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+        })
+    }
+
+    fn metadata(&self, meta: FileMetadata) -> FileMetadata {
+        meta.with_owner(self.mode, self.uid, self.gid).with_times(self.atime, self.mtime, self.ctime)
+    }
+
+    fn accessed(&mut self) {
+        self.atime = crate::time::rtc::realtime();
+    }
+
+    fn modified(&mut self) {
+        self.mtime = crate::time::rtc::realtime();
+        self.ctime = self.mtime;
+    }
+
+    fn set_mode(&mut self, mode: FileMode) {
+        self.mode = mode;
+        self.ctime = crate::time::rtc::realtime();
+    }
+
+    fn set_owner(&mut self, uid: u32, gid: u32) {
+        self.uid = uid;
+        self.gid = gid;
+        self.ctime = crate::time::rtc::realtime();
+    }
+
+    fn set_times(&mut self, atime: Option<u64>, mtime: Option<u64>) {
+        self.atime = atime.unwrap_or(self.atime);
+        self.mtime = mtime.unwrap_or(self.mtime);
+        self.ctime = crate::time::rtc::realtime();
+    }
 }
 
 struct TmpFsRootInner {
@@ -41,26 +108,33 @@ struct TmpFsRootInner {
     fs_opts: spin::RwLock<FsOpts>,
     dev_id: DevID,
     serial_count: atomic::Atomic<u64>,
+    /// Every file holds a [Charge] for its inode and the pages of its data.
+    quota: Arc<Quota>,
+    /// Held for reading by operations which modify the filesystem and for writing while a
+    /// snapshot is taken, so that a snapshot never contains half of an operation.
+    snapshot_lock: async_lock::RwLock<()>,
 }
 
 impl TmpFsRootInner {
-    fn new_file(&self) -> Option<Arc<FileAccessor>> {
+    fn new_file(&self) -> Result<Arc<FileAccessor>, IoError> {
+        let charge = self.quota.alloc_inode()?;
         let serial = self.serial_count.fetch_add(1,atomic::Ordering::Relaxed);
-        let file = Arc::new(FileAccessor::new(serial));
+        let file = Arc::new(FileAccessor::new(serial, charge));
         if self.f_map.write().insert(serial,file.clone()).is_some() {
-            None
+            Err(IoError::DeviceError)
         } else {
-            Some(file)
+            Ok(file)
         }
     }
 
-    fn new_dir(&self,parent: &DirAccessor) -> Option<Arc<DirAccessor>> {
+    fn new_dir(&self,parent: &DirAccessor) -> Result<Arc<DirAccessor>, IoError> {
+        let charge = self.quota.alloc_inode()?;
         let serial = self.serial_count.fetch_add(1,atomic::Ordering::Relaxed);
-        let file = Arc::new(DirAccessor::new(serial,parent.serial));
+        let file = Arc::new(DirAccessor::new(serial,parent.serial, charge));
         if self.f_map.write().insert(serial,file.clone()).is_some() {
-            None
+            Err(IoError::DeviceError)
         } else {
-            Some(file)
+            Ok(file)
         }
     }
 
@@ -88,17 +162,163 @@ impl TmpFsRootInner {
         }
     }
 
-    fn store_dev(&self, dev: Box<dyn device::DeviceFile>) -> u64 {
+    /// Increments the link count of the file with the ID `serial`. Directories can't be linked.
+    fn link_file(&self, serial: u64) -> Result<(), IoError> {
+        // Locked for writing so this is not interleaved with `remove_file`
+        let l = self.f_map.write();
+        let f = l.get(&serial).ok_or(IoError::NotPresent)?;
+        if TmpFsFile::type_id(&**f) == TypeId::of::<DirAccessor>() {
+            return Err(IoError::PermissionDenied);
+        }
+        f.set_link(f.link_count() + 1);
+        Ok(())
+    }
+
+    /// Moves the entry `old` in `src` to `new` in `dst`, see [Directory::rename].
+    fn rename(&self, src: &DirAccessor, old: &str, dst: &DirAccessor, new: &str) -> Result<(), IoError> {
+        // Both directories stay locked until the entries are updated. They are locked in order of
+        // their serial numbers so that concurrent renames can't deadlock.
+        let (mut src_l, mut dst_l) = if src.serial == dst.serial {
+            (src.map.write(), None)
+        } else if src.serial < dst.serial {
+            let s = src.map.write();
+            (s, Some(dst.map.write()))
+        } else {
+            let d = dst.map.write();
+            (src.map.write(), Some(d))
+        };
+
+        let id = *src_l.get(old).ok_or(IoError::NotPresent)?;
+        let moved = self.fetch_raw(id).ok_or(IoError::NotPresent)?.as_dir();
+        let replaced = match &dst_l {
+            Some(d) => d.get(new).copied(),
+            None => src_l.get(new).copied(),
+        };
+        if replaced == Some(id) {
+            return Ok(());
+        }
+
+        if let Some(dir) = &moved {
+            // A directory can't be moved into itself
+            let mut cur = dst.serial;
+            loop {
+                if cur == dir.serial {
+                    return Err(IoError::InvalidData);
+                }
+                let Some(d) = self.fetch_raw(cur).and_then(TmpFsFile::as_dir) else { break };
+                if d.is_root() {
+                    break;
+                }
+                cur = d.parent.load(atomic::Ordering::Relaxed);
+            }
+        }
+        if let Some(r) = replaced {
+            // `src` is locked and is not empty because it contains `old`
+            if r == src.serial {
+                return Err(IoError::NotEmpty);
+            }
+            let r = self.fetch_raw(r).ok_or(IoError::NotPresent)?.as_dir();
+            match (&moved, r) {
+                (None, Some(_)) => return Err(IoError::IsDirectory),
+                (Some(_), None) => return Err(IoError::InvalidData),
+                (Some(_), Some(d)) if !d.map.read().is_empty() => return Err(IoError::NotEmpty),
+                _ => {}
+            }
+        }
+
+        src_l.remove(old);
+        match &mut dst_l {
+            Some(d) => d.insert(new.to_string(), id),
+            None => src_l.insert(new.to_string(), id),
+        };
+        if let Some(dir) = &moved {
+            dir.parent.store(dst.serial, atomic::Ordering::Relaxed);
+        }
+        drop(src_l);
+        drop(dst_l);
+        src.attr.lock().modified();
+        dst.attr.lock().modified();
+
+        match replaced {
+            Some(r) => self.remove_file(r),
+            None => Ok(()),
+        }
+    }
+
+    fn store_dev(&self, dev: Box<dyn device::DeviceFile>) -> Result<u64, IoError> {
+        let charge = self.quota.alloc_inode()?;
         let id = self.serial_count.fetch_add(1, atomic::Ordering::Relaxed);
         let dev = Arc::new(DeviceFileObj {
             inner: dev,
             _serial: id,
             link_count: atomic::Atomic::new(1),
+            _charge: charge,
         });
 
         // map drops the returned file because it does not implement Debug which is required by expect_err::<Result<Debug,_>>()
         let _ = self.f_map.write().insert(id,dev).map(|_|()).ok_or(()).expect_err("Duplicate file serial number");
-        id
+        Ok(id)
+    }
+
+    /// Prevents snapshots from being taken until the returned guard is dropped.
+    ///
+    /// This must be acquired before any other lock of the filesystem.
+    async fn modifying(&self) -> async_lock::RwLockReadGuard<'_, ()> {
+        self.snapshot_lock.read().waiting_on(WaitPoint::lock("tmpfs snapshot")).await
+    }
+
+    /// Copies the directory `dir` and its subtree into a new tmpfs, see
+    /// [FileSystem::snapshot](device::FileSystem::snapshot).
+    ///
+    /// File data is shared with the copy until either file is written. Both filesystems are
+    /// charged for shared data.
+    async fn snapshot(&self, dir: u64) -> Result<Box<dyn device::FileSystem>, IoError> {
+        let src = self.fetch_raw(dir).ok_or(IoError::NotPresent)?.as_dir().ok_or(IoError::InvalidData)?;
+        let _frozen = self.snapshot_lock.write().waiting_on(WaitPoint::lock("tmpfs snapshot")).await;
+
+        let snap = TmpFsRoot::new_root();
+        let dst = &snap.inner;
+        let root = dst.fetch_raw(0).and_then(TmpFsFile::as_dir).unwrap(); // root is always present
+        *root.attr.lock() = src.attr.lock().clone();
+
+        // Files with multiple links in the subtree are copied once, then linked again
+        let mut copied = BTreeMap::new();
+        let mut dirs = alloc::vec![(src, root)];
+        while let Some((s, d)) = dirs.pop() {
+            let entries: Vec<(String, u64)> = s.map.read().iter().map(|(n, id)| (n.clone(), *id)).collect();
+            for (name, id) in entries {
+                let new = match copied.get(&id) {
+                    Some(new) => {
+                        dst.link_file(*new)?;
+                        *new
+                    }
+                    None => {
+                        let f = self.fetch_raw(id).ok_or(IoError::NotPresent)?;
+                        let new = if let Some(sd) = f.clone().as_dir() {
+                            let nd = dst.new_dir(&d)?;
+                            *nd.attr.lock() = sd.attr.lock().clone();
+                            let serial = nd.serial;
+                            dirs.push((sd, nd));
+                            serial
+                        } else if let Some(sf) = f.clone().as_file() {
+                            let data = sf.data.read().waiting_on(WaitPoint::lock("tmpfs file")).await.clone();
+                            let nf = dst.new_file()?;
+                            nf.charge.resize(data.len() as u64)?;
+                            *nf.data.write().waiting_on(WaitPoint::lock("tmpfs file")).await = data;
+                            *nf.attr.lock() = sf.attr.lock().clone();
+                            nf.serial
+                        } else {
+                            let dev = cast_file!(device::DeviceFile: f.get_file_obj(Weak::new())).map_err(|_| IoError::NotSupported)?;
+                            dst.store_dev(dev)?
+                        };
+                        copied.insert(id, new);
+                        new
+                    }
+                };
+                d.map.write().insert(name, new);
+            }
+        }
+        Ok(Box::new(snap))
     }
 
     fn fetch(self: &Arc<Self>, id: u64) -> Option<Box<dyn File>> {
@@ -120,17 +340,24 @@ pub struct TmpFsRoot {
 
 impl TmpFsRoot {
     pub fn new() -> Box<dyn device::FileSystem> {
+        Box::new(Self::new_root())
+    }
 
-        let this = Box::new( Self {
+    fn new_root() -> Self {
+        let dev_id = DevID::new(*DRIVER_MAJOR, MINOR.fetch_add(1,atomic::Ordering::Relaxed));
+        let this = Self {
             inner: Arc::new(TmpFsRootInner{
                 f_map: spin::RwLock::new(BTreeMap::new()),
                 fs_opts: spin::RwLock::new(FsOpts::new(true,true)),
-                dev_id: DevID::new(*DRIVER_MAJOR, MINOR.fetch_add(1,atomic::Ordering::Relaxed)),
+                dev_id,
                 serial_count: atomic::Atomic::new(1), // this file is 0
+                quota: Quota::new(dev_id, crate::mem::PAGE_SIZE as u64),
+                snapshot_lock: async_lock::RwLock::new(()),
             })
-        });
+        };
         let mut l = this.inner.f_map.write();
-        let root = DirAccessor::new(0,0); // special exception parent of root has itself as parent
+        let charge = this.inner.quota.alloc_inode().unwrap(); // No limits are set yet
+        let root = DirAccessor::new(0,0, charge); // special exception parent of root has itself as parent
         l.insert(0,Arc::new(root));
         drop(l);
 
@@ -188,12 +415,15 @@ impl device::FileSystem for TmpFsRoot {
         l.get(option)
     }
 
+    /// Quota limits are set using the options described in [Quota::set_opt], the block size is the page size.
     fn set_opts(&mut self, options: &str) {
         let mut new_opts = FsOpts::new(false,true);
+        self.inner.quota.clear_limits();
         for i in options.split_whitespace() {
             match i {
                 "NODEV" => { new_opts.set(FsOpts::DEV_ALLOWED.to_string(), FsOpts::FALSE.to_string()); }
                 "NOCACHE" => log::trace!("NOCACHE passed to tmpfs, ignoring"),
+                e if self.inner.quota.set_opt(e) => {}
                 e => log::warn!(r#"Unknown option "{e}" will be ignored"#)
             }
         }
@@ -208,26 +438,62 @@ impl device::FileSystem for TmpFsRoot {
     fn raw_file(&self) -> Option<&str> {
         None
     }
+
+    fn quota(&self) -> Option<&Quota> {
+        Some(&self.inner.quota)
+    }
+
+    /// Files are stored on the kernel heap, so free space is limited by both the quota and the
+    /// space the heap may still grow by.
+    fn statfs(&self) -> IoResult<super::statfs::FsStat> {
+        async {
+            let q = &self.inner.quota;
+            let heap = crate::mem::allocator::heap_ceiling().saturating_sub(crate::mem::allocator::heap_mapped()) as u64;
+            let free = q.blocks().available().min(heap / q.block_size());
+            // Inodes are unlimited unless a quota is set
+            let inodes_free = if q.inodes().hard() == u64::MAX { u64::MAX } else { q.inodes().available() };
+            Ok(super::statfs::FsStat {
+                block_size: q.block_size(),
+                blocks: q.blocks().used() + free,
+                blocks_free: free,
+                blocks_avail: free,
+                inodes: q.inodes().hard(),
+                inodes_free,
+                flags: MountFlags::empty(),
+            })
+        }.boxed()
+    }
+
+    /// Snapshots are copy-on-write, the data of each file is copied when it is first written by
+    /// either filesystem.
+    fn snapshot(&self, dir: u64) -> IoResult<Box<dyn device::FileSystem>> {
+        async move { self.inner.snapshot(dir).await }.boxed()
+    }
 }
 
 struct DirAccessor {
     map: spin::RwLock<BTreeMap<String,u64>>,
-    parent: u64,
-    serial: u64
+    attr: spin::Mutex<Attributes>,
+    /// Changed when the directory is moved by [TmpFsRootInner::rename].
+    parent: atomic::Atomic<u64>,
+    serial: u64,
+    _charge: Charge,
 }
 
 impl DirAccessor {
 
-    fn new(serial: u64, parent: u64) -> Self {
+    fn new(serial: u64, parent: u64, charge: Charge) -> Self {
         Self {
             map: Default::default(),
-            parent,
+            attr: Attributes::new(FileType::Directory),
+            parent: atomic::Atomic::new(parent),
             serial,
+            _charge: charge,
         }
     }
 
     fn is_root(&self) -> bool {
-        self.parent == self.serial
+        self.parent.load(atomic::Ordering::Relaxed) == self.serial
     }
 }
 
@@ -243,6 +509,10 @@ impl TmpFsFile for DirAccessor {
     fn type_id(&self) -> TypeId {
         TypeId::of::<Self>()
     }
+
+    fn as_dir(self: Arc<Self>) -> Option<Arc<DirAccessor>> {
+        Some(self)
+    }
 }
 
 #[derive(Clone)]
@@ -283,6 +553,33 @@ impl File for Dir {
             Ok(b.len() as u64)
         }.boxed()
     }
+
+    fn metadata(&self) -> IoResult<FileMetadata> {
+        async {
+            let fs = self.fs.upgrade().ok_or(IoError::NotPresent)?;
+            let ids: Vec<u64> = self.accessor.map.read().values().copied().collect();
+            // Each subdirectory links to `self` through its ".." entry
+            let subdirs = ids.iter().filter_map(|id| fs.fetch_raw(*id)).filter(|f| TmpFsFile::type_id(&**f) == TypeId::of::<DirAccessor>()).count();
+            let meta = FileMetadata::new(ids.len() as u64, self.block_size(), self.serial, self.device(), FileType::Directory)
+                .with_nlink(2 + subdirs as u64);
+            Ok(self.accessor.attr.lock().metadata(meta))
+        }.boxed()
+    }
+
+    fn set_mode(&self, mode: FileMode) -> IoResult<()> {
+        self.accessor.attr.lock().set_mode(mode);
+        async { Ok(()) }.boxed()
+    }
+
+    fn set_owner(&self, uid: u32, gid: u32) -> IoResult<()> {
+        self.accessor.attr.lock().set_owner(uid, gid);
+        async { Ok(()) }.boxed()
+    }
+
+    fn set_times(&self, atime: Option<u64>, mtime: Option<u64>) -> IoResult<()> {
+        self.accessor.attr.lock().set_times(atime, mtime);
+        async { Ok(()) }.boxed()
+    }
 }
 
 impl Directory for Dir {
@@ -295,12 +592,13 @@ impl Directory for Dir {
 
     fn new_file<'f, 'b: 'f, 'a:'f>(&'a self, name: &'b str, file: Option<&'b mut dyn NormalFile<u8>>) -> BoxFuture<'f, Result<(), (Option<IoError>, Option<IoError>)>> {
         async {
+            let fs = self.fs.upgrade().ok_or((Some(IoError::NotPresent),None))?;
+            let _frozen = fs.modifying().await;
 
             let mut l = self.accessor.map.write();
             if let alloc::collections::btree_map::Entry::Vacant(entry) = l.entry(name.to_string()) {
 
-                let fs = self.fs.upgrade().ok_or((Some(IoError::NotPresent),None))?;
-                let new_file = fs.new_file().unwrap(); // im really not sure what to do if this occurs
+                let new_file = fs.new_file().map_err(|e| (Some(e), None))?;
 
                 if let Some(file) = file {
                     let len = file.len_chars().await.map_err(|e| (None, Some(e)))?;
@@ -319,9 +617,16 @@ impl Directory for Dir {
 
                     vec.truncate(read_len); // If the file shrinks between getting len and reading then we truncate garbage data.
 
-                    *new_file.data.write().await = vec;
+                    let mut data = new_file.data.write().waiting_on(WaitPoint::lock("tmpfs file")).await;
+                    if let Err(e) = new_file.charge.resize(vec.len() as u64) {
+                        drop(data);
+                        let _ = fs.remove_file(new_file.serial);
+                        return Err((Some(e), None));
+                    }
+                    *data = Arc::new(vec);
                 }
                 entry.insert(new_file.serial);
+                self.accessor.attr.lock().modified();
                 Ok(())
 
             } else {
@@ -332,12 +637,14 @@ impl Directory for Dir {
 
     fn new_dir<'f, 'a: 'f, 'b: 'f>(&'a self, name: &'b str) -> IoResult<'f, Box<dyn Directory>> {
         async {
+            let fs = self.fs.upgrade().ok_or(IoError::NotPresent)?;
+            let _frozen = fs.modifying().await;
 
             let mut l = self.accessor.map.write();
             if let alloc::collections::btree_map::Entry::Vacant(entry) = l.entry(name.to_string()) {
-                let fs = self.fs.upgrade().ok_or(IoError::NotPresent)?;
-                let dir = fs.new_dir(&self.accessor).ok_or(IoError::DeviceError)?;
+                let dir = fs.new_dir(&self.accessor)?;
                 entry.insert(dir.serial);
+                self.accessor.attr.lock().modified();
                 //let t = cast_file!(Directory: dir.get_file_obj(self.fs.clone()).try_into()).unwrap();
                 let f = dir.get_file_obj(self.fs.clone());
                 let t: Box<dyn Directory> = f.dyn_cast().ok().unwrap(); // will never fail
@@ -350,13 +657,17 @@ impl Directory for Dir {
 
     fn store<'f, 'a: 'f, 'b: 'f>(&'a self, name: &'b str, file: Box<dyn File>) -> IoResult<'f, ()> {
         async {
+            let fs = self.fs.upgrade().ok_or(IoError::NotPresent)?;
+            let _frozen = fs.modifying().await;
+
             let mut l = self.accessor.map.write();
             if let alloc::collections::btree_map::Entry::Vacant(entry) = l.entry(name.to_string()) {
 
                 match cast_file!(device::DeviceFile: file) {
                     Ok(device) => {
-                        let id = self.fs.upgrade().unwrap().store_dev(device);
+                        let id = fs.store_dev(device)?;
                         entry.insert(id);
+                        self.accessor.attr.lock().modified();
                     }
                     Err(_) => {
                         log::warn!("Attempted to store() non device file");
@@ -418,30 +729,92 @@ impl Directory for Dir {
 
     fn remove<'f, 'a: 'f, 'b: 'f>(&'a self, name: &'b str) -> IoResult<'f, ()> {
         async {
-            let id = *self.accessor.map.read().get(name).ok_or(IoError::NotPresent)?;
             let fs = self.fs.upgrade().ok_or(IoError::NotPresent)?;
+            let _frozen = fs.modifying().await;
+            let id = *self.accessor.map.read().get(name).ok_or(IoError::NotPresent)?;
             let file = fs.fetch(id).ok_or(IoError::NotPresent)?;
 
             if file.file_type() == FileType::Directory && file.len().await? > 0 {
                 return Err(IoError::NotEmpty)
             }
             fs.remove_file(id)?;
+            self.accessor.map.write().remove(name);
+            self.accessor.attr.lock().modified();
             Ok(())
         }.boxed()
     }
+
+    fn link<'f, 'a: 'f, 'b: 'f>(&'a self, name: &'b str, file: &'b dyn File) -> IoResult<'f, ()> {
+        async move {
+            if file.device() != self.device() {
+                return Err(IoError::CrossDevice);
+            }
+            let fs = self.fs.upgrade().ok_or(IoError::NotPresent)?;
+            let _frozen = fs.modifying().await;
+            let mut l = self.accessor.map.write();
+            let alloc::collections::btree_map::Entry::Vacant(entry) = l.entry(name.to_string()) else {
+                return Err(IoError::AlreadyExists);
+            };
+            fs.link_file(file.id())?;
+            entry.insert(file.id());
+            self.accessor.attr.lock().modified();
+            Ok(())
+        }.boxed()
+    }
+
+    fn unlink<'f, 'a: 'f, 'b: 'f>(&'a self, name: &'b str) -> IoResult<'f, ()> {
+        async move {
+            let fs = self.fs.upgrade().ok_or(IoError::NotPresent)?;
+            let _frozen = fs.modifying().await;
+            // Held until the entry is removed so that `name` can't be replaced by a directory
+            let mut l = self.accessor.map.write();
+            let id = *l.get(name).ok_or(IoError::NotPresent)?;
+            if fs.fetch_raw(id).is_some_and(|f| TmpFsFile::type_id(&*f) == TypeId::of::<DirAccessor>()) {
+                return Err(IoError::IsDirectory);
+            }
+            fs.remove_file(id)?;
+            l.remove(name);
+            drop(l);
+            self.accessor.attr.lock().modified();
+            Ok(())
+        }.boxed()
+    }
+
+    fn rename<'f, 'a: 'f, 'b: 'f>(&'a self, old: &'b str, new_dir: &'b dyn Directory, new: &'b str) -> IoResult<'f, ()> {
+        async move {
+            if new_dir.device() != self.device() {
+                return Err(IoError::CrossDevice);
+            }
+            if [old, new].iter().any(|n| *n == THIS_DIR || *n == PARENT_DIR) {
+                return Err(IoError::InvalidData);
+            }
+            let fs = self.fs.upgrade().ok_or(IoError::NotPresent)?;
+            let _frozen = fs.modifying().await;
+            let dst = fs.fetch_raw(new_dir.id()).and_then(TmpFsFile::as_dir).ok_or(IoError::NotPresent)?;
+            fs.rename(&self.accessor, old, &dst, new)
+        }.boxed()
+    }
 }
 
 struct FileAccessor {
-    data: async_lock::RwLock<Vec<u8>>,
+    /// Shared with copies made by [TmpFsRootInner::snapshot] until either file is written.
+    data: async_lock::RwLock<Arc<Vec<u8>>>,
+    attr: spin::Mutex<Attributes>,
     lock: spin::Mutex<crate::util::Weak<dyn NormalFile<u8>>>,
+    link_count: atomic::Atomic<u64>,
+    /// Charged for the length of `data`, resized while `data` is locked for writing.
+    charge: Charge,
     serial: u64
 }
 
 impl FileAccessor {
-    fn new(serial: u64) -> Self {
+    fn new(serial: u64, charge: Charge) -> Self {
         Self {
-            data: async_lock::RwLock::new(Vec::new()),
+            data: async_lock::RwLock::new(Arc::new(Vec::new())),
+            attr: Attributes::new(FileType::NormalFile),
             lock: spin::Mutex::new(crate::util::Weak::default()),
+            link_count: atomic::Atomic::new(1),
+            charge,
             serial
         }
     }
@@ -456,9 +829,23 @@ impl TmpFsFile for FileAccessor{
         })
     }
 
+    fn link_count(&self) -> u64 {
+        self.link_count.load(atomic::Ordering::Relaxed)
+    }
+
+    fn set_link(&self, count: u64) {
+        self.link_count.store(count, atomic::Ordering::Relaxed);
+        // A change in the link count is a change of the file's status
+        self.attr.lock().ctime = crate::time::rtc::realtime();
+    }
+
     fn type_id(&self) -> TypeId {
         <Self as core::any::Any>::type_id(self)
     }
+
+    fn as_file(self: Arc<Self>) -> Option<Arc<FileAccessor>> {
+        Some(self)
+    }
 }
 
 
@@ -476,7 +863,7 @@ struct TmpFsNormalFile {
 
 impl NormalFile<u8> for TmpFsNormalFile {
     fn len_chars(&self) -> IoResult<u64> {
-        async { Ok(self.accessor.data.read().await.len() as u64) }.boxed()
+        async { Ok(self.accessor.data.read().waiting_on(WaitPoint::lock("tmpfs file")).await.len() as u64) }.boxed()
     }
 
     fn file_lock<'a>(self: Box<Self>) -> BoxFuture<'a, Result<LockedFile<u8>, (IoError, Box<dyn NormalFile<u8>>)>> {
@@ -503,7 +890,7 @@ impl NormalFile<u8> for TmpFsNormalFile {
 
 impl File for TmpFsNormalFile {
     fn file_type(&self) -> FileType {
-        FileType::Directory
+        FileType::NormalFile
     }
 
     fn block_size(&self) -> u64 {
@@ -524,10 +911,34 @@ impl File for TmpFsNormalFile {
 
     fn len(&self) -> IoResult<u64> {
         async {
-            let l = self.accessor.data.read().await;
+            let l = self.accessor.data.read().waiting_on(WaitPoint::lock("tmpfs file")).await;
             Ok(l.len() as u64)
         }.boxed()
     }
+
+    fn metadata(&self) -> IoResult<FileMetadata> {
+        async {
+            let len = self.accessor.data.read().waiting_on(WaitPoint::lock("tmpfs file")).await.len() as u64;
+            let meta = FileMetadata::new(len, self.block_size(), self.serial, self.device(), FileType::NormalFile)
+                .with_nlink(self.accessor.link_count.load(atomic::Ordering::Relaxed));
+            Ok(self.accessor.attr.lock().metadata(meta))
+        }.boxed()
+    }
+
+    fn set_mode(&self, mode: FileMode) -> IoResult<()> {
+        self.accessor.attr.lock().set_mode(mode);
+        async { Ok(()) }.boxed()
+    }
+
+    fn set_owner(&self, uid: u32, gid: u32) -> IoResult<()> {
+        self.accessor.attr.lock().set_owner(uid, gid);
+        async { Ok(()) }.boxed()
+    }
+
+    fn set_times(&self, atime: Option<u64>, mtime: Option<u64>) -> IoResult<()> {
+        self.accessor.attr.lock().set_times(atime, mtime);
+        async { Ok(()) }.boxed()
+    }
 }
 
 impl Read<u8> for TmpFsNormalFile {
@@ -537,15 +948,44 @@ impl Read<u8> for TmpFsNormalFile {
             if !self.accessor.lock.lock().cmp_t(self) {
                 return Err((IoError::Exclusive, dbuff, 0));
             }
-            let file = self.accessor.data.read().await;
+            let file = self.accessor.data.read().waiting_on(WaitPoint::lock("tmpfs file")).await;
             if pos as usize >= file.len() {
                 return Err((IoError::EndOfFile, dbuff, 0))
             }
             let count = buff.len().min(file.len() - pos as usize); // either selects the remaining `file` length or the entire `buff` length
             buff[..count].copy_from_slice(&file[pos as usize..pos as usize + count]);
+            self.accessor.attr.lock().accessed();
             Ok((dbuff,count))
         }.boxed()
     }
+
+    fn lend<'f, 'a: 'f>(&'a self, pos: u64, len: usize) -> Option<BoxFuture<'f, Result<DmaBuff<'a>, IoError>>> {
+        Some(async move {
+            if !self.accessor.lock.lock().cmp_t(self) {
+                return Err(IoError::Exclusive);
+            }
+            let file = self.accessor.data.read().waiting_on(WaitPoint::lock("tmpfs file")).await;
+            let pos = pos as usize;
+            if pos >= file.len() {
+                return Err(IoError::EndOfFile)
+            }
+            let range = pos..file.len().min(pos + len);
+            Ok(Box::new(LentBuff { file, range }) as DmaBuff<'a>)
+        }.boxed())
+    }
+}
+
+/// Buffer lent by [TmpFsNormalFile::lend], writes to the file are blocked until this is dropped.
+struct LentBuff<'a> {
+    file: async_lock::RwLockReadGuard<'a, Arc<Vec<u8>>>,
+    range: core::ops::Range<usize>,
+}
+
+// SAFETY: Users of `lend` may not write to the buffer.
+unsafe impl DmaTarget for LentBuff<'_> {
+    fn as_mut(&mut self) -> *mut [u8] {
+        &self.file[self.range.clone()] as *const [u8] as *mut [u8]
+    }
 }
 
 impl Write<u8> for TmpFsNormalFile {
@@ -555,14 +995,22 @@ impl Write<u8> for TmpFsNormalFile {
             if !self.accessor.lock.lock().cmp_t(self) {
                 return Err((IoError::Exclusive, dbuff, 0))
             }
-            let mut file = self.accessor.data.write().await;
+            let Some(fs) = self.fs.upgrade() else { return Err((IoError::NotPresent, dbuff, 0)) };
+            let _frozen = fs.modifying().await;
+            let mut file = self.accessor.data.write().waiting_on(WaitPoint::lock("tmpfs file")).await;
+            // Copies the data if it is shared with a snapshot
+            let file = Arc::make_mut(&mut *file);
             // extend file if necessary
             if buff.len() + pos as usize > file.len() {
+                if let Err(e) = self.accessor.charge.resize((buff.len() + pos as usize) as u64) {
+                    return Err((e, dbuff, 0));
+                }
                 // SAFETY: new len is valid & u8 does not have an invalid state
                 file.reserve_exact(buff.len() + pos as usize);
                 unsafe { file.set_len(buff.len() + pos as usize) };
             }
             file[pos as usize..pos as usize + buff.len()].copy_from_slice(buff);
+            self.accessor.attr.lock().modified();
 
             Ok((dbuff, buff.len()))
         }.boxed()
@@ -573,7 +1021,8 @@ impl Write<u8> for TmpFsNormalFile {
 struct DeviceFileObj {
     inner: Box<dyn super::device::DeviceFile>,
     _serial: u64,
-    link_count: atomic::Atomic<u64>
+    link_count: atomic::Atomic<u64>,
+    _charge: Charge,
 }
 
 impl TmpFsFile for DeviceFileObj {
@@ -15,6 +15,8 @@ use cast_trait_object::DynCastExt;
 use futures_util::FutureExt as _;
 use crate::fs::IoError;
 use crate::fs::device::{DeviceFile, FileSystem};
+use crate::fs::perm::{Access, Credentials};
+use crate::fs::statfs::FsStat;
 use super::file::*;
 
 pub type VfsFuture<'a, T> = futures_util::future::BoxFuture<'a, Result<T, VfsError>>;
@@ -24,6 +26,7 @@ type FileId = (DevID, u64);
 pub struct VirtualFileSystem {
     root: Box<dyn FileSystem>,
     device_ctl: spin::RwLock<DeviceCtl>,
+    locks: super::lock::LockManager,
 }
 
 impl VirtualFileSystem {
@@ -34,17 +37,32 @@ impl VirtualFileSystem {
                 mounts: MountPoints{ mount_list: BTreeMap::new() },
                 dev_override: DevOverrides::new()
             }),
+            locks: super::lock::LockManager::new(),
         };
 
         let mut b = this.device_ctl.write();
-        let _ = b.mounts.insert(MountDescription::new(this.root.clone_file().dyn_cast().unwrap(),"/".to_string(), String::new())).unwrap(); // This is the first entry so this will never return Err(_)
+        let _ = b.mounts.insert(MountDescription::new(this.root.clone_file().dyn_cast().unwrap(),"/".to_string(), String::new(), MountFlags::empty())).unwrap(); // This is the first entry so this will never return Err(_)
         drop(b);
         this
         // todo vfs-persistent pseudo filesystems should be mounted here
     }
 
+    /// Returns the advisory lock manager.
+    pub fn locks(&self) -> &super::lock::LockManager {
+        &self.locks
+    }
+
+    /// Opens the file at `path` with kernel credentials.
     pub fn open<'a>(&'a self, path: &'a str) -> VfsFuture<Box<dyn File>> {
-        async {
+        self.open_as(path, Credentials::Kernel, Access::empty())
+    }
+
+    /// Opens the file at `path` on behalf of `cred`.
+    ///
+    /// Each directory in `path` must permit `cred` to search it and the file itself must permit `access`.
+    /// Otherwise, this returns [IoError::PermissionDenied].
+    pub fn open_as<'a>(&'a self, path: &'a str, cred: Credentials, access: Access) -> VfsFuture<'a, Box<dyn File>> {
+        async move {
             path.is_absolute()?;
             let mut file = self.root.root().clone_file();
             for (i, filename) in path.split(super::PATH_SEPARATOR).enumerate() {
@@ -54,6 +72,8 @@ impl VirtualFileSystem {
                     continue;
                 }
 
+                super::perm::check_file(&*file, cred, Access::EXEC).await?;
+
                 let dir = cast_dir!(file).map_err(|e: Box<dyn File>| {
                     // Returns the correct error
                     match e.file_type() {
@@ -82,6 +102,8 @@ impl VirtualFileSystem {
                 file = file_handle.file().expect("Filesystem did not return file.\nIt either hinted a device file and one was not found or did not return a file at all");
             }
 
+            super::perm::check_file(&*file, cred, access).await?;
+
             match cast_file!(FileSystem: file) {
                 Ok(fs) => Ok(fs.dyn_upcast()),
                 Err(file) => Ok(file)
@@ -89,6 +111,14 @@ impl VirtualFileSystem {
         }.boxed()
     }
 
+    /// Returns the metadata of the file at `path`.
+    pub fn stat<'a>(&'a self, path: &'a str) -> VfsFuture<'a, FileMetadata> {
+        async {
+            let file = self.open(path).await?;
+            Ok(file.metadata().await?)
+        }.boxed()
+    }
+
     /// Attempts to traverse the filesystem to the directory containing the requested file.
     ///
     /// On success this will return the requested directory, the remaining path segment and the depth of the returned file.
@@ -224,6 +254,72 @@ impl VirtualFileSystem {
         }.boxed()
     }
 
+    /// Returns whether a filesystem or device is mounted at `path`.
+    fn is_mountpoint(&self, path: &str) -> bool {
+        self.device_ctl.read().mounts.search(path).is_some()
+    }
+
+    /// Like [Self::is_mountpoint], but also returns `true` when anything is mounted beneath `path`.
+    fn contains_mountpoint(&self, path: &str) -> bool {
+        let ctl = self.device_ctl.read();
+        ctl.mounts.search(path).is_some() || ctl.mounts.within(path)
+    }
+
+    /// Creates a hard link at `new` to the file at `existing`.
+    ///
+    /// Both paths must be on the same filesystem, see [Directory::link].
+    pub fn link<'a>(&'a self, existing: &'a str, new: &'a str) -> VfsFuture<'a, ()> {
+        async move {
+            existing.is_absolute()?;
+            new.is_absolute()?;
+            let file = self.open(existing).await?;
+            let (dir, name, _) = self.traverse_to_dir(new).await?;
+            if file.device() != dir.device() {
+                return Err(VfsError::LowerLevel(IoError::CrossDevice));
+            }
+            dir.link(name, &*file).await?;
+            Ok(())
+        }.boxed()
+    }
+
+    /// Removes the link at `path`, see [Directory::unlink].
+    ///
+    /// Mountpoints can't be unlinked and return `Err(Busy)`.
+    pub fn unlink<'a>(&'a self, path: &'a str) -> VfsFuture<'a, ()> {
+        async move {
+            path.is_absolute()?;
+            if self.is_mountpoint(path) {
+                return Err(VfsError::LowerLevel(IoError::Busy));
+            }
+            let (dir, name, _) = self.traverse_to_dir(path).await?;
+            dir.unlink(name).await?;
+            Ok(())
+        }.boxed()
+    }
+
+    /// Atomically moves the file at `old` to `new`, replacing `new` if it exists.
+    ///
+    /// Files can't be moved between mounts, this returns `Err(CrossDevice)` and the caller must
+    /// copy the file instead. Mountpoints and directories containing mountpoints can't be moved or
+    /// replaced and return `Err(Busy)`.
+    /// See [Directory::rename].
+    pub fn rename<'a>(&'a self, old: &'a str, new: &'a str) -> VfsFuture<'a, ()> {
+        async move {
+            old.is_absolute()?;
+            new.is_absolute()?;
+            if self.contains_mountpoint(old) || self.contains_mountpoint(new) {
+                return Err(VfsError::LowerLevel(IoError::Busy));
+            }
+            let (src, old_name, _) = self.traverse_to_dir(old).await?;
+            let (dst, new_name, _) = self.traverse_to_dir(new).await?;
+            if src.device() != dst.device() {
+                return Err(VfsError::LowerLevel(IoError::CrossDevice));
+            }
+            src.rename(old_name, &*dst, new_name).await?;
+            Ok(())
+        }.boxed()
+    }
+
     /// Attempts to mount a new filesystem at the indicated mountpoint with the specified options.
     ///
     /// `vfs_options` are options given to the VFS for when the filesystem is mounted.
@@ -235,13 +331,13 @@ impl VirtualFileSystem {
     /// - `fs.device()` must not return [DevID::NULL].
     /// - Excluding the last segment `mountpoint` must be a valid accessible location in the filesystem.
     // todo should this be sync?
-    pub fn mount<'a>(&'a self, mut fs: Box<dyn FileSystem>, mountpoint: &'a str, _vfs_options: MountFlags, options: &'a str) -> VfsFuture<()> {
-        async {
+    pub fn mount<'a>(&'a self, mut fs: Box<dyn FileSystem>, mountpoint: &'a str, vfs_options: MountFlags, options: &'a str) -> VfsFuture<()> {
+        async move {
 
             //log::info!("Mounting {} to {mountpoint} ({})", fs.device(), fs.raw_file().unwrap_or(fs.driver_name()) );
 
             fs.set_opts(options);
-            self.mount_dev( cast_file!(DeviceFile: fs.dyn_upcast()).ok().unwrap(),mountpoint).await
+            self.mount_with( cast_file!(DeviceFile: fs.dyn_upcast()).ok().unwrap(),mountpoint, vfs_options).await
 
         }.boxed()
     }
@@ -256,6 +352,11 @@ impl VirtualFileSystem {
     /// - `fs.device()` must not return [DevID::NULL].
     /// - Excluding the last segment `mountpoint` must be a valid accessible location in the filesystem.
     pub fn mount_dev<'a>(&'a self, dev: Box<dyn DeviceFile>, mountpoint: &'a str) -> VfsFuture<()> {
+        self.mount_with(dev, mountpoint, MountFlags::empty())
+    }
+
+    /// See [Self::mount_dev], `flags` are recorded for [Self::statfs].
+    fn mount_with<'a>(&'a self, dev: Box<dyn DeviceFile>, mountpoint: &'a str, flags: MountFlags) -> VfsFuture<'a, ()> {
         async move {
             mountpoint.is_absolute()?;
             let id = dev.device();
@@ -291,7 +392,7 @@ impl VirtualFileSystem {
             };
 
             // fs may require looking up dev in VFS managed table, so we store it here and remove if an error occurs
-            let new_description = MountDescription::new(dev.clone_file().dyn_cast().ok().unwrap(),mountpoint.to_string(), String::new());
+            let new_description = MountDescription::new(dev.clone_file().dyn_cast().ok().unwrap(),mountpoint.to_string(), String::new(), flags);
 
             if let Err(_) = l.mounts.insert(new_description) {
                 log::error!("Device already mounted {id}");
@@ -324,10 +425,117 @@ impl VirtualFileSystem {
         }.boxed()
     }
 
+    /// Returns the capacity of the filesystem containing the file at `path`.
+    pub fn statfs<'a>(&'a self, path: &'a str) -> VfsFuture<'a, FsStat> {
+        async move {
+            let dev = self.open(path).await?.device();
+            self.statfs_dev(dev).await
+        }.boxed()
+    }
+
+    /// Returns the capacity of the filesystem mounted with the ID `dev`.
+    ///
+    /// Returns `Err(LowerLevel(NotSupported))` if `dev` is a mounted device which is not a filesystem.
+    pub fn statfs_dev(&self, dev: DevID) -> VfsFuture<'_, FsStat> {
+        async move {
+            // The filesystem is cloned so the device list is not locked while it is accessed
+            let (fs, flags) = {
+                let l = self.device_ctl.read();
+                let desc = l.mounts.get_dev(dev).ok_or(VfsError::DoesNotExist(usize::MAX))?;
+                (cast_file!(FileSystem: desc.file.clone_file().dyn_upcast()).map_err(|_| VfsError::LowerLevel(IoError::NotSupported))?, desc.flags)
+            };
+            let mut stat = fs.statfs().await?;
+            stat.flags = flags;
+            Ok(stat)
+        }.boxed()
+    }
+
+    /// Snapshots the directory at `path` and mounts the snapshot at `mountpoint` with `options`,
+    /// see [FileSystem::snapshot].
+    ///
+    /// Returns `Err(LowerLevel(NotSupported))` if the filesystem containing `path` can't be
+    /// snapshotted.
+    pub fn snapshot<'a>(&'a self, path: &'a str, mountpoint: &'a str, options: &'a str) -> VfsFuture<'a, ()> {
+        async move {
+            let dir = self.open(path).await?;
+            if dir.file_type() != FileType::Directory {
+                return Err(VfsError::NotADirectory(usize::MAX));
+            }
+            let fs = {
+                let l = self.device_ctl.read();
+                let desc = l.mounts.get_dev(dir.device()).ok_or(VfsError::DoesNotExist(usize::MAX))?;
+                cast_file!(FileSystem: desc.file.clone_file().dyn_upcast()).map_err(|_| VfsError::LowerLevel(IoError::NotSupported))?
+            };
+            let snap = fs.snapshot(dir.id()).await?;
+            self.mount(snap, mountpoint, MountFlags::empty(), options).await
+        }.boxed()
+    }
+
+    /// Mounts an overlay of the filesystem mounted at `upper` over the filesystem mounted at
+    /// `lower` at `mountpoint`, see [OverlayFs](super::overlay::OverlayFs). When `upper` is `None`
+    /// modifications are kept in memory.
+    ///
+    /// Returns `Err(DoesNotExist)` if nothing is mounted at `lower` or `upper`.
+    pub fn mount_overlay<'a>(&'a self, lower: &'a str, upper: Option<&'a str>, mountpoint: &'a str, options: &'a str) -> VfsFuture<'a, ()> {
+        async move {
+            let lower = self.mounted_fs(lower)?;
+            let fs = match upper {
+                Some(upper) => super::overlay::OverlayFs::new(lower, self.mounted_fs(upper)?),
+                None => super::overlay::OverlayFs::with_tmpfs(lower),
+            };
+            self.mount(fs, mountpoint, MountFlags::empty(), options).await
+        }.boxed()
+    }
+
+    /// Returns the filesystem mounted at `path`.
+    fn mounted_fs(&self, path: &str) -> Result<Box<dyn FileSystem>, VfsError> {
+        let l = self.device_ctl.read();
+        let desc = l.mounts.search(path).ok_or(VfsError::DoesNotExist(usize::MAX))?;
+        cast_file!(FileSystem: desc.file.clone_file().dyn_upcast()).map_err(|_| VfsError::LowerLevel(IoError::NotSupported))
+    }
+
+    /// Returns the mounted filesystems ordered by their ID.
+    pub fn filesystems(&self) -> alloc::vec::Vec<MountInfo> {
+        self.device_ctl.read().mounts.mount_list.values().filter(|d| d.is_fs).map(|d| MountInfo {
+            device: d.file.device(),
+            source: d.dev.clone(),
+            fs_type: d.ty.clone().unwrap_or_default(),
+            location: d.location.clone(),
+            flags: d.flags,
+        }).collect()
+    }
+
     pub fn umount(&self, dev: &str) -> Option<Box<dyn DeviceFile>> {
         self.device_ctl.read().mounts.search(dev).map(|d| d.file.clone_file().dyn_cast().ok().unwrap())
     }
 
+    /// Removes the device file mounted at `mountpoint` and returns it.
+    ///
+    /// This must not be used to remove a [FileSystem], use [Self::umount] instead.
+    pub fn remove_dev<'a>(&'a self, mountpoint: &'a str) -> VfsFuture<'a, Box<dyn DeviceFile>> {
+        async move {
+            mountpoint.is_absolute()?;
+            let id = match self.device_ctl.read().mounts.search(mountpoint) {
+                Some(d) if d.is_fs => return Err(VfsError::InvalidArg),
+                Some(d) => d.file.device(),
+                None => return Err(VfsError::DoesNotExist(usize::MAX)),
+            };
+
+            let (dir, name, _) = self.traverse_to_dir(mountpoint).await?;
+            match dir.remove(name).await {
+                Ok(()) => {}
+                // The device was mounted using VFS lookup
+                Err(IoError::IsDevice) => { self.device_ctl.write().dev_override.remove((dir.device(), dir.id()), name); }
+                Err(e) => return Err(e.into()),
+            }
+
+            // May be None if this raced with another removal
+            let desc = self.device_ctl.write().mounts.remove(id).ok_or(VfsError::DoesNotExist(usize::MAX))?;
+            log::info!("Removed {id} from {mountpoint}");
+            Ok(desc.file)
+        }.boxed()
+    }
+
     pub fn file_list<'a>(&'a self, path: &'a str) -> VfsFuture<alloc::vec::Vec<String>> {
         async {
             path.is_absolute()?;
@@ -356,9 +564,22 @@ struct DeviceCtl {
     dev_override: DevOverrides
 }
 
+/// A mounted filesystem, see [VirtualFileSystem::filesystems].
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    pub device: DevID,
+    /// See [FileSystem::raw_file].
+    pub source: Option<String>,
+    /// See [FileSystem::driver_name].
+    pub fs_type: String,
+    pub location: String,
+    pub flags: MountFlags,
+}
+
 bitflags::bitflags! {
     // 32bits this struct may need to be passed via a syscall.
     // on 32bit systems a larger struct may be troublesome
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
     pub struct MountFlags: u32 {
         const READ_ONLY = 1;
         const NO_EXECUTE = 1 << 1;
@@ -423,19 +644,104 @@ impl DevID {
     /// This may be used when a driver does not want a device file to appear globally.
     pub const NULL: Self = Self(0,0);
 
-    // todo add make major a private type
     pub fn new(maj: MajorNum, minor: usize) -> Self {
         Self(maj.0,minor)
     }
+
+    pub fn major(&self) -> MajorNum {
+        MajorNum(self.0)
+    }
+
+    pub fn minor(&self) -> usize {
+        self.1
+    }
 }
 
+/// Identifies the driver which owns a device.
+///
+/// Major numbers are assigned by driver name so that device numbers remain the same between boots.
+/// In-tree drivers are assigned a fixed number from [KNOWN_MAJORS], other drivers are assigned a
+/// number derived from a hash of their name. When a hashed number collides with one which is
+/// already registered the collision is logged and the next free number is used instead, in which
+/// case the number is only stable if drivers are registered in the same order.
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
 pub struct MajorNum(usize);
 
+/// Major numbers reserved for in-tree drivers.
+const KNOWN_MAJORS: &[(&str, usize)] = &[
+    ("tmpfs", 1),
+    ("blkdev", 8),
+    ("vconsole", 4),
+    ("serial", 5),
+    ("clock", 10),
+    ("config", 11),
+    ("irq_stats", 12),
+    ("io_stats", 13),
+];
+
+/// Hashed major numbers are allocated from this range, numbers below this are reserved for [KNOWN_MAJORS].
+const DYNAMIC_MAJORS: core::ops::Range<usize> = 256..4096;
+
+static MAJORS: spin::Mutex<BTreeMap<usize, &'static str>> = spin::Mutex::new(BTreeMap::new());
+
+#[derive(Debug)]
+pub enum MajorError {
+    /// A driver with the same name has already registered a major number.
+    AlreadyRegistered(MajorNum),
+}
+
 impl MajorNum {
-    pub fn new() -> Self {
-        static NEXT: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(1); // 0 is not allowed.
-        Self(NEXT.fetch_add(1,atomic::Ordering::Relaxed))
+    /// Registers `driver` and returns its major number.
+    pub fn register(driver: &'static str) -> Result<Self, MajorError> {
+        let mut majors = MAJORS.lock();
+        if let Some((n, _)) = majors.iter().find(|(_, d)| **d == driver) {
+            return Err(MajorError::AlreadyRegistered(Self(*n)));
+        }
+
+        let n = match KNOWN_MAJORS.iter().find(|(d, _)| *d == driver) {
+            Some((_, n)) => *n,
+            None => {
+                let len = DYNAMIC_MAJORS.len();
+                let hash = Self::hash(driver) as usize % len;
+                let n = (0..len)
+                    .map(|i| DYNAMIC_MAJORS.start + (hash + i) % len)
+                    .find(|n| !majors.contains_key(n))
+                    .expect("Exhausted dynamic major numbers");
+                let preferred = DYNAMIC_MAJORS.start + hash;
+                if n != preferred {
+                    log::warn!("Major number {preferred} for {driver} collides with {}, using {n}", majors[&preferred]);
+                }
+                n
+            }
+        };
+        majors.insert(n, driver);
+        Ok(Self(n))
+    }
+
+    /// Returns the major number registered by `driver`.
+    pub fn lookup(driver: &str) -> Option<Self> {
+        MAJORS.lock().iter().find(|(_, d)| **d == driver).map(|(n, _)| Self(*n))
+    }
+
+    /// Returns the name of the driver which registered `self`.
+    pub fn driver(&self) -> Option<&'static str> {
+        MAJORS.lock().get(&self.0).copied()
+    }
+
+    /// Returns all registered major numbers and their drivers.
+    pub fn registered() -> alloc::vec::Vec<(Self, &'static str)> {
+        MAJORS.lock().iter().map(|(n, d)| (Self(*n), *d)).collect()
+    }
+
+    /// FNV-1a
+    fn hash(name: &str) -> u32 {
+        name.bytes().fold(0x811c9dc5, |h, b| (h ^ b as u32).wrapping_mul(0x01000193))
+    }
+}
+
+impl Display for MajorNum {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        core::write!(f, "{}", self.0)
     }
 }
 
@@ -463,6 +769,14 @@ impl MountPoints {
         None
     }
 
+    /// Returns whether any mountpoint is at `path` or beneath it.
+    fn within(&self, path: &str) -> bool {
+        let path = path.trim_end_matches(super::PATH_SEPARATOR);
+        self.mount_list.values().any(|m| {
+            m.location.strip_prefix(path).is_some_and(|r| r.is_empty() || r.starts_with(super::PATH_SEPARATOR))
+        })
+    }
+
     fn get_dev(&self, id: DevID) -> Option<&MountDescription> {
         self.mount_list.get(&id)
     }
@@ -488,10 +802,11 @@ struct MountDescription {
     location: String,
     ty: Option<String>,
     options: String,
+    flags: MountFlags,
 }
 
 impl MountDescription {
-    fn new(dev: Box<dyn DeviceFile>, mountpoint: String, options: String, ) -> Self {
+    fn new(dev: Box<dyn DeviceFile>, mountpoint: String, options: String, flags: MountFlags) -> Self {
         let fs = cast_file!(&FileSystem: (&*dev).dyn_upcast());
 
         let name= if let Ok(f) = fs {
@@ -506,6 +821,7 @@ impl MountDescription {
             location: mountpoint,
             ty: fs.map(|fs| fs.driver_name().to_string()).ok(),
             options,
+            flags,
             file: dev,
         }
     }
@@ -0,0 +1,201 @@
+//! Dirty data accounting and write-back throttling.
+//!
+//! Caches which hold modified data until it is written back charge it to the [DirtyAccount] of
+//! the device it belongs to, accounts are also summed into a global count. Without a limit a task
+//! writing faster than its disk would fill memory with dirty data.
+//!
+//! Each limit has a high and a low watermark, measured in pages. When either the global or the
+//! device's dirty data exceeds its high watermark, [DirtyAccount::over_high] returns `true` and
+//! the cache must throttle the writer before accepting more data: the writer waits while the
+//! cache writes back its oldest dirty data until both counts are below their low watermarks or the
+//! cache has nothing left to write. Write-back is done for the throttled writer rather than by a
+//! separate task because caches borrow their disk, so the writer is slowed to the speed of the disk.
+//!
+//! A cache only writes back its own data, when the global limit is exceeded by other devices each
+//! of their writers is throttled on their next write.
+//!
+//! Written data may still be held in a device's volatile write cache. [sync] flushes every block
+//! device, the `/bin/sync` program calls it or syncs the files given to it, see
+//! [crate::fs::file::NormalFile::sync]. `-F FD` syncs a descriptor inherited by the program using
+//! [crate::fs::fd::fsync].
+
+use super::vfs::DevID;
+use crate::config::Tunable;
+use crate::error::KernelError;
+use crate::task::spawn::Args;
+use crate::task::TaskResult;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Writers are throttled when this many pages are dirty.
+pub static DIRTY_HIGH: Tunable<usize> = Tunable::new("writeback.dirty_high", "Dirty pages at which writers are throttled", 4096);
+/// Throttled writers write back until fewer pages than this are dirty.
+pub static DIRTY_LOW: Tunable<usize> = Tunable::new("writeback.dirty_low", "Dirty pages write-back reduces to", 1024);
+/// Like [DIRTY_HIGH] for a single device.
+pub static DEVICE_HIGH: Tunable<usize> = Tunable::new("writeback.device_high", "Dirty pages of a device at which its writers are throttled", 1024);
+/// Like [DIRTY_LOW] for a single device.
+pub static DEVICE_LOW: Tunable<usize> = Tunable::new("writeback.device_low", "Dirty pages of a device write-back reduces to", 256);
+
+/// Dirty bytes of all devices.
+static DIRTY: AtomicUsize = AtomicUsize::new(0);
+
+static ACCOUNTS: spin::Mutex<BTreeMap<DevID, Weak<DirtyAccount>>> = spin::Mutex::new(BTreeMap::new());
+
+fn pages(bytes: usize) -> usize {
+    bytes.div_ceil(crate::mem::PAGE_SIZE)
+}
+
+/// Dirty data of a single device, see the module documentation.
+pub struct DirtyAccount {
+    dev: DevID,
+    /// Dirty bytes
+    dirty: AtomicUsize,
+}
+
+impl DirtyAccount {
+    pub fn device(&self) -> DevID {
+        self.dev
+    }
+
+    /// Returns the number of dirty pages charged to the device.
+    pub fn dirty_pages(&self) -> usize {
+        pages(self.dirty.load(Ordering::Relaxed))
+    }
+
+    /// Charges `bytes` of newly dirtied data.
+    pub fn dirtied(&self, bytes: usize) {
+        self.dirty.fetch_add(bytes, Ordering::Relaxed);
+        DIRTY.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Releases `bytes` of data which was written back or discarded.
+    pub fn cleaned(&self, bytes: usize) {
+        self.dirty.fetch_sub(bytes, Ordering::Relaxed);
+        DIRTY.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Returns whether writers to the device must be throttled.
+    pub fn over_high(&self) -> bool {
+        dirty_pages() > DIRTY_HIGH.get() || self.dirty_pages() > DEVICE_HIGH.get()
+    }
+
+    /// Returns whether a throttled writer must continue writing back.
+    pub fn over_low(&self) -> bool {
+        dirty_pages() > DIRTY_LOW.get() || self.dirty_pages() > DEVICE_LOW.get()
+    }
+}
+
+impl Drop for DirtyAccount {
+    fn drop(&mut self) {
+        // Discarded data is no longer charged
+        DIRTY.fetch_sub(*self.dirty.get_mut(), Ordering::Relaxed);
+        let mut l = ACCOUNTS.lock();
+        if l.get(&self.dev).is_some_and(|a| a.strong_count() == 0) {
+            l.remove(&self.dev);
+        }
+    }
+}
+
+/// Returns the account of `dev`, all caches of a device share its account.
+pub fn account(dev: DevID) -> Arc<DirtyAccount> {
+    let mut l = ACCOUNTS.lock();
+    if let Some(a) = l.get(&dev).and_then(Weak::upgrade) {
+        return a;
+    }
+    let a = Arc::new(DirtyAccount { dev, dirty: AtomicUsize::new(0) });
+    l.insert(dev, Arc::downgrade(&a));
+    a
+}
+
+/// Returns the number of dirty pages of all devices.
+pub fn dirty_pages() -> usize {
+    pages(DIRTY.load(Ordering::Relaxed))
+}
+
+/// Returns the number of dirty pages of each device which has an account.
+pub fn device_stats() -> Vec<(DevID, usize)> {
+    // Dropped after unlocking, dropping the last reference locks the list
+    let accounts: Vec<Arc<DirtyAccount>> = ACCOUNTS.lock().values().filter_map(Weak::upgrade).collect();
+    accounts.iter().map(|a| (a.dev, a.dirty_pages())).collect()
+}
+
+/// Flushes every block device, once this returns data written to any block device is durable.
+///
+/// Caches holding dirty data must be synced by their owners first, e.g. by
+/// [BufferCache::sync](super::disk_util::buffer_cache::BufferCache::sync). Every device is flushed
+/// even when one fails, the first error is returned.
+pub async fn sync() -> Result<(), KernelError> {
+    let list = crate::system::sysfs::get_sysfs().get_blk_dev();
+    let mut r = Ok(());
+    for id in list.list() {
+        let Some(dev) = list.fetch(id) else { continue };
+        if let Err(e) = dev.flush().await {
+            let err = KernelError::from(e).with_device(id).with_context("Flush failed");
+            log::error!("sync: {err}");
+            r = r.and(Err(err));
+        }
+    }
+    r
+}
+
+/// Syncs the normal file at `path`.
+async fn sync_file(path: &str, data_only: bool) -> Result<(), KernelError> {
+    let file = crate::fs::get_vfs().open(path).await.map_err(|e| KernelError::from(e).wrap("sync", alloc::format!("Failed to open {path}")))?;
+    let file = super::file::cast_file!(super::file::NormalFile<u8>: file)
+        .map_err(|_| KernelError::new("sync", crate::error::Errno::EINVAL).with_context(alloc::format!("{path} is not a normal file")))?;
+    file.sync(data_only).await.map_err(|e| KernelError::new("sync", e).with_context(alloc::format!("Failed to sync {path}")))
+}
+
+fn program(args: Args) -> Pin<Box<dyn Future<Output = TaskResult> + Send>> {
+    Box::pin(async move {
+        let mut data_only = false;
+        let mut files: Vec<&String> = Vec::new();
+        let mut fds: Vec<super::fd::Fd> = Vec::new();
+        let usage = || {
+            crate::serial_println!("Usage: sync [-d] [-F FD]... [FILE]...");
+            TaskResult::Error
+        };
+        let mut argv = args.argv.iter().skip(1);
+        while let Some(a) = argv.next() {
+            match a.as_str() {
+                "-d" => data_only = true,
+                "-F" => match argv.next().and_then(|fd| fd.parse().ok()) {
+                    Some(fd) => fds.push(fd),
+                    None => return usage(),
+                },
+                s if s.starts_with('-') => return usage(),
+                _ => files.push(a),
+            }
+        }
+
+        let mut ok = true;
+        if files.is_empty() && fds.is_empty() {
+            ok = sync().await.is_ok();
+        }
+        for f in files {
+            if let Err(e) = sync_file(f, data_only).await {
+                crate::serial_println!("sync: {e}");
+                ok = false;
+            }
+        }
+        for fd in fds {
+            let r = if data_only { super::fd::fdatasync(fd).await } else { super::fd::fsync(fd).await };
+            if let Err(e) = r {
+                crate::serial_println!("sync: {}", KernelError::new("sync", e).with_context(alloc::format!("Failed to sync descriptor {fd}")));
+                ok = false;
+            }
+        }
+        if ok { TaskResult::ExitedNormally } else { TaskResult::Error }
+    })
+}
+
+/// Registers the `/bin/sync` program.
+pub fn init() {
+    crate::task::spawn::register("/bin/sync", program).expect("Failed to register sync");
+}
@@ -5,20 +5,24 @@ use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// NMIs may arrive while any stack is in use, including while another exception is being handled.
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
 const STACK_SIZE: usize = 4096 * 5;
+/// IST indices which are given a stack.
+const IST_INDICES: [u16; 3] = [DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX, MACHINE_CHECK_IST_INDEX];
 
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+        static mut STACKS: [[u8; STACK_SIZE]; IST_INDICES.len()] = [[0; STACK_SIZE]; IST_INDICES.len()];
 
-            // SAFETY: This is safe STACK is only accessed by the bsp
-            let stack_start = unsafe {VirtAddr::from_ptr(core::ptr::addr_of!(STACK)) };
-            let stack_end = stack_start + STACK_SIZE as u64;
-            stack_end
-        };
+        let mut tss = TaskStateSegment::new();
+        for (i, ist) in IST_INDICES.iter().enumerate() {
+            // SAFETY: This is safe STACKS is only accessed by the bsp
+            let stack_start = unsafe { VirtAddr::from_ptr(core::ptr::addr_of!(STACKS[i])) };
+            tss.interrupt_stack_table[*ist as usize] = stack_start + STACK_SIZE as u64;
+        }
         tss
     };
 }
@@ -46,13 +50,15 @@ lazy_static! {
 
 pub(crate) fn new_tss() -> TaskStateSegment {
     let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        // This is preferred over allocating a Box<[_,_]>
-        let mut b: alloc::vec::Vec<u8> = alloc::vec::Vec::new();
-        b.resize(STACK_SIZE,0);
-        let l = b.leak();
-        VirtAddr::from_ptr(&l[0]) + STACK_SIZE as u64
-    };
+    for ist in IST_INDICES {
+        tss.interrupt_stack_table[ist as usize] = {
+            // This is preferred over allocating a Box<[_,_]>
+            let mut b: alloc::vec::Vec<u8> = alloc::vec::Vec::new();
+            b.resize(STACK_SIZE,0);
+            let l = b.leak();
+            VirtAddr::from_ptr(&l[0]) + STACK_SIZE as u64
+        };
+    }
     tss
 }
 
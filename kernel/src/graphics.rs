@@ -1,6 +1,7 @@
 use crate::graphics::pixel::{PixBgr3Byte, PixBgr4Byte, Pixel};
 
 pub mod basic_output;
+pub mod vconsole;
 
 mod pixel;
 
@@ -114,6 +115,60 @@ impl FrameBuffer {
             None
         }
     }
+
+    /// Fills the rectangle at `x,y` with the colour `rgb`.
+    /// Any part of the rectangle outside the framebuffer is ignored.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, rgb: (u8, u8, u8)) {
+        for scan in y..y + height {
+            let format = self.format;
+            if let Some(buff) = self.scan(scan) {
+                let mut buff: pixel::PixBuff = buff.into();
+                match format {
+                    PixelFormat::Bgr4Byte => fill_px(buff.buff::<PixBgr4Byte>(), x, width, rgb),
+                    PixelFormat::Bgr3Byte => fill_px(buff.buff::<PixBgr3Byte>(), x, width, rgb),
+                    _ => panic!("Unable to convert between pixel formats"),
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Draws `glyph` at `x,y` using `fg` for set pixels and `bg` for clear pixels.
+    pub(crate) fn draw_glyph(&mut self, glyph: &bitmap_fontgen::BitMap, x: usize, y: usize, fg: (u8, u8, u8), bg: (u8, u8, u8)) {
+        for glyph_scan in 0..glyph.height() {
+            let format = self.format;
+            if let Some(buff) = self.scan(y + glyph_scan) {
+                let mut buff: pixel::PixBuff = buff.into();
+                match format {
+                    PixelFormat::Bgr4Byte => glyph.draw_into_scan(
+                        glyph_scan,
+                        |b| rgb_px::<PixBgr4Byte>(if b { fg } else { bg }),
+                        &mut buff.buff::<PixBgr4Byte>()[x..],
+                    ),
+                    PixelFormat::Bgr3Byte => glyph.draw_into_scan(
+                        glyph_scan,
+                        |b| rgb_px::<PixBgr3Byte>(if b { fg } else { bg }),
+                        &mut buff.buff::<PixBgr3Byte>()[x..],
+                    ),
+                    _ => panic!("Unable to convert between pixel formats"),
+                }
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+fn rgb_px<P: Pixel>(rgb: (u8, u8, u8)) -> P {
+    P::from_pix_data(pixel::GenericPixelData::Colour(rgb.0, rgb.1, rgb.2))
+}
+
+fn fill_px<P: Pixel + Clone>(buff: &mut [P], x: usize, width: usize, rgb: (u8, u8, u8)) {
+    let end = (x + width).min(buff.len());
+    if x < end {
+        buff[x..end].fill(rgb_px(rgb));
+    }
 }
 
 impl Sprite for FrameBuffer {
@@ -1,4 +1,6 @@
 use super::*;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 use core::fmt;
 use core::fmt::Write;
 use x86_64::instructions::interrupts::without_interrupts;
@@ -11,9 +13,48 @@ const FONT_WEIGHT: bitmap_fontgen::FontWeight = bitmap_fontgen::FontWeight { inn
 
 type LockedFb<'a> = crate::util::static_protected::Ref<'a, FrameBuffer>;
 
+/// Number of screens of text kept in the scrollback buffer, including the visible screen.
+const SCROLLBACK_PAGES: usize = 8;
+/// Maximum number of parameters accepted in a control sequence, extra parameters are ignored.
+const MAX_PARAMS: usize = 8;
+
+/// Standard VGA palette used for the 16 ANSI colours.
+const PALETTE: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (170, 0, 0),
+    (0, 170, 0),
+    (170, 85, 0),
+    (0, 0, 170),
+    (170, 0, 170),
+    (0, 170, 170),
+    (170, 170, 170),
+    (85, 85, 85),
+    (255, 85, 85),
+    (85, 255, 85),
+    (255, 255, 85),
+    (85, 85, 255),
+    (255, 85, 255),
+    (85, 255, 255),
+    (255, 255, 255),
+];
+
+/// Number of virtual consoles.
+pub const VCONSOLE_COUNT: usize = 4;
+/// Console used for kernel messages, this is [WRITER].
+pub const VC_LOG: usize = 0;
+/// Console reserved for the shell.
+pub const VC_SHELL: usize = 1;
+/// Console receiving debug and trace log messages.
+pub const VC_DEBUG: usize = 2;
+
 //TODO add scheduled write from buffer
+/// The kernel log console, this is written to by [print] and is virtual console [VC_LOG].
 pub static WRITER: spin::Mutex<Option<BasicTTY>> = spin::Mutex::new(None);
 
+/// Virtual consoles other than [WRITER], index `n` is console `n + 1`.
+static VCONSOLES: [spin::Mutex<Option<BasicTTY>>; VCONSOLE_COUNT - 1] = [const { spin::Mutex::new(None) }; VCONSOLE_COUNT - 1];
+static ACTIVE_CONSOLE: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(VC_LOG);
+
 //assume framebuffer is always `Some`
 pub struct BasicTTY {
     framebuffer: &'static crate::util::KernelStatic<FrameBuffer>,
@@ -27,6 +68,82 @@ pub struct BasicTTY {
 
     char_width: usize,
     char_height: usize,
+
+    attr: Attr,
+    saved_cursor: (usize, usize),
+    escape: Escape,
+
+    /// Lines of text, the last `cursor_y_max` lines are the lines currently on screen.
+    scrollback: VecDeque<Vec<Cell>>,
+    /// Number of lines the view is scrolled back by.
+    view_offset: usize,
+    /// Whether this console is displayed. Inactive consoles only write to their scrollback buffer.
+    active: bool,
+}
+
+/// Colour attributes for a character. Colours are indexes into [PALETTE].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Attr {
+    fg: u8,
+    bg: u8,
+    bold: bool,
+    reverse: bool,
+}
+
+impl Attr {
+    const DEFAULT_FG: u8 = 15;
+    const DEFAULT_BG: u8 = 0;
+    const DEFAULT: Self = Self {
+        fg: Self::DEFAULT_FG,
+        bg: Self::DEFAULT_BG,
+        bold: false,
+        reverse: false,
+    };
+
+    /// Returns the foreground and background colours
+    fn colours(&self) -> ((u8, u8, u8), (u8, u8, u8)) {
+        let fg = if self.bold && self.fg < 8 { self.fg + 8 } else { self.fg };
+        let (fg, bg) = (PALETTE[fg as usize], PALETTE[self.bg as usize]);
+        if self.reverse { (bg, fg) } else { (fg, bg) }
+    }
+
+    /// Applies the SGR (Select Graphic Rendition) parameter `p`
+    fn apply_sgr(&mut self, p: u16) {
+        match p {
+            0 => *self = Self::DEFAULT,
+            1 => self.bold = true,
+            22 => self.bold = false,
+            7 => self.reverse = true,
+            27 => self.reverse = false,
+            30..=37 => self.fg = (p - 30) as u8,
+            39 => self.fg = Self::DEFAULT_FG,
+            40..=47 => self.bg = (p - 40) as u8,
+            49 => self.bg = Self::DEFAULT_BG,
+            90..=97 => self.fg = (p - 90) as u8 + 8,
+            100..=107 => self.bg = (p - 100) as u8 + 8,
+            _ => {}
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Cell {
+    c: char,
+    attr: Attr,
+}
+
+impl Cell {
+    const BLANK: Self = Self { c: ' ', attr: Attr::DEFAULT };
+}
+
+/// State of the escape sequence parser.
+#[derive(Copy, Clone, Debug)]
+enum Escape {
+    None,
+    /// An ESC has been received
+    Esc,
+    /// Within a control sequence (`ESC [`), holds the parameters received so far.
+    Csi { params: [u16; MAX_PARAMS], count: usize },
 }
 
 impl BasicTTY {
@@ -49,6 +166,12 @@ impl BasicTTY {
             cursor_y_max,
             char_width,
             char_height,
+            attr: Attr::DEFAULT,
+            saved_cursor: (0, 0),
+            escape: Escape::None,
+            scrollback: core::iter::repeat_with(Vec::new).take(cursor_y_max).collect(),
+            view_offset: 0,
+            active: true,
         }
     }
 
@@ -59,12 +182,49 @@ impl BasicTTY {
 
     #[inline]
     fn print_char_inner(&mut self, c: char, fb: &mut LockedFb) {
+        if self.view_offset != 0 {
+            self.view_offset = 0;
+            self.redraw(fb);
+        }
+
+        match &mut self.escape {
+            Escape::None => {}
+            Escape::Esc => {
+                self.escape = match c {
+                    '[' => Escape::Csi { params: [0; MAX_PARAMS], count: 0 },
+                    _ => Escape::None,
+                };
+                return;
+            }
+            Escape::Csi { params, count } => {
+                match c {
+                    '0'..='9' => {
+                        *count = (*count).max(1);
+                        let p = &mut params[*count - 1];
+                        *p = p.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                    }
+                    ';' => *count = ((*count).max(1) + 1).min(MAX_PARAMS),
+                    // intermediate and private bytes are ignored
+                    ' '..='?' => {}
+                    '@'..='~' => {
+                        let (params, count) = (*params, *count);
+                        self.escape = Escape::None;
+                        self.csi_dispatch(c, &params[..count], fb);
+                    }
+                    _ => self.escape = Escape::None,
+                }
+                return;
+            }
+        }
+
         match c {
             '\n' => {
                 self.newline_inner(fb);
                 self.carriage_return();
             }
             '\r' => self.carriage_return(),
+            '\x08' => self.cursor_x = self.cursor_x.saturating_sub(1),
+            '\x1b' => self.escape = Escape::Esc,
             c => {
                 if let Some(bitmap) = self.font_map.get(FONT_WEIGHT, FONT_SIZE, c) {
                     if self.cursor_x >= self.cursor_x_max {
@@ -72,18 +232,136 @@ impl BasicTTY {
                         self.carriage_return();
                     }
 
-                    let l = &mut *fb;
-                    l.draw_into_self(
-                        &bitmap,
-                        self.cursor_x * self.char_width,
-                        self.cursor_y * self.char_height,
-                    );
+                    let cell = Cell { c, attr: self.attr };
+                    let x = self.cursor_x;
+                    let line = self.line_mut(self.cursor_y);
+                    if line.len() <= x {
+                        line.resize(x + 1, Cell::BLANK);
+                    }
+                    line[x] = cell;
+
+                    if self.active {
+                        let (fg, bg) = cell.attr.colours();
+                        fb.draw_glyph(
+                            &bitmap,
+                            self.cursor_x * self.char_width,
+                            self.cursor_y * self.char_height,
+                            fg,
+                            bg,
+                        );
+                    }
                     self.cursor_x += 1;
                 }
             }
         }
     }
 
+    /// Handles the control sequence with the final byte `c`
+    fn csi_dispatch(&mut self, c: char, params: &[u16], fb: &mut LockedFb) {
+        // A parameter of `0` is treated as if it was omitted
+        let arg = |i: usize, default: usize| params.get(i).map(|p| *p as usize).filter(|p| *p != 0).unwrap_or(default);
+        let (cols, rows) = (self.cursor_x_max, self.cursor_y_max);
+
+        match c {
+            'A' => self.cursor_y = self.cursor_y.saturating_sub(arg(0, 1)),
+            'B' => self.cursor_y = (self.cursor_y + arg(0, 1)).min(rows - 1),
+            'C' => self.cursor_x = (self.cursor_x + arg(0, 1)).min(cols - 1),
+            'D' => self.cursor_x = self.cursor_x.saturating_sub(arg(0, 1)),
+            'G' => self.cursor_x = (arg(0, 1) - 1).min(cols - 1),
+            'H' | 'f' => {
+                self.cursor_y = (arg(0, 1) - 1).min(rows - 1);
+                self.cursor_x = (arg(1, 1) - 1).min(cols - 1);
+            }
+            'J' => {
+                let (lines, partial) = match arg(0, 0) {
+                    0 => (self.cursor_y + 1..rows, Some(self.cursor_x..cols)),
+                    1 => (0..self.cursor_y, Some(0..self.cursor_x + 1)),
+                    _ => (0..rows, None),
+                };
+                for y in lines {
+                    self.erase(y, 0..cols, fb);
+                }
+                if let Some(range) = partial {
+                    self.erase(self.cursor_y, range, fb);
+                }
+            }
+            'K' => {
+                let range = match arg(0, 0) {
+                    0 => self.cursor_x..cols,
+                    1 => 0..self.cursor_x + 1,
+                    _ => 0..cols,
+                };
+                self.erase(self.cursor_y, range, fb);
+            }
+            'm' if params.is_empty() => self.attr = Attr::DEFAULT,
+            'm' => params.iter().for_each(|p| self.attr.apply_sgr(*p)),
+            's' => self.saved_cursor = (self.cursor_x, self.cursor_y),
+            'u' => (self.cursor_x, self.cursor_y) = self.saved_cursor,
+            _ => {}
+        }
+    }
+
+    /// Returns the on screen line `y`
+    fn line_mut(&mut self, y: usize) -> &mut Vec<Cell> {
+        let i = self.scrollback.len() - self.cursor_y_max + y;
+        &mut self.scrollback[i]
+    }
+
+    /// Erases the characters in `range` on the line `y`
+    fn erase(&mut self, y: usize, range: core::ops::Range<usize>, fb: &mut LockedFb) {
+        let line = self.line_mut(y);
+        if range.end >= line.len() {
+            line.truncate(range.start);
+        } else {
+            line[range.clone()].fill(Cell::BLANK);
+        }
+
+        if !self.active {
+            return;
+        }
+        let width = range.len().min(self.cursor_x_max.saturating_sub(range.start));
+        fb.fill_rect(
+            range.start * self.char_width,
+            y * self.char_height,
+            width * self.char_width,
+            self.char_height,
+            PALETTE[Attr::DEFAULT_BG as usize],
+        );
+    }
+
+    /// Redraws the whole screen from the scrollback buffer using the current view offset.
+    fn redraw(&mut self, fb: &mut LockedFb) {
+        if !self.active {
+            return;
+        }
+        let first = self.scrollback.len() - self.cursor_y_max - self.view_offset;
+        fb.clear_lines(0..self.cursor_y_max * self.char_height);
+        for (y, line) in self.scrollback.range(first..first + self.cursor_y_max).enumerate() {
+            for (x, cell) in line.iter().enumerate() {
+                if cell.c == ' ' && cell.attr.colours().1 == PALETTE[Attr::DEFAULT_BG as usize] {
+                    continue;
+                }
+                if let Some(bitmap) = self.font_map.get(FONT_WEIGHT, FONT_SIZE, cell.c) {
+                    let (fg, bg) = cell.attr.colours();
+                    fb.draw_glyph(&bitmap, x * self.char_width, y * self.char_height, fg, bg);
+                }
+            }
+        }
+    }
+
+    /// Scrolls the view back into the scrollback buffer by `lines`.
+    /// A negative value scrolls toward the most recent output.
+    ///
+    /// The view will return to the bottom of the buffer when any characters are printed.
+    pub fn scroll_view(&mut self, lines: isize) {
+        let max = (self.scrollback.len() - self.cursor_y_max) as isize;
+        let offset = (self.view_offset as isize + lines).clamp(0, max) as usize;
+        if offset != self.view_offset {
+            self.view_offset = offset;
+            self.redraw(&mut self.framebuffer.get());
+        }
+    }
+
     /// Prints a string to the screen
     /// using print char
     pub fn print_str(&mut self, s: &str) {
@@ -105,7 +383,15 @@ impl BasicTTY {
     fn newline_inner(&mut self, fb: &mut LockedFb) {
         if self.cursor_y + 1 >= self.cursor_y_max {
             let l = self.char_height;
-            fb.scroll_up(l);
+            if self.active {
+                fb.scroll_up(l);
+                fb.clear_lines(self.cursor_y * l..(self.cursor_y + 1) * l);
+            }
+
+            self.scrollback.push_back(Vec::new());
+            if self.scrollback.len() > self.cursor_y_max * SCROLLBACK_PAGES {
+                self.scrollback.pop_front();
+            }
         } else {
             self.cursor_y += 1;
         }
@@ -118,9 +404,24 @@ impl BasicTTY {
     }
 
     fn clear(&mut self) {
-        self.framebuffer.get().clear();
+        if self.active {
+            self.framebuffer.get().clear();
+        }
+        for y in 0..self.cursor_y_max {
+            self.line_mut(y).clear();
+        }
         self.cursor_x = 0;
         self.cursor_y = 0;
+        self.view_offset = 0;
+    }
+
+    /// Sets whether this console is displayed, when it becomes active the screen is redrawn.
+    fn set_active(&mut self, active: bool) {
+        self.active = active;
+        if active {
+            self.view_offset = 0;
+            self.redraw(&mut self.framebuffer.get());
+        }
     }
 }
 
@@ -139,7 +440,69 @@ pub fn _print(args: fmt::Arguments) {
     })
 }
 pub unsafe fn _panic_print() {
-    WRITER.force_unlock()
+    WRITER.force_unlock();
+    for c in &VCONSOLES {
+        c.force_unlock();
+    }
+    // Panic messages are written to the kernel log so make sure it's visible.
+    switch_console(VC_LOG);
+}
+
+/// Initializes all virtual consoles on `buff`. The kernel log console is displayed.
+pub fn init(buff: &'static crate::util::KernelStatic<FrameBuffer>) {
+    *WRITER.lock() = Some(BasicTTY::new(buff));
+    for c in &VCONSOLES {
+        let mut tty = BasicTTY::new(buff);
+        tty.active = false;
+        *c.lock() = Some(tty);
+    }
+}
+
+/// Returns the virtual console `n`
+pub fn console(n: usize) -> Option<&'static spin::Mutex<Option<BasicTTY>>> {
+    match n {
+        VC_LOG => Some(&WRITER),
+        n => VCONSOLES.get(n - 1),
+    }
+}
+
+/// Returns the number of columns and rows of virtual console `n`.
+pub fn console_size(n: usize) -> Option<(usize, usize)> {
+    let c = console(n)?;
+    without_interrupts(|| c.lock().as_ref().map(|tty| (tty.cursor_x_max, tty.cursor_y_max)))
+}
+
+/// Returns the index of the currently displayed console.
+pub fn active_console() -> usize {
+    ACTIVE_CONSOLE.load(atomic::Ordering::Relaxed)
+}
+
+/// Displays virtual console `n`. Does nothing if `n` is not a valid console.
+pub fn switch_console(n: usize) {
+    let Some(new) = console(n) else { return };
+    without_interrupts(|| {
+        let old = ACTIVE_CONSOLE.swap(n, atomic::Ordering::Relaxed);
+        if old == n {
+            return;
+        }
+        // Only one console is locked at a time, the old console must stop drawing before the new one starts.
+        if let Some(tty) = console(old).unwrap().lock().as_mut() {
+            tty.set_active(false);
+        }
+        if let Some(tty) = new.lock().as_mut() {
+            tty.set_active(true);
+        }
+    })
+}
+
+/// Prints `args` to the virtual console `n`
+pub fn _print_to(n: usize, args: fmt::Arguments) {
+    let Some(c) = console(n) else { return };
+    without_interrupts(|| {
+        if let Some(tty) = c.lock().as_mut() {
+            tty.write_fmt(args).unwrap() //does not return `err()`
+        }
+    })
 }
 
 pub fn _clear() {
@@ -150,6 +513,17 @@ pub fn _clear() {
     })
 }
 
+/// Scrolls the displayed console by `steps` half-screens, positive values scroll back into the scrollback buffer.
+pub fn scroll_console(steps: isize) {
+    let Some(c) = console(active_console()) else { return };
+    without_interrupts(|| {
+        if let Some(tty) = c.lock().as_mut() {
+            let lines = (tty.cursor_y_max / 2).max(1) as isize;
+            tty.scroll_view(steps * lines)
+        }
+    })
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::graphics::basic_output::_print(format_args!($($arg)*)));
@@ -0,0 +1,311 @@
+//! TTY device files for the virtual consoles.
+//!
+//! Each virtual console is exposed as a character device at `/dev/tty{n}`. Writing to the file prints
+//! to the console, reading returns keyboard input which was received while the console was displayed.
+//!
+//! A console may be the controlling terminal of one [Session], set by the session leader using
+//! [set_controlling]. The terminal has a foreground process group which is initially the leader's
+//! group and is changed using [tcsetpgrp]. When the console has a controlling session the line
+//! discipline sends [Signal::Int] to the foreground group when Ctrl+C is typed and [Signal::Tstp]
+//! when Ctrl+Z is typed, these characters and any unread input are discarded.
+
+use super::basic_output::{self, VCONSOLE_COUNT};
+use crate::fs::devfs::naming::DeviceClass;
+use crate::fs::device::{Fifo, OpenMode};
+use crate::fs::file::*;
+use crate::fs::vfs::{DevID, MajorNum};
+use crate::fs::{IoError, IoResult};
+use crate::mem::dma::DmaBuff;
+use crate::task::job::{self, Session, Signal};
+use crate::task::TaskId;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::future::BoxFuture;
+use futures_util::task::AtomicWaker;
+use futures_util::FutureExt;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Maximum number of unread input bytes buffered for each console. Further input is dropped.
+pub(crate) static INPUT_LIMIT: crate::config::Tunable<usize> =
+    crate::config::Tunable::new("vconsole.input_limit", "Number of unread input bytes buffered for each virtual console", 256);
+
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("vconsole").unwrap(););
+
+static INPUT: [Input; VCONSOLE_COUNT] = [const { Input::new() }; VCONSOLE_COUNT];
+
+struct Input {
+    queue: spin::Mutex<VecDeque<u8>>,
+    waker: AtomicWaker,
+    /// Only one reader is allowed for each console.
+    reader: atomic::Atomic<bool>,
+    terminal: spin::Mutex<Terminal>,
+}
+
+/// Job control state of a console.
+struct Terminal {
+    session: Weak<Session>,
+    foreground: TaskId,
+}
+
+impl Input {
+    const fn new() -> Self {
+        Self {
+            queue: spin::Mutex::new(VecDeque::new()),
+            waker: AtomicWaker::new(),
+            reader: atomic::Atomic::new(false),
+            terminal: spin::Mutex::new(Terminal { session: Weak::new(), foreground: TaskId::KERNEL }),
+        }
+    }
+
+    /// Returns the foreground group if the console has a controlling session.
+    fn foreground(&self) -> Option<TaskId> {
+        let l = self.terminal.lock();
+        l.session.upgrade().map(|_| l.foreground)
+    }
+}
+
+/// Makes `console` the controlling terminal of the running task's session.
+///
+/// Returns [IoError::PermissionDenied] if the task is not a session leader or the session already
+/// has a controlling terminal and [IoError::Busy] if the console is controlled by another session.
+pub fn set_controlling(console: usize) -> Result<(), IoError> {
+    let input = INPUT.get(console).ok_or(IoError::NotPresent)?;
+    let proc = job::current().ok_or(IoError::NotSupported)?;
+    let group = proc.group();
+    let session = group.session();
+    if session.id() != proc.id() || session.tty().is_some() {
+        return Err(IoError::PermissionDenied);
+    }
+    let mut l = input.terminal.lock();
+    if l.session.strong_count() != 0 {
+        return Err(IoError::Busy);
+    }
+    l.session = Arc::downgrade(session);
+    l.foreground = group.id();
+    session.set_tty(Some(console));
+    Ok(())
+}
+
+/// Returns the foreground process group of `console`, `None` if it has no controlling session.
+pub fn tcgetpgrp(console: usize) -> Option<TaskId> {
+    INPUT.get(console)?.foreground()
+}
+
+/// Sets the foreground process group of `console`. The running task and the group must both be in
+/// the session which controls the console.
+pub fn tcsetpgrp(console: usize, pgid: TaskId) -> Result<(), IoError> {
+    let input = INPUT.get(console).ok_or(IoError::NotPresent)?;
+    let target = job::group(pgid).ok_or(IoError::NotPresent)?;
+    let caller = job::current().ok_or(IoError::NotSupported)?.group();
+    let mut l = input.terminal.lock();
+    let session = l.session.upgrade().ok_or(IoError::NotReady)?;
+    if !Arc::ptr_eq(&session, caller.session()) || !Arc::ptr_eq(&session, target.session()) {
+        return Err(IoError::PermissionDenied);
+    }
+    l.foreground = pgid;
+    Ok(())
+}
+
+/// Returns the signal sent by the control character `c`.
+fn signal_char(c: char) -> Option<Signal> {
+    match c {
+        '\u{3}' => Some(Signal::Int),
+        '\u{1a}' => Some(Signal::Tstp),
+        _ => None,
+    }
+}
+
+/// Queues `c` as input for the displayed console.
+///
+/// Returns `false` if `c` was consumed by the line discipline.
+pub(crate) fn push_input(c: char) -> bool {
+    let input = &INPUT[basic_output::active_console()];
+    if let Some(sig) = signal_char(c) {
+        if let Some(pgid) = input.foreground() {
+            without_interrupts(|| input.queue.lock().clear());
+            if let Err(e) = job::kill(pgid, sig) {
+                log::debug!("Failed to send {sig:?} to process group {pgid}: {e:?}");
+            }
+            return false;
+        }
+    }
+    let mut b = [0; 4];
+    without_interrupts(|| {
+        let mut l = input.queue.lock();
+        for i in c.encode_utf8(&mut b).bytes() {
+            if l.len() >= INPUT_LIMIT.get() {
+                break;
+            }
+            l.push_back(i);
+        }
+    });
+    input.waker.wake();
+    true
+}
+
+/// Mounts the TTY device for each virtual console.
+pub fn init() {
+    for n in 0..VCONSOLE_COUNT {
+        crate::task::util::block_on!(crate::fs::devfs::register(Box::new(VConsole::new(n)), DeviceClass::Console))
+            .expect("Failed to register virtual console with devfs");
+    }
+}
+
+/// File object for a virtual console.
+///
+/// Writes are interpreted as UTF-8, invalid sequences are replaced. Reads will wait until at least
+/// one byte of input is available.
+#[derive(Clone)]
+pub struct VConsole {
+    console: usize,
+    fifo_lock: OpenMode,
+    id: DevID,
+}
+
+impl VConsole {
+    fn new(console: usize) -> Self {
+        Self {
+            console,
+            fifo_lock: OpenMode::Locked,
+            id: DevID::new(*MAJOR, console),
+        }
+    }
+}
+
+#[cast_trait_object::dyn_upcast]
+#[cast_trait_object::dyn_cast(NormalFile<u8>, Directory, crate::fs::device::FileSystem, crate::fs::device::Fifo<u8>, crate::fs::device::DeviceFile )]
+impl File for VConsole {
+    fn file_type(&self) -> FileType {
+        FileType::CharDev
+    }
+
+    fn block_size(&self) -> u64 {
+        1
+    }
+
+    fn device(&self) -> DevID {
+        self.id
+    }
+
+    fn clone_file(&self) -> Box<dyn File> {
+        Box::new(self.clone())
+    }
+
+    fn id(&self) -> u64 {
+        0
+    }
+
+    fn len(&self) -> IoResult<u64> {
+        async { Ok(without_interrupts(|| INPUT[self.console].queue.lock().len()) as u64) }.boxed()
+    }
+}
+
+impl Drop for VConsole {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+impl crate::fs::device::DeviceFile for VConsole {}
+
+impl Fifo<u8> for VConsole {
+    fn open(&mut self, mode: OpenMode) -> Result<(), IoError> {
+        if self.fifo_lock != OpenMode::Locked {
+            return Err(IoError::DeviceError);
+        }
+        if mode.is_read() {
+            if let Err(_) = INPUT[self.console].reader.compare_exchange(false, true, atomic::Ordering::Acquire, atomic::Ordering::Relaxed) {
+                return Err(IoError::Busy);
+            }
+        }
+        self.fifo_lock = mode;
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), IoError> {
+        if self.fifo_lock == OpenMode::Locked {
+            return Err(IoError::NotReady);
+        }
+        if self.fifo_lock.is_read() {
+            INPUT[self.console].reader.store(false, atomic::Ordering::Release);
+        }
+        self.fifo_lock = OpenMode::Locked;
+        Ok(())
+    }
+
+    fn locks_remain(&self, mode: OpenMode) -> usize {
+        if mode.is_read() {
+            (!INPUT[self.console].reader.load(atomic::Ordering::Relaxed)) as usize
+        } else {
+            usize::MAX
+        }
+    }
+
+    fn is_master(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl Read<u8> for VConsole {
+    fn read<'f, 'a: 'f, 'b: 'f>(&'a self, _: u64, mut dbuff: DmaBuff<'b>) -> BoxFuture<'f, Result<(DmaBuff<'b>, usize), (IoError, DmaBuff<'b>, usize)>> {
+        async {
+            if !self.fifo_lock.is_read() {
+                return Err((IoError::NotReady, dbuff, 0));
+            }
+            InputFut { input: &INPUT[self.console] }.await;
+
+            // SAFETY: as_mut guarantees that this is safe.
+            let buff = unsafe { &mut *crate::mem::dma::DmaTarget::as_mut(&mut *dbuff) };
+            let count = without_interrupts(|| {
+                let mut l = INPUT[self.console].queue.lock();
+                let count = l.len().min(buff.len());
+                for (i, b) in l.drain(..count).enumerate() {
+                    buff[i] = b;
+                }
+                count
+            });
+            Ok((dbuff, count))
+        }.boxed()
+    }
+}
+
+impl Write<u8> for VConsole {
+    fn write<'f, 'a: 'f, 'b: 'f>(&'a self, _: u64, mut dbuff: DmaBuff<'b>) -> BoxFuture<'f, Result<(DmaBuff<'b>, usize), (IoError, DmaBuff<'b>, usize)>> {
+        async {
+            if !self.fifo_lock.is_write() {
+                return Err((IoError::NotReady, dbuff, 0));
+            }
+            // SAFETY: as_mut guarantees that this is safe.
+            let buff = unsafe { &mut *crate::mem::dma::DmaTarget::as_mut(&mut *dbuff) };
+            let len = buff.len();
+            basic_output::_print_to(self.console, format_args!("{}", alloc::string::String::from_utf8_lossy(buff)));
+            Ok((dbuff, len))
+        }.boxed()
+    }
+}
+
+/// Completes when input is available.
+struct InputFut<'a> {
+    input: &'a Input,
+}
+
+impl<'a> core::future::Future for InputFut<'a> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let ready = || without_interrupts(|| !self.input.queue.lock().is_empty());
+        if ready() {
+            return Poll::Ready(());
+        }
+        self.input.waker.register(cx.waker());
+        if ready() {
+            Poll::Ready(())
+        } else {
+            crate::task::wait::note(crate::task::wait::WaitPoint::device("vconsole input"));
+            Poll::Pending
+        }
+    }
+}
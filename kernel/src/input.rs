@@ -0,0 +1,100 @@
+//! The input subsystem collects events from input devices into a single queue.
+//!
+//! Device drivers convert their device specific reports into [InputEvent]'s and push them using
+//! [push_event], consumers read them using an [InputEventStream].
+//! Pointer events are relative and are not bound to any particular output.
+
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+pub mod ps2_mouse;
+
+pub(crate) static EVENT_QUEUE_LEN: crate::config::Tunable<usize> = crate::config::Tunable::new(
+    "input.queue_len",
+    "Number of input events which may be queued",
+    if cfg!(feature = "low-mem") { 64 } else { 256 },
+);
+
+static EVENT_QUEUE: OnceCell<ArrayQueue<InputEvent>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+/// Number of events discarded because the queue was full or not initialized.
+static DROPPED: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    /// Button 4, normally "back"
+    Side,
+    /// Button 5, normally "forward"
+    Extra,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum InputEvent {
+    /// Relative pointer motion. Positive `dy` is toward the bottom of the screen.
+    PointerMotion { dx: i16, dy: i16 },
+    /// Scroll wheel motion. Positive values scroll down.
+    Scroll { dz: i8 },
+    Button { button: MouseButton, pressed: bool },
+}
+
+/// Queues an event for consumers.
+///
+/// Must not block or allocate, this may be called from an interrupt handler.
+pub(crate) fn push_event(event: InputEvent) {
+    if let Ok(queue) = EVENT_QUEUE.try_get() {
+        if queue.push(event).is_ok() {
+            WAKER.wake();
+            return;
+        }
+    }
+    DROPPED.fetch_add(1, atomic::Ordering::Relaxed);
+}
+
+/// Returns the number of events which have been dropped.
+pub fn dropped_events() -> usize {
+    DROPPED.load(atomic::Ordering::Relaxed)
+}
+
+/// Stream of input events from all devices.
+///
+/// Events which are raised before the stream is constructed are discarded.
+pub struct InputEventStream {
+    _private: (),
+}
+
+impl InputEventStream {
+    pub fn new() -> Self {
+        EVENT_QUEUE
+            .try_init_once(|| ArrayQueue::new(EVENT_QUEUE_LEN.get()))
+            .expect("InputEventStream::new() should only be called once");
+        Self { _private: () }
+    }
+}
+
+impl Stream for InputEventStream {
+    type Item = InputEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let queue = EVENT_QUEUE.try_get().expect("ERROR: EVENT_QUEUE not initialized");
+
+        if let Some(event) = queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+
+        WAKER.register(&cx.waker());
+        match queue.pop() {
+            Some(event) => {
+                WAKER.take();
+                Poll::Ready(Some(event))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,263 @@
+//! Driver for a PS/2 mouse attached to the auxiliary port of an i8042 controller.
+//!
+//! During initialization the mouse is probed for the IntelliMouse extensions, if these are
+//! supported the scroll wheel, and possibly buttons 4 and 5, are reported.
+
+use super::{push_event, InputEvent, MouseButton};
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+const CMD_PORT: u16 = 0x64;
+const ISA_IRQ: u8 = 12;
+
+/// Number of polls before a controller operation is considered to have failed.
+const TIMEOUT: usize = 100_000;
+
+const STATUS_OUTPUT_FULL: u8 = 1;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const CTL_ENABLE_AUX: u8 = 0xA8;
+const CTL_READ_CONFIG: u8 = 0x20;
+const CTL_WRITE_CONFIG: u8 = 0x60;
+const CTL_WRITE_AUX: u8 = 0xD4;
+
+const CONFIG_AUX_IRQ: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_DISABLE: u8 = 1 << 5;
+
+const MOUSE_ACK: u8 = 0xFA;
+const MOUSE_SET_DEFAULTS: u8 = 0xF6;
+const MOUSE_SET_SAMPLE_RATE: u8 = 0xF3;
+const MOUSE_GET_ID: u8 = 0xF2;
+const MOUSE_ENABLE_REPORTING: u8 = 0xF4;
+
+static PACKET: spin::Mutex<PacketState> = spin::Mutex::new(PacketState::new(Protocol::Standard));
+
+/// Protocol negotiated with the mouse, determined by its device ID.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Protocol {
+    /// ID 0, 3 byte packets.
+    Standard,
+    /// ID 3, 4 byte packets with a scroll wheel.
+    Wheel,
+    /// ID 4, 4 byte packets with a scroll wheel and buttons 4 and 5.
+    FiveButton,
+}
+
+impl Protocol {
+    fn packet_len(&self) -> usize {
+        match self {
+            Protocol::Standard => 3,
+            Protocol::Wheel | Protocol::FiveButton => 4,
+        }
+    }
+}
+
+struct PacketState {
+    protocol: Protocol,
+    buff: [u8; 4],
+    len: usize,
+    /// Buttons reported by the previous packet, uses the same layout as `buff[0]` with buttons 4 and 5 in bits 3 and 4.
+    buttons: u8,
+}
+
+impl PacketState {
+    const fn new(protocol: Protocol) -> Self {
+        Self { protocol, buff: [0; 4], len: 0, buttons: 0 }
+    }
+
+    fn push(&mut self, b: u8) {
+        // Bit 3 is always set in the first byte, if it isn't we have lost sync and must drop bytes
+        // until the start of a packet is found.
+        if self.len == 0 && b & (1 << 3) == 0 {
+            return;
+        }
+        self.buff[self.len] = b;
+        self.len += 1;
+        if self.len == self.protocol.packet_len() {
+            self.len = 0;
+            self.decode();
+        }
+    }
+
+    fn decode(&mut self) {
+        let [flags, x, y, ext] = self.buff;
+
+        // Overflowed packets don't contain meaningful motion.
+        if flags & 0xC0 == 0 {
+            let dx = x as i16 - (((flags as i16) << 4) & 0x100);
+            let dy = y as i16 - (((flags as i16) << 3) & 0x100);
+            if dx != 0 || dy != 0 {
+                push_event(InputEvent::PointerMotion { dx, dy: -dy });
+            }
+        }
+
+        let mut buttons = flags & 0b111;
+        match self.protocol {
+            Protocol::Standard => {}
+            Protocol::Wheel => self.scroll(ext as i8),
+            Protocol::FiveButton => {
+                // sign extend the 4-bit value
+                self.scroll(((ext << 4) as i8) >> 4);
+                buttons |= (ext >> 1) & 0b11000;
+            }
+        }
+
+        let changed = buttons ^ self.buttons;
+        self.buttons = buttons;
+        for (bit, button) in [MouseButton::Left, MouseButton::Right, MouseButton::Middle, MouseButton::Side, MouseButton::Extra].into_iter().enumerate() {
+            if changed & (1 << bit) != 0 {
+                push_event(InputEvent::Button { button, pressed: buttons & (1 << bit) != 0 });
+            }
+        }
+    }
+
+    fn scroll(&self, dz: i8) {
+        if dz != 0 {
+            push_event(InputEvent::Scroll { dz });
+        }
+    }
+}
+
+fn wait_write() -> Result<(), ()> {
+    let mut status: Port<u8> = Port::new(CMD_PORT);
+    for _ in 0..TIMEOUT {
+        // SAFETY: Reading the status register has no side effects
+        if unsafe { status.read() } & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(())
+}
+
+fn read_data() -> Result<u8, ()> {
+    let mut status: Port<u8> = Port::new(CMD_PORT);
+    for _ in 0..TIMEOUT {
+        // SAFETY: Reading the status register has no side effects
+        if unsafe { status.read() } & STATUS_OUTPUT_FULL != 0 {
+            // SAFETY: The output buffer is full
+            return Ok(unsafe { Port::new(DATA_PORT).read() });
+        }
+        core::hint::spin_loop();
+    }
+    Err(())
+}
+
+fn controller_cmd(cmd: u8) -> Result<(), ()> {
+    wait_write()?;
+    // SAFETY: The caller must ensure the command is valid.
+    unsafe { Port::new(CMD_PORT).write(cmd) };
+    Ok(())
+}
+
+fn write_data(data: u8) -> Result<(), ()> {
+    wait_write()?;
+    // SAFETY: The controller is ready to accept data.
+    unsafe { Port::new(DATA_PORT).write(data) };
+    Ok(())
+}
+
+/// Sends `cmd` to the mouse and waits for it to be acknowledged.
+fn mouse_cmd(cmd: u8) -> Result<(), ()> {
+    controller_cmd(CTL_WRITE_AUX)?;
+    write_data(cmd)?;
+    match read_data()? {
+        MOUSE_ACK => Ok(()),
+        _ => Err(()),
+    }
+}
+
+/// Sets the sample rates in `rates` and then returns the device ID.
+///
+/// Writing particular sample rate sequences are used to enable protocol extensions.
+fn knock(rates: &[u8]) -> Result<u8, ()> {
+    for r in rates {
+        mouse_cmd(MOUSE_SET_SAMPLE_RATE)?;
+        mouse_cmd(*r)?;
+    }
+    mouse_cmd(MOUSE_GET_ID)?;
+    read_data()
+}
+
+fn negotiate() -> Result<Protocol, ()> {
+    if knock(&[200, 100, 80])? != 3 {
+        return Ok(Protocol::Standard);
+    }
+    if knock(&[200, 200, 80])? == 4 {
+        Ok(Protocol::FiveButton)
+    } else {
+        Ok(Protocol::Wheel)
+    }
+}
+
+fn init_device() -> Result<Protocol, ()> {
+    controller_cmd(CTL_ENABLE_AUX)?;
+
+    controller_cmd(CTL_READ_CONFIG)?;
+    let config = read_data()?;
+    controller_cmd(CTL_WRITE_CONFIG)?;
+    write_data((config | CONFIG_AUX_IRQ) & !CONFIG_AUX_CLOCK_DISABLE)?;
+
+    mouse_cmd(MOUSE_SET_DEFAULTS)?;
+    let protocol = negotiate()?;
+    // restore the normal sample rate after negotiation
+    mouse_cmd(MOUSE_SET_SAMPLE_RATE)?;
+    mouse_cmd(100)?;
+    mouse_cmd(MOUSE_ENABLE_REPORTING)?;
+    Ok(protocol)
+}
+
+fn int_handler() {
+    // SAFETY: The mouse has raised an interrupt so data is present.
+    let b = unsafe { Port::<u8>::new(DATA_PORT).read() };
+    PACKET.lock().push(b);
+
+    // SAFETY: This is an interrupt handler
+    unsafe { crate::interrupts::apic::apic_eoi() }
+}
+
+/// Probes for a PS/2 mouse and configures it.
+///
+/// If no mouse is present this fn logs the failure and returns.
+pub fn init() {
+    // The IRQ must not be raised during initialization because the response bytes are read by polling.
+    let protocol = match x86_64::instructions::interrupts::without_interrupts(init_device) {
+        Ok(p) => p,
+        Err(()) => {
+            log::info!("No PS/2 mouse found");
+            return;
+        }
+    };
+    *PACKET.lock() = PacketState::new(protocol);
+
+    let t = crate::interrupts::reserve_irq(0, 1).expect("Failed to allocate IRQ for PS/2 mouse");
+    let irq = crate::interrupts::InterruptIndex::Generic(t);
+    let (mut gsi, ov) = irq.get_isa(ISA_IRQ);
+    gsi.trigger_mode = ov
+        .as_ref()
+        .map_or(None, |s| s.trigger_mode)
+        .unwrap_or(crate::interrupts::apic::ioapic::TriggerMode::EdgeTriggered);
+    gsi.polarity = ov
+        .as_ref()
+        .map_or(None, |s| s.polarity)
+        .unwrap_or(crate::interrupts::apic::ioapic::PinPolarity::AssertHigh);
+    gsi.mask = false;
+
+    // SAFETY: int_handler handles interrupts for the mouse
+    unsafe {
+        irq.set(crate::interrupts::vector_tables::InterruptHandleContainer::SpecialHandle(int_handler));
+        gsi.set().expect("Failed to set GSI");
+    }
+    crate::interrupts::affinity::register(&[t], "PS/2 mouse", alloc::boxed::Box::new(spin::Mutex::new(gsi)), 0);
+
+    // A packet may have arrived before the IRQ was configured, the controller will not raise
+    // another interrupt until it is read.
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // SAFETY: Reading the status register has no side effects
+        if unsafe { Port::<u8>::new(CMD_PORT).read() } & STATUS_OUTPUT_FULL != 0 {
+            // SAFETY: The byte is discarded, PACKET will resync on the next packet
+            let _ = unsafe { Port::<u8>::new(DATA_PORT).read() };
+        }
+    });
+    log::info!("PS/2 mouse initialized using {protocol:?} protocol");
+}
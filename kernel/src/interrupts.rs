@@ -1,12 +1,15 @@
 use crate::gdt;
 use crate::interrupts::apic::LOCAL_APIC;
 use crate::println;
-use log::{error, warn};
+use log::error;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
+pub mod affinity;
 pub mod apic;
 pub mod vector_tables;
 pub mod buff;
+pub mod spurious;
+pub mod stats;
 
 pub const PIC_0_OFFSET: u8 = 32;
 pub const PIC_1_OFFSET: u8 = PIC_0_OFFSET + 8;
@@ -35,6 +38,16 @@ pub fn init_exceptions() {
             .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
     }
 
+    unsafe {
+        idt.non_maskable_interrupt
+            .set_handler_fn(except_nmi)
+            .set_stack_index(gdt::NMI_IST_INDEX);
+        // The handler returns when a probe raised the machine check, see `crate::mem::probe`
+        idt.machine_check
+            .set_handler_addr(x86_64::VirtAddr::new(except_machine_check as usize as u64))
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+    }
+
     idt[InterruptIndex::Keyboard.as_u8()].set_handler_fn(keyboard_interrupt_handler);
     idt.general_protection_fault.set_handler_fn(except_general_protection);
     idt.segment_not_present.set_handler_fn(except_seg_not_present);
@@ -46,6 +59,40 @@ pub fn init_exceptions() {
     // SAFETY: This is the only write to `IDT` and it occurs before multiprocessing is initialized
     unsafe { core::ptr::addr_of_mut!(IDT).write(idt); }
     unsafe { IDT.load() }
+    enable_machine_check();
+}
+
+/// Enables machine check exceptions on this CPU, without this a machine check shuts down the CPU.
+fn enable_machine_check() {
+    if raw_cpuid::CpuId::new().get_feature_info().is_some_and(|f| f.has_mce() && f.has_mca()) {
+        use x86_64::registers::control::{Cr4, Cr4Flags};
+        // SAFETY: Machine checks are handled by `except_machine_check`
+        unsafe { Cr4::update(|f| f.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION)) }
+    }
+}
+
+/// Writes directly to the early console, the exceptions using this may interrupt the serial driver.
+struct EarlyConsole;
+
+impl core::fmt::Write for EarlyConsole {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        crate::serial::early::write(format_args!("{s}"));
+        Ok(())
+    }
+}
+
+/// Dumps the interrupted context, the current task and this CPU's recent [crate::trace] events.
+fn dump_context(what: &str, sf: &InterruptStackFrame) {
+    use core::fmt::Write;
+    let mut con = EarlyConsole;
+    let _ = writeln!(con, "***{what} on CPU {}***", crate::mp::who_am_i());
+    let _ = writeln!(con, "{sf:#?}");
+    let _ = match crate::task::job::current_id() {
+        Some(id) => writeln!(con, "Task: {id}"),
+        None => writeln!(con, "Task: none"),
+    };
+    let _ = writeln!(con, "Recent events, TSC cycles ago:");
+    let _ = crate::trace::dump(&mut con);
 }
 
 extern "x86-interrupt" fn except_breakpoint(stack_frame: InterruptStackFrame) {
@@ -53,11 +100,76 @@ extern "x86-interrupt" fn except_breakpoint(stack_frame: InterruptStackFrame) {
 }
 
 extern "x86-interrupt" fn except_double(stack: InterruptStackFrame, _err: u64) -> ! {
-    println!("***DOUBLE FAULT***");
-    println!("{:#?}", stack);
+    crate::serial::early::takeover();
+    crate::sound::pc_speaker::fault_code(crate::sound::pc_speaker::FaultCode::DoubleFault);
+    dump_context("DOUBLE FAULT", &stack);
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}\n", stack);
 }
 
+/// NMIs are raised by [crate::mp::ipi::halt_others] to stop this CPU. Otherwise they are either a
+/// hardware error or raised externally e.g. by the hypervisor to inspect a hung system, the context
+/// is dumped and execution continues unless a hardware error is reported.
+extern "x86-interrupt" fn except_nmi(sf: InterruptStackFrame) {
+    if crate::mp::ipi::is_halting() {
+        x86_64::instructions::interrupts::disable();
+        crate::stop()
+    }
+    // SAFETY: Reading system control port B has no side effects
+    let ctl = unsafe { x86_64::instructions::port::PortReadOnly::<u8>::new(0x61).read() };
+    dump_context("NMI", &sf);
+    // Bit 7 reports a memory parity error, bit 6 an I/O channel check
+    if ctl & 0xc0 != 0 {
+        crate::serial::early::takeover();
+        panic!("NMI: Hardware error, system control port B: {ctl:#x}");
+    }
+}
+
+extern "x86-interrupt" fn except_machine_check(mut sf: InterruptStackFrame) {
+    use core::fmt::Write;
+    use x86_64::registers::model_specific::Msr;
+    const VALID: u64 = 1 << 63;
+    const MISC_VALID: u64 = 1 << 59;
+    const ADDR_VALID: u64 = 1 << 58;
+    const EIPV: u64 = 1 << 1;
+
+    // SAFETY: The machine check MSRs exist, machine checks are only enabled when MCA is supported
+    unsafe {
+        if Msr::new(0x17a).read() & EIPV != 0 && crate::mem::probe::fixup(&mut sf, crate::mem::probe::ProbeError::MachineCheck) {
+            let banks = Msr::new(0x179).read() as u32 & 0xff;
+            for i in 0..banks {
+                Msr::new(0x401 + 4 * i).write(0);
+            }
+            // Clears MCIP, another machine check while it is set shuts down the CPU
+            Msr::new(0x17a).write(0);
+            return;
+        }
+    }
+
+    crate::serial::early::takeover();
+    dump_context("MACHINE CHECK", &sf);
+    let mut con = EarlyConsole;
+    // SAFETY: The machine check MSRs exist, machine checks are only enabled when MCA is supported
+    unsafe {
+        let banks = Msr::new(0x179).read() as u32 & 0xff; // IA32_MCG_CAP
+        let _ = writeln!(con, "MCG_STATUS: {:#x}", Msr::new(0x17a).read());
+        for i in 0..banks {
+            let status = Msr::new(0x401 + 4 * i).read();
+            if status & VALID == 0 {
+                continue;
+            }
+            let _ = write!(con, "MC{i}_STATUS: {status:#x}");
+            if status & ADDR_VALID != 0 {
+                let _ = write!(con, " ADDR: {:#x}", Msr::new(0x402 + 4 * i).read());
+            }
+            if status & MISC_VALID != 0 {
+                let _ = write!(con, " MISC: {:#x}", Msr::new(0x403 + 4 * i).read());
+            }
+            let _ = writeln!(con);
+        }
+    }
+    panic!("EXCEPTION: MACHINE CHECK\n{sf:#?}\n");
+}
+
 extern "x86-interrupt" fn keyboard_interrupt_handler(_sf: InterruptStackFrame) {
     use x86_64::instructions::port::Port;
 
@@ -96,7 +208,7 @@ impl Drop for RecursiveLock {
 }
 
 
-extern "x86-interrupt" fn except_page(sf: InterruptStackFrame, e: PageFaultErrorCode) {
+extern "x86-interrupt" fn except_page(mut sf: InterruptStackFrame, e: PageFaultErrorCode) {
     use x86_64::registers::control::Cr2;
 
     let r_l = RecursiveLock::new();
@@ -124,6 +236,9 @@ extern "x86-interrupt" fn except_page(sf: InterruptStackFrame, e: PageFaultError
         }
     }
 
+    if crate::mem::probe::fixup(&mut sf, crate::mem::probe::ProbeError::PageFault) {
+        return;
+    }
 
     println!("*EXCEPTION: PAGE FAULT*\n");
 
@@ -149,7 +264,11 @@ unsafe extern "C" fn _page_fault_fixup_inner(fix: *mut crate::mem::virt_fixup::C
     unsafe { fix.read().fixup(); }
 }
 
-extern "x86-interrupt" fn except_general_protection(sf: InterruptStackFrame, e: u64) {
+extern "x86-interrupt" fn except_general_protection(mut sf: InterruptStackFrame, e: u64) {
+    // Raised when a probe accesses a non-canonical address
+    if crate::mem::probe::fixup(&mut sf, crate::mem::probe::ProbeError::GeneralProtection) {
+        return;
+    }
     println!("GENERAL PROTECTION FAULT");
     println!("error: {}", e);
     println!("{:#?}", sf);
@@ -169,9 +288,8 @@ extern "x86-interrupt" fn apic_error(_sf: InterruptStackFrame) {
     }
 }
 
-extern "x86-interrupt" fn spurious(sf: InterruptStackFrame) {
-    warn!("spurious Interrupt");
-    println!("{sf:#?}");
+extern "x86-interrupt" fn spurious(_sf: InterruptStackFrame) {
+    spurious::apic_spurious();
 }
 
 extern "x86-interrupt" fn except_seg_not_present(sf: InterruptStackFrame, e: u64) {
@@ -324,6 +442,7 @@ pub unsafe fn alloc_irq(
 /// The caller must ensure that the IRQ will not be raised.
 /// If the IRQ is raised the may cause UB.
 pub(crate) unsafe fn free_irq(irq: InterruptIndex) -> Result<(), ()> {
+    affinity::unregister(irq.as_u8());
     vector_tables::IHR.free_irq(irq)
 }
 
@@ -340,4 +459,5 @@ pub(crate) fn reg_waker(irq: InterruptIndex, waker: &core::task::Waker) -> Resul
 
 pub fn load_idt() {
     unsafe { IDT.load(); }
+    enable_machine_check();
 }
\ No newline at end of file
@@ -0,0 +1,255 @@
+//! Interrupt affinity steering.
+//!
+//! Device interrupts which can be moved between CPUs are registered with [register] along with a
+//! [Retarget] which reprograms the device or IO-APIC. The MSI and MSI-X configuration in
+//! [crate::system::pci] registers every vector it allocates, other drivers must register their
+//! vectors themselves.
+//!
+//! Every [BALANCE_MS] the balancer moves each interrupt to the CPU which submitted the most work to
+//! the device during the last interval, drivers report submissions with [note_submit]. Interrupts
+//! without submissions are balanced using [super::stats], one interrupt is moved from the CPU
+//! receiving the most steered interrupts to the CPU receiving the fewest when it would reduce the
+//! difference. New interrupts are spread across the CPUs which are not isolated, see
+//! [crate::task::affinity].
+//!
+//! The target of each interrupt is exported at `/irq_affinity`. Writing `<vector> <cpu>` lines pins
+//! interrupts to a CPU, the balancer does not move pinned interrupts. Writing `<vector> auto`
+//! returns the interrupt to the balancer.
+
+use crate::config::Tunable;
+use crate::fs::report::ReportFile;
+use crate::fs::vfs::{DevID, MajorNum};
+use crate::fs::IoError;
+use crate::mp::CpuIndex;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+const FS_NAME: &str = "/irq_affinity";
+
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("irq_affinity").unwrap(););
+
+/// Interval between rebalancing interrupts.
+pub static BALANCE_MS: Tunable<u64> = Tunable::new("irq.balance_ms", "Milliseconds between rebalancing device interrupts, 0 disables balancing", 1000);
+
+/// Minimum number of submissions during an interval before an interrupt follows the submitting CPU.
+const MIN_SUBMITS: usize = 16;
+
+/// Reprograms the source of an interrupt.
+pub trait Retarget: Send + Sync {
+    /// Routes the interrupt to the CPU `cpu`. The interrupt may be raised on either CPU while this
+    /// is called, so handlers must not assume which CPU they run on.
+    fn retarget(&self, cpu: CpuIndex) -> Result<(), ()>;
+}
+
+struct Steered {
+    name: String,
+    vectors: Vec<u8>,
+    source: Box<dyn Retarget>,
+    cpu: AtomicU32,
+    pinned: AtomicBool,
+    /// Work submitted by each CPU since the last rebalance, see [note_submit].
+    submits: Box<[AtomicUsize]>,
+    /// Number of interrupts at the last rebalance, summed over `vectors`.
+    last_count: AtomicUsize,
+}
+
+impl Steered {
+    fn count(&self) -> usize {
+        self.vectors.iter().map(|v| super::stats::vector_counts(*v).values().sum::<usize>()).sum()
+    }
+
+    fn move_to(&self, cpu: CpuIndex) -> Result<(), IoError> {
+        self.source.retarget(cpu).map_err(|()| IoError::DeviceError)?;
+        self.cpu.store(cpu, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Steered interrupts by vector, each interrupt is present once for each of its vectors.
+static STEERED: spin::RwLock<BTreeMap<u8, Arc<Steered>>> = spin::RwLock::new(BTreeMap::new());
+
+/// Returns the CPUs which may receive device interrupts by default.
+fn allowed_cpus() -> Vec<CpuIndex> {
+    let set = crate::task::affinity::default_set();
+    super::stats::cpus().into_iter().filter(|c| set.contains(*c)).collect()
+}
+
+/// Returns the CPU which should receive a new interrupt, new interrupts are spread evenly between
+/// CPUs which are not isolated.
+pub fn initial_cpu() -> CpuIndex {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let cpus = allowed_cpus();
+    match cpus.len() {
+        0 => crate::who_am_i(),
+        n => cpus[NEXT.fetch_add(1, Ordering::Relaxed) % n],
+    }
+}
+
+/// Registers an interrupt using `vectors` which is currently targeted at `cpu`. `name` identifies
+/// the device in `/irq_affinity`.
+///
+/// All vectors are moved together. A vector may only be registered once, see [unregister].
+pub fn register(vectors: &[u8], name: impl Into<String>, source: Box<dyn Retarget>, cpu: CpuIndex) {
+    let steered = Arc::new(Steered {
+        name: name.into(),
+        vectors: vectors.to_vec(),
+        source,
+        cpu: AtomicU32::new(cpu),
+        pinned: AtomicBool::new(false),
+        submits: (0..crate::task::affinity::MAX_CPUS).map(|_| AtomicUsize::new(0)).collect(),
+        last_count: AtomicUsize::new(0),
+    });
+    steered.last_count.store(steered.count(), Ordering::Relaxed);
+    let mut l = STEERED.write();
+    for v in vectors {
+        if l.insert(*v, steered.clone()).is_some() {
+            log::warn!("IRQ affinity: Vector {v} was registered twice");
+        }
+    }
+}
+
+/// Stops steering the interrupt using `vector`. This is called when the vector is freed.
+pub(crate) fn unregister(vector: u8) {
+    let mut l = STEERED.write();
+    if let Some(s) = l.remove(&vector) {
+        for v in &s.vectors {
+            l.remove(v);
+        }
+    }
+}
+
+/// Records that the calling CPU submitted work which will complete with an interrupt on `irq`.
+pub fn note_submit(irq: super::InterruptIndex) {
+    if let Some(s) = STEERED.read().get(&irq.as_u8()) {
+        if let Some(n) = s.submits.get(crate::who_am_i() as usize) {
+            n.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Pins the interrupt using `vector` to `cpu`, or returns it to the balancer when `cpu` is `None`.
+pub fn set_affinity(vector: u8, cpu: Option<CpuIndex>) -> Result<(), IoError> {
+    let s = STEERED.read().get(&vector).cloned().ok_or(IoError::NotPresent)?;
+    match cpu {
+        Some(cpu) if !super::stats::cpus().contains(&cpu) => Err(IoError::InvalidData),
+        Some(cpu) => {
+            s.move_to(cpu)?;
+            s.pinned.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+        None => {
+            s.pinned.store(false, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+}
+
+/// Returns each steered interrupt once.
+fn unique() -> Vec<Arc<Steered>> {
+    let mut v: Vec<Arc<Steered>> = STEERED.read().values().cloned().collect();
+    v.sort_by_key(|s| Arc::as_ptr(s) as usize);
+    v.dedup_by(|a, b| Arc::ptr_eq(a, b));
+    v
+}
+
+/// Moves interrupts according to the policy described in the [module docs](self).
+pub fn rebalance() {
+    let cpus = allowed_cpus();
+    let mut load: BTreeMap<CpuIndex, usize> = cpus.iter().map(|c| (*c, 0)).collect();
+    // Interrupts which were not moved towards a submitter, with the number of interrupts since the last rebalance
+    let mut idle = Vec::new();
+
+    for s in unique() {
+        let count = s.count();
+        let delta = count.saturating_sub(s.last_count.swap(count, Ordering::Relaxed));
+        let submits: Vec<(CpuIndex, usize)> = s.submits.iter().enumerate().map(|(c, n)| (c as CpuIndex, n.swap(0, Ordering::Relaxed))).collect();
+        if s.pinned.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        let total: usize = submits.iter().map(|(_, n)| n).sum();
+        let (best, n) = submits.into_iter().max_by_key(|(_, n)| *n).unwrap_or((0, 0));
+        if total >= MIN_SUBMITS && n * 2 > total {
+            if best != s.cpu.load(Ordering::Relaxed) {
+                if let Err(e) = s.move_to(best) {
+                    log::warn!("IRQ affinity: Failed to move {} to CPU{best}: {e:?}", s.name);
+                }
+            }
+            continue;
+        }
+
+        let cpu = s.cpu.load(Ordering::Relaxed);
+        if let Some(l) = load.get_mut(&cpu) {
+            *l += delta;
+            idle.push((s, delta));
+        }
+    }
+
+    let (Some((&busy, &high)), Some((&quiet, &low))) = (load.iter().max_by_key(|(_, l)| **l), load.iter().min_by_key(|(_, l)| **l)) else {
+        return;
+    };
+    // Move the interrupt which brings the two CPUs closest to each other
+    let target = (high - low) / 2;
+    let candidate = idle
+        .into_iter()
+        .filter(|(s, delta)| s.cpu.load(Ordering::Relaxed) == busy && *delta > 0 && *delta < high - low)
+        .min_by_key(|(_, delta)| delta.abs_diff(target));
+    if let Some((s, _)) = candidate {
+        if let Err(e) = s.move_to(quiet) {
+            log::warn!("IRQ affinity: Failed to move {} to CPU{quiet}: {e:?}", s.name);
+        }
+    }
+}
+
+/// Formats the target of every steered interrupt, one interrupt per line.
+pub fn report() -> String {
+    let mut s = String::new();
+    let _ = writeln!(s, "{:>12} {:>4} {:>6}  DEVICE", "VECTORS", "CPU", "MODE");
+    for i in unique() {
+        let mut vectors = String::new();
+        for (n, v) in i.vectors.iter().enumerate() {
+            let _ = write!(vectors, "{}{v}", if n == 0 { "" } else { "," });
+        }
+        let mode = if i.pinned.load(Ordering::Relaxed) { "pinned" } else { "auto" };
+        let _ = writeln!(s, "{vectors:>12} {:>4} {mode:>6}  {}", i.cpu.load(Ordering::Relaxed), i.name);
+    }
+    s
+}
+
+/// Parses and applies a `<vector> <cpu>` or `<vector> auto` line.
+fn set_line(line: &str) -> Result<(), IoError> {
+    let (vector, cpu) = line.split_once(char::is_whitespace).ok_or(IoError::InvalidData)?;
+    let vector = vector.parse::<u8>().map_err(|_| IoError::InvalidData)?;
+    let cpu = match cpu.trim() {
+        "auto" => None,
+        c => Some(c.parse::<CpuIndex>().map_err(|_| IoError::InvalidData)?),
+    };
+    set_affinity(vector, cpu)
+}
+
+/// Mounts the affinity file and starts the balancer.
+///
+/// Reading the file returns the output of [report], writing `<vector> <cpu>` or `<vector> auto`
+/// lines sets the affinity of interrupts.
+pub fn init() {
+    let file = ReportFile::with_commands(DevID::new(*MAJOR, 0), report, set_line);
+    crate::task::util::block_on!(crate::fs::get_vfs().mount_dev(Box::new(file), FS_NAME))
+        .expect("Failed to mount IRQ affinity to VFS");
+    crate::task::run_task(Box::pin(balancer()));
+}
+
+async fn balancer() -> crate::task::TaskResult {
+    loop {
+        let ms = BALANCE_MS.get();
+        // Check again later in case balancing is re-enabled
+        crate::task::util::sleep(if ms == 0 { 1000 } else { ms }).await;
+        if ms != 0 {
+            rebalance();
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use crate::alloc_interface::MmioAlloc;
 use crate::interrupts::apic::apic_structures::apic_types::TimerMode;
 use crate::interrupts::apic::pub_apic::SysApic;
+use crate::interrupts::apic::x2apic::x2Apic;
 use crate::interrupts::apic::xapic::xApic;
 use crate::util::KernelStatic;
 use alloc::boxed::Box;
@@ -10,6 +11,7 @@ use apic_structures::registers::ApicError;
 pub mod apic_structures;
 pub(crate) mod ioapic;
 pub mod pub_apic;
+pub mod x2apic;
 pub mod xapic;
 
 /// Contains the cpus local APIC. Uses mutex in case of mischievous r/w
@@ -19,6 +21,11 @@ pub(crate) static LOCAL_APIC: KernelStatic<Box<dyn Apic, crate::mem::allocator::
 
 static TIMER_IRQ: crate::util::Worm<super::InterruptIndex> = crate::util::Worm::new();
 
+/// KVM paravirtual EOI word for this CPU, bit 0 is set by the host when the EOI for the current
+/// interrupt may be skipped.
+#[thread_local]
+static PV_EOI: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
+
 /// Trait for control over the Local Advanced Programmable Interrupt Controller
 ///
 /// #LVT Entry
@@ -78,6 +85,8 @@ pub enum IpiError {
     ///
     /// All non-Fixed modes must use vector 0 for forward compatibility.
     BadMode,
+    /// The vector for the IPI has not been allocated yet, see [crate::mp::ipi].
+    Unavailable,
 }
 
 pub enum IpiTarget {
@@ -127,6 +136,12 @@ const TARGET_PERIOD: u64 = (1000000000f64 * (1f64 / TARGET_FREQ as f64)) as u64;
 //#[thread_local]
 static mut CALI: Option<u64> = None;
 
+/// Initial count of the timer for a period of [TARGET_PERIOD], `0` until the timer is calibrated.
+static PERIOD_COUNT: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
+
+/// Nanoseconds between periodic timer interrupts.
+pub(crate) const TICK_PERIOD_NS: u64 = TARGET_PERIOD;
+
 /// Interrupt handler used for calibrating the local APIC timer.
 fn handle_timer_and_calibrate() {
     unsafe {
@@ -137,9 +152,87 @@ fn handle_timer_and_calibrate() {
 
 /// Default timer handler. Updates system time then exits.
 fn timer_handler() {
+    timer_tick();
+    unsafe { apic_eoi() };
+}
+
+/// Updates the system time and wakes expired timers, this is the work done by each timer interrupt.
+pub(crate) fn timer_tick() {
     crate::time::update_timer();
     crate::task::util::check_slp();
-    unsafe { apic_eoi() };
+    crate::task::timer_wheel::check_expired();
+}
+
+/// Programs the local APIC timer of the calling CPU to raise a single interrupt after `nanos`.
+///
+/// Returns `false` if the timer has not been calibrated, in which case it is not modified.
+pub(crate) fn set_timer_oneshot(nanos: u64) -> bool {
+    let period = PERIOD_COUNT.load(core::sync::atomic::Ordering::Relaxed);
+    if period == 0 {
+        return false;
+    }
+    let count = (nanos as u128 * period as u128 / TARGET_PERIOD as u128).clamp(1, u32::MAX as u128) as u32;
+    // SAFETY: The timer vector is already configured
+    unsafe { LOCAL_APIC.get().set_timer(TimerMode::OneShot, count) };
+    true
+}
+
+/// Restores the periodic timer of the calling CPU after [set_timer_oneshot].
+pub(crate) fn set_timer_periodic() {
+    let period = PERIOD_COUNT.load(core::sync::atomic::Ordering::Relaxed);
+    if period != 0 {
+        // SAFETY: The timer vector is already configured
+        unsafe { LOCAL_APIC.get().set_timer(TimerMode::Periodic, period) };
+    }
+}
+
+/// Calibrates the timer of `apic` against the system clock, see [Apic::begin_calibration].
+fn calibrate<A: Apic + ?Sized>(apic: &mut A, test_time: u32, vec: u8) {
+    unsafe {
+        super::vector_tables::alloc_irq_special(vec, handle_timer_and_calibrate)
+            .expect("Vector already occupied");
+
+        apic.init_timer(vec, false);
+        apic.set_timer(TimerMode::Periodic, test_time);
+    }
+
+    let initial_time;
+    let duration;
+
+    // compiler wont like this. Optimizations might cause bugs
+    // this needs to be done twice because the first clock is always very slow
+    loop {
+        if let Some(_) = unsafe { CALI } {
+            initial_time = crate::time::get_sys_time();
+            unsafe {
+                CALI = None;
+            }
+            break;
+        }
+        x86_64::instructions::hlt()
+    }
+
+    loop {
+        if let Some(new) = unsafe { CALI } {
+            duration = new - initial_time;
+            break;
+        }
+        x86_64::instructions::hlt()
+    }
+
+    let ratio = TARGET_PERIOD as f32 / duration as f32;
+    let out = ratio * test_time as f32; // Uses fp for precision. shouldn't have to big of an effect on performance
+
+    let (time, divide) = apic_structures::apic_types::TimerDivisionMode::best_try_divide(out as u64);
+    let _ = apic.set_division_mode(divide.to_divide_value() as u32);
+
+    // SAFETY: init_timer(_,true) is safe to use and makes th following fn's to use
+    unsafe {
+        apic.init_timer(vec, true);
+        apic.set_timer(TimerMode::Periodic, time);
+        // disable all the stuff this enabled
+        super::vector_tables::IHR.unset(vec);
+    }
 }
 
 /// Calibrates local APIC and sets interrupt handler.
@@ -151,6 +244,9 @@ pub fn cal_and_run(time: u32) {
     }
 
     LOCAL_APIC.get().begin_calibration(time, TIMER_IRQ.read().as_u8());
+    if let Ok(count) = crate::time::Timer::get_initial_clock(&**LOCAL_APIC.get()) {
+        PERIOD_COUNT.store(count as u32, core::sync::atomic::Ordering::Relaxed);
+    }
 
     unsafe {
         super::vector_tables::alloc_irq_special(TIMER_IRQ.as_u8(), timer_handler).expect("???");
@@ -179,15 +275,11 @@ pub fn load_apic() {
     use x86_msr::Msr;
     let t = unsafe { x86_msr::architecture::ApicBase::read() };
     let addr = x86_64::PhysAddr::new(t.get_apic_base_addr());
-    let apic;
+    let apic: Box<dyn Apic, crate::mem::allocator::GenericAlloc>;
     if (raw_cpuid::cpuid!(1).ecx >> 21) & 1 > 0 {
-        // todo implement x2apic
-        let alloc = unsafe { MmioAlloc::new_from_phys_addr(addr) };
-        let mut sec = alloc
-            .allocate(core::alloc::Layout::new::<xApic>())
-            .expect("not enough memory")
-            .cast::<xApic>();
-        apic = unsafe { Box::from_raw_in(sec.as_mut(), alloc.into()) };
+        // SAFETY: CPUID advertises x2APIC support
+        let x2 = unsafe { x2Apic::enable() };
+        apic = Box::new_in(x2, crate::mem::allocator::GenericAlloc::Global(alloc::alloc::Global));
     } else {
         let alloc = unsafe { MmioAlloc::new_from_phys_addr(addr) };
         let mut sec = alloc
@@ -198,6 +290,20 @@ pub fn load_apic() {
     }
 
     LOCAL_APIC.init(apic);
+    enable_pv_eoi();
+}
+
+/// Enables KVM paravirtual EOI for this CPU when it is available.
+///
+/// This allows most interrupts to be completed without the VM-exit caused by writing the EOI register.
+pub(crate) fn enable_pv_eoi() {
+    if crate::system::hypervisor::kvm_features() & crate::system::hypervisor::kvm_feature::PV_EOI == 0 {
+        return;
+    }
+    let Some(addr) = crate::mem::mem_map::translate(&PV_EOI as *const _ as usize) else { return };
+    use x86_msr::Msr;
+    // SAFETY: The MSR is available and PV_EOI is never freed.
+    unsafe { x86_msr::kvm::PvEoiEn::write((addr | 1).into()) }
 }
 
 /// Declares End Of Interrupt on apic devices, without potentially causing a deadlock.
@@ -207,6 +313,11 @@ pub fn load_apic() {
 /// This must not be called outside of an interrupt handler
 #[inline]
 pub(crate) unsafe fn apic_eoi() {
+    // The host only modifies the flag while this CPU is not running
+    if PV_EOI.load(core::sync::atomic::Ordering::Relaxed) & 1 != 0 {
+        PV_EOI.store(0, core::sync::atomic::Ordering::Relaxed);
+        return;
+    }
     LOCAL_APIC.force_get_mut().declare_eoi()
 }
 
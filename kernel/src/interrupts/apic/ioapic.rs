@@ -129,6 +129,29 @@ impl IoApic {
         self.write(((index * 2) + 0x10) as u32, entry.seg[0]);
     }
 
+    /// Masks all unmasked entries targeting `vector` and returns the number of entries masked.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that interrupts on `vector` are not required.
+    pub(crate) unsafe fn mask_vector(&mut self, vector: u8) -> usize {
+        let mut count = 0;
+        for i in 0..self.size {
+            let mut e = self.get_entry(i);
+            if e.vector() == vector && !e.mask() {
+                e.set_mask(true);
+                self.set_entry(i, e);
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Returns the number of redirection entries.
+    pub(crate) fn len(&self) -> u8 {
+        self.size
+    }
+
     pub(crate) fn is_gsi(&self, gsi: u8) -> bool {
         gsi > self.gsi_base && gsi < self.gsi_base + self.size
     }
@@ -336,6 +359,18 @@ impl GlobalSystemInterrupt {
     }
 }
 
+/// Moves the GSI to another CPU using physical destination mode. The GSI must have been set with
+/// [GlobalSystemInterrupt::set] before it is registered.
+impl crate::interrupts::affinity::Retarget for spin::Mutex<GlobalSystemInterrupt> {
+    fn retarget(&self, cpu: crate::mp::CpuIndex) -> Result<(), ()> {
+        let id = u8::try_from(cpu).ok().filter(|id| *id < 16).ok_or(())?;
+        let mut gsi = self.lock();
+        gsi.target = Target::Physical(PhysicalTarget::new(id));
+        // SAFETY: Only the target is modified, the handler was configured when the GSI was first set.
+        unsafe { gsi.set() }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PhysicalTarget {
     id: u8, // only 4 bits usable
@@ -0,0 +1,202 @@
+use super::apic_structures::{apic_types::*, registers::*};
+use super::{Apic, InterruptType, IpiTarget};
+use super::xapic::InterruptCommandRegisterLow;
+use crate::time::{Duration, Timer, TimerError, TimerResult};
+use x86_64::registers::model_specific::Msr;
+
+// Registers are located at `0x800 + (xAPIC offset >> 4)`, see ISDM v3 11.12.1.2
+const ID: u32 = 0x802;
+const EOI: u32 = 0x80b;
+const SPURIOUS_VECTOR: u32 = 0x80f;
+const ERROR_STATUS: u32 = 0x828;
+const INTERRUPT_COMMAND: u32 = 0x830;
+const TIMER_VECTOR: u32 = 0x832;
+const ERROR_VECTOR: u32 = 0x837;
+const INITIAL_COUNT: u32 = 0x838;
+const CURRENT_COUNT: u32 = 0x839;
+const DIVIDE_CONFIGURATION: u32 = 0x83e;
+
+/// Reads the 32bit register `reg`, all registers used here are 32bits except the ICR.
+fn read(reg: u32) -> u32 {
+    // SAFETY: The register exists while the local APIC is in x2APIC mode
+    unsafe { Msr::new(reg).read() as u32 }
+}
+
+/// # Safety
+///
+/// See [Msr::write], the caller must ensure that writing `value` to `reg` does not cause UB.
+unsafe fn write(reg: u32, value: u32) {
+    unsafe { Msr::new(reg).write(value as u64) }
+}
+
+/// An LVT entry read from an MSR, changes must be written back.
+struct Lvt(u32);
+
+impl LocalVectorEntry for Lvt {
+    fn get_reg(&self) -> &u32 {
+        &self.0
+    }
+
+    fn get_reg_mut(&mut self) -> &mut u32 {
+        &mut self.0
+    }
+}
+
+#[allow(non_camel_case_types)]
+/// The cpu's local APIC operating in x2APIC mode.
+///
+/// In x2APIC mode registers are accessed using MSRs instead of MMIO and APIC IDs are 32bits wide,
+/// so CPUs with IDs greater than 255 can be targeted by IPIs.
+/// I/O APIC redirection entries still use 8bit destinations.
+pub struct x2Apic {
+    _private: (),
+}
+
+impl x2Apic {
+    /// Switches the calling CPU's local APIC into x2APIC mode.
+    ///
+    /// # Safety
+    ///
+    /// The CPU must support x2APIC, once enabled the xAPIC MMIO registers are no longer accessible.
+    pub(super) unsafe fn enable() -> Self {
+        use x86_msr::architecture::{ApicBase, ApicBaseData};
+        use x86_msr::Msr as _;
+        // SAFETY: The caller guarantees that x2APIC is supported
+        unsafe {
+            let mut base = ApicBase::read();
+            base.insert(ApicBaseData::APIC_GLOBAL_ENABLE | ApicBaseData::X2APIC_ENABLE_MODE);
+            ApicBase::write(base);
+        }
+        Self { _private: () }
+    }
+}
+
+impl Apic for x2Apic {
+    unsafe fn set_enable(&mut self, enable: bool) {
+        let mut sv = SpuriousVector::from_bits_retain(read(SPURIOUS_VECTOR));
+        sv.set(SpuriousVector::APIC_ENABLE, enable);
+        unsafe { write(SPURIOUS_VECTOR, sv.bits()) }
+    }
+
+    unsafe fn init_err(&mut self, vector: u8, mask: bool) {
+        let mut lvt = Lvt(read(ERROR_VECTOR));
+        unsafe {
+            write(ERROR_STATUS, 0);
+            lvt.set_vector(vector, InterruptDeliveryMode::Fixed);
+            lvt.set_mask(mask);
+            write(ERROR_VECTOR, lvt.0);
+        }
+    }
+
+    unsafe fn init_timer(&mut self, vector: u8, mask: bool) {
+        let mut lvt = Lvt(read(TIMER_VECTOR));
+        unsafe {
+            lvt.set_vector(vector, InterruptDeliveryMode::Fixed);
+            lvt.set_mask(mask);
+            write(TIMER_VECTOR, lvt.0);
+            write(DIVIDE_CONFIGURATION, TimerDivisionMode::Divide1 as u32);
+        }
+    }
+
+    unsafe fn set_timer(&mut self, mode: TimerMode, time: u32) {
+        let lvt = read(TIMER_VECTOR) & !(3 << 17);
+        let mode: u32 = mode.into();
+        unsafe {
+            write(TIMER_VECTOR, lvt | mode);
+            write(INITIAL_COUNT, time);
+        }
+    }
+
+    fn declare_eoi(&mut self) {
+        // SAFETY: Writing 0 to the EOI register is always valid
+        unsafe { write(EOI, 0) }
+    }
+
+    /// The error status register must be written before it is read, so this only returns errors
+    /// which occurred since the last call.
+    fn get_err(&self) -> ApicError {
+        // SAFETY: Writing the error status register only updates it
+        unsafe { write(ERROR_STATUS, 0) };
+        ApicError::from_bits_retain(read(ERROR_STATUS))
+    }
+
+    fn begin_calibration(&mut self, test_time: u32, vec: u8) {
+        super::calibrate(self, test_time, vec)
+    }
+
+    fn get_id(&self) -> u32 {
+        read(ID)
+    }
+
+    unsafe fn send_ipi(&mut self, target: IpiTarget, int_type: InterruptType, vector: u8) -> Result<(), super::IpiError> {
+        let (dst, short) = match target {
+            IpiTarget::Other(v) => (v, super::DestinationShorthand::NoShorthand),
+            IpiTarget::ThisCpu => (0, super::DestinationShorthand::ThisCpu),
+            IpiTarget::All => (0, super::DestinationShorthand::All),
+            IpiTarget::AllNotThisCpu => (0, super::DestinationShorthand::AllNotSelf),
+        };
+
+        if ((int_type != InterruptType::Fixed) && (int_type != InterruptType::SIPI)) && vector != 0 {
+            return Err(super::IpiError::BadMode)
+        }
+
+        let mut icrl = InterruptCommandRegisterLow::new();
+        icrl.set_vector(vector);
+        icrl.set_delivery_mode(int_type);
+        icrl.set_polarity(true);
+        icrl.set_dest_shorthand(short);
+        let icrl: u32 = icrl.into();
+
+        // The ICR is a single 64bit register in x2APIC mode, it has no delivery status
+        unsafe { Msr::new(INTERRUPT_COMMAND).write((dst as u64) << 32 | icrl as u64) };
+        Ok(())
+    }
+
+    /// x2APIC does not report the delivery status, IPIs are sent when the ICR is written.
+    fn block_ipi_delivered(&self, _timeout: Duration) -> bool {
+        true
+    }
+}
+
+impl Timer for x2Apic {
+    fn get_division_mode(&self) -> u32 {
+        TimerDivisionMode::from(read(DIVIDE_CONFIGURATION) & 0xb).to_divide_value().into()
+    }
+
+    /// Supported division modes are `1,2,4,8,16,32,64,128`
+    fn set_division_mode(&mut self, div: u32) -> TimerResult {
+        if div > 128 {
+            return Err(TimerError::DivisionModeUnsupported);
+        }
+        let mode = TimerDivisionMode::from_divide_value(div as u8).ok_or(TimerError::DivisionModeUnsupported)?;
+        // SAFETY: The divide configuration only changes the timer's rate
+        unsafe { write(DIVIDE_CONFIGURATION, mode as u32) };
+        Ok(())
+    }
+
+    /// Setting the clock on the local APIC starts the clock and setting it to 0 will stop it.
+    fn set_clock_count(&mut self, count: u64, mode: crate::time::TimerMode) -> TimerResult {
+        if count > u32::MAX as u64 {
+            return Err(TimerError::CountTooHigh);
+        }
+        let mode: TimerMode = mode.try_into()?;
+        // SAFETY: The timer vector is configured by `init_timer`
+        unsafe { self.set_timer(mode, count as u32) };
+        Ok(())
+    }
+
+    fn get_initial_clock(&self) -> Result<u64, TimerError> {
+        Ok(read(INITIAL_COUNT) as u64)
+    }
+}
+
+impl core::fmt::Debug for x2Apic {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("x2Apic")
+            .field("id", &self.get_id())
+            .field("timer_vector", &Lvt(read(TIMER_VECTOR)).get_vector())
+            .field("initial_timer_count", &read(INITIAL_COUNT))
+            .field("current_timer_count", &read(CURRENT_COUNT))
+            .finish()
+    }
+}
@@ -112,6 +112,26 @@ impl<T: Copy> ChonkyBuff<T> {
 
     pub fn pop(&self) -> Option<T> {
         let mut l = self.inner.lock();
+        self.pop_locked(&mut l)
+    }
+
+    /// Pops up to `buff.len()` elements into `buff`, returns the number of elements popped.
+    ///
+    /// This may span multiple chunks and only acquires the lock once.
+    pub fn pop_into(&self, buff: &mut [T]) -> usize {
+        let mut l = self.inner.lock();
+        let mut count = 0;
+        for i in buff.iter_mut() {
+            match self.pop_locked(&mut l) {
+                Some(t) => *i = t,
+                None => break,
+            }
+            count += 1;
+        }
+        count
+    }
+
+    fn pop_locked(&self, l: &mut ChonkyBuffInner<T>) -> Option<T> {
         // fetch T
         let ch = l.chonk.front();
         if ch.is_none() {
@@ -194,6 +214,7 @@ impl<T> core::future::Future for Push<'_,T> {
             Poll::Ready(())
         } else {
             self.buff.waker.register(&cx.waker());
+            crate::task::wait::note(crate::task::wait::WaitPoint::channel("interrupt buffer"));
             Poll::Pending
         }
     }
@@ -0,0 +1,152 @@
+//! Detection and accounting of spurious and unhandled interrupts.
+//!
+//! The 8259 PICs are disabled, but may still raise spurious interrupts on IRQ 7 and 15. These are
+//! detected by checking the PIC's in-service register and are counted without sending an EOI.
+//! Spurious interrupts from the local APIC are delivered to vector 255 and are counted by [apic_spurious].
+//!
+//! Vectors which fire without a registered handler are counted, if a vector exceeds
+//! [UNHANDLED_THRESHOLD] within [UNHANDLED_WINDOW_NS] it is masked at the IO-APIC to prevent a
+//! misbehaving device from live-locking the CPU.
+
+use x86_64::instructions::port::Port;
+
+const PIC_MASTER_CMD: u16 = 0x20;
+const PIC_SLAVE_CMD: u16 = 0xA0;
+/// OCW3 command to read the in-service register.
+const PIC_READ_ISR: u8 = 0x0B;
+const PIC_EOI: u8 = 0x20;
+const PIC_SPURIOUS_MASTER: u8 = super::PIC_0_OFFSET + 7;
+const PIC_SPURIOUS_SLAVE: u8 = super::PIC_1_OFFSET + 7;
+
+/// Number of unhandled interrupts allowed on a vector within [UNHANDLED_WINDOW_NS] before it is masked.
+pub static UNHANDLED_THRESHOLD: crate::config::Tunable<usize> = crate::config::Tunable::new(
+    "irq.unhandled_threshold",
+    "Number of unhandled interrupts per second before a vector is masked",
+    1000,
+);
+pub const UNHANDLED_WINDOW_NS: u64 = 1_000_000_000;
+
+/// Only one message is logged for each period, further messages are dropped.
+const LOG_INTERVAL_NS: u64 = 1_000_000_000;
+
+static APIC_SPURIOUS: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+static PIC_SPURIOUS: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+static LAST_LOG: atomic::Atomic<u64> = atomic::Atomic::new(0);
+static UNHANDLED: [UnhandledVector; 256] = [const { UnhandledVector::new() }; 256];
+
+struct UnhandledVector {
+    total: core::sync::atomic::AtomicUsize,
+    window_start: atomic::Atomic<u64>,
+    window_count: core::sync::atomic::AtomicUsize,
+    masked: atomic::Atomic<bool>,
+}
+
+impl UnhandledVector {
+    const fn new() -> Self {
+        Self {
+            total: core::sync::atomic::AtomicUsize::new(0),
+            window_start: atomic::Atomic::new(0),
+            window_count: core::sync::atomic::AtomicUsize::new(0),
+            masked: atomic::Atomic::new(false),
+        }
+    }
+}
+
+/// Returns whether a message should be logged now.
+fn rate_limit() -> bool {
+    let now = crate::time::get_sys_time();
+    let last = LAST_LOG.load(atomic::Ordering::Relaxed);
+    now.saturating_sub(last) >= LOG_INTERVAL_NS
+        && LAST_LOG.compare_exchange(last, now, atomic::Ordering::Relaxed, atomic::Ordering::Relaxed).is_ok()
+}
+
+fn pic_isr(cmd: u16) -> u8 {
+    let mut port = Port::<u8>::new(cmd);
+    // SAFETY: Reading the ISR has no side effects, the next read from the command port will return the ISR.
+    unsafe {
+        port.write(PIC_READ_ISR);
+        port.read()
+    }
+}
+
+/// Returns the number of spurious interrupts raised by the local APIC.
+pub fn apic_spurious_count() -> usize {
+    APIC_SPURIOUS.load(atomic::Ordering::Relaxed)
+}
+
+/// Returns the number of spurious interrupts raised by the PICs.
+pub fn pic_spurious_count() -> usize {
+    PIC_SPURIOUS.load(atomic::Ordering::Relaxed)
+}
+
+/// Returns the number of times `vector` was raised without a handler and whether it has been masked.
+pub fn unhandled_count(vector: u8) -> (usize, bool) {
+    let v = &UNHANDLED[vector as usize];
+    (v.total.load(atomic::Ordering::Relaxed), v.masked.load(atomic::Ordering::Relaxed))
+}
+
+/// Called by the local APIC spurious vector handler. Spurious APIC interrupts must not be acknowledged.
+pub(super) fn apic_spurious() {
+    let n = APIC_SPURIOUS.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+    if rate_limit() {
+        log::warn!("Spurious APIC interrupt ({n} total)");
+    }
+}
+
+/// Called from the interrupt stub when `vector` has no handler.
+pub(super) fn unhandled(vector: u8) {
+    if vector == PIC_SPURIOUS_MASTER || vector == PIC_SPURIOUS_SLAVE {
+        if pic_spurious(vector) {
+            return;
+        }
+    }
+
+    let v = &UNHANDLED[vector as usize];
+    v.total.fetch_add(1, atomic::Ordering::Relaxed);
+
+    let now = crate::time::get_sys_time();
+    let start = v.window_start.load(atomic::Ordering::Relaxed);
+    let count = if now.saturating_sub(start) > UNHANDLED_WINDOW_NS {
+        v.window_start.store(now, atomic::Ordering::Relaxed);
+        v.window_count.store(1, atomic::Ordering::Relaxed);
+        1
+    } else {
+        v.window_count.fetch_add(1, atomic::Ordering::Relaxed) + 1
+    };
+
+    if count > UNHANDLED_THRESHOLD.get() && !v.masked.swap(true, atomic::Ordering::Relaxed) {
+        // SAFETY: The vector has no handler, masking it only prevents further interrupts.
+        match unsafe { crate::system::sysfs::get_sysfs().systemctl.ioapic.mask_vector(vector) } {
+            Some(0) => log::error!("Vector {vector} raised {count} times without a handler, unable to locate source"),
+            Some(n) => log::error!("Vector {vector} raised {count} times without a handler, masked {n} GSIs"),
+            // retry on the next interrupt
+            None => v.masked.store(false, atomic::Ordering::Relaxed),
+        }
+    } else if rate_limit() {
+        log::warn!("Unhandled interrupt at vector {vector}");
+    }
+
+    // The interrupt was delivered by the local APIC which will block lower priority vectors until
+    // it is acknowledged.
+    // SAFETY: This is called from an interrupt handler
+    unsafe { super::apic::apic_eoi() }
+}
+
+/// Checks whether an interrupt on a PIC's IRQ 7 vector was spurious. Returns `true` when the
+/// interrupt was spurious and has been handled.
+fn pic_spurious(vector: u8) -> bool {
+    let cmd = if vector == PIC_SPURIOUS_MASTER { PIC_MASTER_CMD } else { PIC_SLAVE_CMD };
+    if pic_isr(cmd) & (1 << 7) != 0 {
+        return false;
+    }
+    // A spurious interrupt from the slave is not spurious to the master, which must be acknowledged.
+    if cmd == PIC_SLAVE_CMD {
+        // SAFETY: The master has an IRQ in service on its cascade line.
+        unsafe { Port::<u8>::new(PIC_MASTER_CMD).write(PIC_EOI) };
+    }
+    let n = PIC_SPURIOUS.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+    if rate_limit() {
+        log::warn!("Spurious PIC interrupt at vector {vector} ({n} total)");
+    }
+    true
+}
@@ -0,0 +1,98 @@
+//! Per-CPU interrupt statistics.
+//!
+//! Each CPU counts interrupts and handler latencies in its own [InterruptLog]. The logs of all CPUs
+//! are registered here so they can be read together, and are exported as text at `/interrupts`.
+//!
+//! Only vectors which are dispatched through the [super::vector_tables::IHR] are counted, exceptions
+//! and handlers bound directly into the IDT are not.
+
+use super::vector_tables::{InterruptLog, IHR, INT_LOG, LATENCY_BASE_SHIFT, LATENCY_BUCKETS};
+use crate::fs::report::ReportFile;
+use crate::fs::vfs::{DevID, MajorNum};
+use crate::mp::CpuIndex;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::fmt::Write as _;
+
+const FS_NAME: &str = "/interrupts";
+
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("irq_stats").unwrap(););
+
+static CPU_LOGS: spin::RwLock<BTreeMap<CpuIndex, &'static InterruptLog>> = spin::RwLock::new(BTreeMap::new());
+
+/// Registers the interrupt log for the calling CPU. This must be called once by each CPU after its
+/// thread local storage is initialized.
+pub fn register_cpu() {
+    // SAFETY: Thread local storage is never freed, so the log lives for the rest of the kernel's lifetime.
+    let log: &'static InterruptLog = unsafe { &*(&INT_LOG as *const InterruptLog) };
+    CPU_LOGS.write().insert(crate::who_am_i(), log);
+}
+
+/// Returns the CPUs which have registered their interrupt log.
+pub fn cpus() -> alloc::vec::Vec<CpuIndex> {
+    CPU_LOGS.read().keys().copied().collect()
+}
+
+/// Returns the number of interrupts raised on `vector` for each CPU.
+pub fn vector_counts(vector: u8) -> BTreeMap<CpuIndex, usize> {
+    CPU_LOGS.read().iter().map(|(cpu, log)| (*cpu, log.fetch_vec(vector))).collect()
+}
+
+/// Returns the handler latency histogram for `vector` summed over all CPUs.
+///
+/// See [LATENCY_BASE_SHIFT] for the bucket sizes.
+pub fn vector_latency(vector: u8) -> [usize; LATENCY_BUCKETS] {
+    sum_latency(CPU_LOGS.read().values(), vector)
+}
+
+fn sum_latency<'a>(logs: impl Iterator<Item = &'a &'static InterruptLog>, vector: u8) -> [usize; LATENCY_BUCKETS] {
+    let mut hist = [0; LATENCY_BUCKETS];
+    for log in logs {
+        for (acc, n) in hist.iter_mut().zip(log.fetch_latency(vector)) {
+            *acc += n;
+        }
+    }
+    hist
+}
+
+/// Formats a table of interrupt counts for all vectors which have been raised.
+///
+/// Each line contains the vector, the count for each CPU, the handler type and the latency
+/// histogram. Latencies are given in TSC cycles. The last line contains the number of spurious interrupts.
+pub fn report() -> String {
+    let logs = CPU_LOGS.read();
+    let mut s = String::new();
+
+    let _ = write!(s, "VEC");
+    for cpu in logs.keys() {
+        let _ = write!(s, " {:>10}", format_args!("CPU{cpu}"));
+    }
+    let _ = write!(s, " {:>10} LATENCY(<{}..)", "HANDLER", 1u64 << LATENCY_BASE_SHIFT);
+    let _ = writeln!(s);
+
+    for vector in 0..=u8::MAX {
+        let counts: alloc::vec::Vec<usize> = logs.values().map(|l| l.fetch_vec(vector)).collect();
+        if counts.iter().all(|c| *c == 0) {
+            continue;
+        }
+        let _ = write!(s, "{vector:>3}");
+        for c in counts {
+            let _ = write!(s, " {c:>10}");
+        }
+        let _ = write!(s, " {:>10}", IHR.get(vector).read().kind());
+
+        for n in sum_latency(logs.values(), vector) {
+            let _ = write!(s, " {n}");
+        }
+        let _ = writeln!(s);
+    }
+    let _ = writeln!(s, "SPU APIC {} PIC {}", super::spurious::apic_spurious_count(), super::spurious::pic_spurious_count());
+    s
+}
+
+/// Mounts the statistics file.
+pub fn init() {
+    crate::task::util::block_on!(crate::fs::get_vfs().mount_dev(Box::new(ReportFile::new(DevID::new(*MAJOR, 0), report)), FS_NAME))
+        .expect("Failed to mount interrupt statistics to VFS");
+}
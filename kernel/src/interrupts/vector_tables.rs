@@ -1,38 +1,91 @@
 pub static IHR: HandleRegistry = HandleRegistry::new();
 
 #[thread_local]
-pub(super) static mut INT_LOG: InterruptLog = InterruptLog::new();
+pub(super) static INT_LOG: InterruptLog = InterruptLog::new();
+
+/// Number of interrupt stubs currently running on this CPU.
+#[thread_local]
+static IRQ_DEPTH: core::cell::Cell<usize> = core::cell::Cell::new(0);
+
+pub(super) fn irq_enter(vector: u8) {
+    IRQ_DEPTH.set(IRQ_DEPTH.get() + 1);
+    crate::trace::irq(vector);
+}
+
+pub(super) fn irq_exit() {
+    IRQ_DEPTH.set(IRQ_DEPTH.get() - 1);
+}
+
+/// Returns whether the caller is running within an interrupt handler dispatched through [IHR].
+///
+/// Exception handlers are not counted.
+pub fn in_interrupt() -> bool {
+    crate::runlevel::runlevel() != crate::runlevel::Runlevel::PreInit && IRQ_DEPTH.get() > 0
+}
+
+/// Number of buckets in each latency histogram.
+pub const LATENCY_BUCKETS: usize = 12;
+/// The first histogram bucket counts handlers which completed in fewer than `1 << LATENCY_BASE_SHIFT`
+/// TSC cycles, each subsequent bucket doubles the limit. The last bucket counts all remaining handlers.
+pub const LATENCY_BASE_SHIFT: u32 = 8;
 
 /// The InterruptLog is a thread local structure used for counting the number of interrupts that
 /// have occurred  on each cpu. When an interrupt occurs its vector within log should be incremented.
 /// No other software should modify InterruptLog. It is made thread local to avoid the use of mutexes
 /// which may in some cases cause a deadlock, this also reduces access time helping to
-/// remove interrupt overhead.
+/// remove interrupt overhead. Other CPUs may read the log, see [super::stats].
 ///
 /// When the values within this are read and are lower than when they were read previously this can
 /// be guaranteed to either a hardware fault or an overflow and should be treated as the latter,
 /// however is likely the former.
 pub struct InterruptLog {
-    log: [usize; 256],
+    log: [core::sync::atomic::AtomicUsize; 256],
+    /// Histogram of handler run time in TSC cycles.
+    latency: [[core::sync::atomic::AtomicUsize; LATENCY_BUCKETS]; 256],
 }
 
 impl InterruptLog {
     pub const fn new() -> Self {
-        Self { log: [0; 256] }
+        Self {
+            log: [const { core::sync::atomic::AtomicUsize::new(0) }; 256],
+            latency: [const { [const { core::sync::atomic::AtomicUsize::new(0) }; LATENCY_BUCKETS] }; 256],
+        }
     }
 
     /// Logs an interrupt into internal storage at the given vector
-    pub(super) fn log(&mut self, vector: u8) {
-        self.log[vector as usize] = self.log[vector as usize].wrapping_add(1);
+    pub(super) fn log(&self, vector: u8) {
+        // Only the owning CPU writes to the log so the load and store do not need to be atomic together.
+        let c = &self.log[vector as usize];
+        c.store(c.load(atomic::Ordering::Relaxed).wrapping_add(1), atomic::Ordering::Relaxed);
+    }
+
+    /// Returns the current TSC value, this is passed to [Self::log_latency] when the handler has completed.
+    pub(super) fn start(&self) -> u64 {
+        // SAFETY: rdtsc has no side effects
+        unsafe { core::arch::x86_64::_rdtsc() }
+    }
+
+    /// Logs the run time of the handler for `vector` which was started at `start`.
+    pub(super) fn log_latency(&self, vector: u8, start: u64) {
+        // SAFETY: rdtsc has no side effects
+        let cycles = unsafe { core::arch::x86_64::_rdtsc() }.saturating_sub(start);
+        let bucket = (u64::BITS - (cycles >> LATENCY_BASE_SHIFT).leading_zeros()) as usize;
+        let c = &self.latency[vector as usize][bucket.min(LATENCY_BUCKETS - 1)];
+        c.store(c.load(atomic::Ordering::Relaxed).wrapping_add(1), atomic::Ordering::Relaxed);
     }
 
     /// Returns a copy of the data contained within the given vector
     pub fn fetch_vec(&self, vector: u8) -> usize {
-        self.log[vector as usize]
+        self.log[vector as usize].load(atomic::Ordering::Relaxed)
     }
 
     pub fn fetch_all(&self) -> [usize; 256] {
-        self.log.clone()
+        core::array::from_fn(|i| self.log[i].load(atomic::Ordering::Relaxed))
+    }
+
+    /// Returns the latency histogram for `vector`.
+    pub fn fetch_latency(&self, vector: u8) -> [usize; LATENCY_BUCKETS] {
+        core::array::from_fn(|i| self.latency[vector as usize][i].load(atomic::Ordering::Relaxed))
     }
 }
 
@@ -292,6 +345,17 @@ impl InterruptHandleContainer {
         }
     }
 
+    /// Returns a short description of the handler type.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            InterruptHandleContainer::Empty => "empty",
+            InterruptHandleContainer::Reserved => "reserved",
+            InterruptHandleContainer::SpecialHandle(_) => "special",
+            InterruptHandleContainer::Generic(_) => "generic",
+            InterruptHandleContainer::HighPerfCascading(_) => "cascading",
+        }
+    }
+
     pub(super) fn call(&self) {
         match self {
             InterruptHandleContainer::SpecialHandle(h) => h(),
@@ -0,0 +1,202 @@
+//! In-memory kernel log buffer shared with readers.
+//!
+//! Each log message is appended to [LogBuffer], a page aligned ring which contains no kernel
+//! pointers and is intended to be mapped read-only into user processes, so that a log daemon or the
+//! shell can follow the log without copying every message through `read()`.
+//!
+//! The [Header] counts bytes rather than positions in the ring. `head` is the number of bytes
+//! written since boot and `tail` is the number of bytes which have been overwritten, so the ring
+//! holds the bytes `tail..head` and byte `n` is stored at `n % size`. The writer advances `tail`
+//! before it overwrites the ring and advances `head` after the new bytes are written. A reader
+//! copies the bytes from its position up to `head`, then loads `tail` again and discards the bytes
+//! it copied from below it because they may have been overwritten, see [Reader::read]. When the
+//! ring wraps a reader may start in the middle of a line.
+//!
+//! There are no user processes yet, so the buffer is not mapped anywhere. [vm_object] describes
+//! it for the process loader.
+//!
+//! The `/bin/dmesg [-f]` program prints the buffer using a [Reader], `-f` keeps following the log.
+
+use crate::mem::vm_object::VmObject;
+use crate::task::spawn::Args;
+use crate::task::TaskResult;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+pub const MAGIC: u64 = u64::from_le_bytes(*b"HOOTKLOG");
+
+/// Size of the buffer including the header.
+const SIZE: usize = 64 * 1024;
+const RING_SIZE: usize = SIZE - size_of::<Header>();
+
+/// Milliseconds between polls of the buffer by `dmesg -f`.
+const FOLLOW_MS: u64 = 100;
+
+/// Start of the buffer, the ring occupies the remainder of the buffer.
+#[repr(C)]
+pub struct Header {
+    magic: u64,
+    /// Size of the ring in bytes.
+    size: u64,
+    /// Number of bytes written since boot.
+    head: AtomicU64,
+    /// Number of bytes which have been overwritten.
+    tail: AtomicU64,
+}
+
+/// The log buffer shared with readers, see the module documentation.
+#[repr(C, align(4096))]
+pub struct LogBuffer {
+    header: Header,
+    ring: [AtomicU8; RING_SIZE],
+}
+
+static BUFFER: LogBuffer = LogBuffer {
+    header: Header {
+        magic: MAGIC,
+        size: RING_SIZE as u64,
+        head: AtomicU64::new(0),
+        tail: AtomicU64::new(0),
+    },
+    ring: [const { AtomicU8::new(0) }; RING_SIZE],
+};
+
+/// Serializes writers of [BUFFER].
+static WRITER: spin::Mutex<()> = spin::Mutex::new(());
+
+impl LogBuffer {
+    pub fn magic(&self) -> u64 {
+        self.header.magic
+    }
+
+    pub fn size(&self) -> u64 {
+        self.header.size
+    }
+
+    pub fn head(&self) -> u64 {
+        self.header.head.load(Ordering::Acquire)
+    }
+
+    pub fn tail(&self) -> u64 {
+        self.header.tail.load(Ordering::Acquire)
+    }
+
+    /// Only one writer may call this at a time.
+    fn append(&self, s: &str) {
+        // Only the end of a message longer than the ring is kept
+        let bytes = &s.as_bytes()[s.len().saturating_sub(RING_SIZE)..];
+        let size = RING_SIZE as u64;
+        let head = self.header.head.load(Ordering::Relaxed);
+        let end = head + bytes.len() as u64;
+        if end - self.header.tail.load(Ordering::Relaxed) > size {
+            self.header.tail.store(end - size, Ordering::Relaxed);
+            core::sync::atomic::fence(Ordering::Release);
+        }
+        for (i, b) in bytes.iter().enumerate() {
+            self.ring[((head + i as u64) % size) as usize].store(*b, Ordering::Relaxed);
+        }
+        self.header.head.store(end, Ordering::Release);
+    }
+}
+
+struct Writer<'a>(&'a LogBuffer);
+
+impl core::fmt::Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.0.append(s);
+        Ok(())
+    }
+}
+
+/// Follows a [LogBuffer] using only the shared memory, as a user process would.
+pub struct Reader<'a> {
+    buffer: &'a LogBuffer,
+    /// The number of bytes written before the next byte to be read.
+    pos: u64,
+}
+
+impl<'a> Reader<'a> {
+    /// Constructs a reader starting at the oldest byte in `buffer`.
+    pub fn new(buffer: &'a LogBuffer) -> Self {
+        Self { buffer, pos: buffer.tail() }
+    }
+
+    /// Appends the bytes written since the previous call to `out`. Returns the number of bytes
+    /// which were overwritten before they could be read.
+    pub fn read(&mut self, out: &mut Vec<u8>) -> u64 {
+        let size = self.buffer.size();
+        let head = self.buffer.head();
+        let start = self.pos.max(head.saturating_sub(size));
+        let first = out.len();
+        out.extend((start..head).map(|i| self.buffer.ring[(i % size) as usize].load(Ordering::Relaxed)));
+
+        core::sync::atomic::fence(Ordering::Acquire);
+        let valid = self.buffer.header.tail.load(Ordering::Relaxed).clamp(start, head);
+        out.drain(first..first + (valid - start) as usize);
+        let lost = valid - self.pos;
+        self.pos = head;
+        lost
+    }
+}
+
+/// Appends a message to the log buffer, called by the logger.
+pub(crate) fn write(args: core::fmt::Arguments) {
+    use core::fmt::Write;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _l = WRITER.lock();
+        let _ = Writer(&BUFFER).write_fmt(args);
+    })
+}
+
+/// Returns the kernel's log buffer.
+pub fn buffer() -> &'static LogBuffer {
+    &BUFFER
+}
+
+/// Describes the log buffer for mapping it read-only into a user process.
+pub fn vm_object() -> Option<VmObject> {
+    VmObject::from_static(&BUFFER, false)
+}
+
+fn program(args: Args) -> Pin<Box<dyn Future<Output = TaskResult> + Send>> {
+    Box::pin(async move {
+        let follow = match args.argv.get(1).map(String::as_str) {
+            None => false,
+            Some("-f") if args.argv.len() == 2 => true,
+            Some(_) => {
+                crate::serial_println!("Usage: dmesg [-f]");
+                return TaskResult::Error;
+            }
+        };
+
+        let mut reader = Reader::new(buffer());
+        let mut pending = Vec::new();
+        loop {
+            let lost = reader.read(&mut pending);
+            if lost > 0 {
+                crate::serial_println!("dmesg: {lost} bytes were overwritten before they were read");
+            }
+            // Incomplete lines are kept until the rest of the line is written
+            let end = match follow {
+                true => pending.iter().rposition(|b| *b == b'\n').map_or(0, |p| p + 1),
+                false => pending.len(),
+            };
+            crate::serial_print!("{}", String::from_utf8_lossy(&pending[..end]));
+            pending.drain(..end);
+
+            if !follow {
+                return TaskResult::ExitedNormally;
+            }
+            crate::task::util::sleep(FOLLOW_MS).await;
+        }
+    })
+}
+
+/// Registers the `/bin/dmesg` program.
+pub fn init() {
+    crate::task::spawn::register("/bin/dmesg", program).expect("Failed to register dmesg");
+}
@@ -0,0 +1,178 @@
+//! Kernel symbol map.
+//!
+//! The `.ksyms` section is reserved within the kernel binary and the symbol map is written into it
+//! by the loader after the kernel is linked. The map is exported as text at `/ksyms` so that
+//! tooling can symbolize addresses without access to the kernel's ELF file.
+//!
+//! The map contains one symbol per line sorted by address, formatted as
+//! `address size type module name`. The address and size are hexadecimal, the type is `T` for
+//! functions and `D` for data. The module is the crate which defines the symbol or `-` when it is
+//! not known. Legacy mangled Rust symbols are demangled and their hash is removed.
+//!
+//! When the kernel was not linked by the loader the map is empty.
+//!
+//! [Backtrace] captures the return addresses of the calling stack and symbolizes them using the map.
+
+use crate::fs::report::ReportFile;
+use crate::fs::vfs::{DevID, MajorNum};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+
+const FS_NAME: &str = "/ksyms";
+
+/// Size of the reserved map, the loader truncates the map if it does not fit.
+const CAPACITY: usize = 0x20_0000;
+
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("ksyms").unwrap(););
+
+/// Layout of the `.ksyms` section, this must match the loader.
+#[repr(C)]
+struct KsymsSection {
+    magic: [u8; 8],
+    len: u64,
+    map: [u8; CAPACITY],
+}
+
+/// The section is written after the kernel is compiled, so the compiler must not assume that it
+/// contains its initial value.
+struct KsymsCell(UnsafeCell<KsymsSection>);
+
+// SAFETY: The section is never written by the kernel
+unsafe impl Sync for KsymsCell {}
+
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: KsymsCell = KsymsCell(UnsafeCell::new(KsymsSection {
+    magic: *b"HOOTKSYM",
+    len: 0,
+    map: [0; CAPACITY],
+}));
+
+static SYMBOLS: spin::Once<Vec<Symbol>> = spin::Once::new();
+
+#[derive(Debug, Copy, Clone)]
+pub struct Symbol {
+    pub addr: u64,
+    pub size: u64,
+    pub module: &'static str,
+    pub name: &'static str,
+}
+
+impl core::fmt::Display for Symbol {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Returns the symbol map as text.
+pub fn map() -> &'static str {
+    // SAFETY: The section is never written by the kernel
+    let section = unsafe { &*KSYMS.0.get() };
+    // SAFETY: `len` is written by the loader, it must be read from memory
+    let len = unsafe { core::ptr::read_volatile(&section.len) } as usize;
+    let map = &section.map[..len.min(CAPACITY)];
+    match core::str::from_utf8(map) {
+        Ok(s) => s,
+        Err(e) => core::str::from_utf8(&map[..e.valid_up_to()]).unwrap(),
+    }
+}
+
+/// Returns all symbols sorted by address.
+pub fn symbols() -> &'static [Symbol] {
+    SYMBOLS.call_once(|| {
+        map()
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(5, ' ');
+                let addr = u64::from_str_radix(fields.next()?, 16).ok()?;
+                let size = u64::from_str_radix(fields.next()?, 16).ok()?;
+                let _kind = fields.next()?;
+                let module = fields.next()?;
+                let name = fields.next()?;
+                Some(Symbol { addr, size, module, name })
+            })
+            .collect()
+    })
+}
+
+/// Locates the symbol containing `addr` and returns it with the offset of `addr` into the symbol.
+///
+/// Symbols with a size of `0` are assumed to extend to the next symbol.
+pub fn lookup(addr: u64) -> Option<(Symbol, u64)> {
+    let syms = symbols();
+    let i = syms.partition_point(|s| s.addr <= addr).checked_sub(1)?;
+    let sym = syms[i];
+    let offset = addr - sym.addr;
+    (sym.size == 0 || offset < sym.size).then_some((sym, offset))
+}
+
+/// Maximum number of frames recorded by [Backtrace::capture].
+const MAX_FRAMES: usize = 16;
+
+/// Return addresses of the calling stack, found by following frame pointers.
+///
+/// This requires the kernel to be compiled with frame pointers. Walking stops at the first frame
+/// pointer which is not mapped or does not point further up the stack.
+#[derive(Copy, Clone)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Captures the stack of the caller. This does not allocate or lock.
+    #[inline(never)]
+    pub fn capture() -> Self {
+        let mut bt = Self { frames: [0; MAX_FRAMES], len: 0 };
+        let mut fp: u64;
+        // SAFETY: Only reads rbp
+        unsafe { core::arch::asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags)) };
+
+        while bt.len < MAX_FRAMES {
+            // The saved frame pointer and return address are in the same page when `fp` is aligned
+            if fp < 0xffff_8000_0000_0000 || fp % 8 != 0 || crate::mem::mem_map::translate(fp as usize).is_none() {
+                break;
+            }
+            // SAFETY: The frame is mapped, see above
+            let (next, ret) = unsafe { (*(fp as *const u64), *((fp + 8) as *const u64)) };
+            if ret == 0 {
+                break;
+            }
+            bt.frames[bt.len] = ret;
+            bt.len += 1;
+            // The stack grows down, callers always have a higher frame pointer
+            if next <= fp {
+                break;
+            }
+            fp = next;
+        }
+        bt
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+impl core::fmt::Display for Backtrace {
+    /// Formats one frame per line, symbolized where possible.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for addr in self.frames() {
+            match lookup(*addr) {
+                Some((sym, offset)) => writeln!(f, "  {addr:#x} {sym}+{offset:#x}")?,
+                None => writeln!(f, "  {addr:#x}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Mounts the symbol map.
+pub fn init() {
+    if map().is_empty() {
+        log::warn!("Kernel symbol map is not present");
+    }
+    crate::task::util::block_on!(crate::fs::get_vfs().mount_dev(Box::new(ReportFile::new_static(DevID::new(*MAJOR, 0), || map().as_bytes())), FS_NAME))
+        .expect("Failed to mount kernel symbol map to VFS");
+}
@@ -23,18 +23,32 @@
 extern crate alloc;
 pub use mem::allocator::alloc_interface;
 
+pub mod config;
+pub mod crypto;
+pub mod error;
+pub mod fault_inject;
 mod device_check;
 pub mod gdt;
 pub mod graphics;
+pub mod input;
 pub mod interrupts;
-mod logger;
+pub mod klog;
+pub mod ksyms;
+pub mod lockdep;
+pub mod logger;
 pub mod mem;
+pub mod memdbg;
 pub mod mp;
+pub mod net;
+pub mod pstore;
 pub mod runlevel;
+pub mod selftest;
 pub mod serial;
+pub mod sound;
 pub mod system;
 pub mod task;
 pub mod time;
+pub mod trace;
 mod util;
 pub mod llvm;
 pub mod fs;
@@ -110,7 +124,7 @@ pub fn init() {
 
 pub fn init_logger() {
     log::set_logger(&logger::LOGGER).expect("failed to initialize logger");
-    log::set_max_level(log::LevelFilter::Trace);
+    logger::default_level_changed(config::LOG_LEVEL.get());
 }
 
 #[inline]
@@ -121,6 +135,7 @@ pub fn stop() -> ! {
 }
 
 pub fn test_panic(info: &core::panic::PanicInfo) -> ! {
+    serial::early::takeover();
     serial_println!("[FAILED]");
     serial_println!("Error: {}", info);
     exit_qemu(QemuExitCode::Failed);
@@ -0,0 +1,533 @@
+//! Lock dependency validation.
+//!
+//! In debug builds each acquisition of a tracked lock records the locks which are already held by
+//! the CPU, building a graph of the orders in which locks are acquired. The first acquisition
+//! which would complete a cycle in that graph, e.g. one path takes `A` then `B` and another takes
+//! `B` then `A`, is reported along with the chain of dependencies, the locks held by the CPU and a
+//! backtrace. This is reported when the order is first seen, the two paths do not need to race.
+//!
+//! A lock which is acquired within an interrupt handler and elsewhere with interrupts enabled is
+//! reported in the same way, an interrupt which arrives while the lock is held spins forever.
+//!
+//! Only the first problem is reported, validation is disabled afterwards.
+//!
+//! Locks are grouped into classes. A lock in a static is its own class and is named using
+//! [crate::ksyms]. Other locks are classed by the call to `new` which constructed them, like
+//! `lock_class_key` in Linux, so every lock constructed by the same line shares a class. Locks
+//! which are constructed by a shared helper should be given their own class using [lock_class] and
+//! `with_class`. Acquiring two locks of the same class is not checked.
+//!
+//! [crate::util::Mutex] and [crate::util::mutex::ReentrantMutex] are always tracked, spin locks are
+//! tracked by replacing them with [Mutex] and [RwLock]. Locks acquired using `try_lock` cannot
+//! deadlock so they do not add dependencies, they do count as held for locks acquired after them.
+//!
+//! The held locks are tracked per CPU, guards must not be held across an `.await`.
+//! Release builds compile the hooks to nothing.
+
+use core::cell::Cell;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+/// Maximum number of lock classes, further classes disable validation.
+const MAX_CLASSES: usize = 256;
+
+/// Maximum number of locks a CPU may hold, further locks are not tracked.
+const MAX_HELD: usize = 32;
+
+/// A shared class for locks which are not in statics.
+pub struct LockClass {
+    name: &'static str,
+}
+
+impl LockClass {
+    pub const fn new(name: &'static str) -> Self {
+        Self { name }
+    }
+}
+
+/// Declares a [LockClass] and returns a `&'static` reference to it.
+///
+/// ```ignore
+/// let port = hootux::lockdep::Mutex::with_class(state, hootux::lock_class!("ahci port"));
+/// ```
+#[macro_export]
+macro_rules! lock_class {
+    ($name:expr) => {{
+        static CLASS: $crate::lockdep::LockClass = $crate::lockdep::LockClass::new($name);
+        &CLASS
+    }};
+}
+
+/// Identifies the class of a lock.
+#[derive(Copy, Clone)]
+pub(crate) enum ClassKey {
+    /// A class declared using [lock_class].
+    Named(&'static LockClass),
+    /// The site which constructed the lock. A lock in a static is its own class instead.
+    Site(&'static Location<'static>),
+}
+
+/// What the key of a class is the address of.
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum KeySource {
+    Static,
+    Named,
+    Site,
+}
+
+extern "C" {
+    // Bounds of the writable statics, defined in `scripts/kernel.ld`
+    static __kernel_data_start: u8;
+    static __kernel_data_end: u8;
+}
+
+/// Returns whether `addr` is within a writable static.
+fn is_static(addr: usize) -> bool {
+    (&raw const __kernel_data_start as usize..&raw const __kernel_data_end as usize).contains(&addr)
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Kind {
+    Lock,
+    TryLock,
+    Read,
+    TryRead,
+    /// The lock may be acquired again by its owner, so it cannot deadlock with an interrupt on the
+    /// same CPU.
+    Reentrant,
+}
+
+impl Kind {
+    fn is_try(self) -> bool {
+        matches!(self, Self::TryLock | Self::TryRead)
+    }
+
+    fn is_read(self) -> bool {
+        matches!(self, Self::Read | Self::TryRead)
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Class keys, the address of a static lock, a [LockClass] or a [Location]. `0` is unused.
+static KEYS: [AtomicUsize; MAX_CLASSES] = [const { AtomicUsize::new(0) }; MAX_CLASSES];
+/// The [KeySource] of each key.
+static SOURCES: [AtomicU8; MAX_CLASSES] = [const { AtomicU8::new(KeySource::Static as u8) }; MAX_CLASSES];
+/// `DEPS[a]` bit `b` is set when `b` was acquired while `a` was held.
+static DEPS: [[AtomicU64; MAX_CLASSES / 64]; MAX_CLASSES] = [const { [const { AtomicU64::new(0) }; MAX_CLASSES / 64] }; MAX_CLASSES];
+/// First acquisition of each class within an interrupt handler.
+static IRQ_SITE: [AtomicPtr<Location<'static>>; MAX_CLASSES] = [const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_CLASSES];
+/// First acquisition of each class outside interrupt handlers with interrupts enabled.
+static UNSAFE_SITE: [AtomicPtr<Location<'static>>; MAX_CLASSES] = [const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_CLASSES];
+
+#[derive(Copy, Clone)]
+struct Held {
+    lock: usize,
+    class: usize,
+    kind: Kind,
+    site: &'static Location<'static>,
+}
+
+struct HeldLocks {
+    depth: Cell<usize>,
+    locks: [Cell<Option<Held>>; MAX_HELD],
+}
+
+#[thread_local]
+static HELD: HeldLocks = HeldLocks {
+    depth: Cell::new(0),
+    locks: [const { Cell::new(None) }; MAX_HELD],
+};
+
+impl HeldLocks {
+    fn iter(&self) -> impl Iterator<Item = Held> + '_ {
+        self.locks[..self.depth.get()].iter().filter_map(Cell::get)
+    }
+
+    fn push(&self, held: Held) {
+        let depth = self.depth.get();
+        if let Some(slot) = self.locks.get(depth) {
+            slot.set(Some(held));
+            self.depth.set(depth + 1);
+        }
+    }
+
+    /// Removes the most recent entry for `lock`. Locks which are not tracked are ignored.
+    fn remove(&self, lock: usize) {
+        let depth = self.depth.get();
+        let Some(i) = self.locks[..depth].iter().rposition(|h| h.get().is_some_and(|h| h.lock == lock)) else { return };
+        for j in i..depth - 1 {
+            self.locks[j].set(self.locks[j + 1].get());
+        }
+        self.locks[depth - 1].set(None);
+        self.depth.set(depth - 1);
+    }
+}
+
+fn active() -> bool {
+    cfg!(debug_assertions) && ENABLED.load(Ordering::Relaxed) && crate::runlevel::runlevel() != crate::runlevel::Runlevel::PreInit
+}
+
+/// Validates and records the acquisition of `lock`.
+///
+/// Blocking acquisitions must call this before spinning, so the report is made before the
+/// deadlock. `try` acquisitions call this after the lock was acquired.
+#[inline]
+pub(crate) fn acquire(lock: usize, class: ClassKey, site: &'static Location<'static>, kind: Kind) {
+    if !active() {
+        return;
+    }
+    let irqs = x86_64::instructions::interrupts::are_enabled();
+    x86_64::instructions::interrupts::without_interrupts(|| validate(lock, class, site, kind, irqs))
+}
+
+/// Records that `lock` was released.
+#[inline]
+pub(crate) fn release(lock: usize) {
+    if !active() {
+        return;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| HELD.remove(lock))
+}
+
+fn validate(lock: usize, class: ClassKey, site: &'static Location<'static>, kind: Kind, irqs: bool) {
+    let (key, source) = match class {
+        ClassKey::Named(c) => (c as *const LockClass as usize, KeySource::Named),
+        ClassKey::Site(_) if is_static(lock) => (lock, KeySource::Static),
+        ClassKey::Site(s) => (s as *const Location as usize, KeySource::Site),
+    };
+    let Some(c) = class_index(key, source) else {
+        report(|| log::error!("Lockdep: Lock class table is full"));
+        return;
+    };
+
+    if !kind.is_try() && kind != Kind::Reentrant {
+        let site_ptr = site as *const Location as *mut Location;
+        if crate::interrupts::vector_tables::in_interrupt() {
+            let _ = IRQ_SITE[c].compare_exchange(core::ptr::null_mut(), site_ptr, Ordering::Relaxed, Ordering::Relaxed);
+        } else if irqs {
+            let _ = UNSAFE_SITE[c].compare_exchange(core::ptr::null_mut(), site_ptr, Ordering::Relaxed, Ordering::Relaxed);
+        }
+        let (irq, task) = (IRQ_SITE[c].load(Ordering::Relaxed), UNSAFE_SITE[c].load(Ordering::Relaxed));
+        if !irq.is_null() && !task.is_null() {
+            // SAFETY: Sites are only set from `&'static Location`
+            let (irq, task) = unsafe { (&*irq, &*task) };
+            report(|| {
+                log::error!("Lockdep: {} is acquired in an interrupt handler at {irq}", ClassName(c));
+                log::error!("Lockdep: and with interrupts enabled at {task}");
+                log::error!("Lockdep: An interrupt taken while the lock is held will deadlock");
+            });
+            return;
+        }
+    }
+
+    if !kind.is_try() {
+        for h in HELD.iter() {
+            if h.class == c {
+                if h.lock == lock && !(h.kind.is_read() && kind.is_read()) {
+                    report(|| log::error!("Lockdep: Recursive acquisition of {} at {site}, already held from {}", ClassName(c), h.site));
+                    return;
+                }
+                continue;
+            }
+            if has_dep(h.class, c) {
+                continue;
+            }
+            if let Some(chain) = find_path(c, h.class) {
+                report(|| {
+                    log::error!("Lockdep: Possible deadlock acquiring {} at {site} while holding {}", ClassName(c), ClassName(h.class));
+                    log::error!("Lockdep: Existing dependency chain, each lock was held while acquiring the next:");
+                    for n in chain.iter() {
+                        log::error!("  {}", ClassName(n));
+                    }
+                });
+                return;
+            }
+            DEPS[h.class][c / 64].fetch_or(1 << (c % 64), Ordering::Relaxed);
+        }
+    }
+
+    HELD.push(Held { lock, class, kind, site });
+}
+
+/// Returns the index of the class for `key`, allocating one if it is new.
+fn class_index(key: usize, source: KeySource) -> Option<usize> {
+    let start = ((key >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (usize::BITS - 8)) % MAX_CLASSES;
+    for i in 0..MAX_CLASSES {
+        let idx = (start + i) % MAX_CLASSES;
+        match KEYS[idx].compare_exchange(0, key, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => {
+                SOURCES[idx].store(source as u8, Ordering::Relaxed);
+                return Some(idx);
+            }
+            Err(k) if k == key => return Some(idx),
+            Err(_) => {}
+        }
+    }
+    None
+}
+
+fn has_dep(from: usize, to: usize) -> bool {
+    DEPS[from][to / 64].load(Ordering::Relaxed) & (1 << (to % 64)) != 0
+}
+
+/// A path through the dependency graph.
+struct Chain {
+    nodes: [u16; MAX_CLASSES],
+    len: usize,
+}
+
+impl Chain {
+    fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.nodes[..self.len].iter().map(|n| *n as usize)
+    }
+}
+
+/// Searches for a chain of dependencies from `from` to `to`.
+fn find_path(from: usize, to: usize) -> Option<Chain> {
+    const UNVISITED: u16 = u16::MAX;
+    let mut parent = [UNVISITED; MAX_CLASSES];
+    let mut stack = [0u16; MAX_CLASSES];
+    let mut sp = 1;
+    stack[0] = from as u16;
+    parent[from] = from as u16;
+
+    while sp > 0 {
+        sp -= 1;
+        let n = stack[sp] as usize;
+        if n == to {
+            let mut chain = Chain { nodes: [0; MAX_CLASSES], len: 0 };
+            let mut i = to;
+            loop {
+                chain.nodes[chain.len] = i as u16;
+                chain.len += 1;
+                if i == from {
+                    break;
+                }
+                i = parent[i] as usize;
+            }
+            chain.nodes[..chain.len].reverse();
+            return Some(chain);
+        }
+        for m in 0..MAX_CLASSES {
+            // Each class is pushed at most once so the stack cannot overflow
+            if parent[m] == UNVISITED && has_dep(n, m) {
+                parent[m] = n as u16;
+                stack[sp] = m as u16;
+                sp += 1;
+            }
+        }
+    }
+    None
+}
+
+/// Logs a report using `f` followed by the held locks and a backtrace, only the first report is logged.
+fn report(f: impl FnOnce()) {
+    if !ENABLED.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    f();
+    log::error!("Lockdep: Locks held by CPU {}:", crate::who_am_i());
+    for h in HELD.iter() {
+        log::error!("  {} acquired at {}", ClassName(h.class), h.site);
+    }
+    log::error!("Lockdep: Backtrace:\n{}", crate::ksyms::Backtrace::capture());
+    log::error!("Lockdep: Lock dependency validation is disabled");
+}
+
+struct ClassName(usize);
+
+impl core::fmt::Display for ClassName {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let key = KEYS[self.0].load(Ordering::Relaxed);
+        let source = SOURCES[self.0].load(Ordering::Relaxed);
+        if source == KeySource::Named as u8 {
+            // SAFETY: Named keys are only set from `&'static LockClass`
+            return f.write_str(unsafe { &*(key as *const LockClass) }.name);
+        }
+        if source == KeySource::Site as u8 {
+            // SAFETY: Site keys are only set from `&'static Location`
+            return write!(f, "lock constructed at {}", unsafe { &*(key as *const Location) });
+        }
+        match crate::ksyms::lookup(key as u64) {
+            Some((sym, 0)) => write!(f, "{sym}"),
+            Some((sym, offset)) => write!(f, "{sym}+{offset:#x}"),
+            None => write!(f, "{key:#x}"),
+        }
+    }
+}
+
+/// A [spin::Mutex] which is tracked, see the module documentation.
+pub struct Mutex<T> {
+    class: ClassKey,
+    inner: spin::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self { class: ClassKey::Site(Location::caller()), inner: spin::Mutex::new(data) }
+    }
+
+    pub const fn with_class(data: T, class: &'static LockClass) -> Self {
+        Self { class: ClassKey::Named(class), inner: spin::Mutex::new(data) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        acquire(self.addr(), self.class, Location::caller(), Kind::Lock);
+        MutexGuard { lock: self.addr(), inner: self.inner.lock() }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let inner = self.inner.try_lock()?;
+        acquire(self.addr(), self.class, Location::caller(), Kind::TryLock);
+        Some(MutexGuard { lock: self.addr(), inner })
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    lock: usize,
+    inner: spin::MutexGuard<'a, T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        release(self.lock);
+    }
+}
+
+/// A [spin::RwLock] which is tracked, see the module documentation.
+///
+/// Read locks are ordered the same as write locks, but a CPU may hold multiple read locks on the
+/// same lock.
+pub struct RwLock<T> {
+    class: ClassKey,
+    inner: spin::RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self { class: ClassKey::Site(Location::caller()), inner: spin::RwLock::new(data) }
+    }
+
+    pub const fn with_class(data: T, class: &'static LockClass) -> Self {
+        Self { class: ClassKey::Named(class), inner: spin::RwLock::new(data) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    #[track_caller]
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        acquire(self.addr(), self.class, Location::caller(), Kind::Read);
+        RwLockReadGuard { lock: self.addr(), inner: self.inner.read() }
+    }
+
+    #[track_caller]
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let inner = self.inner.try_read()?;
+        acquire(self.addr(), self.class, Location::caller(), Kind::TryRead);
+        Some(RwLockReadGuard { lock: self.addr(), inner })
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        acquire(self.addr(), self.class, Location::caller(), Kind::Lock);
+        RwLockWriteGuard { lock: self.addr(), inner: self.inner.write() }
+    }
+
+    #[track_caller]
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let inner = self.inner.try_write()?;
+        acquire(self.addr(), self.class, Location::caller(), Kind::TryLock);
+        Some(RwLockWriteGuard { lock: self.addr(), inner })
+    }
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: usize,
+    inner: spin::RwLockReadGuard<'a, T>,
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        release(self.lock);
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: usize,
+    inner: spin::RwLockWriteGuard<'a, T>,
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        release(self.lock);
+    }
+}
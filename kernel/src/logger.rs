@@ -1,7 +1,30 @@
-use crate::{println, serial_println};
-use log::{Log, Metadata, Record};
+//! Kernel logger.
+//!
+//! Messages are filtered by [crate::config::LOG_LEVEL] unless a level has been set for the module
+//! which logged them. Module levels are set at runtime by writing `module=level` lines to
+//! `/loglevel` or on the kernel command line using `log.module=module=level,...`. A module name
+//! matches the module and all of its children, the `hootux::` prefix may be omitted for kernel
+//! modules and drivers are named by their crate, e.g. `ahci` or `system::pci`. When multiple names
+//! match a module the longest is used. Setting a module's level to `default` removes it.
+//!
+//! Reading `/loglevel` returns the default level followed by all module levels.
+
+use crate::fs::report::ReportFile;
+use crate::fs::vfs::{DevID, MajorNum};
+use crate::fs::IoError;
+use crate::graphics::basic_output::{_print_to, VC_DEBUG, VC_LOG};
+use crate::serial_println;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use core::fmt::Write as _;
+use log::{LevelFilter, Log, Metadata, Record};
 use spin::RwLock;
 
+const FS_NAME: &str = "/loglevel";
+
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("loglevel").unwrap(););
+
 pub(crate) static LOGGER: Logger = Logger::new();
 
 pub(crate) struct Logger {
@@ -11,6 +34,8 @@ pub(crate) struct Logger {
 struct LoggerInner {
     serial: bool,
     graphical: bool,
+    /// Log levels which override the default level for a module and its children.
+    modules: BTreeMap<String, LevelFilter>,
 }
 
 impl Logger {
@@ -26,14 +51,90 @@ impl LoggerInner {
         Self {
             serial: true,
             graphical: true,
+            modules: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the level for messages logged from `target`.
+    fn level_for(&self, target: &str) -> LevelFilter {
+        let short = target.strip_prefix("hootux::").unwrap_or(target);
+        let matches = |name: &str, t: &str| t == name || t.strip_prefix(name).is_some_and(|r| r.starts_with("::"));
+        self.modules
+            .iter()
+            .filter(|(name, _)| matches(name, target) || matches(name, short))
+            .max_by_key(|(name, _)| name.len())
+            .map_or(crate::config::LOG_LEVEL.get(), |(_, level)| *level)
+    }
+}
+
+/// Sets the log level of `module`, `None` removes the module's level so that the default is used.
+pub fn set_module_level(module: &str, level: Option<LevelFilter>) {
+    let mut inner = LOGGER.inner.write();
+    match level {
+        Some(level) => {
+            inner.modules.insert(module.to_string(), level);
+        }
+        None => {
+            inner.modules.remove(module);
+        }
+    }
+    update_max_level(&inner);
+}
+
+/// Called when the default log level is changed.
+pub(crate) fn default_level_changed(_: LevelFilter) {
+    update_max_level(&LOGGER.inner.read());
+}
+
+/// The `log` macros discard messages above [log::max_level] before they reach the logger, so it
+/// must permit the most verbose level in use.
+fn update_max_level(inner: &LoggerInner) {
+    let max = inner.modules.values().copied().fold(crate::config::LOG_LEVEL.get(), LevelFilter::max);
+    log::set_max_level(max);
+}
+
+/// Sets module levels from a `module=level` string.
+pub fn set_arg(arg: &str) -> Result<(), ()> {
+    let (module, level) = arg.split_once('=').ok_or(())?;
+    let (module, level) = (module.trim(), level.trim());
+    if module.is_empty() {
+        return Err(());
+    }
+    match level {
+        "default" => set_module_level(module, None),
+        level => set_module_level(module, Some(level.parse().map_err(|_| ())?)),
+    }
+    Ok(())
+}
+
+/// Applies the `log.module=` argument on the kernel command line, which contains a comma separated
+/// list of `module=level` pairs.
+pub fn parse_cmdline(cmdline: &str) {
+    for arg in cmdline.split_whitespace().filter_map(|a| a.strip_prefix("log.module=")) {
+        for module in arg.split(',').filter(|m| !m.is_empty()) {
+            match set_arg(module) {
+                Ok(()) => log::info!("Log level: {module}"),
+                Err(()) => log::warn!("Log level: Invalid module level {module}"),
+            }
         }
     }
 }
 
+/// Formats the default level and each module level as `module=level` lines.
+pub fn report() -> String {
+    let inner = LOGGER.inner.read();
+    let mut s = String::new();
+    let _ = writeln!(s, "default={}", crate::config::LOG_LEVEL.get());
+    for (module, level) in &inner.modules {
+        let _ = writeln!(s, "{module}={level}");
+    }
+    s
+}
+
 impl Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
         let logger = self.inner.read();
-        return if log::max_level() >= metadata.level() && (logger.serial || logger.graphical) {
+        return if logger.level_for(metadata.target()) >= metadata.level() && (logger.serial || logger.graphical) {
             true
         } else {
             false
@@ -41,14 +142,21 @@ impl Log for Logger {
     }
 
     fn log(&self, record: &Record) {
-        let logger = self.inner.read();
         if self.enabled(record.metadata()) {
+            let logger = self.inner.read();
             if logger.graphical {
-                println!("[{}] {}", record.level(), record.args());
+                // Debug output is kept off the kernel log console so it doesn't bury everything else.
+                let console = match record.level() {
+                    log::Level::Debug | log::Level::Trace => VC_DEBUG,
+                    _ => VC_LOG,
+                };
+                _print_to(console, format_args!("[{}{}\x1b[0m] {}\n", level_colour(record.level()), record.level(), record.args()));
             }
             if logger.serial {
                 serial_println!("[{}] {}", record.level(), record.args());
             }
+            crate::pstore::write(format_args!("[{}] {}\n", record.level(), record.args()));
+            crate::klog::write(format_args!("[{}] {}\n", record.level(), record.args()));
         }
     }
 
@@ -57,9 +165,29 @@ impl Log for Logger {
     }
 }
 
+/// Returns the SGR escape sequence used to colour `level` on the console
+fn level_colour(level: log::Level) -> &'static str {
+    match level {
+        log::Level::Error => "\x1b[1;31m",
+        log::Level::Warn => "\x1b[1;33m",
+        log::Level::Info => "\x1b[32m",
+        log::Level::Debug => "\x1b[36m",
+        log::Level::Trace => "\x1b[90m",
+    }
+}
+
 #[macro_export]
 macro_rules! set_logger_level {
     ($lvl:expr) => {
         unsafe { log::set_max_level($lvl) }
     };
 }
+
+/// Mounts the log level file.
+///
+/// Reading the file returns the output of [report], writing `module=level` lines sets module levels.
+pub fn init() {
+    let file = ReportFile::with_commands(DevID::new(*MAJOR, 0), report, |line| set_arg(line).map_err(|()| IoError::InvalidData));
+    crate::task::util::block_on!(crate::fs::get_vfs().mount_dev(Box::new(file), FS_NAME))
+        .expect("Failed to mount loglevel to VFS");
+}
@@ -6,7 +6,7 @@ use x86_64::{
     },
     PhysAddr, VirtAddr,
 };
-use crate::mem::allocator::{buddy_alloc, combined_allocator, fixed_size_block};
+use crate::mem::allocator::{range_alloc, combined_allocator, fixed_size_block};
 
 // offset 0-11
 // l1 12-2
@@ -15,23 +15,32 @@ use crate::mem::allocator::{buddy_alloc, combined_allocator, fixed_size_block};
 // l4 39-47
 // quick maffs
 
+pub mod addr_space;
 pub mod allocator;
 pub mod buddy_frame_alloc;
+pub mod leak;
 mod high_order_alloc;
 pub mod mem_map;
 pub(self) mod offset_page_table;
+pub mod page;
 pub mod thread_local_storage;
 pub mod tlb;
 pub mod write_combining;
 pub mod virt_fixup;
 pub mod frame_attribute_table;
 pub mod dma;
+pub mod phys_map;
+pub mod numa;
+pub mod vm_object;
+pub mod probe;
 
 pub const PAGE_SIZE: usize = 4096;
 
 
 /// Run PreInitialization for SYS_FRAME_ALLOC
 pub unsafe fn set_sys_frame_alloc(mem_map: libboot::boot_info::MemoryMap) {
+    phys_map::PHYS_MAP.init(&mem_map);
+    crate::pstore::reserve();
     buddy_frame_alloc::init_mem_map(mem_map)
 }
 /// This is the page table tree for the higher half kernel is shared by all CPU's. It should be used
@@ -47,7 +56,7 @@ impl SysMapper {
     }
 }
 pub (crate) struct MapperWorkaround {
-    inner: crate::util::mutex::ReentrantMutexGuard<'static, combined_allocator::DualHeap<buddy_alloc::BuddyHeap, fixed_size_block::NewFixedBlockAllocator>>,
+    inner: crate::util::mutex::ReentrantMutexGuard<'static, combined_allocator::DualHeap<range_alloc::RangeHeap, fixed_size_block::NewFixedBlockAllocator>>,
 }
 
 impl core::ops::Deref for MapperWorkaround {
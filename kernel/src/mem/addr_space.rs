@@ -0,0 +1,229 @@
+//! Address spaces and switching between them.
+//!
+//! An [AddressSpace] is a level 4 page table, its higher half is shared with the kernel and its
+//! lower half belongs to the tasks which use it. A task's address space is set by
+//! [Process::set_address_space](crate::task::job::Process::set_address_space), tasks inherit the
+//! address space of the task which spawned them.
+//!
+//! The executor calls [switch] before polling a task. Tasks without an address space are kernel
+//! threads which only access the higher half, they borrow the address space which is already
+//! loaded (lazy TLB) so polling them never writes CR3. The loaded address space is kept alive by
+//! the CPU until another one is loaded, because its page tables are still in use.
+//!
+//! When the CPU supports PCIDs each address space is tagged with its own PCID and CR3 is written
+//! without invalidating the TLB, so entries survive switching between address spaces. Shootdowns
+//! only invalidate entries tagged with the loaded PCID. Instead, each address space records the
+//! [generation](super::tlb::ActiveCpus::generation) it was last loaded at on each CPU, and the TLB
+//! entries for its PCID are invalidated when it is loaded after a shootdown was raised on it or on
+//! the kernel. When more address spaces exist than PCIDs are available the remaining ones share
+//! [SHARED_PCID], which is invalidated every time it is loaded.
+//!
+//! The higher half is copied from the kernel's page table when an address space is created and
+//! when it is loaded, so level 4 entries added by the kernel are seen by every address space.
+
+use super::tlb::ActiveCpus;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use x86_64::structures::paging::{FrameAllocator, PageTable, PhysFrame, Size4KiB};
+
+const MAX_CPUS: usize = crate::task::affinity::MAX_CPUS;
+
+/// The PCID used by the kernel's page table, this is the PCID used while PCIDs are disabled.
+const KERNEL_PCID: u16 = 0;
+/// Used by address spaces created while no other PCID is free.
+const SHARED_PCID: u16 = 4095;
+/// Index of the first higher half level 4 entry.
+const HIGHER_HALF: usize = 256;
+
+/// Bit 63 of CR3, when set writing CR3 does not invalidate entries tagged with the new PCID.
+const CR3_NOFLUSH: u64 = 1 << 63;
+
+static NEXT_PCID: AtomicU16 = AtomicU16::new(KERNEL_PCID + 1);
+static FREE_PCIDS: spin::Mutex<Vec<u16>> = spin::Mutex::new(Vec::new());
+
+static KERNEL: spin::Once<Arc<AddressSpace>> = spin::Once::new();
+
+/// The address space loaded on this CPU, `None` while the kernel's page table is loaded.
+#[thread_local]
+static LOADED: Cell<Option<Arc<AddressSpace>>> = Cell::new(None);
+
+/// Whether this CPU has PCIDs enabled, `None` until [switch] first loads an address space.
+#[thread_local]
+static PCID_ENABLED: Cell<Option<bool>> = Cell::new(None);
+
+pub struct AddressSpace {
+    l4: PhysFrame,
+    table: *mut PageTable,
+    pcid: u16,
+    active: ActiveCpus,
+    /// The generation each CPU last loaded `self` at, `u64::MAX` if it has never been loaded
+    seen: [AtomicU64; MAX_CPUS],
+}
+
+// SAFETY: `table` is only modified while `self` is not loaded or by the kernel half sync, which
+// only writes entries equal to the kernel's.
+unsafe impl Send for AddressSpace {}
+unsafe impl Sync for AddressSpace {}
+
+impl AddressSpace {
+    /// Creates an address space with an empty lower half.
+    ///
+    /// Returns `None` if no memory is available for the page table.
+    pub fn new() -> Option<Arc<Self>> {
+        let l4: PhysFrame<Size4KiB> = super::DummyFrameAlloc.allocate_frame()?;
+        let offset = super::SYS_MAPPER.get().phys_offset();
+        let table = (offset + l4.start_address().as_u64()).as_mut_ptr::<PageTable>();
+        // SAFETY: The frame was just allocated and is mapped at the physical memory offset
+        unsafe { table.write(PageTable::new()) };
+
+        let space = Self {
+            l4,
+            table,
+            pcid: alloc_pcid(),
+            active: ActiveCpus::new(),
+            // A PCID may be reused, so entries tagged with it must be invalidated on first load
+            seen: [const { AtomicU64::new(u64::MAX) }; MAX_CPUS],
+        };
+        space.sync_kernel_half();
+        Some(Arc::new(space))
+    }
+
+    /// The CPUs which have `self` loaded, shootdowns for the lower half of `self` must be sent
+    /// to these CPUs using [super::tlb::shootdown_on].
+    pub fn active(&self) -> &ActiveCpus {
+        &self.active
+    }
+
+    pub fn pcid(&self) -> u16 {
+        self.pcid
+    }
+
+    /// Returns the level 4 table of `self`.
+    pub fn l4_frame(&self) -> PhysFrame {
+        self.l4
+    }
+
+    /// Changes to both `self` and the kernel's higher half require loaded entries to be invalidated.
+    fn generation(&self) -> u64 {
+        super::tlb::KERNEL.generation().wrapping_add(self.active.generation())
+    }
+
+    /// Copies the kernel's higher half level 4 entries into `self`.
+    fn sync_kernel_half(&self) {
+        let kernel = kernel();
+        if core::ptr::eq(self, &**kernel) {
+            return;
+        }
+        for i in HIGHER_HALF..512 {
+            // SAFETY: Both tables are mapped, entries are read and written as a single u64
+            unsafe {
+                let src = (kernel.table as *const u64).add(i).read_volatile();
+                let dst = (self.table as *mut u64).add(i);
+                if dst.read_volatile() != src {
+                    dst.write_volatile(src);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // Only the level 4 table is freed, the lower half must be unmapped by its users.
+        let l = super::allocator::COMBINED_ALLOCATOR.lock();
+        // SAFETY: The frame was allocated by `new` and is not loaded by any CPU, CPUs hold a reference while it is loaded
+        unsafe { l.phys_alloc().dealloc(self.l4.start_address().as_u64() as usize, 0x1000) };
+        drop(l);
+        if self.pcid != SHARED_PCID {
+            FREE_PCIDS.lock().push(self.pcid);
+        }
+    }
+}
+
+fn alloc_pcid() -> u16 {
+    if let Some(p) = FREE_PCIDS.lock().pop() {
+        return p;
+    }
+    NEXT_PCID.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |p| (p < SHARED_PCID).then_some(p + 1)).unwrap_or(SHARED_PCID)
+}
+
+/// Returns the kernel's address space, this uses the page table loaded during boot.
+pub fn kernel() -> &'static Arc<AddressSpace> {
+    KERNEL.call_once(|| {
+        let m = super::SYS_MAPPER.get();
+        let table = m.get_l4_table() as *const PageTable as *mut PageTable;
+        Arc::new(AddressSpace {
+            l4: x86_64::registers::control::Cr3::read().0,
+            table,
+            pcid: KERNEL_PCID,
+            active: ActiveCpus::new(),
+            seen: [const { AtomicU64::new(0) }; MAX_CPUS],
+        })
+    })
+}
+
+/// Enables PCIDs on this CPU if they are supported, returns whether they are enabled.
+fn pcid_enabled() -> bool {
+    if let Some(e) = PCID_ENABLED.get() {
+        return e;
+    }
+    use x86_64::registers::control::{Cr3, Cr4, Cr4Flags};
+    // PCIDE can only be set while the low 12 bits of CR3 are 0
+    let e = raw_cpuid::cpuid!(1).ecx & (1 << 17) != 0 && Cr3::read_raw().1 == 0;
+    if e {
+        // SAFETY: PCIDs are supported and the loaded PCID is 0
+        unsafe { Cr4::update(|f| f.insert(Cr4Flags::PCID)) };
+    }
+    PCID_ENABLED.set(Some(e));
+    e
+}
+
+/// Loads `next` on this CPU. When `next` is `None` the loaded address space is kept.
+///
+/// Returns whether CR3 was written.
+pub(crate) fn switch(next: Option<&Arc<AddressSpace>>) -> bool {
+    let Some(next) = next else { return false };
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let prev = LOADED.take();
+        let cur = prev.as_ref().unwrap_or_else(|| kernel());
+        if Arc::ptr_eq(cur, next) {
+            LOADED.set(prev);
+            return false;
+        }
+
+        let cpu = crate::who_am_i() as usize;
+        next.active.activate();
+        // Read after activating, see ActiveCpus::generation
+        let gen = next.generation();
+        let stale = next.seen[cpu].swap(gen, Ordering::Relaxed) != gen;
+        next.sync_kernel_half();
+
+        let mut cr3 = next.l4.start_address().as_u64();
+        if pcid_enabled() {
+            cr3 |= next.pcid as u64;
+            if !stale && next.pcid != SHARED_PCID {
+                cr3 |= CR3_NOFLUSH;
+            }
+        }
+        // SAFETY: The higher half of `next` is the kernel's, so the kernel remains mapped
+        unsafe { core::arch::asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags)) };
+
+        cur.active.deactivate();
+        LOADED.set(Some(next.clone()));
+        // `prev` may be the last reference, it is dropped after it is no longer loaded
+        drop(prev);
+        true
+    })
+}
+
+/// Returns the address space loaded on this CPU.
+pub fn current() -> Arc<AddressSpace> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let loaded = LOADED.take();
+        let r = loaded.clone().unwrap_or_else(|| kernel().clone());
+        LOADED.set(loaded);
+        r
+    })
+}
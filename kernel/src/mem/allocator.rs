@@ -14,7 +14,7 @@ pub mod page_table_allocator;
 //pub mod mmio_bump_alloc;
 pub mod alloc_interface;
 pub(self) mod allocator_linked_list;
-pub(super) mod buddy_alloc;
+pub mod range_alloc;
 pub(super) mod combined_allocator;
 
 pub struct Locked<A> {
@@ -39,15 +39,110 @@ pub const HEAP_SIZE: usize = 1024 * 1024;
 
 #[global_allocator]
 pub(super) static COMBINED_ALLOCATOR: crate::util::mutex::ReentrantMutex<
-    combined_allocator::DualHeap<buddy_alloc::BuddyHeap, fixed_size_block::NewFixedBlockAllocator>,
+    combined_allocator::DualHeap<range_alloc::RangeHeap, fixed_size_block::NewFixedBlockAllocator>,
 > = crate::util::mutex::ReentrantMutex::new(combined_allocator::DualHeap::new(
-    buddy_alloc::BuddyHeap::new(),
+    range_alloc::RangeHeap::new(),
     fixed_size_block::NewFixedBlockAllocator::new(),
 ));
 
+/// Default maximum number of bytes the heap may map. See [set_heap_ceiling]
+pub const DEFAULT_HEAP_CEILING: usize = 0x4000_0000;
+/// Usage percentages of the heap ceiling which will emit a log message when crossed.
+const HEAP_WATERMARKS: [usize; 3] = [50, 75, 90];
+/// Minimum time between reclaiming heap memory in nanoseconds.
+const RECLAIM_INTERVAL: u64 = 1_000_000_000;
+
+/// Maximum number of bytes the heap may map, see [set_heap_ceiling].
+pub(crate) static HEAP_CEILING: crate::config::Tunable<usize> = crate::config::Tunable::new(
+    "mm.heap_ceiling",
+    "Maximum number of bytes the kernel heap may map",
+    DEFAULT_HEAP_CEILING,
+);
+/// Number of bytes currently mapped into the heap
+static HEAP_MAPPED: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+/// Index+1 into [HEAP_WATERMARKS] of the highest watermark reached, and the highest one which has been logged.
+static HEAP_WATERMARK: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+static HEAP_WATERMARK_LOGGED: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+static LAST_RECLAIM: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
+
+/// Sets the maximum number of bytes the kernel heap may map.
+/// Allocations which would exceed this will fail.
+///
+/// Setting this below the currently mapped size will not release any memory.
+pub fn set_heap_ceiling(bytes: usize) {
+    HEAP_CEILING.set(bytes)
+}
+
+pub fn heap_ceiling() -> usize {
+    HEAP_CEILING.get()
+}
+
+/// Returns the number of bytes currently mapped into the kernel heap.
+pub fn heap_mapped() -> usize {
+    HEAP_MAPPED.load(atomic::Ordering::Relaxed)
+}
+
+/// Records that `bytes` will be mapped into the heap. Returns `false` if this would exceed the ceiling.
+///
+/// This is called while the allocator is locked so it must not allocate or log.
+fn heap_charge(bytes: usize) -> bool {
+    let ceiling = heap_ceiling();
+    let Ok(old) = HEAP_MAPPED.fetch_update(atomic::Ordering::Relaxed, atomic::Ordering::Relaxed, |n| {
+        n.checked_add(bytes).filter(|n| *n <= ceiling)
+    }) else {
+        return false;
+    };
+
+    let used = (old + bytes) * 100 / ceiling.max(1);
+    let mark = HEAP_WATERMARKS.iter().take_while(|m| used >= **m).count();
+    HEAP_WATERMARK.fetch_max(mark, atomic::Ordering::Relaxed);
+    true
+}
+
+fn heap_uncharge(bytes: usize) {
+    HEAP_MAPPED.fetch_sub(bytes, atomic::Ordering::Relaxed);
+}
+
+/// Returns fully free heap memory back to the frame allocator and logs any heap watermarks which
+/// have been crossed.
+///
+/// This is intended to be called when a CPU is idle, it will return immediately if it was
+/// called less than [RECLAIM_INTERVAL] ago or if the allocator is in use.
+pub fn idle_reclaim() {
+    let now = crate::time::get_sys_time();
+    let last = LAST_RECLAIM.load(atomic::Ordering::Relaxed);
+    if now.saturating_sub(last) < RECLAIM_INTERVAL
+        || LAST_RECLAIM.compare_exchange(last, now, atomic::Ordering::Relaxed, atomic::Ordering::Relaxed).is_err()
+    {
+        return;
+    }
+
+    let mark = HEAP_WATERMARK.load(atomic::Ordering::Relaxed);
+    if mark > HEAP_WATERMARK_LOGGED.swap(mark, atomic::Ordering::Relaxed) {
+        log::warn!("Kernel heap above {}% of ceiling: {:#x}/{:#x} bytes mapped", HEAP_WATERMARKS[mark - 1], heap_mapped(), heap_ceiling());
+    }
+
+    let Some(l) = COMBINED_ALLOCATOR.try_lock_inner() else { return };
+    let freed = l.reclaim();
+    drop(l);
+
+    if freed > 0 {
+        log::trace!("Reclaimed {freed:#x} bytes from the kernel heap");
+        let used = heap_mapped() * 100 / heap_ceiling().max(1);
+        let mark = HEAP_WATERMARKS.iter().take_while(|m| used >= **m).count();
+        HEAP_WATERMARK.store(mark, atomic::Ordering::Relaxed);
+        HEAP_WATERMARK_LOGGED.fetch_min(mark, atomic::Ordering::Relaxed);
+    }
+}
+
+/// Returns statistics for the kernel heaps virtual allocator.
+pub fn virt_alloc_stats() -> range_alloc::VirtAllocStats {
+    COMBINED_ALLOCATOR.lock().virt_stats()
+}
+
 /// Maps memory to addr and uses it to initialize the allocator
 pub unsafe fn init_comb_heap(addr: usize) {
-    assert_eq!(addr & (buddy_alloc::ORDER_MAX_SIZE - 1), 0);
+    assert_eq!(addr & (mem::PAGE_SIZE - 1), 0);
 
     let ptr = addr as *mut u8;
     let mut lock = COMBINED_ALLOCATOR.lock();
@@ -143,62 +238,3 @@ pub(crate) unsafe trait HeapAlloc {
 
     fn virt_deallocate(&self, ptr: NonNull<u8>, layout: Layout);
 }
-
-mod memory_counter {
-
-    /// Struct for recording memory usage
-    #[derive(Debug)]
-    pub(crate) struct MemoryCounter {
-        free: usize,
-        used: usize,
-    }
-
-    impl MemoryCounter {
-        /// Creates a new `MemoryCounter` with `size` free bytes
-        pub const fn new(size: usize) -> Self {
-            Self {
-                free: 0,
-                used: size,
-            }
-        }
-
-        /// Adds `count` to the free count and subtracts it from used count returning the new free count.
-        /// Returns `Err()` if `count` is greater than `self.used`
-        ///
-        /// #Panics
-        ///
-        /// This fn will panic if `self.free + count` overflows
-        pub fn free(&mut self, count: usize) -> Result<usize, usize> {
-            if self.used >= count {
-                self.free += count;
-                self.used -= count;
-                Ok(self.free)
-            } else {
-                Err(self.free)
-            }
-        }
-
-        /// Acts the same as [Self::free] but adding memory to used and removing it from free.
-        pub fn reserve(&mut self, count: usize) -> Result<usize, usize> {
-            if self.free >= count {
-                self.used += count;
-                self.free -= count;
-                Ok(self.used)
-            } else {
-                Err(self.used)
-            }
-        }
-
-        /// Adds `count` to `self.free` ignoring `self.used`
-        pub fn extend(&mut self, count: usize) {
-            self.free += count
-        }
-
-        pub fn get_used(&self) -> usize {
-            self.used
-        }
-        pub fn get_free(&self) -> usize {
-            self.free
-        }
-    }
-}
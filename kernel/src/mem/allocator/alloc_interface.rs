@@ -112,14 +112,17 @@ unsafe impl Allocator for MmioAlloc {
 
         let ptr = NonNull::new(addr as *mut u8).expect("Tried to deallocate illegal address");
 
-        for page in pages {
-            mem::SYS_MAPPER
-                .get()
-                .unmap(page)
-                .expect("Tried to deallocate unhandled memory")
-                .1
-                .flush();
-        }
+        // The virtual region is released after the batch so it can't be reused before it's flushed
+        mem::tlb::batch(|| {
+            for page in pages {
+                mem::SYS_MAPPER
+                    .get()
+                    .unmap(page)
+                    .expect("Tried to deallocate unhandled memory")
+                    .1
+                    .flush();
+            }
+        });
 
         super::COMBINED_ALLOCATOR
             .lock()
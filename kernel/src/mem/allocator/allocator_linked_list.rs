@@ -46,6 +46,42 @@ impl RawLinkedList {
         };
     }
 
+    /// Returns whether a node at `addr` is present in the list
+    pub fn contains(&self, addr: usize) -> bool {
+        let mut next = self.head.as_ref();
+        while let Some(n) = next {
+            if n.get_addr() == addr {
+                return true;
+            }
+            next = n.next.as_ref();
+        }
+        false
+    }
+
+    /// Calls `f` with the address of each node in the list
+    pub fn for_each<F: FnMut(usize)>(&self, mut f: F) {
+        let mut next = self.head.as_ref();
+        while let Some(n) = next {
+            f(n.get_addr());
+            next = n.next.as_ref();
+        }
+    }
+
+    /// Removes the node at `addr` from the list. Returns `false` if the node was not present.
+    pub fn remove(&mut self, addr: usize) -> bool {
+        let mut cursor = &mut self.head;
+        while cursor.is_some() {
+            if cursor.as_ref().unwrap().get_addr() == addr {
+                let n = cursor.take().unwrap();
+                *cursor = n.next.take();
+                self.len -= 1;
+                return true;
+            }
+            cursor = &mut cursor.as_mut().unwrap().next;
+        }
+        false
+    }
+
     #[cfg(feature = "alloc-debug-serial")]
     pub fn dump(&self) {
         let mut next = self.head.as_ref();
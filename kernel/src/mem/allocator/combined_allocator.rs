@@ -53,6 +53,16 @@ impl<S: SuperiorAllocator, I: InferiorAllocator> DualHeap<S, I> {
     pub(crate) fn phys_alloc(&self) -> &buddy_frame_alloc::BuddyFrameAlloc {
         &self.phys_alloc
     }
+
+    pub(super) fn virt_stats(&self) -> super::range_alloc::VirtAllocStats {
+        self.superior.stats()
+    }
+
+    /// Returns unused memory held by the inferior allocator to the system.
+    /// Returns the number of bytes reclaimed.
+    pub(super) fn reclaim(&self) -> usize {
+        self.inferior.reclaim()
+    }
 }
 
 unsafe impl<S: SuperiorAllocator, I: InferiorAllocator> core::alloc::GlobalAlloc
@@ -65,15 +75,25 @@ unsafe impl<S: SuperiorAllocator, I: InferiorAllocator> core::alloc::GlobalAlloc
         };
         use x86_64::VirtAddr;
 
+        if crate::fault_inject::HEAP.should_fail() {
+            return core::ptr::null_mut();
+        }
+        if !crate::task::rlimit::charge_heap(layout.size()) {
+            return core::ptr::null_mut();
+        }
         let alloc = self.lock();
 
         let cmp = layout.size().max(layout.align());
 
-        return if cmp < 2048 {
+        let ret = if cmp < 2048 {
             // inferior max size
-            let t = alloc.inferior.allocate(layout).unwrap().cast().as_ptr(); // panic is expected on fail
-            t
+            alloc.inferior.allocate(layout).map_or(core::ptr::null_mut(), |p| p.cast().as_ptr())
         } else {
+            if !super::heap_charge(S::allocated_size(layout)) {
+                drop(alloc);
+                crate::task::rlimit::uncharge_heap(layout.size());
+                return core::ptr::null_mut();
+            }
             let ret = alloc
                 .superior
                 .virt_allocate(layout)
@@ -95,12 +115,21 @@ unsafe impl<S: SuperiorAllocator, I: InferiorAllocator> core::alloc::GlobalAlloc
 
             ret
         };
+        drop(alloc);
+        if ret.is_null() {
+            crate::task::rlimit::uncharge_heap(layout.size());
+        } else {
+            mem::leak::record_alloc(ret, layout.size());
+        }
+        ret
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         use x86_64::structures::paging::page::{Page, PageRangeInclusive, Size4KiB};
         use x86_64::VirtAddr;
 
+        crate::task::rlimit::uncharge_heap(layout.size());
+        mem::leak::record_dealloc(ptr);
         let cmp = layout.size().max(layout.align());
         if cmp < 2048 {
             self.lock()
@@ -119,7 +148,7 @@ unsafe impl<S: SuperiorAllocator, I: InferiorAllocator> core::alloc::GlobalAlloc
                 super::super::mem_map::unmap_and_free(i).expect("Failed to free memory");
             }
 
-            // TODO: deallocate frames
+            super::heap_uncharge(S::allocated_size(layout));
 
             self.lock()
                 .superior
@@ -247,6 +276,10 @@ impl SuperiorAllocator for InteriorAlloc {
     fn allocated_size(_layout: Layout) -> usize {
         panic!("unable to call init on InteriorAlloc")
     }
+
+    fn stats(&self) -> super::range_alloc::VirtAllocStats {
+        super::COMBINED_ALLOCATOR.lock().superior.stats()
+    }
 }
 
 impl InferiorAllocator for InteriorAlloc {
@@ -269,11 +302,21 @@ pub(crate) trait SuperiorAllocator: HeapAlloc {
     unsafe fn init(&mut self, addr: usize);
 
     fn allocated_size(layout: Layout) -> usize;
+
+    /// Returns allocation statistics for diagnostics
+    fn stats(&self) -> super::range_alloc::VirtAllocStats;
 }
 
 pub trait InferiorAllocator: core::alloc::Allocator {
     const INIT_BYTES: usize;
 
+    /// Returns fully free memory back to the system. Returns the number of bytes released.
+    ///
+    /// The default implementation releases nothing.
+    fn reclaim(&self) -> usize {
+        0
+    }
+
     /// Initializes allocator using memory at `ptr`
     ///
     /// #Safety
@@ -144,9 +144,15 @@ impl NewFixedBlockAllocator {
     }
 
     /// Extends self mapping a page from the superior allocator and adding it to the free list.
-    fn extend(&self) {
+    ///
+    /// Returns `Err(AllocError)` if the heap ceiling has been reached.
+    fn extend(&self) -> Result<(), AllocError> {
         use x86_64::structures::paging::page_table::PageTableFlags;
 
+        if !super::heap_charge(mem::PAGE_SIZE) {
+            return Err(AllocError);
+        }
+
         // SAFETY this is safe because self's parent is interior alloc
         let alloc = unsafe { InteriorAlloc::new() };
         let layout = Layout::from_size_align(mem::PAGE_SIZE, mem::PAGE_SIZE).unwrap(); // will not panic
@@ -170,6 +176,49 @@ impl NewFixedBlockAllocator {
             // This is safe because
             self.force_dealloc(ptr.cast(), layout)
         }
+        Ok(())
+    }
+
+    /// Returns pages which are entirely free back to the superior allocator and frees their frames.
+    /// Only pages which were split into the largest block size and have not been cracked are
+    /// reclaimed, the page given to [InferiorAllocator::init] is never reclaimed.
+    ///
+    /// Returns the number of bytes reclaimed.
+    fn reclaim_pages(&self) -> usize {
+        const BATCH: usize = 32;
+        let alloc = unsafe { &mut *self.inner.get() };
+        let largest = *BLOCK_SIZES.last().unwrap();
+        let init_page = alloc.start as usize & !(mem::PAGE_SIZE - 1);
+
+        let mut found = [0usize; BATCH];
+        let mut count = 0;
+        let list = alloc.list_heads.last().unwrap();
+        list.for_each(|addr| {
+            if count < BATCH
+                && addr & (mem::PAGE_SIZE - 1) == 0
+                && addr != init_page
+                && (1..mem::PAGE_SIZE / largest).all(|i| list.contains(addr + i * largest))
+            {
+                found[count] = addr;
+                count += 1;
+            }
+        });
+
+        for &page in &found[..count] {
+            let list = alloc.list_heads.last_mut().unwrap();
+            for i in 0..mem::PAGE_SIZE / largest {
+                list.remove(page + i * largest);
+            }
+
+            // SAFETY: All blocks within the page were free and have been removed from the list
+            unsafe { mem::mem_map::unmap_and_free(VirtAddr::new(page as u64)) }.expect("Heap page was not mapped");
+            // SAFETY: this is safe because self's parent is interior alloc
+            let sup = unsafe { InteriorAlloc::new() };
+            HeapAlloc::virt_deallocate(&sup, NonNull::new(page as *mut u8).unwrap(), Layout::from_size_align(mem::PAGE_SIZE, mem::PAGE_SIZE).unwrap());
+            super::heap_uncharge(mem::PAGE_SIZE);
+        }
+
+        count * mem::PAGE_SIZE
     }
 }
 
@@ -250,7 +299,7 @@ unsafe impl Allocator for NewFixedBlockAllocator {
                 #[cfg(feature = "alloc-debug-serial")]
                 serial_println!("extending");
 
-                self.extend();
+                self.extend()?;
 
                 #[cfg(feature = "alloc-debug-serial")]
                 alloc.list_heads[index].dump();
@@ -306,6 +355,10 @@ unsafe impl Allocator for NewFixedBlockAllocator {
 impl InferiorAllocator for NewFixedBlockAllocator {
     const INIT_BYTES: usize = 4096;
 
+    fn reclaim(&self) -> usize {
+        self.reclaim_pages()
+    }
+
     unsafe fn init(&self, ptr: *mut [u8; Self::INIT_BYTES]) {
         let alloc = unsafe { &mut *self.inner.get() };
         let initial_block_size = *BLOCK_SIZES.last().unwrap();
@@ -0,0 +1,262 @@
+//! Virtual address range allocator used as the superior allocator for the kernel heap.
+//!
+//! Free ranges are stored as extents sorted by address so they can be coalesced when freed.
+//! Allocations use a best-fit search, extents are counted by size class (log2 of the length) so
+//! searches which can't succeed are skipped without scanning the list.
+//!
+//! Extents are stored inline because any allocation made by this allocator may recurse into itself
+//! via the inferior allocator.
+
+use super::combined_allocator::SuperiorAllocator;
+use crate::mem::PAGE_SIZE;
+use core::alloc::{AllocError, Layout};
+use core::ptr::NonNull;
+
+/// Maximum number of free extents. When this is exceeded freed memory is leaked, see [VirtAllocStats::leaked]
+const MAX_EXTENTS: usize = 512;
+const CLASSES: usize = usize::BITS as usize;
+/// Minimum number of bytes the heap will grow by
+const GROW_MIN: usize = 0x20_0000;
+
+/// Statistics for the virtual range allocator
+#[derive(Copy, Clone, Debug, Default)]
+pub struct VirtAllocStats {
+    /// Size of the managed virtual region in bytes
+    pub heap_size: usize,
+    /// Number of bytes currently allocated
+    pub allocated: usize,
+    /// Number of currently live allocations
+    pub allocations: usize,
+    /// Number of free extents
+    pub free_extents: usize,
+    /// Size of the largest free extent in bytes
+    pub largest_free: usize,
+    /// Number of times the heap has been grown
+    pub grows: usize,
+    /// Number of bytes which could not be returned to the free list because it was full.
+    pub leaked: usize,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Extent {
+    start: usize,
+    len: usize,
+}
+
+impl Extent {
+    fn end(&self) -> usize {
+        self.start + self.len
+    }
+}
+
+pub struct RangeHeap {
+    inner: core::cell::UnsafeCell<RangeHeapInner>,
+}
+
+struct RangeHeapInner {
+    start: usize,
+    end: usize,
+    count: usize,
+    /// Sorted by start address, only `..count` is valid
+    extents: [Extent; MAX_EXTENTS],
+    /// Number of extents in each size class
+    class_count: [u32; CLASSES],
+    stats: VirtAllocStats,
+}
+
+impl RangeHeap {
+    /// Creates an uninitialized instance of `Self`
+    pub const fn new() -> Self {
+        Self {
+            inner: core::cell::UnsafeCell::new(RangeHeapInner {
+                start: 0,
+                end: 0,
+                count: 0,
+                extents: [Extent { start: 0, len: 0 }; MAX_EXTENTS],
+                class_count: [0; CLASSES],
+                stats: VirtAllocStats {
+                    heap_size: 0,
+                    allocated: 0,
+                    allocations: 0,
+                    free_extents: 0,
+                    largest_free: 0,
+                    grows: 0,
+                    leaked: 0,
+                },
+            }),
+        }
+    }
+
+    /// Returns the allocation size and alignment used for `layout`
+    fn size_align(layout: Layout) -> (usize, usize) {
+        (layout.size().max(1).next_multiple_of(PAGE_SIZE), layout.align().max(PAGE_SIZE))
+    }
+}
+
+fn class_of(len: usize) -> usize {
+    (usize::BITS - 1 - len.leading_zeros()) as usize
+}
+
+impl RangeHeapInner {
+    fn insert_at(&mut self, i: usize, e: Extent) -> Result<(), ()> {
+        if self.count == MAX_EXTENTS {
+            return Err(());
+        }
+        self.extents[i..=self.count].rotate_right(1);
+        self.extents[i] = e;
+        self.count += 1;
+        self.class_count[class_of(e.len)] += 1;
+        Ok(())
+    }
+
+    fn remove_at(&mut self, i: usize) -> Extent {
+        let e = self.extents[i];
+        self.extents[i..self.count].rotate_left(1);
+        self.count -= 1;
+        self.class_count[class_of(e.len)] -= 1;
+        e
+    }
+
+    fn set(&mut self, i: usize, e: Extent) {
+        self.class_count[class_of(self.extents[i].len)] -= 1;
+        self.class_count[class_of(e.len)] += 1;
+        self.extents[i] = e;
+    }
+
+    /// Returns `start..start+len` to the free list merging it with adjacent extents.
+    fn free_range(&mut self, start: usize, len: usize) {
+        let i = self.extents[..self.count].partition_point(|e| e.start < start);
+        let prev = (i > 0).then(|| self.extents[i - 1]).filter(|p| p.end() == start);
+        let next = (i < self.count).then(|| self.extents[i]).filter(|n| start + len == n.start);
+
+        match (prev, next) {
+            (Some(p), Some(n)) => {
+                self.remove_at(i);
+                self.set(i - 1, Extent { start: p.start, len: p.len + len + n.len });
+            }
+            (Some(p), None) => self.set(i - 1, Extent { start: p.start, len: p.len + len }),
+            (None, Some(n)) => self.set(i, Extent { start, len: len + n.len }),
+            (None, None) => {
+                if self.insert_at(i, Extent { start, len }).is_err() {
+                    self.stats.leaked += len;
+                }
+            }
+        }
+    }
+
+    /// Locates the best fitting extent for the requested size and alignment.
+    /// Returns the index of the extent and the aligned address within it.
+    fn find(&self, size: usize, align: usize) -> Option<(usize, usize)> {
+        if self.class_count[class_of(size)..].iter().all(|c| *c == 0) {
+            return None;
+        }
+
+        let mut best: Option<(usize, usize, usize)> = None;
+        for (i, e) in self.extents[..self.count].iter().enumerate() {
+            if e.len < size {
+                continue;
+            }
+            let aligned = e.start.next_multiple_of(align);
+            if aligned + size > e.end() {
+                continue;
+            }
+            let waste = e.len - size;
+            if best.map_or(true, |(_, _, w)| waste < w) {
+                best = Some((i, aligned, waste));
+                if waste == 0 {
+                    break;
+                }
+            }
+        }
+        best.map(|(i, a, _)| (i, a))
+    }
+
+    /// Removes `aligned..aligned+size` from the extent at `i`
+    fn take(&mut self, i: usize, aligned: usize, size: usize) {
+        let e = self.extents[i];
+        let head = aligned - e.start;
+        let tail = e.end() - (aligned + size);
+        match (head > 0, tail > 0) {
+            (false, false) => {
+                self.remove_at(i);
+            }
+            (true, false) => self.set(i, Extent { start: e.start, len: head }),
+            (false, true) => self.set(i, Extent { start: aligned + size, len: tail }),
+            (true, true) => {
+                self.set(i, Extent { start: e.start, len: head });
+                if self.insert_at(i + 1, Extent { start: aligned + size, len: tail }).is_err() {
+                    self.stats.leaked += tail;
+                }
+            }
+        }
+    }
+
+    /// Extends the end of the heap so that an allocation of `size` with `align` will fit
+    fn grow(&mut self, size: usize, align: usize) {
+        let len = (size + align).next_multiple_of(PAGE_SIZE).max(GROW_MIN);
+        let old = self.end;
+        self.end += len;
+        self.stats.grows += 1;
+        self.free_range(old, len);
+    }
+}
+
+unsafe impl super::HeapAlloc for RangeHeap {
+    fn virt_allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        // SAFETY: This is only accessed within COMBINED_ALLOCATOR's mutex
+        let alloc = unsafe { &mut *self.inner.get() };
+        let (size, align) = Self::size_align(layout);
+
+        let (i, addr) = match alloc.find(size, align) {
+            Some(r) => r,
+            None => {
+                alloc.grow(size, align);
+                alloc.find(size, align).ok_or(AllocError)?
+            }
+        };
+        alloc.take(i, addr, size);
+        alloc.stats.allocated += size;
+        alloc.stats.allocations += 1;
+
+        // SAFETY: This region is unused and is not accessed here
+        Ok(NonNull::new(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, size) }).unwrap())
+    }
+
+    fn virt_deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // SAFETY: This is only accessed within COMBINED_ALLOCATOR's mutex
+        let alloc = unsafe { &mut *self.inner.get() };
+        let (size, _) = Self::size_align(layout);
+        let addr = ptr.as_ptr() as usize;
+        assert!(addr >= alloc.start && addr + size <= alloc.end, "Tried to deallocate illegal pointer");
+
+        alloc.free_range(addr, size);
+        alloc.stats.allocated -= size;
+        alloc.stats.allocations -= 1;
+    }
+}
+
+impl SuperiorAllocator for RangeHeap {
+    unsafe fn init(&mut self, addr: usize) {
+        let alloc = self.inner.get_mut();
+        assert_eq!(addr & (PAGE_SIZE - 1), 0);
+
+        // the first page is used by the inferior allocator
+        alloc.start = addr;
+        alloc.end = addr + PAGE_SIZE;
+    }
+
+    fn allocated_size(layout: Layout) -> usize {
+        Self::size_align(layout).0
+    }
+
+    fn stats(&self) -> VirtAllocStats {
+        // SAFETY: This is only accessed within COMBINED_ALLOCATOR's mutex
+        let alloc = unsafe { &*self.inner.get() };
+        VirtAllocStats {
+            heap_size: alloc.end - alloc.start,
+            free_extents: alloc.count,
+            largest_free: alloc.extents[..alloc.count].iter().map(|e| e.len).max().unwrap_or(0),
+            ..alloc.stats
+        }
+    }
+}
@@ -156,6 +156,18 @@ impl PreInitFrameAlloc {
     }
 }
 
+/// Allocates a frame from `MEM_MAP` skipping any frames reserved in [super::phys_map::PHYS_MAP].
+/// Skipped frames are leaked, they are owned by whoever reserved them.
+fn pre_init_alloc(region: MemRegion) -> Option<usize> {
+    let mut map = MEM_MAP.get();
+    loop {
+        let ret = map.alloc(region)?;
+        if !super::phys_map::PHYS_MAP.is_reserved(PhysAddr::new(ret as u64), PAGE_SIZE as u64) {
+            return Some(ret);
+        }
+    }
+}
+
 /// Properly initializes [super::SYS_FRAME_ALLOC] this cannot be done too early to because it relies
 /// on [alloc::alloc::Global]. Drains the unused memory given to [init_mem_map] into
 /// `super::SYS_FRAME_ALLOC`. This fn should be called immediately after initializing the Global heap.
@@ -242,8 +254,21 @@ fn drain_map_inner(region: MemRegion) {
 
         drop(f_alloc);
 
-        // SAFETY: region is given by frame allocator and is unused
-        unsafe { super::allocator::COMBINED_ALLOCATOR.lock().phys_alloc().dealloc(base, len) }
+        // Frames reserved in the physical memory map are never given to the allocator
+        let mut cursor = base;
+        for i in (base..base + len).step_by(PAGE_SIZE) {
+            if super::phys_map::PHYS_MAP.is_reserved(PhysAddr::new(i as u64), PAGE_SIZE as u64) {
+                if cursor < i {
+                    // SAFETY: region is given by frame allocator and is unused
+                    unsafe { super::allocator::COMBINED_ALLOCATOR.lock().phys_alloc().dealloc(cursor, i - cursor) }
+                }
+                cursor = i + PAGE_SIZE;
+            }
+        }
+        if cursor < base + len {
+            // SAFETY: region is given by frame allocator and is unused
+            unsafe { super::allocator::COMBINED_ALLOCATOR.lock().phys_alloc().dealloc(cursor, base + len - cursor) }
+        }
 
         #[cfg(feature = "alloc-debug-serial")]
         {
@@ -316,6 +341,26 @@ impl DmaRegion {
         }
     }
 
+    /// Retrieves a block of size `order` where `pred` returns `true` for the blocks address and size.
+    /// Larger blocks are split as necessary, this will not fetch memory from the high order allocator.
+    fn fetch_where<F: Fn(usize, usize) -> bool>(&mut self, order: usize, pred: F) -> Option<usize> {
+        for i in order..ORDERS {
+            let mut cursor = self.free_list[i].cursor_front_mut();
+            while let Some(block) = cursor.current() {
+                // The returned block is the start of the larger block
+                if pred(*block, Self::block_size(order)) {
+                    let block = cursor.remove_current().unwrap(); // current is Some
+                    for j in (order..i).rev() {
+                        self.free_list[j].push_front(block ^ Self::block_size(j));
+                    }
+                    return Some(block);
+                }
+                cursor.move_next();
+            }
+        }
+        None
+    }
+
     /// Breaks unused blocks to create at least one free block at the target order.
     fn split(&mut self, order: usize) -> Result<(), ()> {
         for i in order..ORDERS {
@@ -454,6 +499,18 @@ impl super::MemRegion {
 }
 
 impl FrameAllocInner {
+    /// Attempts to allocate `size` bytes from memory within the NUMA node `node`.
+    /// Falls back to lower regions like [Self::allocate] but never to another node.
+    fn allocate_on_node(&mut self, size: usize, region: MemRegion, node: super::numa::NodeId) -> Option<usize> {
+        let order = DmaRegion::order_from_exact_size(size)?;
+        let pred = |b: usize, len: usize| super::numa::range_in_node(PhysAddr::new(b as u64), len as u64, node);
+        region.list(self).fetch_where(order, pred).or_else(|| match region {
+            MemRegion::Mem16 => None,
+            MemRegion::Mem32 => self.allocate_on_node(size, MemRegion::Mem16, node),
+            MemRegion::Mem64 => self.allocate_on_node(size, MemRegion::Mem32, node),
+        })
+    }
+
     /// Attempt to allocate `size` bytes from  physical memory size will be aligned to the next
     /// highest power of two
     ///
@@ -725,7 +782,7 @@ impl BuddyFrameAlloc {
             Some(r) => {
                 assert!(layout.align() <= 0x1000);
                 assert_eq!(layout.size(), 0x1000);
-                if let Some(ret) = MEM_MAP.get().alloc(region) {
+                if let Some(ret) = pre_init_alloc(region) {
                     return Some(ret);
                 } else if r == MemRegion::Mem16 {
                     // This is a hack fix to prevent my single frame of free mem16 memory from being
@@ -741,13 +798,22 @@ impl BuddyFrameAlloc {
             None => {
                 assert!(layout.align() <= 0x1000);
                 assert_eq!(layout.size(), 0x1000);
-                return MEM_MAP.get().alloc(region);
+                return pre_init_alloc(region);
             }
         }
 
+        if crate::fault_inject::FRAME.should_fail() {
+            return None;
+        }
 
         if limit > ORDER_MAX_SIZE {
             self.alloc_huge(layout, region)
+        } else if let Some((node, bind)) = super::numa::alloc_target() {
+            match alloc.allocate_on_node(limit, region, node) {
+                Some(r) => Some(r),
+                None if bind => None,
+                None => alloc.allocate(limit, region),
+            }
         } else {
             alloc.allocate(limit, region)
         }
@@ -77,7 +77,7 @@ unsafe impl<T,C> DmaClaimable for DmaGuard<T, C> where Self: DmaTarget {
     }
 
     fn query_owned(&self) -> bool {
-        self.lock.as_ref().is_some_and(|v| v.load(atomic::Ordering::Acquire))
+        !self.lock.as_ref().is_some_and(|v| v.load(atomic::Ordering::Acquire))
     }
 }
 
@@ -134,6 +134,33 @@ unsafe impl<T: ?Sized + Send> DmaTarget for StackDmaGuard<'_, T> {
     }
 }
 
+/// Restricts a [DmaBuff] to a sub-region of its buffer.
+pub struct DmaSlice<'a> {
+    inner: DmaBuff<'a>,
+    range: core::ops::Range<usize>,
+}
+
+impl<'a> DmaSlice<'a> {
+    /// # Panics
+    ///
+    /// This fn will panic if `range` is not within `inner`.
+    pub fn new(mut inner: DmaBuff<'a>, range: core::ops::Range<usize>) -> Self {
+        assert!(range.start <= range.end && range.end <= inner.as_mut().len(), "DmaSlice: {range:?} out of bounds");
+        Self { inner, range }
+    }
+
+    pub fn into_inner(self) -> DmaBuff<'a> {
+        self.inner
+    }
+}
+
+unsafe impl DmaTarget for DmaSlice<'_> {
+    fn as_mut(&mut self) -> *mut [u8] {
+        let data = self.inner.as_mut();
+        // SAFETY: The range was checked when self was constructed.
+        unsafe { &mut (&mut *data)[self.range.clone()] as *mut [u8] }
+    }
+}
 
 mod sealed {
     pub trait Sealed {}
@@ -170,23 +197,21 @@ impl Iterator for PhysicalRegionDescriber<'_> {
         let base = self.next_chunk(self.next)?;
         // SAFETY: I think this is unsound
         let data = unsafe { & *self.data };
+        let remain = data.len() - self.next;
 
-        let mut diff = super::PAGE_SIZE - (base as usize & (super::PAGE_SIZE-1)).min(data.len()); // diff between next index and base
+        let mut diff = (super::PAGE_SIZE - (base as usize & (super::PAGE_SIZE-1))).min(remain); // diff between next index and base
 
-        loop {
+        while diff < remain {
             match self.next_chunk(diff + self.next) {
                 // Ok(_) ensures that this is offset is valid
                 // match guard checks that addr is contiguous
-                Some(addr) if addr - base == diff as u64 => {
+                Some(addr) if addr.wrapping_sub(base) == diff as u64 => {
                     diff += super::PAGE_SIZE;
-                    diff = diff.min(data.len()); // make sure we dont overflow
+                    diff = diff.min(remain); // make sure we dont overflow
                 }
                 // When either of the above checks fail we have reached the end of the region
                 _ => break,
             }
-            if diff == data.len() {
-                break;
-            }
         }
 
         self.next += diff;
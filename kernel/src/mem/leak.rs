@@ -0,0 +1,323 @@
+//! Heap leak detection.
+//!
+//! When [TRACK] is enabled each heap allocation is recorded in a table along with its [Owner],
+//! the subsystem entered with [enter] or otherwise the task which was being polled. Freeing the
+//! allocation removes it from the table, regardless of which task frees it, so the table contains
+//! the live allocations of each owner.
+//!
+//! The `/bin/leakcheck` program compares the live allocations of each owner between two points in
+//! time. Running `leakcheck snapshot [NAME]` before a soak test and `leakcheck diff [NAME]` after it
+//! lists the owners whose allocations grew, these are written to the serial port. `leakcheck diff
+//! A B` compares two snapshots and `leakcheck show` lists the current allocations.
+//!
+//! Allocations made before tracking was enabled are not recorded, so [TRACK] should be enabled on
+//! the command line. Disabling tracking discards the table. Allocations which do not fit into the
+//! table are counted but are not recorded.
+
+use crate::config::Tunable;
+use crate::task::spawn::Args;
+use crate::task::{TaskId, TaskResult};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Whether heap allocations are recorded.
+pub static TRACK: Tunable<bool> = Tunable::with_hook("leakcheck", "Record the owner of each heap allocation", false, set_tracking);
+
+/// Number of allocations which can be recorded.
+const CAPACITY: usize = 1 << 16;
+
+/// Allocations which could not be recorded because the table was full.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// The party responsible for an allocation.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Owner {
+    /// Allocated outside a task and outside a subsystem.
+    Kernel,
+    Task(TaskId),
+    Subsystem(&'static str),
+}
+
+impl core::fmt::Display for Owner {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Kernel => f.write_str("kernel"),
+            Self::Task(id) => write!(f, "task {id}"),
+            Self::Subsystem(name) => f.write_str(name),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    /// Address of the allocation, `0` if the entry is unused.
+    addr: usize,
+    size: usize,
+    owner: Owner,
+}
+
+impl Entry {
+    const EMPTY: Self = Self { addr: 0, size: 0, owner: Owner::Kernel };
+}
+
+/// Open addressed table of live allocations, indexed by address.
+///
+/// This is locked with interrupts disabled by the allocator, it must not allocate while locked.
+static TABLE: spin::Mutex<Option<&'static mut [Entry]>> = spin::Mutex::new(None);
+
+fn slot(addr: usize) -> usize {
+    (addr >> 4).wrapping_mul(0x9e37_79b9_7f4a_7c15) % CAPACITY
+}
+
+/// Subsystem entered by the code running on this CPU.
+#[thread_local]
+static SUBSYSTEM: core::cell::Cell<Option<&'static str>> = core::cell::Cell::new(None);
+
+/// Attributes allocations made on this CPU to `name` until the returned guard is dropped.
+///
+/// The guard must not be held across an `.await`.
+pub fn enter(name: &'static str) -> SubsystemGuard {
+    SubsystemGuard { prev: SUBSYSTEM.replace(Some(name)) }
+}
+
+pub struct SubsystemGuard {
+    prev: Option<&'static str>,
+}
+
+impl Drop for SubsystemGuard {
+    fn drop(&mut self) {
+        SUBSYSTEM.set(self.prev);
+    }
+}
+
+fn current_owner() -> Owner {
+    if let Some(name) = SUBSYSTEM.get() {
+        return Owner::Subsystem(name);
+    }
+    crate::task::job::current_id().map_or(Owner::Kernel, Owner::Task)
+}
+
+fn set_tracking(enable: bool) {
+    use x86_64::instructions::interrupts::without_interrupts;
+    let old = if enable {
+        if without_interrupts(|| TABLE.lock().is_some()) {
+            return;
+        }
+        let table = Box::leak(alloc::vec![Entry::EMPTY; CAPACITY].into_boxed_slice());
+        without_interrupts(|| TABLE.lock().replace(table))
+    } else {
+        DROPPED.store(0, Ordering::Relaxed);
+        without_interrupts(|| TABLE.lock().take())
+    };
+    // Freed after the lock is released, the allocator locks the table
+    if let Some(old) = old {
+        // SAFETY: The table was leaked above and is no longer referenced
+        drop(unsafe { Box::from_raw(old as *mut [Entry]) });
+    }
+}
+
+/// Records an allocation, called by the global allocator. `addr` must not be null.
+pub(crate) fn record_alloc(addr: *mut u8, size: usize) {
+    // The TLS may not be initialized yet.
+    if !TRACK.get() || crate::runlevel::runlevel() == crate::runlevel::Runlevel::PreInit {
+        return;
+    }
+    let owner = current_owner();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut l = TABLE.lock();
+        let Some(table) = l.as_mut() else { return };
+        let start = slot(addr as usize);
+        for i in 0..CAPACITY {
+            let e = &mut table[(start + i) % CAPACITY];
+            if e.addr == 0 {
+                *e = Entry { addr: addr as usize, size, owner };
+                return;
+            }
+        }
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    })
+}
+
+/// Removes an allocation, called by the global allocator.
+pub(crate) fn record_dealloc(addr: *mut u8) {
+    if !TRACK.get() || crate::runlevel::runlevel() == crate::runlevel::Runlevel::PreInit {
+        return;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut l = TABLE.lock();
+        let Some(table) = l.as_mut() else { return };
+        let mut i = slot(addr as usize);
+        for _ in 0..CAPACITY {
+            match table[i].addr {
+                0 => return,
+                a if a == addr as usize => break,
+                _ => i = (i + 1) % CAPACITY,
+            }
+        }
+        if table[i].addr != addr as usize {
+            return;
+        }
+
+        // Backward shift deletion, entries after the hole which may be moved into it are moved
+        let mut hole = i;
+        let mut j = i;
+        loop {
+            table[hole] = Entry::EMPTY;
+            loop {
+                j = (j + 1) % CAPACITY;
+                if table[j].addr == 0 {
+                    return;
+                }
+                let home = slot(table[j].addr);
+                // Moves `j` when its home slot is not cyclically within (hole, j]
+                let stays = if hole <= j { hole < home && home <= j } else { hole < home || home <= j };
+                if !stays {
+                    break;
+                }
+            }
+            table[hole] = table[j];
+            hole = j;
+        }
+    })
+}
+
+/// Live allocations of a single owner.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Usage {
+    pub allocations: u64,
+    pub bytes: u64,
+}
+
+/// Live allocations of every owner at a point in time.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    pub owners: BTreeMap<Owner, Usage>,
+    /// Allocations which were not recorded, see [DROPPED].
+    pub dropped: u64,
+}
+
+/// Returns the live allocations of every owner.
+///
+/// The table is copied in chunks so that the allocator is not blocked for long, allocations made
+/// or freed while the snapshot is taken may be miscounted.
+pub fn snapshot() -> Snapshot {
+    const CHUNK: usize = 256;
+    let mut snap = Snapshot { owners: BTreeMap::new(), dropped: DROPPED.load(Ordering::Relaxed) };
+    let mut buff = [Entry::EMPTY; CHUNK];
+    for base in (0..CAPACITY).step_by(CHUNK) {
+        let copied = x86_64::instructions::interrupts::without_interrupts(|| {
+            let l = TABLE.lock();
+            let Some(table) = l.as_ref() else { return false };
+            buff.copy_from_slice(&table[base..base + CHUNK]);
+            true
+        });
+        if !copied {
+            break;
+        }
+        for e in buff.iter().filter(|e| e.addr != 0) {
+            let u = snap.owners.entry(e.owner).or_default();
+            u.allocations += 1;
+            u.bytes += e.size as u64;
+        }
+    }
+    snap
+}
+
+/// Saved snapshots by name.
+static SNAPSHOTS: spin::Mutex<BTreeMap<String, Snapshot>> = spin::Mutex::new(BTreeMap::new());
+
+/// Returns a description of `owner`, tasks which still exist include where they were spawned.
+fn describe(owner: Owner) -> String {
+    match owner {
+        Owner::Task(id) => match crate::task::mp_executor::task_stats().into_iter().find(|t| t.id == id) {
+            Some(t) => alloc::format!("{owner} ({})", t.origin),
+            None => alloc::format!("{owner} (exited)"),
+        },
+        _ => alloc::format!("{owner}"),
+    }
+}
+
+/// Prints the change in live allocations of each owner from `old` to `new`. Owners whose
+/// allocations grew are printed first, marked with `!`, largest growth first.
+pub fn print_diff(old: &Snapshot, new: &Snapshot) {
+    let mut rows: alloc::vec::Vec<(Owner, Usage, i64, i64)> = old
+        .owners
+        .keys()
+        .chain(new.owners.keys())
+        .collect::<alloc::collections::BTreeSet<_>>()
+        .into_iter()
+        .map(|o| {
+            let (a, b) = (old.owners.get(o).copied().unwrap_or_default(), new.owners.get(o).copied().unwrap_or_default());
+            (*o, b, b.allocations as i64 - a.allocations as i64, b.bytes as i64 - a.bytes as i64)
+        })
+        .filter(|(_, _, da, db)| *da != 0 || *db != 0)
+        .collect();
+    rows.sort_by(|a, b| b.3.cmp(&a.3).then(b.2.cmp(&a.2)));
+
+    crate::serial_println!("  {:>10} {:>12} {:>10} {:>12}  OWNER", "ALLOCS", "BYTES", "+ALLOCS", "+BYTES");
+    for (owner, u, da, db) in &rows {
+        let mark = if *da > 0 && *db > 0 { '!' } else { ' ' };
+        crate::serial_println!("{mark} {:>10} {:>12} {:>+10} {:>+12}  {}", u.allocations, u.bytes, da, db, describe(*owner));
+    }
+    if rows.is_empty() {
+        crate::serial_println!("No change");
+    }
+    if new.dropped > old.dropped {
+        crate::serial_println!("{} allocations were not recorded, the table is full", new.dropped - old.dropped);
+    }
+}
+
+fn print_usage() {
+    crate::serial_println!("Usage: leakcheck snapshot [NAME] | diff [NAME] | diff OLD NEW | show");
+}
+
+fn program(args: Args) -> Pin<Box<dyn Future<Output = TaskResult> + Send>> {
+    Box::pin(async move {
+        if !TRACK.get() {
+            crate::serial_println!("leakcheck: Tracking is disabled, set leakcheck=true");
+            return TaskResult::Error;
+        }
+        let argv: alloc::vec::Vec<&str> = args.argv.iter().skip(1).map(String::as_str).collect();
+        match argv.as_slice() {
+            ["snapshot", name @ ..] if name.len() <= 1 => {
+                let name = name.first().copied().unwrap_or("default");
+                let snap = snapshot();
+                crate::serial_println!("leakcheck: Saved {name}, {} owners", snap.owners.len());
+                SNAPSHOTS.lock().insert(String::from(name), snap);
+            }
+            ["diff", old, new] => {
+                let l = SNAPSHOTS.lock();
+                let (Some(a), Some(b)) = (l.get(*old), l.get(*new)) else {
+                    crate::serial_println!("leakcheck: No such snapshot");
+                    return TaskResult::Error;
+                };
+                let (a, b) = (a.clone(), b.clone());
+                drop(l);
+                print_diff(&a, &b);
+            }
+            ["diff", name @ ..] if name.len() <= 1 => {
+                let name = name.first().copied().unwrap_or("default");
+                let Some(old) = SNAPSHOTS.lock().get(name).cloned() else {
+                    crate::serial_println!("leakcheck: No such snapshot {name}");
+                    return TaskResult::Error;
+                };
+                print_diff(&old, &snapshot());
+            }
+            ["show"] => print_diff(&Snapshot::default(), &snapshot()),
+            _ => {
+                print_usage();
+                return TaskResult::Error;
+            }
+        }
+        TaskResult::ExitedNormally
+    })
+}
+
+/// Registers the `/bin/leakcheck` program.
+pub fn init() {
+    crate::task::spawn::register("/bin/leakcheck", program).expect("Failed to register leakcheck");
+}
@@ -66,17 +66,19 @@ where
     offset_page_table::OffsetPageTable: Mapper<S>,
 {
     use x86_64::structures::paging::mapper::UnmapError;
-    for page in pages {
-        match SYS_MAPPER.get().unmap(page) {
-            Ok((_, flush)) => flush.flush(),
+    super::tlb::batch(|| {
+        for page in pages {
+            match SYS_MAPPER.get().unmap(page) {
+                Ok((_, flush)) => flush.flush(),
 
-            Err(UnmapError::PageNotMapped) => continue,
+                Err(UnmapError::PageNotMapped) => continue,
 
-            Err(err) => {
-                panic!("{:?}", err)
+                Err(err) => {
+                    panic!("{:?}", err)
+                }
             }
         }
-    }
+    })
 }
 
 /// Maps a single page of memory, flushing the tlb entry for the given page. This is the preferred
@@ -0,0 +1,264 @@
+//! Contains the NUMA topology of the system and memory placement policies.
+//!
+//! The topology is parsed from the ACPI SRAT and SLIT tables. If the SRAT is not present the
+//! system is treated as a single node and no placement decisions are made.
+//!
+//! Each task has a [MemPolicy] which is used by the frame allocator to choose which node physical
+//! memory is allocated from. The policy is stored in the task and is loaded into [CURRENT_POLICY]
+//! by the executor while the task is running.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+pub type NodeId = u32;
+
+/// Distance reported between a node and itself. See ACPI 6.5 5.2.17
+pub const LOCAL_DISTANCE: u8 = 10;
+
+static TOPOLOGY: crate::util::Worm<NumaTopology> = crate::util::Worm::new();
+
+#[thread_local]
+static CURRENT_POLICY: core::cell::Cell<MemPolicy> = core::cell::Cell::new(MemPolicy::Local);
+
+/// Describes where physical memory should be allocated from.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum MemPolicy {
+    /// Prefer memory local to the CPU which is running the task.
+    #[default]
+    Local,
+    /// Prefer memory from the given node, falling back to any node.
+    Preferred(NodeId),
+    /// Only allocate memory from the given node. Allocations fail when the node is exhausted.
+    Bind(NodeId),
+    /// Don't care where memory comes from.
+    Any,
+}
+
+pub struct NumaNode {
+    pub id: NodeId,
+    /// APIC-IDs of CPUs within this node
+    pub cpus: Vec<u32>,
+    /// Physical memory ranges `(base, len)` within this node
+    pub memory: Vec<(PhysAddr, u64)>,
+}
+
+impl NumaNode {
+    /// Returns whether `len` bytes at `addr` are within a single memory range of this node.
+    pub fn contains(&self, addr: PhysAddr, len: u64) -> bool {
+        self.memory.iter().any(|(b, l)| addr >= *b && addr.as_u64() + len <= b.as_u64() + l)
+    }
+}
+
+pub struct NumaTopology {
+    nodes: Vec<NumaNode>,
+    /// `(localities, matrix)` from the SLIT. The matrix is indexed by `[from * localities + to]`
+    distance: Option<(usize, Box<[u8]>)>,
+}
+
+impl NumaTopology {
+    pub fn nodes(&self) -> &[NumaNode] {
+        &self.nodes
+    }
+
+    pub fn node(&self, id: NodeId) -> Option<&NumaNode> {
+        self.nodes.iter().find(|n| n.id == id)
+    }
+
+    /// Returns the node containing `addr`. If `addr` is not described by the SRAT returns `None`
+    pub fn node_of_addr(&self, addr: PhysAddr) -> Option<NodeId> {
+        self.nodes.iter()
+            .find(|n| n.memory.iter().any(|(b, l)| addr >= *b && addr.as_u64() < b.as_u64() + l))
+            .map(|n| n.id)
+    }
+
+    pub fn node_of_cpu(&self, apic_id: u32) -> Option<NodeId> {
+        self.nodes.iter().find(|n| n.cpus.contains(&apic_id)).map(|n| n.id)
+    }
+
+    /// Returns the relative distance between two nodes.
+    /// When no SLIT is present `from == to` will return [LOCAL_DISTANCE] and all others will return `2 * LOCAL_DISTANCE`
+    pub fn distance(&self, from: NodeId, to: NodeId) -> u8 {
+        match &self.distance {
+            Some((n, m)) if (from as usize) < *n && (to as usize) < *n => m[from as usize * n + to as usize],
+            _ if from == to => LOCAL_DISTANCE,
+            _ => LOCAL_DISTANCE * 2,
+        }
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut NumaNode {
+        if let Some(i) = self.nodes.iter().position(|n| n.id == id) {
+            &mut self.nodes[i]
+        } else {
+            self.nodes.push(NumaNode { id, cpus: Vec::new(), memory: Vec::new() });
+            self.nodes.last_mut().unwrap()
+        }
+    }
+}
+
+/// Parses the SRAT and SLIT and sets the system topology.
+///
+/// # Safety
+///
+/// This must be called before MP initialization and after the firmware ACPI tables have been configured.
+pub unsafe fn init() {
+    let tables = crate::system::sysfs::get_sysfs().firmware().get_acpi();
+    let srat = match tables.find_table::<Srat>() {
+        Ok(s) => s,
+        Err(_) => {
+            log::info!("No SRAT present, NUMA disabled");
+            return;
+        }
+    };
+
+    let mut topology = NumaTopology { nodes: Vec::new(), distance: None };
+
+    // SAFETY: The mapping covers the whole table, `length` is given by the firmware
+    let raw = unsafe { core::slice::from_raw_parts(srat.virtual_start().as_ptr() as *const u8, srat.header.length as usize) };
+    let mut offset = core::mem::size_of::<Srat>();
+    while offset + 2 <= raw.len() {
+        let (ty, len) = (raw[offset], raw[offset + 1] as usize);
+        if len == 0 || offset + len > raw.len() {
+            log::warn!("Malformed SRAT entry at offset {offset}");
+            break;
+        }
+        let e = &raw[offset..offset + len];
+        match ty {
+            // Processor local APIC affinity
+            0 if len >= 16 && read_u32(e, 4) & 1 != 0 => {
+                let domain = e[2] as u32 | (e[9] as u32) << 8 | (e[10] as u32) << 16 | (e[11] as u32) << 24;
+                topology.node_mut(domain).cpus.push(e[3] as u32);
+            }
+            // Memory affinity
+            1 if len >= 40 && read_u32(e, 28) & 1 != 0 => {
+                let base = read_u32(e, 8) as u64 | (read_u32(e, 12) as u64) << 32;
+                let size = read_u32(e, 16) as u64 | (read_u32(e, 20) as u64) << 32;
+                topology.node_mut(read_u32(e, 2)).memory.push((PhysAddr::new(base), size));
+            }
+            // Processor local x2APIC affinity
+            2 if len >= 24 && read_u32(e, 12) & 1 != 0 => {
+                topology.node_mut(read_u32(e, 4)).cpus.push(read_u32(e, 8));
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+
+    if let Ok(slit) = tables.find_table::<Slit>() {
+        let n = slit.localities as usize;
+        // SAFETY: See above
+        let raw = unsafe { core::slice::from_raw_parts(slit.virtual_start().as_ptr() as *const u8, slit.header.length as usize) };
+        let start = core::mem::size_of::<Slit>();
+        if let Some(m) = raw.get(start..start + n * n) {
+            topology.distance = Some((n, m.into()));
+        } else {
+            log::warn!("SLIT too short for {n} localities");
+        }
+    }
+
+    topology.nodes.sort_by_key(|n| n.id);
+    for n in &topology.nodes {
+        log::info!("NUMA node {}: {} CPUs, {:#x} bytes", n.id, n.cpus.len(), n.memory.iter().map(|(_, l)| l).sum::<u64>());
+    }
+    // SAFETY: Guaranteed by caller
+    unsafe { TOPOLOGY.write(topology) }
+}
+
+fn read_u32(b: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(b[offset..offset + 4].try_into().unwrap())
+}
+
+/// Returns the system topology. Returns `None` if the system is not NUMA.
+pub fn topology() -> Option<&'static NumaTopology> {
+    TOPOLOGY.is_set().then(|| TOPOLOGY.read())
+}
+
+/// Returns the node containing `addr`, addresses not described by the SRAT are considered to be node `0`.
+pub fn node_of(addr: PhysAddr) -> NodeId {
+    topology().and_then(|t| t.node_of_addr(addr)).unwrap_or(0)
+}
+
+/// Returns whether all `len` bytes at `addr` are within `node`, see [node_of].
+pub fn range_in_node(addr: PhysAddr, len: u64, node: NodeId) -> bool {
+    let Some(t) = topology() else { return node == 0 };
+    match t.node_of_addr(addr) {
+        Some(n) => n == node && t.node(n).is_some_and(|n| n.contains(addr, len)),
+        // Memory not described by the SRAT is node 0, the range must not extend into any node
+        None => {
+            let end = addr.as_u64() + len;
+            node == 0 && !t.nodes.iter().flat_map(|n| &n.memory).any(|(b, l)| b.as_u64() < end && addr.as_u64() < b.as_u64() + l)
+        }
+    }
+}
+
+/// Returns the node of the current CPU.
+pub fn this_node() -> NodeId {
+    topology().and_then(|t| t.node_of_cpu(crate::who_am_i())).unwrap_or(0)
+}
+
+/// Sets the memory policy for the current task.
+pub fn set_task_policy(policy: MemPolicy) {
+    CURRENT_POLICY.set(policy)
+}
+
+/// Returns the memory policy of the current task.
+pub fn task_policy() -> MemPolicy {
+    // The TLS may not be initialized yet.
+    if TOPOLOGY.is_set() {
+        CURRENT_POLICY.get()
+    } else {
+        MemPolicy::Local
+    }
+}
+
+/// Sets the current policy and returns the previous one. Used by the executor when switching tasks.
+pub(crate) fn swap_policy(policy: MemPolicy) -> MemPolicy {
+    CURRENT_POLICY.replace(policy)
+}
+
+/// Returns the node the frame allocator should attempt to allocate from and whether the
+/// allocation must fail if that node cannot satisfy it.
+///
+/// Returns `None` when no placement is required.
+pub(super) fn alloc_target() -> Option<(NodeId, bool)> {
+    // The topology is only set after the TLS is initialized, so CURRENT_POLICY is safe to access.
+    let t = topology()?;
+    if t.nodes.len() < 2 {
+        return None;
+    }
+    match CURRENT_POLICY.get() {
+        MemPolicy::Local => Some((this_node(), false)),
+        MemPolicy::Preferred(n) => Some((n, false)),
+        MemPolicy::Bind(n) => Some((n, true)),
+        MemPolicy::Any => None,
+    }
+}
+
+#[repr(C, packed)]
+struct Srat {
+    header: acpi::sdt::SdtHeader,
+    _reserved0: u32,
+    _reserved1: u64,
+}
+
+unsafe impl acpi::AcpiTable for Srat {
+    const SIGNATURE: acpi::sdt::Signature = acpi::sdt::Signature::SRAT;
+
+    fn header(&self) -> &acpi::sdt::SdtHeader {
+        &self.header
+    }
+}
+
+#[repr(C, packed)]
+struct Slit {
+    header: acpi::sdt::SdtHeader,
+    localities: u64,
+}
+
+unsafe impl acpi::AcpiTable for Slit {
+    const SIGNATURE: acpi::sdt::Signature = acpi::sdt::Signature::SLIT;
+
+    fn header(&self) -> &acpi::sdt::SdtHeader {
+        &self.header
+    }
+}
@@ -46,6 +46,11 @@ impl OffsetPageTable {
         self.l4_table
     }
 
+    /// Returns the address physical memory is mapped at.
+    pub(super) fn phys_offset(&self) -> VirtAddr {
+        self.offset_base
+    }
+
     /// Returns all mapped pages and their frames from `start` to `end`
     pub(super) fn get_allocated_frames_within(
         &self,
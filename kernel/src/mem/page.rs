@@ -0,0 +1,263 @@
+//! Shared pages.
+//!
+//! A [Page] holds the contents of a page of a file or device so that one copy can back several
+//! views, e.g. the block device page cache and a filesystem's buffer cache. Views share a page
+//! through an [Arc] and the page is freed when the last view drops it.
+//!
+//! Each page has [PageFlags] describing the state of its contents and a list of tasks waiting for
+//! them to change.
+//! - [PageFlags::UPTODATE]: The contents are valid, set by [Page::fill] once a read completes.
+//! - [PageFlags::DIRTY]: The contents were modified since they were last written back.
+//! - [PageFlags::WRITEBACK]: The contents are being written back, see [Page::begin_writeback].
+//!
+//! # Merging
+//!
+//! Clean pages with identical contents are merged by [merge] when [MERGE_PAGES] is enabled. This
+//! is common for metadata, e.g. zeroed bitmaps and unused inode tables. A merged page has
+//! [PageFlags::MERGED] set and must not be modified in place because views of unrelated data share
+//! it, [Page::make_mut] must be used to get a private copy first.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use core::task::{Poll, Waker};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Enables merging of identical pages.
+pub static MERGE_PAGES: crate::config::Tunable<bool> =
+    crate::config::Tunable::new("mm.merge_pages", "Merge clean cached pages with identical contents", true);
+
+/// Merged pages indexed by their length and checksum.
+static MERGED: spin::Mutex<BTreeMap<(usize, u32), Vec<Weak<Page>>>> = spin::Mutex::new(BTreeMap::new());
+/// Number of pages which were dropped because an identical page was found.
+static PAGES_SAVED: AtomicUsize = AtomicUsize::new(0);
+
+bitflags::bitflags! {
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub struct PageFlags: u8 {
+        const UPTODATE = 1;
+        const DIRTY = 1 << 1;
+        const WRITEBACK = 1 << 2;
+        /// The page may be shared by unrelated views, see [merge].
+        const MERGED = 1 << 3;
+    }
+}
+
+pub struct Page {
+    data: spin::RwLock<Box<[u8]>>,
+    flags: AtomicU8,
+    /// Checksum the page is registered under while it is merged.
+    crc: core::sync::atomic::AtomicU32,
+    /// Tasks waiting for the flags to change.
+    waiters: spin::Mutex<Vec<Waker>>,
+}
+
+impl Page {
+    /// Constructs a zeroed page of `len` bytes which is not up to date.
+    pub fn new(len: usize) -> Arc<Self> {
+        Self::with_flags(alloc::vec![0u8; len].into_boxed_slice(), PageFlags::empty())
+    }
+
+    /// Constructs an up to date page containing `data`.
+    pub fn from_data(data: Box<[u8]>) -> Arc<Self> {
+        Self::with_flags(data, PageFlags::UPTODATE)
+    }
+
+    fn with_flags(data: Box<[u8]>, flags: PageFlags) -> Arc<Self> {
+        Arc::new(Self {
+            data: spin::RwLock::new(data),
+            flags: AtomicU8::new(flags.bits()),
+            crc: core::sync::atomic::AtomicU32::new(0),
+            waiters: spin::Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.read().len()
+    }
+
+    pub fn flags(&self) -> PageFlags {
+        PageFlags::from_bits_retain(self.flags.load(Ordering::Acquire))
+    }
+
+    fn set(&self, flags: PageFlags) -> PageFlags {
+        PageFlags::from_bits_retain(self.flags.fetch_or(flags.bits(), Ordering::AcqRel))
+    }
+
+    fn clear(&self, flags: PageFlags) -> PageFlags {
+        PageFlags::from_bits_retain(self.flags.fetch_and(!flags.bits(), Ordering::AcqRel))
+    }
+
+    /// Calls `f` with the contents of the page.
+    pub fn read<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
+        f(&self.data.read())
+    }
+
+    /// Calls `f` with the contents of the page and marks it dirty.
+    ///
+    /// # Panics
+    ///
+    /// This fn will panic if the page is merged, use [Self::make_mut].
+    pub fn modify<R>(&self, f: impl FnOnce(&mut [u8]) -> R) -> R {
+        let mut l = self.data.write();
+        assert!(!self.flags().contains(PageFlags::MERGED), "Modified merged page");
+        let r = f(&mut l);
+        self.set(PageFlags::DIRTY);
+        r
+    }
+
+    /// Calls `f` to initialize the contents of the page, marks the page up to date and wakes tasks
+    /// waiting for it. Used when the contents have been read from their backing store.
+    pub fn fill<R>(&self, f: impl FnOnce(&mut [u8]) -> R) -> R {
+        let r = f(&mut self.data.write());
+        self.set(PageFlags::UPTODATE);
+        self.wake();
+        r
+    }
+
+    /// Marks the contents as no longer valid, e.g. when the read which would fill it failed.
+    pub fn invalidate(&self) {
+        self.clear(PageFlags::UPTODATE);
+        self.wake();
+    }
+
+    /// Begins writing back the page, returns `false` if it is already being written back.
+    ///
+    /// The page is marked clean, modifications made during write-back mark it dirty again.
+    pub fn begin_writeback(&self) -> bool {
+        if self.set(PageFlags::WRITEBACK).contains(PageFlags::WRITEBACK) {
+            return false;
+        }
+        self.clear(PageFlags::DIRTY);
+        true
+    }
+
+    /// Completes write-back, if it failed the page is marked dirty again.
+    pub fn end_writeback(&self, ok: bool) {
+        if !ok {
+            self.set(PageFlags::DIRTY);
+        }
+        self.clear(PageFlags::WRITEBACK);
+        self.wake();
+    }
+
+    /// Marks the page clean without writing it back, used by write-through views once the
+    /// contents have been written.
+    pub fn clean(&self) {
+        self.clear(PageFlags::DIRTY);
+    }
+
+    fn wake(&self) {
+        let waiters = without_interrupts(|| core::mem::take(&mut *self.waiters.lock()));
+        waiters.into_iter().for_each(Waker::wake);
+    }
+
+    /// Waits until `cond` returns `true` for the page's flags.
+    async fn wait_until(&self, cond: impl Fn(PageFlags) -> bool, what: &'static str) {
+        core::future::poll_fn(|cx| {
+            if cond(self.flags()) {
+                return Poll::Ready(());
+            }
+            without_interrupts(|| self.waiters.lock().push(cx.waker().clone()));
+            if cond(self.flags()) {
+                Poll::Ready(())
+            } else {
+                crate::task::wait::note(crate::task::wait::WaitPoint::device(what));
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Waits until the page is up to date.
+    pub async fn wait_uptodate(&self) {
+        self.wait_until(|f| f.contains(PageFlags::UPTODATE), "page read").await
+    }
+
+    /// Waits until the page is not being written back.
+    pub async fn wait_writeback(&self) {
+        self.wait_until(|f| !f.contains(PageFlags::WRITEBACK), "page writeback").await
+    }
+
+    /// Returns a page which may be modified in place. If `this` is merged and shared with
+    /// another view it is replaced with a private copy.
+    pub fn make_mut(this: &mut Arc<Self>) -> &Self {
+        if !this.flags().contains(PageFlags::MERGED) {
+            return this;
+        }
+        // Merging upgrades references while the registry is locked, so the count can't increase
+        let mut l = MERGED.lock();
+        if Arc::strong_count(this) == 1 {
+            this.unregister(&mut l);
+            return this;
+        }
+        drop(l);
+        let copy = Self::with_flags(this.data.read().clone(), this.flags() - PageFlags::MERGED);
+        *this = copy;
+        this
+    }
+
+    fn unregister(&self, registry: &mut BTreeMap<(usize, u32), Vec<Weak<Page>>>) {
+        let key = (self.len(), self.crc.load(Ordering::Relaxed));
+        if let Some(v) = registry.get_mut(&key) {
+            v.retain(|p| !core::ptr::eq(p.as_ptr(), self));
+            if v.is_empty() {
+                registry.remove(&key);
+            }
+        }
+        self.clear(PageFlags::MERGED);
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        if self.flags().contains(PageFlags::MERGED) {
+            self.unregister(&mut MERGED.lock());
+        }
+    }
+}
+
+/// Merges `page` with an identical page if one exists, returns the page which should be used in
+/// place of `page`.
+///
+/// Only clean, up to date pages are merged. Other pages and all pages while [MERGE_PAGES] is
+/// disabled are returned unchanged.
+pub fn merge(page: Arc<Page>) -> Arc<Page> {
+    let flags = page.flags();
+    if !MERGE_PAGES.get() || flags & (PageFlags::DIRTY | PageFlags::WRITEBACK | PageFlags::MERGED) != PageFlags::empty() || !flags.contains(PageFlags::UPTODATE) {
+        return page;
+    }
+    let data = page.data.read();
+    let crc = crate::crypto::crc::crc32c(&data);
+    let key = (data.len(), crc);
+
+    let mut l = MERGED.lock();
+    let entry = l.entry(key).or_default();
+    entry.retain(|p| p.strong_count() != 0);
+    // Dropped after the registry is unlocked, dropping the last reference to a page unregisters it
+    let candidates: Vec<Arc<Page>> = entry.iter().filter_map(Weak::upgrade).collect();
+    let found = candidates.iter().find(|o| o.data.read()[..] == data[..]).cloned();
+    if found.is_none() {
+        page.crc.store(crc, Ordering::Relaxed);
+        page.set(PageFlags::MERGED);
+        entry.push(Arc::downgrade(&page));
+    }
+    drop(l);
+    drop(data);
+    drop(candidates);
+    match found {
+        Some(other) => {
+            PAGES_SAVED.fetch_add(1, Ordering::Relaxed);
+            other
+        }
+        None => page,
+    }
+}
+
+/// Returns the number of merged pages and the number of pages which were freed by merging.
+pub fn merge_stats() -> (usize, usize) {
+    let merged = MERGED.lock().values().map(Vec::len).sum();
+    (merged, PAGES_SAVED.load(Ordering::Relaxed))
+}
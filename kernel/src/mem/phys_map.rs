@@ -0,0 +1,328 @@
+//! The physical memory map describes the layout of physical memory as given by the bootloader.
+//!
+//! Unlike the [libboot::boot_info::MemoryMap] which is consumed by the frame allocator this map is
+//! kept for the lifetime of the kernel. Ranges within the map can be reserved at runtime so that
+//! drivers (and the frame allocator) can determine what a physical address is used for.
+//!
+//! The map is stored within a fixed size array because it must be constructed before the heap is
+//! initialized.
+
+use x86_64::PhysAddr;
+
+/// Maximum number of regions that can be stored within the map.
+/// Firmware memory maps rarely contain more than 100 entries, this leaves room for reservations
+/// splitting regions.
+const MAX_REGIONS: usize = 256;
+
+/// Memory above this address is never considered to be an MMIO hole
+const HOLE_LIMIT: u64 = 0x1_0000_0000;
+
+pub static PHYS_MAP: PhysMap = PhysMap::new();
+
+/// Describes what a physical memory region is used for.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PhysRegionKind {
+    /// Free memory which is owned by the frame allocator.
+    Usable,
+    /// Memory used by the bootloader. This contains the kernel image and boot information.
+    Bootloader,
+    /// ACPI tables which may be reclaimed once they are no longer needed.
+    AcpiReclaimable,
+    /// ACPI non-volatile storage. This must never be used by the kernel.
+    AcpiNvs,
+    /// Memory mapped IO described by the firmware.
+    Mmio,
+    /// A gap below 4GiB which is not described by the firmware.
+    /// These are normally used for PCI BARs and other MMIO devices.
+    MmioHole,
+    /// Reserved by the firmware or unusable for some other reason.
+    FirmwareReserved,
+    /// Reserved by the kernel at runtime. The `&str` describes the owner of the region.
+    KernelReserved(&'static str),
+}
+
+impl PhysRegionKind {
+    fn from_boot_region(region: &libboot::boot_info::MemoryRegion) -> Self {
+        use libboot::boot_info::{MemoryRegionDistinct, MemoryRegionType, MemoryType};
+        match region.ty {
+            MemoryRegionType::Usable => Self::Usable,
+            MemoryRegionType::Bootloader => Self::Bootloader,
+            MemoryRegionType::Unusable(_) | MemoryRegionType::Unknown(_) => {
+                match region.distinct {
+                    MemoryRegionDistinct::Uefi { ty, .. } => match ty {
+                        MemoryType::ACPI_RECLAIM => Self::AcpiReclaimable,
+                        MemoryType::ACPI_NON_VOLATILE => Self::AcpiNvs,
+                        MemoryType::MMIO | MemoryType::MMIO_PORT_SPACE => Self::Mmio,
+                        _ => Self::FirmwareReserved,
+                    },
+                    #[allow(unreachable_patterns)]
+                    _ => Self::FirmwareReserved,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PhysRegion {
+    pub base: PhysAddr,
+    /// Length of the region in bytes
+    pub len: u64,
+    pub kind: PhysRegionKind,
+}
+
+impl PhysRegion {
+    /// Returns the first address after the end of the region.
+    pub fn end(&self) -> u64 {
+        self.base.as_u64() + self.len
+    }
+
+    pub fn contains(&self, addr: PhysAddr) -> bool {
+        addr >= self.base && addr.as_u64() < self.end()
+    }
+
+    fn overlaps(&self, base: u64, end: u64) -> bool {
+        self.base.as_u64() < end && base < self.end()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PhysMapError {
+    /// The map has no free slots remaining.
+    MapFull,
+    /// The requested range is already reserved or is not usable memory.
+    Conflict(PhysRegion),
+    /// The requested range is not described by the map.
+    NotPresent,
+    /// The range was not page aligned or had a length of `0`.
+    BadRange,
+}
+
+struct PhysMapInner {
+    count: usize,
+    // Sorted by base address, entries `count..` are ignored.
+    regions: [Option<PhysRegion>; MAX_REGIONS],
+}
+
+pub struct PhysMap {
+    inner: spin::RwLock<PhysMapInner>,
+}
+
+impl PhysMap {
+    const fn new() -> Self {
+        Self {
+            inner: spin::RwLock::new(PhysMapInner {
+                count: 0,
+                regions: [None; MAX_REGIONS],
+            }),
+        }
+    }
+
+    /// Fills the map using the bootloader memory map.
+    /// Gaps below 4GiB are recorded as [PhysRegionKind::MmioHole].
+    ///
+    /// # Panics
+    ///
+    /// This fn will panic if the map has already been initialized
+    pub(super) fn init(&self, map: &libboot::boot_info::MemoryMap) {
+        let mut l = self.inner.write();
+        assert_eq!(l.count, 0, "Physical memory map already initialized");
+        for i in map.iter() {
+            if i.size == 0 {
+                continue;
+            }
+            let region = PhysRegion {
+                base: PhysAddr::new(i.phys_addr),
+                len: i.size,
+                kind: PhysRegionKind::from_boot_region(&i),
+            };
+            if l.insert(region).is_err() {
+                log::error!("Physical memory map full, dropped {region:x?}");
+            }
+        }
+        l.merge();
+
+        // find holes, a hole is inserted before `r` so `i` must skip over it
+        let mut last = 0;
+        let mut i = 0;
+        while i < l.count {
+            let r = l.regions[i].unwrap();
+            if r.base.as_u64() >= HOLE_LIMIT {
+                break;
+            }
+            if r.base.as_u64() > last {
+                let hole = PhysRegion {
+                    base: PhysAddr::new(last),
+                    len: r.base.as_u64() - last,
+                    kind: PhysRegionKind::MmioHole,
+                };
+                if l.insert(hole).is_ok() {
+                    i += 1;
+                } else {
+                    log::warn!("Physical memory map full, dropped {hole:x?}");
+                }
+            }
+            last = last.max(r.end());
+            i += 1;
+        }
+        // regions above HOLE_LIMIT were skipped, so this is the gap up to HOLE_LIMIT
+        if last < HOLE_LIMIT {
+            let _ = l.insert(PhysRegion { base: PhysAddr::new(last), len: HOLE_LIMIT - last, kind: PhysRegionKind::MmioHole });
+        }
+    }
+
+    /// Returns the region containing `addr`
+    pub fn lookup(&self, addr: PhysAddr) -> Option<PhysRegion> {
+        self.inner.read().iter().find(|r| r.contains(addr)).copied()
+    }
+
+    /// Returns whether the whole range `base..base+len` is [PhysRegionKind::Usable].
+    pub fn is_usable(&self, base: PhysAddr, len: u64) -> bool {
+        let l = self.inner.read();
+        let mut cursor = base.as_u64();
+        let end = base.as_u64() + len;
+        for r in l.iter().filter(|r| r.overlaps(base.as_u64(), end)) {
+            if r.kind != PhysRegionKind::Usable || r.base.as_u64() > cursor {
+                return false;
+            }
+            cursor = r.end();
+        }
+        cursor >= end
+    }
+
+    /// Returns whether any part of `base..base+len` has been reserved by the kernel.
+    pub fn is_reserved(&self, base: PhysAddr, len: u64) -> bool {
+        let end = base.as_u64() + len;
+        self.inner.read().iter()
+            .any(|r| matches!(r.kind, PhysRegionKind::KernelReserved(_)) && r.overlaps(base.as_u64(), end))
+    }
+
+    /// Calls `f` on each region within the map in order of address.
+    ///
+    /// The map is locked while `f` is called, `f` must not attempt to modify the map.
+    pub fn for_each<F: FnMut(&PhysRegion)>(&self, f: F) {
+        self.inner.read().iter().for_each(f)
+    }
+
+    /// Reserves the given range with the owner `name`.
+    ///
+    /// The range may either be [PhysRegionKind::Usable] or [PhysRegionKind::Bootloader], the
+    /// reservation only records the owner of the range, it does not remove it from the frame allocator.
+    /// Frames which are reserved before [super::buddy_frame_alloc::drain_map] is called are never
+    /// given to the frame allocator. Memory reserved after this should already be owned by the caller.
+    ///
+    /// # Errors
+    ///
+    /// Returns [PhysMapError::Conflict] containing the first conflicting region if the range
+    /// overlaps any region which is not usable.
+    pub fn reserve(&self, base: PhysAddr, len: u64, name: &'static str) -> Result<(), PhysMapError> {
+        if !base.is_aligned(super::PAGE_SIZE as u64) || len == 0 || len % super::PAGE_SIZE as u64 != 0 {
+            return Err(PhysMapError::BadRange);
+        }
+        let end = base.as_u64() + len;
+        let mut l = self.inner.write();
+
+        let mut cursor = base.as_u64();
+        for r in l.iter().filter(|r| r.overlaps(base.as_u64(), end)) {
+            match r.kind {
+                PhysRegionKind::Usable | PhysRegionKind::Bootloader => {}
+                _ => return Err(PhysMapError::Conflict(*r)),
+            }
+            if r.base.as_u64() > cursor {
+                return Err(PhysMapError::NotPresent);
+            }
+            cursor = r.end();
+        }
+        if cursor < end {
+            return Err(PhysMapError::NotPresent);
+        }
+
+        l.set_kind(base.as_u64(), end, PhysRegionKind::KernelReserved(name))
+    }
+
+    /// Releases a range previously reserved with [Self::reserve], returning it to [PhysRegionKind::Usable].
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the released range is not in use or is owned by the frame allocator.
+    pub unsafe fn release(&self, base: PhysAddr, len: u64) -> Result<(), PhysMapError> {
+        let end = base.as_u64() + len;
+        let mut l = self.inner.write();
+        if let Some(r) = l.iter().find(|r| r.overlaps(base.as_u64(), end) && !matches!(r.kind, PhysRegionKind::KernelReserved(_))) {
+            return Err(PhysMapError::Conflict(*r));
+        }
+        l.set_kind(base.as_u64(), end, PhysRegionKind::Usable)?;
+        l.merge();
+        Ok(())
+    }
+}
+
+impl PhysMapInner {
+    fn iter(&self) -> impl Iterator<Item = &PhysRegion> {
+        self.regions[..self.count].iter().map(|r| r.as_ref().unwrap())
+    }
+
+    /// Inserts `region` keeping the map sorted. Does not check for overlap.
+    fn insert(&mut self, region: PhysRegion) -> Result<(), PhysMapError> {
+        if self.count == MAX_REGIONS {
+            return Err(PhysMapError::MapFull);
+        }
+        let idx = self.iter().position(|r| r.base > region.base).unwrap_or(self.count);
+        self.regions[idx..=self.count].rotate_right(1);
+        self.regions[idx] = Some(region);
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Merges adjacent regions of the same kind.
+    fn merge(&mut self) {
+        let mut i = 1;
+        while i < self.count {
+            let (prev, cur) = (self.regions[i - 1].unwrap(), self.regions[i].unwrap());
+            if prev.kind == cur.kind && prev.end() == cur.base.as_u64() {
+                self.regions[i - 1].as_mut().unwrap().len += cur.len;
+                self.regions[i..self.count].rotate_left(1);
+                self.count -= 1;
+                self.regions[self.count] = None;
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Sets the kind of the range `base..end` splitting regions where necessary.
+    /// The caller must ensure the range is fully described by the map.
+    fn set_kind(&mut self, base: u64, end: u64, kind: PhysRegionKind) -> Result<(), PhysMapError> {
+        // Worst case is splitting one region into 3.
+        if self.count + 2 > MAX_REGIONS {
+            return Err(PhysMapError::MapFull);
+        }
+
+        let mut i = 0;
+        while i < self.count {
+            let r = self.regions[i].unwrap();
+            if !r.overlaps(base, end) {
+                i += 1;
+                continue;
+            }
+
+            if r.base.as_u64() < base {
+                // split off head, the head keeps the old kind
+                let head_len = base - r.base.as_u64();
+                self.regions[i].as_mut().unwrap().len = head_len;
+                self.insert(PhysRegion { base: PhysAddr::new(base), len: r.len - head_len, kind: r.kind })?;
+                i += 1;
+                continue;
+            }
+
+            if r.end() > end {
+                // split off tail
+                self.regions[i].as_mut().unwrap().len = end - r.base.as_u64();
+                self.insert(PhysRegion { base: PhysAddr::new(end), len: r.end() - end, kind: r.kind })?;
+            }
+            self.regions[i].as_mut().unwrap().kind = kind;
+            i += 1;
+        }
+        Ok(())
+    }
+}
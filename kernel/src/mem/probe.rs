@@ -0,0 +1,164 @@
+//! Memory accesses which may fault.
+//!
+//! Hardware bring-up often needs to access an address which may not be backed by anything, e.g.
+//! the configuration space of an absent PCI bus or a register of a device which may not exist.
+//! [probe_read] and [probe_write] perform a single access and return a [ProbeError] instead of
+//! panicking when the access raises a page fault, a general protection fault or a machine check.
+//!
+//! Before the access a probe records the address of the accessing instruction and of the code
+//! following it in a per-CPU fixup, interrupts are disabled until the probe completes. The
+//! exception handlers call [fixup], which resumes execution after the access when the exception was
+//! raised by the recorded instruction. Exceptions raised anywhere else are handled as normal.
+//!
+//! Machine checks are only recovered when `MCG_STATUS.EIPV` reports that the error was raised by
+//! the interrupted instruction, otherwise the error may belong to an earlier access and the machine
+//! check is fatal as normal.
+
+use core::cell::Cell;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+/// The exception raised by a probe.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ProbeError {
+    PageFault,
+    GeneralProtection,
+    MachineCheck,
+}
+
+/// Written by the probe's assembly, the layout must not be changed.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Fixup {
+    /// Address of the accessing instruction, `0` while no probe is running.
+    access: u64,
+    /// Address the probe resumes at after a fault.
+    resume: u64,
+    fault: Option<ProbeError>,
+}
+
+impl Fixup {
+    const EMPTY: Self = Self { access: 0, resume: 0, fault: None };
+}
+
+#[thread_local]
+static PROBE: Cell<Fixup> = Cell::new(Fixup::EMPTY);
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Types which can be accessed by a probe, each access is a single instruction of the type's width.
+pub trait Probe: Copy + sealed::Sealed {
+    /// `fixup` points to the [Fixup] of this CPU.
+    #[doc(hidden)]
+    unsafe fn read(addr: *const Self, fixup: *mut u64) -> Self;
+
+    /// `fixup` points to the [Fixup] of this CPU.
+    #[doc(hidden)]
+    unsafe fn write(addr: *mut Self, value: Self, fixup: *mut u64);
+}
+
+macro_rules! impl_probe {
+    ($t:ty, $width:literal, $class:ident, $modifier:literal) => {
+        impl sealed::Sealed for $t {}
+
+        impl Probe for $t {
+            unsafe fn read(addr: *const Self, fixup: *mut u64) -> Self {
+                let value: $t;
+                // SAFETY: The caller guarantees that the access has no side effects on memory which
+                // Rust can observe, faults are handled by `fixup`
+                unsafe {
+                    core::arch::asm!(
+                        "lea {tmp}, [rip + 2f]",
+                        "mov [{fixup}], {tmp}",
+                        "lea {tmp}, [rip + 3f]",
+                        "mov [{fixup} + 8], {tmp}",
+                        concat!("2: mov {value", $modifier, "}, ", $width, " ptr [{addr}]"),
+                        "3:",
+                        addr = in(reg) addr,
+                        fixup = in(reg) fixup,
+                        tmp = out(reg) _,
+                        value = out($class) value,
+                        options(nostack),
+                    );
+                }
+                value
+            }
+
+            unsafe fn write(addr: *mut Self, value: Self, fixup: *mut u64) {
+                // SAFETY: See `read`
+                unsafe {
+                    core::arch::asm!(
+                        "lea {tmp}, [rip + 2f]",
+                        "mov [{fixup}], {tmp}",
+                        "lea {tmp}, [rip + 3f]",
+                        "mov [{fixup} + 8], {tmp}",
+                        concat!("2: mov ", $width, " ptr [{addr}], {value", $modifier, "}"),
+                        "3:",
+                        addr = in(reg) addr,
+                        fixup = in(reg) fixup,
+                        tmp = out(reg) _,
+                        value = in($class) value,
+                        options(nostack),
+                    );
+                }
+            }
+        }
+    };
+}
+
+impl_probe!(u8, "byte", reg_byte, "");
+impl_probe!(u16, "word", reg, ":x");
+impl_probe!(u32, "dword", reg, ":e");
+impl_probe!(u64, "qword", reg, "");
+
+/// Runs a probe, `f` is given the fixup of this CPU.
+fn run<R>(f: impl FnOnce(*mut u64) -> R) -> Result<R, ProbeError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        PROBE.set(Fixup::EMPTY);
+        let r = f(PROBE.as_ptr().cast());
+        match PROBE.replace(Fixup::EMPTY).fault {
+            Some(e) => Err(e),
+            None => Ok(r),
+        }
+    })
+}
+
+/// Reads `addr` using a single access, returns an error if the access raised an exception.
+///
+/// # Safety
+///
+/// `addr` must be aligned, and reading it must not modify memory which is accessed by Rust. The
+/// read may have side effects on a device.
+///
+/// This must not be called from an exception handler.
+pub unsafe fn probe_read<T: Probe>(addr: *const T) -> Result<T, ProbeError> {
+    // SAFETY: Upheld by the caller
+    run(|fixup| unsafe { T::read(addr, fixup) })
+}
+
+/// Writes `value` to `addr` using a single access, returns an error if the access raised an
+/// exception.
+///
+/// # Safety
+///
+/// See [probe_read], writing `addr` must not modify memory which is accessed by Rust apart from
+/// `addr` itself.
+pub unsafe fn probe_write<T: Probe>(addr: *mut T, value: T) -> Result<(), ProbeError> {
+    // SAFETY: Upheld by the caller
+    run(|fixup| unsafe { T::write(addr, value, fixup) })
+}
+
+/// Called by the handler of `fault`. When the exception was raised by a probe the probe is resumed
+/// after its access and this returns `true`.
+pub(crate) fn fixup(sf: &mut InterruptStackFrame, fault: ProbeError) -> bool {
+    let f = PROBE.get();
+    if f.access == 0 || f.access != sf.instruction_pointer.as_u64() {
+        return false;
+    }
+    PROBE.set(Fixup { fault: Some(fault), ..f });
+    // SAFETY: The probe does not depend on the result of its access once it has faulted
+    unsafe { sf.as_mut().update(|v| v.instruction_pointer = VirtAddr::new(f.resume)) };
+    true
+}
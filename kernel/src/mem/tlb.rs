@@ -9,18 +9,55 @@
 //! Hootux' model for handling TLB synchronization is to always raise a shootdown when
 //! page table entries are modified (excl setting  "P" flag).
 //! Modules may implement their own TLB synchronization and use the [without_shootdowns] fn to improve performance
-
-use core::ops::Deref;
-use crate::interrupts::apic::Apic;
+//!
+//! A shootdown is only sent to the CPUs in the [ActiveCpus] of the address space which was modified,
+//! kernel mappings are present in every address space so they are sent to the CPUs in [KERNEL].
+//! TLB entries cached under another PCID are not invalidated by a shootdown, the generation of the
+//! address space is checked when it is loaded instead, see [super::addr_space].
+//! The initiator publishes the request, sets a pending bit for each target and raises
+//! [Ipi::TlbShootdown](crate::mp::ipi::Ipi::TlbShootdown) on them. Each target acknowledges the
+//! request by clearing its bit once it has invalidated the entries, the initiator waits until all
+//! bits are clear. Only one request is in progress at a time, CPUs waiting to send a request handle
+//! the current one while they wait so two initiators never wait for each other.
+//!
+//! Unmapping many pages raises one shootdown per page, [batch] defers them and raises a single
+//! shootdown containing all the pages instead. Batches run while the allocators are locked, so the
+//! deferred regions are held in a fixed size buffer, a batch which overflows it invalidates the
+//! entire TLB including global pages instead, because the deferred regions may be kernel pages.
+
+use core::cell::Cell;
+use core::sync::atomic;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use x86_64::structures::paging::{Page, PageSize, Size1GiB, Size2MiB, Size4KiB};
+use x86_64::VirtAddr;
 use crate::interrupts::apic;
+use crate::mp::{ipi, CpuIndex};
+
+const MAX_CPUS: usize = crate::task::affinity::MAX_CPUS;
+
+/// Batches invalidating more than this many pages invalidate the entire TLB instead.
+const FULL_FLUSH_PAGES: u64 = 64;
+/// Number of regions a batch can defer, see [DeferredEntries].
+const BATCH_ENTRIES: usize = 32;
 
-const SHOOTDOWN_VECTOR: crate::interrupts::InterruptIndex = crate::interrupts::InterruptIndex::TlbShootdown; // This uses a u8 because it needs to use a vector not an IRQ. This will use a private kernel API
-static SHOOTDOWN_SYNC: ShootdownSyncCounter = ShootdownSyncCounter::new();
 #[thread_local]
 static MASK_SHOOTDOWN: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(true); // default state should be true on bsp and false on ap
-static SHOOTDOWN_LIST: ShootdownListMutex = ShootdownListMutex::new();
 static SHOOTDOWN_WARN: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
 
+/// Held by the CPU sending a shootdown.
+static LOCK: AtomicBool = AtomicBool::new(false);
+/// The request in progress, only valid while a bit in [PENDING] is set.
+static REQUEST: AtomicPtr<ShootdownContent> = AtomicPtr::new(core::ptr::null_mut());
+/// CPUs which have not yet handled [REQUEST].
+static PENDING: [AtomicU64; MAX_CPUS / 64] = [const { AtomicU64::new(0) }; MAX_CPUS / 64];
+
+/// CPUs using the kernel's page tables, this is every CPU which has enabled shootdowns.
+pub static KERNEL: ActiveCpus = ActiveCpus::new();
+
+/// Entries deferred by [batch] on this CPU, `None` outside of a batch.
+#[thread_local]
+static BATCH: Cell<Option<DeferredEntries>> = Cell::new(None);
+
 /// This fn is to indicate that page tables are being modified and a shootdown is about to occur.
 /// This will indicate to other CPUs that page faults may occur and should be retried.
 pub fn shootdown_hint<T,F>(f: F) -> T
@@ -32,96 +69,148 @@ pub fn shootdown_hint<T,F>(f: F) -> T
     rc
 }
 
+fn slot(cpu: CpuIndex) -> (usize, u64) {
+    assert!((cpu as usize) < MAX_CPUS, "CPU {cpu} exceeds MAX_CPUS");
+    (cpu as usize / 64, 1 << (cpu % 64))
+}
+
+/// The CPUs which have an address space loaded and may have cached its TLB entries.
+///
+/// Also counts the shootdowns raised on the address space, see [Self::generation].
+pub struct ActiveCpus {
+    bits: [AtomicU64; MAX_CPUS / 64],
+    gen: AtomicU64,
+}
+
+impl ActiveCpus {
+    pub const fn new() -> Self {
+        Self { bits: [const { AtomicU64::new(0) }; MAX_CPUS / 64], gen: AtomicU64::new(0) }
+    }
+
+    /// Returns the number of shootdowns raised on the address space. This is incremented before the
+    /// targets are selected, so a CPU which activates the address space and then reads this either
+    /// receives the shootdown or observes the new generation.
+    pub fn generation(&self) -> u64 {
+        self.gen.load(Ordering::SeqCst)
+    }
+
+    /// Marks the address space as loaded by the calling CPU.
+    pub fn activate(&self) {
+        let (word, bit) = slot(crate::who_am_i());
+        self.bits[word].fetch_or(bit, Ordering::SeqCst);
+    }
+
+    /// Marks the address space as no longer loaded by the calling CPU, the CPU must not hold any
+    /// of its TLB entries.
+    pub fn deactivate(&self) {
+        let (word, bit) = slot(crate::who_am_i());
+        self.bits[word].fetch_and(!bit, Ordering::SeqCst);
+    }
+
+    pub fn contains(&self, cpu: CpuIndex) -> bool {
+        let (word, bit) = slot(cpu);
+        self.bits[word].load(Ordering::SeqCst) & bit != 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = CpuIndex> + '_ {
+        iter_bits(&self.bits)
+    }
+}
+
+fn iter_bits(bits: &[AtomicU64]) -> impl Iterator<Item = CpuIndex> + '_ {
+    bits.iter().enumerate().flat_map(|(i, w)| {
+        let w = w.load(Ordering::SeqCst);
+        (0..64).filter(move |b| w & 1 << b != 0).map(move |b| (i * 64 + b) as CpuIndex)
+    })
+}
+
 /// Initiates a TLB shootdown to all CPUs on the memory regions provided in `shootdown_content`.
 /// This should only be used to shootdown kernel memory regions.
+///
+/// Within [batch] single regions are deferred until the batch completes.
 pub fn shootdown(shootdown_content: ShootdownContent) {
+    // Entries cached under other PCIDs are only invalidated when they are loaded
+    KERNEL.gen.fetch_add(1, Ordering::SeqCst);
     if MASK_SHOOTDOWN.load(atomic::Ordering::Relaxed) {
         return;
     }
-    let _l = SHOOTDOWN_LIST.set(shootdown_content); // not used, dropped at the end of this scope
-    SHOOTDOWN_SYNC.sync(crate::mp::num_cpus(), || {
-        unsafe { apic::get_apic().send_ipi(apic::IpiTarget::AllNotThisCpu, apic::InterruptType::Fixed, SHOOTDOWN_VECTOR.as_u8()).unwrap() };
-        handle_shootdown(); // handle shootdown here while we're waiting for the others.
-    });
+    if let ShootdownContent::Short(entry) = shootdown_content {
+        if let Some(mut b) = BATCH.take() {
+            b.push(entry);
+            BATCH.set(Some(b));
+            return;
+        }
+    }
+    shootdown_on(&KERNEL, shootdown_content)
 }
 
-/// Performs a targeted shootdown to specified CPUs.
-/// This has a lesser performance impact than [shootdown],
-/// however should only be used when its known which CPUs have cached `shootdown_content`
+/// Invalidates `shootdown_content` on this CPU and every CPU in `cpus`, returns once all of them
+/// have invalidated it.
 ///
-/// Note that the CPU may speculatively cache `shootdown_content`.
-pub fn target_shootdown(targets: crate::mp::bitmap::CpuBMap, shootdown_content: ShootdownContent) {
+/// This must not be called with interrupts disabled while another CPU may be waiting for this one.
+pub fn shootdown_on(cpus: &ActiveCpus, shootdown_content: ShootdownContent) {
+    cpus.gen.fetch_add(1, Ordering::SeqCst);
     if MASK_SHOOTDOWN.load(atomic::Ordering::Relaxed) {
-        return
-    }
-    let c = if let Some(c) = targets.count() {
-        c
-    } else {
         return;
-    };
+    }
+    // Interrupts are disabled so a handler on this CPU can't raise a shootdown while the lock is held
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        while LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            // The CPU holding the lock may be waiting for this one
+            handle_pending();
+            core::hint::spin_loop();
+        }
 
-    let tgt_self = if targets.get(crate::mp::who_am_i()) {
-        targets.clear();
-        true
-    } else {
-        false
-    };
-
-    let _l = SHOOTDOWN_LIST.set(shootdown_content);
-    SHOOTDOWN_SYNC.sync(c, || {
-        for i in targets.iter() {
-            // SAFETY: The actions of the other CPU are
-            unsafe { apic::get_apic().send_ipi(apic::IpiTarget::Other(i), apic::InterruptType::Fixed, SHOOTDOWN_VECTOR.as_u8()).unwrap() }
+        let (me_word, me_bit) = slot(crate::who_am_i());
+        REQUEST.store(&shootdown_content as *const _ as *mut _, Ordering::Release);
+        for (i, (p, a)) in PENDING.iter().zip(cpus.bits.iter()).enumerate() {
+            let mut t = a.load(Ordering::SeqCst);
+            if i == me_word {
+                t &= !me_bit;
+            }
+            p.store(t, Ordering::Release);
         }
-        if tgt_self {
-            handle_shootdown();
+        for cpu in iter_bits(&PENDING) {
+            ipi::send(ipi::IpiTarget::Other(cpu), ipi::Ipi::TlbShootdown).expect("Failed to send TLB shootdown");
         }
-    });
-}
 
-struct ShootdownSyncCounter {
-    lock: core::sync::atomic::AtomicBool,
-    count: atomic::Atomic<crate::mp::CpuCount>,
+        shootdown_content.invalidate();
+        while PENDING.iter().any(|p| p.load(Ordering::Acquire) != 0) {
+            core::hint::spin_loop();
+        }
+        REQUEST.store(core::ptr::null_mut(), Ordering::Relaxed);
+        LOCK.store(false, Ordering::Release);
+    })
 }
 
-impl ShootdownSyncCounter {
-    const fn new() -> Self {
-        Self {
-            lock: core::sync::atomic::AtomicBool::new(false),
-            count: atomic::Atomic::new(0)
-        }
+/// Calls `f` and defers the shootdowns of single regions raised by this CPU within it. A single
+/// shootdown is raised for all of them when `f` returns, or the entire TLB is invalidated if
+/// more than [FULL_FLUSH_PAGES] pages or more than [BATCH_ENTRIES] discontiguous regions are
+/// deferred.
+///
+/// Other CPUs may access the deferred regions until `f` returns. The pages must not be reused and
+/// their frames must not be freed within `f`. Nested calls are part of the outermost batch.
+pub fn batch<T, F>(f: F) -> T
+    where F: FnOnce() -> T
+{
+    if MASK_SHOOTDOWN.load(atomic::Ordering::Relaxed) {
+        return f();
     }
-
-    /// Syncs all other CPUs to this one.
-    ///
-    /// This fn will acquire an internal spinlock, once it is acquired `f` is called. `f`
-    /// must ensure that [self.shoot] is called `count` times.
-    /// This fn will spin until [self.shoot] has been called `count` times.
-    fn sync<T,F>(&self, count: crate::mp::CpuCount, f: F) -> T
-        where F: FnOnce() -> T
-    {
-        loop {
-            match self.lock.compare_exchange_weak(false, true, atomic::Ordering::Acquire, atomic::Ordering::Relaxed) {
-                Ok(_) => {
-                    self.count.store(count,atomic::Ordering::Release);
-                    let rc = f();
-                    while self.count.load(atomic::Ordering::Relaxed) != 0 {
-                        core::hint::spin_loop();
-                    }
-                    self.lock.store(false,atomic::Ordering::Release);
-                    return rc
-                },
-                Err(_) => {
-                    core::hint::spin_loop();
-                    continue;
-                },
+    match BATCH.take() {
+        Some(outer) => {
+            BATCH.set(Some(outer));
+            f()
+        }
+        None => {
+            BATCH.set(Some(DeferredEntries::EMPTY));
+            let r = f();
+            match BATCH.take().map(DeferredEntries::into_content) {
+                None | Some(ShootdownContent::None) => {}
+                Some(content) => shootdown_on(&KERNEL, content),
             }
+            r
         }
     }
-
-    fn shoot(&self) {
-        self.count.fetch_sub(1,atomic::Ordering::Release);
-    }
 }
 
 /// Blocks TLB-shootdowns from occurring within `f`.
@@ -140,16 +229,33 @@ pub unsafe fn without_shootdowns<T,F>(f: F) -> T
     r
 }
 
+/// Invalidates the entries of the current context on this CPU, excluding global pages. Unlike
+/// [x86_64::instructions::tlb::flush_all] the PCID of the context is kept.
+pub(crate) fn flush_local_context() {
+    ShootdownContent::FullContext.invalidate()
+}
+
+/// Enables shootdowns on this CPU and adds it to [KERNEL].
 pub(crate) fn enable_shootdowns() {
+    KERNEL.activate();
     MASK_SHOOTDOWN.store(false,atomic::Ordering::Release);
 }
 
+/// Handles the current request if this CPU has not yet handled it.
+fn handle_pending() {
+    let (word, bit) = slot(crate::who_am_i());
+    if PENDING[word].load(Ordering::Acquire) & bit == 0 {
+        return;
+    }
+    // SAFETY: The initiator keeps the request alive until every pending bit is cleared
+    unsafe { &*REQUEST.load(Ordering::Acquire) }.invalidate();
+    PENDING[word].fetch_and(!bit, Ordering::Release);
+}
+
 fn handle_shootdown() {
     // SAFETY: This interrupt is handled properly, it is edge-triggered so EOI can be sent before handling.
     unsafe { apic::apic_eoi() }
-    // this might seem a bit misleading
-    SHOOTDOWN_LIST.visit().unwrap().invalidate();
-    SHOOTDOWN_SYNC.shoot();
+    handle_pending();
 }
 
 cfg_if::cfg_if!{
@@ -163,111 +269,9 @@ cfg_if::cfg_if!{
     }
 }
 
-
-
-
-/// This is an odd mutex, it must be locked by a writer for the data to be read.
-/// ```ignore
-/// fn example(data: alloc::vec::Vec<CompressedTlbEntry>) {
-///     let m = ShootdownListMutex::new();
-///     let l = m.set();
-///     let r = m.get();
-///     for i in r {
-///         // do things
-///     }
-///     drop(r);
-///     drop(l); // this will deadlock if r is not dropped.
-///     assert_eq!(m.get(),None) // this will panic, because `m` contains no data
-///
-/// }
-// todo should this be moved to hootux::util::mutex
-struct ShootdownListMutex {
-    master: atomic::Atomic<bool>,
-    guest: atomic::Atomic<usize>,
-    data: core::cell::UnsafeCell<ShootdownContent>
-}
-
-// SAFETY: This contains its own synchronization
-unsafe impl Sync for ShootdownListMutex {}
-unsafe impl Send for ShootdownListMutex {}
-
-struct ShootdownMutexMasterGuard<'a> {
-    parent: &'a ShootdownListMutex
-}
-
-
-impl<'a> Drop for ShootdownMutexMasterGuard<'a> {
-    fn drop(&mut self) {
-        // master can leave while guests are present.
-        // spin until they leave.
-        while self.parent.guest.load(atomic::Ordering::Relaxed) > 1 {
-            core::hint::spin_loop();
-        }
-        self.parent.guest.fetch_sub(1,atomic::Ordering::Release);
-        *unsafe { &mut *self.parent.data.get() } = ShootdownContent::None;
-        self.parent.master.store(false,atomic::Ordering::Release);
-    }
-}
-
-impl ShootdownListMutex {
-    const fn new() -> Self {
-        Self {
-            master: atomic::Atomic::new(false),
-            guest: atomic::Atomic::new(0),
-            data: core::cell::UnsafeCell::new(ShootdownContent::None),
-        }
-    }
-    fn set(&self, data: ShootdownContent) -> ShootdownMutexMasterGuard {
-        while let Err(_) = self.master.compare_exchange_weak(false,true,atomic::Ordering::Acquire,atomic::Ordering::Relaxed) {
-            core::hint::spin_loop();
-        }
-        unsafe  { self.data.get().write(data) }
-        self.guest.fetch_add(1,atomic::Ordering::Acquire);
-        ShootdownMutexMasterGuard { parent: self }
-    }
-
-    fn visit(&self) -> Option<ShootdownVisitorGuard> {
-        if self.master.load(atomic::Ordering::Acquire) {
-            // Either data is bing dropped or initialized if visitors is 0.
-            // Loop until it is >1
-            let mut m = self.master.load(atomic::Ordering::Acquire);
-            while m && self.guest.load(atomic::Ordering::Acquire) == 0 {
-                // self was released, there is no data to acquire
-                if m == false { return None }
-                core::hint::spin_loop();
-                m = self.master.load(atomic::Ordering::Acquire);
-            }
-            self.guest.fetch_add(1,atomic::Ordering::Acquire);
-            Some(ShootdownVisitorGuard { parent: self })
-        } else {
-            None
-        }
-    }
-}
-
-
-struct ShootdownVisitorGuard<'a> {
-    parent: &'a ShootdownListMutex
-}
-
-impl<'a> Drop for ShootdownVisitorGuard<'a> {
-    fn drop(&mut self) {
-        self.parent.guest.fetch_sub(1, atomic::Ordering::Release);
-    }
-}
-
-impl<'a> Deref for ShootdownVisitorGuard<'a> {
-    type Target = ShootdownContent;
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.parent.data.get() }
-    }
-}
-
-
-
 /// Compressed version of [TlbDropEntry] using only 8bytes. The len of the compressed value has
 /// a limit depending on the page size it represents.
-// The count is stored as count - 1. So a value of 0 is a count of 1
+// Bits 0..2 contain the page size, the remaining bits below the page size contain count - 1.
 #[cfg(target_arch = "x86_64")]
 #[derive(Debug, Copy, Clone)]
 pub struct CompressedTlbDropEntry {
@@ -283,173 +287,150 @@ pub struct CompressedTlbDropEntry {
 ///
 /// Shootdowns may need to be performed on multiple regions of memory, so [Self] may need to be
 /// stored in a [alloc::vec::Vec], however storing more than 1028 entries may require another
-/// shootdown, [CompressedTlbEntry] is provided to decrease the amount of heap memory required
+/// shootdown, [CompressedTlbDropEntry] is provided to decrease the amount of heap memory required
 /// to perform a shootdown
 #[cfg(target_arch = "x86_64")]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum TlbDropEntry {
     Kib4 {
-        addr: x86_64::structures::paging::Page<x86_64::structures::paging::Size4KiB>,
+        addr: Page<Size4KiB>,
         count: u32,
     },
     Mib2 {
-        addr: x86_64::structures::paging::Page<x86_64::structures::paging::Size4KiB>,
+        addr: Page<Size4KiB>,
         count: u32,
     },
     Gib1 {
-        addr: x86_64::structures::paging::Page<x86_64::structures::paging::Size4KiB>,
+        addr: Page<Size4KiB>,
         count: u32,
     }
 }
 
 impl TlbDropEntry {
+    /// Returns the start address, the page size and the number of pages.
+    fn parts(&self) -> (VirtAddr, u64, u32) {
+        match *self {
+            TlbDropEntry::Kib4 { addr, count } => (addr.start_address(), Size4KiB::SIZE, count),
+            TlbDropEntry::Mib2 { addr, count } => (addr.start_address(), Size2MiB::SIZE, count),
+            TlbDropEntry::Gib1 { addr, count } => (addr.start_address(), Size1GiB::SIZE, count),
+        }
+    }
+
+    fn from_parts(start: VirtAddr, size: u64, count: u32) -> Self {
+        let addr = Page::containing_address(start);
+        match size {
+            Size4KiB::SIZE => TlbDropEntry::Kib4 { addr, count },
+            Size2MiB::SIZE => TlbDropEntry::Mib2 { addr, count },
+            _ => TlbDropEntry::Gib1 { addr, count },
+        }
+    }
+
     fn flush(self) {
-        match self {
-            TlbDropEntry::Kib4 { mut addr, count } => {
-                for _ in 0..count {
-                    x86_64::instructions::tlb::flush(addr.start_address());
-                    addr += 1;
-                }
-            }
-            TlbDropEntry::Mib2 { mut addr, count } => {
-                for _ in 0..count {
-                    x86_64::instructions::tlb::flush(addr.start_address());
-                    addr += 1;
-                }
-            }
-            TlbDropEntry::Gib1 { mut addr, count } => {
-                for _ in 0..count {
-                    x86_64::instructions::tlb::flush(addr.start_address());
-                    addr += 1;
-                }
-            }
+        let (start, size, count) = self.parts();
+        for i in 0..count as u64 {
+            x86_64::instructions::tlb::flush(start + i * size);
         }
     }
 
     /// Attempts to append `other` to `self`.
-    /// To succeed both must be the same variant, and `self.count` must not overflow.
+    /// To succeed both must be the same variant, `other` must start where `self` ends and
+    /// `self.count` must not overflow. Returns `other` if it was not appended.
     pub fn append(&mut self, other: Self) -> Option<Self> {
-        match (self, other) {
-            ( TlbDropEntry::Kib4 { addr,count }, TlbDropEntry::Kib4 { addr: op, count: oc } ) => {
-                if *addr + *count as u64 + 1 == op {
-                    *count = count.checked_add(oc)?
-                }
+        let (start, size, count) = self.parts();
+        let (o_start, o_size, o_count) = other.parts();
+        if size == o_size && start.as_u64().checked_add(count as u64 * size) == Some(o_start.as_u64()) {
+            if let Some(count) = count.checked_add(o_count) {
+                *self = Self::from_parts(start, size, count);
+                return None;
             }
-            ( TlbDropEntry::Mib2 { addr,count }, TlbDropEntry::Mib2 { addr: op, count: oc } ) => {
-                if *addr + *count as u64 + 1 == op {
-                    *count = count.checked_add(oc)?
-                }
-            }
-            ( TlbDropEntry::Gib1 { addr,count }, TlbDropEntry::Gib1 { addr: op, count: oc } ) => {
-                if *addr + *count as u64 + 1 == op {
-                    *count = count.checked_add(oc)?
-                }
-            }
-            _ => {}
         }
-        None
+        Some(other)
     }
 }
 
 impl From<CompressedTlbDropEntry> for TlbDropEntry {
     fn from(value: CompressedTlbDropEntry) -> Self {
-        match value.raw & 0b11 {
-            0 => {
-                // SAFETY: This is safe because the address is always 4K aligned
-                let page = unsafe { x86_64::structures::paging::Page::from_start_address_unchecked(x86_64::VirtAddr::new(value.raw & !((1 << 13) - 1))) };
-                let count = ((value.raw & ((1 << 11) - 1) << 2) >> 2) as u32;
-                Self::Kib4 {
-                    addr: page,
-                    count,
-                }
-            }
-            1 => {
-                let page = unsafe { x86_64::structures::paging::Page::from_start_address_unchecked(x86_64::VirtAddr::new(value.raw & !((1 << 22) - 1))) };
-                let count = ((value.raw & ((1 << 20) - 1) << 2) >> 2) as u32;
-                Self::Mib2 {
-                    addr: page,
-                    count,
-                }
-            }
-            2 => {
-                let page = unsafe { x86_64::structures::paging::Page::from_start_address_unchecked(x86_64::VirtAddr::new(value.raw & !((1 << 31) - 1))) };
-                let count = ((value.raw & ((1 << 29) - 1) << 2) >> 2) as u32;
-                Self::Gib1 {
-                    addr: page,
-                    count,
-                }
-            }
-            3 => panic!("{} Failed to read {value:x?}", core::any::type_name::<Self>()),
-
-            // SAFETY: This is safe matched value is `& 3` only 0..=3 values are possible
-            _ => unsafe { core::hint::unreachable_unchecked() }
-        }
+        let size = match value.raw & 0b11 {
+            0 => Size4KiB::SIZE,
+            1 => Size2MiB::SIZE,
+            2 => Size1GiB::SIZE,
+            _ => panic!("{} Failed to read {value:x?}", core::any::type_name::<Self>()),
+        };
+        let count = ((value.raw & (size - 1)) >> 2) as u32 + 1;
+        Self::from_parts(VirtAddr::new(value.raw & !(size - 1)), size, count)
     }
 }
 
 impl<S: x86_64::structures::paging::PageSize + 'static> From<x86_64::structures::paging::Page<S>> for TlbDropEntry {
     fn from(value: x86_64::structures::paging::Page<S>) -> Self {
-        use core::any::TypeId;
-        // SAFETY: These unchecked Page constructors ar basically just typecasting from Page<T> into Page<Size#>
-        // Comparisons are evaluated at compile time
-        if TypeId::of::<S>() == TypeId::of::<x86_64::structures::paging::Size4KiB>() {
-            Self::Kib4 {
-                addr: unsafe { x86_64::structures::paging::Page::from_start_address_unchecked(value.start_address()) },
-                count: 1,
-            }
-        }
-        else if TypeId::of::<S>() == TypeId::of::<x86_64::structures::paging::Size2MiB>() {
-            Self::Mib2 {
-                addr: unsafe { x86_64::structures::paging::Page::from_start_address_unchecked(value.start_address()) },
-                count: 1,
-            }
-        }
-        else if TypeId::of::<S>() == TypeId::of::<x86_64::structures::paging::Size1GiB>() {
-            Self::Gib1 {
-                addr: unsafe { x86_64::structures::paging::Page::from_start_address_unchecked(value.start_address()) },
-                count: 1,
-            }
-        }
-        else {
-            // This will be dropped by the linker
-            unreachable!()
-        }
+        Self::from_parts(value.start_address(), S::SIZE, 1)
     }
 }
 
 impl TryInto<CompressedTlbDropEntry> for TlbDropEntry {
     type Error = (CompressedTlbDropEntry, Self);
     fn try_into(self) -> Result<CompressedTlbDropEntry, Self::Error> {
-        match self {
-            TlbDropEntry::Kib4 { addr, count } => {
-                let mut raw = addr.start_address().as_u64();
-                if count > (0x1000 >> 2) {
-                    Err((CompressedTlbDropEntry { raw }, Self::Kib4 { addr: addr + (0x1000 >> 2), count: count + 0x1000 >> 2 }))
-                } else {
-                    raw &= (count as u64 - 1) << 2;
-                    Ok(CompressedTlbDropEntry { raw })
-                }
-            }
-            TlbDropEntry::Mib2 { addr, count } => {
-                let mut raw = addr.start_address().as_u64();
-                if count > (0x200000 >> 2) {
-                    Err((CompressedTlbDropEntry { raw }, Self::Kib4 { addr: addr + (0x200000u64 >> 2), count: count + 0x1000 >> 2 }))
-                } else {
-                    raw &= (count as u64 - 1) << 2;
+        let (start, size, count) = self.parts();
+        let kind = match size {
+            Size4KiB::SIZE => 0,
+            Size2MiB::SIZE => 1,
+            _ => 2,
+        };
+        // count - 1 is stored in bits 2..log2(size)
+        let max = size >> 2;
+        let n = (count as u64).min(max);
+        let raw = start.as_u64() | (n - 1) << 2 | kind;
+        if count as u64 > max {
+            Err((CompressedTlbDropEntry { raw }, Self::from_parts(start + n * size, size, count - n as u32)))
+        } else {
+            Ok(CompressedTlbDropEntry { raw })
+        }
+    }
+}
 
-                    Ok(CompressedTlbDropEntry { raw })
-                }
-            }
-            TlbDropEntry::Gib1 { addr, count } => {
-                let mut raw = addr.start_address().as_u64();
-                if count > (0x10000000 >> 2) {
-                    // I'm not entirely sure if this branch is possible
-                    Err((CompressedTlbDropEntry { raw }, Self::Kib4 { addr: addr + (0x10000000u64 >> 2), count: count + 0x1000 >> 2 }))
-                } else {
-                    raw &= (count as u64 - 1) << 2;
-                    Ok(CompressedTlbDropEntry { raw })
-                }
+/// Regions deferred by [batch]. The regions are stored inline because deferring a region must not
+/// allocate, see the module documentation.
+#[derive(Copy, Clone)]
+pub struct DeferredEntries {
+    entries: [Option<TlbDropEntry>; BATCH_ENTRIES],
+    len: usize,
+    /// Set when a region did not fit, the entire TLB must be invalidated.
+    overflow: bool,
+}
+
+impl DeferredEntries {
+    const EMPTY: Self = Self { entries: [None; BATCH_ENTRIES], len: 0, overflow: false };
+
+    fn iter(&self) -> impl Iterator<Item = TlbDropEntry> + '_ {
+        self.entries[..self.len].iter().flatten().copied()
+    }
+
+    /// Appends `entry`, merging it into the last region if they are contiguous.
+    fn push(&mut self, entry: TlbDropEntry) {
+        let entry = match self.len.checked_sub(1).and_then(|i| self.entries[i].as_mut()) {
+            Some(last) => match last.append(entry) {
+                Some(e) => e,
+                None => return,
+            },
+            None => entry,
+        };
+        match self.entries.get_mut(self.len) {
+            Some(slot) => {
+                *slot = Some(entry);
+                self.len += 1;
             }
+            None => self.overflow = true,
+        }
+    }
+
+    /// Returns the content invalidating the deferred regions.
+    fn into_content(self) -> ShootdownContent {
+        let pages: u64 = self.iter().map(|e| e.parts().2 as u64).sum();
+        match self.len {
+            _ if self.overflow || pages > FULL_FLUSH_PAGES => ShootdownContent::All,
+            0 => ShootdownContent::None,
+            1 => ShootdownContent::Short(self.entries[0].unwrap()),
+            _ => ShootdownContent::Deferred(self),
         }
     }
 }
@@ -464,8 +445,12 @@ pub enum ShootdownContent {
     ///
     /// If the vec is longer than 256 entries then dropping `self` may raise another shootdown.
     Long(alloc::vec::Vec<CompressedTlbDropEntry>),
+    /// Invalidate the regions deferred by [batch].
+    Deferred(DeferredEntries),
     /// Invalidate the entire context, excl global pages
     FullContext,
+    /// Invalidate the entire TLB, including global pages.
+    All,
     // Invalidate the kernel region.
     // Kernel
 }
@@ -482,6 +467,7 @@ impl ShootdownContent {
                     u.flush();
                 }
             }
+            ShootdownContent::Deferred(d) => d.iter().for_each(TlbDropEntry::flush),
             ShootdownContent::FullContext => {
                 cfg_if::cfg_if!(
                     if #[cfg(target_arch = "x86_64")] {
@@ -498,6 +484,19 @@ impl ShootdownContent {
                     }
                 );
             }
+            ShootdownContent::All => {
+                use x86_64::registers::control::{Cr4, Cr4Flags};
+                let cr4 = Cr4::read();
+                if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+                    // SAFETY: Toggling PGE invalidates the entire TLB, it is restored immediately
+                    unsafe {
+                        Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+                        Cr4::write(cr4);
+                    }
+                } else {
+                    x86_64::instructions::tlb::flush_all();
+                }
+            }
         }
     }
 }
@@ -507,60 +506,3 @@ impl<S: x86_64::structures::paging::PageSize + 'static> From<x86_64::structures:
         Self::Short(value.into())
     }
 }
-
-/*
-impl<T,U> From<T> for ShootdownContent
-    where
-        T: Iterator<Item = U>,
-        U: Into<TlbDropEntry>
-{
-    fn from(value: T) -> Self {
-        let mut v = alloc::vec::Vec::new();
-        let mut hold = None;
-
-        for i in value.map(|i| i.into()) {
-            if hold == None {
-                hold = Some(i);
-            } else {
-                if let Some(mut of) = hold.as_mut().unwrap().append(i) {
-                    loop {
-                        match of.try_into() {
-                            Ok(comp) => {
-                                v.push(comp);
-                                break;
-                            },
-                            Err((comp,oof)) => {
-                                v.push(comp);
-                                // do it again.
-                                of = oof;
-                            }
-                        }
-                    }
-                }
-            }
-        }
-
-        loop {
-            match hold.map(|i| i.try_into()) {
-                Some(Ok(comp)) => {
-                    v.push(comp);
-                    break;
-                },
-                Some(Err((comp, of))) => {
-                    v.push(comp);
-                    // do it again.
-                    hold = Some(of);
-                }
-                None => {break}
-            }
-        }
-
-        if v.len() > 0 {
-            Self::Long(v)
-        } else {
-            Self::Short(hold.unwrap()) // if v.len() is 0 then this cannot be None
-        }
-    }
-}
-
- */
\ No newline at end of file
@@ -0,0 +1,51 @@
+//! Kernel memory which may be mapped into user processes.
+//!
+//! A [VmObject] lists the physical frames of a kernel object together with the access user
+//! processes are given to it, e.g. the clock data of [crate::time::vdso] or the kernel log buffer
+//! of [crate::klog]. There are no user processes yet, the process loader is expected to map
+//! [VmObject::frames] using [VmObject::page_flags].
+
+use alloc::vec::Vec;
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::PhysAddr;
+
+pub struct VmObject {
+    frames: Vec<PhysFrame>,
+    writable: bool,
+}
+
+impl VmObject {
+    /// Describes the pages containing `obj`, which must be page aligned and must not share its
+    /// pages with other data because the whole pages are mapped.
+    ///
+    /// Returns `None` if a page of `obj` is not mapped.
+    pub fn from_static<T>(obj: &'static T, writable: bool) -> Option<Self> {
+        let addr = obj as *const T as usize;
+        assert_eq!(addr % super::PAGE_SIZE, 0, "VmObject must be page aligned");
+        let frames = (addr..addr + size_of::<T>())
+            .step_by(super::PAGE_SIZE)
+            .map(|page| Some(PhysFrame::containing_address(PhysAddr::new(super::mem_map::translate(page)?))))
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self { frames, writable })
+    }
+
+    /// Returns the frames of the object in order.
+    pub fn frames(&self) -> &[PhysFrame] {
+        &self.frames
+    }
+
+    /// Returns the size of the object in bytes, rounded up to a whole page.
+    pub fn len(&self) -> usize {
+        self.frames.len() * super::PAGE_SIZE
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    /// Returns the flags the object's pages must be mapped with.
+    pub fn page_flags(&self) -> PageTableFlags {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+        if self.writable { flags | PageTableFlags::WRITABLE } else { flags }
+    }
+}
@@ -47,7 +47,7 @@ pub(crate) fn init_wc() {
                 unsafe { control::Cr4::write(f) }
                 true
             } else {
-                x86_64::structures::paging::mapper::MapperFlushAll::new().flush_all();
+                super::tlb::flush_local_context();
                 false
             };
 
@@ -64,7 +64,7 @@ pub(crate) fn init_wc() {
             unsafe { x86_msr::architecture::Pat::write(pat) }
 
             // Manual says TLB flush is always required, and `wbinvd` may not be required but does not specify how to check
-            x86_64::structures::paging::mapper::MapperFlushAll::new().flush_all();
+            super::tlb::flush_local_context();
             // I assume that this is one of the checks though
             if raw_cpuid::cpuid!(1).edx & (1 << 27) == 0 {
                 // SAFETY: Doesn't do anything sketchey
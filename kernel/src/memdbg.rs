@@ -0,0 +1,286 @@
+//! Memory and register inspection for hardware bring-up.
+//!
+//! Commands are written to `/memdbg` one per line and the output of the last command is returned
+//! when the file is read. Numbers may be given in decimal or in hexadecimal with a `0x` prefix.
+//!
+//! - `hexdump virt|phys ADDR LEN`: Dumps at most [MAX_DUMP] bytes.
+//! - `peek virt|phys|port|msr ADDR [WIDTH]`: Reads a single register.
+//! - `poke virt|phys|port|msr ADDR VALUE [WIDTH]`: Writes a single register.
+//!
+//! `WIDTH` is the access size in bytes and defaults to `4`, port accesses may not be wider than
+//! `4` and MSR accesses are always `8`. Physical addresses are accessed via a temporary uncached
+//! mapping, virtual addresses are rejected if any byte is not mapped.
+//!
+//! `poke` is only available when the kernel is built with the `debug-poke` feature, otherwise it
+//! is rejected. Every poke is logged.
+//!
+//! Reading an MSR which is not implemented or a physical address which is not backed by anything
+//! may fault the CPU, reading device registers may have side effects.
+
+use crate::alloc_interface::MmioAlloc;
+use crate::fs::report::{Report, ReportFile};
+use crate::fs::vfs::{DevID, MajorNum};
+use crate::fs::IoError;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt::Write as _;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+
+const FS_NAME: &str = "/memdbg";
+
+/// Maximum number of bytes which may be dumped by a single command.
+pub const MAX_DUMP: usize = 0x1000;
+
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("memdbg").unwrap(););
+
+/// Output of the last command.
+static OUTPUT: spin::Mutex<String> = spin::Mutex::new(String::new());
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Space {
+    Virt,
+    Phys,
+    Port,
+    Msr,
+}
+
+impl Space {
+    fn parse(s: &str) -> Result<Self, &'static str> {
+        match s {
+            "virt" => Ok(Space::Virt),
+            "phys" => Ok(Space::Phys),
+            "port" => Ok(Space::Port),
+            "msr" => Ok(Space::Msr),
+            _ => Err("Expected virt, phys, port or msr"),
+        }
+    }
+}
+
+fn parse_num(s: Option<&str>) -> Result<u64, &'static str> {
+    let s = s.ok_or("Missing argument")?;
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| "Invalid number")
+}
+
+/// Checks that `width` is a legal access size for `space` at `addr`.
+fn check_width(space: Space, addr: u64, width: u64) -> Result<usize, &'static str> {
+    match (space, width) {
+        (Space::Msr, 8) => Ok(8),
+        (Space::Msr, _) => Err("MSR accesses must be 8 bytes wide"),
+        (Space::Port, 8) => Err("Port accesses may not be wider than 4 bytes"),
+        (Space::Port, _) if addr > u16::MAX as u64 => Err("Port number out of range"),
+        (_, 1 | 2 | 4 | 8) if addr % width == 0 => Ok(width as usize),
+        (_, 1 | 2 | 4 | 8) => Err("Address is not aligned to the access width"),
+        _ => Err("Width must be 1, 2, 4 or 8"),
+    }
+}
+
+/// Returns `addr` if `addr..addr+len` is entirely mapped.
+fn check_mapped(addr: u64, len: usize) -> Result<*mut u8, &'static str> {
+    let end = addr.checked_add(len as u64 - 1).ok_or("Range overflows the address space")?;
+    x86_64::VirtAddr::try_new(addr).and(x86_64::VirtAddr::try_new(end)).map_err(|_| "Address is not canonical")?;
+    let mut page = addr & !(crate::mem::PAGE_SIZE as u64 - 1);
+    while page <= end {
+        crate::mem::mem_map::translate(page as usize).ok_or("Address is not mapped")?;
+        page = match page.checked_add(crate::mem::PAGE_SIZE as u64) {
+            Some(p) => p,
+            None => break,
+        };
+    }
+    Ok(addr as *mut u8)
+}
+
+/// Calls `f` with a pointer to `len` bytes at `addr` in `space`.
+fn with_mapping<R>(space: Space, addr: u64, len: usize, f: impl FnOnce(*mut u8) -> R) -> Result<R, &'static str> {
+    match space {
+        Space::Virt => Ok(f(check_mapped(addr, len)?)),
+        Space::Phys => {
+            addr.checked_add(len as u64).ok_or("Range overflows the address space")?;
+            // Map from the start of the page so that ranges crossing a page boundary are fully mapped
+            let offset = addr as usize & (crate::mem::PAGE_SIZE - 1);
+            // SAFETY: The mapping is only accessed using volatile operations and dropped immediately.
+            let mut map = alloc::vec::Vec::<u8, _>::new_in(unsafe { MmioAlloc::new(addr as usize - offset) });
+            map.reserve_exact(offset + len);
+            // SAFETY: The mapping contains `offset + len` bytes
+            Ok(f(unsafe { map.as_mut_ptr().add(offset) }))
+        }
+        Space::Port | Space::Msr => Err("hexdump is not supported for ports or MSRs"),
+    }
+}
+
+/// Reads a `width` byte value from `ptr`.
+///
+/// # Safety
+///
+/// `ptr` must be mapped and aligned to `width`
+unsafe fn read_ptr(ptr: *mut u8, width: usize) -> u64 {
+    match width {
+        1 => core::ptr::read_volatile(ptr) as u64,
+        2 => core::ptr::read_volatile(ptr.cast::<u16>()) as u64,
+        4 => core::ptr::read_volatile(ptr.cast::<u32>()) as u64,
+        _ => core::ptr::read_volatile(ptr.cast::<u64>()),
+    }
+}
+
+/// Writes the low `width` bytes of `value` to `ptr`.
+///
+/// # Safety
+///
+/// See [read_ptr]
+#[cfg(feature = "debug-poke")]
+unsafe fn write_ptr(ptr: *mut u8, width: usize, value: u64) {
+    match width {
+        1 => core::ptr::write_volatile(ptr, value as u8),
+        2 => core::ptr::write_volatile(ptr.cast(), value as u16),
+        4 => core::ptr::write_volatile(ptr.cast(), value as u32),
+        _ => core::ptr::write_volatile(ptr.cast(), value),
+    }
+}
+
+fn hexdump(space: Space, addr: u64, len: usize) -> Result<String, &'static str> {
+    if len == 0 || len > MAX_DUMP {
+        return Err("Length must be between 1 and 4096");
+    }
+    with_mapping(space, addr, len, |ptr| {
+        let mut out = String::new();
+        let mut line = [0u8; 16];
+        for row in (0..len).step_by(16) {
+            let count = 16.min(len - row);
+            for (i, b) in line[..count].iter_mut().enumerate() {
+                // SAFETY: The whole range is mapped
+                *b = unsafe { core::ptr::read_volatile(ptr.add(row + i)) };
+            }
+            let _ = write!(out, "{:016x}:", addr + row as u64);
+            for i in 0..16 {
+                match line[..count].get(i) {
+                    Some(b) => { let _ = write!(out, " {b:02x}"); }
+                    None => out.push_str("   "),
+                }
+            }
+            out.push_str("  |");
+            out.extend(line[..count].iter().map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' }));
+            out.push_str("|\n");
+        }
+        out
+    })
+}
+
+fn peek(space: Space, addr: u64, width: usize) -> Result<u64, &'static str> {
+    match space {
+        // SAFETY: The port number and width have been checked, reads may have side effects.
+        Space::Port => Ok(unsafe {
+            match width {
+                1 => x86_64::instructions::port::Port::<u8>::new(addr as u16).read() as u64,
+                2 => x86_64::instructions::port::Port::<u16>::new(addr as u16).read() as u64,
+                _ => x86_64::instructions::port::Port::<u32>::new(addr as u16).read() as u64,
+            }
+        }),
+        // SAFETY: Reading an MSR does not modify the system state, this may fault if the MSR is not implemented.
+        Space::Msr => Ok(unsafe { x86_64::registers::model_specific::Msr::new(addr as u32).read() }),
+        // SAFETY: The address is mapped and aligned
+        _ => with_mapping(space, addr, width, |ptr| unsafe { read_ptr(ptr, width) }),
+    }
+}
+
+#[cfg(feature = "debug-poke")]
+fn poke(space: Space, addr: u64, value: u64, width: usize) -> Result<(), &'static str> {
+    if width < 8 && value >> (width * 8) != 0 {
+        return Err("Value does not fit in the access width");
+    }
+    log::warn!("memdbg: poke {space:?} {addr:#x} <- {value:#x} ({width} bytes)");
+    // SAFETY: Pokes are explicitly requested by the user in a debug build, they may do anything.
+    unsafe {
+        match space {
+            Space::Port => match width {
+                1 => x86_64::instructions::port::Port::<u8>::new(addr as u16).write(value as u8),
+                2 => x86_64::instructions::port::Port::<u16>::new(addr as u16).write(value as u16),
+                _ => x86_64::instructions::port::Port::<u32>::new(addr as u16).write(value as u32),
+            },
+            Space::Msr => x86_64::registers::model_specific::Msr::new(addr as u32).write(value),
+            _ => with_mapping(space, addr, width, |ptr| write_ptr(ptr, width, value))?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "debug-poke"))]
+fn poke(_: Space, _: u64, _: u64, _: usize) -> Result<(), &'static str> {
+    Err("poke requires the debug-poke feature")
+}
+
+/// Runs a single command and returns its output.
+pub fn run(cmd: &str) -> Result<String, &'static str> {
+    let mut args = cmd.split_whitespace();
+    let name = args.next().ok_or("No command given")?;
+    let space = Space::parse(args.next().ok_or("Missing address space")?)?;
+    let addr = parse_num(args.next())?;
+
+    let out = match name {
+        "hexdump" => hexdump(space, addr, parse_num(args.next())? as usize)?,
+        "peek" => {
+            let width = check_width(space, addr, args.next().map_or(Ok(if space == Space::Msr { 8 } else { 4 }), |w| parse_num(Some(w)))?)?;
+            let value = peek(space, addr, width)?;
+            alloc::format!("{addr:#x}: {value:#0w$x}\n", w = width * 2 + 2)
+        }
+        "poke" => {
+            let value = parse_num(args.next())?;
+            let width = check_width(space, addr, args.next().map_or(Ok(if space == Space::Msr { 8 } else { 4 }), |w| parse_num(Some(w)))?)?;
+            poke(space, addr, value, width)?;
+            String::new()
+        }
+        _ => return Err("Unknown command, expected hexdump, peek or poke"),
+    };
+    if args.next().is_some() {
+        log::warn!("memdbg: Ignored trailing arguments in \"{cmd}\"");
+    }
+    Ok(out)
+}
+
+/// Mounts the command file.
+pub fn init() {
+    crate::task::util::block_on!(crate::fs::get_vfs().mount_dev(Box::new(ReportFile::with_report(DevID::new(*MAJOR, 0), Arc::new(MemDbg))), FS_NAME))
+        .expect("Failed to mount memdbg to VFS");
+}
+
+/// Writing a command to this file runs it, reading returns the output of the last command.
+///
+/// If a command fails the write returns [IoError::InvalidData] and the error message is returned
+/// when the file is read. Only the superuser may access this file.
+struct MemDbg;
+
+impl Report for MemDbg {
+    fn report(&self) -> String {
+        OUTPUT.lock().clone()
+    }
+
+    fn writable(&self) -> bool {
+        true
+    }
+
+    fn mode(&self) -> u16 {
+        0o600
+    }
+
+    fn write<'a>(&'a self, data: &'a str) -> BoxFuture<'a, Result<(), IoError>> {
+        async move {
+            let mut output = String::new();
+            for line in data.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                match run(line) {
+                    Ok(out) => output.push_str(&out),
+                    Err(e) => {
+                        let _ = writeln!(output, "error: {e}");
+                        *OUTPUT.lock() = output;
+                        return Err(IoError::InvalidData);
+                    }
+                }
+            }
+            *OUTPUT.lock() = output;
+            Ok(())
+        }.boxed()
+    }
+}
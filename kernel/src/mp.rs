@@ -1,6 +1,7 @@
 
 mod init;
 pub mod bitmap;
+pub mod ipi;
 
 pub type CpuCount = u32;
 pub type CpuIndex = u32;
@@ -36,6 +36,10 @@ pub(super) unsafe fn start_mp(tls_data: *const u8, tls_file_size: usize, tls_dat
         Err(()) => return
     };
 
+    let tramp_phys = x86_64::PhysAddr::new(addr.as_u64());
+    // Failing to reserve is not fatal, it only means the owner isn't recorded.
+    let _ = crate::mem::phys_map::PHYS_MAP.reserve(tramp_phys, 4096, "AP trampoline");
+
     let init_gdt = addr + (unsafe { &_trampoline_data as *const _ as usize } - _trampoline as *const fn() as usize) as u64;
 
     let gdt_ptr = GdtPtr{size: 64, ptr: init_gdt.as_u64() as u32 + core::mem::offset_of!(TrampolineData,gdt) as u32}; // size is in bytes
@@ -80,6 +84,8 @@ pub(super) unsafe fn start_mp(tls_data: *const u8, tls_file_size: usize, tls_dat
         });
     }
     ap_init_sync();
+    // SAFETY: The trampoline is owned by `tramp_box` which is freed below
+    let _ = unsafe { crate::mem::phys_map::PHYS_MAP.release(tramp_phys, 4096) };
 }
 
 /// Brings up the AP specified by `id`.
@@ -307,6 +313,9 @@ fn long_mode_init() -> ! {
     }
 
     crate::interrupts::load_idt();
+    crate::interrupts::stats::register_cpu();
+    crate::system::watchdog::register_cpu();
+    crate::time::vdso::register_cpu();
     // todo lInt pins need to be configured
     crate::interrupts::apic::load_apic();
 
@@ -0,0 +1,173 @@
+//! Inter-processor interrupts.
+//!
+//! [send] raises one of the kernel's [Ipi]s on other CPUs. The vectors are allocated by [init],
+//! until then sending returns [IpiError::Unavailable].
+//!
+//! - [Ipi::Reschedule] wakes the target so it polls its run queue, the handler only declares EOI.
+//! - [Ipi::TlbShootdown] raises the vector handled by [crate::mem::tlb].
+//! - [call_on] and [call_all] run a function on other CPUs from their IPI handler and wait for it to
+//!   complete.
+//! - [halt_others] stops all other CPUs using an NMI, so CPUs with interrupts disabled are stopped
+//!   too. This is used by the panic handler.
+
+use super::CpuIndex;
+use crate::interrupts::apic::{Apic, InterruptType};
+use crate::interrupts::InterruptIndex;
+pub use crate::interrupts::apic::{IpiError, IpiTarget};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Ipi {
+    Reschedule,
+    TlbShootdown,
+    /// Runs the calls queued for the target, see [call_on].
+    Call,
+}
+
+static RESCHEDULE_IRQ: crate::util::Worm<InterruptIndex> = crate::util::Worm::new();
+static CALL_IRQ: crate::util::Worm<InterruptIndex> = crate::util::Worm::new();
+
+/// Set by [halt_others], CPUs which receive an NMI while this is set stop.
+static HALTING: AtomicBool = AtomicBool::new(false);
+
+struct Call {
+    cpu: CpuIndex,
+    f: Box<dyn FnOnce() + Send>,
+    done: Arc<AtomicBool>,
+}
+
+/// Calls waiting to be run by their target CPU.
+static CALLS: spin::Mutex<Vec<Call>> = spin::Mutex::new(Vec::new());
+
+/// Returns whether the vectors have been allocated by [init].
+pub fn is_ready() -> bool {
+    RESCHEDULE_IRQ.is_set() && CALL_IRQ.is_set()
+}
+
+/// Raises `ipi` on `target`.
+pub fn send(target: IpiTarget, ipi: Ipi) -> Result<(), IpiError> {
+    let vector = match ipi {
+        Ipi::Reschedule if RESCHEDULE_IRQ.is_set() => RESCHEDULE_IRQ.read().as_u8(),
+        Ipi::Call if CALL_IRQ.is_set() => CALL_IRQ.read().as_u8(),
+        Ipi::TlbShootdown => InterruptIndex::TlbShootdown.as_u8(),
+        _ => return Err(IpiError::Unavailable),
+    };
+    // SAFETY: The handlers of all kernel IPIs are always installed
+    unsafe { crate::interrupts::apic::get_apic().send_ipi(target, InterruptType::Fixed, vector) }
+}
+
+/// Wakes `cpu` so that it polls its run queue.
+pub fn reschedule(cpu: CpuIndex) -> Result<(), IpiError> {
+    send(IpiTarget::Other(cpu), Ipi::Reschedule)
+}
+
+fn reschedule_handler() {
+    // SAFETY: Called from the interrupt handler
+    unsafe { crate::interrupts::apic::apic_eoi() };
+}
+
+fn call_handler() {
+    // SAFETY: This is edge-triggered so EOI can be sent before handling.
+    unsafe { crate::interrupts::apic::apic_eoi() };
+    let me = crate::who_am_i();
+    loop {
+        let call = {
+            let mut l = CALLS.lock();
+            match l.iter().position(|c| c.cpu == me) {
+                Some(i) => l.swap_remove(i),
+                None => break,
+            }
+        };
+        (call.f)();
+        call.done.store(true, Ordering::Release);
+    }
+}
+
+/// Queues `f` to be called on `cpu`, returns the flag which is set once it has returned.
+fn queue(cpu: CpuIndex, f: Box<dyn FnOnce() + Send>) -> Result<Arc<AtomicBool>, IpiError> {
+    let done = Arc::new(AtomicBool::new(false));
+    without_interrupts(|| CALLS.lock().push(Call { cpu, f, done: done.clone() }));
+    if let Err(e) = send(IpiTarget::Other(cpu), Ipi::Call) {
+        without_interrupts(|| CALLS.lock().retain(|c| !Arc::ptr_eq(&c.done, &done)));
+        return Err(e);
+    }
+    Ok(done)
+}
+
+fn wait(done: &AtomicBool) {
+    while !done.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+}
+
+/// Calls `f` on `cpu` from its IPI handler and waits for it to return. When `cpu` is the calling
+/// CPU `f` is called directly.
+///
+/// `f` runs with interrupts disabled. This must be called with interrupts enabled, otherwise two
+/// CPUs calling each other would wait forever.
+pub fn call_on<F: FnOnce() + Send + 'static>(cpu: CpuIndex, f: F) -> Result<(), IpiError> {
+    debug_assert!(x86_64::instructions::interrupts::are_enabled());
+    if cpu == crate::who_am_i() {
+        f();
+        return Ok(());
+    }
+    wait(&queue(cpu, Box::new(f))?);
+    Ok(())
+}
+
+/// Calls `f` on every running CPU, including this one, and waits for all calls to return.
+///
+/// See [call_on], returns the first error. `f` is still called on the CPUs which could be
+/// interrupted.
+pub fn call_all(f: fn()) -> Result<(), IpiError> {
+    debug_assert!(x86_64::instructions::interrupts::are_enabled());
+    let me = crate::who_am_i();
+    let mut err = Ok(());
+    let mut pending = Vec::new();
+    for cpu in crate::interrupts::stats::cpus().into_iter().filter(|c| *c != me) {
+        match queue(cpu, Box::new(f)) {
+            Ok(done) => pending.push(done),
+            Err(e) => err = err.and(Err(e)),
+        }
+    }
+    without_interrupts(f);
+    pending.iter().for_each(|d| wait(d));
+    err
+}
+
+/// Stops all other CPUs, this does not wait for them to stop.
+///
+/// This may be called at any time, it does not take locks.
+pub fn halt_others() {
+    HALTING.store(true, Ordering::SeqCst);
+    if super::num_cpus() < 2 {
+        return;
+    }
+    // SAFETY: Other CPUs are stopped by the NMI handler, the local APIC is initialized because
+    // other CPUs are running. The lock is not taken because the caller may hold it.
+    let _ = unsafe {
+        crate::interrupts::apic::LOCAL_APIC.force_get_mut().send_ipi(IpiTarget::AllNotThisCpu, InterruptType::NMI, 0)
+    };
+}
+
+/// Returns whether [halt_others] has been called, the NMI handler stops the CPU when this is set.
+pub(crate) fn is_halting() -> bool {
+    HALTING.load(Ordering::SeqCst)
+}
+
+/// Allocates the IPI vectors, this must be called before other CPUs are started.
+pub fn init() {
+    let vector = crate::interrupts::reserve_irq(0, 1).expect("Failed to allocate IRQ for reschedule IPI");
+    crate::interrupts::vector_tables::alloc_irq_special(vector, reschedule_handler).expect("Failed to allocate IRQ for reschedule IPI");
+    // SAFETY: This is only called once during boot
+    unsafe { RESCHEDULE_IRQ.write(InterruptIndex::Generic(vector)) };
+
+    let vector = crate::interrupts::reserve_irq(0, 1).expect("Failed to allocate IRQ for call IPI");
+    crate::interrupts::vector_tables::alloc_irq_special(vector, call_handler).expect("Failed to allocate IRQ for call IPI");
+    // SAFETY: This is only called once during boot
+    unsafe { CALL_IRQ.write(InterruptIndex::Generic(vector)) };
+}
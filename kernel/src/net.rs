@@ -0,0 +1,53 @@
+//! Networking.
+//!
+//! This contains the protocol state shared between network interfaces, link drivers register
+//! each port as an [interface::Interface].
+
+pub mod buffer;
+pub mod capture;
+pub mod fetch;
+pub mod filter;
+pub mod interface;
+pub mod neighbor;
+pub mod tftp;
+
+/// An IEEE 802 MAC address.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: Self = Self([0xff; 6]);
+
+    /// Returns whether this is a group address, this includes the broadcast address.
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
+impl core::fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+impl core::str::FromStr for MacAddr {
+    type Err = ();
+
+    /// Parses six colon or hyphen separated hexadecimal octets.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut addr = [0; 6];
+        let mut parts = s.split([':', '-']);
+        for b in &mut addr {
+            let p = parts.next().ok_or(())?;
+            if p.len() != 2 {
+                return Err(());
+            }
+            *b = u8::from_str_radix(p, 16).map_err(|_| ())?;
+        }
+        match parts.next() {
+            Some(_) => Err(()),
+            None => Ok(Self(addr)),
+        }
+    }
+}
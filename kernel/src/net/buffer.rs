@@ -0,0 +1,243 @@
+//! Packet buffers.
+//!
+//! A [PacketBuff] holds a single packet within a fixed size buffer allocated from a
+//! [BufferPool]. The packet occupies a window of the buffer, the space before the window is
+//! headroom and the space after it is tailroom. Transmitted packets are allocated with enough
+//! headroom for all headers so each layer can [PacketBuff::push] its header in front of the
+//! payload without copying. Received packets are written by the NIC and each layer
+//! [PacketBuff::pull]s its header off the front.
+//!
+//! Cloning a packet shares the buffer, so a received packet may be delivered to multiple
+//! consumers without copying. Clones may adjust their window independently, but the first
+//! modification of shared data copies the buffer. [super::capture] and [super::filter] only
+//! inspect frames through [PacketBuff::data], a capture copies the bytes it records so that it
+//! does not hold pool buffers while its records wait to be read.
+//!
+//! Buffers are allocated from DMA memory in [BUFFER_SIZE] pieces which never cross a page, so
+//! each buffer is physically contiguous and can be described by a single descriptor. Pools cache
+//! freed buffers, drivers whose hardware can not address all memory should use their own pool
+//! with an appropriate [MemRegion].
+
+use crate::alloc_interface::DmaAlloc;
+use crate::mem::dma::DmaTarget;
+use crate::mem::{MemRegion, PAGE_SIZE};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+
+/// Size of each buffer, this is large enough for an Ethernet frame.
+pub const BUFFER_SIZE: usize = 2048;
+/// Headroom reserved by [alloc], this is enough for the link, network and transport headers.
+pub const DEFAULT_HEADROOM: usize = 128;
+
+const _: () = assert!(PAGE_SIZE % BUFFER_SIZE == 0);
+
+lazy_static::lazy_static! {
+    static ref DEFAULT_POOL: Arc<BufferPool> = BufferPool::new(MemRegion::Mem64);
+}
+
+/// Allocates a packet with [DEFAULT_HEADROOM] from the default pool.
+pub fn alloc() -> PacketBuff {
+    DEFAULT_POOL.alloc(DEFAULT_HEADROOM)
+}
+
+/// Returns the default pool, which may be addressed using 64-bit DMA.
+pub fn default_pool() -> &'static Arc<BufferPool> {
+    &DEFAULT_POOL
+}
+
+/// Pointer to a free buffer.
+struct Slot(NonNull<u8>);
+
+// SAFETY: Slots are only accessed by the owner of the buffer
+unsafe impl Send for Slot {}
+
+/// A cache of DMA buffers.
+pub struct BufferPool {
+    region: MemRegion,
+    free: spin::Mutex<Vec<Slot>>,
+    /// Pages which buffers are allocated from, these are never freed while the pool exists.
+    pages: spin::Mutex<Vec<Box<[u8; PAGE_SIZE], DmaAlloc>>>,
+}
+
+impl BufferPool {
+    /// Constructs an empty pool which allocates from `region`.
+    pub fn new(region: MemRegion) -> Arc<Self> {
+        Arc::new(Self { region, free: spin::Mutex::new(Vec::new()), pages: spin::Mutex::new(Vec::new()) })
+    }
+
+    /// Allocates a packet with `headroom` bytes before the data, the packet is initially empty.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `headroom` is larger than [BUFFER_SIZE].
+    pub fn alloc(self: &Arc<Self>, headroom: usize) -> PacketBuff {
+        assert!(headroom <= BUFFER_SIZE);
+        let slot = self.free.lock().pop();
+        let slot = slot.unwrap_or_else(|| self.grow());
+        PacketBuff { buff: Arc::new(Buffer { ptr: slot.0, pool: self.clone() }), start: headroom, end: headroom }
+    }
+
+    /// Allocates a new page and returns one of its buffers, the rest are added to the free list.
+    fn grow(&self) -> Slot {
+        let mut page = Box::new_in([0u8; PAGE_SIZE], DmaAlloc::new(self.region, PAGE_SIZE));
+        let base = page.as_mut_ptr();
+        let mut slots = (0..PAGE_SIZE / BUFFER_SIZE).map(|i| {
+            // SAFETY: The offset is within the page
+            Slot(unsafe { NonNull::new_unchecked(base.add(i * BUFFER_SIZE)) })
+        });
+        let first = slots.next().unwrap();
+        self.free.lock().extend(slots);
+        self.pages.lock().push(page);
+        first
+    }
+
+    /// Returns the number of buffers allocated by this pool and how many of them are free.
+    pub fn stats(&self) -> (usize, usize) {
+        (self.pages.lock().len() * (PAGE_SIZE / BUFFER_SIZE), self.free.lock().len())
+    }
+}
+
+/// A buffer owned by a packet, this is returned to its pool when dropped.
+struct Buffer {
+    ptr: NonNull<u8>,
+    pool: Arc<BufferPool>,
+}
+
+// SAFETY: The buffer is only mutated through an exclusive reference
+unsafe impl Send for Buffer {}
+unsafe impl Sync for Buffer {}
+
+impl Buffer {
+    fn as_ptr(&self) -> *mut [u8] {
+        core::ptr::slice_from_raw_parts_mut(self.ptr.as_ptr(), BUFFER_SIZE)
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        self.pool.free.lock().push(Slot(self.ptr));
+    }
+}
+
+/// A packet within a reference counted buffer.
+#[derive(Clone)]
+pub struct PacketBuff {
+    buff: Arc<Buffer>,
+    /// Offset of the first byte of the packet.
+    start: usize,
+    /// Offset after the last byte of the packet.
+    end: usize,
+}
+
+impl PacketBuff {
+    /// Returns the length of the packet.
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Returns the number of bytes which may be pushed in front of the packet.
+    pub fn headroom(&self) -> usize {
+        self.start
+    }
+
+    /// Returns the number of bytes which may be put after the packet.
+    pub fn tailroom(&self) -> usize {
+        BUFFER_SIZE - self.end
+    }
+
+    /// Returns whether the buffer is shared with a clone of this packet.
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.buff) != 1
+    }
+
+    pub fn data(&self) -> &[u8] {
+        // SAFETY: The buffer is only written through `&mut self` when it is not shared
+        unsafe { &(&*self.buff.as_ptr())[self.start..self.end] }
+    }
+
+    /// Returns the packet data, copying the buffer if it is shared.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        let (start, end) = (self.start, self.end);
+        &mut self.buffer_mut()[start..end]
+    }
+
+    /// Returns the whole buffer, copying it if it is shared.
+    fn buffer_mut(&mut self) -> &mut [u8] {
+        if self.is_shared() {
+            let mut new = self.buff.pool.alloc(self.start);
+            new.end = self.end;
+            // SAFETY: `new` is not shared
+            unsafe { (&mut *new.buff.as_ptr())[self.start..self.end].copy_from_slice(self.data()) };
+            *self = new;
+        }
+        // SAFETY: The buffer is not shared and we have exclusive access to self
+        unsafe { &mut *self.buff.as_ptr() }
+    }
+
+    /// Prepends `len` bytes to the packet and returns them.
+    ///
+    /// Returns `None` if there is not enough headroom.
+    pub fn push(&mut self, len: usize) -> Option<&mut [u8]> {
+        let start = self.start.checked_sub(len)?;
+        let old = self.start;
+        self.start = start;
+        Some(&mut self.buffer_mut()[start..old])
+    }
+
+    /// Removes `len` bytes from the front of the packet and returns them.
+    ///
+    /// Returns `None` if the packet is shorter than `len`.
+    pub fn pull(&mut self, len: usize) -> Option<&[u8]> {
+        if len > self.len() {
+            return None;
+        }
+        self.start += len;
+        // SAFETY: See Self::data
+        Some(unsafe { &(&*self.buff.as_ptr())[self.start - len..self.start] })
+    }
+
+    /// Appends `len` bytes to the packet and returns them.
+    ///
+    /// Returns `None` if there is not enough tailroom.
+    pub fn put(&mut self, len: usize) -> Option<&mut [u8]> {
+        if len > self.tailroom() {
+            return None;
+        }
+        let old = self.end;
+        self.end += len;
+        Some(&mut self.buffer_mut()[old..old + len])
+    }
+
+    /// Appends `data` to the packet, returns `None` if there is not enough tailroom.
+    pub fn extend_from_slice(&mut self, data: &[u8]) -> Option<()> {
+        self.put(data.len())?.copy_from_slice(data);
+        Some(())
+    }
+
+    /// Shortens the packet to `len` bytes, this has no effect if the packet is already shorter.
+    pub fn trim(&mut self, len: usize) {
+        self.end = self.end.min(self.start + len);
+    }
+
+    /// Returns the physical address of the packet data.
+    ///
+    /// The packet data is physically contiguous.
+    pub fn phys_addr(&self) -> u64 {
+        // The buffer is always mapped
+        crate::mem::mem_map::translate_ptr(self.data().as_ptr()).unwrap()
+    }
+}
+
+// SAFETY: as_mut returns the packet data of an unshared buffer which is not accessed until
+// `self` is returned.
+unsafe impl DmaTarget for PacketBuff {
+    fn as_mut(&mut self) -> *mut [u8] {
+        self.data_mut()
+    }
+}
@@ -0,0 +1,264 @@
+//! Packet capture.
+//!
+//! A [Capture] taps the frames received and transmitted by an interface into a ring buffer which
+//! is read as a stream in the pcap format, so it can be copied to a disk or serial port and opened
+//! by Wireshark or tcpdump on another machine.
+//!
+//! Captures are started and stopped by writing the following commands to the control file of an
+//! interface, a capture node (`pcap0`, `pcap1`, ...) is created for the duration of the capture.
+//!
+//! - `capture [snaplen <bytes>] [<rule>]` starts capturing frames which match the rule, see
+//!   [super::filter::compile]. Frames are truncated to `snaplen` bytes.
+//! - `capture off` stops the capture and removes the node, an open node may still be read until
+//!   the remaining records are consumed.
+//!
+//! The stream begins with the pcap file header. Records are discarded when the buffer is full,
+//! they are never partially written. Reading the node waits until records are available and
+//! returns [IoError::EndOfFile] once the capture has stopped and the buffer is empty.
+
+use super::filter::Filter;
+use super::interface::Interface;
+use crate::fs::devfs::naming::DeviceClass;
+use crate::fs::device::OpenMode;
+use crate::fs::report::{Report, ReportFile};
+use crate::fs::vfs::{DevID, MajorNum};
+use crate::fs::IoError;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::Poll;
+use futures_util::future::BoxFuture;
+use futures_util::task::AtomicWaker;
+use futures_util::FutureExt;
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub static BUFFER_SIZE: crate::config::Tunable<usize> = crate::config::Tunable::new(
+    "net.capture.buffer_kib",
+    "Size of the buffer of each packet capture in KiB",
+    if cfg!(feature = "low-mem") { 64 } else { 256 },
+);
+
+/// Snapshot length used when none is given.
+pub const DEFAULT_SNAPLEN: u32 = 65535;
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_VERSION: (u16, u16) = (2, 4);
+const LINKTYPE_ETHERNET: u32 = 1;
+const RECORD_HEADER: usize = 16;
+
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("pcap").unwrap(););
+static NEXT_MINOR: AtomicUsize = AtomicUsize::new(0);
+
+/// A running packet capture.
+pub struct Capture {
+    id: DevID,
+    name: conquer_once::spin::OnceCell<String>,
+    snaplen: u32,
+    filter: Option<Arc<Filter>>,
+    capacity: usize,
+    /// Pcap records which have not been read, this is accessed with interrupts disabled because
+    /// drivers may receive frames from their interrupt handlers.
+    buff: spin::Mutex<VecDeque<u8>>,
+    waker: AtomicWaker,
+    /// Set while the node is open, records are consumed by reading so only one reader is permitted.
+    reader: AtomicBool,
+    stopped: AtomicBool,
+    captured: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Capture {
+    fn new(snaplen: u32, filter: Option<Arc<Filter>>) -> Self {
+        let capacity = BUFFER_SIZE.get() * 1024;
+        let mut buff = VecDeque::with_capacity(capacity);
+        let header = [
+            PCAP_MAGIC.to_le_bytes(),
+            ((PCAP_VERSION.1 as u32) << 16 | PCAP_VERSION.0 as u32).to_le_bytes(),
+            0u32.to_le_bytes(), // Timestamps are UTC
+            0u32.to_le_bytes(), // Timestamp accuracy
+            snaplen.to_le_bytes(),
+            LINKTYPE_ETHERNET.to_le_bytes(),
+        ];
+        buff.extend(header.iter().flatten());
+
+        Self {
+            id: DevID::new(*MAJOR, NEXT_MINOR.fetch_add(1, Ordering::Relaxed)),
+            name: conquer_once::spin::OnceCell::uninit(),
+            snaplen,
+            filter,
+            capacity,
+            buff: spin::Mutex::new(buff),
+            waker: AtomicWaker::new(),
+            reader: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            captured: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the name of the capture node.
+    pub fn name(&self) -> &str {
+        self.name.get().map_or("", |s| s.as_str())
+    }
+
+    /// Returns the number of frames captured and the number discarded because the buffer was full.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.captured.load(Ordering::Relaxed), self.dropped.load(Ordering::Relaxed))
+    }
+
+    /// Records `frame` if it is accepted by the filter.
+    pub fn tap(&self, frame: &[u8]) {
+        if self.stopped.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut len = (frame.len() as u32).min(self.snaplen);
+        if let Some(filter) = &self.filter {
+            len = len.min(filter.run(frame));
+            if len == 0 {
+                return;
+            }
+        }
+
+        let time = crate::time::rtc::realtime_nanos();
+        let header = [
+            ((time / 1_000_000_000) as u32).to_le_bytes(),
+            ((time % 1_000_000_000 / 1000) as u32).to_le_bytes(),
+            len.to_le_bytes(),
+            (frame.len() as u32).to_le_bytes(),
+        ];
+        let record = &frame[..len as usize];
+
+        without_interrupts(|| {
+            let mut buff = self.buff.lock();
+            if buff.len() + RECORD_HEADER + record.len() > self.capacity {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            buff.extend(header.iter().flatten());
+            buff.extend(record);
+            self.captured.fetch_add(1, Ordering::Relaxed);
+        });
+        self.waker.wake();
+    }
+
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        self.waker.wake();
+    }
+
+    /// Waits until data is available and copies as much as possible into `out`.
+    ///
+    /// Returns `None` when the capture has stopped and all records have been read.
+    async fn read_records(&self, out: &mut [u8]) -> Option<usize> {
+        core::future::poll_fn(|cx| {
+            self.waker.register(cx.waker());
+            // Read `stopped` first so that records written before it was set are not missed
+            let stopped = self.stopped.load(Ordering::Relaxed);
+            let count = without_interrupts(|| {
+                let mut buff = self.buff.lock();
+                let count = buff.len().min(out.len());
+                for (o, b) in out.iter_mut().zip(buff.drain(..count)) {
+                    *o = b;
+                }
+                count
+            });
+            match count {
+                0 if stopped => Poll::Ready(None),
+                0 => Poll::Pending,
+                n => Poll::Ready(Some(n)),
+            }
+        })
+        .await
+    }
+}
+
+/// Starts capturing frames on `iface` and creates the capture node.
+///
+/// Returns [IoError::Busy] if the interface is already being captured.
+pub async fn start(iface: &Interface, snaplen: u32, filter: Option<Arc<Filter>>) -> Result<Arc<Capture>, IoError> {
+    let capture = Arc::new(Capture::new(snaplen, filter));
+    iface.attach_capture(capture.clone())?;
+    match crate::fs::devfs::register(Box::new(ReportFile::with_report(capture.id, capture.clone())), DeviceClass::Capture).await {
+        Ok(name) => {
+            log::info!("Capturing {} to {name}", iface.name());
+            let _ = capture.name.try_init_once(|| name); // only initialized here
+            Ok(capture)
+        }
+        Err(e) => {
+            log::error!("Failed to register capture node with devfs: {e:?}");
+            iface.detach_capture();
+            Err(IoError::DeviceError)
+        }
+    }
+}
+
+/// Stops the capture running on `iface` and removes its node, open files may continue to read
+/// the remaining records.
+pub async fn stop(iface: &Interface) -> Result<(), IoError> {
+    let capture = iface.detach_capture().ok_or(IoError::NotPresent)?;
+    capture.stop();
+    if let Err(e) = crate::fs::devfs::unregister(capture.id).await {
+        log::error!("Failed to remove devfs node for {}: {e:?}", capture.name());
+    }
+    Ok(())
+}
+
+/// Applies a `capture` command written to an interface, `args` follows the `capture` keyword.
+pub(super) async fn command(iface: &Interface, args: &str) -> Result<(), IoError> {
+    let args = args.trim();
+    if args == "off" {
+        return stop(iface).await;
+    }
+    let mut snaplen = DEFAULT_SNAPLEN;
+    let mut rule = args;
+    if let Some(rest) = args.strip_prefix("snaplen") {
+        let rest = rest.trim_start();
+        let (len, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        snaplen = len.parse().map_err(|_| IoError::InvalidData)?;
+        rule = rest.trim_start();
+    }
+    let filter = match rule {
+        "" => None,
+        rule => Some(Arc::new(super::filter::compile(rule).map_err(|_| IoError::InvalidData)?)),
+    };
+    start(iface, snaplen, filter).await.map(|_| ())
+}
+
+/// Capture node, reads return the pcap stream. `pos` is ignored, data is consumed when it is read.
+///
+/// Captures may contain sensitive traffic, only the superuser may read them.
+impl Report for Capture {
+    fn report(&self) -> String {
+        // The capture is streamed by `read`, it is never generated as a whole
+        String::new()
+    }
+
+    fn mode(&self) -> u16 {
+        0o400
+    }
+
+    fn len(&self) -> u64 {
+        0
+    }
+
+    fn read<'a>(&'a self, _: u64, buff: &'a mut [u8]) -> BoxFuture<'a, Result<usize, IoError>> {
+        async { self.read_records(buff).await.ok_or(IoError::EndOfFile) }.boxed()
+    }
+
+    fn open(&self, _: OpenMode) -> Result<(), IoError> {
+        if self.reader.swap(true, Ordering::Acquire) {
+            return Err(IoError::Exclusive);
+        }
+        Ok(())
+    }
+
+    fn close(&self) {
+        self.reader.store(false, Ordering::Release);
+    }
+
+    fn locks_remain(&self, _: OpenMode) -> usize {
+        if self.reader.load(Ordering::Relaxed) { 0 } else { 1 }
+    }
+}
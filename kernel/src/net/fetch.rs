@@ -0,0 +1,140 @@
+//! Boot time file fetcher.
+//!
+//! Files named on the kernel command line are downloaded into the root filesystem once the
+//! network is available, so test payloads and programs can be provided without rebuilding disk
+//! images. Each `fetch=<url>[,<path>]` argument downloads one file to `path`, or into [FETCH_DIR]
+//! using the last component of the URL when no path is given. Existing files are replaced.
+//!
+//! URLs take the form `tftp://<ipv4>[:<port>]/<path>`. `http://` URLs are parsed but fetching them
+//! fails with [Errno::EOPNOTSUPP] until the network stack provides TCP.
+//!
+//! The fetcher is started using [start] with a [tftp::Socket] provided by the network stack.
+
+use super::tftp;
+use crate::error::{ErrorContext, Errno, KernelError};
+use crate::fs::file::{cast_file, NormalFile};
+use crate::fs::vfs::VfsError;
+use crate::fs::IoError;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::net::SocketAddrV4;
+
+/// Directory which files are fetched into when no destination is given.
+pub const FETCH_DIR: &str = "/fetch";
+
+const HTTP_PORT: u16 = 80;
+
+/// Files waiting to be fetched.
+static PENDING: spin::Mutex<Vec<(Url, String)>> = spin::Mutex::new(Vec::new());
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Url {
+    Tftp { server: SocketAddrV4, path: String },
+    Http { server: SocketAddrV4, path: String },
+}
+
+impl Url {
+    /// Returns the path of the file on the server.
+    pub fn path(&self) -> &str {
+        match self {
+            Url::Tftp { path, .. } | Url::Http { path, .. } => path,
+        }
+    }
+
+    /// Returns the last component of the path.
+    pub fn file_name(&self) -> Option<&str> {
+        self.path().rsplit('/').next().filter(|s| !s.is_empty())
+    }
+}
+
+impl core::str::FromStr for Url {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s.split_once("://").ok_or(())?;
+        let (host, path) = rest.split_once('/').ok_or(())?;
+        let default_port = match scheme {
+            "tftp" => tftp::PORT,
+            "http" => HTTP_PORT,
+            _ => return Err(()),
+        };
+        let server = match host.split_once(':') {
+            Some((addr, port)) => SocketAddrV4::new(addr.parse().map_err(|_| ())?, port.parse().map_err(|_| ())?),
+            None => SocketAddrV4::new(host.parse().map_err(|_| ())?, default_port),
+        };
+        let path = path.to_string();
+        Ok(match scheme {
+            "tftp" => Url::Tftp { server, path },
+            _ => Url::Http { server, path: format!("/{path}") },
+        })
+    }
+}
+
+impl core::fmt::Display for Url {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Url::Tftp { server, path } => write!(f, "tftp://{server}/{path}"),
+            Url::Http { server, path } => write!(f, "http://{server}{path}"),
+        }
+    }
+}
+
+/// Queues the files named by `fetch=` arguments on the kernel command line.
+pub fn parse_cmdline(cmdline: &str) {
+    for arg in cmdline.split_whitespace() {
+        let Some(value) = arg.strip_prefix("fetch=") else { continue };
+        let (url, dest) = value.split_once(',').unwrap_or((value, ""));
+        let Ok(url) = url.parse::<Url>() else {
+            log::warn!("fetch: Invalid URL {url}");
+            continue;
+        };
+        let dest = match (dest, url.file_name()) {
+            ("", Some(name)) => format!("{FETCH_DIR}/{name}"),
+            ("", None) => {
+                log::warn!("fetch: No destination given for {url}");
+                continue;
+            }
+            (dest, _) => dest.to_string(),
+        };
+        PENDING.lock().push((url, dest));
+    }
+}
+
+/// Downloads `url` into the file at `dest`, returns the size of the file.
+pub async fn fetch(socket: &dyn tftp::Socket, url: &Url, dest: &str) -> Result<u64, KernelError> {
+    let Url::Tftp { server, path } = url else {
+        return Err(KernelError::new("fetch", Errno::EOPNOTSUPP).with_context("Only tftp URLs are supported"));
+    };
+    let vfs = crate::fs::get_vfs();
+    let vfs_err = |context: &'static str| move |e: VfsError| KernelError::from(e).wrap("fetch", alloc::format!("{context} {dest}"));
+    match vfs.remove(dest).await {
+        Ok(()) | Err(VfsError::DoesNotExist(_) | VfsError::LowerLevel(IoError::NotPresent)) => {}
+        Err(e) => return Err(vfs_err("Failed to remove")(e)),
+    }
+    vfs.new_file(dest, None).await.map_err(vfs_err("Failed to create"))?;
+    let file = cast_file!(NormalFile<u8>: vfs.open(dest).await.map_err(vfs_err("Failed to open"))?)
+        .map_err(|_| KernelError::new("fetch", Errno::EINVAL).with_context(alloc::format!("{dest} is not a normal file")))?;
+    tftp::get(socket, *server, path, &*file).await.context("fetch", alloc::format!("{url} -> {dest}"))
+}
+
+/// Starts a task which fetches the files given on the kernel command line using `socket`.
+pub fn start(socket: Box<dyn tftp::Socket>) {
+    crate::task::run_task(Box::pin(fetcher(socket)));
+}
+
+async fn fetcher(socket: Box<dyn tftp::Socket>) -> crate::task::TaskResult {
+    let pending = core::mem::take(&mut *PENDING.lock());
+    if pending.iter().any(|(_, dest)| dest.starts_with(FETCH_DIR)) {
+        // Fails if the directory already exists
+        let _ = crate::fs::get_vfs().mkdir(FETCH_DIR).await;
+    }
+    for (url, dest) in pending {
+        match fetch(&*socket, &url, &dest).await {
+            Ok(len) => log::info!("fetch: {url} -> {dest} ({len} bytes)"),
+            Err(e) => log::error!("{e}"),
+        }
+    }
+    crate::task::TaskResult::ExitedNormally
+}
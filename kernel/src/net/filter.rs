@@ -0,0 +1,381 @@
+//! Berkeley packet filter.
+//!
+//! [Filter] runs classic BPF programs over received frames. A program returns the number of bytes
+//! of the frame to accept, returning 0 drops the frame. Programs are checked when the filter is
+//! constructed so that [Filter::run] can not loop or access memory outside the frame, loads from
+//! beyond the end of the frame drop it.
+//!
+//! Programs may be written directly or compiled from a rule using [compile], which accepts one of
+//! the following.
+//!
+//! | Rule                          | Accepts                                   |
+//! |-------------------------------|-------------------------------------------|
+//! | `all`                         | All frames                                |
+//! | `ether <type>`                | Ethernet frames with the given ethertype  |
+//! | `arp`, `ip`, `ip6`            | ARP, IPv4 and IPv6 frames                 |
+//! | `icmp`, `tcp`, `udp`          | IPv4 packets of the given protocol        |
+//! | `tcp port <n>`, `udp port <n>`| IPv4 TCP or UDP with either port `n`      |
+//! | `port <n>`                    | IPv4 TCP or UDP with either port `n`      |
+//!
+//! Numbers may be given in decimal or in hexadecimal with a `0x` prefix.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Largest number of instructions in a program.
+pub const MAX_INSNS: usize = 4096;
+/// Number of words of scratch memory.
+const MEM_WORDS: usize = 16;
+/// Value returned by compiled rules to accept the whole frame.
+const ACCEPT: u32 = u32::MAX;
+
+// Instruction classes
+pub const LD: u16 = 0x00;
+pub const LDX: u16 = 0x01;
+pub const ST: u16 = 0x02;
+pub const STX: u16 = 0x03;
+pub const ALU: u16 = 0x04;
+pub const JMP: u16 = 0x05;
+pub const RET: u16 = 0x06;
+pub const MISC: u16 = 0x07;
+
+// Load sizes
+pub const W: u16 = 0x00;
+pub const H: u16 = 0x08;
+pub const B: u16 = 0x10;
+
+// Load modes
+pub const IMM: u16 = 0x00;
+pub const ABS: u16 = 0x20;
+pub const IND: u16 = 0x40;
+pub const MEM: u16 = 0x60;
+pub const LEN: u16 = 0x80;
+/// Loads `4 * (frame[k] & 0xf)` into X, this is used to skip the IPv4 header.
+pub const MSH: u16 = 0xa0;
+
+// ALU operations
+pub const ADD: u16 = 0x00;
+pub const SUB: u16 = 0x10;
+pub const MUL: u16 = 0x20;
+pub const DIV: u16 = 0x30;
+pub const OR: u16 = 0x40;
+pub const AND: u16 = 0x50;
+pub const LSH: u16 = 0x60;
+pub const RSH: u16 = 0x70;
+pub const NEG: u16 = 0x80;
+pub const MOD: u16 = 0x90;
+pub const XOR: u16 = 0xa0;
+
+// Jumps
+pub const JA: u16 = 0x00;
+pub const JEQ: u16 = 0x10;
+pub const JGT: u16 = 0x20;
+pub const JGE: u16 = 0x30;
+pub const JSET: u16 = 0x40;
+
+// Operand sources
+pub const K: u16 = 0x00;
+pub const X: u16 = 0x08;
+/// Return the accumulator.
+pub const A: u16 = 0x10;
+
+// Miscellaneous operations
+pub const TAX: u16 = 0x00;
+pub const TXA: u16 = 0x80;
+
+/// A single BPF instruction, this has the same layout as `struct sock_filter`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct Insn {
+    pub code: u16,
+    /// Instructions to skip if the condition is true.
+    pub jt: u8,
+    /// Instructions to skip if the condition is false.
+    pub jf: u8,
+    pub k: u32,
+}
+
+impl Insn {
+    pub const fn stmt(code: u16, k: u32) -> Self {
+        Self { code, jt: 0, jf: 0, k }
+    }
+
+    pub const fn jump(code: u16, k: u32, jt: u8, jf: u8) -> Self {
+        Self { code, jt, jf, k }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FilterError {
+    /// The program is empty or longer than [MAX_INSNS].
+    Length,
+    /// The instruction at the index is invalid or jumps out of the program.
+    Invalid(usize),
+    /// The last instruction is not a return.
+    NoReturn,
+    /// The rule given to [compile] was not recognised.
+    Syntax,
+}
+
+/// A checked BPF program.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    prog: Vec<Insn>,
+    desc: String,
+}
+
+impl Filter {
+    /// Checks `prog` and constructs a filter from it.
+    pub fn new(prog: Vec<Insn>) -> Result<Self, FilterError> {
+        if prog.is_empty() || prog.len() > MAX_INSNS {
+            return Err(FilterError::Length);
+        }
+        for (pc, insn) in prog.iter().enumerate() {
+            if !Self::check(insn, prog.len() - pc - 1) {
+                return Err(FilterError::Invalid(pc));
+            }
+        }
+        if prog.last().unwrap().code & 7 != RET {
+            return Err(FilterError::NoReturn);
+        }
+        let desc = format!("bytecode ({} instructions)", prog.len());
+        Ok(Self { prog, desc })
+    }
+
+    /// Returns whether `insn` is valid when `remain` instructions follow it.
+    fn check(insn: &Insn, remain: usize) -> bool {
+        let code = insn.code;
+        let k = insn.k as usize;
+        match code & 7 {
+            LD | LDX => match code {
+                c if c == LD | W | ABS || c == LD | H | ABS || c == LD | B | ABS => true,
+                c if c == LD | W | IND || c == LD | H | IND || c == LD | B | IND => true,
+                c if c == LD | W | LEN || c == LDX | W | LEN => true,
+                c if c == LD | IMM || c == LDX | IMM => true,
+                c if c == LD | MEM || c == LDX | MEM => k < MEM_WORDS,
+                c if c == LDX | B | MSH => true,
+                _ => false,
+            },
+            ST | STX => code & !7 == 0 && k < MEM_WORDS,
+            ALU if code & !0xf8 != ALU => false,
+            ALU => match code & 0xf0 {
+                NEG => code & X == 0,
+                DIV | MOD => code & X != 0 || insn.k != 0,
+                ADD | SUB | MUL | OR | AND | LSH | RSH | XOR => true,
+                _ => false,
+            },
+            JMP => match code & 0xf0 {
+                JA => code == JMP | JA && k < remain,
+                JEQ | JGT | JGE | JSET => code & !0xf8 == JMP && (insn.jt as usize) < remain && (insn.jf as usize) < remain,
+                _ => false,
+            },
+            RET => code == RET | K || code == RET | A,
+            _ => code == MISC | TAX || code == MISC | TXA,
+        }
+    }
+
+    /// Returns a description of the filter.
+    pub fn description(&self) -> &str {
+        &self.desc
+    }
+
+    pub fn program(&self) -> &[Insn] {
+        &self.prog
+    }
+
+    /// Returns whether the filter accepts `frame`.
+    pub fn accepts(&self, frame: &[u8]) -> bool {
+        self.run(frame) != 0
+    }
+
+    /// Runs the filter and returns the number of bytes of `frame` to accept.
+    pub fn run(&self, frame: &[u8]) -> u32 {
+        let mut a: u32 = 0;
+        let mut x: u32 = 0;
+        let mut mem = [0u32; MEM_WORDS];
+        let mut pc = 0;
+
+        let load = |off: u32, size: usize| -> Option<u32> {
+            let off = off as usize;
+            let b = frame.get(off..off.checked_add(size)?)?;
+            Some(b.iter().fold(0, |acc, b| acc << 8 | *b as u32))
+        };
+
+        loop {
+            // Programs are checked to end with a return and never jump past it
+            let insn = self.prog[pc];
+            pc += 1;
+            let src = if insn.code & X != 0 { x } else { insn.k };
+            match insn.code & 7 {
+                LD | LDX => {
+                    let size = match insn.code & 0x18 {
+                        B => 1,
+                        H => 2,
+                        _ => 4,
+                    };
+                    let v = match insn.code & 0xe0 {
+                        IMM => insn.k,
+                        LEN => frame.len() as u32,
+                        MEM => mem[insn.k as usize],
+                        ABS => match load(insn.k, size) {
+                            Some(v) => v,
+                            None => return 0,
+                        },
+                        IND => match load(x.wrapping_add(insn.k), size) {
+                            Some(v) => v,
+                            None => return 0,
+                        },
+                        _ => match load(insn.k, 1) {
+                            Some(v) => (v & 0xf) * 4,
+                            None => return 0,
+                        },
+                    };
+                    if insn.code & 7 == LD {
+                        a = v
+                    } else {
+                        x = v
+                    }
+                }
+                ST => mem[insn.k as usize] = a,
+                STX => mem[insn.k as usize] = x,
+                ALU => {
+                    a = match insn.code & 0xf0 {
+                        ADD => a.wrapping_add(src),
+                        SUB => a.wrapping_sub(src),
+                        MUL => a.wrapping_mul(src),
+                        DIV if src == 0 => return 0,
+                        DIV => a / src,
+                        MOD if src == 0 => return 0,
+                        MOD => a % src,
+                        OR => a | src,
+                        AND => a & src,
+                        LSH => a.checked_shl(src).unwrap_or(0),
+                        RSH => a.checked_shr(src).unwrap_or(0),
+                        NEG => a.wrapping_neg(),
+                        _ => a ^ src,
+                    }
+                }
+                JMP => {
+                    let cond = match insn.code & 0xf0 {
+                        JA => {
+                            pc += insn.k as usize;
+                            continue;
+                        }
+                        JEQ => a == src,
+                        JGT => a > src,
+                        JGE => a >= src,
+                        _ => a & src != 0,
+                    };
+                    pc += if cond { insn.jt } else { insn.jf } as usize;
+                }
+                RET => return if insn.code & A != 0 { a } else { insn.k },
+                _ => {
+                    if insn.code == MISC | TAX {
+                        x = a
+                    } else {
+                        a = x
+                    }
+                }
+            }
+        }
+    }
+}
+
+const ETH_TYPE_OFFSET: u32 = 12;
+const ETH_HEADER: u32 = 14;
+const ETHERTYPE_IPV4: u32 = 0x0800;
+const ETHERTYPE_ARP: u32 = 0x0806;
+const ETHERTYPE_IPV6: u32 = 0x86dd;
+const IPV4_PROTO_OFFSET: u32 = ETH_HEADER + 9;
+const IPV4_FRAG_OFFSET: u32 = ETH_HEADER + 6;
+const PROTO_ICMP: u32 = 1;
+const PROTO_TCP: u32 = 6;
+const PROTO_UDP: u32 = 17;
+
+fn parse_num(s: &str) -> Result<u32, FilterError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| FilterError::Syntax)
+}
+
+/// Accepts frames with the ethertype `ty`.
+fn ethertype(ty: u32) -> Vec<Insn> {
+    alloc::vec![
+        Insn::stmt(LD | H | ABS, ETH_TYPE_OFFSET),
+        Insn::jump(JMP | JEQ | K, ty, 0, 1),
+        Insn::stmt(RET | K, ACCEPT),
+        Insn::stmt(RET | K, 0),
+    ]
+}
+
+/// Accepts IPv4 packets with any of the protocols in `protos`, and optionally the port `port`.
+fn ipv4(protos: &[u32], port: Option<u32>) -> Vec<Insn> {
+    let mut prog = alloc::vec![Insn::stmt(LD | H | ABS, ETH_TYPE_OFFSET)];
+    // Jump offsets to `drop` are patched once the program is complete
+    let mut to_drop = Vec::new();
+    to_drop.push(prog.len());
+    prog.push(Insn::jump(JMP | JEQ | K, ETHERTYPE_IPV4, 0, 0));
+    prog.push(Insn::stmt(LD | B | ABS, IPV4_PROTO_OFFSET));
+    for (i, p) in protos.iter().enumerate() {
+        // Matching protocols skip the remaining protocol checks
+        let skip = (protos.len() - i - 1) as u8;
+        if i == protos.len() - 1 {
+            to_drop.push(prog.len());
+            prog.push(Insn::jump(JMP | JEQ | K, *p, 0, 0));
+        } else {
+            prog.push(Insn::jump(JMP | JEQ | K, *p, skip, 0));
+        }
+    }
+    if let Some(port) = port {
+        // Only the first fragment contains the transport header
+        prog.push(Insn::stmt(LD | H | ABS, IPV4_FRAG_OFFSET));
+        let frag = prog.len();
+        prog.push(Insn::jump(JMP | JSET | K, 0x1fff, 0, 0));
+        to_drop.push(frag);
+        prog.push(Insn::stmt(LDX | B | MSH, ETH_HEADER));
+        prog.push(Insn::stmt(LD | H | IND, ETH_HEADER));
+        prog.push(Insn::jump(JMP | JEQ | K, port, 2, 0));
+        prog.push(Insn::stmt(LD | H | IND, ETH_HEADER + 2));
+        to_drop.push(prog.len());
+        prog.push(Insn::jump(JMP | JEQ | K, port, 0, 0));
+    }
+    prog.push(Insn::stmt(RET | K, ACCEPT));
+    let drop = prog.len();
+    prog.push(Insn::stmt(RET | K, 0));
+
+    for pc in to_drop {
+        let off = (drop - pc - 1) as u8;
+        // JSET jumps to drop on a match, the equality checks on a mismatch
+        if prog[pc].code == JMP | JSET | K {
+            prog[pc].jt = off;
+        } else {
+            prog[pc].jf = off;
+        }
+    }
+    prog
+}
+
+/// Compiles a rule into a filter, see the module documentation for the accepted rules.
+pub fn compile(rule: &str) -> Result<Filter, FilterError> {
+    let words: Vec<&str> = rule.split_whitespace().collect();
+    let prog = match words.as_slice() {
+        ["all"] => alloc::vec![Insn::stmt(RET | K, ACCEPT)],
+        ["ether", ty] => ethertype(parse_num(ty)?),
+        ["arp"] => ethertype(ETHERTYPE_ARP),
+        ["ip"] => ethertype(ETHERTYPE_IPV4),
+        ["ip6"] => ethertype(ETHERTYPE_IPV6),
+        ["icmp"] => ipv4(&[PROTO_ICMP], None),
+        ["tcp"] => ipv4(&[PROTO_TCP], None),
+        ["udp"] => ipv4(&[PROTO_UDP], None),
+        ["tcp", "port", p] => ipv4(&[PROTO_TCP], Some(parse_num(p)?)),
+        ["udp", "port", p] => ipv4(&[PROTO_UDP], Some(parse_num(p)?)),
+        ["port", p] => ipv4(&[PROTO_TCP, PROTO_UDP], Some(parse_num(p)?)),
+        _ => return Err(FilterError::Syntax),
+    };
+    let mut filter = Filter::new(prog)?;
+    filter.desc = words.join(" ");
+    Ok(filter)
+}
@@ -0,0 +1,445 @@
+//! Network interfaces.
+//!
+//! Link drivers register each port using [register], which returns the [Interface] used to pass
+//! packets between the driver and the network stack. The interface counts the packets passing
+//! through it and tracks the configuration of the port.
+//!
+//! Each interface has a devfs node (`eth0`, `eth1`, ...). Reading it returns the configuration,
+//! link state and statistics of the interface. Writing it accepts the following commands, one per
+//! line.
+//!
+//! - `up` or `down` enables or disables the interface.
+//! - `mtu <bytes>` sets the MTU.
+//! - `promisc on` or `promisc off` sets promiscuous mode.
+//! - `filter <rule>` only accepts frames matching the rule, see [super::filter::compile].
+//! - `filter none` removes the filter.
+//! - `clear` resets the statistics.
+//! - `capture ...` starts or stops a packet capture, see [super::capture].
+
+use super::buffer::PacketBuff;
+use super::capture::Capture;
+use super::filter::Filter;
+use super::MacAddr;
+use crate::fs::devfs::naming::DeviceClass;
+use crate::fs::report::{Report, ReportFile};
+use crate::fs::vfs::{DevID, MajorNum};
+use crate::fs::IoError;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("net").unwrap(););
+
+/// Smallest MTU which may be set, this is the minimum required by IPv4.
+pub const MIN_MTU: u32 = 68;
+
+static INTERFACES: spin::RwLock<Vec<Arc<Interface>>> = spin::RwLock::new(Vec::new());
+static NEXT_MINOR: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Duplex {
+    Half,
+    Full,
+    Unknown,
+}
+
+/// Link state reported by the driver.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LinkStatus {
+    /// Whether a link partner is present.
+    pub carrier: bool,
+    /// Negotiated speed in Mb/s, if known.
+    pub speed: Option<u32>,
+    pub duplex: Duplex,
+}
+
+/// Interface to a network port, implemented by link drivers.
+pub trait LinkDriver: Send + Sync {
+    /// Returns the hardware address of the port.
+    fn mac(&self) -> MacAddr;
+
+    fn link(&self) -> LinkStatus;
+
+    /// Transmits a complete frame.
+    fn transmit(&self, packet: PacketBuff) -> BoxFuture<Result<(), IoError>>;
+
+    /// Enables or disables the port, received frames should be discarded while it is disabled.
+    fn set_up(&self, up: bool) -> Result<(), IoError>;
+
+    /// Returns the largest MTU supported by the port.
+    fn max_mtu(&self) -> u32 {
+        1500
+    }
+
+    /// Notifies the driver that the MTU has changed, `mtu` is within [MIN_MTU] and [Self::max_mtu].
+    fn set_mtu(&self, _mtu: u32) -> Result<(), IoError> {
+        Ok(())
+    }
+
+    /// Enables or disables reception of frames which are not addressed to the port.
+    fn set_promiscuous(&self, _on: bool) -> Result<(), IoError> {
+        Err(IoError::NotSupported)
+    }
+}
+
+/// Packet counters of an interface.
+#[derive(Default)]
+pub struct InterfaceStats {
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64,
+    rx_errors: AtomicU64,
+    rx_dropped: AtomicU64,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+    tx_errors: AtomicU64,
+    tx_dropped: AtomicU64,
+}
+
+/// A copy of [InterfaceStats] at a point in time.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct StatsSnapshot {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub rx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_errors: u64,
+    pub tx_dropped: u64,
+}
+
+impl InterfaceStats {
+    fn counters(&self) -> [&AtomicU64; 8] {
+        [
+            &self.rx_packets,
+            &self.rx_bytes,
+            &self.rx_errors,
+            &self.rx_dropped,
+            &self.tx_packets,
+            &self.tx_bytes,
+            &self.tx_errors,
+            &self.tx_dropped,
+        ]
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let [rx_packets, rx_bytes, rx_errors, rx_dropped, tx_packets, tx_bytes, tx_errors, tx_dropped] =
+            self.counters().map(|c| c.load(Ordering::Relaxed));
+        StatsSnapshot { rx_packets, rx_bytes, rx_errors, rx_dropped, tx_packets, tx_bytes, tx_errors, tx_dropped }
+    }
+
+    fn clear(&self) {
+        for c in self.counters() {
+            c.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Handles packets received by an interface.
+pub type RxHandler = Box<dyn Fn(&Interface, PacketBuff) + Send + Sync>;
+
+/// A registered network port.
+pub struct Interface {
+    /// Name of the devfs node, this is set once the node has been created.
+    name: conquer_once::spin::OnceCell<String>,
+    id: DevID,
+    driver: Box<dyn LinkDriver>,
+    stats: InterfaceStats,
+    up: AtomicBool,
+    mtu: AtomicU32,
+    promiscuous: AtomicBool,
+    rx_handler: spin::RwLock<Option<RxHandler>>,
+    filter: spin::RwLock<Option<Arc<Filter>>>,
+    capture: spin::RwLock<Option<Arc<Capture>>>,
+}
+
+/// Registers a network port and creates its devfs node, the interface is initially down.
+pub async fn register(driver: Box<dyn LinkDriver>) -> Result<Arc<Interface>, IoError> {
+    let mtu = driver.max_mtu().min(1500);
+    let iface = Arc::new(Interface {
+        name: conquer_once::spin::OnceCell::uninit(),
+        id: DevID::new(*MAJOR, NEXT_MINOR.fetch_add(1, Ordering::Relaxed)),
+        driver,
+        stats: InterfaceStats::default(),
+        up: AtomicBool::new(false),
+        mtu: AtomicU32::new(mtu),
+        promiscuous: AtomicBool::new(false),
+        rx_handler: spin::RwLock::new(None),
+        filter: spin::RwLock::new(None),
+        capture: spin::RwLock::new(None),
+    });
+    let name = crate::fs::devfs::register(Box::new(ReportFile::with_report(iface.id, iface.clone())), DeviceClass::Network).await.map_err(|e| {
+        log::error!("Failed to register network interface with devfs: {e:?}");
+        IoError::DeviceError
+    })?;
+    log::info!("Registered network interface {name} ({})", iface.mac());
+    let _ = iface.name.try_init_once(|| name); // only initialized here
+    INTERFACES.write().push(iface.clone());
+    Ok(iface)
+}
+
+/// Takes the interface down and removes it.
+pub async fn unregister(iface: &Arc<Interface>) {
+    let _ = iface.set_up(false);
+    INTERFACES.write().retain(|i| !Arc::ptr_eq(i, iface));
+    if let Err(e) = crate::fs::devfs::unregister(iface.id).await {
+        log::error!("Failed to remove devfs node for {}: {e:?}", iface.name());
+    }
+}
+
+/// Returns all registered interfaces.
+pub fn interfaces() -> Vec<Arc<Interface>> {
+    INTERFACES.read().clone()
+}
+
+/// Returns the interface named `name`.
+pub fn get(name: &str) -> Option<Arc<Interface>> {
+    INTERFACES.read().iter().find(|i| i.name() == name).cloned()
+}
+
+impl Interface {
+    pub fn name(&self) -> &str {
+        self.name.get().map_or("", String::as_str)
+    }
+
+    pub fn mac(&self) -> MacAddr {
+        self.driver.mac()
+    }
+
+    pub fn link(&self) -> LinkStatus {
+        self.driver.link()
+    }
+
+    pub fn stats(&self) -> &InterfaceStats {
+        &self.stats
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.up.load(Ordering::Acquire)
+    }
+
+    pub fn mtu(&self) -> u32 {
+        self.mtu.load(Ordering::Relaxed)
+    }
+
+    pub fn is_promiscuous(&self) -> bool {
+        self.promiscuous.load(Ordering::Relaxed)
+    }
+
+    pub fn set_up(&self, up: bool) -> Result<(), IoError> {
+        self.driver.set_up(up)?;
+        if self.up.swap(up, Ordering::AcqRel) != up {
+            log::info!("{} is {}", self.name(), if up { "up" } else { "down" });
+        }
+        Ok(())
+    }
+
+    /// Sets the MTU, returns [IoError::InvalidData] if the driver does not support `mtu`.
+    pub fn set_mtu(&self, mtu: u32) -> Result<(), IoError> {
+        if !(MIN_MTU..=self.driver.max_mtu()).contains(&mtu) {
+            return Err(IoError::InvalidData);
+        }
+        self.driver.set_mtu(mtu)?;
+        self.mtu.store(mtu, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn set_promiscuous(&self, on: bool) -> Result<(), IoError> {
+        self.driver.set_promiscuous(on)?;
+        self.promiscuous.store(on, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Sets the function which received packets are passed to. Packets are dropped while no
+    /// handler is set.
+    pub fn set_rx_handler(&self, handler: Option<RxHandler>) {
+        *self.rx_handler.write() = handler;
+    }
+
+    /// Sets the filter applied to received frames. Frames rejected by the filter are counted as
+    /// dropped and are not passed to the receive handler.
+    pub fn set_filter(&self, filter: Option<Arc<Filter>>) {
+        *self.filter.write() = filter;
+    }
+
+    pub fn filter(&self) -> Option<Arc<Filter>> {
+        self.filter.read().clone()
+    }
+
+    /// Returns the running packet capture.
+    pub fn capture(&self) -> Option<Arc<Capture>> {
+        self.capture.read().clone()
+    }
+
+    /// Returns [IoError::Busy] if a capture is already attached.
+    pub(super) fn attach_capture(&self, capture: Arc<Capture>) -> Result<(), IoError> {
+        let mut l = self.capture.write();
+        if l.is_some() {
+            return Err(IoError::Busy);
+        }
+        *l = Some(capture);
+        Ok(())
+    }
+
+    pub(super) fn detach_capture(&self) -> Option<Arc<Capture>> {
+        self.capture.write().take()
+    }
+
+    /// Transmits `packet`.
+    ///
+    /// Returns [IoError::NotReady] if the interface is down.
+    pub async fn transmit(&self, packet: PacketBuff) -> Result<(), IoError> {
+        if !self.is_up() {
+            InterfaceStats::count(&self.stats.tx_dropped);
+            return Err(IoError::NotReady);
+        }
+        let len = packet.len() as u64;
+        if let Some(capture) = &*self.capture.read() {
+            capture.tap(packet.data());
+        }
+        if crate::fault_inject::NET_TX.should_fail() {
+            InterfaceStats::count(&self.stats.tx_errors);
+            return Err(IoError::DeviceError);
+        }
+        match self.driver.transmit(packet).await {
+            Ok(()) => {
+                InterfaceStats::count(&self.stats.tx_packets);
+                self.stats.tx_bytes.fetch_add(len, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(e) => {
+                InterfaceStats::count(&self.stats.tx_errors);
+                Err(e)
+            }
+        }
+    }
+
+    /// Called by the driver for each frame received.
+    pub fn receive(&self, packet: PacketBuff) {
+        if !self.is_up() || crate::fault_inject::NET_RX.should_fail() {
+            InterfaceStats::count(&self.stats.rx_dropped);
+            return;
+        }
+        InterfaceStats::count(&self.stats.rx_packets);
+        self.stats.rx_bytes.fetch_add(packet.len() as u64, Ordering::Relaxed);
+        if let Some(capture) = &*self.capture.read() {
+            capture.tap(packet.data());
+        }
+        // The accepted length is ignored, the frame is either passed on whole or dropped
+        if let Some(filter) = &*self.filter.read() {
+            if !filter.accepts(packet.data()) {
+                InterfaceStats::count(&self.stats.rx_dropped);
+                return;
+            }
+        }
+        match &*self.rx_handler.read() {
+            Some(h) => h(self, packet),
+            None => InterfaceStats::count(&self.stats.rx_dropped),
+        }
+    }
+
+    /// Called by the driver when a frame was received with an error.
+    pub fn rx_error(&self) {
+        InterfaceStats::count(&self.stats.rx_errors);
+    }
+
+    /// Called by the driver or the network stack when a received frame is discarded, e.g.
+    /// because no buffers were available.
+    pub fn rx_dropped(&self) {
+        InterfaceStats::count(&self.stats.rx_dropped);
+    }
+
+    /// Formats the configuration, link state and statistics of the interface.
+    pub fn report(&self) -> String {
+        let mut s = String::new();
+        let link = self.link();
+        let stats = self.stats.snapshot();
+        let _ = writeln!(
+            s,
+            "{}: {} mtu {} promisc {}",
+            self.name(),
+            if self.is_up() { "UP" } else { "DOWN" },
+            self.mtu(),
+            if self.is_promiscuous() { "on" } else { "off" }
+        );
+        let _ = write!(s, "    link {}", if link.carrier { "carrier" } else { "no-carrier" });
+        if let Some(speed) = link.speed {
+            let _ = write!(s, " {speed}Mb/s");
+        }
+        let _ = writeln!(s, " {:?} duplex", link.duplex);
+        let _ = writeln!(s, "    ether {}", self.mac());
+        if let Some(filter) = &*self.filter.read() {
+            let _ = writeln!(s, "    filter {}", filter.description());
+        }
+        if let Some(capture) = &*self.capture.read() {
+            let (captured, dropped) = capture.stats();
+            let _ = writeln!(s, "    capture {} packets {captured} dropped {dropped}", capture.name());
+        }
+        let _ = writeln!(s, "    RX packets {} bytes {} errors {} dropped {}", stats.rx_packets, stats.rx_bytes, stats.rx_errors, stats.rx_dropped);
+        let _ = writeln!(s, "    TX packets {} bytes {} errors {} dropped {}", stats.tx_packets, stats.tx_bytes, stats.tx_errors, stats.tx_dropped);
+        s
+    }
+
+    /// Applies a single control command, see the module documentation.
+    fn command(&self, cmd: &str) -> Result<(), IoError> {
+        let mut args = cmd.split_whitespace();
+        match (args.next(), args.next(), args.next()) {
+            (Some("up"), None, _) => self.set_up(true),
+            (Some("down"), None, _) => self.set_up(false),
+            (Some("mtu"), Some(mtu), None) => self.set_mtu(mtu.parse().map_err(|_| IoError::InvalidData)?),
+            (Some("promisc"), Some("on"), None) => self.set_promiscuous(true),
+            (Some("promisc"), Some("off"), None) => self.set_promiscuous(false),
+            (Some("filter"), Some("none"), None) => {
+                self.set_filter(None);
+                Ok(())
+            }
+            (Some("filter"), Some(_), _) => {
+                let rule = cmd.trim_start().strip_prefix("filter").unwrap();
+                let filter = super::filter::compile(rule).map_err(|_| IoError::InvalidData)?;
+                self.set_filter(Some(Arc::new(filter)));
+                Ok(())
+            }
+            (Some("clear"), None, _) => {
+                self.stats.clear();
+                Ok(())
+            }
+            (None, ..) => Ok(()),
+            _ => Err(IoError::InvalidData),
+        }
+    }
+}
+
+/// Control file for an interface.
+///
+/// Reads return [Interface::report]. Writes are rejected if any command fails, commands before the
+/// failed command will have been applied.
+impl Report for Interface {
+    fn report(&self) -> String {
+        Interface::report(self)
+    }
+
+    fn writable(&self) -> bool {
+        true
+    }
+
+    fn write<'a>(&'a self, data: &'a str) -> BoxFuture<'a, Result<(), IoError>> {
+        async move {
+            for line in data.lines() {
+                match line.trim_start().strip_prefix("capture") {
+                    Some(args) if args.is_empty() || args.starts_with(char::is_whitespace) => super::capture::command(self, args).await?,
+                    _ => self.command(line)?,
+                }
+            }
+            Ok(())
+        }.boxed()
+    }
+}
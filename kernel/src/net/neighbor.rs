@@ -0,0 +1,296 @@
+//! Neighbor cache for ARP and NDP.
+//!
+//! Each network interface has a [NeighborTable] which maps IPv4 and IPv6 addresses to link
+//! addresses. Entries follow the reachability state machine from RFC 4861 section 7.3, which is
+//! also used for ARP.
+//!
+//! - [NeighborState::Incomplete] resolution is in progress, solicitations are sent every
+//!   [RETRANS_TIME] until [MAX_PROBES] have been sent, then the entry is removed.
+//! - [NeighborState::Reachable] the address was confirmed within [REACHABLE_TIME].
+//! - [NeighborState::Stale] the address may still be used but is not known to be reachable, this
+//!   is entered when a reachable entry expires or when an unsolicited packet announces a new
+//!   address. Stale entries are probed the next time they are used and removed once they have
+//!   been unused for [GC_STALE_TIME].
+//! - [NeighborState::Probe] the cached address is used while unicast probes are sent to confirm
+//!   it, the entry is removed if no probe is answered.
+//! - [NeighborState::Permanent] static entries which are never modified by received packets.
+//!
+//! Because a changed address is only ever trusted after it is announced or confirmed, and
+//! unconfirmed addresses are removed, a neighbor which changes its link address is not
+//! black-holed for longer than the probe period.
+//!
+//! All tables are listed at `/neighbors`.
+
+use super::MacAddr;
+use crate::config::Tunable;
+use crate::fs::report::ReportFile;
+use crate::fs::vfs::{DevID, MajorNum};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::fmt::Write as _;
+use core::net::IpAddr;
+
+const FS_NAME: &str = "/neighbors";
+
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("neighbors").unwrap(););
+
+/// Milliseconds after confirmation that an entry is considered reachable.
+pub static REACHABLE_TIME: Tunable<u64> = Tunable::new("net.neigh.reachable_ms", "Milliseconds a neighbor remains reachable after confirmation", 30_000);
+/// Milliseconds between solicitations.
+pub static RETRANS_TIME: Tunable<u64> = Tunable::new("net.neigh.retrans_ms", "Milliseconds between neighbor solicitations", 1000);
+/// Number of solicitations sent before resolution fails.
+pub static MAX_PROBES: Tunable<u32> = Tunable::new("net.neigh.probes", "Neighbor solicitations sent before resolution fails", 3);
+/// Milliseconds after which unused stale entries are removed.
+pub static GC_STALE_TIME: Tunable<u64> = Tunable::new("net.neigh.gc_stale_ms", "Milliseconds before unused stale neighbors are removed", 60_000);
+
+/// All tables which have been constructed, these are removed when the timer task finds them dropped.
+static TABLES: spin::RwLock<Vec<Weak<NeighborTable>>> = spin::RwLock::new(Vec::new());
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NeighborState {
+    Incomplete,
+    Reachable,
+    Stale,
+    Probe,
+    Permanent,
+}
+
+impl NeighborState {
+    fn name(&self) -> &'static str {
+        match self {
+            NeighborState::Incomplete => "INCOMPLETE",
+            NeighborState::Reachable => "REACHABLE",
+            NeighborState::Stale => "STALE",
+            NeighborState::Probe => "PROBE",
+            NeighborState::Permanent => "PERMANENT",
+        }
+    }
+}
+
+/// Sends solicitations for a [NeighborTable], this is implemented by the link driver.
+pub trait Solicit: Send + Sync {
+    /// Sends an ARP request or neighbor solicitation for `addr`.
+    ///
+    /// `target` is `None` when the address is unknown and the request must be broadcast, or the
+    /// cached address when an existing entry is being probed.
+    fn solicit(&self, addr: IpAddr, target: Option<MacAddr>);
+}
+
+struct Entry {
+    mac: Option<MacAddr>,
+    state: NeighborState,
+    /// System time in nanoseconds when the state was last changed or a solicitation was sent.
+    updated: u64,
+    /// Solicitations sent in the current state.
+    probes: u32,
+}
+
+impl Entry {
+    fn new(mac: Option<MacAddr>, state: NeighborState, now: u64) -> Self {
+        Self { mac, state, updated: now, probes: 0 }
+    }
+
+    fn set_state(&mut self, state: NeighborState, now: u64) {
+        self.state = state;
+        self.updated = now;
+        self.probes = 0;
+    }
+
+    fn age(&self, now: u64) -> u64 {
+        now.saturating_sub(self.updated) / 1_000_000
+    }
+
+    /// Moves reachable entries which have expired to [NeighborState::Stale].
+    fn expire(&mut self, now: u64) {
+        if self.state == NeighborState::Reachable && self.age(now) >= REACHABLE_TIME.get() {
+            self.set_state(NeighborState::Stale, now);
+        }
+    }
+}
+
+/// The neighbor cache of a single interface.
+pub struct NeighborTable {
+    name: String,
+    solicit: Box<dyn Solicit>,
+    entries: spin::Mutex<BTreeMap<IpAddr, Entry>>,
+}
+
+impl NeighborTable {
+    /// Constructs a table for the interface `name`, solicitations are sent using `solicit`.
+    pub fn new(name: String, solicit: Box<dyn Solicit>) -> Arc<Self> {
+        let table = Arc::new(Self { name, solicit, entries: spin::Mutex::new(BTreeMap::new()) });
+        TABLES.write().push(Arc::downgrade(&table));
+        table
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the link address of `addr` if it is known.
+    ///
+    /// If the address is unknown resolution is started and the caller should queue or drop the
+    /// packet. Stale entries are returned, but a probe is started to confirm them.
+    pub fn lookup(&self, addr: IpAddr) -> Option<MacAddr> {
+        let now = crate::time::get_sys_time();
+        let (mac, send) = {
+            let mut l = self.entries.lock();
+            match l.get_mut(&addr) {
+                None => {
+                    let mut e = Entry::new(None, NeighborState::Incomplete, now);
+                    e.probes = 1;
+                    l.insert(addr, e);
+                    (None, Some(None))
+                }
+                Some(e) => {
+                    e.expire(now);
+                    if e.state == NeighborState::Stale {
+                        e.set_state(NeighborState::Probe, now);
+                        e.probes = 1;
+                        (e.mac, Some(e.mac))
+                    } else {
+                        (e.mac, None)
+                    }
+                }
+            }
+        };
+        if let Some(target) = send {
+            self.solicit.solicit(addr, target);
+        }
+        mac
+    }
+
+    /// Handles a reply to a solicitation, `addr` is reachable at `mac`.
+    ///
+    /// Replies for addresses which are not in the table are ignored.
+    pub fn confirm(&self, addr: IpAddr, mac: MacAddr) {
+        let now = crate::time::get_sys_time();
+        if let Some(e) = self.entries.lock().get_mut(&addr) {
+            if e.state != NeighborState::Permanent {
+                e.mac = Some(mac);
+                e.set_state(NeighborState::Reachable, now);
+            }
+        }
+    }
+
+    /// Handles an unsolicited packet which announced that `addr` is at `mac`, such as an ARP
+    /// request or a gratuitous ARP.
+    ///
+    /// New or changed addresses are entered as [NeighborState::Stale] so they are confirmed
+    /// before being relied upon.
+    pub fn update(&self, addr: IpAddr, mac: MacAddr) {
+        let now = crate::time::get_sys_time();
+        let mut l = self.entries.lock();
+        match l.get_mut(&addr) {
+            Some(e) if e.state == NeighborState::Permanent || e.mac == Some(mac) => {}
+            Some(e) => {
+                if e.mac.is_some() {
+                    log::debug!("{}: {addr} moved to {mac}", self.name);
+                }
+                e.mac = Some(mac);
+                e.set_state(NeighborState::Stale, now);
+            }
+            None => {
+                l.insert(addr, Entry::new(Some(mac), NeighborState::Stale, now));
+            }
+        }
+    }
+
+    /// Adds a permanent entry, replacing any existing entry for `addr`.
+    pub fn add_static(&self, addr: IpAddr, mac: MacAddr) {
+        let now = crate::time::get_sys_time();
+        self.entries.lock().insert(addr, Entry::new(Some(mac), NeighborState::Permanent, now));
+    }
+
+    /// Removes the entry for `addr`, returns whether it existed.
+    pub fn remove(&self, addr: IpAddr) -> bool {
+        self.entries.lock().remove(&addr).is_some()
+    }
+
+    /// Removes all entries which are not permanent, this should be called when the interface's
+    /// addresses change.
+    pub fn flush(&self) {
+        self.entries.lock().retain(|_, e| e.state == NeighborState::Permanent);
+    }
+
+    /// Returns `(address, link address, state, milliseconds since the state changed)` for each
+    /// entry.
+    pub fn entries(&self) -> Vec<(IpAddr, Option<MacAddr>, NeighborState, u64)> {
+        let now = crate::time::get_sys_time();
+        let mut l = self.entries.lock();
+        l.iter_mut()
+            .map(|(a, e)| {
+                e.expire(now);
+                (*a, e.mac, e.state, e.age(now))
+            })
+            .collect()
+    }
+
+    /// Advances timers, sending solicitations and removing failed and unused entries.
+    fn tick(&self) {
+        let now = crate::time::get_sys_time();
+        let retrans = RETRANS_TIME.get();
+        let max_probes = MAX_PROBES.get();
+        let gc = GC_STALE_TIME.get();
+        let mut send = Vec::new();
+        self.entries.lock().retain(|addr, e| {
+            e.expire(now);
+            match e.state {
+                NeighborState::Incomplete | NeighborState::Probe if e.age(now) >= retrans => {
+                    if e.probes >= max_probes {
+                        log::debug!("{}: Failed to resolve {addr}", self.name);
+                        return false;
+                    }
+                    e.probes += 1;
+                    e.updated = now;
+                    send.push((*addr, if e.state == NeighborState::Probe { e.mac } else { None }));
+                    true
+                }
+                NeighborState::Stale => e.age(now) < gc,
+                _ => true,
+            }
+        });
+        for (addr, target) in send {
+            self.solicit.solicit(addr, target);
+        }
+    }
+}
+
+/// Runs the timers of all tables every [RETRANS_TIME].
+async fn timer_task() -> crate::task::TaskResult {
+    loop {
+        crate::task::util::sleep(RETRANS_TIME.get().max(10)).await;
+        let tables: Vec<Arc<NeighborTable>> = {
+            let mut l = TABLES.write();
+            l.retain(|t| t.strong_count() != 0);
+            l.iter().filter_map(Weak::upgrade).collect()
+        };
+        for t in tables {
+            t.tick();
+        }
+    }
+}
+
+/// Formats the entries of all tables, one per line.
+pub fn report() -> String {
+    let tables: Vec<Arc<NeighborTable>> = TABLES.read().iter().filter_map(Weak::upgrade).collect();
+    let mut s = String::new();
+    let _ = writeln!(s, "{:<8} {:<39} {:<17} {:<10} AGE(ms)", "IFACE", "ADDRESS", "LLADDR", "STATE");
+    for t in tables {
+        for (addr, mac, state, age) in t.entries() {
+            let mac = mac.map_or_else(|| String::from("-"), |m| alloc::format!("{m}"));
+            let _ = writeln!(s, "{:<8} {:<39} {:<17} {:<10} {age}", t.name, addr, mac, state.name());
+        }
+    }
+    s
+}
+
+/// Mounts the neighbor list and starts the neighbor timers.
+pub fn init() {
+    crate::task::util::block_on!(crate::fs::get_vfs().mount_dev(Box::new(ReportFile::new(DevID::new(*MAJOR, 0), report)), FS_NAME))
+        .expect("Failed to mount neighbor list to VFS");
+    crate::task::run_task(Box::pin(timer_task()));
+}
@@ -0,0 +1,184 @@
+//! TFTP client (RFC 1350).
+//!
+//! Only reading files in octet mode is supported. The client requests [BLOCK_SIZE] byte blocks
+//! using the block size option (RFC 2348) and falls back to 512 byte blocks if the server does not
+//! acknowledge it. Block numbers are permitted to wrap, so files larger than 65535 blocks may be
+//! fetched from servers which support rollover.
+//!
+//! Like [crate::time::sntp] the client does not own a socket, the network stack provides a
+//! [Socket] bound to an ephemeral port.
+
+use crate::fs::file::Write;
+use crate::error::{ErrorContext, KernelError};
+use crate::fs::IoError;
+use crate::net::buffer::PacketBuff;
+use alloc::boxed::Box;
+use core::net::SocketAddrV4;
+use futures_util::future::{BoxFuture, Either};
+
+/// Well known TFTP server port.
+pub const PORT: u16 = 69;
+/// Block size requested from the server, this fits a block into a 1500 byte MTU.
+pub const BLOCK_SIZE: usize = 1432;
+/// Block size used when the server does not acknowledge the block size option.
+const DEFAULT_BLOCK_SIZE: usize = 512;
+/// Time to wait for a packet before retransmitting, in milliseconds.
+const TIMEOUT_MSEC: u64 = 1000;
+/// Number of consecutive timeouts before the transfer is abandoned.
+const RETRIES: usize = 5;
+
+const OP_RRQ: u16 = 1;
+const OP_DATA: u16 = 3;
+const OP_ACK: u16 = 4;
+const OP_ERROR: u16 = 5;
+const OP_OACK: u16 = 6;
+
+const ERR_NOT_FOUND: u16 = 1;
+const ERR_ACCESS: u16 = 2;
+const ERR_OPTION: u16 = 8;
+
+/// A UDP socket used for a single transfer.
+pub trait Socket: Send + Sync {
+    /// Sends `packet` to `addr`. `packet` is allocated with enough headroom for the UDP and lower
+    /// headers.
+    fn send_to(&self, addr: SocketAddrV4, packet: PacketBuff) -> BoxFuture<Result<(), IoError>>;
+
+    /// Waits for a datagram and returns its payload and source address.
+    fn recv_from(&self) -> BoxFuture<Result<(PacketBuff, SocketAddrV4), IoError>>;
+}
+
+fn opcode(packet: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes(packet.get(..2)?.try_into().unwrap()))
+}
+
+fn ack(block: u16) -> PacketBuff {
+    let mut packet = crate::net::buffer::alloc();
+    let b = packet.put(4).unwrap(); // the buffer is larger than the packet
+    b[..2].copy_from_slice(&OP_ACK.to_be_bytes());
+    b[2..].copy_from_slice(&block.to_be_bytes());
+    packet
+}
+
+fn read_request(path: &str) -> Result<PacketBuff, IoError> {
+    let mut packet = crate::net::buffer::alloc();
+    let blksize = alloc::format!("{BLOCK_SIZE}");
+    let fields: [&[u8]; 5] = [&OP_RRQ.to_be_bytes(), path.as_bytes(), b"octet", b"blksize", blksize.as_bytes()];
+    for (i, f) in fields.iter().enumerate() {
+        packet.extend_from_slice(f).ok_or(IoError::InvalidData)?;
+        // The opcode is not terminated
+        if i != 0 {
+            packet.extend_from_slice(&[0]).ok_or(IoError::InvalidData)?;
+        }
+    }
+    Ok(packet)
+}
+
+/// Parses an option acknowledgement and returns the block size.
+fn parse_oack(packet: &[u8]) -> Result<usize, IoError> {
+    let mut fields = packet[2..].split(|b| *b == 0);
+    let mut block_size = DEFAULT_BLOCK_SIZE;
+    while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+        if name.eq_ignore_ascii_case(b"blksize") {
+            let size = core::str::from_utf8(value).ok().and_then(|s| s.parse().ok()).ok_or(IoError::InvalidData)?;
+            // The server may only reduce the block size
+            if !(8..=BLOCK_SIZE).contains(&size) {
+                return Err(IoError::InvalidData);
+            }
+            block_size = size;
+        }
+    }
+    Ok(block_size)
+}
+
+fn error_code(packet: &[u8]) -> IoError {
+    let code = packet.get(2..4).map(|c| u16::from_be_bytes(c.try_into().unwrap()));
+    let msg = packet.get(4..).and_then(|m| core::str::from_utf8(m.split(|b| *b == 0).next()?).ok()).unwrap_or("");
+    log::debug!("tftp: Server returned error {code:?}: {msg}");
+    match code {
+        Some(ERR_NOT_FOUND) => IoError::NotPresent,
+        Some(ERR_ACCESS) => IoError::PermissionDenied,
+        Some(ERR_OPTION) => IoError::NotSupported,
+        _ => IoError::DeviceError,
+    }
+}
+
+/// Sends `packet` and waits for a reply from `peer`, or from any port on the server when `peer`
+/// is `None`. The packet is retransmitted when no reply arrives within the timeout.
+async fn exchange(socket: &dyn Socket, dst: SocketAddrV4, peer: Option<SocketAddrV4>, packet: &PacketBuff) -> Result<(PacketBuff, SocketAddrV4), IoError> {
+    for _ in 0..RETRIES {
+        socket.send_to(dst, packet.clone()).await?;
+        let mut timeout = Box::pin(crate::task::util::sleep(TIMEOUT_MSEC));
+        loop {
+            match futures_util::future::select(socket.recv_from(), timeout).await {
+                Either::Left((Ok((reply, src)), t)) => {
+                    let expected = peer.map_or(src.ip() == dst.ip(), |p| p == src);
+                    if expected {
+                        return Ok((reply, src));
+                    }
+                    // Packets from other transfers are ignored
+                    timeout = t;
+                }
+                Either::Left((Err(e), _)) => return Err(e),
+                Either::Right(_) => break,
+            }
+        }
+    }
+    Err(IoError::NotReady)
+}
+
+/// Fetches `path` from `server` and writes it into `dst` starting at offset 0.
+///
+/// Returns the size of the file.
+pub async fn get(socket: &dyn Socket, server: SocketAddrV4, path: &str, dst: &dyn Write<u8>) -> Result<u64, KernelError> {
+    transfer(socket, server, path, dst).await.with_context("tftp", || alloc::format!("Failed to get {path} from {server}"))
+}
+
+async fn transfer(socket: &dyn Socket, server: SocketAddrV4, path: &str, dst: &dyn Write<u8>) -> Result<u64, IoError> {
+    let mut packet = read_request(path)?;
+    let mut dst_addr = server;
+    let mut peer = None;
+    let mut block_size = DEFAULT_BLOCK_SIZE;
+    let mut block: u16 = 1;
+    let mut len = 0u64;
+
+    loop {
+        let (mut reply, src) = exchange(socket, dst_addr, peer, &packet).await?;
+        // The server replies from a new port which identifies the transfer
+        if peer.is_none() {
+            peer = Some(src);
+            dst_addr = src;
+        }
+
+        match opcode(reply.data()) {
+            Some(OP_OACK) if block == 1 && len == 0 => {
+                block_size = parse_oack(reply.data())?;
+                packet = ack(0);
+            }
+            Some(OP_DATA) if reply.len() >= 4 => {
+                let n = u16::from_be_bytes(reply.data()[2..4].try_into().unwrap());
+                if n != block {
+                    // Duplicate of the previous block, our ack was lost so it is sent again
+                    continue;
+                }
+                reply.pull(4);
+                let count = reply.len();
+                if count > block_size {
+                    return Err(IoError::InvalidData);
+                }
+                if count != 0 {
+                    crate::fs::disk_util::write_all(dst, len, Box::new(reply)).await.map_err(|(e, _)| e)?;
+                }
+                len += count as u64;
+                packet = ack(block);
+                block = block.wrapping_add(1);
+                if count < block_size {
+                    // The final ack is not retransmitted, the server times out if it is lost
+                    socket.send_to(dst_addr, packet).await?;
+                    return Ok(len);
+                }
+            }
+            Some(OP_ERROR) => return Err(error_code(reply.data())),
+            _ => return Err(IoError::InvalidData),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Persistent kernel log.
+//!
+//! A region of RAM is reserved in [PHYS_MAP] early during boot and each log message is mirrored
+//! into a ring buffer within it. RAM keeps its contents across a warm reboot, e.g. a watchdog reset
+//! or [crate::system::shutdown] with a reboot, so when the region contains a valid header on the
+//! next boot the previous boot's log is recovered and exported as text at `/pstore`. This allows
+//! crash loops to be diagnosed without capturing the serial port.
+//!
+//! The region is placed at the top of the highest usable memory region, so it is only found again
+//! while the firmware's memory map is unchanged. When the ring wraps the oldest partial line is
+//! dropped. Messages logged before [init] are not recorded.
+//!
+//! [PHYS_MAP]: crate::mem::phys_map::PHYS_MAP
+
+use crate::fs::report::ReportFile;
+use crate::fs::vfs::{DevID, MajorNum};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const FS_NAME: &str = "/pstore";
+
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("pstore").unwrap(););
+
+/// Size of the region including the header.
+const SIZE: u64 = 64 * 1024;
+const MAGIC: u64 = u64::from_le_bytes(*b"HOOTLOG1");
+
+/// Start of the region, the log is stored in the remainder of the region.
+///
+/// `check` is written after `head`, a header where `check != !head` was being updated when the
+/// system was reset and is not trusted.
+#[repr(C)]
+struct Header {
+    magic: u64,
+    /// Size of the ring.
+    size: u64,
+    /// Number of bytes which have been written to the ring.
+    head: u64,
+    check: u64,
+}
+
+const DATA_OFFSET: usize = core::mem::size_of::<Header>();
+const RING_SIZE: u64 = SIZE - DATA_OFFSET as u64;
+
+/// Physical address of the region, `0` if none was reserved.
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// The mapped region, `None` until [init] has run.
+static RING: spin::Mutex<Option<Ring>> = spin::Mutex::new(None);
+
+/// Log recovered from the previous boot.
+static PREVIOUS: spin::Once<Vec<u8>> = spin::Once::new();
+
+struct Ring {
+    header: *mut Header,
+    data: *mut u8,
+}
+
+// SAFETY: The region is only accessed while `RING` is locked.
+unsafe impl Send for Ring {}
+
+impl core::fmt::Write for Ring {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // SAFETY: The header and ring are mapped for the lifetime of the kernel.
+        unsafe {
+            let head = core::ptr::addr_of!((*self.header).head).read_volatile();
+            for (i, b) in s.bytes().enumerate() {
+                self.data.add(((head + i as u64) % RING_SIZE) as usize).write_volatile(b);
+            }
+            let head = head + s.len() as u64;
+            core::ptr::addr_of_mut!((*self.header).head).write_volatile(head);
+            core::ptr::addr_of_mut!((*self.header).check).write_volatile(!head);
+        }
+        Ok(())
+    }
+}
+
+/// Reserves the region, this must be called after the physical memory map is initialized and
+/// before frames are allocated.
+pub fn reserve() {
+    use crate::mem::phys_map::{PhysRegionKind, PHYS_MAP};
+    let mut base = None;
+    PHYS_MAP.for_each(|r| {
+        let b = r.end().saturating_sub(SIZE) & !(crate::mem::PAGE_SIZE as u64 - 1);
+        if r.kind == PhysRegionKind::Usable && r.len >= SIZE && b >= r.base.as_u64() {
+            base = Some(b);
+        }
+    });
+    // The logger is not initialized yet, failure is reported by `mount`
+    if let Some(b) = base.filter(|b| PHYS_MAP.reserve(x86_64::PhysAddr::new(*b), SIZE, "pstore").is_ok()) {
+        BASE.store(b, Ordering::Relaxed);
+    }
+}
+
+/// Maps the region, recovers the previous boot's log and begins mirroring the log into it. This
+/// must be called after the heap is initialized.
+pub fn init() {
+    let base = BASE.load(Ordering::Relaxed);
+    if base == 0 {
+        return;
+    }
+    // SAFETY: The region was reserved for this module by `reserve`
+    let mut map = Vec::<u8, _>::new_in(unsafe { crate::alloc_interface::MmioAlloc::new(base as usize) });
+    map.reserve_exact(SIZE as usize);
+    let ptr = map.as_mut_ptr();
+    // The region is never unmapped
+    core::mem::forget(map);
+
+    // SAFETY: The mapping is `SIZE` bytes long
+    let ring = Ring { header: ptr.cast(), data: unsafe { ptr.add(DATA_OFFSET) } };
+    // SAFETY: The region is mapped and reserved for this module.
+    unsafe {
+        let h = ring.header;
+        let magic = core::ptr::addr_of!((*h).magic).read_volatile();
+        let size = core::ptr::addr_of!((*h).size).read_volatile();
+        let head = core::ptr::addr_of!((*h).head).read_volatile();
+        let check = core::ptr::addr_of!((*h).check).read_volatile();
+        let mut previous = Vec::new();
+        if magic == MAGIC && size == RING_SIZE && check == !head {
+            let len = head.min(RING_SIZE);
+            previous.extend((head - len..head).map(|i| ring.data.add((i % RING_SIZE) as usize).read_volatile()));
+            // Drops the partial line at the start of a wrapped ring
+            if head > RING_SIZE {
+                let start = previous.iter().position(|b| *b == b'\n').map_or(previous.len(), |p| p + 1);
+                previous.drain(..start);
+            }
+        }
+        PREVIOUS.call_once(|| previous);
+
+        core::ptr::addr_of_mut!((*h).magic).write_volatile(MAGIC);
+        core::ptr::addr_of_mut!((*h).size).write_volatile(RING_SIZE);
+        core::ptr::addr_of_mut!((*h).head).write_volatile(0);
+        core::ptr::addr_of_mut!((*h).check).write_volatile(!0);
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| *RING.lock() = Some(ring));
+}
+
+/// Appends a message to the persistent log, called by the logger.
+pub(crate) fn write(args: core::fmt::Arguments) {
+    use core::fmt::Write;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if let Some(ring) = RING.lock().as_mut() {
+            let _ = ring.write_fmt(args);
+        }
+    })
+}
+
+/// Returns the log recovered from the previous boot, empty if none was found.
+pub fn previous() -> &'static [u8] {
+    PREVIOUS.get().map(Vec::as_slice).unwrap_or(&[])
+}
+
+/// Mounts the recovered log at `/pstore`.
+pub fn mount() {
+    match (BASE.load(Ordering::Relaxed), previous().len()) {
+        (0, _) => log::warn!("pstore: No memory could be reserved, the log will not be kept"),
+        (_, 0) => log::info!("pstore: No log from the previous boot"),
+        (_, n) => log::info!("pstore: Recovered {n} bytes of the previous boot's log"),
+    }
+    crate::task::util::block_on!(crate::fs::get_vfs().mount_dev(Box::new(ReportFile::new_static(DevID::new(*MAJOR, 0), previous)), FS_NAME))
+        .expect("Failed to mount pstore to VFS");
+}
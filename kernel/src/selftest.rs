@@ -0,0 +1,328 @@
+//! Kernel self-tests.
+//!
+//! Unlike `#[test_case]` tests self-tests run on a normally booted kernel, so they exercise the
+//! subsystems as they are configured on the running machine. Tests are registered with [register],
+//! the built-in tests are registered by [init].
+//!
+//! Tests are run by the `/bin/selftest` program (see [crate::task::spawn]), the arguments select
+//! which tests are run, all tests are run when none are given. Tests can also be run at boot using
+//! `selftest` or `selftest=<name>,<name>` on the kernel command line, after the tests complete the system
+//! is shut down (see [crate::system::shutdown]) and QEMU is exited with [crate::QemuExitCode::Success] when all tests passed.
+//!
+//! Results are written to the serial port in the same format as the `#[test_case]` test runner.
+//! A test which panics panics the kernel.
+
+use crate::fs::IoError;
+use crate::task::spawn::Args;
+use crate::task::TaskResult;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+
+/// A self-test, returns a description of the failure when it fails.
+pub type Test = fn() -> BoxFuture<'static, Result<(), String>>;
+
+static TESTS: spin::RwLock<BTreeMap<&'static str, Test>> = spin::RwLock::new(BTreeMap::new());
+
+/// Tests given on the command line, `Some` when tests are run at boot.
+static BOOT_TESTS: spin::Mutex<Option<Vec<String>>> = spin::Mutex::new(None);
+
+/// Registers `test` as `name`.
+pub fn register(name: &'static str, test: Test) -> Result<(), IoError> {
+    let mut l = TESTS.write();
+    if l.contains_key(name) {
+        return Err(IoError::AlreadyExists);
+    }
+    l.insert(name, test);
+    Ok(())
+}
+
+/// Results of [run].
+#[derive(Debug, Default)]
+pub struct Report {
+    pub results: Vec<(String, Result<(), String>)>,
+}
+
+impl Report {
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|(_, r)| r.is_ok()).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.results.len() - self.passed()
+    }
+}
+
+/// Runs the tests named in `names` in order, or every test if `names` is empty.
+pub async fn run<S: AsRef<str>>(names: &[S]) -> Report {
+    let tests: Vec<(String, Option<Test>)> = if names.is_empty() {
+        TESTS.read().iter().map(|(n, t)| (String::from(*n), Some(*t))).collect()
+    } else {
+        let l = TESTS.read();
+        names.iter().map(|n| (String::from(n.as_ref()), l.get(n.as_ref()).copied())).collect()
+    };
+
+    crate::serial_println!("Running {} self-tests", tests.len());
+    let mut report = Report::default();
+    for (name, test) in tests {
+        crate::serial_print!("{name}...\t");
+        let result = match test {
+            Some(test) => test().await,
+            None => Err(String::from("No such test")),
+        };
+        match &result {
+            Ok(()) => crate::serial_println!("[PASSED]"),
+            Err(e) => {
+                crate::serial_println!("[FAILED]");
+                crate::serial_println!("Error: {e}");
+            }
+        }
+        report.results.push((name, result));
+    }
+    crate::serial_println!("{} passed, {} failed", report.passed(), report.failed());
+    report
+}
+
+/// Reads `selftest` from the kernel command line.
+pub fn parse_cmdline(cmdline: &str) {
+    for arg in cmdline.split_whitespace() {
+        let names = match arg.strip_prefix("selftest=") {
+            Some(list) => list.split(',').filter(|n| !n.is_empty()).map(String::from).collect(),
+            None if arg == "selftest" => Vec::new(),
+            None => continue,
+        };
+        *BOOT_TESTS.lock() = Some(names);
+    }
+}
+
+fn program(args: Args) -> Pin<Box<dyn Future<Output = TaskResult> + Send>> {
+    Box::pin(async move {
+        let report = run(args.argv.get(1..).unwrap_or(&[])).await;
+        match report.failed() {
+            0 => TaskResult::ExitedNormally,
+            _ => TaskResult::Error,
+        }
+    })
+}
+
+async fn boot_tests(names: Vec<String>) -> TaskResult {
+    let report = run(names.as_slice()).await;
+    let code = if report.failed() == 0 {
+        crate::QemuExitCode::Success
+    } else {
+        log::error!("Self-test: {} of {} tests failed", report.failed(), report.results.len());
+        crate::QemuExitCode::Failed
+    };
+    // Shuts down so that data written by the tests reaches the disk before QEMU exits
+    crate::system::shutdown::shutdown(crate::system::shutdown::Action::ExitQemu(code)).await;
+    TaskResult::ExitedNormally
+}
+
+/// Registers the built-in tests and the `/bin/selftest` program, and starts the tests requested on
+/// the command line. This should be called after drivers are initialized.
+pub fn init() {
+    register("alloc", || alloc_stress().boxed()).unwrap();
+    register("page_table", || page_table().boxed()).unwrap();
+    register("tmpfs", || tmpfs_conformance().boxed()).unwrap();
+    register("dma_guard", || dma_guard().boxed()).unwrap();
+    register("md_raid1_resync_write", || crate::system::sysfs::block::md::selftest::raid1_resync_write().boxed()).unwrap();
+    register("md_raid1_degraded_read", || crate::system::sysfs::block::md::selftest::raid1_degraded_read().boxed()).unwrap();
+    register("md_raid1_write_zeroes", || crate::system::sysfs::block::md::selftest::raid1_write_zeroes().boxed()).unwrap();
+    crate::task::spawn::register("/bin/selftest", program).expect("Failed to register selftest");
+
+    if let Some(names) = BOOT_TESTS.lock().take() {
+        crate::task::run_task(Box::pin(boot_tests(names)));
+    }
+}
+
+fn ensure(cond: bool, msg: &str) -> Result<(), String> {
+    cond.then_some(()).ok_or_else(|| String::from(msg))
+}
+
+fn err<E: core::fmt::Debug>(e: E) -> String {
+    alloc::format!("{e:?}")
+}
+
+/// Xorshift generator, test sequences are reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 as usize
+    }
+}
+
+/// Allocates and frees buffers of mixed sizes in a random order, checking that no buffer is
+/// overwritten while it is live and that alignment is honoured.
+async fn alloc_stress() -> Result<(), String> {
+    const ROUNDS: usize = 4096;
+    const MAX_LIVE: usize = 256;
+
+    let check = |buf: &[u8], fill: u8| -> Result<(), String> {
+        match buf.iter().position(|b| *b != fill) {
+            Some(i) => Err(alloc::format!("Buffer at {:p} of {} bytes corrupted at offset {i}", buf.as_ptr(), buf.len())),
+            None => Ok(()),
+        }
+    };
+
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut live: Vec<(Vec<u8>, u8)> = Vec::new();
+    for i in 0..ROUNDS {
+        let size = match rng.next() % 4 {
+            0 => rng.next() % 64,
+            1 => rng.next() % crate::mem::PAGE_SIZE,
+            2 => rng.next() % 0x10000,
+            _ => crate::mem::PAGE_SIZE * (1 + rng.next() % 8),
+        } + 1;
+        let fill = i as u8;
+        live.push((alloc::vec![fill; size], fill));
+
+        if live.len() > MAX_LIVE || rng.next() % 3 == 0 {
+            let (buf, fill) = live.swap_remove(rng.next() % live.len());
+            check(&buf, fill)?;
+        }
+    }
+    for (buf, fill) in live {
+        check(&buf, fill)?;
+    }
+
+    for align in [8, 64, crate::mem::PAGE_SIZE, 0x10000] {
+        let layout = core::alloc::Layout::from_size_align(align, align).unwrap();
+        // SAFETY: The layout has a non-zero size and the pointer is freed with the same layout
+        let ptr = unsafe { alloc::alloc::alloc(layout) };
+        ensure(!ptr.is_null(), "Aligned allocation failed")?;
+        let aligned = ptr as usize % align == 0;
+        // SAFETY: See above
+        unsafe { alloc::alloc::dealloc(ptr, layout) };
+        ensure(aligned, "Allocation did not honour its alignment")?;
+    }
+    Ok(())
+}
+
+/// Checks that translations are consistent within a page, that distinct pages do not alias and
+/// that MMIO mappings are removed when they are freed.
+async fn page_table() -> Result<(), String> {
+    use crate::mem::mem_map::translate;
+    use crate::mem::PAGE_SIZE;
+    const PAGES: usize = 4;
+
+    let mut buf = Vec::<u8, _>::new_in(crate::alloc_interface::DmaAlloc::new(crate::mem::MemRegion::Mem64, PAGE_SIZE));
+    buf.resize(PAGES * PAGE_SIZE, 0);
+    let base = buf.as_ptr() as usize;
+    ensure(base % PAGE_SIZE == 0, "Page allocation is not page aligned")?;
+
+    let mut frames = Vec::new();
+    for page in 0..PAGES {
+        let addr = base + page * PAGE_SIZE;
+        let phys = translate(addr).ok_or_else(|| alloc::format!("{addr:#x} is not mapped"))?;
+        ensure(phys % PAGE_SIZE as u64 == 0, "Page is not mapped to the start of a frame")?;
+        for offset in [1, PAGE_SIZE / 2, PAGE_SIZE - 1] {
+            if translate(addr + offset) != Some(phys + offset as u64) {
+                return Err(alloc::format!("{:#x} does not translate to {:#x}", addr + offset, phys + offset as u64));
+            }
+        }
+        if frames.contains(&phys) {
+            return Err(alloc::format!("{addr:#x} aliases frame {phys:#x}"));
+        }
+        frames.push(phys);
+    }
+
+    // SAFETY: The mapping is never accessed
+    let mut alias = Vec::<u8, _>::new_in(unsafe { crate::alloc_interface::MmioAlloc::new(frames[0] as usize) });
+    alias.reserve_exact(PAGE_SIZE);
+    let addr = alias.as_ptr() as usize;
+    ensure(translate(addr) == Some(frames[0]), "MMIO mapping does not translate to its frame")?;
+    drop(alias);
+    ensure(translate(addr).is_none(), "MMIO mapping is still present after it was freed")?;
+    drop(buf);
+    Ok(())
+}
+
+/// Checks that a new tmpfs follows the [crate::fs::file::Directory] contract.
+async fn tmpfs_conformance() -> Result<(), String> {
+    use crate::fs::file::*;
+    let fs = crate::fs::tmpfs::TmpFsRoot::new();
+    let root = fs.root();
+
+    ensure(root.get_file(crate::fs::PARENT_DIR).await.err() == Some(IoError::IsDevice), "`..` of the root must return IsDevice")?;
+    ensure(root.get_file("missing").await.err() == Some(IoError::NotPresent), "Missing file must return NotPresent")?;
+
+    root.new_file("file", None).await.map_err(err)?;
+    ensure(root.new_file("file", None).await == Err((Some(IoError::AlreadyExists), None)), "Duplicate file must return AlreadyExists")?;
+
+    let file = cast_file!(NormalFile<u8>: root.get_file("file").await.map_err(err)?).map_err(|_| String::from("File is not a normal file"))?;
+    ensure(file.len().await.map_err(err)? == 0, "New file is not empty")?;
+    file.write(0, Box::new(crate::mem::dma::DmaGuard::from(alloc::vec![b'a'; 5]))).await.map_err(|(e, _, _)| err(e))?;
+    file.write(3, Box::new(crate::mem::dma::DmaGuard::from(alloc::vec![b'b'; 5]))).await.map_err(|(e, _, _)| err(e))?;
+    ensure(file.len().await.map_err(err)? == 8, "Overlapping write did not extend the file")?;
+
+    let mut data = [0u8; 16];
+    // SAFETY: The futures are completed before `data` is accessed
+    let (_, len) = file.read(0, Box::new(unsafe { crate::mem::dma::StackDmaGuard::new(&mut data) })).await.map_err(|(e, _, _)| err(e))?;
+    ensure(len == 8 && &data[..len] == b"aaabbbbb", "Read returned the wrong data")?;
+    // SAFETY: See above
+    let eof = file.read(8, Box::new(unsafe { crate::mem::dma::StackDmaGuard::new(&mut data) })).await.err().map(|(e, _, _)| e);
+    ensure(eof == Some(IoError::EndOfFile), "Read at the end of the file must return EndOfFile")?;
+    drop(file);
+
+    let dir = root.new_dir("dir").await.map_err(err)?;
+    ensure(root.new_dir("dir").await.err() == Some(IoError::AlreadyExists), "Duplicate directory must return AlreadyExists")?;
+    dir.new_file("inner", None).await.map_err(err)?;
+    ensure(root.remove("dir").await.err() == Some(IoError::NotEmpty), "Removing a non-empty directory must return NotEmpty")?;
+    dir.remove("inner").await.map_err(err)?;
+    ensure(dir.entries().await.map_err(err)? == 0, "Removed file is still listed")?;
+    root.remove("dir").await.map_err(err)?;
+
+    root.remove("file").await.map_err(err)?;
+    ensure(root.get_file("file").await.err() == Some(IoError::NotPresent), "Removed file must return NotPresent")?;
+    ensure(root.file_list().await.map_err(err)?.is_empty(), "Removed files are still listed")?;
+    Ok(())
+}
+
+/// Checks the ownership rules of [crate::mem::dma::DmaClaimable] and that the physical region
+/// description of a buffer covers exactly the buffer. Each run leaks a 64 byte buffer.
+async fn dma_guard() -> Result<(), String> {
+    use crate::mem::dma::{DmaClaimable, DmaGuard, DmaTarget};
+    const LEN: usize = 0x3000 + 0x80;
+
+    let mut guard = DmaGuard::from(alloc::vec![0u8; LEN]);
+    ensure(guard.query_owned(), "A new guard must own its buffer")?;
+
+    let ptr = DmaTarget::as_mut(&mut guard) as *mut u8;
+    let mut offset = 0;
+    for region in guard.prd() {
+        // SAFETY: `offset` is within the buffer
+        let expected = crate::mem::mem_map::translate_ptr(unsafe { ptr.add(offset) });
+        if expected != Some(region.addr) {
+            return Err(alloc::format!("Region at offset {offset:#x} starts at {:#x}, expected {expected:x?}", region.addr));
+        }
+        offset += region.size;
+    }
+    ensure(offset == LEN, "Physical regions do not cover the buffer")?;
+
+    let (claimed, mut lent) = guard.claim().ok_or_else(|| String::from("Failed to claim an owned guard"))?;
+    // SAFETY: The lent buffer is exclusively owned by `lent`
+    unsafe { &mut *lent.as_mut() }.fill(0xa5);
+    let claimed = match claimed.unwrap() {
+        Ok(_) => return Err(String::from("Guard was unwrapped while its buffer was lent")),
+        Err(claimed) => claimed,
+    };
+    drop(lent);
+    let guard = claimed.unwrap().map_err(|_| String::from("Guard could not be unwrapped after its buffer was returned"))?;
+    ensure(guard.unwrap().iter().all(|b| *b == 0xa5), "Writes to the lent buffer were lost")?;
+
+    // Dropping a guard while it is lent leaks the buffer rather than freeing it
+    let (claimed, mut lent) = DmaGuard::from(alloc::vec![0x5au8; 64]).claim().unwrap();
+    drop(claimed);
+    // SAFETY: See above
+    ensure(unsafe { &*lent.as_mut() }.iter().all(|b| *b == 0x5a), "Buffer was freed while it was lent")?;
+    Ok(())
+}
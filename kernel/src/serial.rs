@@ -1,7 +1,7 @@
 //! This module handles UART devices (obviously). It handles it in 2 modes "deaf & dumb" and async.
 //!
 //! - Deaf & dumb does not attempt to actually check that a UART device exists ad is intended for
-//! early startup messages
+//! early startup messages, see [early]
 //! - Async is initialized with other drivers and configures the serial device as a
 //! [futures_util::Sink] it also provides sync methods. The async mode also allows the serial device
 //! to act as a [futures_util::Stream] although the sink is mutually exclusive.
@@ -9,18 +9,17 @@
 //! If I ever write serial drivers for other devices (i.e PCI) I need to check if this module owns
 //! the device  and claim ownership.  
 
+use crate::fs::devfs::naming::DeviceClass;
 use alloc::boxed::Box;
-use alloc::string::ToString;
 use core::pin::Pin;
 use core::task::{Context, Poll};
-use lazy_static::lazy_static;
+use futures_util::FutureExt;
 use modular_bitfield::{bitfield, BitfieldSpecifier};
 use spin::Mutex;
-use uart_16550::SerialPort;
-
-const FS_LOCATION: &str = "/";
 
 mod dispatcher;
+pub mod early;
+pub(crate) use dispatcher::QUOTA_SIZE;
 
 static COM_REAL: spin::RwLock<alloc::vec::Vec<alloc::sync::Arc<Serial>>> =
     spin::RwLock::new(alloc::vec::Vec::new());
@@ -28,26 +27,15 @@ static COM_REAL: spin::RwLock<alloc::vec::Vec<alloc::sync::Arc<Serial>>> =
 pub static COM: spin::RwLock<alloc::vec::Vec<dispatcher::SerialDispatcher>> =
     spin::RwLock::new(alloc::vec::Vec::new());
 
-lazy_static! {
-    pub static ref SP0: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3f8) };
-        serial_port.init();
-        Mutex::new(serial_port)
-    };
-}
-
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
-    use core::fmt::Write;
-
-    if let Some(s) = COM.read().get(0) {
-        let _ = s.write_sync(args); // What exactly are we supposed to do for an error?
-    } else {
-        x86_64::instructions::interrupts::without_interrupts(|| {
-            SP0.lock()
-                .write_fmt(args)
-                .expect("Printing to serial failed")
-        });
+    if early::is_active() {
+        return early::write(args);
+    }
+    // Falls back to the early console once the port has been removed, e.g. during shutdown
+    let sent = COM.read().get(0).is_some_and(|s| s.write_sync(args).is_ok());
+    if !sent {
+        early::write(args);
     }
 }
 
@@ -88,6 +76,8 @@ struct Serial {
     /// Always clear for 8250
     rx_idle: atomic::Atomic<bool>,
     rx_idle_enable: bool, // this is immutable
+    /// Number of bytes which can be written to the transmitter at once. 16 when a 16550A FIFO is enabled.
+    tx_fifo_len: atomic::Atomic<u8>,
     //read_buff: spin::RwLock<Option<crossbeam_queue::ArrayQueue<u8>>>,
     rx_tgt: spin::Mutex<Option<(*mut [u8], usize)>>,
     dispatcher: futures_util::task::AtomicWaker,
@@ -113,6 +103,15 @@ impl Serial {
     // not used because it doesn't work on qemu
     const SCRATCH_REG: u16 = 7;
 
+    /// Size of the 16550A FIFOs
+    const FIFO_LEN: u8 = 16;
+    /// Enables and clears both FIFOs. The receiver trigger level is left at 1 byte.
+    const FIFO_ENABLE: u8 = 0x07;
+    /// Line control bit which holds the transmit line low.
+    const BREAK_ENABLE: u8 = 1 << 6;
+    /// The UART clock divided by 16, this is the baud-rate when the divisor is `1`.
+    const BASE_BAUD: u32 = 115200;
+
     pub fn new(addr: u16) -> Result<Self, SerialError> {
         let mut s = Self {
             base: addr,
@@ -128,6 +127,7 @@ impl Serial {
 
             rx_idle: atomic::Atomic::new(true),
             rx_idle_enable: false,
+            tx_fifo_len: atomic::Atomic::new(1),
             //read_buff: spin::RwLock::new(None),
             rx_tgt: spin::Mutex::new(None),
             dispatcher: futures_util::task::AtomicWaker::new(),
@@ -158,6 +158,35 @@ impl Serial {
         self.set_char_mode(self.bits.load(atomic::Ordering::Relaxed), self.parity.load(atomic::Ordering::Relaxed), self.stop.load(atomic::Ordering::Relaxed));
     }
 
+    /// Returns the current baud-rate, rounded down.
+    pub fn baud_rate(&self) -> u32 {
+        Self::BASE_BAUD / self.divisor.load(atomic::Ordering::Relaxed) as u32
+    }
+
+    /// Returns the divisor which gives the nearest baud-rate to `baud_rate`.
+    ///
+    /// Returns `None` if `baud_rate` is higher than the maximum or is too low to be represented.
+    pub fn divisor_for(baud_rate: u32) -> Option<u16> {
+        if baud_rate == 0 || baud_rate > Self::BASE_BAUD {
+            return None;
+        }
+        // rounded division
+        let div = (Self::BASE_BAUD + baud_rate / 2) / baud_rate;
+        div.try_into().ok()
+    }
+
+    /// Holds the transmit line in the break condition while `enable` is set.
+    ///
+    /// The break condition is cleared by [Self::set_char_mode].
+    pub fn set_break(&self, enable: bool) {
+        let mut line = x86_64::instructions::port::Port::<u8>::new(self.base + Self::LINE_CTL);
+        // SAFETY: The line control register is owned by `self`, only the break bit is modified.
+        unsafe {
+            let v = line.read();
+            line.write(if enable { v | Self::BREAK_ENABLE } else { v & !Self::BREAK_ENABLE });
+        }
+    }
+
     pub fn set_char_mode(&self, data_bits: DataBits, parity: Parity, stop_bits: StopBits) {
         let mut b = data_bits as u8;
         b |= (parity as u8) << 2;
@@ -263,17 +292,53 @@ impl Serial {
             let r= l.push(buff);
 
             // try to start sending if it isn't already
-            if !self.run.load(atomic::Ordering::Relaxed) {
-                if let Some(b) = l.pop() {
-                    if self.try_send(b).is_ok() {
-                        self.run.store(true, atomic::Ordering::Relaxed);
-                    }
-                }
-            }
+            self.kick_tx(&l);
             r
         })
     }
 
+    /// Starts transmitting `buff` if the transmitter is idle.
+    ///
+    /// Interrupts must be disabled while this is called.
+    fn kick_tx(&self, buff: &crate::interrupts::buff::ChonkyBuff<u8>) {
+        if !self.run.load(atomic::Ordering::Relaxed) && self.can_send() && self.fill_tx(buff) {
+            self.run.store(true, atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Writes up to [Self::tx_fifo_len] bytes from `buff` without polling the line status between
+    /// each byte. Small writes queued in `buff` are coalesced into a single burst.
+    ///
+    /// The caller must ensure that the transmitter is empty. Returns `false` if `buff` was empty.
+    fn fill_tx(&self, buff: &crate::interrupts::buff::ChonkyBuff<u8>) -> bool {
+        let mut burst = [0u8; Self::FIFO_LEN as usize];
+        let len = self.tx_fifo_len.load(atomic::Ordering::Relaxed) as usize;
+        let count = buff.pop_into(&mut burst[..len]);
+
+        let mut reg = x86_64::instructions::port::Port::new(self.base);
+        for b in &burst[..count] {
+            // SAFETY: The port is owned by self, the FIFO has room for `len` bytes.
+            unsafe { reg.write(*b) }
+        }
+        count != 0
+    }
+
+    /// Attempts to enable the 16550A FIFOs. The 8250 and 16450 have no FIFO, and the 16550 has a
+    /// broken one, these are left with a transmit depth of 1.
+    ///
+    /// Returns whether the FIFOs were enabled.
+    fn enable_fifo(&self) -> bool {
+        self.set_fifo(Self::FIFO_ENABLE);
+        if self.int_id().fifo() == FifoEnabled::Working {
+            self.tx_fifo_len.store(Self::FIFO_LEN, atomic::Ordering::Relaxed);
+            true
+        } else {
+            self.set_fifo(0);
+            self.tx_fifo_len.store(1, atomic::Ordering::Relaxed);
+            false
+        }
+    }
+
     /// Returns the interrupt vector for this port.
     ///
     /// Note: These are routed to IOAPIC's not directly to the CPU's interrupt vectors
@@ -307,12 +372,13 @@ impl Serial {
                 match id.reason() {
                     IntReason::ModemStatus => panic!("Serial modem status change"), // This is not configured to raise an interrupt
                     IntReason::TransmitterEmpty => {
+                        let l = self.write_buff.lock();
+                        // The FIFO may have drained while it was being filled on an 8250.
                         while self.can_send() {
-                            // fill the fifo
-                            if let Some(b) = self.write_buff.lock().pop() {
-                                self.try_send(b).unwrap() // checks if sending is allowed beforehand
+                            // Relaxed because `in`/`out` instructions are serializing
+                            if self.fill_tx(&l) {
+                                self.run.store(true,atomic::Ordering::Relaxed);
                             } else {
-                                // Relaxed because `in`/`out` instructions are serializing and `try_send()` here will always send
                                 self.run.store(false,atomic::Ordering::Relaxed);
                                 break;
                             }
@@ -417,6 +483,17 @@ pub enum SerialError {
     NoLoopback,
 }
 
+/// Control requests for serial ports, see [crate::fs::device::DeviceFile::control].
+#[derive(Debug, Clone)]
+pub enum SerialCtl {
+    /// Holds the transmit line in the break condition while set.
+    SetBreak(bool),
+    /// Returns the baud-rate as [crate::fs::device::CtlResponse::Value].
+    GetBaud,
+    /// Sets the baud-rate to the nearest rate supported by the device.
+    SetBaud(u32),
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub enum Parity {
@@ -525,6 +602,9 @@ pub fn init_rt_serial() {
                 log::info!("Found UART device on {a:#x}");
 
                 p.set_fifo(6); // Clears & disables FIFO
+                if p.enable_fifo() {
+                    log::info!("Enabled 16550A FIFO on {a:#x}");
+                }
 
                 // Handle pending interrupts by ignoring them.
                 loop {
@@ -559,14 +639,39 @@ pub fn init_rt_serial() {
                     )
                 }
                 let p = alloc::sync::Arc::new(p);
-                let d = dispatcher::SerialDispatcher::new(&p);
+                let dev = crate::system::device::Device::new(alloc::format!("COM{i}"), None).expect("Failed to create UART device");
+                let d = dispatcher::SerialDispatcher::new(crate::system::device::Handle::new(dev.clone(), p.clone()));
 
                 // todo store in sysfs
-                // root must always be present as a directory
-                //let fs_dst = cast_file!(Directory: crate::task::util::block_on!(crate::fs::get_vfs().open("/")).unwrap()).unwrap();
-                //fs_dst.store( &format_args!("COM{i}").to_string() ,Box::new(d.clone()));
-                let name = format_args!("{FS_LOCATION}COM{i}").to_string();
-                crate::task::util::block_on!(crate::fs::get_vfs().mount_dev(Box::new(d.clone()),&name)).expect("Failed to crate mount FIFO for UART to VFS");
+                let name = crate::task::util::block_on!(crate::fs::devfs::register(Box::new(d.clone()), DeviceClass::Serial)).expect("Failed to register UART with devfs");
+                log::info!("COM{i} registered as {name}");
+
+                // Callbacks run in reverse, the devfs node is removed before interrupts are disabled
+                let port = p.clone();
+                let _ = dev.on_remove("disable interrupts", move || {
+                    async move {
+                        // Gives buffered output a chance to be sent
+                        for _ in 0..50 {
+                            if x86_64::instructions::interrupts::without_interrupts(|| port.write_buff.lock().valid_len()) == 0 {
+                                break;
+                            }
+                            crate::task::util::sleep(10).await;
+                        }
+                        // SAFETY: Disabling interrupts does not affect the handler
+                        unsafe { port.set_int_enable(InterruptEnable::empty()) };
+                        // Wakes the dispatcher so it sees the removal and exits
+                        port.dirty.store(true, atomic::Ordering::Relaxed);
+                        port.dispatcher.wake();
+                    }
+                    .boxed()
+                });
+                let id = crate::fs::file::File::device(&d);
+                let _ = dev.on_remove("unregister devfs node", move || {
+                    async move {
+                        let _ = crate::fs::devfs::unregister(id).await;
+                    }
+                    .boxed()
+                });
 
                 com.push(p);
 
@@ -611,6 +716,7 @@ pub fn init_rt_serial() {
             );
             gsi.set().expect("Failed to set GSI");
         }
+        crate::interrupts::affinity::register(&[t], alloc::format!("serial ISA {isa_irq}"), Box::new(Mutex::new(gsi)), 0);
     }
 
     for i in &com {
@@ -620,7 +726,11 @@ pub fn init_rt_serial() {
     }
 
     *COM_REAL.write() = com;
+    let started = !dispatchers.is_empty();
     *COM.write() = dispatchers;
+    if started {
+        early::retire();
+    }
 }
 
 #[allow(unused)]
@@ -638,7 +748,6 @@ struct IntIdentification {
     _reserved: modular_bitfield::specifiers::B1,
     #[allow(dead_code)]
     fifo_64_bytes: bool,
-    #[allow(dead_code)]
     fifo: FifoEnabled,
 }
 
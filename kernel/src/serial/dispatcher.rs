@@ -3,7 +3,7 @@ use core::pin::Pin;
 use core::task::{Context, Poll};
 use futures_util::future::BoxFuture;
 use futures_util::{FutureExt};
-use crate::fs::device::{Fifo, OpenMode};
+use crate::fs::device::{CtlResponse, DeviceCtl, Fifo, OpenMode};
 use crate::serial::Serial;
 use crate::fs::file::*;
 use crate::fs::{IoError, IoResult};
@@ -18,9 +18,14 @@ use kernel_proc_macro::ok_or_lazy;
 // fixme there is a bug in here somewhere causing stack overflows to occur
 // I fixed it partially, it no longer panics the kernel but I'm not sure what the root cause is.
 
-const DEFAULT_QUOTA_SIZE: usize = 4096;
+/// Number of bytes which may be buffered for writing to a serial port.
+pub(crate) static QUOTA_SIZE: crate::config::Tunable<usize> = crate::config::Tunable::new(
+    "serial.quota",
+    "Number of bytes buffered for writes to each serial port",
+    if cfg!(feature = "low-mem") { 1024 } else { 4096 },
+);
 
-lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::new(););
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("serial").unwrap(););
 static MINOR: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
 
 /// This struct handles managing an instance of [Serial].
@@ -28,20 +33,30 @@ static MINOR: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize:
 /// waking tasks requesting to use the serial port.
 ///
 /// This is the file object representing a [Serial] device.
+///
+/// Writes are limited by [QUOTA_SIZE], when the quota is exhausted writers wait in the order they
+/// arrived. A file object may be set to non-blocking mode using [Self::set_nonblocking], in which
+/// case writes which would wait return [IoError::WouldBlock] instead.
 #[derive(Clone)]
 pub struct SerialDispatcher {
     inner: alloc::sync::Arc<SerialDispatcherInner>,
     fifo_lock: OpenMode,
+    nonblocking: bool,
+    /// Ignores the quota, used by [Self::write_sync]
+    force: bool,
     // This is a hack work-around.
     // We need a read-buffer but none can be provided using [crate::fs::device::Fifo::open].
 }
 
 struct SerialDispatcherInner {
-    real: alloc::sync::Weak<Serial>,
+    real: crate::system::device::Handle<Serial>,
     quota: atomic::Atomic<usize>,
 
-    pend: spin::Mutex<alloc::collections::VecDeque<core::task::Waker>>,
-    draining: atomic::Atomic<bool>,
+    /// Writers waiting for the quota, in the order they arrived. Only the front writer may proceed.
+    pend: spin::Mutex<alloc::collections::VecDeque<(u64, core::task::Waker)>>,
+    next_ticket: core::sync::atomic::AtomicU64,
+    /// Tasks waiting in [SerialDispatcher::poll_write_ready]
+    writable: spin::Mutex<alloc::vec::Vec<core::task::Waker>>,
     stream: futures_util::task::AtomicWaker,
 
     stream_lock: atomic::Atomic<bool>,
@@ -50,26 +65,65 @@ struct SerialDispatcherInner {
 }
 
 impl SerialDispatcher {
-    pub(super) fn new(real: &alloc::sync::Arc<Serial>) -> Self {
+    pub(super) fn new(real: crate::system::device::Handle<Serial>) -> Self {
         Self {
             inner: alloc::sync::Arc::new(SerialDispatcherInner {
-                real: alloc::sync::Arc::downgrade(real),
-                quota: atomic::Atomic::new(DEFAULT_QUOTA_SIZE),
+                real,
+                quota: atomic::Atomic::new(QUOTA_SIZE.get()),
                 pend: Default::default(),
-                draining: atomic::Atomic::new(false),
+                next_ticket: core::sync::atomic::AtomicU64::new(0),
+                writable: Default::default(),
                 stream: Default::default(),
                 stream_lock: atomic::Atomic::new(false),
 
                 id: DevID::new(*MAJOR,MINOR.fetch_add(1,atomic::Ordering::Relaxed))
             }),
             fifo_lock: Default::default(),
+            nonblocking: false,
+            force: false,
+        }
+    }
+
+    /// Sets whether writes by this file object return [IoError::WouldBlock] instead of waiting
+    /// when the write quota is exhausted.
+    ///
+    /// This is intended for writers such as loggers which would rather drop data than stall.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    pub fn is_nonblocking(&self) -> bool {
+        self.nonblocking
+    }
+
+    /// Returns whether a write of `len` bytes could currently proceed without waiting.
+    pub fn write_ready(&self, len: usize) -> bool {
+        let Some(real) = self.inner.real.get() else { return false };
+        without_interrupts(|| self.inner.pend.lock().is_empty() && self.inner.has_room(&real, len))
+    }
+
+    /// Polls whether a write of `len` bytes could proceed without waiting.
+    ///
+    /// When this returns [Poll::Pending] the task will be woken when buffered data has been sent.
+    /// Readiness is not a reservation, another writer may consume the quota before this task writes.
+    pub fn poll_write_ready(&self, cx: &mut Context, len: usize) -> Poll<()> {
+        if self.write_ready(len) {
+            return Poll::Ready(());
+        }
+        without_interrupts(|| self.inner.writable.lock().push(cx.waker().clone()));
+        // data may have been sent before the waker was registered
+        if self.write_ready(len) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
         }
     }
 
     pub(super) async fn run(self) -> crate::task::TaskResult {
         loop {
-            if let Some(r) = self.inner.real.upgrade() {
-                (&*r).await;
+            // The device is not entered while waiting, so removal can proceed while the port is idle
+            (&**self.inner.real.data()).await;
+            if let Some(r) = self.inner.real.get() {
 
                 // todo set a threshold for data out size. To prevent excess waking of the stream
                 // check if the stream needs to be woken
@@ -77,28 +131,21 @@ impl SerialDispatcher {
                     self.inner.stream.wake();
                 }
 
-                x86_64::instructions::interrupts::without_interrupts(|| {
+                let freed = x86_64::instructions::interrupts::without_interrupts(|| {
                     let mut l = r.write_buff.lock();
-
-                    if l.len() > l.valid_len() {
-                        l.free();
-                        if l.len() < self.inner.quota.load(atomic::Ordering::Relaxed) {
-                            if !self.inner.draining.swap(true, atomic::Ordering::Acquire) {
-                                // waiting tasks will wake the next on if it can.
-                                // calling wake may wake the wrong task
-                                // so this checks if the wakers are currently being woken
-                                self.inner.pend.lock().pop_front().map(|d| d.wake());
-                            }
-                        }
-                    } else {
-                        // free must be called regardless
-                        l.free();
-                    }
+                    let freed = l.len() > l.valid_len();
+                    // free must be called regardless
+                    l.free();
+                    freed
                 });
+
+                // `write_buff` must not be locked while `pend` is acquired
+                if freed {
+                    self.inner.wake_writers();
+                }
             } else {
-                // this allows self.parent to be dropped if this is the only reference to it.
-                // Self.inner was dropped. This shouldn't happen normally. Maybe self was hot pluggable?
-                // todo log actual info about why this was stopped
+                // The device was removed
+                log::debug!("Serial device {} removed, stopping dispatcher", self.inner.real.device().name());
                 return crate::task::TaskResult::StoppedExternally;
             }
         }
@@ -112,6 +159,7 @@ impl SerialDispatcher {
     pub fn write_sync(&self, data: core::fmt::Arguments) -> Result<(),(IoError, usize)> {
         use crate::util::WriteableBuffer;
         let mut self_mut = self.clone();
+        self_mut.force = true;
         let mut st = [0u8;128];
 
         let mut stw = st.writable();
@@ -126,11 +174,102 @@ impl SerialDispatcher {
         // SAFETY: This is unsafe because this may attempt to call deallocate() on &dma_buff. Box::leak() is called below
         let stack_box = unsafe { Box::from_raw(&mut dma_buff) };
         // pos=0: `pos` ignored by this module
-        let (Ok((stack_box,_)) | Err((_,stack_box,_))) = crate::task::util::block_on!(self_mut.write(0,stack_box));
+        let (stack_box, r) = match crate::task::util::block_on!(self_mut.write(0,stack_box)) {
+            Ok((stack_box,_)) => (stack_box, Ok(())),
+            Err((e,stack_box,n)) => (stack_box, Err((e,n))),
+        };
         // SAFETY: This must be called because of Box::from_raw above.
         let _ = Box::leak(stack_box);
 
-        Ok(())
+        r
+    }
+}
+
+impl SerialDispatcherInner {
+    /// Returns whether `len` bytes can be buffered without exceeding the quota.
+    /// Writes larger than the quota are allowed when nothing is buffered.
+    fn has_room(&self, real: &Serial, len: usize) -> bool {
+        let used = without_interrupts(|| real.write_buff.lock().valid_len());
+        used == 0 || used + len <= self.quota.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Wakes the writer at the front of the queue and all tasks waiting for readiness.
+    fn wake_writers(&self) {
+        let ready = without_interrupts(|| {
+            if let Some((_, w)) = self.pend.lock().front() {
+                w.wake_by_ref();
+            }
+            core::mem::take(&mut *self.writable.lock())
+        });
+        for w in ready {
+            w.wake();
+        }
+    }
+}
+
+/// Waits until the writer is at the front of [SerialDispatcherInner::pend] and the quota has room.
+///
+/// While this completes the writer remains at the front of the queue, dropping this removes the
+/// writer from the queue and allows the next writer to proceed.
+struct QuotaWait<'a> {
+    inner: &'a SerialDispatcherInner,
+    real: &'a Serial,
+    len: usize,
+    nonblocking: bool,
+    ticket: Option<u64>,
+}
+
+impl core::future::Future for QuotaWait<'_> {
+    type Output = Result<(), IoError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let (inner, real) = (self.inner, self.real);
+        without_interrupts(|| {
+            let mut pend = inner.pend.lock();
+            match self.ticket {
+                None => {
+                    // writers may only skip the queue when nobody is waiting
+                    if pend.is_empty() && inner.has_room(real, self.len) {
+                        return Poll::Ready(Ok(()));
+                    }
+                    if self.nonblocking {
+                        return Poll::Ready(Err(IoError::WouldBlock));
+                    }
+                    let ticket = inner.next_ticket.fetch_add(1, atomic::Ordering::Relaxed);
+                    pend.push_back((ticket, cx.waker().clone()));
+                    self.ticket = Some(ticket);
+                    crate::task::wait::note(crate::task::wait::WaitPoint::device("serial tx"));
+                    Poll::Pending
+                }
+                Some(ticket) => {
+                    if pend.front().map(|(t, _)| *t) == Some(ticket) && inner.has_room(real, self.len) {
+                        return Poll::Ready(Ok(()));
+                    }
+                    if let Some((_, w)) = pend.iter_mut().find(|(t, _)| *t == ticket) {
+                        w.clone_from(cx.waker());
+                    }
+                    crate::task::wait::note(crate::task::wait::WaitPoint::device("serial tx"));
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+impl Drop for QuotaWait<'_> {
+    fn drop(&mut self) {
+        let Some(ticket) = self.ticket else { return };
+        without_interrupts(|| {
+            let mut pend = self.inner.pend.lock();
+            if let Some(i) = pend.iter().position(|(t, _)| *t == ticket) {
+                pend.remove(i);
+                if i == 0 {
+                    if let Some((_, w)) = pend.front() {
+                        w.wake_by_ref();
+                    }
+                }
+            }
+        })
     }
 }
 
@@ -184,11 +323,26 @@ impl Drop for SerialDispatcher {
     }
 }
 
-impl crate::fs::device::DeviceFile for SerialDispatcher {}
+impl crate::fs::device::DeviceFile for SerialDispatcher {
+    fn control<'f, 'a: 'f>(&'a mut self, request: DeviceCtl) -> IoResult<'f, CtlResponse> {
+        async move {
+            let DeviceCtl::Serial(request) = request else {
+                return Err(IoError::NotSupported);
+            };
+            let real = self.inner.real.get().ok_or(IoError::MediaError)?;
+            match request {
+                super::SerialCtl::SetBreak(enable) => real.set_break(enable),
+                super::SerialCtl::GetBaud => return Ok(CtlResponse::Value(real.baud_rate() as u64)),
+                super::SerialCtl::SetBaud(baud) => real.set_divisor(Serial::divisor_for(baud).ok_or(IoError::InvalidData)?),
+            }
+            Ok(CtlResponse::None)
+        }.boxed()
+    }
+}
 
 impl crate::fs::device::Fifo<u8> for SerialDispatcher {
     fn open(&mut self, mode: OpenMode) -> Result<(), IoError> {
-        let _ = self.inner.real.upgrade().ok_or(IoError::MediaError)?; // assert that controller is still there
+        let _ = self.inner.real.get().ok_or(IoError::MediaError)?; // assert that controller is still there
 
         if mode.is_read() {
             if let Err(_) = self.inner.stream_lock.compare_exchange_weak(false,true, atomic::Ordering::Acquire, atomic::Ordering::Relaxed) {
@@ -245,7 +399,7 @@ impl Read<u8> for SerialDispatcher {
         async {
             if self.fifo_lock.is_read() {
                 // cant use ok_or(_) here because of use after free on `dbuff`
-                let real = match self.inner.real.upgrade() {
+                let real = match self.inner.real.get() {
                     Some(real) => real,
                     None => return Err((IoError::MediaError,dbuff,0)),
                 };
@@ -279,7 +433,8 @@ impl Read<u8> for SerialDispatcher {
                     Some(Err((rc,count))) => return Err((rc,dbuff,count)),
                     _ => {}
                 }
-
+                // Received data is waited for without entering the device, ReadFut enters it for each poll
+                drop(real);
 
                 let rc = ReadFut {
                     dispatch: self,
@@ -371,7 +526,7 @@ impl<'a,'b> core::future::Future for ReadFut<'a,'b> {
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let real = if self.dispatch.fifo_lock.is_read() {
-            self.dispatch.inner.real.upgrade().ok_or((IoError::MediaError, 0))?
+            self.dispatch.inner.real.get().ok_or((IoError::MediaError, 0))?
         } else {
             Err((IoError::NotReady, 0))?
         };
@@ -390,6 +545,7 @@ impl<'a,'b> core::future::Future for ReadFut<'a,'b> {
 
             } else {
                 self.dispatch.inner.stream.register(cx.waker());
+                crate::task::wait::note(crate::task::wait::WaitPoint::device("serial rx"));
                 Poll::Pending
             }
         })
@@ -403,19 +559,35 @@ impl Write<u8> for SerialDispatcher {
                 // SAFETY: as_mut guarantees that this is safe.
                 let buff = unsafe { &mut *crate::mem::dma::DmaTarget::as_mut(&mut *dbuff) };
                 // Returning here indicates that the driver has closed the controller.
-                let real = ok_or_lazy!(self.inner.real.upgrade() => Err((IoError::MediaError, dbuff, 0)));
-                let run = real.run.swap(true,atomic::Ordering::Acquire);
-                let mut write_buff = real.write_buff.lock();
-                let push = write_buff.push(buff);
-
-                if !run {
-                    without_interrupts(|| {
-                        if let Some(b) = write_buff.pop() {
-                            real.try_send(b).unwrap(); // Should never be None
-                        }
-                        drop(write_buff);
-                    });
+                if self.inner.real.device().state() != crate::system::device::DeviceState::Active {
+                    return Err((IoError::MediaError, dbuff, 0));
+                }
+
+                // The quota is waited for without entering the device
+                let mut wait = QuotaWait {
+                    inner: &self.inner,
+                    real: self.inner.real.data(),
+                    len: buff.len(),
+                    nonblocking: self.nonblocking,
+                    ticket: None,
+                };
+                if !self.force {
+                    if let Err(e) = (&mut wait).await {
+                        return Err((e, dbuff, 0));
+                    }
                 }
+
+                let real = ok_or_lazy!(self.inner.real.get() => Err((IoError::MediaError, dbuff, 0)));
+                // The interrupt handler also locks the buffer.
+                let push = without_interrupts(|| {
+                    let mut write_buff = real.write_buff.lock();
+                    let push = write_buff.push(buff);
+                    real.kick_tx(&write_buff);
+                    push
+                });
+                // lets the next writer proceed
+                drop(wait);
+                drop(real);
                 push.await;
 
                 Ok((dbuff,buff.len()))
@@ -493,7 +665,7 @@ impl Read<u8> for FrameCtlBFile {
     fn read<'f, 'a: 'f,'b: 'f>(&'a self, _: u64, mut dbuff: DmaBuff<'b>) -> BoxFuture<'f, Result<(DmaBuff<'b>, usize), (IoError, DmaBuff<'b>, usize)>> {
         async {
             let buff = unsafe { &mut *crate::mem::dma::DmaTarget::as_mut(&mut *dbuff) };
-            let real = ok_or_lazy!(self.dispatch.inner.real.upgrade() => Err((IoError::MediaError, dbuff, 0)));
+            let real = ok_or_lazy!(self.dispatch.inner.real.get() => Err((IoError::MediaError, dbuff, 0)));
             let b_rate = 115200f32/(real.divisor.load(atomic::Ordering::Relaxed) as f32); // use emulated float for conversion to baud-rate
             let data_bits: u8 = real.bits.load(atomic::Ordering::Relaxed).into();
             let parity: char = real.parity.load(atomic::Ordering::Relaxed).into();
@@ -530,29 +702,10 @@ impl Write<u8> for FrameCtlBFile {
                     Ok(baud) => baud,
                     Err(_) => return Err((IoError::InvalidData, dbuff, 0)),
                 };
-            if baud_rate > 115200 {
-                return Err((IoError::InvalidData, dbuff, 0))
-            }
-
-            // performs rounded integer division.
-            // there's probably a better way to do this.
-            // looks more complicated than it is.
-            let divisor: u16 =
-                {
-                    let clock_rate: u64 = 115200 << 16;
-                    let tgt = baud_rate as u64;
-                    let div_high = clock_rate / tgt;
-                    let div;
-                    if div_high & u16::MAX as u64 > (u16::MAX/2) as u64 {
-                        div = (div_high >> 16) + 1;
-                    } else {
-                        div = div_high >> 16;
-                    };
-                    match div.try_into() {
-                        Ok(v) => v,
-                        Err(_) => return Err((IoError::InvalidData, dbuff, 0)),
-                    }
-                };
+            let divisor = match Serial::divisor_for(baud_rate) {
+                Some(d) => d,
+                None => return Err((IoError::InvalidData, dbuff, 0)),
+            };
             if frame.len() != 4 {
                 return Err((IoError::InvalidData, dbuff, 0))
             }
@@ -588,7 +741,7 @@ impl Write<u8> for FrameCtlBFile {
                 _ => return Err((IoError::InvalidData,dbuff,0))
             };
 
-            let real = ok_or_lazy!(self.dispatch.inner.real.upgrade()  => Err((IoError::MediaError, dbuff,0)));
+            let real = ok_or_lazy!(self.dispatch.inner.real.get()  => Err((IoError::MediaError, dbuff,0)));
             real.set_char_mode(data_bits,parity,stop_bits);
             real.set_divisor(divisor);
             Ok((dbuff, buff.len()))
@@ -653,7 +806,7 @@ impl Read<u8> for RingbuffCtlBFile {
             // If you modify this fn then ensure that `write!` never returns Err(_)
 
             let mut stack_buff = [0u8; 8];
-            let real = self.inner.inner.real.upgrade().ok_or((IoError::MediaError, 0))?;
+            let real = self.inner.inner.real.get().ok_or((IoError::MediaError, 0))?;
             let len = real.read_buff.read().as_ref().map_or(0,|b| b.len());
 
             let _ = write!(stack_buff.writable(),"{}",len); // will never fail
@@ -673,7 +826,7 @@ impl Read<u8> for RingbuffCtlBFile {
 impl Write<u8> for RingbuffCtlBFile {
     fn write<'a>(&'a mut self, buff: &'a [u8]) -> BoxFuture<Result<usize, (IoError, usize)>> {
         async {
-            let real  = self.inner.inner.real.upgrade().ok_or((IoError::MediaError,0))?;
+            let real  = self.inner.inner.real.get().ok_or((IoError::MediaError,0))?;
             let l = real.read_buff.upgradeable_read();
             let n_len: u16 = core::str::from_utf8(buff).map_err(|_| (IoError::InvalidData,0))?.parse().map_err(|_| (IoError::InvalidData,0))?;
 
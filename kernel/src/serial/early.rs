@@ -0,0 +1,106 @@
+//! Early console.
+//!
+//! The early console writes directly to the first UART using polled I/O. It does not allocate, take
+//! locks which may be held forever or depend on interrupts, so it works before the heap and the
+//! serial driver are initialized and while the kernel is panicking.
+//!
+//! [super::_print] uses the early console until the serial driver has started and calls [retire].
+//! The panic handler calls [takeover] so that the panic message is written even though the serial
+//! driver can no longer make progress. Output is also written here when the serial driver's port
+//! has been removed.
+//!
+//! The framebuffer is not used because it is only mapped once the memory manager is initialized.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+const BASE: u16 = 0x3f8;
+const LINE_STS: u16 = 5;
+/// Line status `THRE`, the transmit holding register is empty.
+const TX_EMPTY: u8 = 1 << 5;
+
+/// Maximum number of times the line status is polled before a byte is dropped. This prevents
+/// hanging when no UART is present.
+const TX_SPINS: usize = 0x10000;
+/// Maximum number of times the lock is polled, a CPU which panicked while writing never releases it.
+const LOCK_SPINS: usize = 0x100000;
+
+const UNINIT: u8 = 0;
+const ACTIVE: u8 = 1;
+const RETIRED: u8 = 2;
+
+static STATE: AtomicU8 = AtomicU8::new(UNINIT);
+static LOCK: AtomicBool = AtomicBool::new(false);
+
+/// Programs the UART for 115200 baud 8N1 with interrupts disabled.
+fn init() {
+    // SAFETY: The UART is only programmed here, before the serial driver has started.
+    unsafe {
+        let port = |offset: u16, v: u8| x86_64::instructions::port::Port::<u8>::new(BASE + offset).write(v);
+        port(1, 0); // Disables interrupts
+        port(3, 0x80); // Sets DLAB
+        port(0, 1); // Divisor low
+        port(1, 0); // Divisor high
+        port(3, 0x03); // 8N1, clears DLAB
+        port(2, 0xc7); // Enables and clears the FIFOs
+        port(4, 0x03); // DTR | RTS
+    }
+}
+
+struct Writer;
+
+impl core::fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut sts = x86_64::instructions::port::PortReadOnly::<u8>::new(BASE + LINE_STS);
+        let mut data = x86_64::instructions::port::PortWriteOnly::<u8>::new(BASE);
+        for b in s.bytes() {
+            // SAFETY: Reading the line status and writing the data register have no side effects
+            // other than transmitting the byte.
+            unsafe {
+                for _ in 0..TX_SPINS {
+                    if sts.read() & TX_EMPTY != 0 {
+                        break;
+                    }
+                    core::hint::spin_loop();
+                }
+                data.write(b);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns whether [super::_print] should use the early console.
+pub fn is_active() -> bool {
+    STATE.load(Ordering::Acquire) != RETIRED
+}
+
+/// Writes `args` to the UART, this may be called at any time.
+pub fn write(args: core::fmt::Arguments) {
+    use core::fmt::Write;
+    if STATE.compare_exchange(UNINIT, ACTIVE, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+        init();
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let locked = (0..LOCK_SPINS).any(|_| {
+            let l = LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok();
+            core::hint::spin_loop();
+            l
+        });
+        let _ = Writer.write_fmt(args);
+        if locked {
+            LOCK.store(false, Ordering::Release);
+        }
+    })
+}
+
+/// Hands output over to the serial driver, called once it has started.
+pub(super) fn retire() {
+    STATE.store(RETIRED, Ordering::Release);
+}
+
+/// Makes the early console active again, called by the panic handler.
+pub fn takeover() {
+    if STATE.swap(ACTIVE, Ordering::AcqRel) == UNINIT {
+        init();
+    }
+}
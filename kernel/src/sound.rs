@@ -0,0 +1,5 @@
+//! Minimal sound support. Currently this is only the PC speaker which is used for diagnostic beeps.
+//!
+//! Sound controllers such as Intel HDA are handled by driver crates.
+
+pub mod pc_speaker;
@@ -0,0 +1,117 @@
+//! Driver for the PC speaker driven by channel 2 of the PIT.
+//!
+//! [fault_code] can be used before the timer or interrupts are configured, it is intended to give
+//! feedback on systems which have no other output.
+
+use x86_64::instructions::port::Port;
+
+/// Frequency of the PIT input clock in Hz
+const PIT_FREQUENCY: u32 = 1_193_182;
+const PIT_CH2_DATA: u16 = 0x42;
+const PIT_CMD: u16 = 0x43;
+/// Channel 2, access lo/hi byte, mode 3 (square wave), binary
+const PIT_CH2_SQUARE_WAVE: u8 = 0b1011_0110;
+const SPEAKER_PORT: u16 = 0x61;
+/// Bit 0 gates PIT channel 2, bit 1 connects it to the speaker.
+const SPEAKER_ENABLE: u8 = 0b11;
+
+/// Frequency used by [fault_code]
+const FAULT_TONE: u32 = 880;
+const SHORT_BEEP_MS: u64 = 100;
+const LONG_BEEP_MS: u64 = 400;
+const GAP_MS: u64 = 150;
+
+static SPEAKER: spin::Mutex<()> = spin::Mutex::new(());
+
+/// Fault codes beeped by the kernel. See [fault_code]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum FaultCode {
+    Panic = 0b0001,
+    DoubleFault = 0b0010,
+    NoFramebuffer = 0b0011,
+}
+
+/// Starts a tone at `freq` Hz. The tone will continue until [tone_off] is called.
+///
+/// Frequencies above the PIT frequency are clamped.
+pub fn tone_on(freq: u32) {
+    let _l = SPEAKER.lock();
+    start_tone(freq);
+}
+
+/// Stops the current tone.
+pub fn tone_off() {
+    let _l = SPEAKER.lock();
+    stop_tone();
+}
+
+/// The caller should hold [SPEAKER].
+fn start_tone(freq: u32) {
+    let divisor = (PIT_FREQUENCY / freq.clamp(19, PIT_FREQUENCY)).min(u16::MAX as u32) as u16;
+    // SAFETY: PIT channel 2 is only used by the speaker
+    unsafe {
+        Port::new(PIT_CMD).write(PIT_CH2_SQUARE_WAVE);
+        let mut data = Port::new(PIT_CH2_DATA);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+
+        let mut ctl: Port<u8> = Port::new(SPEAKER_PORT);
+        let v = ctl.read();
+        ctl.write(v | SPEAKER_ENABLE);
+    }
+}
+
+/// The caller should hold [SPEAKER].
+fn stop_tone() {
+    // SAFETY: Only the speaker bits are modified.
+    unsafe {
+        let mut ctl: Port<u8> = Port::new(SPEAKER_PORT);
+        let v = ctl.read();
+        ctl.write(v & !SPEAKER_ENABLE);
+    }
+}
+
+/// Beeps at `freq` Hz for `msec` milliseconds.
+pub async fn beep(freq: u32, msec: u64) {
+    tone_on(freq);
+    crate::task::util::sleep(msec).await;
+    tone_off();
+}
+
+/// Beeps at `freq` Hz for `msec` milliseconds, busy waiting.
+///
+/// This doesn't depend on the system timer and may be called at any point after boot.
+pub fn beep_blocking(freq: u32, msec: u64) {
+    tone_on(freq);
+    delay_ms(msec);
+    tone_off();
+}
+
+/// Beeps `code` as a 4-bit value, most significant bit first.
+/// A long beep represents `1`, a short beep represents `0`.
+///
+/// This is called from the panic and double fault handlers, it does not wait for the speaker lock.
+pub fn fault_code(code: FaultCode) {
+    // The faulting code may hold the lock, in which case the speaker is used regardless.
+    let _l = SPEAKER.try_lock();
+    let code = code as u8;
+    for bit in (0..4).rev() {
+        let len = if code & (1 << bit) != 0 { LONG_BEEP_MS } else { SHORT_BEEP_MS };
+        start_tone(FAULT_TONE);
+        delay_ms(len);
+        stop_tone();
+        delay_ms(GAP_MS);
+    }
+}
+
+/// Busy waits for approximately `msec` milliseconds.
+///
+/// Writes to port `0x80` take approximately 1µs on all PC compatible hardware.
+fn delay_ms(msec: u64) {
+    let mut port: Port<u8> = Port::new(0x80);
+    for _ in 0..msec * 1000 {
+        // SAFETY: Port 0x80 is the POST code port, writing to it has no side effects
+        unsafe { port.write(0) }
+    }
+}
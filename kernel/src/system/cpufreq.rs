@@ -0,0 +1,264 @@
+//! CPU frequency scaling.
+//!
+//! Performance states are selected by a [Governor] from the utilization of each CPU, which is the
+//! fraction of time the CPU's executor was not halted while idle. Each CPU samples its own
+//! utilization and requests its own performance state from within the executor's idle loop, because
+//! performance requests are made through per-CPU MSRs.
+//!
+//! Two drivers are supported
+//! - Intel Speed Shift (HWP): The requested level is given to the hardware as the desired
+//!   performance, the hardware selects the frequency within the given range.
+//! - Enhanced Intel SpeedStep: The requested ratio is written to `IA32_PERF_CTL`, the available
+//!   ratios are read from `MSR_PLATFORM_INFO`.
+//!
+//! ACPI `_PSS` objects require an AML interpreter and are not used, AMD processors are not supported.
+//! Frequency scaling is disabled when running under a hypervisor.
+//!
+//! While the system is throttled by [super::thermal] the lowest performance level is requested
+//! regardless of the governor.
+
+use crate::config::Tunable;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_msr::architecture::{HwpCapabilities, HwpRequest, MiscEnable, PerfCtl, PmEnable, RawValue};
+use x86_msr::{Msr, MsrAvailability, MsrReadWrite};
+
+/// Selected performance governor.
+pub static GOVERNOR: Tunable<Governor> = Tunable::new("cpufreq.governor", "CPU frequency governor, one of performance, powersave or schedutil", Governor::Schedutil);
+
+/// Interval between utilization samples.
+pub static SAMPLE_MS: Tunable<u64> = Tunable::new("cpufreq.sample_ms", "Milliseconds between CPU frequency updates", 10);
+
+static DRIVER: spin::Once<Option<Driver>> = spin::Once::new();
+static THROTTLE: AtomicBool = AtomicBool::new(false);
+
+/// Utilization scale, `UTIL_SCALE` is fully utilized.
+const UTIL_SCALE: u64 = 1024;
+/// `IA32_MISC_ENABLE` Enhanced Intel SpeedStep enable
+const EIST_ENABLE: u64 = 1 << 16;
+
+/// MSR_PLATFORM_INFO, contains the maximum non-turbo ratio in bits 15:8 and the minimum ratio in
+/// bits 47:40. This is not architectural, it is only read on Intel processors.
+struct PlatformInfo;
+
+impl Msr<RawValue> for PlatformInfo {
+    const MSR_ADDR: u32 = 0xce;
+
+    fn availability() -> MsrAvailability {
+        MsrAvailability::new(MsrReadWrite::Read)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Driver {
+    /// Hardware controlled performance states, contains the lowest and highest performance levels.
+    Hwp { lowest: u8, highest: u8 },
+    /// Enhanced Intel SpeedStep, contains the minimum and maximum non-turbo bus ratios.
+    Eist { min: u8, max: u8 },
+}
+
+impl Driver {
+    fn range(&self) -> (u8, u8) {
+        match *self {
+            Driver::Hwp { lowest, highest } => (lowest, highest),
+            Driver::Eist { min, max } => (min, max),
+        }
+    }
+
+    /// Detects the performance state interface supported by this CPU.
+    fn detect() -> Option<Self> {
+        if crate::system::hypervisor::hypervisor().is_some() {
+            return None;
+        }
+        let cpuid = raw_cpuid::CpuId::new();
+        if !cpuid.get_vendor_info().is_some_and(|v| v.as_str() == "GenuineIntel") {
+            return None;
+        }
+
+        if PmEnable::availability().get_bit(0) == MsrReadWrite::Write {
+            // SAFETY: HWP is supported
+            let caps = unsafe { HwpCapabilities::read() }.value;
+            let (highest, lowest) = (caps as u8, (caps >> 24) as u8);
+            if lowest <= highest && highest != 0 {
+                return Some(Driver::Hwp { lowest, highest });
+            }
+        }
+
+        if PerfCtl::availability().get_bit(8) == MsrReadWrite::Write {
+            // SAFETY: MISC_ENABLE is present on all Intel processors supporting EIST
+            if unsafe { MiscEnable::read() }.value & EIST_ENABLE == 0 {
+                return None;
+            }
+            // SAFETY: MSR_PLATFORM_INFO is present on Intel processors supporting EIST
+            let info = unsafe { PlatformInfo::read() }.value;
+            let (max, min) = ((info >> 8) as u8, (info >> 40) as u8);
+            if min <= max && min != 0 {
+                return Some(Driver::Eist { min, max });
+            }
+        }
+        None
+    }
+
+    /// Initializes the driver on this CPU.
+    fn init_cpu(&self) {
+        if let Driver::Hwp { .. } = self {
+            // SAFETY: HWP is supported, enabling it does not change the current performance state
+            unsafe { PmEnable::write(1u64.into()) }
+        }
+    }
+
+    /// Requests the performance level `level` on this CPU.
+    fn request(&self, level: u8) {
+        match self {
+            // SAFETY: Requests are checked to be within the range given by the hardware.
+            Driver::Hwp { lowest, highest } => unsafe {
+                // Energy performance preference is scaled with the request, 0 is maximum performance
+                let epp = (0xff - (level - lowest) as u64 * 0xff / (*highest - lowest).max(1) as u64) << 24;
+                HwpRequest::write((*lowest as u64 | (*highest as u64) << 8 | (level as u64) << 16 | epp).into())
+            },
+            Driver::Eist { .. } => unsafe {
+                let ctl = PerfCtl::read().value & !0xff00;
+                PerfCtl::write((ctl | (level as u64) << 8).into())
+            },
+        }
+    }
+}
+
+/// Selects the performance level from utilization.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Governor {
+    /// Always uses the highest performance level.
+    Performance,
+    /// Always uses the lowest performance level.
+    Powersave,
+    /// Scales the performance level with utilization, with headroom so that a CPU which is
+    /// nearly fully utilized runs at the highest level.
+    Schedutil,
+}
+
+impl Governor {
+    /// Returns the performance level within `min..=max` for `util` out of [UTIL_SCALE].
+    pub fn target(&self, util: u64, min: u8, max: u8) -> u8 {
+        match self {
+            Governor::Performance => max,
+            Governor::Powersave => min,
+            Governor::Schedutil => {
+                // 1.25 * util, so that 80% utilization selects the highest level
+                let scaled = (util + util / 4).min(UTIL_SCALE);
+                min + ((max - min) as u64 * scaled / UTIL_SCALE) as u8
+            }
+        }
+    }
+}
+
+impl core::str::FromStr for Governor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "performance" => Ok(Governor::Performance),
+            "powersave" => Ok(Governor::Powersave),
+            "schedutil" => Ok(Governor::Schedutil),
+            _ => Err(()),
+        }
+    }
+}
+
+impl core::fmt::Display for Governor {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Governor::Performance => write!(f, "performance"),
+            Governor::Powersave => write!(f, "powersave"),
+            Governor::Schedutil => write!(f, "schedutil"),
+        }
+    }
+}
+
+/// Per-CPU sampling state.
+struct CpuState {
+    initialized: Cell<bool>,
+    /// Start of the current sample
+    window_start: Cell<u64>,
+    /// Nanoseconds spent halted during the current sample
+    idle: Cell<u64>,
+    /// Last requested performance level
+    level: Cell<u8>,
+}
+
+#[thread_local]
+static STATE: CpuState = CpuState {
+    initialized: Cell::new(false),
+    window_start: Cell::new(0),
+    idle: Cell::new(0),
+    level: Cell::new(0),
+};
+
+/// Detects the frequency scaling driver, this must be called on the BSP.
+pub fn init() {
+    let driver = DRIVER.call_once(Driver::detect);
+    match driver {
+        Some(d) => log::info!("cpufreq: Using {d:?}, governor {}", GOVERNOR.get()),
+        None => log::info!("cpufreq: Frequency scaling is not available"),
+    }
+}
+
+/// Returns the frequency scaling driver in use.
+pub fn driver() -> Option<Driver> {
+    DRIVER.get().copied().flatten()
+}
+
+/// Returns whether utilization is sampled on this CPU.
+pub(crate) fn enabled() -> bool {
+    driver().is_some()
+}
+
+/// Causes this CPU to be reinitialized, the performance state is lost while the system is suspended.
+pub(crate) fn resume() {
+    STATE.initialized.set(false);
+    STATE.level.set(0);
+}
+
+/// Forces all CPUs to the lowest performance level while `throttle` is set. Each CPU applies this
+/// on its next sample.
+pub(crate) fn set_throttle(throttle: bool) {
+    THROTTLE.store(throttle, Ordering::Relaxed);
+}
+
+/// Returns whether the performance level is being throttled.
+pub fn throttled() -> bool {
+    THROTTLE.load(Ordering::Relaxed)
+}
+
+/// Records that this CPU was halted for `nanos`.
+pub(crate) fn account_idle(nanos: u64) {
+    STATE.idle.set(STATE.idle.get() + nanos);
+}
+
+/// Samples this CPU's utilization and requests a new performance level when the sample period
+/// has elapsed. This is called from the executor's idle loop.
+pub(crate) fn update() {
+    let Some(driver) = driver() else { return };
+    let now = crate::time::get_sys_time();
+    if !STATE.initialized.get() {
+        driver.init_cpu();
+        STATE.initialized.set(true);
+        STATE.window_start.set(now);
+        return;
+    }
+
+    let elapsed = now.saturating_sub(STATE.window_start.get());
+    if elapsed < SAMPLE_MS.get() * 1_000_000 {
+        return;
+    }
+    let busy = elapsed.saturating_sub(STATE.idle.get());
+    let util = busy * UTIL_SCALE / elapsed;
+    STATE.window_start.set(now);
+    STATE.idle.set(0);
+
+    let (min, max) = driver.range();
+    let level = if throttled() { min } else { GOVERNOR.get().target(util, min, max) };
+    if level != STATE.level.get() {
+        driver.request(level);
+        STATE.level.set(level);
+    }
+}
@@ -0,0 +1,288 @@
+//! Device object model.
+//!
+//! Each device a driver starts is represented by a [Device]. Devices form a tree, a device holds a
+//! reference to its parent so a parent always outlives its children. Drivers access their state
+//! through a [Handle] and must [enter](Handle::get) the device for the duration of each operation,
+//! entering fails once removal has begun.
+//!
+//! [Device::remove] tears a device down in a fixed order
+//! 1. New operations are rejected.
+//! 2. Children are removed, most recently added first.
+//! 3. Operations in progress are waited for.
+//! 4. The callbacks registered with [Device::on_remove] run, most recently registered first.
+//!
+//! So a teardown callback never runs concurrently with an operation on the device, and driver
+//! state is only freed when the last [Handle] is dropped. Hot-unplug removes the device which was
+//! unplugged, [remove_all] removes every device for shutdown.
+//!
+//! Operations must not hold a guard while waiting for something which only happens after removal,
+//! e.g. waiting for an interrupt from the device, removal would wait forever. Such waits should be
+//! made without a guard and the device entered again once they complete.
+
+use crate::fs::IoError;
+use crate::task::wait::WaitPoint;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::task::{Poll, Waker};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use x86_64::instructions::interrupts::without_interrupts;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct DeviceId(u64);
+
+impl core::fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DeviceState {
+    Active,
+    /// Removal has begun, the device may not be entered.
+    Removing,
+    Removed,
+}
+
+type Teardown = Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>;
+
+pub struct Device {
+    id: DeviceId,
+    name: String,
+    parent: Option<Arc<Device>>,
+    children: spin::Mutex<Vec<Weak<Device>>>,
+    state: atomic::Atomic<DeviceState>,
+    /// Number of operations in progress, see [Device::enter].
+    active: AtomicUsize,
+    /// Tasks waiting for `active` to reach zero or for removal to complete.
+    waiters: spin::Mutex<Vec<Waker>>,
+    teardown: spin::Mutex<Vec<(&'static str, Teardown)>>,
+}
+
+/// All devices which have not been removed, devices are owned by this until they are removed.
+static DEVICES: spin::RwLock<BTreeMap<DeviceId, Arc<Device>>> = spin::RwLock::new(BTreeMap::new());
+
+impl Device {
+    /// Creates a device named `name` as a child of `parent`.
+    ///
+    /// Returns [IoError::NotPresent] if the parent is being removed.
+    pub fn new(name: String, parent: Option<&Arc<Device>>) -> Result<Arc<Self>, IoError> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let dev = Arc::new(Self {
+            id: DeviceId(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+            name,
+            parent: parent.cloned(),
+            children: spin::Mutex::new(Vec::new()),
+            state: atomic::Atomic::new(DeviceState::Active),
+            active: AtomicUsize::new(0),
+            waiters: spin::Mutex::new(Vec::new()),
+            teardown: spin::Mutex::new(Vec::new()),
+        });
+        if let Some(p) = parent {
+            // Checked while locked so the child is either seen by removal or rejected
+            let mut children = p.children.lock();
+            if p.state() != DeviceState::Active {
+                return Err(IoError::NotPresent);
+            }
+            children.push(Arc::downgrade(&dev));
+        }
+        DEVICES.write().insert(dev.id, dev.clone());
+        Ok(dev)
+    }
+
+    pub fn id(&self) -> DeviceId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn parent(&self) -> Option<&Arc<Device>> {
+        self.parent.as_ref()
+    }
+
+    pub fn state(&self) -> DeviceState {
+        self.state.load(Ordering::Acquire)
+    }
+
+    /// Begins an operation on the device, returns `None` if the device is being removed.
+    ///
+    /// This may be called from interrupt handlers.
+    pub fn enter(&self) -> Option<DeviceGuard<'_>> {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        // Ordered after the increment so removal either sees the operation or the operation sees removal
+        if self.state.load(Ordering::SeqCst) != DeviceState::Active {
+            self.exit();
+            return None;
+        }
+        Some(DeviceGuard { dev: self })
+    }
+
+    fn exit(&self) {
+        if self.active.fetch_sub(1, Ordering::SeqCst) == 1 && self.state.load(Ordering::SeqCst) != DeviceState::Active {
+            self.wake();
+        }
+    }
+
+    fn wake(&self) {
+        let waiters = without_interrupts(|| core::mem::take(&mut *self.waiters.lock()));
+        waiters.into_iter().for_each(Waker::wake);
+    }
+
+    /// Registers `f` to be called when the device is removed, callbacks are called in the reverse
+    /// order they were registered.
+    ///
+    /// Returns [IoError::NotPresent] if removal has already begun, `f` is not called.
+    pub fn on_remove<F>(&self, name: &'static str, f: F) -> Result<(), IoError>
+    where
+        F: FnOnce() -> BoxFuture<'static, ()> + Send + 'static,
+    {
+        let mut l = self.teardown.lock();
+        if self.state() != DeviceState::Active {
+            return Err(IoError::NotPresent);
+        }
+        l.push((name, Box::new(f)));
+        Ok(())
+    }
+
+    /// Waits until `cond` returns `true`, `cond` is checked each time [Self::wake] is called.
+    async fn wait_until(&self, cond: impl Fn(&Self) -> bool) {
+        core::future::poll_fn(|cx| {
+            if cond(self) {
+                return Poll::Ready(());
+            }
+            without_interrupts(|| self.waiters.lock().push(cx.waker().clone()));
+            if cond(self) {
+                Poll::Ready(())
+            } else {
+                crate::task::wait::note(WaitPoint::device("device removal"));
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Removes the device and its children, see the module documentation for the order of
+    /// teardown. Completes when the device has been removed, including when removal was started
+    /// by another caller.
+    pub fn remove(self: &Arc<Self>) -> BoxFuture<'static, ()> {
+        let dev = self.clone();
+        async move {
+            // Taking the teardown lock orders this against `on_remove`
+            let started = {
+                let _l = dev.teardown.lock();
+                let _c = dev.children.lock();
+                dev.state.compare_exchange(DeviceState::Active, DeviceState::Removing, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+            };
+            if !started {
+                dev.wait_until(|d| d.state() == DeviceState::Removed).await;
+                return;
+            }
+            log::debug!("Removing device {} ({})", dev.name, dev.id);
+
+            let children: Vec<Arc<Device>> = dev.children.lock().iter().rev().filter_map(Weak::upgrade).collect();
+            for child in children {
+                child.remove().await;
+            }
+
+            dev.wait_until(|d| d.active.load(Ordering::SeqCst) == 0).await;
+
+            let callbacks = core::mem::take(&mut *dev.teardown.lock());
+            for (name, f) in callbacks.into_iter().rev() {
+                log::trace!("Device {}: {name}", dev.name);
+                f().await;
+            }
+
+            if let Some(p) = &dev.parent {
+                p.children.lock().retain(|c| c.upgrade().is_some_and(|c| c.id != dev.id));
+            }
+            DEVICES.write().remove(&dev.id);
+            dev.state.store(DeviceState::Removed, Ordering::SeqCst);
+            dev.wake();
+        }
+        .boxed()
+    }
+}
+
+/// An operation in progress on a device, see [Device::enter].
+pub struct DeviceGuard<'a> {
+    dev: &'a Device,
+}
+
+impl Drop for DeviceGuard<'_> {
+    fn drop(&mut self) {
+        self.dev.exit();
+    }
+}
+
+/// Driver state belonging to a [Device].
+pub struct Handle<T> {
+    device: Arc<Device>,
+    data: Arc<T>,
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Self { device: self.device.clone(), data: self.data.clone() }
+    }
+}
+
+impl<T> Handle<T> {
+    pub fn new(device: Arc<Device>, data: Arc<T>) -> Self {
+        Self { device, data }
+    }
+
+    pub fn device(&self) -> &Arc<Device> {
+        &self.device
+    }
+
+    /// Enters the device and returns its state, `None` if the device is being removed.
+    pub fn get(&self) -> Option<Active<'_, T>> {
+        Some(Active { _guard: self.device.enter()?, data: &self.data })
+    }
+
+    /// Returns the state without entering the device. This must only be used to wait for events,
+    /// see the module documentation.
+    pub fn data(&self) -> &Arc<T> {
+        &self.data
+    }
+}
+
+/// Driver state of a device which has been entered, see [Handle::get].
+pub struct Active<'a, T> {
+    _guard: DeviceGuard<'a>,
+    data: &'a T,
+}
+
+impl<T> core::ops::Deref for Active<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+
+/// Returns the device `id` if it has not been removed.
+pub fn get(id: DeviceId) -> Option<Arc<Device>> {
+    DEVICES.read().get(&id).cloned()
+}
+
+/// Returns all devices which have not been removed, in the order they were created.
+pub fn devices() -> Vec<Arc<Device>> {
+    DEVICES.read().values().cloned().collect()
+}
+
+/// Removes every device, most recently created first. Children are always removed before their
+/// parents.
+pub async fn remove_all() {
+    let roots: Vec<Arc<Device>> = DEVICES.read().values().rev().filter(|d| d.parent.is_none()).cloned().collect();
+    for dev in roots {
+        dev.remove().await;
+    }
+}
@@ -23,6 +23,28 @@ pub trait DriverProfile: Send {
     /// - The string should be in camelCase not PascalCase.
     /// - The string should not include version numbers. Incompatible versions should be checked inside [Self::try_start].
     fn bus_name(&self) -> &str;
+
+    /// Called before the system is suspended. The driver must stop the devices it has started from
+    /// performing DMA or raising interrupts and save any state required to resume them.
+    ///
+    /// Returning `Err(())` aborts the suspend, drivers which were already suspended are resumed.
+    /// The default implementation returns `Err(())`, so the system is only suspended when every
+    /// driver supports it.
+    fn suspend(&self) -> Result<(), ()> {
+        Err(())
+    }
+
+    /// Called after the system has resumed. Devices lose their state while the system is
+    /// suspended and must be reinitialized.
+    fn resume(&self) {}
+
+    /// Called when the system is shut down, after devices have been removed. The driver must stop
+    /// the devices it has started from performing DMA or raising interrupts, they will not be resumed.
+    ///
+    /// The default implementation calls [Self::suspend].
+    fn shutdown(&self) {
+        let _ = self.suspend();
+    }
 }
 
 /// This trait is for structs that store and locate system resources that should be made available to drivers.
@@ -123,6 +145,36 @@ impl DiscoveryDriver {
         arr
     }
 
+    /// Calls [DriverProfile::suspend] for all registered drivers.
+    ///
+    /// If a driver fails to suspend, the drivers which were already suspended are resumed and the
+    /// bus name of the failing driver is returned.
+    pub(crate) fn suspend_drivers(&self) -> Result<(), String> {
+        let l = self.inner.lock();
+        let drivers: alloc::vec::Vec<&dyn DriverProfile> = l.driver_registry.values().flatten().map(|d| &**d).collect();
+        for (i, drv) in drivers.iter().enumerate() {
+            if drv.suspend().is_err() {
+                drivers[..i].iter().rev().for_each(|d| d.resume());
+                return Err(drv.bus_name().into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls [DriverProfile::resume] for all registered drivers, in the reverse order they were
+    /// suspended.
+    pub(crate) fn resume_drivers(&self) {
+        let l = self.inner.lock();
+        l.driver_registry.values().flatten().rev().for_each(|d| d.resume());
+    }
+
+    /// Calls [DriverProfile::shutdown] for all registered drivers, in the reverse order they were
+    /// registered.
+    pub(crate) fn shutdown_drivers(&self) {
+        let l = self.inner.lock();
+        l.driver_registry.values().flatten().rev().for_each(|d| d.shutdown());
+    }
+
     pub fn resources(&self) -> String {
         use core::fmt::Write;
         let l = self.inner.lock();
@@ -0,0 +1,85 @@
+//! Hypervisor detection.
+//!
+//! The hypervisor is identified by the vendor signature in CPUID leaf `0x4000_0000`. KVM may also be
+//! present behind Hyper-V enlightenments, in which case [hypervisor] reports [Hypervisor::HyperV]
+//! and [kvm_features] still reports the KVM features.
+//!
+//! Under KVM the paravirtual clock is used as the system timekeeper (see [crate::time::kvmclock])
+//! and paravirtual EOI is enabled for each local APIC. Asynchronous page faults are not enabled,
+//! handling them requires rescheduling from within the page fault handler.
+
+lazy_static::lazy_static! {
+    static ref HYPERVISOR: Option<Hypervisor> = detect();
+}
+
+/// KVM paravirtual features, from the KVM feature leaf.
+pub mod kvm_feature {
+    /// `MSR_KVM_WALL_CLOCK` and `MSR_KVM_SYSTEM_TIME` are available.
+    pub const CLOCKSOURCE: u32 = 1 << 0;
+    pub const NOP_IO_DELAY: u32 = 1 << 1;
+    pub const MMU_OP: u32 = 1 << 2;
+    /// `MSR_KVM_WALL_CLOCK_NEW` and `MSR_KVM_SYSTEM_TIME_NEW` are available.
+    pub const CLOCKSOURCE2: u32 = 1 << 3;
+    pub const ASYNC_PF: u32 = 1 << 4;
+    pub const STEAL_TIME: u32 = 1 << 5;
+    pub const PV_EOI: u32 = 1 << 6;
+    pub const PV_UNHALT: u32 = 1 << 7;
+    /// The `PVCLOCK_TSC_STABLE_BIT` flag may be set in the pvclock structure.
+    pub const CLOCKSOURCE_STABLE: u32 = 1 << 24;
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Hypervisor {
+    Kvm,
+    HyperV,
+    VMware,
+    Xen,
+    /// QEMU without hardware acceleration.
+    Tcg,
+    /// Contains the vendor signature.
+    Unknown([u8; 12]),
+}
+
+fn detect() -> Option<Hypervisor> {
+    if raw_cpuid::cpuid!(1).ecx & (1 << 31) == 0 {
+        return None;
+    }
+    let leaf = raw_cpuid::cpuid!(0x4000_0000);
+    let mut sig = [0; 12];
+    sig[..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+    sig[4..8].copy_from_slice(&leaf.ecx.to_le_bytes());
+    sig[8..].copy_from_slice(&leaf.edx.to_le_bytes());
+
+    let hv = match &sig {
+        b"KVMKVMKVM\0\0\0" => Hypervisor::Kvm,
+        b"Microsoft Hv" => Hypervisor::HyperV,
+        b"VMwareVMware" => Hypervisor::VMware,
+        b"XenVMMXenVMM" => Hypervisor::Xen,
+        b"TCGTCGTCGTCG" => Hypervisor::Tcg,
+        _ => Hypervisor::Unknown(sig),
+    };
+    Some(hv)
+}
+
+/// Returns the hypervisor the system is running under, or `None` when running on hardware.
+pub fn hypervisor() -> Option<Hypervisor> {
+    *HYPERVISOR
+}
+
+/// Returns the KVM features which are available, see [kvm_feature].
+///
+/// Returns `0` when KVM is not present.
+pub fn kvm_features() -> u32 {
+    lazy_static::lazy_static! {
+        static ref FEATURES: u32 = x86_msr::kvm::kvm_features();
+    }
+    *FEATURES
+}
+
+/// Logs the detected hypervisor and its paravirtual features.
+pub fn log_hypervisor() {
+    match hypervisor() {
+        Some(hv) => log::info!("Running under hypervisor {hv:?}, KVM features {:#x}", kvm_features()),
+        None => log::debug!("No hypervisor detected"),
+    }
+}
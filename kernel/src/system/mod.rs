@@ -1,6 +1,13 @@
 //! This module is for gathering and exposing system hardware and system metadata
 
 pub mod acpi;
+pub mod cpufreq;
+pub mod device;
 pub mod driver_if;
+pub mod hypervisor;
 pub mod pci;
+pub mod shutdown;
+pub mod suspend;
 pub mod sysfs;
+pub mod thermal;
+pub mod watchdog;
@@ -162,8 +162,11 @@ impl DeviceControl {
         let header_region =
             unsafe { &mut *(&mut cfg_region[0] as *mut _ as *mut configuration::CommonHeader) };
 
-        if header_region.vendor() == u16::MAX {
-            return None;
+        // The host bridge may not decode the configuration space of an absent bus
+        // SAFETY: The vendor ID is the first register of the configuration space
+        match unsafe { crate::mem::probe::probe_read(cfg_region.as_ptr() as *const u16) } {
+            Ok(u16::MAX) | Err(_) => return None,
+            Ok(_) => {}
         }
         let header_type = header_region.header_type();
 
@@ -248,6 +251,15 @@ impl DeviceControl {
         self.bar[id as usize].as_ref()
     }
 
+    /// Enables or disables the function acting as a bus master. This must be enabled for the function to perform DMA.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the function will only access memory which has been allocated to it.
+    pub unsafe fn set_bus_master(&mut self, enable: bool) {
+        self.header.update_control(configuration::register::CommandRegister::BUS_MASTER, enable);
+    }
+
     pub fn capability(
         &mut self,
         id: capabilities::CapabilityId,
@@ -255,6 +267,17 @@ impl DeviceControl {
         self.capabilities.get_mut(&id)
     }
 
+    /// Returns the configuration space of each capability with the ID `id`, each slice begins at
+    /// the capability header.
+    ///
+    /// Unlike [Self::capability] this returns every instance of the capability, this is required
+    /// for capabilities which may be present multiple times, such as vendor specific capabilities.
+    pub fn capability_regions(&self, id: capabilities::CapabilityId) -> alloc::vec::Vec<&[u8]> {
+        // SAFETY: This is safe because header and cfg_region are for the same device
+        let iter = unsafe { capabilities::CapabilityIter::new(&*self.header, &*self.cfg_region) };
+        iter.filter(|cap| cap.id() == id).map(|cap| &self.cfg_region[cap.offset() as usize..]).collect()
+    }
+
     pub fn get_cap_structure_mut<'a>(
         &'a mut self,
         id: capabilities::CapabilityId,
@@ -378,6 +401,7 @@ impl DeviceControl {
         use capabilities::msi;
         assert!(override_count.unwrap_or(0) < 2048);
 
+        let self_address = self.address;
         let msi = {
             match capabilities::msi::MessageSignaledIntX::try_from(self) {
                 Ok(r) => r,
@@ -431,18 +455,27 @@ impl DeviceControl {
             }
         }
 
+        // Entries sharing a vector must target the same CPU
+        let targets: alloc::vec::Vec<u8> = reserved_irq.iter().map(|_| msi::get_next_msi_affinity()).collect();
         let mut ret = alloc::vec::Vec::with_capacity(vectors as usize);
         for (i, e) in msi.get_vec_table().iter_mut().enumerate() {
             let loc_msg = i % reserved_irq.len();
             let i_vec = reserved_irq[loc_msg];
             e.set_entry(
-                msi::InterruptAddress::new(msi::get_next_msi_affinity()),
+                msi::InterruptAddress::new(targets[loc_msg]),
                 msi::InterruptMessage::new(i_vec, msi::InterruptDeliveryMode::Fixed, false, false),
             );
             e.mask(false);
             ret.push(crate::task::InterruptMessage(loc_msg as u64));
         }
 
+        let table_size = msi.get_ctl().table_size();
+        let name = alloc::format!("{self_address} MSI-X");
+        for (n, irq) in reserved_irq.iter().enumerate() {
+            let entries = (0..table_size).filter(|i| *i as usize % reserved_irq.len() == n);
+            crate::interrupts::affinity::register(&[*irq], name.clone(), alloc::boxed::Box::new(msi.retarget(entries)), targets[n] as crate::mp::CpuIndex);
+        }
+
         if let Some(count) = override_count {
             let t_count = msi.get_ctl().table_size();
             if count < t_count {
@@ -493,6 +526,7 @@ impl DeviceControl {
         use capabilities::msi;
         assert!(override_count.unwrap_or(0) < 32);
 
+        let self_address = self.address;
         let mut msi = match msi::MessageSigInt::try_from(self) {
             Ok(r) => r,
             Err(_) => return (CfgIntResult::Failed, None),
@@ -537,10 +571,15 @@ impl DeviceControl {
             irqs.push(irq.into());
         }
 
+        let target = msi::get_next_msi_affinity();
         msi.set_interrupt(
-            msi::InterruptAddress::new(msi::get_next_msi_affinity()),
+            msi::InterruptAddress::new(target),
             msi::InterruptMessage::new(irq, msi::InterruptDeliveryMode::Fixed, false, false),
         );
+        let vectors: alloc::vec::Vec<u8> = (irq..irq + count).collect();
+        // SAFETY: The configuration space is mapped while the function exists, its vectors are freed before then.
+        let retarget = unsafe { msi::MsiRetarget::new(&mut msi) };
+        crate::interrupts::affinity::register(&vectors, alloc::format!("{self_address} MSI"), alloc::boxed::Box::new(retarget), target as crate::mp::CpuIndex);
 
         // Mask bits if available
         if let Some(oc) = override_count {
@@ -588,6 +627,36 @@ impl DeviceControl {
     pub fn check_self_test(&self) -> Option<Result<u8, u8>> {
         self.header.check_test()
     }
+
+    /// Reads a device specific register from the function's configuration space.
+    ///
+    /// # Panics
+    ///
+    /// This fn will panic if `offset` is within the common header or is not aligned to `T`.
+    pub fn read_cfg<T: Copy>(&self, offset: u16) -> T {
+        let offset = offset as usize;
+        assert!(offset >= 0x40 && offset + size_of::<T>() <= self.cfg_region.len());
+        assert_eq!(offset % align_of::<T>(), 0);
+        // SAFETY: The offset is within the configuration region and is aligned
+        unsafe { core::ptr::read_volatile(self.cfg_region.as_ptr().add(offset) as *const T) }
+    }
+
+    /// Writes a device specific register in the function's configuration space.
+    ///
+    /// # Panics
+    ///
+    /// See [Self::read_cfg]
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that writing `value` to the register does not cause the function to
+    /// access memory which it does not own.
+    pub unsafe fn write_cfg<T: Copy>(&mut self, offset: u16, value: T) {
+        let offset = offset as usize;
+        assert!(offset >= 0x40 && offset + size_of::<T>() <= self.cfg_region.len());
+        assert_eq!(offset % align_of::<T>(), 0);
+        core::ptr::write_volatile(self.cfg_region.as_mut_ptr().add(offset) as *mut T, value)
+    }
 }
 
 // Because of how BARS need to be used they are interacted with using DeviceControl not their own
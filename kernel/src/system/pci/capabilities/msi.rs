@@ -4,12 +4,63 @@ use crate::system::pci::DeviceControl;
 use core::alloc::Allocator;
 use core::any::Any;
 
+/// Returns the CPU which the next MSI should target, see [crate::interrupts::affinity::initial_cpu].
 pub(crate) fn get_next_msi_affinity() -> u8 {
-    static MSI_NEXT_AFFINITY: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
+    // MSI can only use 8 bits of cpu address on x86
+    crate::interrupts::affinity::initial_cpu().try_into().unwrap_or(0)
+}
+
+/// Moves an MSI to another CPU. All vectors of the function are moved together.
+pub(crate) struct MsiRetarget {
+    /// Virtual address of the message address register, this is within the function's configuration space.
+    addr_low: *mut u32,
+}
+
+impl MsiRetarget {
+    /// # Safety
+    ///
+    /// The configuration space of the function must remain mapped while `self` is registered.
+    pub(crate) unsafe fn new(msi: &mut MessageSigInt) -> Self {
+        Self { addr_low: &mut *msi.addr_low as *mut u32 }
+    }
+}
+
+// SAFETY: The register is only written by retarget
+unsafe impl Send for MsiRetarget {}
+unsafe impl Sync for MsiRetarget {}
+
+impl crate::interrupts::affinity::Retarget for MsiRetarget {
+    fn retarget(&self, cpu: crate::mp::CpuIndex) -> Result<(), ()> {
+        let (_, low) = InterruptAddress::new(cpu.try_into().map_err(|_| ())?).half();
+        // SAFETY: See Self::new, the upper half is always 0 for x86 targets.
+        unsafe { core::ptr::write_volatile(self.addr_low, low) };
+        Ok(())
+    }
+}
+
+/// Moves MSI-X table entries to another CPU.
+pub(crate) struct MsiXRetarget {
+    /// Physical addresses of the table entries
+    entries: alloc::vec::Vec<u64>,
+}
 
-    let count = MSI_NEXT_AFFINITY.fetch_add(1, atomic::Ordering::Relaxed);
-    let num_cpus = 1; // fixme actually get num of cpus
-    (count % (num_cpus.min(u8::MAX) as u64)) as u8 // mod of num cpus or u8::MAX whichever is lower (MSI can only use 8 bits of cpu address on x86)
+impl crate::interrupts::affinity::Retarget for MsiXRetarget {
+    fn retarget(&self, cpu: crate::mp::CpuIndex) -> Result<(), ()> {
+        let cpu: u8 = cpu.try_into().map_err(|_| ())?;
+        for addr in &self.entries {
+            // SAFETY: The address was read from the function's BAR by get_vec_table
+            let alloc = unsafe { crate::alloc_interface::MmioAlloc::new(*addr as usize) };
+            let ptr = alloc.allocate(core::alloc::Layout::new::<MsiXVectorEntry>()).map_err(|_| ())?.cast::<MsiXVectorEntry>();
+            // SAFETY: The entry is mapped and is unmapped when the box is dropped
+            let mut entry = unsafe { alloc::boxed::Box::from_raw_in(ptr.as_ptr(), alloc) };
+            // The entry is masked while it is modified so the address and message are never torn
+            entry.mask(true);
+            // SAFETY: Writes the address without modifying the message
+            unsafe { core::ptr::write_volatile(&mut entry.address, InterruptAddress::new(cpu)) };
+            entry.mask(false);
+        }
+        Ok(())
+    }
 }
 
 pub struct MessageSigInt<'a> {
@@ -389,6 +440,17 @@ impl<'a> MessageSignaledIntX<'a> {
         }
     }
 
+    /// Returns a [MsiXRetarget] which moves the table entries `entries`.
+    pub(crate) fn retarget(&self, entries: impl Iterator<Item = u16>) -> MsiXRetarget {
+        let t = self.parent.bar[self.table_bar as usize]
+            .as_ref()
+            .expect("PCI device did not implement BAR specified for MSI-X table");
+        let base = t.addr + self.table_offset as u64;
+        MsiXRetarget {
+            entries: entries.map(|i| base + (i as usize * core::mem::size_of::<MsiXVectorEntry>()) as u64).collect(),
+        }
+    }
+
     /// Returns a reference to the Pending Bit Array
     ///
     /// # Safety
@@ -254,20 +254,15 @@ impl BaseAddressRegister {
     /// Gets the alignment of the register. The alignemtn of the register can also be used ta the
     /// size of the  register.
     /// The returned value can be assumed to be a power of two.
+    /// Returns `0` if the register faulted while it was accessed.
     ///
     /// # Safety
     ///
     /// This fn requires writing to the BAR so it should not be called while the device is in use.
     pub unsafe fn alignment(&mut self) -> u32 {
-        let cache = self.read();
-        self.write(u32::MAX);
+        let Ok(align) = (unsafe { size_bar(&mut self.data, u32::MAX) }) else { return 0 };
 
-        let align = self.read();
-
-        let bt = self.bar_type();
-        self.write(cache);
-
-        if let BarType::DwordIO = bt {
+        if let BarType::DwordIO = Self::get_type(align) {
             (!(align & !3)).wrapping_add(1)
         } else {
             (!(align & !0xf)).wrapping_add(1)
@@ -300,16 +295,32 @@ impl BarRegisterLong {
     /// Gets the alignment of the register. The alignemtn of the register can also be used ta the
     /// size of the  register.
     /// The returned value can be assumed to be a power of two.
+    /// Returns `0` if the register faulted while it was accessed.
     ///
     /// # Safety
     ///
     /// This fn requires writing to the BAR so it should not be called while the device is in use.
     pub unsafe fn alignment(&mut self) -> u64 {
-        let cache = self.read();
-        self.write(u64::MAX);
+        let Ok(align) = (unsafe { size_bar(&mut self.inner, u64::MAX) }) else { return 0 };
+        (!(align & (!0xf))).wrapping_add(1)
+    }
+}
 
-        let align = (!(self.read() & (!0xf))) + 1;
-        self.write(cache);
-        align
+/// Writes `ones` to the BAR at `reg` and returns the value read back, the original value is restored.
+///
+/// The configuration space of a function may not be decoded, so the register is accessed using probes.
+///
+/// # Safety
+///
+/// `reg` must point to a BAR in a mapped configuration space.
+unsafe fn size_bar<T: crate::mem::probe::Probe>(reg: *mut T, ones: T) -> Result<T, crate::mem::probe::ProbeError> {
+    use crate::mem::probe::{probe_read, probe_write};
+    // SAFETY: Accessing the BAR only affects the device
+    unsafe {
+        let cache = probe_read(reg)?;
+        probe_write(reg, ones)?;
+        let size = probe_read(reg);
+        probe_write(reg, cache)?;
+        size
     }
 }
@@ -0,0 +1,245 @@
+//! Orderly shutdown.
+//!
+//! [shutdown] stops the system in a fixed order so that data written before it was called reaches
+//! the disk.
+//! 1. New [Inhibitor]s are refused and the existing ones are waited for. Operations which modify a
+//!    filesystem through a [BufferCache] hold an inhibitor, so they complete and sync their caches
+//!    and journals before I/O is stopped.
+//! 2. New block device requests fail with [IoError::NotReady] and requests in progress are waited
+//!    for. The block device page cache is write-through so no dirty pages remain once this completes,
+//!    block devices are then flushed using [crate::fs::writeback::sync].
+//! 3. Devices are removed, which runs their teardown callbacks, see [super::device::remove_all].
+//! 4. Drivers are quiesced using [DriverProfile::shutdown].
+//! 5. The system is powered off, reset, or QEMU is exited.
+//!
+//! Steps 1 and 2 give up after [TIMEOUT_MS] and the shutdown continues, what was still pending is
+//! logged. The `/bin/shutdown` program powers off the system, `shutdown -r` reboots it.
+//!
+//! [BufferCache]: crate::fs::disk_util::buffer_cache::BufferCache
+//! [IoError::NotReady]: crate::fs::IoError::NotReady
+//! [DriverProfile::shutdown]: super::driver_if::DriverProfile::shutdown
+
+use crate::config::Tunable;
+use crate::task::spawn::Args;
+use crate::task::wait::WaitPoint;
+use crate::task::TaskResult;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::task::{Poll, Waker};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Maximum time to wait for inhibitors and for block requests in progress.
+pub static TIMEOUT_MS: Tunable<u64> = Tunable::new("shutdown.timeout_ms", "Milliseconds to wait for operations in progress during shutdown", 5000);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Stage {
+    Running,
+    /// New inhibitors are refused.
+    Draining,
+    /// New block device requests are refused.
+    Stopped,
+}
+
+/// What the system does once it has been shut down.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Action {
+    PowerOff,
+    Reboot,
+    /// Exits QEMU with the exit code, the system is halted when not running in QEMU.
+    ExitQemu(crate::QemuExitCode),
+}
+
+static STAGE: atomic::Atomic<Stage> = atomic::Atomic::new(Stage::Running);
+/// Live inhibitors by ID.
+static INHIBITORS: spin::Mutex<Vec<(u64, &'static str)>> = spin::Mutex::new(Vec::new());
+/// Block device requests in progress.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+/// Tasks waiting for inhibitors or requests to complete.
+static WAITERS: spin::Mutex<Vec<Waker>> = spin::Mutex::new(Vec::new());
+
+pub fn stage() -> Stage {
+    STAGE.load(Ordering::SeqCst)
+}
+
+fn wake() {
+    let waiters = without_interrupts(|| core::mem::take(&mut *WAITERS.lock()));
+    waiters.into_iter().for_each(Waker::wake);
+}
+
+/// Delays the shutdown until the returned guard is dropped. `what` is logged when the shutdown
+/// times out waiting for it.
+///
+/// Returns `None` once shutdown has begun, the caller should not begin the operation.
+pub fn inhibit(what: &'static str) -> Option<Inhibitor> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let mut l = INHIBITORS.lock();
+    // Checked while locked so that each inhibitor is either waited for or refused
+    if stage() != Stage::Running {
+        return None;
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    l.push((id, what));
+    Some(Inhibitor { id })
+}
+
+/// Delays the shutdown, see [inhibit].
+pub struct Inhibitor {
+    id: u64,
+}
+
+impl Drop for Inhibitor {
+    fn drop(&mut self) {
+        let empty = {
+            let mut l = INHIBITORS.lock();
+            l.retain(|(id, _)| *id != self.id);
+            l.is_empty()
+        };
+        if empty && stage() != Stage::Running {
+            wake();
+        }
+    }
+}
+
+/// Begins a block device request, returns `None` once I/O has been stopped.
+pub fn begin_io() -> Option<IoGuard> {
+    IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+    // Ordered after the increment so the shutdown either sees the request or the request sees the shutdown
+    if stage() == Stage::Stopped {
+        drop(IoGuard { _private: () });
+        return None;
+    }
+    Some(IoGuard { _private: () })
+}
+
+/// A block device request in progress, see [begin_io].
+pub struct IoGuard {
+    _private: (),
+}
+
+impl Drop for IoGuard {
+    fn drop(&mut self) {
+        if IN_FLIGHT.fetch_sub(1, Ordering::SeqCst) == 1 && stage() == Stage::Stopped {
+            wake();
+        }
+    }
+}
+
+/// Waits until `done` returns true or [TIMEOUT_MS] expires, returns whether `done` returned true.
+async fn drain(done: impl Fn() -> bool) -> bool {
+    let wait = core::future::poll_fn(|cx| {
+        if done() {
+            return Poll::Ready(());
+        }
+        without_interrupts(|| WAITERS.lock().push(cx.waker().clone()));
+        if done() {
+            Poll::Ready(())
+        } else {
+            crate::task::wait::note(WaitPoint::device("shutdown"));
+            Poll::Pending
+        }
+    });
+    let timeout = crate::task::timer_wheel::Timeout::new(crate::time::Duration::millis(TIMEOUT_MS.get()));
+    match futures_util::future::select(core::pin::pin!(wait), timeout).await {
+        futures_util::future::Either::Left(_) => true,
+        futures_util::future::Either::Right(_) => false,
+    }
+}
+
+/// Shuts down the system, see the module documentation for the order. This never completes.
+///
+/// Only the first call shuts down the system, later calls wait forever.
+pub async fn shutdown(action: Action) {
+    // Taking the lock orders this against `inhibit`
+    let started = {
+        let _l = INHIBITORS.lock();
+        STAGE.compare_exchange(Stage::Running, Stage::Draining, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    };
+    if !started {
+        return core::future::pending().await;
+    }
+    log::info!("Shutting down: {action:?}");
+
+    if !drain(|| INHIBITORS.lock().is_empty()).await {
+        for (_, what) in INHIBITORS.lock().iter() {
+            log::warn!("Shutdown: Timed out waiting for {what}");
+        }
+    }
+
+    STAGE.store(Stage::Stopped, Ordering::SeqCst);
+    if !drain(|| IN_FLIGHT.load(Ordering::SeqCst) == 0).await {
+        log::warn!("Shutdown: Timed out waiting for {} block requests", IN_FLIGHT.load(Ordering::SeqCst));
+    }
+    // Failures are logged by sync
+    let _ = crate::fs::writeback::sync().await;
+
+    super::device::remove_all().await;
+    super::sysfs::get_sysfs().get_discovery().shutdown_drivers();
+    super::watchdog::suspend();
+    log::info!("Shutdown complete");
+
+    match action {
+        Action::PowerOff => {
+            let e = super::suspend::power_off_quiesced();
+            log::error!("Failed to power off: {e:?}, halting");
+        }
+        Action::Reboot => reset(),
+        Action::ExitQemu(code) => {
+            crate::exit_qemu(code);
+            log::error!("Not running in QEMU, halting");
+        }
+    }
+    x86_64::instructions::interrupts::disable();
+    crate::stop()
+}
+
+/// Resets the system using the keyboard controller, falling back to a triple fault.
+fn reset() -> ! {
+    x86_64::instructions::interrupts::disable();
+    // SAFETY: The system is being reset
+    unsafe {
+        let mut status = x86_64::instructions::port::PortReadOnly::<u8>::new(0x64);
+        let mut cmd = x86_64::instructions::port::PortWriteOnly::<u8>::new(0x64);
+        // Waits for the input buffer to be empty
+        for _ in 0..0x10000 {
+            if status.read() & 2 == 0 {
+                break;
+            }
+        }
+        // Pulses the reset line
+        cmd.write(0xfe);
+    }
+    for _ in 0..0x100_0000 {
+        core::hint::spin_loop();
+    }
+
+    log::warn!("Keyboard controller reset failed, triple faulting");
+    let idt = x86_64::structures::DescriptorTablePointer { limit: 0, base: x86_64::VirtAddr::zero() };
+    // SAFETY: An exception with an empty IDT causes a triple fault, which resets the system
+    unsafe {
+        x86_64::instructions::tables::lidt(&idt);
+        core::arch::asm!("int3", options(noreturn));
+    }
+}
+
+fn program(args: Args) -> Pin<Box<dyn Future<Output = TaskResult> + Send>> {
+    Box::pin(async move {
+        let action = match args.argv.get(1).map(alloc::string::String::as_str) {
+            None => Action::PowerOff,
+            Some("-r") => Action::Reboot,
+            Some(_) => {
+                crate::serial_println!("Usage: shutdown [-r]");
+                return TaskResult::Error;
+            }
+        };
+        shutdown(action).await;
+        TaskResult::ExitedNormally
+    })
+}
+
+/// Registers the `/bin/shutdown` program.
+pub fn init() {
+    crate::task::spawn::register("/bin/shutdown", program).expect("Failed to register shutdown");
+}
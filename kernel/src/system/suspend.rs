@@ -0,0 +1,439 @@
+//! ACPI S3 (suspend-to-RAM).
+//!
+//! [suspend] quiesces drivers using [super::driver_if::DriverProfile::suspend], saves the processor
+//! state and enters S3 by writing `SLP_TYP` to the PM1 control registers. The firmware resumes the
+//! system by entering the waking vector in real mode, the vector is set to a copy of
+//! `x86_wakeup.asm` in low memory which restores the processor state and returns into [suspend].
+//!
+//! There is no AML interpreter, so `\_PTS` and `\_WAK` are not evaluated and `\_S3` is only located
+//! when it is declared as a package of constants, which is how most firmware declares it.
+//! Only a single CPU may be running, application processors cannot be taken offline and
+//! restarted.
+//!
+//! Drivers must opt in to suspending by implementing [super::driver_if::DriverProfile::suspend],
+//! the system is not suspended while any registered driver does not support it.
+//!
+//! [power_off] enters S5 (soft-off) using the same registers. An orderly shutdown should use
+//! [super::shutdown] instead, which flushes data to the disk first.
+//!
+//! The `/bin/suspend` program syncs the block devices and suspends the system.
+
+use crate::system::acpi::data_access::DataAccessType;
+use crate::task::spawn::Args;
+use crate::task::TaskResult;
+use acpi::address::{AccessSize, GenericAddress};
+use acpi::AcpiHandler;
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::future::Future;
+use core::pin::Pin;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+
+/// Offset of the long mode code within the waking vector page.
+const LONG_MODE_OFFSET: u64 = 0x100;
+/// Offset of [WakeData] within the waking vector page.
+const DATA_OFFSET: usize = 0x800;
+
+/// PM1 status `WAK_STS`
+const WAK_STS: u64 = 1 << 15;
+const SLP_TYP_SHIFT: u64 = 10;
+const SLP_TYP_MASK: u64 = 7 << SLP_TYP_SHIFT;
+const SLP_EN: u64 = 1 << 13;
+/// PM1 control `SCI_EN`, the system is not in ACPI mode when this is clear.
+const SCI_EN: u64 = 1;
+
+/// Offset of the 32-bit waking vector within the FACS.
+const FACS_WAKING_VECTOR: usize = 12;
+/// Offset of the 64-bit waking vector within the FACS.
+const FACS_X_WAKING_VECTOR: usize = 24;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SuspendError {
+    /// The firmware does not support S3, or it could not be located.
+    Unsupported,
+    /// More than one CPU is running.
+    MultipleCpus,
+    /// The driver for the contained bus failed to suspend.
+    DriverFailed(String),
+    /// Memory for the waking vector could not be allocated.
+    OutOfMemory,
+    /// The system did not enter S3.
+    Failed,
+}
+
+extern "C" {
+    fn _wakeup();
+    fn _wakeup_resume();
+    fn _suspend_save(rsp: *mut u64, enter: extern "C" fn(usize), arg: usize) -> u64;
+}
+
+/// Processor state restored by the waking vector, the layout must match `x86_wakeup.asm`.
+#[repr(C)]
+#[derive(Default)]
+struct WakeData {
+    /// Temporary GDT pointer, `limit, base[0..16], base[16..32]`
+    gdt_ptr: [u16; 4],
+    /// Temporary GDT containing a 64-bit code segment.
+    gdt: [u64; 2],
+    /// Far pointer to the long mode code, `offset, selector`
+    entry64: [u32; 2],
+    boot_cr3: u64,
+    pat: u64,
+    efer: u64,
+    cr4: u64,
+    cr0: u64,
+    cr3: u64,
+    rsp: u64,
+    fs_base: u64,
+    gs_base: u64,
+    kernel_gs_base: u64,
+    /// Kernel GDT pointer, `limit, base[0..64]`
+    gdtr: [u16; 8],
+    /// Kernel IDT pointer, `limit, base[0..64]`
+    idtr: [u16; 8],
+    cs: u64,
+    ds: u64,
+    resume: u64,
+}
+
+const _: () = assert!(core::mem::offset_of!(WakeData, resume) == 160);
+
+fn table_ptr(ptr: x86_64::structures::DescriptorTablePointer) -> [u16; 8] {
+    let base = ptr.base.as_u64();
+    [ptr.limit, base as u16, (base >> 16) as u16, (base >> 32) as u16, (base >> 48) as u16, 0, 0, 0]
+}
+
+/// A copy of the waking vector in low memory, which is identity mapped while it exists.
+struct WakePage {
+    page: Box<[u8; 4096], crate::alloc_interface::DmaAlloc>,
+    /// Copy of the top level page table below 4GiB, used when the kernel's table is above 4GiB.
+    _l4: Option<Box<x86_64::structures::paging::PageTable, crate::alloc_interface::DmaAlloc>>,
+    phys: u64,
+}
+
+impl WakePage {
+    fn new() -> Option<Self> {
+        let tramp = _wakeup as *const fn() as *const [u8; 4096];
+        // SAFETY: The waking vector is 4096 bytes long
+        let page = Box::try_new_in(unsafe { *tramp }, crate::alloc_interface::DmaAlloc::new(crate::mem::MemRegion::Mem16, 4096)).ok()?;
+        let phys = crate::mem::mem_map::translate_ptr(&*page)?;
+
+        let frame = PhysFrame::<Size4KiB>::from_start_address(x86_64::PhysAddr::new(phys)).unwrap();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        // SAFETY: The frame is owned by `page`, the mapping is removed when self is dropped.
+        unsafe { crate::mem::SYS_MAPPER.get().identity_map(frame, flags, &mut crate::mem::DummyFrameAlloc) }.ok()?.flush();
+
+        let (l4, _) = x86_64::registers::control::Cr3::read();
+        let (boot_cr3, l4) = if l4.start_address().as_u64() > u32::MAX as u64 {
+            let table = Box::try_new_in(crate::mem::SYS_MAPPER.get().get_l4_table().clone(), crate::alloc_interface::DmaAlloc::new(crate::mem::MemRegion::Mem32, 4096)).ok()?;
+            (crate::mem::mem_map::translate_ptr(&*table)?, Some(table))
+        } else {
+            (l4.start_address().as_u64(), None)
+        };
+
+        let mut this = Self { page, _l4: l4, phys };
+        let gdt = phys + DATA_OFFSET as u64 + core::mem::offset_of!(WakeData, gdt) as u64;
+        *this.data() = WakeData {
+            gdt_ptr: [15, gdt as u16, (gdt >> 16) as u16, 0],
+            gdt: [0, 0x00af_9a00_0000_ffff],
+            entry64: [(phys + LONG_MODE_OFFSET) as u32, 8],
+            boot_cr3,
+            ..Default::default()
+        };
+        Some(this)
+    }
+
+    fn data(&mut self) -> &mut WakeData {
+        // SAFETY: The data area is within the page and aligned
+        unsafe { &mut *(self.page.as_mut_ptr().add(DATA_OFFSET) as *mut WakeData) }
+    }
+}
+
+impl Drop for WakePage {
+    fn drop(&mut self) {
+        let page = Page::<Size4KiB>::containing_address(x86_64::VirtAddr::new(self.phys));
+        if let Ok((_, flush)) = crate::mem::SYS_MAPPER.get().unmap(page) {
+            flush.flush();
+        }
+    }
+}
+
+/// The registers used to enter a sleep state.
+struct SleepRegs {
+    pm1a_sts: DataAccessType,
+    pm1b_sts: Option<DataAccessType>,
+    pm1a_cnt: DataAccessType,
+    pm1b_cnt: Option<DataAccessType>,
+    slp_typ: (u8, u8),
+    facs: usize,
+}
+
+impl SleepRegs {
+    /// Locates the registers used to enter the sleep state named `state`, e.g. `_S3_`.
+    fn locate(state: &[u8; 4]) -> Result<Self, SuspendError> {
+        let tables = super::sysfs::get_sysfs().firmware().get_acpi();
+        let fadt = tables.find_table::<acpi::fadt::Fadt>().map_err(|_| SuspendError::Unsupported)?;
+        let slp_typ = core::iter::once(tables.dsdt().map_err(|_| SuspendError::Unsupported)?)
+            .chain(tables.ssdts())
+            .find_map(|t| {
+                // SAFETY: The table is given by the firmware
+                let map = unsafe { super::acpi::AcpiGrabber.map_physical_region::<u8>(t.address, t.length as usize) };
+                // SAFETY: The mapping is `length` bytes long
+                let aml = unsafe { core::slice::from_raw_parts(map.virtual_start().as_ptr(), t.length as usize) };
+                find_sleep_type(aml, state)
+            })
+            .ok_or(SuspendError::Unsupported)?;
+
+        // The status registers are the first half of the event blocks
+        let status = |gas: GenericAddress| {
+            DataAccessType::from(GenericAddress {
+                bit_width: 16,
+                access_size: AccessSize::WordAccess,
+                ..gas
+            })
+        };
+        let control = |gas: GenericAddress| {
+            let mut access = DataAccessType::from(gas);
+            if !access.is_size_defined() {
+                // SAFETY: PM1 control registers are 16 bits wide
+                unsafe { access.define_size(crate::system::acpi::data_access::DataSize::Word) }
+            }
+            access
+        };
+        let err = |_| SuspendError::Unsupported;
+        Ok(Self {
+            pm1a_sts: status(fadt.pm1a_event_block().map_err(err)?),
+            pm1b_sts: fadt.pm1b_event_block().map_err(err)?.map(status),
+            pm1a_cnt: control(fadt.pm1a_control_block().map_err(err)?),
+            pm1b_cnt: fadt.pm1b_control_block().map_err(err)?.map(control),
+            slp_typ,
+            facs: fadt.facs_address().map_err(err)?,
+        })
+    }
+
+    /// Sets the waking vector in the FACS, `0` clears it.
+    fn set_waking_vector(&self, vector: u32) {
+        // SAFETY: The FACS is given by the firmware
+        let map = unsafe { super::acpi::AcpiGrabber.map_physical_region::<u8>(self.facs, FACS_X_WAKING_VECTOR + 8) };
+        // SAFETY: The mapping contains both waking vectors
+        unsafe {
+            let ptr = map.virtual_start().as_ptr();
+            (ptr.add(FACS_WAKING_VECTOR) as *mut u32).write_volatile(vector);
+            (ptr.add(FACS_X_WAKING_VECTOR) as *mut u64).write_volatile(0);
+        }
+    }
+}
+
+/// Locates `name` declared as a package and returns its first two elements, these are
+/// `SLP_TYPa` and `SLP_TYPb` for sleep states.
+fn find_sleep_type(aml: &[u8], name: &[u8; 4]) -> Option<(u8, u8)> {
+    /// Parses an integer constant, returns the value and the remaining bytes.
+    fn integer(aml: &[u8]) -> Option<(u8, &[u8])> {
+        match *aml.first()? {
+            0x00 => Some((0, &aml[1..])),              // ZeroOp
+            0x01 => Some((1, &aml[1..])),              // OneOp
+            0x0a => Some((*aml.get(1)?, &aml[2..])), // BytePrefix
+            _ => None,
+        }
+    }
+
+    let mut start = 0;
+    while let Some(pos) = aml[start..].windows(4).position(|w| w == name) {
+        let at = start + pos;
+        start = at + 1;
+        // NameOp, optionally followed by the root prefix
+        let named = match at {
+            0 => false,
+            1 => aml[0] == 0x08,
+            _ => aml[at - 1] == 0x08 || (aml[at - 1] == b'\\' && aml[at - 2] == 0x08),
+        };
+        // PackageOp PkgLength NumElements
+        if !named || aml.get(at + 4) != Some(&0x12) {
+            continue;
+        }
+        let Some(&lead) = aml.get(at + 5) else { continue };
+        let Some(elements) = aml.get(at + 7 + (lead >> 6) as usize..) else { continue };
+        let Some((a, rest)) = integer(elements) else { continue };
+        let Some((b, _)) = integer(rest) else { continue };
+        return Some((a, b));
+    }
+    None
+}
+
+/// Writes the sleep type to the PM1 control registers. This only returns if the system failed to
+/// enter the sleep state.
+extern "C" fn enter_sleep(regs: usize) {
+    // SAFETY: `regs` is given by `enter`
+    let regs = unsafe { &mut *(regs as *mut SleepRegs) };
+    // SAFETY: Memory must be written back before it loses power.
+    unsafe { core::arch::asm!("wbinvd", options(nostack, preserves_flags)) };
+
+    regs.pm1a_sts.write(WAK_STS);
+    if let Some(sts) = regs.pm1b_sts.as_mut() {
+        sts.write(WAK_STS);
+    }
+
+    let cnt = |reg: &mut DataAccessType, typ: u8| {
+        let v: u64 = reg.read().try_into().unwrap();
+        let v = (v & !(SLP_TYP_MASK | SLP_EN)) | ((typ as u64) << SLP_TYP_SHIFT);
+        reg.write(v);
+        reg.write(v | SLP_EN);
+    };
+    // PM1b is written first because writing PM1a may put the system to sleep
+    if let Some(reg) = regs.pm1b_cnt.as_mut() {
+        cnt(reg, regs.slp_typ.1);
+    }
+    cnt(&mut regs.pm1a_cnt, regs.slp_typ.0);
+
+    // Some chipsets take some time before the system is suspended
+    for _ in 0..0x100_0000 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Saves the processor state into `wake` and enters S3. Returns once the system has resumed.
+///
+/// # Safety
+///
+/// Interrupts must be disabled and no other CPUs may be running.
+unsafe fn enter(regs: &mut SleepRegs, wake: &mut WakePage) -> Result<(), SuspendError> {
+    use x86_64::registers::{control, model_specific, segmentation::Segment};
+    use crate::interrupts::apic::Apic;
+    use x86_msr::{Msr, MsrFlags};
+
+    let xsave = control::Cr4::read().contains(control::Cr4Flags::OSXSAVE);
+    let xcr0 = xsave.then(|| x86_64::registers::xcontrol::XCr0::read_raw());
+    let tr: u16;
+    core::arch::asm!("str {:x}", out(reg) tr, options(nomem, nostack, preserves_flags));
+
+    let data = wake.data();
+    let (h, l) = x86_msr::architecture::Pat::read().bits();
+    data.pat = (h as u64) << 32 | l as u64;
+    data.efer = model_specific::Efer::read_raw();
+    data.cr4 = control::Cr4::read_raw();
+    data.cr0 = control::Cr0::read_raw();
+    let (l4, pcid) = control::Cr3::read_raw();
+    data.cr3 = l4.start_address().as_u64() | pcid as u64;
+    data.fs_base = model_specific::FsBase::read().as_u64();
+    data.gs_base = model_specific::GsBase::read().as_u64();
+    data.kernel_gs_base = model_specific::KernelGsBase::read().as_u64();
+    data.gdtr = table_ptr(x86_64::instructions::tables::sgdt());
+    data.idtr = table_ptr(x86_64::instructions::tables::sidt());
+    data.cs = x86_64::registers::segmentation::CS::get_reg().0 as u64;
+    data.ds = x86_64::registers::segmentation::DS::get_reg().0 as u64;
+    data.resume = _wakeup_resume as usize as u64;
+    let rsp = &mut data.rsp as *mut u64;
+
+    let ioapic = super::sysfs::get_sysfs().systemctl.ioapic.save_entries();
+    regs.set_waking_vector(wake.phys as u32);
+
+    if _suspend_save(rsp, enter_sleep, regs as *mut SleepRegs as usize) == 0 {
+        regs.set_waking_vector(0);
+        return Err(SuspendError::Failed);
+    }
+
+    // Resumed, `x86_wakeup.asm` has restored the control registers, segments and descriptor tables.
+    if let Some(xcr0) = xcr0 {
+        x86_64::registers::xcontrol::XCr0::write_raw(xcr0);
+    }
+    // The TSS is marked busy in the GDT, it must be cleared before it can be loaded again
+    let gdt = x86_64::instructions::tables::sgdt().base.as_mut_ptr::<u64>();
+    *gdt.add(tr as usize >> 3) &= !(1 << 41);
+    x86_64::instructions::tables::load_tss(x86_64::structures::gdt::SegmentSelector(tr));
+
+    crate::interrupts::PICS.lock().disable();
+    crate::interrupts::apic::get_apic().set_enable(true);
+    crate::interrupts::apic::enable_pv_eoi();
+    let _ = crate::interrupts::apic::try_start_timer_residual();
+    super::sysfs::get_sysfs().systemctl.ioapic.restore_entries(&ioapic);
+    crate::time::resume_timer();
+    super::cpufreq::resume();
+    regs.set_waking_vector(0);
+    Ok(())
+}
+
+/// Suspends the system to RAM, completes once the system has resumed.
+pub async fn suspend() -> Result<(), SuspendError> {
+    if crate::mp::num_cpus() > 1 {
+        return Err(SuspendError::MultipleCpus);
+    }
+    let mut regs = SleepRegs::locate(b"_S3_")?;
+    let mut wake = WakePage::new().ok_or(SuspendError::OutOfMemory)?;
+
+    let discovery = super::sysfs::get_sysfs().get_discovery();
+    discovery.suspend_drivers().map_err(SuspendError::DriverFailed)?;
+    super::watchdog::suspend();
+    log::info!("Entering S3");
+
+    // SAFETY: Interrupts are disabled and only this CPU is running.
+    let r = x86_64::instructions::interrupts::without_interrupts(|| unsafe { enter(&mut regs, &mut wake) });
+    match &r {
+        Ok(()) => log::info!("Resumed from S3"),
+        Err(e) => log::error!("Failed to suspend: {e:?}"),
+    }
+    super::watchdog::resume();
+    discovery.resume_drivers();
+    r
+}
+
+/// Quiesces drivers and powers off the system by entering S5. Drivers which fail to suspend are
+/// ignored, the system is powered off regardless.
+///
+/// This only returns if the system could not be powered off, in which case drivers remain suspended.
+pub fn power_off() -> SuspendError {
+    let regs = match SleepRegs::locate(b"_S5_") {
+        Ok(regs) => regs,
+        Err(e) => return e,
+    };
+    if let Err(bus) = super::sysfs::get_sysfs().get_discovery().suspend_drivers() {
+        log::warn!("Driver for {bus} failed to suspend, powering off anyway");
+    }
+    enter_s5(regs)
+}
+
+/// Powers off the system without quiescing drivers, used by [super::shutdown] which has already
+/// shut them down.
+pub(crate) fn power_off_quiesced() -> SuspendError {
+    match SleepRegs::locate(b"_S5_") {
+        Ok(regs) => enter_s5(regs),
+        Err(e) => e,
+    }
+}
+
+fn enter_s5(mut regs: SleepRegs) -> SuspendError {
+    super::watchdog::suspend();
+    log::info!("Entering S5");
+    x86_64::instructions::interrupts::disable();
+    enter_sleep(&mut regs as *mut SleepRegs as usize);
+    SuspendError::Failed
+}
+
+fn program(args: Args) -> Pin<Box<dyn Future<Output = TaskResult> + Send>> {
+    Box::pin(async move {
+        if args.argv.len() > 1 {
+            crate::serial_println!("Usage: suspend");
+            return TaskResult::Error;
+        }
+        // Data in volatile caches would be lost if the system does not resume
+        if crate::fs::writeback::sync().await.is_err() {
+            crate::serial_println!("suspend: Failed to sync block devices");
+            return TaskResult::Error;
+        }
+        match suspend().await {
+            Ok(()) => TaskResult::ExitedNormally,
+            Err(SuspendError::DriverFailed(bus)) => {
+                crate::serial_println!("suspend: A driver for {bus} does not support suspending");
+                TaskResult::Error
+            }
+            Err(e) => {
+                crate::serial_println!("suspend: {e:?}");
+                TaskResult::Error
+            }
+        }
+    })
+}
+
+/// Registers the `/bin/suspend` program.
+pub fn init() {
+    crate::task::spawn::register("/bin/suspend", program).expect("Failed to register suspend");
+}
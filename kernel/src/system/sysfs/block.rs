@@ -7,9 +7,17 @@
 //! At any point the hardware device may become unavailable if this occurs the [BlockDev] should be
 //! dropped as the device will never return.
 
+use crate::mem::dma::DmaTarget;
 use alloc::{boxed::Box, string::String};
+use futures_util::FutureExt;
 use log::warn;
 
+pub mod dev_file;
+pub mod dm;
+pub mod loop_dev;
+pub mod md;
+pub mod stats;
+
 /// This Alias exists for a similar reason to [BlockDevCounter]. Its purpose is for handling block
 /// device geometry.
 /// At some point in the future it may be necessary to increase the size of this
@@ -33,24 +41,34 @@ impl BlockDeviceList {
 
     /// Registers a block device into self.
     /// This fn will return `device` if the block device is already registered.
+    ///
+    /// The device is wrapped so that its I/O is recorded by [stats], devices fetched from this list
+    /// are the wrapped device.
     pub fn register_dev(
         &self,
         device: Box<dyn SysFsBlockDevice>,
     ) -> Option<Box<dyn SysFsBlockDevice>> {
         let id = device.get_id();
-        log::debug!("registered {id}");
-        let mut ret = self.list.write().insert(id, device);
-        if ret.is_some() {
+        let mut list = self.list.write();
+        let alloc::collections::btree_map::Entry::Vacant(entry) = list.entry(id) else {
             warn!("Driver attempted to register block device {id} twice");
-            ret = self.list.write().insert(id, ret.unwrap())
-        }
-
-        ret
+            return Some(device);
+        };
+        log::debug!("registered {id}");
+        let file_dev = entry.insert(stats::track(device)).s_clone();
+        drop(list);
+        dev_file::attach(file_dev);
+        None
     }
 
     /// Removes a device an all its artifacts from the SysFs
     pub fn remove_dev(&self, id: BlockDeviceId) -> Option<Box<dyn SysFsBlockDevice>> {
-        self.list.write().remove(&id)
+        let ret = self.list.write().remove(&id);
+        if ret.is_some() {
+            dev_file::detach(id);
+            stats::untrack(id);
+        }
+        ret
     }
 
     /// Returns a copy of the requested block device, if it exists.
@@ -113,6 +131,110 @@ pub enum BlockDevIoErr {
     /// The device had been disabled or removed and may not be accessed anymore. All further
     /// operations will return this error.
     DeviceOffline,
+    /// Attempted to write to a device which does not permit writes.
+    ReadOnly,
+    /// The device does not support the requested operation.
+    NotSupported,
+}
+
+impl From<BlockDevIoErr> for crate::fs::IoError {
+    fn from(value: BlockDevIoErr) -> Self {
+        match value {
+            BlockDevIoErr::Misaligned | BlockDevIoErr::GeomError => Self::InvalidData,
+            BlockDevIoErr::OutOfRange => Self::EndOfFile,
+            BlockDevIoErr::HardwareError => Self::MediaError,
+            BlockDevIoErr::InternalDriverErr => Self::DeviceError,
+            BlockDevIoErr::DeviceOffline => Self::NotPresent,
+            BlockDevIoErr::ReadOnly => Self::ReadOnly,
+            BlockDevIoErr::NotSupported => Self::NotSupported,
+        }
+    }
+}
+
+/// Health and lifetime statistics reported by a device, see [BlockDev::device_statistics].
+///
+/// Fields are `None` when the device does not report them.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DeviceStatistics {
+    pub power_on_hours: Option<u64>,
+    pub power_cycles: Option<u64>,
+    pub bytes_written: Option<u64>,
+    pub bytes_read: Option<u64>,
+    /// Current temperature in degrees Celsius.
+    pub temperature: Option<i16>,
+    /// Highest temperature over the lifetime of the device in degrees Celsius.
+    pub highest_temperature: Option<i16>,
+    /// Lowest temperature over the lifetime of the device in degrees Celsius.
+    pub lowest_temperature: Option<i16>,
+    /// Maximum temperature the device is specified to operate at in degrees Celsius.
+    pub max_operating_temperature: Option<i16>,
+    /// Estimated percentage of the device's write endurance which has been used, this may exceed 100.
+    pub endurance_used: Option<u8>,
+}
+
+impl DeviceStatistics {
+    /// Formats the statistics as `name: value` lines, statistics which are not reported are omitted.
+    pub fn report(&self) -> String {
+        use core::fmt::Write;
+        let mut s = String::new();
+        let mut line = |name: &str, v: Option<i64>, unit: &str| {
+            if let Some(v) = v {
+                let _ = writeln!(s, "{name}: {v}{unit}");
+            }
+        };
+        line("power_on_hours", self.power_on_hours.map(|v| v as i64), "");
+        line("power_cycles", self.power_cycles.map(|v| v as i64), "");
+        line("bytes_written", self.bytes_written.map(|v| v as i64), "");
+        line("bytes_read", self.bytes_read.map(|v| v as i64), "");
+        line("temperature", self.temperature.map(|v| v as i64), "C");
+        line("highest_temperature", self.highest_temperature.map(|v| v as i64), "C");
+        line("lowest_temperature", self.lowest_temperature.map(|v| v as i64), "C");
+        line("max_operating_temperature", self.max_operating_temperature.map(|v| v as i64), "C");
+        line("endurance_used", self.endurance_used.map(|v| v as i64), "%");
+        s
+    }
+}
+
+/// Tracks the progress of a firmware update, see [BlockDev::update_firmware].
+#[derive(Default, Debug)]
+pub struct FirmwareProgress {
+    active: core::sync::atomic::AtomicBool,
+    done: core::sync::atomic::AtomicU64,
+    total: core::sync::atomic::AtomicU64,
+}
+
+impl FirmwareProgress {
+    /// Marks the start of an update of `total` bytes.
+    ///
+    /// Returns `false` if an update is already in progress.
+    pub fn start(&self, total: u64) -> bool {
+        use core::sync::atomic::Ordering;
+        if self.active.swap(true, Ordering::Acquire) {
+            return false;
+        }
+        self.done.store(0, Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+        true
+    }
+
+    /// Records that `bytes` more bytes have been transferred to the device.
+    pub fn advance(&self, bytes: u64) {
+        self.done.fetch_add(bytes, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn finish(&self) {
+        self.active.store(false, core::sync::atomic::Ordering::Release);
+    }
+
+    /// Returns the number of bytes transferred and the size of the image of the most recent update.
+    pub fn get(&self) -> (u64, u64) {
+        use core::sync::atomic::Ordering;
+        (self.done.load(Ordering::Relaxed), self.total.load(Ordering::Relaxed))
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(core::sync::atomic::Ordering::Relaxed)
+    }
 }
 
 impl core::fmt::Display for BlockDeviceId {
@@ -160,6 +282,44 @@ pub trait BlockDev: Sync + Send {
     /// and the DMA will be completed.
     fn write(&self, seek: BlockDevGeomIntegral, buff: IoBuffer) -> IoFut<IoBuffer>;
 
+    /// Reads blocks starting at `seek` directly into `buff`, the length of `buff` must be a
+    /// multiple of the block size and its address must be aligned to
+    /// [BlockDevGeom::req_data_alignment].
+    ///
+    /// The default implementation reads into an intermediate buffer using [Self::read] and copies
+    /// it into `buff`, implementations which can transfer directly into `buff` should override this.
+    fn read_into<'a>(&'a self, seek: BlockDevGeomIntegral, buff: &'a mut dyn DmaTarget) -> IoFut<'a, ()> {
+        async move {
+            // SAFETY: as_mut guarantees that this is safe.
+            let buff = unsafe { &mut *buff.as_mut() };
+            let bs = self.geom().await?.block_size as usize;
+            if buff.len() % bs != 0 {
+                return Err(BlockDevIoErr::GeomError);
+            }
+            let data = self.read(seek, buff.len() / bs).await?;
+            if data.len() != buff.len() {
+                return Err(BlockDevIoErr::InternalDriverErr);
+            }
+            buff.copy_from_slice(&data);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    /// Writes `buff` directly to the device starting at `seek`. The same requirements as
+    /// [Self::read_into] apply.
+    ///
+    /// The default implementation copies `buff` into an [IoBuffer] and uses [Self::write].
+    fn write_from<'a>(&'a self, seek: BlockDevGeomIntegral, buff: &'a mut dyn DmaTarget) -> IoFut<'a, ()> {
+        async move {
+            // SAFETY: as_mut guarantees that this is safe.
+            let buff = unsafe { &*buff.as_mut() };
+            self.write(seek, IoBuffer::new(Box::from(buff))).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
     /// Returns a struct containing the geometry of the device.
     /// The device geometry must include the block size of the device and the number of blocks in
     /// the device.
@@ -171,11 +331,84 @@ pub trait BlockDev: Sync + Send {
     /// If this fn returns `Err(_)` the hardware device can be considered failed.
     fn geom(&self) -> IoFut<BlockDevGeom>;
 
+    /// Replaces the firmware of the device with `image`. Implementations should call
+    /// [FirmwareProgress::advance] as the image is transferred to the device.
+    ///
+    /// The device may change its geometry or identity once the new firmware is activated.
+    /// The default implementation returns [BlockDevIoErr::NotSupported].
+    fn update_firmware<'a>(&'a self, _image: &'a [u8], _progress: &'a FirmwareProgress) -> IoFut<'a, ()> {
+        async { Err(BlockDevIoErr::NotSupported) }.boxed()
+    }
+
+    /// Returns health and lifetime statistics from the device.
+    ///
+    /// The default implementation returns [BlockDevIoErr::NotSupported].
+    fn device_statistics(&self) -> IoFut<DeviceStatistics> {
+        async { Err(BlockDevIoErr::NotSupported) }.boxed()
+    }
+
+    /// Limits the time in milliseconds the device may spend recovering from a read or write
+    /// error before failing the request, `0` disables the limit.
+    ///
+    /// Without a limit a device may retry a failing sector for a long time, redundant storage
+    /// should set a limit so that it can recover the data from another device instead.
+    /// Implementations may round the limits up. The default implementation returns
+    /// [BlockDevIoErr::NotSupported].
+    fn set_error_recovery(&self, _read_ms: u32, _write_ms: u32) -> IoFut<()> {
+        async { Err(BlockDevIoErr::NotSupported) }.boxed()
+    }
+
+    /// Fills `count` blocks starting at `seek` with zeros.
+    ///
+    /// The default implementation uses [zero_fill], implementations which can zero the device
+    /// without transferring data should override this.
+    fn write_zeroes(&self, seek: BlockDevGeomIntegral, count: BlockDevGeomIntegral) -> IoFut<()> {
+        zero_fill(self, seek, count).boxed()
+    }
+
+    /// Waits until every write which completed before this was called is stored on non-volatile
+    /// media, e.g. by flushing the device's write cache. This is the barrier used by
+    /// [NormalFile::sync](crate::fs::file::NormalFile::sync).
+    ///
+    /// The default implementation returns `Ok(())`, which is correct for devices without a volatile
+    /// write cache. Devices built on other devices must flush them.
+    fn flush(&self) -> IoFut<()> {
+        async { Ok(()) }.boxed()
+    }
+
     fn as_any(&self) -> &dyn core::any::Any;
 
     fn b_clone(self: &Self) -> Box<dyn BlockDev>;
 }
 
+/// Maximum number of bytes written by each request made by [zero_fill].
+const ZERO_FILL_CHUNK: BlockDevGeomIntegral = 0x10000;
+
+/// Fills `count` blocks starting at `seek` with zeros by writing zeroed buffers to `dev`.
+///
+/// This is the default implementation of [BlockDev::write_zeroes], drivers may use it when the
+/// device cannot zero the range itself.
+pub async fn zero_fill<D: BlockDev + ?Sized>(
+    dev: &D,
+    seek: BlockDevGeomIntegral,
+    count: BlockDevGeomIntegral,
+) -> Result<(), BlockDevIoErr> {
+    let geom = dev.geom().await?;
+    if seek.checked_add(count).map_or(true, |end| end > geom.blocks) {
+        return Err(BlockDevIoErr::OutOfRange);
+    }
+    let chunk = (ZERO_FILL_CHUNK / geom.block_size).clamp(1, geom.max_blocks_per_transfer.max(1));
+
+    let mut done = 0;
+    while done < count {
+        let n = chunk.min(count - done);
+        let buff = alloc::vec![0u8; (n * geom.block_size) as usize].into_boxed_slice();
+        dev.write(seek + done, IoBuffer::new(buff)).await?;
+        done += n;
+    }
+    Ok(())
+}
+
 /// Wrapper for data used for DMA with block devices.
 ///
 /// Certain restrictions must be placed on IO buffers to ensure memory safety.
@@ -297,13 +530,19 @@ pub struct BlockDevGeom {
 /// all block devices should appear within this list. Partitions can be exported as block devices
 /// but are actually an interface to a hardware device (probably a `SysFsBlockDevice`).
 ///
-/// This trait should only be implemented by structs that directly represent hardware devices
+/// This trait should only be implemented by structs that directly represent hardware devices,
+/// or which present something else as a whole device such as [loop_dev::LoopDevice].
 ///
 /// Implementations should use some form of reference counting
 pub trait SysFsBlockDevice: BlockDev {
     /// Returns the unique id of the device
     fn get_id(&self) -> BlockDeviceId;
 
+    /// Returns the class used to name the device's devfs node.
+    fn device_class(&self) -> crate::fs::devfs::naming::DeviceClass {
+        crate::fs::devfs::naming::DeviceClass::Disk
+    }
+
     fn s_clone(self: &Self) -> Box<dyn SysFsBlockDevice>;
 }
 
@@ -0,0 +1,783 @@
+//! Pass-through files for block devices.
+//!
+//! Each block device registered in the [super::BlockDeviceList] is given a node in the devfs,
+//! the node is a [FileType::BlkDev] file which also implements [NormalFile]. Reads and writes
+//! operate on the raw contents of the device and do not need to be aligned to the block size,
+//! partial blocks are read and modified as required.
+//!
+//! Accesses go through a page cache shared by all file objects for the same device. The cache is
+//! write-through, writes complete once the data has been written to the device. The device may
+//! still hold written data in a volatile cache, [NormalFile::sync] waits for writes in progress
+//! and flushes the device using [super::BlockDev::flush]. Cached pages are
+//! [Page]s merged by [page::merge], so identical pages share memory with each other and with
+//! filesystem buffer caches. A file object may
+//! bypass the cache using [BlockCtl::SetDirect], direct accesses must be aligned to the block size
+//! and any cached pages which they overlap are invalidated. Direct accesses which are also aligned
+//! to the device's physical sector size and data alignment are transferred straight between the
+//! caller's buffer and the device using [super::BlockDev::read_into] and [super::BlockDev::write_from].
+//!
+//! Each file object tracks whether it is being read sequentially. Once a read starts where the
+//! previous one ended the pages following it are prefetched in the background, the prefetch window
+//! starts small and doubles on each sequential read up to [READAHEAD_PAGES]. A non-sequential read
+//! resets the window.
+//!
+//! When [CHECKSUM_PAGES] is enabled a CRC32C is computed for each page as it is cached and the
+//! page is verified each time it is accessed. A page which fails verification is evicted and the
+//! access fails with [IoError::MediaError]. This is intended for catching DMA corruption and
+//! driver bugs during development.
+//!
+//! Requests are refused with [IoError::NotReady] once I/O has been stopped by [crate::system::shutdown].
+//!
+//! B-side file `0` is a read-only text file containing the health statistics reported by the
+//! device, see [super::DeviceStatistics::report]. The statistics are fetched from the device each
+//! time the file is read.
+//!
+//! The `/bin/fwupdate DEVICE IMAGE` program updates the firmware of a device using
+//! [BlockCtl::UpdateFirmware].
+
+use super::{BlockDevGeom, BlockDevIoErr, BlockDeviceId, FirmwareProgress, IoBuffer, SysFsBlockDevice};
+use crate::error::KernelError;
+use crate::fs::device::{BlockCtl, CtlResponse, DeviceCtl};
+use crate::fs::file::*;
+use crate::fs::vfs::{DevID, MajorNum};
+use crate::fs::{IoError, IoResult};
+use crate::mem::dma::{DmaBuff, StackDmaGuard};
+use crate::mem::page::{self, Page};
+use crate::task::wait::{Annotate, WaitPoint};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("blkdev").unwrap(););
+static MINOR: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+/// Maximum number of pages cached for each device.
+const CACHE_PAGES: usize = 64;
+
+/// Maximum number of pages prefetched ahead of a sequential reader. This is limited to half the
+/// size of the cache, 0 disables read-ahead.
+pub static READAHEAD_PAGES: crate::config::Tunable<u64> =
+    crate::config::Tunable::new("blkdev.readahead", "Maximum number of block device pages read ahead of sequential reads", 16);
+
+/// Size of the prefetch window when a sequential read is first detected.
+const READAHEAD_INITIAL: u64 = 2;
+
+/// Enables checksums on cached pages. Only pages cached after this is enabled are verified.
+pub static CHECKSUM_PAGES: crate::config::Tunable<bool> =
+    crate::config::Tunable::new("blkdev.checksum", "Verify cached block device pages with CRC32C", cfg!(feature = "blkdev-checksum"));
+
+/// Device IDs of the nodes created by [attach].
+static NODES: spin::Mutex<BTreeMap<BlockDeviceId, DevID>> = spin::Mutex::new(BTreeMap::new());
+
+/// Creates a devfs node for `dev`.
+pub(super) fn attach(dev: Box<dyn SysFsBlockDevice>) {
+    let block_id = dev.get_id();
+    let class = dev.device_class();
+    let file = BlockDevFile::new(dev);
+    NODES.lock().insert(block_id, file.inner.id);
+    crate::task::run_task(Box::pin(async move {
+        match crate::fs::devfs::register(Box::new(file), class).await {
+            Ok(name) => log::info!("Block device {block_id} registered as {name}"),
+            Err(e) => log::error!("Failed to register block device {block_id} with devfs: {e:?}"),
+        }
+        crate::task::TaskResult::ExitedNormally
+    }));
+}
+
+/// Removes the devfs node for `id`.
+pub(super) fn detach(id: BlockDeviceId) {
+    let Some(dev) = NODES.lock().remove(&id) else { return };
+    crate::task::run_task(Box::pin(async move {
+        if let Err(e) = crate::fs::devfs::unregister(dev).await {
+            log::error!("Failed to remove devfs node for block device {id}: {e:?}");
+        }
+        crate::task::TaskResult::ExitedNormally
+    }));
+}
+
+struct BlockDevInner {
+    dev: Box<dyn SysFsBlockDevice>,
+    id: DevID,
+    cache: spin::Mutex<PageCache>,
+    /// Serializes writes so read-modify-write of partial blocks is not interleaved.
+    write_lock: async_lock::Mutex<()>,
+    firmware: FirmwareProgress,
+}
+
+#[derive(Default)]
+struct PageCache {
+    pages: BTreeMap<u64, CachedPage>,
+    clock: u64,
+}
+
+struct CachedPage {
+    page: Arc<Page>,
+    last_used: u64,
+    crc: Option<u32>,
+}
+
+impl PageCache {
+    fn contains(&self, page: u64) -> bool {
+        self.pages.contains_key(&page)
+    }
+
+    /// Returns the page, `None` if the page is not cached.
+    ///
+    /// If the page has a checksum it is verified first. A page which fails verification is evicted
+    /// and [IoError::MediaError] is returned.
+    fn verify(&mut self, page: u64) -> Option<Result<&mut CachedPage, IoError>> {
+        self.clock += 1;
+        let p = self.pages.get(&page)?;
+        if let Some(crc) = p.crc {
+            let found = p.page.read(crate::crypto::crc::crc32c);
+            if found != crc {
+                log::error!("Checksum mismatch in cached page {page}: expected {crc:#010x} found {found:#010x}");
+                self.pages.remove(&page);
+                return Some(Err(IoError::MediaError));
+            }
+        }
+        let p = self.pages.get_mut(&page).unwrap();
+        p.last_used = self.clock;
+        Some(Ok(p))
+    }
+
+    /// Calls `f` on the page, returns `None` if the page is not cached. See [Self::verify].
+    fn with_page<R>(&mut self, page: u64, f: impl FnOnce(&[u8]) -> R) -> Option<Result<R, IoError>> {
+        Some(self.verify(page)?.map(|p| p.page.read(f)))
+    }
+
+    /// Calls `f` to modify the page and returns the page, which is copied first if it is merged.
+    /// The page is dirty until the caller writes it through. See [Self::verify].
+    fn with_page_mut<R>(&mut self, page: u64, f: impl FnOnce(&mut [u8]) -> R) -> Option<Result<(R, Arc<Page>), IoError>> {
+        Some(self.verify(page)?.map(|p| {
+            let r = Page::make_mut(&mut p.page).modify(f);
+            if p.crc.is_some() {
+                p.crc = Some(p.page.read(crate::crypto::crc::crc32c));
+            }
+            (r, p.page.clone())
+        }))
+    }
+
+    fn insert(&mut self, page: u64, data: Box<[u8]>) {
+        if self.pages.len() >= CACHE_PAGES && !self.pages.contains_key(&page) {
+            let lru = self.pages.iter().min_by_key(|(_, p)| p.last_used).map(|(n, _)| *n).unwrap(); // len is not 0
+            self.pages.remove(&lru);
+        }
+        self.clock += 1;
+        let crc = CHECKSUM_PAGES.get().then(|| crate::crypto::crc::crc32c(&data));
+        self.pages.insert(page, CachedPage { page: page::merge(Page::from_data(data)), last_used: self.clock, crc });
+    }
+
+    fn invalidate(&mut self, pages: core::ops::Range<u64>) {
+        let keys: alloc::vec::Vec<u64> = self.pages.range(pages).map(|(n, _)| *n).collect();
+        for k in keys {
+            self.pages.remove(&k);
+        }
+    }
+}
+
+impl BlockDevInner {
+    /// Converts an error returned by the device into an [IoError].
+    ///
+    /// The failing block is lost in the conversion, so device failures are logged here.
+    fn device_error(&self, e: BlockDevIoErr, op: &'static str, lba: u64) -> IoError {
+        let err = KernelError::from(e).with_device(self.dev.get_id()).wrap("blkdev", alloc::format!("{op} at block {lba} failed")).with_device(self.id);
+        if matches!(e, BlockDevIoErr::HardwareError | BlockDevIoErr::InternalDriverErr) {
+            log::error!("{err}");
+        }
+        err.into()
+    }
+
+    async fn geom(&self) -> Result<BlockDevGeom, IoError> {
+        Ok(self.dev.geom().await?)
+    }
+
+    /// Writes are blocked while the update runs and the cache is dropped afterwards because the
+    /// new firmware may present the device differently.
+    async fn update_firmware(&self, image: &[u8]) -> Result<(), IoError> {
+        if !self.firmware.start(image.len() as u64) {
+            return Err(IoError::Busy);
+        }
+        let _l = self.write_lock.lock().waiting_on(WaitPoint::lock("block write")).await;
+        log::info!("Updating firmware of {}, {} bytes", self.id, image.len());
+        let r = self.dev.update_firmware(image, &self.firmware).await;
+        *self.cache.lock() = PageCache::default();
+        self.firmware.finish();
+        match r {
+            Ok(()) => log::info!("Firmware update of {} complete", self.id),
+            Err(e) => log::error!("{}", KernelError::from(e).with_device(self.dev.get_id()).wrap("blkdev", "Firmware update failed").with_device(self.id)),
+        }
+        Ok(r?)
+    }
+
+    /// Waits for cached writes in progress to complete and flushes the device.
+    async fn sync(&self) -> Result<(), IoError> {
+        let _io = crate::system::shutdown::begin_io().ok_or(IoError::NotReady)?;
+        // Cached writes hold the write lock until the device has completed them
+        drop(self.write_lock.lock().waiting_on(WaitPoint::lock("block write")).await);
+        self.dev.flush().await.map_err(|e| {
+            let err = KernelError::from(e).with_device(self.dev.get_id()).wrap("blkdev", "Flush failed").with_device(self.id);
+            log::error!("{err}");
+            err.into()
+        })
+    }
+
+    fn page_size(geom: &BlockDevGeom) -> u64 {
+        geom.block_size.max(crate::mem::PAGE_SIZE as u64)
+    }
+
+    /// Reads `page` into the cache if it is not already present.
+    async fn load_page(&self, geom: &BlockDevGeom, page: u64) -> Result<(), IoError> {
+        if self.cache.lock().contains(page) {
+            return Ok(());
+        }
+        let blocks_per_page = Self::page_size(geom) / geom.block_size;
+        let first = page * blocks_per_page;
+        let count = blocks_per_page.min(geom.blocks - first);
+        let data = self.dev.read(first, count as usize).await.map_err(|e| self.device_error(e, "Read", first))?;
+        self.cache.lock().insert(page, data);
+        Ok(())
+    }
+
+    /// Returns the number of bytes which may be accessed from `pos`, up to `len`.
+    fn clamp(geom: &BlockDevGeom, pos: u64, len: usize) -> Result<usize, IoError> {
+        let size = geom.blocks * geom.block_size;
+        if pos >= size {
+            return Err(IoError::EndOfFile);
+        }
+        Ok(len.min((size - pos) as usize))
+    }
+
+    async fn read_cached(&self, pos: u64, buff: &mut [u8]) -> Result<usize, (IoError, usize)> {
+        let geom = self.geom().await.map_err(|e| (e, 0))?;
+        let len = Self::clamp(&geom, pos, buff.len()).map_err(|e| (e, 0))?;
+        let page_size = Self::page_size(&geom);
+
+        let mut done = 0;
+        while done < len {
+            let off = pos + done as u64;
+            let page = off / page_size;
+            let in_page = (off % page_size) as usize;
+            self.load_page(&geom, page).await.map_err(|e| (e, done))?;
+
+            // The page may have been evicted since it was loaded, in which case it is loaded again
+            let r = self.cache.lock().with_page(page, |data| {
+                let n = (data.len() - in_page).min(len - done);
+                buff[done..done + n].copy_from_slice(&data[in_page..in_page + n]);
+                n
+            });
+            match r {
+                Some(Ok(n)) => done += n,
+                Some(Err(e)) => {
+                    log::error!("Cached data for {} is corrupt at offset {off:#x}", self.id);
+                    return Err((e, done));
+                }
+                None => {}
+            }
+        }
+        Ok(done)
+    }
+
+    async fn write_cached(&self, pos: u64, buff: &[u8]) -> Result<usize, (IoError, usize)> {
+        let _l = self.write_lock.lock().waiting_on(WaitPoint::lock("block write")).await;
+        let geom = self.geom().await.map_err(|e| (e, 0))?;
+        let len = Self::clamp(&geom, pos, buff.len()).map_err(|e| (e, 0))?;
+        let page_size = Self::page_size(&geom);
+        let block_size = geom.block_size as usize;
+
+        let mut done = 0;
+        while done < len {
+            let off = pos + done as u64;
+            let page = off / page_size;
+            let in_page = (off % page_size) as usize;
+            self.load_page(&geom, page).await.map_err(|e| (e, done))?;
+
+            // Update the cached page and copy out the blocks which were modified
+            let r = self.cache.lock().with_page_mut(page, |data| {
+                let n = (data.len() - in_page).min(len - done);
+                data[in_page..in_page + n].copy_from_slice(&buff[done..done + n]);
+                let first = in_page / block_size * block_size;
+                let end = (in_page + n).div_ceil(block_size) * block_size;
+                (n, first, Box::<[u8]>::from(&data[first..end]))
+            });
+            let ((n, first, blocks), cached) = match r {
+                Some(Ok(r)) => r,
+                Some(Err(e)) => {
+                    log::error!("Cached data for {} is corrupt at offset {off:#x}", self.id);
+                    return Err((e, done));
+                }
+                None => continue,
+            };
+
+            let lba = page * (page_size / geom.block_size) + (first / block_size) as u64;
+            // Writes are serialized by the write lock, so the page is never already under write-back
+            cached.begin_writeback();
+            let r = self.dev.write(lba, IoBuffer::new(blocks)).await;
+            cached.end_writeback(r.is_ok());
+            if let Err(e) = r {
+                // The cached page no longer matches the device
+                self.cache.lock().invalidate(page..page + 1);
+                return Err((self.device_error(e, "Write", lba), done));
+            }
+            done += n;
+        }
+        Ok(done)
+    }
+
+    /// Reads the uncached pages within `pages` into the cache.
+    ///
+    /// Contiguous pages are read using as few requests as the device allows.
+    async fn prefetch(&self, pages: core::ops::Range<u64>) -> Result<(), IoError> {
+        // Prevents writes from updating pages between reading them and inserting them into the cache
+        let _l = self.write_lock.lock().waiting_on(WaitPoint::lock("block write")).await;
+        let _io = crate::system::shutdown::begin_io().ok_or(IoError::NotReady)?;
+        let geom = self.geom().await?;
+        let page_size = Self::page_size(&geom);
+        let blocks_per_page = page_size / geom.block_size;
+        let max_pages = (geom.max_blocks_per_transfer.max(1) / blocks_per_page).max(1);
+        let last_page = (geom.blocks * geom.block_size).div_ceil(page_size);
+
+        let mut page = pages.start;
+        let end = pages.end.min(last_page);
+        while page < end {
+            if self.cache.lock().contains(page) {
+                page += 1;
+                continue;
+            }
+            let mut run = 1;
+            while page + run < end && run < max_pages && !self.cache.lock().contains(page + run) {
+                run += 1;
+            }
+            let first = page * blocks_per_page;
+            let count = (run * blocks_per_page).min(geom.blocks - first);
+            let data = self.dev.read(first, count as usize).await.map_err(|e| self.device_error(e, "Read", first))?;
+
+            let mut cache = self.cache.lock();
+            for (i, chunk) in data.chunks(page_size as usize).enumerate() {
+                if !cache.contains(page + i as u64) {
+                    cache.insert(page + i as u64, chunk.into());
+                }
+            }
+            drop(cache);
+            page += run;
+        }
+        Ok(())
+    }
+
+    /// Checks that a direct access is aligned to the block size and returns the number of bytes to transfer.
+    fn direct_len(geom: &BlockDevGeom, pos: u64, len: usize) -> Result<usize, IoError> {
+        if pos % geom.block_size != 0 || len as u64 % geom.block_size != 0 {
+            return Err(IoError::InvalidData);
+        }
+        Self::clamp(geom, pos, len)
+    }
+
+    async fn read_direct(&self, pos: u64, buff: &mut [u8]) -> Result<usize, (IoError, usize)> {
+        let geom = self.geom().await.map_err(|e| (e, 0))?;
+        let len = Self::direct_len(&geom, pos, buff.len()).map_err(|e| (e, 0))?;
+        let max = (geom.max_blocks_per_transfer.max(1) * geom.block_size) as usize;
+
+        let zero_copy = Self::zero_copy(&geom, pos, &buff[..len]);
+
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(max);
+            let lba = (pos + done as u64) / geom.block_size;
+            if zero_copy {
+                // SAFETY: The future is awaited before the region is accessed again.
+                let mut target = unsafe { StackDmaGuard::new(&mut buff[done..done + n]) };
+                self.dev.read_into(lba, &mut target).await.map_err(|e| (self.device_error(e, "Read", lba), done))?;
+            } else {
+                let data = self.dev.read(lba, n / geom.block_size as usize).await.map_err(|e| (self.device_error(e, "Read", lba), done))?;
+                buff[done..done + n].copy_from_slice(&data[..n]);
+            }
+            done += n;
+        }
+        Ok(done)
+    }
+
+    /// Returns whether a direct access to `buff` at `pos` is aligned to the device's physical
+    /// sectors and data alignment. Such accesses are transferred directly between `buff` and the
+    /// device, other accesses are copied through an intermediate buffer.
+    fn zero_copy(geom: &BlockDevGeom, pos: u64, buff: &[u8]) -> bool {
+        let phys = geom.optimal_block_size.max(geom.block_size);
+        let first_phys = geom.optimal_alignment * geom.block_size;
+        pos >= first_phys
+            && (pos - first_phys) % phys == 0
+            && buff.len() as u64 % phys == 0
+            && buff.as_ptr() as usize % geom.req_data_alignment.max(1) == 0
+    }
+
+    async fn write_direct(&self, pos: u64, buff: &mut [u8]) -> Result<usize, (IoError, usize)> {
+        let _l = self.write_lock.lock().waiting_on(WaitPoint::lock("block write")).await;
+        let geom = self.geom().await.map_err(|e| (e, 0))?;
+        let len = Self::direct_len(&geom, pos, buff.len()).map_err(|e| (e, 0))?;
+        let max = (geom.max_blocks_per_transfer.max(1) * geom.block_size) as usize;
+        let page_size = Self::page_size(&geom);
+        self.cache.lock().invalidate(pos / page_size..(pos + len as u64).div_ceil(page_size));
+
+        let zero_copy = Self::zero_copy(&geom, pos, &buff[..len]);
+
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(max);
+            let lba = (pos + done as u64) / geom.block_size;
+            if zero_copy {
+                // SAFETY: The future is awaited before the region is accessed again.
+                let mut target = unsafe { StackDmaGuard::new(&mut buff[done..done + n]) };
+                self.dev.write_from(lba, &mut target).await.map_err(|e| (self.device_error(e, "Write", lba), done))?;
+            } else {
+                let data = IoBuffer::new(Box::from(&buff[done..done + n]));
+                self.dev.write(lba, data).await.map_err(|e| (self.device_error(e, "Write", lba), done))?;
+            }
+            done += n;
+        }
+        Ok(done)
+    }
+}
+
+/// File object for a block device, see the module level documentation.
+#[derive(Clone)]
+pub struct BlockDevFile {
+    inner: Arc<BlockDevInner>,
+    direct: bool,
+    readahead: ReadAhead,
+}
+
+/// Sequential access detection for a single file object.
+///
+/// Cloned file objects do not inherit the state of the original.
+#[derive(Default)]
+struct ReadAhead {
+    state: spin::Mutex<ReadAheadState>,
+}
+
+#[derive(Default)]
+struct ReadAheadState {
+    /// The position immediately after the last read.
+    next: u64,
+    /// Number of pages to prefetch, 0 until a sequential read is detected.
+    window: u64,
+    /// Pages before this have already been prefetched.
+    prefetched: u64,
+}
+
+impl Clone for ReadAhead {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl ReadAhead {
+    /// Records a read of `len` bytes at `pos` and returns the pages which should be prefetched.
+    fn update(&self, pos: u64, len: u64, page_size: u64) -> Option<core::ops::Range<u64>> {
+        let max = READAHEAD_PAGES.get().min(CACHE_PAGES as u64 / 2);
+        let mut s = self.state.lock();
+        let sequential = pos == s.next && pos != 0;
+        s.next = pos + len;
+        if !sequential || max == 0 {
+            s.window = 0;
+            s.prefetched = 0;
+            return None;
+        }
+        s.window = (s.window * 2).clamp(READAHEAD_INITIAL.min(max), max);
+
+        let start = s.next.div_ceil(page_size).max(s.prefetched);
+        let end = s.next.div_ceil(page_size) + s.window;
+        // Wait until at least half the window has been consumed before issuing another prefetch
+        if start >= end || (s.prefetched != 0 && end - start < s.window / 2) {
+            return None;
+        }
+        s.prefetched = end;
+        Some(start..end)
+    }
+}
+
+impl BlockDevFile {
+    fn new(dev: Box<dyn SysFsBlockDevice>) -> Self {
+        Self {
+            inner: Arc::new(BlockDevInner {
+                dev,
+                id: DevID::new(*MAJOR, MINOR.fetch_add(1, atomic::Ordering::Relaxed)),
+                cache: spin::Mutex::new(PageCache::default()),
+                write_lock: async_lock::Mutex::new(()),
+                firmware: FirmwareProgress::default(),
+            }),
+            direct: false,
+            readahead: ReadAhead::default(),
+        }
+    }
+}
+
+impl BlockDevFile {
+    /// Starts prefetching pages if the read at `pos` was sequential.
+    async fn read_ahead(&self, pos: u64, len: u64) {
+        let Ok(geom) = self.inner.geom().await else { return };
+        let Some(pages) = self.readahead.update(pos, len, BlockDevInner::page_size(&geom)) else { return };
+        let inner = self.inner.clone();
+        crate::task::run_task(Box::pin(async move {
+            if let Err(e) = inner.prefetch(pages).await {
+                log::debug!("Read-ahead on {} failed: {e:?}", inner.id);
+            }
+            crate::task::TaskResult::ExitedNormally
+        }));
+    }
+}
+
+#[cast_trait_object::dyn_upcast]
+#[cast_trait_object::dyn_cast(NormalFile<u8>, Directory, crate::fs::device::FileSystem, crate::fs::device::Fifo<u8>, crate::fs::device::DeviceFile )]
+impl File for BlockDevFile {
+    fn file_type(&self) -> FileType {
+        FileType::BlkDev
+    }
+
+    fn block_size(&self) -> u64 {
+        crate::mem::PAGE_SIZE as u64
+    }
+
+    fn device(&self) -> DevID {
+        self.inner.id
+    }
+
+    fn clone_file(&self) -> Box<dyn File> {
+        Box::new(self.clone())
+    }
+
+    fn id(&self) -> u64 {
+        0
+    }
+
+    fn len(&self) -> IoResult<u64> {
+        async {
+            let geom = self.inner.geom().await?;
+            Ok(geom.blocks * geom.block_size)
+        }.boxed()
+    }
+
+    /// 0. Device statistics, see [StatisticsBFile]
+    fn b_file(&self, id: u64) -> Option<Box<dyn File>> {
+        match id {
+            0 => Some(Box::new(StatisticsBFile { inner: self.inner.clone() })),
+            _ => None,
+        }
+    }
+}
+
+impl crate::fs::device::DeviceFile for BlockDevFile {
+    fn control<'f, 'a: 'f>(&'a mut self, request: DeviceCtl) -> IoResult<'f, CtlResponse> {
+        async move {
+            match request {
+                DeviceCtl::Block(BlockCtl::GetGeometry) => Ok(CtlResponse::Geometry(self.inner.geom().await?)),
+                DeviceCtl::Block(BlockCtl::SetDirect(direct)) => {
+                    self.direct = direct;
+                    Ok(CtlResponse::None)
+                }
+                DeviceCtl::Block(BlockCtl::UpdateFirmware { image, cred }) => {
+                    if !cred.is_privileged() {
+                        return Err(IoError::PermissionDenied);
+                    }
+                    self.inner.update_firmware(&image).await?;
+                    Ok(CtlResponse::None)
+                }
+                DeviceCtl::Block(BlockCtl::FirmwareProgress) => {
+                    let (done, total) = self.inner.firmware.get();
+                    Ok(CtlResponse::Progress { done, total, active: self.inner.firmware.is_active() })
+                }
+                _ => Err(IoError::NotSupported),
+            }
+        }.boxed()
+    }
+}
+
+impl NormalFile for BlockDevFile {
+    fn len_chars(&self) -> IoResult<u64> {
+        self.len()
+    }
+
+    fn file_lock<'a>(self: Box<Self>) -> BoxFuture<'a, Result<LockedFile<u8>, (IoError, Box<dyn NormalFile<u8>>)>> {
+        async { Err((IoError::NotSupported, self as Box<dyn NormalFile>)) }.boxed()
+    }
+
+    unsafe fn unlock_unsafe(&self) -> IoResult<()> {
+        async { Err(IoError::NotSupported) }.boxed()
+    }
+
+    /// Direct writes complete once the device has completed them, so only cached writes are
+    /// waited for. All data is flushed, `data_only` has no effect.
+    fn sync(&self, _data_only: bool) -> IoResult<()> {
+        self.inner.sync().boxed()
+    }
+}
+
+impl Read<u8> for BlockDevFile {
+    fn read<'f, 'a: 'f, 'b: 'f>(&'a self, pos: u64, mut dbuff: DmaBuff<'b>) -> BoxFuture<'f, Result<(DmaBuff<'b>, usize), (IoError, DmaBuff<'b>, usize)>> {
+        async move {
+            // Refused once the system is shutting down
+            let Some(_io) = crate::system::shutdown::begin_io() else {
+                return Err((IoError::NotReady, dbuff, 0));
+            };
+            // SAFETY: as_mut guarantees that this is safe.
+            let buff = unsafe { &mut *crate::mem::dma::DmaTarget::as_mut(&mut *dbuff) };
+            let r = if self.direct {
+                self.inner.read_direct(pos, buff).await
+            } else {
+                let r = self.inner.read_cached(pos, buff).await;
+                if let Ok(n) = r {
+                    self.read_ahead(pos, n as u64).await;
+                }
+                r
+            };
+            match r {
+                Ok(n) => Ok((dbuff, n)),
+                Err((e, n)) => Err((e, dbuff, n)),
+            }
+        }.boxed()
+    }
+}
+
+impl Write<u8> for BlockDevFile {
+    fn write<'f, 'a: 'f, 'b: 'f>(&'a self, pos: u64, mut dbuff: DmaBuff<'b>) -> BoxFuture<'f, Result<(DmaBuff<'b>, usize), (IoError, DmaBuff<'b>, usize)>> {
+        async move {
+            // Refused once the system is shutting down
+            let Some(_io) = crate::system::shutdown::begin_io() else {
+                return Err((IoError::NotReady, dbuff, 0));
+            };
+            // SAFETY: as_mut guarantees that this is safe.
+            let buff = unsafe { &mut *crate::mem::dma::DmaTarget::as_mut(&mut *dbuff) };
+            let r = if self.direct {
+                self.inner.write_direct(pos, buff).await
+            } else {
+                self.inner.write_cached(pos, buff).await
+            };
+            match r {
+                Ok(n) => Ok((dbuff, n)),
+                Err((e, n)) => Err((e, dbuff, n)),
+            }
+        }.boxed()
+    }
+}
+
+/// B-side file containing the device statistics of a block device.
+///
+/// Reads return [super::DeviceStatistics::report], `pos` is an offset into the report. Reads
+/// return [IoError::NotSupported] if the device does not report statistics.
+#[derive(Clone)]
+#[cast_trait_object::dyn_upcast(File)]
+#[cast_trait_object::dyn_cast(File => NormalFile<u8>, Directory, crate::fs::device::FileSystem, crate::fs::device::Fifo<u8>, crate::fs::device::DeviceFile )]
+struct StatisticsBFile {
+    inner: Arc<BlockDevInner>,
+}
+
+impl StatisticsBFile {
+    async fn report(&self) -> Result<alloc::string::String, IoError> {
+        Ok(self.inner.dev.device_statistics().await?.report())
+    }
+}
+
+impl File for StatisticsBFile {
+    fn file_type(&self) -> FileType {
+        FileType::NormalFile
+    }
+
+    fn block_size(&self) -> u64 {
+        1
+    }
+
+    fn device(&self) -> DevID {
+        self.inner.id
+    }
+
+    fn clone_file(&self) -> Box<dyn File> {
+        Box::new(self.clone())
+    }
+
+    fn id(&self) -> u64 {
+        0
+    }
+
+    fn len(&self) -> IoResult<u64> {
+        async { Ok(self.report().await?.len() as u64) }.boxed()
+    }
+}
+
+impl NormalFile for StatisticsBFile {
+    fn len_chars(&self) -> IoResult<u64> {
+        self.len()
+    }
+
+    fn file_lock<'a>(self: Box<Self>) -> BoxFuture<'a, Result<LockedFile<u8>, (IoError, Box<dyn NormalFile<u8>>)>> {
+        async { Err((IoError::NotSupported, self as Box<dyn NormalFile>)) }.boxed()
+    }
+
+    unsafe fn unlock_unsafe(&self) -> IoResult<()> {
+        async { Err(IoError::NotSupported) }.boxed()
+    }
+}
+
+impl Read<u8> for StatisticsBFile {
+    fn read<'f, 'a: 'f, 'b: 'f>(&'a self, pos: u64, mut dbuff: DmaBuff<'b>) -> BoxFuture<'f, Result<(DmaBuff<'b>, usize), (IoError, DmaBuff<'b>, usize)>> {
+        async move {
+            let report = match self.report().await {
+                Ok(r) => r,
+                Err(e) => return Err((e, dbuff, 0)),
+            };
+            let pos = pos as usize;
+            if pos >= report.len() {
+                return Err((IoError::EndOfFile, dbuff, 0));
+            }
+
+            // SAFETY: as_mut guarantees that this is safe.
+            let buff = unsafe { &mut *crate::mem::dma::DmaTarget::as_mut(&mut *dbuff) };
+            let count = buff.len().min(report.len() - pos);
+            buff[..count].copy_from_slice(&report.as_bytes()[pos..pos + count]);
+            Ok((dbuff, count))
+        }.boxed()
+    }
+}
+
+impl Write<u8> for StatisticsBFile {
+    fn write<'f, 'a: 'f, 'b: 'f>(&'a self, _: u64, dbuff: DmaBuff<'b>) -> BoxFuture<'f, Result<(DmaBuff<'b>, usize), (IoError, DmaBuff<'b>, usize)>> {
+        async { Err((IoError::ReadOnly, dbuff, 0)) }.boxed()
+    }
+}
+
+/// Updates the firmware of the block device file at `dev` with the image in the file at `image`.
+async fn update_firmware(dev: &str, image: &str) -> Result<(), KernelError> {
+    let vfs = crate::fs::get_vfs();
+    let file = vfs.open(image).await.map_err(|e| KernelError::from(e).wrap("fwupdate", alloc::format!("Failed to open {image}")))?;
+    let file = cast_file!(NormalFile<u8>: file)
+        .map_err(|_| KernelError::new("fwupdate", crate::error::Errno::EINVAL).with_context(alloc::format!("{image} is not a normal file")))?;
+    let disk = crate::fs::disk_util::Disk::new(&*file, 1).await?;
+    let image: Arc<[u8]> = disk.read_at(0, disk.len() as usize).await?.into();
+
+    let file = vfs.open(dev).await.map_err(|e| KernelError::from(e).wrap("fwupdate", alloc::format!("Failed to open {dev}")))?;
+    let mut file = cast_file!(crate::fs::device::DeviceFile: file)
+        .map_err(|_| KernelError::new("fwupdate", crate::error::Errno::ENODEV).with_context(alloc::format!("{dev} is not a device file")))?;
+    let request = DeviceCtl::Block(BlockCtl::UpdateFirmware { image, cred: crate::fs::perm::Credentials::Kernel });
+    file.control(request).await.map_err(|e| KernelError::new("fwupdate", e).with_context(alloc::format!("Failed to update the firmware of {dev}")))?;
+    Ok(())
+}
+
+fn program(args: crate::task::spawn::Args) -> core::pin::Pin<Box<dyn core::future::Future<Output = crate::task::TaskResult> + Send>> {
+    use crate::task::TaskResult;
+    Box::pin(async move {
+        let [_, dev, image] = &*args.argv else {
+            crate::serial_println!("Usage: fwupdate DEVICE IMAGE");
+            return TaskResult::Error;
+        };
+        match update_firmware(dev, image).await {
+            Ok(()) => TaskResult::ExitedNormally,
+            Err(e) => {
+                crate::serial_println!("fwupdate: {e}");
+                TaskResult::Error
+            }
+        }
+    })
+}
+
+/// Registers the `/bin/fwupdate` program.
+pub fn init() {
+    crate::task::spawn::register("/bin/fwupdate", program).expect("Failed to register fwupdate");
+}
@@ -0,0 +1,340 @@
+//! Device mapper.
+//!
+//! A mapped device is a virtual block device composed from a [Table] of [Target]s. Each entry of
+//! the table maps a contiguous range of the device onto a target which performs the I/O, requests
+//! spanning multiple entries are split between them. Mapped devices may be used as the members of
+//! other mapped devices, allowing devices to be stacked.
+//!
+//! The following targets are provided
+//!
+//! - [Linear] maps the range onto a range of another block device, this can be used to
+//!   concatenate devices or to present part of a device.
+//! - [snapshot::Snapshot] presents a point in time copy of a device using a copy-on-write
+//!   [snapshot::ExceptionStore], writes to the original device must go through
+//!   [snapshot::Origin] to preserve the snapshot.
+//! - [crypt::Crypt] encrypts the blocks of another block device.
+
+use super::{BlockDev, BlockDevGeom, BlockDevGeomIntegral, BlockDevIoErr, BlockDeviceId, IoBuffer, IoFut, SysFsBlockDevice};
+use crate::fs::devfs::naming::DeviceClass;
+use crate::fs::IoError;
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use futures_util::FutureExt;
+
+pub mod crypt;
+pub mod snapshot;
+
+const DRIVER_NAME: &str = "dm";
+
+/// Instance numbers currently in use.
+static INSTANCES: spin::Mutex<BTreeSet<usize>> = spin::Mutex::new(BTreeSet::new());
+
+/// A mapping from a range of a mapped device onto another device.
+///
+/// Offsets and counts are given in blocks relative to the start of the table entry, all targets
+/// within a table use the block size of the table.
+pub trait Target: Send + Sync {
+    /// Reads `count` blocks starting at `offset`.
+    fn read(&self, offset: u64, count: u64) -> IoFut<Box<[u8]>>;
+
+    /// Writes `data` starting at `offset`, the length of `data` is a multiple of the block size.
+    fn write<'a>(&'a self, offset: u64, data: &'a [u8]) -> IoFut<'a, ()>;
+
+    /// Flushes the devices the target writes to, see [BlockDev::flush]. The default implementation
+    /// returns `Ok(())` for targets which do not write to a device.
+    fn flush(&self) -> IoFut<()> {
+        async { Ok(()) }.boxed()
+    }
+
+    /// Returns the number of blocks which may be mapped onto this target.
+    fn blocks(&self) -> u64;
+
+    /// Returns the block size of the device this target maps onto.
+    fn block_size(&self) -> u64;
+
+    /// Returns the largest number of blocks which may be given in a single request.
+    fn max_transfer(&self) -> u64;
+
+    /// Returns the name of the target type.
+    fn name(&self) -> &'static str;
+}
+
+/// A list of targets making up a mapped device.
+#[derive(Default)]
+pub struct Table {
+    entries: Vec<Entry>,
+    blocks: u64,
+}
+
+struct Entry {
+    start: u64,
+    len: u64,
+    target: Box<dyn Target>,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `len` blocks mapped to `target` to the end of the table.
+    ///
+    /// Returns [IoError::EndOfFile] if `target` is smaller than `len`, or [IoError::InvalidData]
+    /// if the target does not use the same block size as the rest of the table.
+    pub fn push(&mut self, len: u64, target: impl Target + 'static) -> Result<&mut Self, IoError> {
+        if len == 0 || len > target.blocks() {
+            return Err(IoError::EndOfFile);
+        }
+        if self.entries.first().is_some_and(|e| e.target.block_size() != target.block_size()) {
+            return Err(IoError::InvalidData);
+        }
+        self.entries.push(Entry { start: self.blocks, len, target: Box::new(target) });
+        self.blocks += len;
+        Ok(self)
+    }
+
+    /// Returns the `(start, len, target name)` of each entry.
+    pub fn entries(&self) -> impl Iterator<Item = (u64, u64, &'static str)> + '_ {
+        self.entries.iter().map(|e| (e.start, e.len, e.target.name()))
+    }
+
+    /// Splits a request into `(entry, offset within the entry, count)` segments.
+    fn split(&self, seek: u64, count: u64) -> Vec<(&Entry, u64, u64)> {
+        let first = self.entries.partition_point(|e| e.start + e.len <= seek);
+        let mut segments = Vec::new();
+        let mut block = seek;
+        for e in &self.entries[first..] {
+            if block >= seek + count {
+                break;
+            }
+            let offset = block - e.start;
+            let len = (e.len - offset).min(seek + count - block);
+            segments.push((e, offset, len));
+            block += len;
+        }
+        segments
+    }
+}
+
+/// A block device composed from a [Table].
+#[derive(Clone)]
+pub struct MappedDevice {
+    inner: Arc<MappedInner>,
+}
+
+struct MappedInner {
+    id: BlockDeviceId,
+    instance: usize,
+    table: Table,
+    block_size: u64,
+    max_transfer: u64,
+    offline: AtomicBool,
+}
+
+impl Drop for MappedInner {
+    fn drop(&mut self) {
+        INSTANCES.lock().remove(&self.instance);
+    }
+}
+
+/// Creates a mapped device from `table` and registers it as a block device.
+///
+/// Returns [IoError::NotReady] if the table is empty.
+pub fn create(table: Table) -> Result<BlockDeviceId, IoError> {
+    let first = table.entries.first().ok_or(IoError::NotReady)?;
+    let block_size = first.target.block_size();
+    let max_transfer = table.entries.iter().map(|e| e.target.max_transfer()).min().unwrap().max(1);
+
+    let instance = {
+        let mut l = INSTANCES.lock();
+        let i = (0..).find(|i| !l.contains(i)).unwrap(); // the set is finite
+        l.insert(i);
+        i
+    };
+    let id = BlockDeviceId::new(DRIVER_NAME, instance, None);
+    let blocks = table.blocks;
+    let targets = table.entries.len();
+    let dev = MappedDevice {
+        inner: Arc::new(MappedInner { id, instance, table, block_size, max_transfer, offline: AtomicBool::new(false) }),
+    };
+    log::info!("Created mapped device {id}, {blocks} blocks in {targets} targets");
+    // The instance is unique so this can't already be registered
+    let _ = crate::system::sysfs::get_sysfs().get_blk_dev().register_dev(Box::new(dev));
+    Ok(id)
+}
+
+/// Removes the mapped device `id` from the block device list.
+///
+/// All clones of the device go offline immediately. Returns [IoError::NotPresent] if `id` is not
+/// a mapped device.
+pub fn remove(id: BlockDeviceId) -> Result<(), IoError> {
+    let list = crate::system::sysfs::get_sysfs().get_blk_dev();
+    let dev = list.fetch(id).ok_or(IoError::NotPresent)?;
+    let dev = dev.as_any().downcast_ref::<MappedDevice>().ok_or(IoError::NotPresent)?;
+    dev.inner.offline.store(true, Ordering::Release);
+    list.remove_dev(id);
+    log::info!("Removed mapped device {id}");
+    Ok(())
+}
+
+impl MappedDevice {
+    pub fn table(&self) -> &Table {
+        &self.inner.table
+    }
+
+    fn check_range(&self, seek: BlockDevGeomIntegral, count: u64) -> Result<(), BlockDevIoErr> {
+        if self.inner.offline.load(Ordering::Acquire) {
+            return Err(BlockDevIoErr::DeviceOffline);
+        }
+        if count > self.inner.max_transfer {
+            return Err(BlockDevIoErr::GeomError);
+        }
+        match seek.checked_add(count) {
+            Some(end) if end <= self.inner.table.blocks => Ok(()),
+            _ => Err(BlockDevIoErr::OutOfRange),
+        }
+    }
+}
+
+impl BlockDev for MappedDevice {
+    fn read(&self, seek: BlockDevGeomIntegral, size: usize) -> IoFut<Box<[u8]>> {
+        async move {
+            self.check_range(seek, size as u64)?;
+            let reads = self.inner.table.split(seek, size as u64).into_iter().map(|(e, offset, len)| e.target.read(offset, len));
+            let parts = futures_util::future::try_join_all(reads).await?;
+            Ok(parts.concat().into_boxed_slice())
+        }
+        .boxed()
+    }
+
+    fn write(&self, seek: BlockDevGeomIntegral, buff: IoBuffer) -> IoFut<IoBuffer> {
+        async move {
+            let bs = self.inner.block_size;
+            if buff.len() as u64 % bs != 0 {
+                return Err(BlockDevIoErr::GeomError);
+            }
+            self.check_range(seek, buff.len() as u64 / bs)?;
+            let data = buff.buff();
+            let mut off = 0;
+            let mut writes = Vec::new();
+            for (e, offset, len) in self.inner.table.split(seek, buff.len() as u64 / bs) {
+                let end = off + (len * bs) as usize;
+                writes.push(e.target.write(offset, &data[off..end]));
+                off = end;
+            }
+            futures_util::future::try_join_all(writes).await?;
+            Ok(buff)
+        }
+        .boxed()
+    }
+
+    fn flush(&self) -> IoFut<()> {
+        async move {
+            if self.inner.offline.load(Ordering::Acquire) {
+                return Err(BlockDevIoErr::DeviceOffline);
+            }
+            futures_util::future::try_join_all(self.inner.table.entries.iter().map(|e| e.target.flush())).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn geom(&self) -> IoFut<BlockDevGeom> {
+        let bs = self.inner.block_size;
+        let geom = BlockDevGeom {
+            blocks: self.inner.table.blocks,
+            block_size: bs,
+            optimal_block_size: bs,
+            optimal_alignment: 0,
+            max_blocks_per_transfer: self.inner.max_transfer,
+            req_data_alignment: 1,
+        };
+        async move {
+            if self.inner.offline.load(Ordering::Acquire) {
+                return Err(BlockDevIoErr::DeviceOffline);
+            }
+            Ok(geom)
+        }
+        .boxed()
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+
+    fn b_clone(&self) -> Box<dyn BlockDev> {
+        Box::new(self.clone())
+    }
+}
+
+impl SysFsBlockDevice for MappedDevice {
+    fn get_id(&self) -> BlockDeviceId {
+        self.inner.id
+    }
+
+    fn device_class(&self) -> DeviceClass {
+        DeviceClass::Mapped
+    }
+
+    fn s_clone(&self) -> Box<dyn SysFsBlockDevice> {
+        Box::new(self.clone())
+    }
+}
+
+/// Maps a range onto another block device starting at `offset`.
+pub struct Linear {
+    dev: Box<dyn SysFsBlockDevice>,
+    offset: u64,
+    geom: BlockDevGeom,
+}
+
+impl Linear {
+    /// Maps onto the block device `id` starting at block `offset`.
+    ///
+    /// Returns [IoError::EndOfFile] if `offset` is beyond the end of the device.
+    pub async fn new(id: BlockDeviceId, offset: u64) -> Result<Self, IoError> {
+        let dev = crate::system::sysfs::get_sysfs().get_blk_dev().fetch(id).ok_or(IoError::NotPresent)?;
+        let geom = dev.geom().await?;
+        if offset >= geom.blocks {
+            return Err(IoError::EndOfFile);
+        }
+        Ok(Self { dev, offset, geom })
+    }
+}
+
+impl Target for Linear {
+    fn read(&self, offset: u64, count: u64) -> IoFut<Box<[u8]>> {
+        self.dev.read(self.offset + offset, count as usize)
+    }
+
+    fn write<'a>(&'a self, offset: u64, data: &'a [u8]) -> IoFut<'a, ()> {
+        async move {
+            self.dev.write(self.offset + offset, IoBuffer::new(Box::from(data))).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn flush(&self) -> IoFut<()> {
+        self.dev.flush()
+    }
+
+    fn blocks(&self) -> u64 {
+        self.geom.blocks - self.offset
+    }
+
+    fn block_size(&self) -> u64 {
+        self.geom.block_size
+    }
+
+    fn max_transfer(&self) -> u64 {
+        self.geom.max_blocks_per_transfer
+    }
+
+    fn name(&self) -> &'static str {
+        "linear"
+    }
+}
@@ -0,0 +1,234 @@
+//! Block encryption target.
+//!
+//! [Crypt] encrypts each block of the device it maps onto using AES-XTS with the block number as
+//! the tweak, so a filesystem on a mapped device containing a [Crypt] target is stored encrypted.
+//!
+//! A device is prepared using [format] which writes a header to the first [HEADER_SIZE] bytes of
+//! the device, encrypted data follows the header. The header contains the salt and iteration
+//! count used to derive the key from a passphrase using PBKDF2-HMAC-SHA256 and a digest used to
+//! check the passphrase. The derived key encrypts the data directly so the passphrase can not be
+//! changed without re-encrypting the device. [Crypt::with_key] maps a device without a header
+//! using a raw key.
+
+use super::Target;
+use crate::fs::IoError;
+use crate::system::sysfs::block::{BlockDevGeom, BlockDevIoErr, BlockDeviceId, IoBuffer, IoFut, SysFsBlockDevice};
+use crate::crypto::hmac::{hmac_sha256, pbkdf2_sha256};
+use crate::crypto::sha256;
+use crate::crypto::xts::Xts;
+use alloc::boxed::Box;
+use futures_util::FutureExt;
+
+const MAGIC: [u8; 8] = *b"HootuxCR";
+const VERSION: u32 = 1;
+/// Bytes reserved at the start of the device for the header.
+pub const HEADER_SIZE: u64 = 4096;
+const SALT_SIZE: usize = 32;
+/// Message authenticated by the derived key to check the passphrase.
+const KEY_CHECK: &[u8] = b"hootux crypt key check";
+
+/// Configuration used by [format].
+#[derive(Copy, Clone, Debug)]
+pub struct CryptConfig {
+    /// The XTS key size in bytes, 32 for AES-128 or 64 for AES-256.
+    pub key_size: usize,
+    /// PBKDF2 iterations used to derive the key from the passphrase.
+    pub iterations: u32,
+}
+
+impl Default for CryptConfig {
+    fn default() -> Self {
+        Self { key_size: 64, iterations: 100_000 }
+    }
+}
+
+struct Header {
+    key_size: usize,
+    iterations: u32,
+    salt: [u8; SALT_SIZE],
+    check: [u8; sha256::DIGEST_SIZE],
+}
+
+impl Header {
+    fn encode(&self, buff: &mut [u8]) {
+        buff[0..8].copy_from_slice(&MAGIC);
+        buff[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        buff[12..16].copy_from_slice(&(self.key_size as u32).to_le_bytes());
+        buff[16..20].copy_from_slice(&self.iterations.to_le_bytes());
+        buff[24..56].copy_from_slice(&self.salt);
+        buff[56..88].copy_from_slice(&self.check);
+    }
+
+    fn decode(buff: &[u8]) -> Option<Self> {
+        if buff.len() < 88 || buff[0..8] != MAGIC || buff[8..12] != VERSION.to_le_bytes() {
+            return None;
+        }
+        let key_size = u32::from_le_bytes(buff[12..16].try_into().unwrap()) as usize;
+        if key_size != 32 && key_size != 64 {
+            return None;
+        }
+        Some(Self {
+            key_size,
+            iterations: u32::from_le_bytes(buff[16..20].try_into().unwrap()),
+            salt: buff[24..56].try_into().unwrap(),
+            check: buff[56..88].try_into().unwrap(),
+        })
+    }
+
+    /// Derives the key from `passphrase`, the key is cleared when the returned buffer is dropped.
+    fn derive(&self, passphrase: &[u8]) -> KeyBuff {
+        let mut key = KeyBuff([0; 64], self.key_size);
+        pbkdf2_sha256(passphrase, &self.salt, self.iterations, key.key_mut());
+        key
+    }
+}
+
+/// Holds key material, the key is cleared when this is dropped.
+struct KeyBuff([u8; 64], usize);
+
+impl KeyBuff {
+    fn key_mut(&mut self) -> &mut [u8] {
+        &mut self.0[..self.1]
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.0[..self.1]
+    }
+}
+
+impl Drop for KeyBuff {
+    fn drop(&mut self) {
+        for b in &mut self.0 {
+            // SAFETY: b is a valid reference
+            unsafe { core::ptr::write_volatile(b, 0) };
+        }
+    }
+}
+
+fn fetch(id: BlockDeviceId) -> Result<Box<dyn SysFsBlockDevice>, IoError> {
+    crate::system::sysfs::get_sysfs().get_blk_dev().fetch(id).ok_or(IoError::NotPresent)
+}
+
+/// Writes a new header to the device `id` keyed from `passphrase`.
+///
+/// Any data previously encrypted on the device can no longer be decrypted. Deriving the key takes
+/// a significant amount of CPU time.
+pub async fn format(id: BlockDeviceId, passphrase: &[u8], config: CryptConfig) -> Result<(), IoError> {
+    if (config.key_size != 32 && config.key_size != 64) || config.iterations == 0 {
+        return Err(IoError::InvalidData);
+    }
+    let dev = fetch(id)?;
+    let geom = dev.geom().await?;
+    if geom.blocks <= HEADER_SIZE.div_ceil(geom.block_size) {
+        return Err(IoError::EndOfFile);
+    }
+
+    let mut header = Header { key_size: config.key_size, iterations: config.iterations, salt: [0; SALT_SIZE], check: [0; sha256::DIGEST_SIZE] };
+    crate::fs::disk_util::random_bytes(&mut header.salt);
+    header.check = hmac_sha256(header.derive(passphrase).key(), KEY_CHECK);
+
+    let blocks = HEADER_SIZE.div_ceil(geom.block_size);
+    let mut buff = alloc::vec![0u8; (blocks * geom.block_size) as usize].into_boxed_slice();
+    header.encode(&mut buff);
+    dev.write(0, IoBuffer::new(buff)).await?;
+    log::info!("crypt: formatted {id}");
+    Ok(())
+}
+
+/// Encrypts blocks using AES-XTS.
+pub struct Crypt {
+    dev: Box<dyn SysFsBlockDevice>,
+    /// First block of encrypted data on `dev`.
+    data_offset: u64,
+    geom: BlockDevGeom,
+    cipher: Xts,
+}
+
+impl Crypt {
+    /// Opens the device `id` which was prepared using [format].
+    ///
+    /// Returns [IoError::InvalidData] if the device does not contain a header and
+    /// [IoError::PermissionDenied] if `passphrase` is incorrect.
+    pub async fn open(id: BlockDeviceId, passphrase: &[u8]) -> Result<Self, IoError> {
+        let dev = fetch(id)?;
+        let geom = dev.geom().await?;
+        let header_blocks = HEADER_SIZE.div_ceil(geom.block_size);
+        let raw = dev.read(0, header_blocks as usize).await?;
+        let header = Header::decode(&raw).ok_or(IoError::InvalidData)?;
+
+        let key = header.derive(passphrase);
+        if hmac_sha256(key.key(), KEY_CHECK) != header.check {
+            return Err(IoError::PermissionDenied);
+        }
+        let cipher = Xts::new(key.key()).map_err(|_| IoError::InvalidData)?;
+        Self::new(dev, geom, header_blocks, cipher)
+    }
+
+    /// Maps the device `id` starting at block `offset` using `key` directly, the device does not
+    /// contain a header. `key` must be 32 or 64 bytes.
+    pub async fn with_key(id: BlockDeviceId, offset: u64, key: &[u8]) -> Result<Self, IoError> {
+        let dev = fetch(id)?;
+        let geom = dev.geom().await?;
+        let cipher = Xts::new(key).map_err(|_| IoError::InvalidData)?;
+        Self::new(dev, geom, offset, cipher)
+    }
+
+    fn new(dev: Box<dyn SysFsBlockDevice>, geom: BlockDevGeom, data_offset: u64, cipher: Xts) -> Result<Self, IoError> {
+        if geom.block_size as usize % crate::crypto::aes::BLOCK_SIZE != 0 {
+            return Err(IoError::NotSupported);
+        }
+        if data_offset >= geom.blocks {
+            return Err(IoError::EndOfFile);
+        }
+        Ok(Self { dev, data_offset, geom, cipher })
+    }
+}
+
+impl Target for Crypt {
+    fn read(&self, offset: u64, count: u64) -> IoFut<Box<[u8]>> {
+        async move {
+            let mut data = self.dev.read(self.data_offset + offset, count as usize).await?;
+            let bs = self.geom.block_size as usize;
+            if data.len() != count as usize * bs {
+                return Err(BlockDevIoErr::InternalDriverErr);
+            }
+            for (i, block) in data.chunks_exact_mut(bs).enumerate() {
+                self.cipher.decrypt_sector(offset + i as u64, block);
+            }
+            Ok(data)
+        }
+        .boxed()
+    }
+
+    fn write<'a>(&'a self, offset: u64, data: &'a [u8]) -> IoFut<'a, ()> {
+        async move {
+            let mut buff: Box<[u8]> = Box::from(data);
+            for (i, block) in buff.chunks_exact_mut(self.geom.block_size as usize).enumerate() {
+                self.cipher.encrypt_sector(offset + i as u64, block);
+            }
+            self.dev.write(self.data_offset + offset, IoBuffer::new(buff)).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn flush(&self) -> IoFut<()> {
+        self.dev.flush()
+    }
+
+    fn blocks(&self) -> u64 {
+        self.geom.blocks - self.data_offset
+    }
+
+    fn block_size(&self) -> u64 {
+        self.geom.block_size
+    }
+
+    fn max_transfer(&self) -> u64 {
+        self.geom.max_blocks_per_transfer
+    }
+
+    fn name(&self) -> &'static str {
+        "crypt"
+    }
+}
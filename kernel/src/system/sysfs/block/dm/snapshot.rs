@@ -0,0 +1,410 @@
+//! Copy-on-write snapshots.
+//!
+//! An [ExceptionStore] records the chunks of an origin device which have changed since the
+//! snapshot was taken. Before a chunk of the origin is first modified it is copied to the COW
+//! device and an exception mapping the origin chunk to the copy is recorded. [Snapshot] presents
+//! the origin as it was when the store was created by reading exceptions from the COW device, it
+//! may also be written to which creates exceptions without modifying the origin. All writes to the
+//! origin must be made through [Origin] while the snapshot exists.
+//!
+//! A persistent store records exceptions on the COW device so that the snapshot can be reopened.
+//! The first chunk of the COW device contains a header, it is followed by metadata chunks each
+//! containing a list of `(origin chunk, COW chunk)` exceptions followed by the data chunks they
+//! refer to. A record with a COW chunk of `0` ends the list. A transient store keeps exceptions
+//! in memory and uses the whole COW device for data.
+//!
+//! When the COW device is full the snapshot is invalidated, I/O to the snapshot fails while the
+//! origin continues to operate.
+
+use super::Target;
+use crate::fs::IoError;
+use crate::system::sysfs::block::{BlockDevIoErr, BlockDeviceId, IoBuffer, IoFut, SysFsBlockDevice};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use futures_util::FutureExt;
+
+const MAGIC: [u8; 8] = *b"HootuxSN";
+const VERSION: u32 = 1;
+/// Size of an exception record in a metadata chunk.
+const RECORD_SIZE: usize = 16;
+
+/// Configuration for a new exception store.
+#[derive(Copy, Clone, Debug)]
+pub struct SnapshotConfig {
+    /// The number of bytes copied when a chunk is first modified, this must be a power of two and a
+    /// multiple of the block size.
+    pub chunk_size: u64,
+    /// Records exceptions on the COW device.
+    pub persistent: bool,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self { chunk_size: 0x1000, persistent: true }
+    }
+}
+
+/// Records the chunks of an origin device which have been copied to a COW device.
+pub struct ExceptionStore {
+    origin: Box<dyn SysFsBlockDevice>,
+    cow: Box<dyn SysFsBlockDevice>,
+    chunk_blocks: u64,
+    block_size: u64,
+    origin_blocks: u64,
+    cow_chunks: u64,
+    max_transfer: u64,
+    persistent: bool,
+    /// Snapshot reads hold this shared, anything which may create an exception holds it exclusively.
+    state: async_lock::RwLock<StoreState>,
+}
+
+struct StoreState {
+    exceptions: BTreeMap<u64, u64>,
+    /// The next free COW chunk for transient stores.
+    next: u64,
+    /// The current metadata chunk for persistent stores and its contents.
+    area_chunk: u64,
+    area: Box<[u8]>,
+    area_used: usize,
+    invalid: bool,
+}
+
+impl ExceptionStore {
+    /// Opens a snapshot of the device `origin` using `cow` to store exceptions.
+    ///
+    /// If the store is persistent and `cow` contains a store with the same chunk size its
+    /// exceptions are loaded, otherwise a new store is created. Returns [IoError::InvalidData] if
+    /// the devices do not use the same block size or the chunk size is invalid.
+    pub async fn open(origin: BlockDeviceId, cow: BlockDeviceId, config: SnapshotConfig) -> Result<Arc<Self>, IoError> {
+        let list = crate::system::sysfs::get_sysfs().get_blk_dev();
+        let origin = list.fetch(origin).ok_or(IoError::NotPresent)?;
+        let cow = list.fetch(cow).ok_or(IoError::NotPresent)?;
+        let og = origin.geom().await?;
+        let cg = cow.geom().await?;
+        let bs = og.block_size;
+        if cg.block_size != bs || !config.chunk_size.is_power_of_two() || config.chunk_size % bs != 0 {
+            return Err(IoError::InvalidData);
+        }
+        let chunk_blocks = config.chunk_size / bs;
+        let max_transfer = og.max_blocks_per_transfer.min(cg.max_blocks_per_transfer);
+        if chunk_blocks > max_transfer || (config.persistent && (config.chunk_size as usize) < RECORD_SIZE) {
+            return Err(IoError::InvalidData);
+        }
+        let cow_chunks = cg.blocks / chunk_blocks;
+        // Persistent stores require a header and a metadata chunk
+        if cow_chunks < if config.persistent { 3 } else { 1 } {
+            return Err(IoError::EndOfFile);
+        }
+
+        let store = Self {
+            origin,
+            cow,
+            chunk_blocks,
+            block_size: bs,
+            origin_blocks: og.blocks,
+            cow_chunks,
+            max_transfer,
+            persistent: config.persistent,
+            state: async_lock::RwLock::new(StoreState {
+                exceptions: BTreeMap::new(),
+                next: 0,
+                area_chunk: 1,
+                area: alloc::vec![0u8; config.chunk_size as usize].into_boxed_slice(),
+                area_used: 0,
+                invalid: false,
+            }),
+        };
+        if config.persistent {
+            store.load().await?;
+        }
+        Ok(Arc::new(store))
+    }
+
+    fn chunk_bytes(&self) -> usize {
+        (self.chunk_blocks * self.block_size) as usize
+    }
+
+    /// Number of exception records in each metadata chunk.
+    fn per_area(&self) -> usize {
+        self.chunk_bytes() / RECORD_SIZE
+    }
+
+    /// Returns the number of COW chunks used and the size of the COW device in chunks.
+    pub async fn usage(&self) -> (u64, u64) {
+        let l = self.state.read().await;
+        let used = if self.persistent { l.area_chunk + 1 + l.area_used as u64 } else { l.next };
+        (used.min(self.cow_chunks), self.cow_chunks)
+    }
+
+    /// Returns whether the snapshot was invalidated because the COW device is full.
+    pub async fn is_invalid(&self) -> bool {
+        self.state.read().await.invalid
+    }
+
+    async fn read_chunk(dev: &dyn SysFsBlockDevice, chunk: u64, chunk_blocks: u64) -> Result<Box<[u8]>, BlockDevIoErr> {
+        dev.read(chunk * chunk_blocks, chunk_blocks as usize).await
+    }
+
+    async fn write_chunk(&self, chunk: u64, data: &[u8]) -> Result<(), BlockDevIoErr> {
+        self.cow.write(chunk * self.chunk_blocks, IoBuffer::new(Box::from(data))).await?;
+        Ok(())
+    }
+
+    /// Returns the header chunk of a persistent store.
+    fn header(&self, invalid: bool) -> Vec<u8> {
+        let mut header = alloc::vec![0u8; self.chunk_bytes()];
+        header[0..8].copy_from_slice(&MAGIC);
+        header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        header[12] = invalid as u8;
+        header[16..24].copy_from_slice(&self.chunk_blocks.to_le_bytes());
+        header
+    }
+
+    /// Loads the exceptions from the COW device, initialising it if it does not contain a store.
+    async fn load(&self) -> Result<(), BlockDevIoErr> {
+        let mut l = self.state.write().await;
+        let header = Self::read_chunk(&*self.cow, 0, self.chunk_blocks).await?;
+        let valid = header[0..8] == MAGIC
+            && header[8..12] == VERSION.to_le_bytes()
+            && header[16..24] == self.chunk_blocks.to_le_bytes();
+        if !valid {
+            self.write_chunk(1, &l.area).await?;
+            self.write_chunk(0, &self.header(false)).await?;
+            log::info!("snapshot: initialised exception store on {}", self.cow.get_id());
+            return Ok(());
+        }
+        if header[12] != 0 {
+            l.invalid = true;
+        }
+
+        let per_area = self.per_area();
+        loop {
+            let area = Self::read_chunk(&*self.cow, l.area_chunk, self.chunk_blocks).await?;
+            let mut used = 0;
+            for r in area.chunks_exact(RECORD_SIZE) {
+                let cow_chunk = u64::from_le_bytes(r[8..16].try_into().unwrap());
+                if cow_chunk == 0 {
+                    break;
+                }
+                l.exceptions.insert(u64::from_le_bytes(r[0..8].try_into().unwrap()), cow_chunk);
+                used += 1;
+            }
+            l.area.copy_from_slice(&area);
+            l.area_used = used;
+            let next = l.area_chunk + per_area as u64 + 1;
+            if used < per_area || next >= self.cow_chunks {
+                break;
+            }
+            l.area_chunk = next;
+            l.area.fill(0);
+            l.area_used = 0;
+        }
+        log::info!("snapshot: loaded {} exceptions from {}", l.exceptions.len(), self.cow.get_id());
+        Ok(())
+    }
+
+    /// Allocates a COW chunk, returns `None` if the COW device is full.
+    fn alloc(&self, l: &mut StoreState) -> Option<u64> {
+        if !self.persistent {
+            let c = l.next;
+            l.next += 1;
+            return (c < self.cow_chunks).then_some(c);
+        }
+        if l.area_used == self.per_area() {
+            l.area_chunk += self.per_area() as u64 + 1;
+            l.area.fill(0);
+            l.area_used = 0;
+        }
+        let c = l.area_chunk + 1 + l.area_used as u64;
+        (c < self.cow_chunks).then_some(c)
+    }
+
+    /// Marks the snapshot as invalid once the COW device is full.
+    async fn invalidate(&self, l: &mut StoreState) {
+        if l.invalid {
+            return;
+        }
+        l.invalid = true;
+        l.exceptions.clear();
+        log::error!("snapshot: {} is full, snapshot of {} invalidated", self.cow.get_id(), self.origin.get_id());
+        if self.persistent {
+            if let Err(e) = self.write_chunk(0, &self.header(true)).await {
+                log::warn!("snapshot: failed to mark {} invalid: {e:?}", self.cow.get_id());
+            }
+        }
+    }
+
+    /// Copies origin chunk `chunk` to the COW device unless it already has an exception.
+    ///
+    /// Returns the COW chunk or `None` if the store is invalid.
+    async fn copy_out(&self, l: &mut StoreState, chunk: u64) -> Result<Option<u64>, BlockDevIoErr> {
+        if l.invalid {
+            return Ok(None);
+        }
+        if let Some(c) = l.exceptions.get(&chunk) {
+            return Ok(Some(*c));
+        }
+        let Some(cow_chunk) = self.alloc(l) else {
+            self.invalidate(l).await;
+            return Ok(None);
+        };
+
+        // The last chunk of the origin may be partial
+        let start = chunk * self.chunk_blocks;
+        let count = self.chunk_blocks.min(self.origin_blocks - start);
+        let mut data = self.origin.read(start, count as usize).await?.into_vec();
+        data.resize(self.chunk_bytes(), 0);
+        self.write_chunk(cow_chunk, &data).await?;
+
+        if self.persistent {
+            let r = l.area_used * RECORD_SIZE;
+            l.area[r..r + 8].copy_from_slice(&chunk.to_le_bytes());
+            l.area[r + 8..r + 16].copy_from_slice(&cow_chunk.to_le_bytes());
+            l.area_used += 1;
+            // The data is written before the record which refers to it
+            self.write_chunk(l.area_chunk, &l.area).await?;
+        }
+        l.exceptions.insert(chunk, cow_chunk);
+        Ok(Some(cow_chunk))
+    }
+
+    /// Splits a request into `(chunk, block within the chunk, count, offset into the data)` segments.
+    fn chunks(&self, offset: u64, count: u64) -> impl Iterator<Item = (u64, u64, u64, usize)> {
+        let cb = self.chunk_blocks;
+        let bs = self.block_size as usize;
+        let mut block = offset;
+        core::iter::from_fn(move || {
+            if block >= offset + count {
+                return None;
+            }
+            let len = (cb - block % cb).min(offset + count - block);
+            let seg = (block / cb, block % cb, len, (block - offset) as usize * bs);
+            block += len;
+            Some(seg)
+        })
+    }
+
+    fn check_range(&self, offset: u64, count: u64) -> Result<(), BlockDevIoErr> {
+        match offset.checked_add(count) {
+            Some(end) if end <= self.origin_blocks => Ok(()),
+            _ => Err(BlockDevIoErr::OutOfRange),
+        }
+    }
+}
+
+/// Presents the origin of an [ExceptionStore] as it was when the store was created.
+pub struct Snapshot(pub Arc<ExceptionStore>);
+
+impl Target for Snapshot {
+    fn read(&self, offset: u64, count: u64) -> IoFut<Box<[u8]>> {
+        async move {
+            let s = &self.0;
+            s.check_range(offset, count)?;
+            let l = s.state.read().await;
+            if l.invalid {
+                return Err(BlockDevIoErr::HardwareError);
+            }
+            let mut out = Vec::with_capacity((count * s.block_size) as usize);
+            for (chunk, off, len, _) in s.chunks(offset, count) {
+                let data = match l.exceptions.get(&chunk) {
+                    Some(c) => s.cow.read(c * s.chunk_blocks + off, len as usize).await?,
+                    None => s.origin.read(chunk * s.chunk_blocks + off, len as usize).await?,
+                };
+                out.extend_from_slice(&data);
+            }
+            Ok(out.into_boxed_slice())
+        }
+        .boxed()
+    }
+
+    fn write<'a>(&'a self, offset: u64, data: &'a [u8]) -> IoFut<'a, ()> {
+        async move {
+            let s = &self.0;
+            let count = data.len() as u64 / s.block_size;
+            s.check_range(offset, count)?;
+            let mut l = s.state.write().await;
+            for (chunk, off, len, pos) in s.chunks(offset, count) {
+                let c = s.copy_out(&mut l, chunk).await?.ok_or(BlockDevIoErr::HardwareError)?;
+                let part = &data[pos..pos + (len * s.block_size) as usize];
+                s.cow.write(c * s.chunk_blocks + off, IoBuffer::new(Box::from(part))).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn flush(&self) -> IoFut<()> {
+        self.0.cow.flush()
+    }
+
+    fn blocks(&self) -> u64 {
+        self.0.origin_blocks
+    }
+
+    fn block_size(&self) -> u64 {
+        self.0.block_size
+    }
+
+    fn max_transfer(&self) -> u64 {
+        self.0.max_transfer
+    }
+
+    fn name(&self) -> &'static str {
+        "snapshot"
+    }
+}
+
+/// Maps onto the origin of an [ExceptionStore], chunks are copied to the store before they are
+/// first written.
+pub struct Origin(pub Arc<ExceptionStore>);
+
+impl Target for Origin {
+    fn read(&self, offset: u64, count: u64) -> IoFut<Box<[u8]>> {
+        async move {
+            self.0.check_range(offset, count)?;
+            self.0.origin.read(offset, count as usize).await
+        }
+        .boxed()
+    }
+
+    fn write<'a>(&'a self, offset: u64, data: &'a [u8]) -> IoFut<'a, ()> {
+        async move {
+            let s = &self.0;
+            let count = data.len() as u64 / s.block_size;
+            s.check_range(offset, count)?;
+            let mut l = s.state.write().await;
+            for (chunk, ..) in s.chunks(offset, count) {
+                s.copy_out(&mut l, chunk).await?;
+            }
+            s.origin.write(offset, IoBuffer::new(Box::from(data))).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn flush(&self) -> IoFut<()> {
+        async move {
+            self.0.cow.flush().await?;
+            self.0.origin.flush().await
+        }
+        .boxed()
+    }
+
+    fn blocks(&self) -> u64 {
+        self.0.origin_blocks
+    }
+
+    fn block_size(&self) -> u64 {
+        self.0.block_size
+    }
+
+    fn max_transfer(&self) -> u64 {
+        self.0.max_transfer
+    }
+
+    fn name(&self) -> &'static str {
+        "snapshot-origin"
+    }
+}
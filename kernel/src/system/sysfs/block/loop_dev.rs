@@ -0,0 +1,217 @@
+//! Loop devices present a file as a block device.
+//!
+//! A loop device is attached to a [NormalFile] using [attach], it is then registered in the
+//! [super::BlockDeviceList] like any other block device and is given a `loop` node in the devfs.
+//! This allows filesystem images stored within files to be accessed by anything which uses block
+//! devices.
+//!
+//! The size of the device is fixed when it is attached, trailing bytes which do not fill a whole
+//! block are not accessible. A read-only loop device returns [BlockDevIoErr::ReadOnly] for all writes.
+
+use super::{BlockDev, BlockDevGeom, BlockDevGeomIntegral, BlockDevIoErr, BlockDeviceId, IoBuffer, IoFut, SysFsBlockDevice};
+use crate::fs::devfs::naming::DeviceClass;
+use crate::fs::disk_util::{read_exact, write_all};
+use crate::fs::file::NormalFile;
+use crate::fs::IoError;
+use crate::mem::dma::{DmaBuff, DmaGuard};
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use futures_util::FutureExt;
+
+const DRIVER_NAME: &str = "loop";
+
+/// Largest transfer advertised in the device geometry.
+const MAX_TRANSFER: u64 = 1024 * 1024;
+
+/// Instance numbers currently in use.
+static INSTANCES: spin::Mutex<BTreeSet<usize>> = spin::Mutex::new(BTreeSet::new());
+
+/// Configuration for a loop device.
+#[derive(Copy, Clone, Debug)]
+pub struct LoopConfig {
+    /// The block size of the device, this must be a power of two between 512 and 65536.
+    pub block_size: u64,
+    /// Disallows writes to the device.
+    pub read_only: bool,
+}
+
+impl Default for LoopConfig {
+    fn default() -> Self {
+        Self { block_size: 512, read_only: false }
+    }
+}
+
+/// A block device backed by a file.
+#[derive(Clone)]
+pub struct LoopDevice {
+    inner: Arc<LoopInner>,
+}
+
+struct LoopInner {
+    file: Box<dyn NormalFile<u8>>,
+    id: BlockDeviceId,
+    instance: usize,
+    blocks: u64,
+    config: LoopConfig,
+    offline: AtomicBool,
+}
+
+impl Drop for LoopInner {
+    fn drop(&mut self) {
+        INSTANCES.lock().remove(&self.instance);
+    }
+}
+
+/// Attaches `file` to a new loop device and registers it as a block device.
+///
+/// Returns [IoError::NotSupported] if the block size is invalid, or [IoError::EndOfFile] if the
+/// file is smaller than one block.
+pub async fn attach(file: Box<dyn NormalFile<u8>>, config: LoopConfig) -> Result<BlockDeviceId, IoError> {
+    if !config.block_size.is_power_of_two() || !(512..=65536).contains(&config.block_size) {
+        return Err(IoError::NotSupported);
+    }
+    let blocks = file.len().await? / config.block_size;
+    if blocks == 0 {
+        return Err(IoError::EndOfFile);
+    }
+
+    let instance = {
+        let mut l = INSTANCES.lock();
+        let i = (0..).find(|i| !l.contains(i)).unwrap(); // the set is finite
+        l.insert(i);
+        i
+    };
+    let id = BlockDeviceId::new(DRIVER_NAME, instance, None);
+    let dev = LoopDevice {
+        inner: Arc::new(LoopInner { file, id, instance, blocks, config, offline: AtomicBool::new(false) }),
+    };
+    log::info!("Attached loop device {id}, {blocks} blocks of {} bytes", config.block_size);
+    // The instance is unique so this can't already be registered
+    let _ = crate::system::sysfs::get_sysfs().get_blk_dev().register_dev(Box::new(dev));
+    Ok(id)
+}
+
+/// Removes the loop device `id` from the block device list.
+///
+/// All clones of the device go offline immediately, the file is released once they are dropped.
+/// Returns [IoError::NotPresent] if `id` is not a loop device.
+pub fn detach(id: BlockDeviceId) -> Result<(), IoError> {
+    let list = crate::system::sysfs::get_sysfs().get_blk_dev();
+    let dev = list.fetch(id).ok_or(IoError::NotPresent)?;
+    let dev = dev.as_any().downcast_ref::<LoopDevice>().ok_or(IoError::NotPresent)?;
+    dev.inner.offline.store(true, Ordering::Release);
+    list.remove_dev(id);
+    log::info!("Detached loop device {id}");
+    Ok(())
+}
+
+impl LoopDevice {
+    pub fn config(&self) -> LoopConfig {
+        self.inner.config
+    }
+
+    /// Checks that the device is online and that `count` blocks at `seek` are within the device,
+    /// returns the byte offset and length of the range.
+    fn range(&self, seek: BlockDevGeomIntegral, count: u64) -> Result<(u64, usize), BlockDevIoErr> {
+        if self.inner.offline.load(Ordering::Acquire) {
+            return Err(BlockDevIoErr::DeviceOffline);
+        }
+        match seek.checked_add(count) {
+            Some(end) if end <= self.inner.blocks => {}
+            _ => return Err(BlockDevIoErr::OutOfRange),
+        }
+        Ok((seek * self.inner.config.block_size, (count * self.inner.config.block_size) as usize))
+    }
+}
+
+fn map_err(e: IoError) -> BlockDevIoErr {
+    match e {
+        IoError::EndOfFile => BlockDevIoErr::OutOfRange,
+        IoError::NotPresent => BlockDevIoErr::DeviceOffline,
+        IoError::ReadOnly | IoError::PermissionDenied => BlockDevIoErr::ReadOnly,
+        _ => BlockDevIoErr::HardwareError,
+    }
+}
+
+impl BlockDev for LoopDevice {
+    fn read(&self, seek: BlockDevGeomIntegral, size: usize) -> IoFut<Box<[u8]>> {
+        async move {
+            let (pos, len) = self.range(seek, size as u64)?;
+            // EndOfFile is returned when the file was truncated after the device was attached
+            let out = read_exact(&*self.inner.file, pos, len).await.map_err(map_err)?;
+            Ok(out.into_boxed_slice())
+        }
+        .boxed()
+    }
+
+    fn write(&self, seek: BlockDevGeomIntegral, buff: IoBuffer) -> IoFut<IoBuffer> {
+        async move {
+            if self.inner.config.read_only {
+                return Err(BlockDevIoErr::ReadOnly);
+            }
+            if buff.len() as u64 % self.inner.config.block_size != 0 {
+                return Err(BlockDevIoErr::GeomError);
+            }
+            let (pos, _) = self.range(seek, buff.len() as u64 / self.inner.config.block_size)?;
+            let data: DmaBuff<'static> = Box::new(DmaGuard::from(Vec::from(buff.buff())));
+            write_all(&*self.inner.file, pos, data).await.map_err(|(e, _)| map_err(e))?;
+            Ok(buff)
+        }
+        .boxed()
+    }
+
+    /// Syncs the backing file.
+    fn flush(&self) -> IoFut<()> {
+        async move {
+            if self.inner.offline.load(Ordering::Acquire) {
+                return Err(BlockDevIoErr::DeviceOffline);
+            }
+            self.inner.file.sync(false).await.map_err(map_err)
+        }
+        .boxed()
+    }
+
+    fn geom(&self) -> IoFut<BlockDevGeom> {
+        let bs = self.inner.config.block_size;
+        let geom = BlockDevGeom {
+            blocks: self.inner.blocks,
+            block_size: bs,
+            optimal_block_size: bs,
+            optimal_alignment: 0,
+            max_blocks_per_transfer: (MAX_TRANSFER / bs).max(1),
+            req_data_alignment: 1,
+        };
+        async move {
+            if self.inner.offline.load(Ordering::Acquire) {
+                return Err(BlockDevIoErr::DeviceOffline);
+            }
+            Ok(geom)
+        }
+        .boxed()
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+
+    fn b_clone(&self) -> Box<dyn BlockDev> {
+        Box::new(self.clone())
+    }
+}
+
+impl SysFsBlockDevice for LoopDevice {
+    fn get_id(&self) -> BlockDeviceId {
+        self.inner.id
+    }
+
+    fn device_class(&self) -> DeviceClass {
+        DeviceClass::Loop
+    }
+
+    fn s_clone(&self) -> Box<dyn SysFsBlockDevice> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,1182 @@
+//! Software RAID.
+//!
+//! An md device combines member block devices into a single block device which either stripes
+//! blocks across its members ([RaidLevel::Raid0]) or mirrors them ([RaidLevel::Raid1]).
+//!
+//! The first [DATA_OFFSET] bytes of each member are reserved for a superblock describing the
+//! array, array data follows it. Arrays are created using [create] and are re-assembled from their
+//! superblocks using [assemble]. The superblock contains an event counter which is incremented
+//! whenever the membership of the array changes, members with an outdated counter are detected
+//! when the array is assembled.
+//!
+//! A RAID1 array continues to operate while at least one member is in sync. Members which fail
+//! are dropped from the array and may be replaced using [add_member], new members are
+//! resynchronised in the background by copying from an in-sync member. Only in-sync members are
+//! read from, writes go to every member which has not failed. A RAID0 array has no redundancy and
+//! fails when any member fails.
+//!
+//! RAID1 members are given an error recovery limit of [ERROR_RECOVERY_MS] when they join the
+//! array, see [BlockDev::set_error_recovery], so that a failing sector is read from another member
+//! instead of stalling the array while the drive retries it. Zeroing the array is passed on to the
+//! members using [BlockDev::write_zeroes].
+
+use super::{BlockDev, BlockDevGeom, BlockDevGeomIntegral, BlockDevIoErr, BlockDeviceId, IoBuffer, IoFut, SysFsBlockDevice};
+use crate::error::KernelError;
+use crate::fs::devfs::naming::DeviceClass;
+use crate::fs::disk_util::gpt::Guid;
+use crate::fs::IoError;
+use crate::task::wait::{Annotate, WaitPoint};
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+use futures_util::FutureExt;
+
+const DRIVER_NAME: &str = "md";
+
+const MAGIC: [u8; 8] = *b"HootuxMD";
+const SB_VERSION: u32 = 1;
+/// Number of bytes of the superblock covered by its checksum, the checksum follows.
+const SB_LEN: usize = 124;
+/// Bytes reserved at the start of each member for the superblock.
+pub const DATA_OFFSET: u64 = 4096;
+
+pub const MIN_MEMBERS: usize = 2;
+/// The in-sync set is stored as a bitmap.
+pub const MAX_MEMBERS: usize = 32;
+
+/// Number of bytes copied by each resync step.
+const RESYNC_CHUNK: u64 = 0x10000;
+/// Number of resync steps between writing the resync position to the superblocks.
+const RESYNC_CHECKPOINT: u64 = 256;
+/// Time to wait before retrying a resync step which was interrupted by a write.
+const RESYNC_RETRY_MSEC: u64 = 10;
+/// Error recovery limit for RAID1 members in milliseconds, for both reads and writes.
+pub const ERROR_RECOVERY_MS: u32 = 7000;
+
+/// Instance numbers currently in use.
+static INSTANCES: spin::Mutex<BTreeSet<usize>> = spin::Mutex::new(BTreeSet::new());
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RaidLevel {
+    /// Blocks are striped across all members in units of [MdConfig::chunk_size].
+    Raid0,
+    /// Every member contains a copy of the data.
+    Raid1,
+}
+
+impl RaidLevel {
+    fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0 => Some(Self::Raid0),
+            1 => Some(Self::Raid1),
+            _ => None,
+        }
+    }
+
+    fn raw(self) -> u32 {
+        match self {
+            Self::Raid0 => 0,
+            Self::Raid1 => 1,
+        }
+    }
+}
+
+/// Configuration for a new array.
+#[derive(Copy, Clone, Debug)]
+pub struct MdConfig {
+    pub level: RaidLevel,
+    /// The number of bytes written to a member before moving to the next member, only used by
+    /// [RaidLevel::Raid0]. This must be a power of two and a multiple of the member block size.
+    pub chunk_size: u64,
+}
+
+impl Default for MdConfig {
+    fn default() -> Self {
+        Self { level: RaidLevel::Raid1, chunk_size: 0x10000 }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MemberState {
+    InSync,
+    /// The member is being resynchronised and is not read from.
+    Resyncing,
+    /// The member has failed or is missing and is not accessed.
+    Failed,
+}
+
+/// On-disk array description, one copy is stored at the start of each member.
+#[derive(Copy, Clone, Debug)]
+struct Superblock {
+    uuid: Guid,
+    level: RaidLevel,
+    members: u32,
+    /// Index of the member this copy is stored on.
+    index: u32,
+    chunk_blocks: u64,
+    block_size: u64,
+    /// Number of data blocks used on each member.
+    member_blocks: u64,
+    events: u64,
+    /// Bitmap of members which are in sync.
+    in_sync: u32,
+    /// Number of blocks resynchronised to members which are not in sync.
+    resync_pos: u64,
+}
+
+impl Superblock {
+    fn encode(&self, buff: &mut [u8]) {
+        buff[..SB_LEN + 4].fill(0);
+        buff[0..8].copy_from_slice(&MAGIC);
+        buff[8..12].copy_from_slice(&SB_VERSION.to_le_bytes());
+        buff[12..16].copy_from_slice(&self.level.raw().to_le_bytes());
+        buff[16..32].copy_from_slice(self.uuid.as_bytes());
+        buff[32..36].copy_from_slice(&self.members.to_le_bytes());
+        buff[36..40].copy_from_slice(&self.index.to_le_bytes());
+        buff[40..48].copy_from_slice(&self.chunk_blocks.to_le_bytes());
+        buff[48..56].copy_from_slice(&self.block_size.to_le_bytes());
+        buff[56..64].copy_from_slice(&self.member_blocks.to_le_bytes());
+        buff[64..72].copy_from_slice(&self.events.to_le_bytes());
+        buff[72..76].copy_from_slice(&self.in_sync.to_le_bytes());
+        buff[80..88].copy_from_slice(&self.resync_pos.to_le_bytes());
+        let crc = crate::crypto::crc::crc32c(&buff[..SB_LEN]);
+        buff[SB_LEN..SB_LEN + 4].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// Returns `None` if `buff` does not contain a valid superblock.
+    fn decode(buff: &[u8]) -> Option<Self> {
+        if buff.len() < SB_LEN + 4 || buff[0..8] != MAGIC {
+            return None;
+        }
+        let u32_at = |o: usize| u32::from_le_bytes(buff[o..o + 4].try_into().unwrap());
+        let u64_at = |o: usize| u64::from_le_bytes(buff[o..o + 8].try_into().unwrap());
+        if u32_at(8) != SB_VERSION || u32_at(SB_LEN) != crate::crypto::crc::crc32c(&buff[..SB_LEN]) {
+            return None;
+        }
+        let sb = Self {
+            uuid: Guid::from_bytes(&buff[16..32]),
+            level: RaidLevel::from_raw(u32_at(12))?,
+            members: u32_at(32),
+            index: u32_at(36),
+            chunk_blocks: u64_at(40),
+            block_size: u64_at(48),
+            member_blocks: u64_at(56),
+            events: u64_at(64),
+            in_sync: u32_at(72),
+            resync_pos: u64_at(80),
+        };
+        let valid = (sb.members as usize) <= MAX_MEMBERS
+            && sb.index < sb.members
+            && sb.chunk_blocks != 0
+            && sb.block_size != 0
+            && sb.member_blocks != 0;
+        valid.then_some(sb)
+    }
+}
+
+/// A software RAID device.
+#[derive(Clone)]
+pub struct MdDevice {
+    inner: Arc<MdInner>,
+}
+
+struct MdInner {
+    id: BlockDeviceId,
+    instance: usize,
+    uuid: Guid,
+    level: RaidLevel,
+    chunk_blocks: u64,
+    block_size: u64,
+    member_blocks: u64,
+    /// Offset of the array data on each member in blocks.
+    data_offset: u64,
+    max_transfer: u64,
+    state: spin::Mutex<MdState>,
+    /// Serialises superblock updates so that a newer superblock is never overwritten by an older one.
+    sb_lock: async_lock::Mutex<()>,
+    offline: AtomicBool,
+}
+
+struct MdState {
+    members: Vec<Member>,
+    events: u64,
+    resync: Resync,
+}
+
+struct Member {
+    /// `None` if the member was missing when the array was assembled.
+    id: Option<BlockDeviceId>,
+    dev: Option<Box<dyn SysFsBlockDevice>>,
+    state: MemberState,
+}
+
+#[derive(Default)]
+struct Resync {
+    /// Blocks below this have been copied to resyncing members.
+    pos: u64,
+    active: bool,
+    /// The range currently being copied.
+    window: Option<Range<u64>>,
+    /// Set when the window is written to while it is being copied, the copy must be repeated.
+    dirty: bool,
+    /// Writes to blocks which are not yet resynced and have not completed.
+    pending: Vec<(u64, Range<u64>)>,
+    next_write: u64,
+}
+
+impl Resync {
+    fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+        a.start < b.end && b.start < a.end
+    }
+}
+
+/// Data written to the array.
+#[derive(Copy, Clone)]
+enum Payload<'a> {
+    Data(&'a [u8]),
+    /// The number of blocks to fill with zeros.
+    Zeroes(u64),
+}
+
+impl Payload<'_> {
+    fn blocks(&self, block_size: u64) -> u64 {
+        match self {
+            Payload::Data(d) => d.len() as u64 / block_size,
+            Payload::Zeroes(count) => *count,
+        }
+    }
+
+    /// Returns `count` blocks of the payload starting at block `offset`.
+    fn slice(&self, offset: u64, count: u64, block_size: u64) -> Self {
+        match self {
+            Payload::Data(d) => Payload::Data(&d[(offset * block_size) as usize..((offset + count) * block_size) as usize]),
+            Payload::Zeroes(_) => Payload::Zeroes(count),
+        }
+    }
+
+    async fn write_to(self, dev: &dyn SysFsBlockDevice, lba: u64) -> Result<(), BlockDevIoErr> {
+        match self {
+            Payload::Data(d) => dev.write(lba, IoBuffer::new(Box::from(d))).await.map(|_| ()),
+            Payload::Zeroes(count) => dev.write_zeroes(lba, count).await,
+        }
+    }
+}
+
+impl Drop for MdInner {
+    fn drop(&mut self) {
+        INSTANCES.lock().remove(&self.instance);
+    }
+}
+
+fn alloc_instance() -> usize {
+    let mut l = INSTANCES.lock();
+    let i = (0..).find(|i| !l.contains(i)).unwrap(); // the set is finite
+    l.insert(i);
+    i
+}
+
+fn fetch(id: BlockDeviceId) -> Result<Box<dyn SysFsBlockDevice>, IoError> {
+    crate::system::sysfs::get_sysfs().get_blk_dev().fetch(id).ok_or(IoError::NotPresent)
+}
+
+/// Sets the error recovery limit of a RAID1 member, members which do not support a limit are used
+/// without one.
+async fn limit_error_recovery(dev: &dyn SysFsBlockDevice) {
+    match dev.set_error_recovery(ERROR_RECOVERY_MS, ERROR_RECOVERY_MS).await {
+        Ok(()) => {}
+        Err(BlockDevIoErr::NotSupported) => log::debug!("md: {} does not support an error recovery limit", dev.get_id()),
+        Err(e) => log::warn!("{}", KernelError::from(e).with_device(dev.get_id()).wrap("md", "Failed to set error recovery limit")),
+    }
+}
+
+/// Creates a new array from `members` and registers it as a block device.
+///
+/// Existing data on the members is lost. The superblocks are written immediately, a RAID1 array
+/// copies the first member to the others in the background. Returns [IoError::InvalidData] if the
+/// members do not have the same block size or `config` is invalid for them.
+pub async fn create(members: &[BlockDeviceId], config: MdConfig) -> Result<BlockDeviceId, IoError> {
+    if members.len() < MIN_MEMBERS || members.len() > MAX_MEMBERS {
+        return Err(IoError::NotSupported);
+    }
+    let mut devs = Vec::new();
+    let mut geoms = Vec::new();
+    for id in members {
+        let dev = fetch(*id)?;
+        geoms.push(dev.geom().await?);
+        devs.push(dev);
+    }
+    let bs = geoms[0].block_size;
+    if geoms.iter().any(|g| g.block_size != bs) {
+        return Err(IoError::InvalidData);
+    }
+    let chunk_blocks = match config.level {
+        RaidLevel::Raid0 => {
+            if !config.chunk_size.is_power_of_two() || config.chunk_size % bs != 0 {
+                return Err(IoError::InvalidData);
+            }
+            config.chunk_size / bs
+        }
+        RaidLevel::Raid1 => 1,
+    };
+    let data_offset = DATA_OFFSET.div_ceil(bs);
+    let smallest = geoms.iter().map(|g| g.blocks).min().unwrap();
+    let member_blocks = smallest.saturating_sub(data_offset) / chunk_blocks * chunk_blocks;
+    if member_blocks == 0 {
+        return Err(IoError::EndOfFile);
+    }
+    if config.level == RaidLevel::Raid1 {
+        for dev in &devs {
+            limit_error_recovery(&**dev).await;
+        }
+    }
+
+    // RAID1 members other than the first are resynced from it
+    let members = devs
+        .into_iter()
+        .zip(members)
+        .enumerate()
+        .map(|(i, (dev, id))| Member {
+            id: Some(*id),
+            dev: Some(dev),
+            state: match (config.level, i) {
+                (RaidLevel::Raid1, 1..) => MemberState::Resyncing,
+                _ => MemberState::InSync,
+            },
+        })
+        .collect();
+    let sb = Superblock {
+        uuid: Guid::random(),
+        level: config.level,
+        members: 0,
+        index: 0,
+        chunk_blocks,
+        block_size: bs,
+        member_blocks,
+        events: 1,
+        in_sync: 0,
+        resync_pos: 0,
+    };
+    let md = MdDevice::new(sb, &geoms, members, 0);
+    md.write_superblocks().await;
+    log::info!("Created {:?} array {} {}", config.level, md.inner.id, md.inner.uuid);
+    Ok(md.register())
+}
+
+/// Assembles an array from the superblocks stored on `members` and registers it as a block device.
+///
+/// The members may be given in any order. A RAID1 array is assembled while at least one in-sync
+/// member is present, outdated members are resynchronised. Returns [IoError::InvalidData] if the
+/// members do not belong to the same array.
+pub async fn assemble(members: &[BlockDeviceId]) -> Result<BlockDeviceId, IoError> {
+    let mut found = Vec::new();
+    let mut geoms = Vec::new();
+    for id in members {
+        let dev = fetch(*id)?;
+        let geom = dev.geom().await?;
+        let raw = dev.read(0, DATA_OFFSET.div_ceil(geom.block_size) as usize).await?;
+        let sb = Superblock::decode(&raw).ok_or_else(|| {
+            log::warn!("md: {id} does not contain an md superblock");
+            IoError::InvalidData
+        })?;
+        geoms.push(geom);
+        found.push((sb, *id, dev));
+    }
+    let newest = found.iter().map(|(sb, ..)| *sb).max_by_key(|sb| sb.events).ok_or(IoError::NotPresent)?;
+    let same_array = |sb: &Superblock| {
+        sb.uuid == newest.uuid
+            && sb.members == newest.members
+            && sb.level == newest.level
+            && sb.chunk_blocks == newest.chunk_blocks
+            && sb.block_size == newest.block_size
+            && sb.member_blocks == newest.member_blocks
+    };
+    if !found.iter().all(|(sb, ..)| same_array(sb)) {
+        return Err(IoError::InvalidData);
+    }
+
+    let mut slots: Vec<Member> = (0..newest.members).map(|_| Member { id: None, dev: None, state: MemberState::Failed }).collect();
+    // The resync position is only valid if no outdated member is added to the resync
+    let mut resume = true;
+    for (sb, id, dev) in found {
+        let slot = &mut slots[sb.index as usize];
+        if slot.dev.is_some() {
+            log::warn!("md: {id} is a duplicate of member {}", sb.index);
+            return Err(IoError::AlreadyExists);
+        }
+        let current = sb.events == newest.events;
+        slot.state = match newest.level {
+            _ if current && newest.in_sync & (1 << sb.index) != 0 => MemberState::InSync,
+            RaidLevel::Raid0 => return Err(IoError::InvalidData),
+            RaidLevel::Raid1 => {
+                resume &= current;
+                MemberState::Resyncing
+            }
+        };
+        slot.id = Some(id);
+        slot.dev = Some(dev);
+    }
+    let missing = slots.iter().filter(|m| m.dev.is_none()).count();
+    if !slots.iter().any(|m| m.state == MemberState::InSync) || (newest.level == RaidLevel::Raid0 && missing != 0) {
+        log::warn!("md: not enough members to assemble {}", newest.uuid);
+        return Err(IoError::NotReady);
+    }
+
+    if newest.level == RaidLevel::Raid1 {
+        for dev in slots.iter().filter_map(|m| m.dev.as_ref()) {
+            limit_error_recovery(&**dev).await;
+        }
+    }
+    let md = MdDevice::new(newest, &geoms, slots, if resume { newest.resync_pos } else { 0 });
+    if missing != 0 {
+        log::warn!("md: assembled {} degraded, {missing} members missing", md.inner.id);
+    }
+    md.inner.state.lock().events += 1;
+    md.write_superblocks().await;
+    log::info!("Assembled {:?} array {} {}", newest.level, md.inner.id, md.inner.uuid);
+    Ok(md.register())
+}
+
+/// Stops the array `id` and removes it from the block device list.
+///
+/// The superblocks are updated before the array is removed. Returns [IoError::NotPresent] if `id`
+/// is not an md device.
+pub async fn stop(id: BlockDeviceId) -> Result<(), IoError> {
+    let list = crate::system::sysfs::get_sysfs().get_blk_dev();
+    let dev = list.fetch(id).ok_or(IoError::NotPresent)?;
+    let md = dev.as_any().downcast_ref::<MdDevice>().ok_or(IoError::NotPresent)?.clone();
+    md.write_superblocks().await;
+    md.inner.offline.store(true, Ordering::Release);
+    list.remove_dev(id);
+    log::info!("Stopped array {id}");
+    Ok(())
+}
+
+/// Marks `member` of the array `id` as failed, the member is no longer accessed by the array.
+///
+/// This is intended for testing redundancy. Returns [IoError::NotPresent] if `member` is not an
+/// active member of the array and [IoError::Busy] if the array can not operate without it.
+pub async fn fail_member(id: BlockDeviceId, member: BlockDeviceId) -> Result<(), IoError> {
+    let md = get(id)?;
+    let index = md.inner.state.lock().members.iter().position(|m| m.id == Some(member) && m.state != MemberState::Failed);
+    if md.fail(index.ok_or(IoError::NotPresent)?).await {
+        Ok(())
+    } else {
+        Err(IoError::Busy)
+    }
+}
+
+/// Adds `member` to the RAID1 array `id` in place of a failed member and starts resynchronising it.
+///
+/// Returns [IoError::NotSupported] if the array is not RAID1, [IoError::Busy] if the array has no
+/// failed members and [IoError::EndOfFile] if `member` is too small.
+pub async fn add_member(id: BlockDeviceId, member: BlockDeviceId) -> Result<(), IoError> {
+    let md = get(id)?;
+    if md.inner.level != RaidLevel::Raid1 {
+        return Err(IoError::NotSupported);
+    }
+    let dev = fetch(member)?;
+    let geom = dev.geom().await?;
+    if geom.block_size != md.inner.block_size {
+        return Err(IoError::InvalidData);
+    }
+    if geom.blocks < md.inner.data_offset + md.inner.member_blocks {
+        return Err(IoError::EndOfFile);
+    }
+    limit_error_recovery(&*dev).await;
+    {
+        let mut l = md.inner.state.lock();
+        if l.members.iter().any(|m| m.id == Some(member) && m.state != MemberState::Failed) {
+            return Err(IoError::AlreadyExists);
+        }
+        let slot = l.members.iter().position(|m| m.state == MemberState::Failed).ok_or(IoError::Busy)?;
+        l.members[slot] = Member { id: Some(member), dev: Some(dev), state: MemberState::Resyncing };
+        l.events += 1;
+        // Members already being resynced are copied again along with the new member
+        l.resync.pos = 0;
+    }
+    log::info!("md: added {member} to {id}");
+    md.write_superblocks().await;
+    md.start_resync();
+    Ok(())
+}
+
+/// Returns the id and state of each member of the array `id`, members which are missing have no id.
+pub fn members(id: BlockDeviceId) -> Result<Vec<(Option<BlockDeviceId>, MemberState)>, IoError> {
+    Ok(get(id)?.inner.state.lock().members.iter().map(|m| (m.id, m.state)).collect())
+}
+
+fn get(id: BlockDeviceId) -> Result<MdDevice, IoError> {
+    let dev = fetch(id)?;
+    Ok(dev.as_any().downcast_ref::<MdDevice>().ok_or(IoError::NotPresent)?.clone())
+}
+
+impl MdDevice {
+    fn new(sb: Superblock, geoms: &[BlockDevGeom], members: Vec<Member>, resync_pos: u64) -> Self {
+        let instance = alloc_instance();
+        let max_transfer = geoms.iter().map(|g| g.max_blocks_per_transfer).min().unwrap_or(1).max(1);
+        let events = sb.events;
+        Self {
+            inner: Arc::new(MdInner {
+                id: BlockDeviceId::new(DRIVER_NAME, instance, None),
+                instance,
+                uuid: sb.uuid,
+                level: sb.level,
+                chunk_blocks: sb.chunk_blocks,
+                block_size: sb.block_size,
+                member_blocks: sb.member_blocks,
+                data_offset: DATA_OFFSET.div_ceil(sb.block_size),
+                max_transfer,
+                state: spin::Mutex::new(MdState { members, events, resync: Resync { pos: resync_pos, ..Default::default() } }),
+                sb_lock: async_lock::Mutex::new(()),
+                offline: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    fn register(self) -> BlockDeviceId {
+        let id = self.inner.id;
+        self.start_resync();
+        // The instance is unique so this can't already be registered
+        let _ = crate::system::sysfs::get_sysfs().get_blk_dev().register_dev(Box::new(self));
+        id
+    }
+
+    pub fn level(&self) -> RaidLevel {
+        self.inner.level
+    }
+
+    pub fn uuid(&self) -> Guid {
+        self.inner.uuid
+    }
+
+    /// Returns whether any member has failed or is missing.
+    pub fn is_degraded(&self) -> bool {
+        self.inner.state.lock().members.iter().any(|m| m.state == MemberState::Failed)
+    }
+
+    fn blocks(&self) -> u64 {
+        match self.inner.level {
+            RaidLevel::Raid0 => self.inner.member_blocks * self.inner.state.lock().members.len() as u64,
+            RaidLevel::Raid1 => self.inner.member_blocks,
+        }
+    }
+
+    /// Checks that the array is online and `count` blocks at `seek` are within the array.
+    fn check_range(&self, seek: BlockDevGeomIntegral, count: u64) -> Result<(), BlockDevIoErr> {
+        if count > self.inner.max_transfer {
+            return Err(BlockDevIoErr::GeomError);
+        }
+        self.check_bounds(seek, count)
+    }
+
+    /// Like [Self::check_range] but `count` is not limited to a single transfer.
+    fn check_bounds(&self, seek: BlockDevGeomIntegral, count: u64) -> Result<(), BlockDevIoErr> {
+        if self.inner.offline.load(Ordering::Acquire) {
+            return Err(BlockDevIoErr::DeviceOffline);
+        }
+        match seek.checked_add(count) {
+            Some(end) if end <= self.blocks() => Ok(()),
+            _ => Err(BlockDevIoErr::OutOfRange),
+        }
+    }
+
+    fn member(&self, index: usize) -> Option<Box<dyn SysFsBlockDevice>> {
+        self.inner.state.lock().members[index].dev.clone()
+    }
+
+    /// Splits a RAID0 request into `(member, member block, count)` segments, one per chunk.
+    fn stripe(&self, seek: u64, count: u64) -> Vec<(usize, u64, u64)> {
+        let n = self.inner.state.lock().members.len() as u64;
+        let cb = self.inner.chunk_blocks;
+        let mut segments = Vec::new();
+        let mut block = seek;
+        while block < seek + count {
+            let chunk = block / cb;
+            let len = (cb - block % cb).min(seek + count - block);
+            segments.push(((chunk % n) as usize, (chunk / n) * cb + block % cb + self.inner.data_offset, len));
+            block += len;
+        }
+        segments
+    }
+
+    /// Marks member `index` as failed and updates the superblocks of the remaining members.
+    ///
+    /// Returns `false` if the member is the last in-sync member or the array is RAID0, the array
+    /// can not operate without it so it is not failed.
+    async fn fail(&self, index: usize) -> bool {
+        {
+            let mut l = self.inner.state.lock();
+            let last = !l.members.iter().enumerate().any(|(i, m)| i != index && m.state == MemberState::InSync);
+            let m = &mut l.members[index];
+            if m.state == MemberState::Failed {
+                return true;
+            }
+            if self.inner.level == RaidLevel::Raid0 || (last && m.state == MemberState::InSync) {
+                return false;
+            }
+            m.state = MemberState::Failed;
+            m.dev = None;
+            if let Some(id) = m.id {
+                log::error!("md: {} member {id} failed", self.inner.id);
+            }
+            l.events += 1;
+        }
+        self.write_superblocks().await;
+        true
+    }
+
+    /// Writes the current state of the array to the superblock of each member.
+    async fn write_superblocks(&self) {
+        let _guard = self.inner.sb_lock.lock().waiting_on(WaitPoint::lock("md superblock")).await;
+        let (targets, sb) = {
+            let l = self.inner.state.lock();
+            let in_sync = l.members.iter().enumerate().filter(|(_, m)| m.state == MemberState::InSync).fold(0, |acc, (i, _)| acc | 1 << i);
+            let sb = Superblock {
+                uuid: self.inner.uuid,
+                level: self.inner.level,
+                members: l.members.len() as u32,
+                index: 0,
+                chunk_blocks: self.inner.chunk_blocks,
+                block_size: self.inner.block_size,
+                member_blocks: self.inner.member_blocks,
+                events: l.events,
+                in_sync,
+                resync_pos: l.resync.pos,
+            };
+            let targets: Vec<_> = l.members.iter().enumerate().filter_map(|(i, m)| Some((i, m.dev.clone()?))).collect();
+            (targets, sb)
+        };
+
+        let len = (self.inner.data_offset * self.inner.block_size) as usize;
+        for (i, dev) in targets {
+            let mut buff = alloc::vec![0u8; len].into_boxed_slice();
+            Superblock { index: i as u32, ..sb }.encode(&mut buff);
+            if let Err(e) = dev.write(0, IoBuffer::new(buff)).await {
+                log::warn!("{}", KernelError::from(e).with_device(dev.get_id()).wrap("md", "Failed to write superblock").with_device(self.inner.id));
+            }
+        }
+    }
+
+    async fn read_raid0(&self, seek: u64, count: u64) -> Result<Box<[u8]>, BlockDevIoErr> {
+        let reads = self.stripe(seek, count).into_iter().map(|(i, lba, len)| async move {
+            let dev = self.member(i).ok_or(BlockDevIoErr::DeviceOffline)?;
+            dev.read(lba, len as usize).await
+        });
+        let parts = futures_util::future::try_join_all(reads).await?;
+        Ok(parts.concat().into_boxed_slice())
+    }
+
+    async fn write_raid0(&self, seek: u64, data: Payload<'_>) -> Result<(), BlockDevIoErr> {
+        let bs = self.inner.block_size;
+        let mut off = 0;
+        let mut writes = Vec::new();
+        for (i, lba, len) in self.stripe(seek, data.blocks(bs)) {
+            let part = data.slice(off, len, bs);
+            off += len;
+            writes.push(async move {
+                let dev = self.member(i).ok_or(BlockDevIoErr::DeviceOffline)?;
+                part.write_to(&*dev, lba).await
+            });
+        }
+        futures_util::future::try_join_all(writes).await?;
+        Ok(())
+    }
+
+    /// Reads from the first in-sync member which succeeds, members which fail are dropped unless
+    /// they are the last in-sync member.
+    async fn read_raid1(&self, seek: u64, count: u64) -> Result<Box<[u8]>, BlockDevIoErr> {
+        loop {
+            let source = {
+                let l = self.inner.state.lock();
+                l.members.iter().enumerate().find_map(|(i, m)| (m.state == MemberState::InSync).then(|| (i, m.dev.clone())))
+            };
+            let Some((i, Some(dev))) = source else {
+                return Err(BlockDevIoErr::DeviceOffline);
+            };
+            match dev.read(seek + self.inner.data_offset, count as usize).await {
+                Err(e @ (BlockDevIoErr::HardwareError | BlockDevIoErr::DeviceOffline)) => {
+                    if !self.fail(i).await {
+                        return Err(e);
+                    }
+                }
+                r => return r,
+            }
+        }
+    }
+
+    /// Writes to every member which has not failed. Members being resynced are only written to
+    /// once part of the range has been copied to them, the remainder is copied again by the resync.
+    async fn write_raid1(&self, seek: u64, data: Payload<'_>) -> Result<(), BlockDevIoErr> {
+        let range = seek..seek + data.blocks(self.inner.block_size);
+        let (targets, ticket) = {
+            let mut l = self.inner.state.lock();
+            let partly_copied = range.start < l.resync.pos;
+            let copied = range.end <= l.resync.pos;
+            let targets: Vec<_> = l
+                .members
+                .iter()
+                .enumerate()
+                .filter(|(_, m)| m.state == MemberState::InSync || (partly_copied && m.state == MemberState::Resyncing))
+                .filter_map(|(i, m)| Some((i, m.dev.clone()?)))
+                .collect();
+            let ticket = (!copied && l.resync.active).then(|| {
+                let r = &mut l.resync;
+                if r.window.as_ref().is_some_and(|w| Resync::overlaps(w, &range)) {
+                    r.dirty = true;
+                }
+                let t = r.next_write;
+                r.next_write += 1;
+                r.pending.push((t, range.clone()));
+                t
+            });
+            (targets, ticket)
+        };
+        if targets.is_empty() {
+            return Err(BlockDevIoErr::DeviceOffline);
+        }
+
+        let writes = targets.into_iter().map(|(i, dev)| async move { (i, data.write_to(&*dev, seek + self.inner.data_offset).await) });
+        let results = futures_util::future::join_all(writes).await;
+        if let Some(t) = ticket {
+            self.inner.state.lock().resync.pending.retain(|(p, _)| *p != t);
+        }
+
+        let mut ok = false;
+        let mut err = BlockDevIoErr::DeviceOffline;
+        for (i, r) in results {
+            match r {
+                Ok(()) => ok |= self.inner.state.lock().members[i].state == MemberState::InSync,
+                Err(e @ (BlockDevIoErr::HardwareError | BlockDevIoErr::DeviceOffline)) => {
+                    err = e;
+                    self.fail(i).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if ok { Ok(()) } else { Err(err) }
+    }
+
+    /// Flushes every member which has not failed. Like writes, a RAID1 flush succeeds if an in-sync
+    /// member was flushed and members which fail to flush are failed.
+    async fn flush_members(&self) -> Result<(), BlockDevIoErr> {
+        if self.inner.offline.load(Ordering::Acquire) {
+            return Err(BlockDevIoErr::DeviceOffline);
+        }
+        let targets: Vec<_> = self
+            .inner
+            .state
+            .lock()
+            .members
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.state != MemberState::Failed)
+            .filter_map(|(i, m)| Some((i, m.dev.clone()?)))
+            .collect();
+        let flushes = targets.into_iter().map(|(i, dev)| async move { (i, dev.flush().await) });
+        let results = futures_util::future::join_all(flushes).await;
+        if self.inner.level == RaidLevel::Raid0 {
+            return results.into_iter().try_for_each(|(_, r)| r);
+        }
+
+        let mut ok = false;
+        let mut err = BlockDevIoErr::DeviceOffline;
+        for (i, r) in results {
+            match r {
+                Ok(()) => ok |= self.inner.state.lock().members[i].state == MemberState::InSync,
+                Err(e @ (BlockDevIoErr::HardwareError | BlockDevIoErr::DeviceOffline)) => {
+                    err = e;
+                    self.fail(i).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if ok { Ok(()) } else { Err(err) }
+    }
+
+    /// Spawns the resync task if any member requires resynchronising and it is not already running.
+    fn start_resync(&self) {
+        {
+            let mut l = self.inner.state.lock();
+            if l.resync.active || !l.members.iter().any(|m| m.state == MemberState::Resyncing) {
+                return;
+            }
+            l.resync.active = true;
+        }
+        log::info!("md: resyncing {}", self.inner.id);
+        let weak = Arc::downgrade(&self.inner);
+        crate::task::run_task(Box::pin(async move {
+            Self::resync(weak).await;
+            crate::task::TaskResult::ExitedNormally
+        }));
+    }
+
+    /// Copies the array from an in-sync member to all resyncing members.
+    async fn resync(weak: Weak<MdInner>) {
+        let mut steps = 0u64;
+        loop {
+            // The array is stopped once all other references are dropped
+            let Some(inner) = weak.upgrade() else { return };
+            let md = MdDevice { inner };
+            if md.inner.offline.load(Ordering::Acquire) {
+                md.inner.state.lock().resync.active = false;
+                return;
+            }
+
+            let step = (RESYNC_CHUNK / md.inner.block_size).clamp(1, md.inner.max_transfer);
+            let job = {
+                let mut l = md.inner.state.lock();
+                let source = l.members.iter().position(|m| m.state == MemberState::InSync);
+                let targets: Vec<_> = l.members.iter().enumerate().filter(|(_, m)| m.state == MemberState::Resyncing).filter_map(|(i, m)| Some((i, m.dev.clone()?))).collect();
+                match source {
+                    Some(s) if !targets.is_empty() && l.resync.pos < md.inner.member_blocks => {
+                        let pos = l.resync.pos;
+                        let range = pos..(pos + step).min(md.inner.member_blocks);
+                        let r = &mut l.resync;
+                        r.dirty = r.pending.iter().any(|(_, p)| Resync::overlaps(p, &range));
+                        r.window = Some(range.clone());
+                        Some((s, l.members[s].dev.clone(), targets, range))
+                    }
+                    _ => None,
+                }
+            };
+
+            let Some((s, Some(source), targets, range)) = job else {
+                md.finish_resync().await;
+                return;
+            };
+            let count = range.end - range.start;
+            let data = match source.read(range.start + md.inner.data_offset, count as usize).await {
+                Ok(d) => d,
+                Err(e) => {
+                    log::error!("{}", KernelError::from(e).with_device(source.get_id()).wrap("md", "Resync read failed").with_device(md.inner.id));
+                    if !md.fail(s).await {
+                        md.inner.state.lock().resync.active = false;
+                        log::error!("md: resync of {} stopped", md.inner.id);
+                        return;
+                    }
+                    continue;
+                }
+            };
+            for (i, dev) in targets {
+                if let Err(e) = dev.write(range.start + md.inner.data_offset, IoBuffer::new(data.clone())).await {
+                    log::error!("{}", KernelError::from(e).with_device(dev.get_id()).wrap("md", "Resync write failed").with_device(md.inner.id));
+                    md.fail(i).await;
+                }
+            }
+
+            let retry = {
+                let mut l = md.inner.state.lock();
+                let r = &mut l.resync;
+                r.window = None;
+                // A member may have been added while copying, which restarts the resync
+                if !r.dirty && r.pos == range.start {
+                    r.pos = range.end;
+                }
+                r.dirty
+            };
+            if retry {
+                crate::task::util::sleep(RESYNC_RETRY_MSEC).await;
+                continue;
+            }
+            steps += 1;
+            if steps % RESYNC_CHECKPOINT == 0 {
+                md.write_superblocks().await;
+            }
+        }
+    }
+
+    /// Marks resynced members as in sync once the whole array has been copied.
+    async fn finish_resync(&self) {
+        let done = {
+            let mut l = self.inner.state.lock();
+            l.resync.active = false;
+            if !l.members.iter().any(|m| m.state == MemberState::Resyncing) {
+                // All members being resynced have failed
+                l.resync.pos = 0;
+                return;
+            }
+            if l.resync.pos < self.inner.member_blocks {
+                // Stopped because there is no source
+                false
+            } else {
+                for m in l.members.iter_mut().filter(|m| m.state == MemberState::Resyncing) {
+                    m.state = MemberState::InSync;
+                }
+                l.resync.pos = 0;
+                l.events += 1;
+                true
+            }
+        };
+        if done {
+            log::info!("md: resync of {} complete", self.inner.id);
+        } else {
+            log::error!("md: resync of {} stopped, no in-sync members remain", self.inner.id);
+        }
+        self.write_superblocks().await;
+    }
+}
+
+impl BlockDev for MdDevice {
+    fn read(&self, seek: BlockDevGeomIntegral, size: usize) -> IoFut<Box<[u8]>> {
+        async move {
+            self.check_range(seek, size as u64)?;
+            match self.inner.level {
+                RaidLevel::Raid0 => self.read_raid0(seek, size as u64).await,
+                RaidLevel::Raid1 => self.read_raid1(seek, size as u64).await,
+            }
+        }
+        .boxed()
+    }
+
+    fn write(&self, seek: BlockDevGeomIntegral, buff: IoBuffer) -> IoFut<IoBuffer> {
+        async move {
+            if buff.len() as u64 % self.inner.block_size != 0 {
+                return Err(BlockDevIoErr::GeomError);
+            }
+            self.check_range(seek, buff.len() as u64 / self.inner.block_size)?;
+            match self.inner.level {
+                RaidLevel::Raid0 => self.write_raid0(seek, Payload::Data(buff.buff())).await?,
+                RaidLevel::Raid1 => self.write_raid1(seek, Payload::Data(buff.buff())).await?,
+            }
+            Ok(buff)
+        }
+        .boxed()
+    }
+
+    /// Zeroes the range on each member, members may zero it without transferring data.
+    fn write_zeroes(&self, seek: BlockDevGeomIntegral, count: BlockDevGeomIntegral) -> IoFut<()> {
+        async move {
+            self.check_bounds(seek, count)?;
+            match self.inner.level {
+                RaidLevel::Raid0 => self.write_raid0(seek, Payload::Zeroes(count)).await,
+                RaidLevel::Raid1 => self.write_raid1(seek, Payload::Zeroes(count)).await,
+            }
+        }
+        .boxed()
+    }
+
+    fn flush(&self) -> IoFut<()> {
+        self.flush_members().boxed()
+    }
+
+    fn geom(&self) -> IoFut<BlockDevGeom> {
+        let bs = self.inner.block_size;
+        let geom = BlockDevGeom {
+            blocks: self.blocks(),
+            block_size: bs,
+            optimal_block_size: match self.inner.level {
+                RaidLevel::Raid0 => bs * self.inner.chunk_blocks,
+                RaidLevel::Raid1 => bs,
+            },
+            optimal_alignment: 0,
+            max_blocks_per_transfer: self.inner.max_transfer,
+            req_data_alignment: 1,
+        };
+        async move {
+            if self.inner.offline.load(Ordering::Acquire) {
+                return Err(BlockDevIoErr::DeviceOffline);
+            }
+            Ok(geom)
+        }
+        .boxed()
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+
+    fn b_clone(&self) -> Box<dyn BlockDev> {
+        Box::new(self.clone())
+    }
+}
+
+impl SysFsBlockDevice for MdDevice {
+    fn get_id(&self) -> BlockDeviceId {
+        self.inner.id
+    }
+
+    fn device_class(&self) -> DeviceClass {
+        DeviceClass::Md
+    }
+
+    fn s_clone(&self) -> Box<dyn SysFsBlockDevice> {
+        Box::new(self.clone())
+    }
+}
+
+/// Self-tests for RAID1 arrays built from in-memory members, see [crate::selftest].
+pub(crate) mod selftest {
+    use super::*;
+    use alloc::string::String;
+
+    const BLOCK_SIZE: u64 = 512;
+    const BLOCKS: u64 = 64;
+
+    /// An in-memory member, every request fails once `failed` is set.
+    #[derive(Clone)]
+    struct MemDisk {
+        id: BlockDeviceId,
+        data: Arc<spin::Mutex<Vec<u8>>>,
+        failed: Arc<AtomicBool>,
+    }
+
+    impl MemDisk {
+        fn new(instance: usize) -> Self {
+            Self {
+                id: BlockDeviceId::new("mdtest", instance, None),
+                data: Arc::new(spin::Mutex::new(alloc::vec![0; (BLOCKS * BLOCK_SIZE) as usize])),
+                failed: Arc::new(AtomicBool::new(false)),
+            }
+        }
+
+        /// Returns the array blocks `range` stored on this member.
+        fn blocks(&self, range: Range<u64>) -> Vec<u8> {
+            let offset = DATA_OFFSET.div_ceil(BLOCK_SIZE);
+            let bytes = ((range.start + offset) * BLOCK_SIZE) as usize..((range.end + offset) * BLOCK_SIZE) as usize;
+            self.data.lock()[bytes].to_vec()
+        }
+
+        fn check(&self, seek: u64, count: u64) -> Result<Range<usize>, BlockDevIoErr> {
+            if self.failed.load(Ordering::Relaxed) {
+                return Err(BlockDevIoErr::HardwareError);
+            }
+            if seek + count > BLOCKS {
+                return Err(BlockDevIoErr::OutOfRange);
+            }
+            Ok((seek * BLOCK_SIZE) as usize..((seek + count) * BLOCK_SIZE) as usize)
+        }
+    }
+
+    impl BlockDev for MemDisk {
+        fn read(&self, seek: BlockDevGeomIntegral, size: usize) -> IoFut<Box<[u8]>> {
+            let r = self.check(seek, size as u64).map(|range| Box::from(&self.data.lock()[range]));
+            async move { r }.boxed()
+        }
+
+        fn write(&self, seek: BlockDevGeomIntegral, buff: IoBuffer) -> IoFut<IoBuffer> {
+            let r = self.check(seek, buff.len() as u64 / BLOCK_SIZE).map(|range| {
+                self.data.lock()[range].copy_from_slice(buff.buff());
+                buff
+            });
+            async move { r }.boxed()
+        }
+
+        fn geom(&self) -> IoFut<BlockDevGeom> {
+            let geom = BlockDevGeom {
+                blocks: BLOCKS,
+                block_size: BLOCK_SIZE,
+                optimal_block_size: BLOCK_SIZE,
+                optimal_alignment: 0,
+                max_blocks_per_transfer: BLOCKS,
+                req_data_alignment: 1,
+            };
+            async move { Ok(geom) }.boxed()
+        }
+
+        fn as_any(&self) -> &dyn core::any::Any {
+            self
+        }
+
+        fn b_clone(&self) -> Box<dyn BlockDev> {
+            Box::new(self.clone())
+        }
+    }
+
+    impl SysFsBlockDevice for MemDisk {
+        fn get_id(&self) -> BlockDeviceId {
+            self.id
+        }
+
+        fn s_clone(&self) -> Box<dyn SysFsBlockDevice> {
+            Box::new(self.clone())
+        }
+    }
+
+    /// Constructs an unregistered RAID1 array with one in-memory member for each of `states`,
+    /// failed members are missing. The resync task is not started.
+    fn array(states: &[MemberState], resync_pos: u64) -> (MdDevice, Vec<MemDisk>) {
+        let disks: Vec<_> = (0..states.len()).map(MemDisk::new).collect();
+        let members = states
+            .iter()
+            .zip(&disks)
+            .map(|(state, disk)| match state {
+                MemberState::Failed => Member { id: None, dev: None, state: MemberState::Failed },
+                _ => Member { id: Some(disk.id), dev: Some(Box::new(disk.clone())), state: *state },
+            })
+            .collect();
+        let sb = Superblock {
+            uuid: Guid::random(),
+            level: RaidLevel::Raid1,
+            members: states.len() as u32,
+            index: 0,
+            chunk_blocks: 1,
+            block_size: BLOCK_SIZE,
+            member_blocks: BLOCKS - DATA_OFFSET.div_ceil(BLOCK_SIZE),
+            events: 1,
+            in_sync: 0,
+            resync_pos,
+        };
+        let geom = BlockDevGeom {
+            blocks: BLOCKS,
+            block_size: BLOCK_SIZE,
+            optimal_block_size: BLOCK_SIZE,
+            optimal_alignment: 0,
+            max_blocks_per_transfer: BLOCKS,
+            req_data_alignment: 1,
+        };
+        (MdDevice::new(sb, &[geom], members, resync_pos), disks)
+    }
+
+    fn ensure(cond: bool, msg: &str) -> Result<(), String> {
+        cond.then_some(()).ok_or_else(|| String::from(msg))
+    }
+
+    fn pattern(blocks: u64, fill: u8) -> IoBuffer {
+        IoBuffer::new(alloc::vec![fill; (blocks * BLOCK_SIZE) as usize].into_boxed_slice())
+    }
+
+    /// Checks that a write which straddles the resync position reaches the resyncing member below
+    /// the position, where it will not be copied again.
+    pub async fn raid1_resync_write() -> Result<(), String> {
+        let (md, disks) = array(&[MemberState::InSync, MemberState::Resyncing], 8);
+        md.write(4, pattern(8, 0xa5)).await.map_err(|e| alloc::format!("{e:?}"))?;
+        ensure(disks[0].blocks(4..12).iter().all(|b| *b == 0xa5), "Write did not reach the in-sync member")?;
+        ensure(disks[1].blocks(4..8).iter().all(|b| *b == 0xa5), "Write below the resync position did not reach the resyncing member")?;
+
+        md.write(16, pattern(4, 0x5a)).await.map_err(|e| alloc::format!("{e:?}"))?;
+        ensure(disks[1].blocks(16..20).iter().all(|b| *b == 0), "Write above the resync position reached the resyncing member")?;
+        Ok(())
+    }
+
+    /// Checks that a degraded array is read from the remaining members and that the last in-sync
+    /// member is not dropped when it fails.
+    pub async fn raid1_degraded_read() -> Result<(), String> {
+        let (md, disks) = array(&[MemberState::Failed, MemberState::InSync, MemberState::InSync], 0);
+        md.write(0, pattern(4, 0x3c)).await.map_err(|e| alloc::format!("{e:?}"))?;
+
+        disks[1].failed.store(true, Ordering::Relaxed);
+        let data = md.read(0, 4).await.map_err(|e| alloc::format!("Degraded read failed: {e:?}"))?;
+        ensure(data.iter().all(|b| *b == 0x3c), "Degraded read returned the wrong data")?;
+        ensure(md.inner.state.lock().members[1].state == MemberState::Failed, "Failing member was not dropped")?;
+
+        disks[2].failed.store(true, Ordering::Relaxed);
+        ensure(md.read(0, 4).await.is_err(), "Read succeeded without an in-sync member")?;
+        ensure(md.inner.state.lock().members[2].state == MemberState::InSync, "The last in-sync member was dropped")?;
+        Ok(())
+    }
+
+    /// Checks that zeroing the array zeroes only the requested range of every member.
+    pub async fn raid1_write_zeroes() -> Result<(), String> {
+        let (md, disks) = array(&[MemberState::InSync, MemberState::InSync], 0);
+        md.write(0, pattern(16, 0xc3)).await.map_err(|e| alloc::format!("{e:?}"))?;
+        md.write_zeroes(4, 8).await.map_err(|e| alloc::format!("{e:?}"))?;
+        for disk in &disks {
+            ensure(disk.blocks(4..12).iter().all(|b| *b == 0), "Range was not zeroed")?;
+            ensure(disk.blocks(0..4).iter().chain(&disk.blocks(12..16)).all(|b| *b == 0xc3), "Blocks outside the range were zeroed")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,364 @@
+//! Per-device block I/O statistics.
+//!
+//! Every device registered in the [super::BlockDeviceList] is wrapped in an [Accounted] device
+//! which records each request as it passes through the block layer. For each device this counts
+//! requests and bytes, the number of requests in flight, the time the device was busy and the
+//! integral of the queue depth over time, and keeps a latency histogram for reads and writes.
+//!
+//! Averages over an interval can be calculated by taking two [IoStats] snapshots and dividing the
+//! difference of each counter by the difference of [IoStats::timestamp].
+//! The statistics for all devices are exported as text at `/iostat`.
+//!
+//! Latency histograms use log-linear buckets like HDR histograms. Latencies below
+//! [SUB_BUCKETS] nanoseconds each have their own bucket, above that each power of two is divided
+//! into [SUB_BUCKETS] buckets, so a bucket's width is at most 25% of its lower bound.
+
+use super::{BlockDev, BlockDevGeom, BlockDevGeomIntegral, BlockDeviceId, FirmwareProgress, IoBuffer, IoFut, SysFsBlockDevice};
+use crate::fs::report::ReportFile;
+use crate::fs::vfs::{DevID, MajorNum};
+use crate::mem::dma::DmaTarget;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt::Write as _;
+use futures_util::FutureExt;
+
+const FS_NAME: &str = "/iostat";
+
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("io_stats").unwrap(););
+
+const SUB_BUCKET_BITS: u32 = 2;
+/// Number of buckets each power of two is divided into.
+pub const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+/// Latencies at or above `2^MAX_LATENCY_SHIFT` nanoseconds (about 68 seconds) are counted in the last bucket.
+const MAX_LATENCY_SHIFT: u32 = 36;
+pub const LATENCY_BUCKETS: usize = (MAX_LATENCY_SHIFT - SUB_BUCKET_BITS + 1) as usize * SUB_BUCKETS + 1;
+
+static STATS: spin::RwLock<BTreeMap<BlockDeviceId, Arc<spin::Mutex<IoStats>>>> = spin::RwLock::new(BTreeMap::new());
+
+/// Latency histogram, see the module level documentation for the bucket layout.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS],
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self { buckets: [0; LATENCY_BUCKETS] }
+    }
+}
+
+impl Histogram {
+    fn bucket(ns: u64) -> usize {
+        if ns < SUB_BUCKETS as u64 {
+            return ns as usize;
+        }
+        let exp = (63 - ns.leading_zeros()).min(MAX_LATENCY_SHIFT);
+        if exp == MAX_LATENCY_SHIFT {
+            return LATENCY_BUCKETS - 1;
+        }
+        let shift = exp - SUB_BUCKET_BITS;
+        let sub = (ns >> shift) as usize & (SUB_BUCKETS - 1);
+        (shift as usize + 1) * SUB_BUCKETS + sub
+    }
+
+    /// Returns the range of latencies in nanoseconds counted by `bucket`.
+    pub fn bucket_range(bucket: usize) -> core::ops::Range<u64> {
+        if bucket < SUB_BUCKETS {
+            return bucket as u64..bucket as u64 + 1;
+        }
+        if bucket == LATENCY_BUCKETS - 1 {
+            return 1 << MAX_LATENCY_SHIFT..u64::MAX;
+        }
+        let shift = (bucket / SUB_BUCKETS - 1) as u32;
+        let start = ((SUB_BUCKETS + bucket % SUB_BUCKETS) as u64) << shift;
+        start..start + (1 << shift)
+    }
+
+    fn record(&mut self, ns: u64) {
+        self.buckets[Self::bucket(ns)] += 1;
+    }
+
+    pub fn buckets(&self) -> &[u64; LATENCY_BUCKETS] {
+        &self.buckets
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Returns the upper bound of the bucket containing the percentile given in tenths of a
+    /// percent, e.g. `999` is the 99.9th percentile. Returns `None` if the histogram is empty.
+    pub fn percentile(&self, per_mille: u64) -> Option<u64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let target = (count * per_mille.min(1000)).div_ceil(1000).max(1);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                return Some(Self::bucket_range(i).end - 1);
+            }
+        }
+        None
+    }
+}
+
+/// Counters for a single direction.
+#[derive(Clone, Debug, Default)]
+pub struct DirStats {
+    pub requests: u64,
+    pub bytes: u64,
+    pub errors: u64,
+    pub latency: Histogram,
+}
+
+/// Statistics for a block device since it was registered.
+#[derive(Clone, Debug, Default)]
+pub struct IoStats {
+    pub read: DirStats,
+    pub write: DirStats,
+    /// Number of requests which have been issued but have not completed.
+    pub in_flight: u64,
+    /// Nanoseconds during which at least one request was in flight.
+    pub busy_ns: u64,
+    /// Queue depth integrated over time in request-nanoseconds. Dividing this by the elapsed time
+    /// gives the average queue depth.
+    pub depth_ns: u64,
+    /// System time in nanoseconds at which this snapshot was taken.
+    pub timestamp: u64,
+    /// System time at which the device was registered.
+    pub registered: u64,
+}
+
+impl IoStats {
+    /// Advances the time weighted counters to `now`.
+    fn tick(&mut self, now: u64) {
+        let dt = now.saturating_sub(self.timestamp);
+        if self.in_flight > 0 {
+            self.busy_ns += dt;
+        }
+        self.depth_ns += self.in_flight * dt;
+        self.timestamp = now;
+    }
+}
+
+/// Returns a snapshot of the statistics for `id`.
+pub fn stats(id: BlockDeviceId) -> Option<IoStats> {
+    let s = STATS.read().get(&id)?.clone();
+    let mut s = s.lock();
+    s.tick(crate::time::get_sys_time());
+    Some(s.clone())
+}
+
+/// Wraps `dev` so that its I/O is recorded.
+pub(super) fn track(dev: Box<dyn SysFsBlockDevice>) -> Box<dyn SysFsBlockDevice> {
+    let now = crate::time::get_sys_time();
+    let stats = Arc::new(spin::Mutex::new(IoStats { timestamp: now, registered: now, ..Default::default() }));
+    STATS.write().insert(dev.get_id(), stats.clone());
+    Box::new(Accounted { inner: dev, stats })
+}
+
+pub(super) fn untrack(id: BlockDeviceId) {
+    STATS.write().remove(&id);
+}
+
+/// Records a request from when it is issued until it is dropped.
+struct Request<'a> {
+    stats: &'a spin::Mutex<IoStats>,
+    start: u64,
+    write: bool,
+    bytes: u64,
+    ok: bool,
+}
+
+impl<'a> Request<'a> {
+    fn start(stats: &'a spin::Mutex<IoStats>, write: bool, bytes: u64) -> Self {
+        let start = crate::time::get_sys_time();
+        let mut s = stats.lock();
+        s.tick(start);
+        s.in_flight += 1;
+        Self { stats, start, write, bytes, ok: false }
+    }
+}
+
+/// Requests which are cancelled are counted as errors.
+impl Drop for Request<'_> {
+    fn drop(&mut self) {
+        let now = crate::time::get_sys_time();
+        let mut s = self.stats.lock();
+        s.tick(now);
+        s.in_flight -= 1;
+        let dir = if self.write { &mut s.write } else { &mut s.read };
+        dir.requests += 1;
+        if self.ok {
+            dir.bytes += self.bytes;
+            dir.latency.record(now - self.start);
+        } else {
+            dir.errors += 1;
+        }
+    }
+}
+
+/// Fails a request if a fault is injected by [crate::fault_inject::BLOCK], the request is
+/// counted as an error.
+fn inject_fault() -> Result<(), super::BlockDevIoErr> {
+    if crate::fault_inject::BLOCK.should_fail() {
+        log::debug!("Injected block device fault");
+        return Err(super::BlockDevIoErr::HardwareError);
+    }
+    Ok(())
+}
+
+/// A block device which records statistics for the device it wraps.
+#[derive(Clone)]
+pub struct Accounted {
+    inner: Box<dyn SysFsBlockDevice>,
+    stats: Arc<spin::Mutex<IoStats>>,
+}
+
+impl BlockDev for Accounted {
+    fn read(&self, seek: BlockDevGeomIntegral, size: usize) -> IoFut<Box<[u8]>> {
+        async move {
+            // The request size is in blocks, the byte count is taken from the result
+            let mut req = Request::start(&self.stats, false, 0);
+            inject_fault()?;
+            let r = self.inner.read(seek, size).await;
+            if let Ok(data) = &r {
+                req.bytes = data.len() as u64;
+                req.ok = true;
+            }
+            r
+        }
+        .boxed()
+    }
+
+    fn write(&self, seek: BlockDevGeomIntegral, buff: IoBuffer) -> IoFut<IoBuffer> {
+        async move {
+            let mut req = Request::start(&self.stats, true, buff.len() as u64);
+            inject_fault()?;
+            let r = self.inner.write(seek, buff).await;
+            req.ok = r.is_ok();
+            r
+        }
+        .boxed()
+    }
+
+    fn read_into<'a>(&'a self, seek: BlockDevGeomIntegral, buff: &'a mut dyn DmaTarget) -> IoFut<'a, ()> {
+        async move {
+            let mut req = Request::start(&self.stats, false, buff.as_mut().len() as u64);
+            inject_fault()?;
+            let r = self.inner.read_into(seek, buff).await;
+            req.ok = r.is_ok();
+            r
+        }
+        .boxed()
+    }
+
+    fn write_from<'a>(&'a self, seek: BlockDevGeomIntegral, buff: &'a mut dyn DmaTarget) -> IoFut<'a, ()> {
+        async move {
+            let mut req = Request::start(&self.stats, true, buff.as_mut().len() as u64);
+            inject_fault()?;
+            let r = self.inner.write_from(seek, buff).await;
+            req.ok = r.is_ok();
+            r
+        }
+        .boxed()
+    }
+
+    fn geom(&self) -> IoFut<BlockDevGeom> {
+        self.inner.geom()
+    }
+
+    fn update_firmware<'a>(&'a self, image: &'a [u8], progress: &'a FirmwareProgress) -> IoFut<'a, ()> {
+        self.inner.update_firmware(image, progress)
+    }
+
+    fn device_statistics(&self) -> IoFut<super::DeviceStatistics> {
+        self.inner.device_statistics()
+    }
+
+    fn set_error_recovery(&self, read_ms: u32, write_ms: u32) -> IoFut<()> {
+        self.inner.set_error_recovery(read_ms, write_ms)
+    }
+
+    fn write_zeroes(&self, seek: BlockDevGeomIntegral, count: BlockDevGeomIntegral) -> IoFut<()> {
+        self.inner.write_zeroes(seek, count)
+    }
+
+    fn flush(&self) -> IoFut<()> {
+        self.inner.flush()
+    }
+
+    /// Returns the wrapped device so that drivers can still downcast their own devices.
+    fn as_any(&self) -> &dyn core::any::Any {
+        self.inner.as_any()
+    }
+
+    fn b_clone(&self) -> Box<dyn BlockDev> {
+        Box::new(self.clone())
+    }
+}
+
+impl SysFsBlockDevice for Accounted {
+    fn get_id(&self) -> BlockDeviceId {
+        self.inner.get_id()
+    }
+
+    fn device_class(&self) -> crate::fs::devfs::naming::DeviceClass {
+        self.inner.device_class()
+    }
+
+    fn s_clone(&self) -> Box<dyn SysFsBlockDevice> {
+        Box::new(self.clone())
+    }
+}
+
+/// Formats a table of statistics for all block devices.
+///
+/// Each line contains the device, the request and byte counts for reads and writes, the number of
+/// errors, the requests in flight, the utilisation and average queue depth since the device was
+/// registered, and the 50th, 99th and 99.9th percentile latencies in microseconds for reads and writes.
+pub fn report() -> String {
+    let ids: alloc::vec::Vec<BlockDeviceId> = STATS.read().keys().copied().collect();
+    let mut s = String::new();
+    let _ = writeln!(
+        s,
+        "{:<16} {:>10} {:>14} {:>10} {:>14} {:>6} {:>5} {:>6} {:>7}  {:>26}  {:>26}",
+        "DEVICE", "READS", "READ_BYTES", "WRITES", "WRITE_BYTES", "ERRORS", "QUEUE", "UTIL%", "AVG_QD", "READ_US(P50/P99/P99.9)", "WRITE_US(P50/P99/P99.9)"
+    );
+    for id in ids {
+        let Some(st) = stats(id) else { continue };
+        let elapsed = (st.timestamp - st.registered).max(1);
+        let _ = write!(
+            s,
+            "{:<16} {:>10} {:>14} {:>10} {:>14} {:>6} {:>5} {:>4}.{} {:>4}.{:02}",
+            alloc::format!("{id}"),
+            st.read.requests,
+            st.read.bytes,
+            st.write.requests,
+            st.write.bytes,
+            st.read.errors + st.write.errors,
+            st.in_flight,
+            st.busy_ns * 100 / elapsed,
+            st.busy_ns * 1000 / elapsed % 10,
+            st.depth_ns / elapsed,
+            st.depth_ns * 100 / elapsed % 100,
+        );
+        for dir in [&st.read, &st.write] {
+            let [p50, p99, p999] = [500, 990, 999].map(|p| dir.latency.percentile(p).map_or(0, |ns| ns / 1000));
+            let _ = write!(s, "  {:>26}", alloc::format!("{p50}/{p99}/{p999}"));
+        }
+        let _ = writeln!(s);
+    }
+    s
+}
+
+/// Mounts the statistics file.
+pub fn init() {
+    crate::task::util::block_on!(crate::fs::get_vfs().mount_dev(Box::new(ReportFile::new(DevID::new(*MAJOR, 0), report)), FS_NAME))
+        .expect("Failed to mount block I/O statistics to VFS");
+}
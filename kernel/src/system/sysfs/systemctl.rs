@@ -67,6 +67,18 @@ impl GlobalIoApic {
         Err(())
     }
 
+    /// Masks all GSIs which are routed to `vector`, returns the number of GSIs which were masked.
+    ///
+    /// This may be called from an interrupt handler, if the IO-APICs are locked this returns `None`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that interrupts on `vector` are not required.
+    pub(crate) unsafe fn mask_vector(&self, vector: u8) -> Option<usize> {
+        let mut l = self.inner.try_lock()?;
+        Some(l.iter_mut().map(|(i, _)| i.mask_vector(vector)).sum())
+    }
+
     pub(super) fn cfg_madt(&self, madt: &acpi::madt::Madt) {
         let parsed = madt
             .parse_interrupt_model_in(alloc::alloc::Global)
@@ -114,6 +126,27 @@ impl GlobalIoApic {
         *self.inner.lock() = arr;
     }
 
+    /// Returns the redirection entries of all IO-APICs.
+    pub(crate) fn save_entries(&self) -> alloc::vec::Vec<alloc::vec::Vec<crate::interrupts::apic::ioapic::RedirectionTableEntry>> {
+        let mut l = self.inner.lock();
+        l.iter_mut().map(|(i, _)| (0..i.len()).map(|n| i.get_entry(n)).collect()).collect()
+    }
+
+    /// Restores redirection entries returned by [Self::save_entries], IO-APICs lose their
+    /// configuration while the system is suspended.
+    ///
+    /// # Safety
+    ///
+    /// `entries` must have been returned by [Self::save_entries]
+    pub(crate) unsafe fn restore_entries(&self, entries: &[alloc::vec::Vec<crate::interrupts::apic::ioapic::RedirectionTableEntry>]) {
+        let mut l = self.inner.lock();
+        for ((apic, _), saved) in l.iter_mut().zip(entries) {
+            for (n, e) in saved.iter().enumerate() {
+                apic.set_entry(n as u8, *e);
+            }
+        }
+    }
+
     pub(crate) fn lookup_override(&self, isa: u8) -> Option<InterruptOverride> {
         for i in self.overrides.0.get().unwrap().iter() {
             if i.isa_source == isa {
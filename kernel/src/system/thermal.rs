@@ -0,0 +1,212 @@
+//! Thermal monitoring.
+//!
+//! The package temperature is read from `IA32_PACKAGE_THERM_STATUS`, or the core temperature of
+//! the CPU running the monitor from `IA32_THERM_STATUS` when package monitoring is not supported.
+//! Both report the temperature as an offset below TjMax, which is read from `MSR_TEMPERATURE_TARGET`.
+//!
+//! The temperature is polled every [POLL_MS], logged every [LOG_INTERVAL] and is exported as text
+//! at `/thermal`. When the temperature reaches [THROTTLE_MARGIN] degrees below TjMax all CPUs are
+//! throttled to their lowest performance level using [super::cpufreq]. When it remains within
+//! [CRITICAL_MARGIN] degrees of TjMax for [CRITICAL_MS] the system is powered off using
+//! [super::suspend::power_off], drivers are quiesced first so that writes are not interrupted by a
+//! hardware thermal trip.
+//!
+//! ACPI thermal zones report their temperature using the `_TMP` method, which requires an AML
+//! interpreter, so they are not used. Monitoring is disabled when running under a hypervisor.
+
+use crate::config::Tunable;
+use crate::fs::report::ReportFile;
+use crate::fs::vfs::{DevID, MajorNum};
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicU8, Ordering};
+use x86_msr::architecture::{PackageThermStatus, RawValue, ThermStatus};
+use x86_msr::{Msr, MsrAvailability, MsrReadWrite};
+
+const FS_NAME: &str = "/thermal";
+
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("thermal").unwrap(););
+
+/// Interval between temperature readings.
+pub static POLL_MS: Tunable<u64> = Tunable::new("thermal.poll_ms", "Milliseconds between temperature readings", 1000);
+
+/// Interval between logging the temperature, `0` disables logging.
+pub static LOG_INTERVAL: Tunable<u64> = Tunable::new("thermal.log_interval", "Seconds between logging the temperature, 0 disables logging", 60);
+
+/// Degrees below TjMax at which CPUs are throttled.
+pub static THROTTLE_MARGIN: Tunable<u8> = Tunable::new("thermal.throttle_margin", "Degrees below TjMax at which CPUs are throttled", 10);
+
+/// Degrees below TjMax which are considered critical.
+pub static CRITICAL_MARGIN: Tunable<u8> = Tunable::new("thermal.critical_margin", "Degrees below TjMax at which the system is powered off", 2);
+
+/// Time the temperature must remain critical before the system is powered off.
+pub static CRITICAL_MS: Tunable<u64> = Tunable::new("thermal.critical_ms", "Milliseconds the temperature must remain critical before powering off", 3000);
+
+/// Throttling is released once the temperature falls this many degrees below the throttle threshold.
+const HYSTERESIS: u8 = 5;
+/// TjMax used when `MSR_TEMPERATURE_TARGET` does not report it.
+const DEFAULT_TJMAX: u8 = 100;
+/// `IA32_THERM_STATUS` reading valid
+const READING_VALID: u64 = 1 << 31;
+
+static SENSOR: spin::Once<Option<Sensor>> = spin::Once::new();
+/// Last temperature read in degrees Celsius, `0` if no reading has been taken.
+static TEMPERATURE: AtomicU8 = AtomicU8::new(0);
+
+/// MSR_TEMPERATURE_TARGET, contains TjMax in bits 23:16. This is not architectural, it is only
+/// read on Intel processors.
+struct TemperatureTarget;
+
+impl Msr<RawValue> for TemperatureTarget {
+    const MSR_ADDR: u32 = 0x1a2;
+
+    fn availability() -> MsrAvailability {
+        MsrAvailability::new(MsrReadWrite::Read)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Sensor {
+    /// Whether the package temperature is reported, otherwise the core temperature is.
+    package: bool,
+    tjmax: u8,
+}
+
+impl Sensor {
+    fn detect() -> Option<Self> {
+        if crate::system::hypervisor::hypervisor().is_some() {
+            return None;
+        }
+        let cpuid = raw_cpuid::CpuId::new();
+        if !cpuid.get_vendor_info().is_some_and(|v| v.as_str() == "GenuineIntel") {
+            return None;
+        }
+
+        let package = PackageThermStatus::availability().get_bit(0) == MsrReadWrite::Read;
+        if !package && ThermStatus::availability().get_bit(0) != MsrReadWrite::Read {
+            return None;
+        }
+        // SAFETY: Intel processors supporting digital thermal sensors have MSR_TEMPERATURE_TARGET
+        let tjmax = match (unsafe { TemperatureTarget::read() }.value >> 16) as u8 {
+            0 => DEFAULT_TJMAX,
+            t => t,
+        };
+        Some(Self { package, tjmax })
+    }
+
+    /// Reads the current temperature in degrees Celsius.
+    fn read(&self) -> Option<u8> {
+        let status = if self.package {
+            // SAFETY: Package thermal status is supported
+            unsafe { PackageThermStatus::read() }.value
+        } else {
+            // SAFETY: Thermal status is supported
+            let status = unsafe { ThermStatus::read() }.value;
+            if status & READING_VALID == 0 {
+                return None;
+            }
+            status
+        };
+        let readout = ((status >> 16) & 0x7f) as u8;
+        Some(self.tjmax.saturating_sub(readout))
+    }
+
+    /// Temperature at which CPUs are throttled.
+    fn throttle_temp(&self) -> u8 {
+        self.tjmax.saturating_sub(THROTTLE_MARGIN.get())
+    }
+
+    /// Temperature at which the system is powered off.
+    fn critical_temp(&self) -> u8 {
+        self.tjmax.saturating_sub(CRITICAL_MARGIN.get())
+    }
+}
+
+/// Returns the temperature sensor in use.
+pub fn sensor() -> Option<Sensor> {
+    SENSOR.get().copied().flatten()
+}
+
+/// Returns the last temperature read in degrees Celsius.
+pub fn temperature() -> Option<u8> {
+    match TEMPERATURE.load(Ordering::Relaxed) {
+        0 => None,
+        t => Some(t),
+    }
+}
+
+/// Polls the temperature and throttles or powers off the system when it is too hot.
+async fn monitor(sensor: Sensor) -> crate::task::TaskResult {
+    let mut last_log = 0;
+    let mut critical_since = None;
+    loop {
+        crate::task::util::sleep(POLL_MS.get()).await;
+        let Some(temp) = sensor.read() else { continue };
+        TEMPERATURE.store(temp, Ordering::Relaxed);
+        let now = crate::time::get_sys_time();
+
+        let interval = LOG_INTERVAL.get() * 1_000_000_000;
+        if interval != 0 && now.saturating_sub(last_log) >= interval {
+            log::info!("thermal: {temp}C");
+            last_log = now;
+        }
+
+        let throttled = super::cpufreq::throttled();
+        if !throttled && temp >= sensor.throttle_temp() {
+            log::warn!("thermal: {temp}C exceeds {}C, throttling", sensor.throttle_temp());
+            super::cpufreq::set_throttle(true);
+        } else if throttled && temp < sensor.throttle_temp().saturating_sub(HYSTERESIS) {
+            log::info!("thermal: {temp}C, throttling released");
+            super::cpufreq::set_throttle(false);
+        }
+
+        if temp < sensor.critical_temp() {
+            critical_since = None;
+            continue;
+        }
+        let since = *critical_since.get_or_insert(now);
+        if now - since >= CRITICAL_MS.get() * 1_000_000 {
+            log::error!("thermal: {temp}C is critical, powering off");
+            let e = super::suspend::power_off();
+            log::error!("thermal: Failed to power off: {e:?}, halting");
+            crate::stop()
+        }
+    }
+}
+
+/// Detects the temperature sensor, starts the monitor and mounts the report file.
+pub fn init() {
+    match SENSOR.call_once(Sensor::detect) {
+        Some(s) => {
+            log::info!("thermal: TjMax {}C, using {} sensor", s.tjmax, if s.package { "package" } else { "core" });
+            crate::task::run_task(Box::pin(monitor(*s)));
+        }
+        None => log::info!("thermal: Temperature monitoring is not available"),
+    }
+    crate::task::util::block_on!(crate::fs::get_vfs().mount_dev(Box::new(ReportFile::new(DevID::new(*MAJOR, 0), report)), FS_NAME))
+        .expect("Failed to mount thermal report to VFS");
+}
+
+/// Formats the sensor state, one `name value` pair per line. Temperatures are in degrees Celsius.
+pub fn report() -> String {
+    let mut s = String::new();
+    let Some(sensor) = sensor() else {
+        let _ = writeln!(s, "sensor none");
+        return s;
+    };
+    let _ = writeln!(s, "sensor {}", if sensor.package { "package" } else { "core" });
+    match temperature() {
+        Some(t) => {
+            let _ = writeln!(s, "temperature {t}");
+        }
+        None => {
+            let _ = writeln!(s, "temperature -");
+        }
+    }
+    let _ = writeln!(s, "tjmax {}", sensor.tjmax);
+    let _ = writeln!(s, "throttle {}", sensor.throttle_temp());
+    let _ = writeln!(s, "critical {}", sensor.critical_temp());
+    let _ = writeln!(s, "throttled {}", super::cpufreq::throttled());
+    s
+}
@@ -0,0 +1,155 @@
+//! Software and hardware watchdogs.
+//!
+//! Each CPU's executor records a heartbeat every time it polls its run queue. A CPU is considered
+//! stalled when it has not recorded a heartbeat for [STALL_MS] while it was not halted, this
+//! happens when a task never yields or a CPU hangs with interrupts disabled. A halted CPU is
+//! considered healthy, it will record a heartbeat when it is woken.
+//!
+//! Hardware watchdogs are registered by drivers using [register]. The first registered watchdog is
+//! started with a timeout of [TIMEOUT] and is reset every [PET_MS] while no CPUs are stalled.
+//! When a CPU stalls the stalled CPUs are logged and the watchdog is no longer reset, so the
+//! hardware resets the system instead of leaving it hung. Hardware watchdogs report whether they
+//! caused the previous reset, which is logged when they are registered. The watchdog is stopped
+//! while the system is suspended.
+
+use crate::config::Tunable;
+use crate::mp::CpuIndex;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Hardware watchdog timeout, `0` disables hardware watchdogs.
+pub static TIMEOUT: Tunable<u32> = Tunable::new("watchdog.timeout", "Seconds before a hardware watchdog resets the system, 0 disables the watchdog", 30);
+
+/// Interval at which the hardware watchdog is reset.
+pub static PET_MS: Tunable<u64> = Tunable::new("watchdog.pet_ms", "Milliseconds between resetting the hardware watchdog", 1000);
+
+/// Time after which a CPU which has not recorded a heartbeat is considered stalled.
+pub static STALL_MS: Tunable<u64> = Tunable::new("watchdog.stall_ms", "Milliseconds without a heartbeat before a CPU is considered stalled", 10_000);
+
+/// Interface to a hardware watchdog timer.
+pub trait HardwareWatchdog: Send + Sync {
+    /// Name of the watchdog used in log messages.
+    fn name(&self) -> &str;
+
+    /// Starts the watchdog, the system is reset if [Self::pet] is not called within `timeout`
+    /// seconds. Returns the timeout which was actually configured.
+    fn start(&self, timeout: u32) -> u32;
+
+    /// Resets the watchdog timer.
+    fn pet(&self);
+
+    /// Stops the watchdog.
+    fn stop(&self);
+
+    /// Returns whether the watchdog caused the previous system reset.
+    fn caused_reset(&self) -> bool;
+}
+
+/// Heartbeat of a single CPU.
+struct Heartbeat {
+    /// System time of the last heartbeat, `0` until the CPU's executor has started.
+    last: AtomicU64,
+    /// Set while the CPU is halted waiting for work.
+    idle: AtomicBool,
+}
+
+#[thread_local]
+static HEARTBEAT: Heartbeat = Heartbeat {
+    last: AtomicU64::new(0),
+    idle: AtomicBool::new(false),
+};
+
+static CPUS: spin::RwLock<BTreeMap<CpuIndex, &'static Heartbeat>> = spin::RwLock::new(BTreeMap::new());
+static HARDWARE: spin::Once<Box<dyn HardwareWatchdog>> = spin::Once::new();
+
+/// Registers the heartbeat for the calling CPU. This must be called once by each CPU after its
+/// thread local storage is initialized.
+pub fn register_cpu() {
+    // SAFETY: Thread local storage is never freed, so the heartbeat lives for the rest of the kernel's lifetime.
+    let hb: &'static Heartbeat = unsafe { &*(&HEARTBEAT as *const Heartbeat) };
+    CPUS.write().insert(crate::who_am_i(), hb);
+}
+
+/// Records a heartbeat for this CPU.
+pub(crate) fn heartbeat() {
+    HEARTBEAT.last.store(crate::time::get_sys_time(), Ordering::Relaxed);
+}
+
+/// Marks whether this CPU is halted, a heartbeat is recorded when it is woken.
+pub(crate) fn set_idle(idle: bool) {
+    if !idle {
+        heartbeat();
+    }
+    HEARTBEAT.idle.store(idle, Ordering::Relaxed);
+}
+
+/// Returns the CPUs which have stalled and the number of milliseconds since their last heartbeat.
+pub fn stalled_cpus() -> Vec<(CpuIndex, u64)> {
+    let now = crate::time::get_sys_time();
+    let limit = STALL_MS.get();
+    CPUS.read()
+        .iter()
+        .filter(|(_, hb)| !hb.idle.load(Ordering::Relaxed))
+        .filter_map(|(cpu, hb)| match hb.last.load(Ordering::Relaxed) {
+            0 => None,
+            last => Some((*cpu, now.saturating_sub(last) / 1_000_000)),
+        })
+        .filter(|(_, ms)| *ms >= limit)
+        .collect()
+}
+
+/// Registers a hardware watchdog and starts it. Only a single hardware watchdog is used, if one
+/// is already registered `watchdog` is returned.
+pub fn register(watchdog: Box<dyn HardwareWatchdog>) -> Result<(), Box<dyn HardwareWatchdog>> {
+    if watchdog.caused_reset() {
+        log::warn!("watchdog: The previous boot was reset by {} because the system stopped responding", watchdog.name());
+    }
+    let mut watchdog = Some(watchdog);
+    let wdt = HARDWARE.call_once(|| watchdog.take().unwrap());
+    if let Some(w) = watchdog {
+        return Err(w);
+    }
+
+    let timeout = TIMEOUT.get();
+    if timeout == 0 {
+        wdt.stop();
+        log::info!("watchdog: {} is disabled", wdt.name());
+        return Ok(());
+    }
+    let timeout = wdt.start(timeout);
+    log::info!("watchdog: Started {} with a timeout of {timeout}s", wdt.name());
+    crate::task::run_task(Box::pin(service(&**wdt)));
+    Ok(())
+}
+
+/// Stops the hardware watchdog before the system is suspended or powered off.
+pub(crate) fn suspend() {
+    if let Some(wdt) = HARDWARE.get() {
+        wdt.stop();
+    }
+}
+
+/// Restarts the hardware watchdog after the system has resumed.
+pub(crate) fn resume() {
+    if let Some(wdt) = HARDWARE.get().filter(|_| TIMEOUT.get() != 0) {
+        wdt.start(TIMEOUT.get());
+    }
+}
+
+/// Resets the hardware watchdog while all CPUs are healthy.
+async fn service(wdt: &'static dyn HardwareWatchdog) -> crate::task::TaskResult {
+    loop {
+        let stalled = stalled_cpus();
+        if !stalled.is_empty() {
+            for (cpu, ms) in stalled {
+                log::error!("watchdog: CPU{cpu} has not responded for {ms}ms");
+            }
+            log::error!("watchdog: No longer resetting {}, the system will be reset", wdt.name());
+            return crate::task::TaskResult::Error;
+        }
+        wdt.pet();
+        crate::task::util::sleep(PET_MS.get()).await;
+    }
+}
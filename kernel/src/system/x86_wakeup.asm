@@ -0,0 +1,150 @@
+; ACPI waking vector.
+;
+; The firmware enters `_wakeup` in real mode with CS set to the page containing it. The page is
+; copied into low memory and identity mapped by the kernel. The stub enters long mode directly,
+; restores the state which was saved by the kernel into the data area and returns from
+; `_suspend_save` through `_wakeup_resume`.
+
+%define LONG_MODE 0x100
+%define DATA 0x800
+
+; Data area offsets, see `WakeData` in suspend.rs
+%define GDT_PTR 0
+%define ENTRY64 24
+%define BOOT_CR3 32
+%define PAT 40
+%define EFER 48
+%define CR4 56
+%define CR0 64
+%define KERNEL_CR3 72
+%define RSP 80
+%define FS_BASE 88
+%define GS_BASE 96
+%define KERNEL_GS_BASE 104
+%define GDTR 112
+%define IDTR 128
+%define CS_SEL 144
+%define DS_SEL 152
+%define RESUME 160
+
+segment .hootux_acpi_wakeup
+
+global _wakeup
+    bits 16
+    align 4096
+_wakeup:
+    cli
+    cld
+    mov ax,cs
+    mov ds,ax
+
+    lgdt [DATA + GDT_PTR]
+
+    mov ecx,0x277
+    mov eax,[DATA + PAT]
+    mov edx,[DATA + PAT + 4]
+    wrmsr ; restore PAT before paging is enabled
+
+    mov eax,cr4
+    or eax,1 << 5 ; cr4.pae
+    mov cr4,eax
+    mov eax,[DATA + BOOT_CR3]
+    mov cr3,eax
+
+    mov ecx,0xC0000080 ; efer
+    mov eax,[DATA + EFER]
+    and eax,~(1 << 10) ; efer.lma is read only
+    mov edx,[DATA + EFER + 4]
+    wrmsr
+
+    ; enable protected mode and paging at once, this enters compatibility mode
+    mov eax,cr0
+    or eax,(1 << 31) | 1
+    mov cr0,eax
+    jmp dword far [DATA + ENTRY64]
+
+    times LONG_MODE - ($ - _wakeup) db 0
+
+    bits 64
+long_mode:
+    lea rbx,[rel _wakeup + DATA]
+
+    mov rax,[rbx + CR4]
+    mov cr4,rax
+    mov rax,[rbx + CR0]
+    mov cr0,rax
+    mov rax,[rbx + KERNEL_CR3]
+    mov cr3,rax
+
+    lgdt [rbx + GDTR]
+    lidt [rbx + IDTR]
+    mov rax,[rbx + DS_SEL]
+    mov ds,ax
+    mov es,ax
+    mov ss,ax
+    mov fs,ax
+    mov gs,ax
+
+    ; segment bases are cleared when the selectors are loaded
+    mov ecx,0xc0000100
+    mov eax,[rbx + FS_BASE]
+    mov edx,[rbx + FS_BASE + 4]
+    wrmsr
+    mov ecx,0xc0000101
+    mov eax,[rbx + GS_BASE]
+    mov edx,[rbx + GS_BASE + 4]
+    wrmsr
+    mov ecx,0xc0000102
+    mov eax,[rbx + KERNEL_GS_BASE]
+    mov edx,[rbx + KERNEL_GS_BASE + 4]
+    wrmsr
+
+    mov rsp,[rbx + RSP]
+    ; reload cs by returning into the kernel
+    push qword [rbx + CS_SEL]
+    push qword [rbx + RESUME]
+    o64 retf
+
+    times DATA - ($ - _wakeup) db 0
+    times 4096 - ($ - _wakeup) db 0
+
+section .text
+
+global _suspend_save
+global _wakeup_resume
+
+; Saves the callee saved registers onto the stack and stores the stack pointer into `rdi`, then
+; calls `rsi` with `rdx` as its argument. Returns 0 if `rsi` returns, or 1 when the system is
+; resumed through `_wakeup_resume`.
+_suspend_save:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    pushfq
+    mov [rdi],rsp
+    mov rdi,rdx
+    call rsi
+
+    popfq
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    xor eax,eax
+    ret
+
+_wakeup_resume:
+    popfq
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    mov eax,1
+    ret
@@ -0,0 +1,211 @@
+//! CPU affinity and CPU isolation.
+//!
+//! Each task may only run on the CPUs in its [CpuSet]. A task inherits the set of the task which
+//! spawned it, tasks spawned from outside a task may run on every CPU which is not isolated.
+//! Isolated CPUs are given on the command line using `isolcpus=<cpulist>` and only run tasks which
+//! are explicitly pinned to them with [set_affinity], so latency-sensitive drivers can own a CPU.
+//!
+//! A CPU list is a comma separated list of CPU indices or inclusive ranges, e.g. `0,2-3`.
+//!
+//! The affinity of every task is exported at `/affinity`. Writing `<task id> <cpulist>` lines sets
+//! the affinity of those tasks.
+
+use super::TaskId;
+use crate::fs::report::ReportFile;
+use crate::fs::vfs::{DevID, MajorNum};
+use crate::fs::IoError;
+use crate::mp::CpuIndex;
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::fmt::Write as _;
+
+const FS_NAME: &str = "/affinity";
+
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("affinity").unwrap(););
+
+/// Number of CPUs a [CpuSet] can describe.
+pub const MAX_CPUS: usize = 256;
+
+/// A set of CPU indices.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct CpuSet {
+    bits: [u64; MAX_CPUS / 64],
+}
+
+impl CpuSet {
+    pub const fn empty() -> Self {
+        Self { bits: [0; MAX_CPUS / 64] }
+    }
+
+    pub const fn all() -> Self {
+        Self { bits: [u64::MAX; MAX_CPUS / 64] }
+    }
+
+    pub fn single(cpu: CpuIndex) -> Self {
+        let mut s = Self::empty();
+        s.insert(cpu);
+        s
+    }
+
+    /// Adds `cpu` to the set, CPUs which are out of range are ignored.
+    pub fn insert(&mut self, cpu: CpuIndex) {
+        if let Some(w) = self.bits.get_mut(cpu as usize / 64) {
+            *w |= 1 << (cpu % 64);
+        }
+    }
+
+    pub fn remove(&mut self, cpu: CpuIndex) {
+        if let Some(w) = self.bits.get_mut(cpu as usize / 64) {
+            *w &= !(1 << (cpu % 64));
+        }
+    }
+
+    pub fn contains(&self, cpu: CpuIndex) -> bool {
+        self.bits.get(cpu as usize / 64).is_some_and(|w| w & (1 << (cpu % 64)) != 0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|w| *w == 0)
+    }
+
+    pub fn intersection(&self, other: &Self) -> Self {
+        let mut s = *self;
+        s.bits.iter_mut().zip(other.bits).for_each(|(a, b)| *a &= b);
+        s
+    }
+
+    pub fn difference(&self, other: &Self) -> Self {
+        let mut s = *self;
+        s.bits.iter_mut().zip(other.bits).for_each(|(a, b)| *a &= !b);
+        s
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = CpuIndex> + '_ {
+        (0..MAX_CPUS as CpuIndex).filter(|c| self.contains(*c))
+    }
+}
+
+impl core::str::FromStr for CpuSet {
+    type Err = ();
+
+    /// Parses a CPU list.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut set = Self::empty();
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (start, end) = match part.split_once('-') {
+                Some((a, b)) => (a.parse::<CpuIndex>().map_err(|_| ())?, b.parse::<CpuIndex>().map_err(|_| ())?),
+                None => {
+                    let c = part.parse::<CpuIndex>().map_err(|_| ())?;
+                    (c, c)
+                }
+            };
+            if start > end || end as usize >= MAX_CPUS {
+                return Err(());
+            }
+            (start..=end).for_each(|c| set.insert(c));
+        }
+        Ok(set)
+    }
+}
+
+impl core::fmt::Display for CpuSet {
+    /// Formats the set as a CPU list.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut first = true;
+        let mut cpus = self.iter().peekable();
+        while let Some(start) = cpus.next() {
+            let mut end = start;
+            while cpus.peek() == Some(&(end + 1)) {
+                end = cpus.next().unwrap();
+            }
+            if !first {
+                f.write_str(",")?;
+            }
+            first = false;
+            match end - start {
+                0 => write!(f, "{start}")?,
+                _ => write!(f, "{start}-{end}")?,
+            }
+        }
+        if first {
+            f.write_str("-")?;
+        }
+        Ok(())
+    }
+}
+
+static ISOLATED: spin::RwLock<CpuSet> = spin::RwLock::new(CpuSet::empty());
+
+/// Returns the CPUs which are isolated from general scheduling.
+pub fn isolated() -> CpuSet {
+    *ISOLATED.read()
+}
+
+/// Returns the affinity of tasks spawned from outside a task.
+pub fn default_set() -> CpuSet {
+    CpuSet::all().difference(&isolated())
+}
+
+/// Reads `isolcpus=` from the kernel command line. This must be called before any tasks are spawned.
+pub fn parse_cmdline(cmdline: &str) {
+    for list in cmdline.split_whitespace().filter_map(|a| a.strip_prefix("isolcpus=")) {
+        match list.parse::<CpuSet>() {
+            Ok(set) if set.contains(0) => log::warn!("Affinity: The boot CPU cannot be isolated: {list}"),
+            Ok(set) => {
+                log::info!("Affinity: Isolated CPUs {set}");
+                *ISOLATED.write() = set;
+            }
+            Err(()) => log::warn!("Affinity: Invalid CPU list: {list}"),
+        }
+    }
+}
+
+/// Returns the affinity of the task `id`.
+pub fn affinity(id: TaskId) -> Option<CpuSet> {
+    super::mp_executor::task_stats().into_iter().find(|t| t.id == id).map(|t| t.affinity)
+}
+
+/// Restricts the task `id` to the CPUs in `set`, moving it if it is owned by a CPU outside `set`.
+///
+/// Returns [IoError::NotPresent] if the task does not exist and [IoError::InvalidData] if `set`
+/// does not contain a running CPU.
+pub fn set_affinity(id: TaskId, set: CpuSet) -> Result<(), IoError> {
+    super::mp_executor::set_affinity(id, set)
+}
+
+/// Restricts the running task to the CPUs in `set`. The task is moved after it next yields.
+///
+/// Returns [IoError::NotReady] when this is not called from within a task.
+pub fn set_current(set: CpuSet) -> Result<(), IoError> {
+    let proc = super::job::current().ok_or(IoError::NotReady)?;
+    set_affinity(proc.id(), set)
+}
+
+/// Formats the affinity of all tasks, one task per line.
+pub fn report() -> String {
+    let mut s = String::new();
+    let _ = writeln!(s, "isolated: {}", isolated());
+    let _ = writeln!(s, "{:>6} {:>16}  ORIGIN", "ID", "CPUS");
+    for t in super::mp_executor::task_stats() {
+        let _ = writeln!(s, "{:>6} {:>16}  {}", t.id, alloc::format!("{}", t.affinity), t.origin);
+    }
+    s
+}
+
+/// Parses and applies a `<task id> <cpulist>` line.
+fn set_line(line: &str) -> Result<(), IoError> {
+    let (id, list) = line.split_once(char::is_whitespace).ok_or(IoError::InvalidData)?;
+    let id = id.parse::<u64>().map_err(|_| IoError::InvalidData)?;
+    let set = list.trim().parse::<CpuSet>().map_err(|()| IoError::InvalidData)?;
+    set_affinity(TaskId(id), set)
+}
+
+/// Mounts the affinity file.
+///
+/// Reading the file returns the output of [report], writing `<task id> <cpulist>` lines sets the
+/// affinity of tasks.
+pub fn init() {
+    let file = ReportFile::with_commands(DevID::new(*MAJOR, 0), report, set_line);
+    crate::task::util::block_on!(crate::fs::get_vfs().mount_dev(Box::new(file), FS_NAME))
+        .expect("Failed to mount affinity to VFS");
+}
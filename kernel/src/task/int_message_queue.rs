@@ -26,6 +26,11 @@ impl<T: MessageCfg> IntMessageQueue<T> {
         }
     }
 
+    /// Returns the IRQs owned by `self`, these may be passed to [crate::interrupts::affinity::note_submit].
+    pub fn irqs(&self) -> &[crate::interrupts::InterruptIndex] {
+        &self.interrupts
+    }
+
     /// Frees all IRQs owned by `self`. This must be called prior to dropping `self`.
     ///
     /// # Safety
@@ -0,0 +1,489 @@
+//! Process groups, sessions and job control signals.
+//!
+//! There are no user processes, job control operates on tasks. Every task belongs to a
+//! [ProcessGroup] and every group belongs to a [Session], a task joins the group of the task which
+//! spawned it. Tasks spawned from outside a task join the kernel group, which has the ID
+//! [TaskId::KERNEL]. Like POSIX the ID of a group or session is the ID of the task which created it.
+//!
+//! A session may have a virtual console as its controlling terminal, see
+//! [crate::graphics::vconsole::set_controlling]. The console's line discipline sends
+//! [Signal::Int] on Ctrl+C and [Signal::Tstp] on Ctrl+Z to its foreground process group.
+//!
+//! Signals are sent to every task in a group using [kill]. A task may catch signals using [catch],
+//! caught signals are queued, the task is woken and may fetch them using [take_signals]. Signals
+//! which are not caught take their default action when the executor next runs the task.
+//! - [Signal::Int], [Signal::Term], [Signal::Kill]: The task is terminated.
+//! - [Signal::Tstp], [Signal::Stop]: The task is stopped, it is not polled until it is continued.
+//! - [Signal::Cont]: The task is continued.
+//!
+//! [Signal::Kill] and [Signal::Stop] can't be caught, [Signal::Cont] always continues the task
+//! even when it is caught.
+//!
+//! # Exit and reaping
+//!
+//! A task exits by returning its [TaskResult] or by being terminated, the executor records an
+//! [ExitStatus]. Like `SIGCHLD` being ignored, children of a task are reaped as soon as they exit
+//! unless the task calls [track_children]. A tracked child which has exited remains a zombie
+//! until its parent collects the status using [waitpid], when the child is reaped its file table
+//! is closed. The future of a task, and the memory it owns, is dropped when the task exits, as is
+//! its reference to its address space.
+//!
+//! When a task exits its tracked children are reparented to the init task started by [init],
+//! which reaps them and logs any which did not exit normally.
+
+use super::rlimit::Resources;
+use super::{TaskId, TaskResult};
+use crate::fs::fd::FdTable;
+use crate::fs::IoError;
+use crate::mem::addr_space::AddressSpace;
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::Poll;
+use futures_util::task::AtomicWaker;
+
+/// Signals used for job control, the values are the same as Linux.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Signal {
+    Int = 2,
+    Kill = 9,
+    Term = 15,
+    Cont = 18,
+    Stop = 19,
+    Tstp = 20,
+}
+
+impl Signal {
+    pub const ALL: [Signal; 6] = [Signal::Int, Signal::Kill, Signal::Term, Signal::Cont, Signal::Stop, Signal::Tstp];
+
+    fn bit(self) -> u32 {
+        1 << self as u8
+    }
+
+    fn catchable(self) -> bool {
+        !matches!(self, Signal::Kill | Signal::Stop)
+    }
+}
+
+/// A set of [Signal]s.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct SignalSet(u32);
+
+impl SignalSet {
+    pub fn contains(&self, sig: Signal) -> bool {
+        self.0 & sig.bit() != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Signal> + '_ {
+        Signal::ALL.into_iter().filter(|s| self.contains(*s))
+    }
+}
+
+/// Run state of a task.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum State {
+    Running,
+    Stopped,
+    /// The task will be terminated the next time the executor runs it.
+    Killed(Signal),
+}
+
+/// How a task exited.
+#[derive(Copy, Clone, Debug)]
+pub enum ExitStatus {
+    /// The task returned.
+    Exited(TaskResult),
+    /// The task was terminated by a signal. Tasks which exceed their heap limit are terminated with [Signal::Kill].
+    Killed(Signal),
+}
+
+pub struct Session {
+    id: TaskId,
+    /// Virtual console which is the controlling terminal of the session.
+    tty: spin::Mutex<Option<usize>>,
+}
+
+impl Session {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn tty(&self) -> Option<usize> {
+        *self.tty.lock()
+    }
+
+    /// Sets the controlling terminal, this should only be called by the TTY layer.
+    pub(crate) fn set_tty(&self, tty: Option<usize>) {
+        *self.tty.lock() = tty;
+    }
+}
+
+pub struct ProcessGroup {
+    id: TaskId,
+    session: Arc<Session>,
+}
+
+impl ProcessGroup {
+    fn new(id: TaskId, session: Arc<Session>) -> Arc<Self> {
+        let g = Arc::new(Self { id, session });
+        GROUPS.write().insert(id, Arc::downgrade(&g));
+        g
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn session(&self) -> &Arc<Session> {
+        &self.session
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        let mut l = GROUPS.write();
+        if l.get(&self.id).is_some_and(|g| g.strong_count() == 0) {
+            l.remove(&self.id);
+        }
+    }
+}
+
+/// Groups which have at least one member.
+static GROUPS: spin::RwLock<BTreeMap<TaskId, Weak<ProcessGroup>>> = spin::RwLock::new(BTreeMap::new());
+
+lazy_static::lazy_static! {
+    static ref KERNEL_GROUP: Arc<ProcessGroup> = ProcessGroup::new(
+        TaskId::KERNEL,
+        Arc::new(Session { id: TaskId::KERNEL, tty: spin::Mutex::new(None) })
+    );
+}
+
+/// The init task, see [init].
+static INIT: spin::Once<Arc<Process>> = spin::Once::new();
+
+/// Job control and lifecycle state of a single task.
+pub struct Process {
+    id: TaskId,
+    group: spin::RwLock<Arc<ProcessGroup>>,
+    caught: AtomicU32,
+    pending: AtomicU32,
+    state: atomic::Atomic<State>,
+    parent: spin::Mutex<Weak<Process>>,
+    /// Children which have not been reaped, only used while `track_children` is set.
+    children: spin::Mutex<BTreeMap<TaskId, Arc<Process>>>,
+    track_children: AtomicBool,
+    /// Woken when a tracked child exits.
+    child_exit: AtomicWaker,
+    status: spin::Mutex<Option<ExitStatus>>,
+    files: spin::Mutex<FdTable>,
+    /// CPUs the task may run on, see [super::affinity].
+    affinity: spin::RwLock<super::affinity::CpuSet>,
+    /// `None` for kernel threads, see [crate::mem::addr_space].
+    address_space: spin::RwLock<Option<Arc<AddressSpace>>>,
+}
+
+impl Process {
+    /// Constructs the state for the new task `id`, joining the current task's group. The task's
+    /// files are charged to `resources`.
+    pub(super) fn inherit(id: TaskId, resources: &Arc<Resources>) -> Arc<Self> {
+        let parent = current().or_else(|| INIT.get().cloned());
+        let group = current().map_or_else(|| KERNEL_GROUP.clone(), |p| p.group());
+        let affinity = current().map_or_else(super::affinity::default_set, |p| p.affinity());
+        let address_space = current().and_then(|p| p.address_space());
+        let proc = Arc::new(Self {
+            id,
+            group: spin::RwLock::new(group),
+            caught: AtomicU32::new(0),
+            pending: AtomicU32::new(0),
+            state: atomic::Atomic::new(State::Running),
+            parent: spin::Mutex::new(parent.as_ref().map_or_else(Weak::new, Arc::downgrade)),
+            children: spin::Mutex::new(BTreeMap::new()),
+            track_children: AtomicBool::new(false),
+            child_exit: AtomicWaker::new(),
+            status: spin::Mutex::new(None),
+            files: spin::Mutex::new(FdTable::with_owner(resources.clone())),
+            affinity: spin::RwLock::new(affinity),
+            address_space: spin::RwLock::new(address_space),
+        });
+        if let Some(p) = parent.filter(|p| p.track_children.load(Ordering::Relaxed)) {
+            p.children.lock().insert(id, proc.clone());
+        }
+        proc
+    }
+
+    /// Records the exit status, releases the task's file locks, notifies the parent and reparents
+    /// tracked children to init.
+    pub(super) fn exit(&self, status: ExitStatus) {
+        *self.status.lock() = Some(status);
+        crate::fs::get_vfs().locks().unlock_all(self.id.into());
+        if let Some(parent) = self.parent.lock().upgrade() {
+            parent.child_exit.wake();
+        }
+
+        let children = core::mem::take(&mut *self.children.lock());
+        let Some(init) = INIT.get().filter(|i| i.id != self.id) else { return };
+        if children.is_empty() {
+            return;
+        }
+        for (id, child) in children {
+            *child.parent.lock() = Arc::downgrade(init);
+            init.children.lock().insert(id, child);
+        }
+        init.child_exit.wake();
+    }
+
+    /// Returns the file table of the task.
+    pub fn files(&self) -> spin::MutexGuard<'_, FdTable> {
+        self.files.lock()
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn affinity(&self) -> super::affinity::CpuSet {
+        *self.affinity.read()
+    }
+
+    pub(super) fn set_affinity(&self, set: super::affinity::CpuSet) {
+        *self.affinity.write() = set;
+    }
+
+    pub fn group(&self) -> Arc<ProcessGroup> {
+        self.group.read().clone()
+    }
+
+    pub fn address_space(&self) -> Option<Arc<AddressSpace>> {
+        self.address_space.read().clone()
+    }
+
+    /// Sets the address space the task runs in, it is loaded the next time the task is polled.
+    /// When this is `None` the task is a kernel thread and runs in whichever address space is loaded.
+    pub fn set_address_space(&self, space: Option<Arc<AddressSpace>>) {
+        *self.address_space.write() = space;
+    }
+
+    pub fn state(&self) -> State {
+        self.state.load(Ordering::Relaxed)
+    }
+
+    /// Delivers `sig` to the task, returns whether the task must be woken to handle it.
+    pub(super) fn deliver(&self, sig: Signal) -> bool {
+        if sig == Signal::Cont {
+            let _ = self.state.compare_exchange(State::Stopped, State::Running, Ordering::Relaxed, Ordering::Relaxed);
+        }
+        if sig.catchable() && self.caught.load(Ordering::Relaxed) & sig.bit() != 0 {
+            self.pending.fetch_or(sig.bit(), Ordering::Release);
+            return true;
+        }
+        match sig {
+            Signal::Int | Signal::Term | Signal::Kill => self.state.store(State::Killed(sig), Ordering::Relaxed),
+            Signal::Tstp | Signal::Stop => {
+                let _ = self.state.compare_exchange(State::Running, State::Stopped, Ordering::Relaxed, Ordering::Relaxed);
+            }
+            Signal::Cont => {}
+        }
+        true
+    }
+}
+
+/// Job control state of the task being polled on this CPU.
+#[thread_local]
+static CURRENT: core::cell::Cell<*const Process> = core::cell::Cell::new(core::ptr::null());
+
+/// Sets the process of the running task and returns the previous value. Used by the executor
+/// when switching tasks.
+pub(super) fn swap_current(proc: *const Process) -> *const Process {
+    CURRENT.replace(proc)
+}
+
+/// Returns the state of the running task, `None` when this is not called from within a task.
+pub fn current() -> Option<Arc<Process>> {
+    if crate::runlevel::runlevel() == crate::runlevel::Runlevel::PreInit {
+        return None;
+    }
+    let ptr = CURRENT.get();
+    if ptr.is_null() {
+        return None;
+    }
+    // SAFETY: CURRENT is only set by the executor to a pointer from Arc::as_ptr while the task,
+    // which holds a strong reference, is being polled.
+    unsafe {
+        Arc::increment_strong_count(ptr);
+        Some(Arc::from_raw(ptr))
+    }
+}
+
+/// Returns the ID of the running task without taking a reference to it, this is used by the
+/// allocator.
+pub(crate) fn current_id() -> Option<TaskId> {
+    if crate::runlevel::runlevel() == crate::runlevel::Runlevel::PreInit {
+        return None;
+    }
+    // SAFETY: See [current]
+    unsafe { CURRENT.get().as_ref() }.map(Process::id)
+}
+
+/// Returns the group with the ID `pgid` if it has any members.
+pub fn group(pgid: TaskId) -> Option<Arc<ProcessGroup>> {
+    GROUPS.read().get(&pgid)?.upgrade()
+}
+
+/// Returns the process group of the running task.
+pub fn getpgid() -> TaskId {
+    current().map_or(TaskId::KERNEL, |p| p.group().id)
+}
+
+/// Returns the session of the running task.
+pub fn getsid() -> TaskId {
+    current().map_or(TaskId::KERNEL, |p| p.group().session.id)
+}
+
+/// Moves the running task into the group `pgid`, or into a new group led by the task when `pgid`
+/// is `None`.
+///
+/// Returns [IoError::NotPresent] if the group does not exist and [IoError::PermissionDenied] if
+/// the group is in another session or the task is the leader of its session.
+pub fn setpgid(pgid: Option<TaskId>) -> Result<(), IoError> {
+    let proc = current().ok_or(IoError::NotSupported)?;
+    let mut l = proc.group.write();
+    if l.session.id == proc.id {
+        return Err(IoError::PermissionDenied);
+    }
+    let new = match pgid {
+        Some(id) if id == l.id => return Ok(()),
+        Some(id) => group(id).ok_or(IoError::NotPresent)?,
+        None => ProcessGroup::new(proc.id, l.session.clone()),
+    };
+    if !Arc::ptr_eq(&new.session, &l.session) {
+        return Err(IoError::PermissionDenied);
+    }
+    *l = new;
+    Ok(())
+}
+
+/// Creates a new session without a controlling terminal and moves the running task into a new
+/// group within it. Returns the ID of the session.
+///
+/// Returns [IoError::PermissionDenied] if the task already leads a group.
+pub fn setsid() -> Result<TaskId, IoError> {
+    let proc = current().ok_or(IoError::NotSupported)?;
+    let mut l = proc.group.write();
+    if l.id == proc.id || group(proc.id).is_some() {
+        return Err(IoError::PermissionDenied);
+    }
+    let session = Arc::new(Session { id: proc.id, tty: spin::Mutex::new(None) });
+    *l = ProcessGroup::new(proc.id, session);
+    Ok(proc.id)
+}
+
+/// Sets whether the running task catches `sig`. This does nothing for signals which can't be caught.
+pub fn catch(sig: Signal, caught: bool) {
+    let Some(proc) = current() else { return };
+    if !sig.catchable() {
+        return;
+    }
+    match caught {
+        true => proc.caught.fetch_or(sig.bit(), Ordering::Relaxed),
+        false => proc.caught.fetch_and(!sig.bit(), Ordering::Relaxed),
+    };
+}
+
+/// Returns and clears the caught signals which are pending for the running task.
+pub fn take_signals() -> SignalSet {
+    SignalSet(current().map_or(0, |p| p.pending.swap(0, Ordering::Acquire)))
+}
+
+/// Sets whether the running task keeps its children as zombies when they exit so that their
+/// status can be collected by [waitpid]. This only affects children spawned afterwards.
+pub fn track_children(track: bool) {
+    if let Some(proc) = current() {
+        proc.track_children.store(track, Ordering::Relaxed);
+    }
+}
+
+/// Removes and returns an exited child matching `pid`.
+fn reap(proc: &Process, pid: Option<TaskId>) -> Option<(TaskId, ExitStatus)> {
+    let mut l = proc.children.lock();
+    let (id, status) = l.iter().filter(|(id, _)| pid.is_none_or(|p| p == **id)).find_map(|(id, c)| Some((*id, (*c.status.lock())?)))?;
+    // The child's file table is closed when it is dropped here
+    l.remove(&id);
+    Some((id, status))
+}
+
+/// Returns the ID and status of a tracked child which has exited, or `None` if no child matching
+/// `pid` has exited. When `pid` is `None` any child may be returned.
+///
+/// Returns [IoError::NotPresent] if the running task has no tracked children matching `pid`.
+pub fn try_waitpid(pid: Option<TaskId>) -> Result<Option<(TaskId, ExitStatus)>, IoError> {
+    let proc = current().ok_or(IoError::NotSupported)?;
+    if let Some(r) = reap(&proc, pid) {
+        return Ok(Some(r));
+    }
+    match pid {
+        Some(p) if !proc.children.lock().contains_key(&p) => Err(IoError::NotPresent),
+        None if proc.children.lock().is_empty() => Err(IoError::NotPresent),
+        _ => Ok(None),
+    }
+}
+
+/// Waits for a tracked child matching `pid` to exit and reaps it. See [try_waitpid].
+pub async fn waitpid(pid: Option<TaskId>) -> Result<(TaskId, ExitStatus), IoError> {
+    let proc = current().ok_or(IoError::NotSupported)?;
+    wait(&proc, pid, false).await
+}
+
+/// Waits for a child of `proc`, when `block` is set this waits even when `proc` has no children.
+async fn wait(proc: &Process, pid: Option<TaskId>, block: bool) -> Result<(TaskId, ExitStatus), IoError> {
+    core::future::poll_fn(|cx| {
+        proc.child_exit.register(cx.waker());
+        if let Some(r) = reap(proc, pid) {
+            return Poll::Ready(Ok(r));
+        }
+        let empty = match pid {
+            Some(p) => !proc.children.lock().contains_key(&p),
+            None => proc.children.lock().is_empty(),
+        };
+        match empty && !block {
+            true => Poll::Ready(Err(IoError::NotPresent)),
+            false => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// Starts the init task which adopts the tracked children of tasks which exit.
+pub fn init() {
+    super::run_task(alloc::boxed::Box::pin(reaper()));
+}
+
+async fn reaper() -> TaskResult {
+    let Some(proc) = current() else { return TaskResult::Error };
+    proc.track_children.store(true, Ordering::Relaxed);
+    INIT.call_once(|| proc.clone());
+    loop {
+        match wait(&proc, None, true).await {
+            Ok((_, ExitStatus::Exited(TaskResult::ExitedNormally))) => {}
+            Ok((id, status)) => log::info!("init: Reaped orphan task {id}, {status:?}"),
+            Err(_) => unreachable!(), // wait blocks while there are no children
+        }
+    }
+}
+
+/// Sends `sig` to every task in the group `pgid`, returns the number of tasks it was sent to.
+///
+/// The kernel group can't be signalled, [IoError::PermissionDenied] is returned.
+pub fn kill(pgid: TaskId, sig: Signal) -> Result<usize, IoError> {
+    if pgid == TaskId::KERNEL {
+        return Err(IoError::PermissionDenied);
+    }
+    match super::mp_executor::signal_group(pgid, sig) {
+        0 => Err(IoError::NotPresent),
+        n => Ok(n),
+    }
+}
@@ -1,4 +1,5 @@
-use crate::{print, println};
+use crate::graphics::{basic_output, vconsole};
+use crate::println;
 use conquer_once::spin::OnceCell;
 use core::pin::Pin;
 use core::task::{Context, Poll};
@@ -6,7 +7,7 @@ use crossbeam_queue::ArrayQueue;
 use futures_util::stream::Stream;
 use futures_util::task::AtomicWaker;
 use futures_util::StreamExt;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1};
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
@@ -64,14 +65,48 @@ impl Stream for ScanCodeStream {
 
 pub async fn print_key() -> crate::task::TaskResult {
     let mut scancodes = ScanCodeStream::new();
-    let mut kb = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+    let mut kb = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::MapLettersToUnicode);
+    let mut shift = false;
+    let mut alt = false;
 
     while let Some(scancode) = scancodes.next().await {
         if let Ok(Some(key_event)) = kb.add_byte(scancode) {
+            match (&key_event.code, &key_event.state) {
+                (KeyCode::ShiftLeft | KeyCode::ShiftRight, state) => shift = *state == KeyState::Down,
+                (KeyCode::AltLeft | KeyCode::AltRight, state) => alt = *state == KeyState::Down,
+                // Alt+Fn displays virtual console n-1
+                (KeyCode::F1 | KeyCode::F2 | KeyCode::F3 | KeyCode::F4, KeyState::Down) if alt => {
+                    let n = match key_event.code {
+                        KeyCode::F1 => 0,
+                        KeyCode::F2 => 1,
+                        KeyCode::F3 => 2,
+                        _ => 3,
+                    };
+                    basic_output::switch_console(n);
+                    continue;
+                }
+                // Shift+PageUp/PageDown navigates the console scrollback
+                (KeyCode::PageUp, KeyState::Down) if shift => {
+                    basic_output::scroll_console(1);
+                    continue;
+                }
+                (KeyCode::PageDown, KeyState::Down) if shift => {
+                    basic_output::scroll_console(-1);
+                    continue;
+                }
+                _ => {}
+            }
+
             if let Some(key) = kb.process_keyevent(key_event) {
+                // Input is echoed to and queued for the displayed console
+                let console = basic_output::active_console();
                 match key {
-                    DecodedKey::Unicode(char) => print!("{}", char),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
+                    DecodedKey::Unicode(char) if !vconsole::push_input(char) => {
+                        // Echo consumed control characters as ^C
+                        basic_output::_print_to(console, format_args!("^{}\n", (char as u8 + b'@') as char))
+                    }
+                    DecodedKey::Unicode(char) => basic_output::_print_to(console, format_args!("{}", char)),
+                    DecodedKey::RawKey(key) => basic_output::_print_to(console, format_args!("{:?}", key)),
                 }
             }
         }
@@ -3,12 +3,20 @@ use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::{Context, Poll};
 use core::{future::Future, pin::Pin};
 
+pub mod affinity;
 pub mod executor;
 pub mod int_message_queue;
+pub mod job;
 pub mod keyboard;
 pub mod mp_executor;
+pub mod rlimit;
 pub mod simple_executor;
+pub mod spawn;
+pub mod timer_wheel;
+pub mod top;
 pub mod util;
+pub mod wait;
+pub mod workqueue;
 
 static SYS_EXECUTOR: spin::RwLock<alloc::collections::BTreeMap<crate::mp::CpuIndex,mp_executor::LocalExec>> =
     spin::RwLock::new(alloc::collections::BTreeMap::new());
@@ -45,12 +53,27 @@ impl Task {
 pub struct TaskId(u64);
 
 impl TaskId {
+    /// Not used by any task, identifies the kernel's process group and session. See [job].
+    pub const KERNEL: TaskId = TaskId(0);
+
     fn new() -> Self {
-        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
 }
 
+impl From<TaskId> for u64 {
+    fn from(id: TaskId) -> Self {
+        id.0
+    }
+}
+
+impl core::fmt::Display for TaskId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 
 #[derive(Debug, Clone, Copy)]
 /// Return type returned by all top-level tasks.
@@ -75,8 +98,18 @@ impl From<()> for TaskResult {
     }
 }
 
+/// Spawns `fut` on this CPU's executor. The caller's location is recorded to identify the task in
+/// [mp_executor::task_stats].
+#[track_caller]
 pub fn run_task(fut: Pin<Box<dyn Future<Output = TaskResult> + Send>>) {
+    start_task(fut);
+}
+
+/// Spawns `fut` like [run_task] and returns the ID of the new task.
+#[track_caller]
+pub fn start_task(fut: Pin<Box<dyn Future<Output = TaskResult> + Send>>) -> TaskId {
     let t = mp_executor::Task::new(fut);
+    let id = t.id();
 
     let b = SYS_EXECUTOR.upgradeable_read();
     if let Some(e) = b.get(&crate::who_am_i()) { // You started an AP without reworking the executor for MP if this panics
@@ -84,8 +117,24 @@ pub fn run_task(fut: Pin<Box<dyn Future<Output = TaskResult> + Send>>) {
     } else {
         let mut w = b.upgrade();
         w.insert(crate::who_am_i(),mp_executor::LocalExec::new());
-        w.get(&crate::who_am_i()).unwrap().spawn(t);
+        // spawn may need to read the executors to place the task on another CPU
+        w.downgrade().get(&crate::who_am_i()).unwrap().spawn(t);
+    }
+    id
+}
+
+/// Spawns `fut` like [run_task] unless the calling task has reached its
+/// [rlimit::Resource::PendingOps] limit, in which case `fut` is returned.
+#[track_caller]
+pub fn try_run_task(fut: Pin<Box<dyn Future<Output = TaskResult> + Send>>) -> Result<(), Pin<Box<dyn Future<Output = TaskResult> + Send>>> {
+    if let Some(r) = rlimit::current() {
+        let limit = r.limit(rlimit::Resource::PendingOps);
+        if limit != 0 && r.usage(rlimit::Resource::PendingOps) >= limit {
+            return Err(fut);
+        }
     }
+    run_task(fut);
+    Ok(())
 }
 
 pub fn run_exec() -> ! {
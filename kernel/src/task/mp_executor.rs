@@ -4,6 +4,7 @@ use alloc::{
 };
 use core::future::Future;
 use core::pin::Pin;
+use core::sync::atomic::AtomicU64;
 use core::task::{Poll, Waker};
 
 const RUN_QUEUE_SIZE: usize = 128;
@@ -19,21 +20,139 @@ struct TaskWaker {
 impl alloc::task::Wake for TaskWaker {
     fn wake(self: Arc<Self>) {
         if let Some(task) = self.task.upgrade() {
-            let n = task.owner.load(atomic::Ordering::Relaxed).num();
-            super::SYS_EXECUTOR.read().get(&n).unwrap()
-                .run_queue
-                .push(self.id)
-                .expect("Run queue is full");
+            debug_assert_eq!(task.id, self.id);
+            if task.notify() {
+                task.enqueue();
+            }
         } // return on else because if the upgrade fails then the task has been dropped and cannot be run anyway
     }
 }
 
+/// Scheduling state of a task.
+///
+/// A sleeping task is only placed on a run queue by its waker, further wakeups are ignored until it
+/// is polled again. Wakeups which arrive while the task is being polled are recorded and the task is
+/// requeued when it returns [Poll::Pending].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Sched {
+    /// The task is in a run queue.
+    Queued,
+    /// The task is being polled.
+    Running,
+    /// The task was woken while it was being polled.
+    Notified,
+    /// The task is waiting to be woken.
+    Sleeping,
+}
+
 
 pub struct Task {
     id: super::TaskId,
     inner: crate::util::mutex::MentallyUnstableMutex<TaskableFuture>, // should not be accessed multiple times
     owner: atomic::Atomic<TaskOwner>,
+    sched: atomic::Atomic<Sched>,
     waker: spin::Mutex<Weak<TaskWaker>>,
+    mem_policy: atomic::Atomic<crate::mem::numa::MemPolicy>,
+    /// Location the task was spawned from
+    origin: &'static core::panic::Location<'static>,
+    polls: AtomicU64,
+    /// Nanoseconds spent polling the task
+    run_time: AtomicU64,
+    /// Number of times polling the task loaded its address space
+    as_switches: AtomicU64,
+    /// What the task waited for when it last returned [Poll::Pending]
+    wait: spin::Mutex<super::wait::WaitChain>,
+    resources: Arc<super::rlimit::Resources>,
+    /// Resources of the task which spawned this task, charged with a pending operation until this task completes.
+    parent: Option<Arc<super::rlimit::Resources>>,
+    process: Arc<super::job::Process>,
+}
+
+/// Accounting information for a task, see [task_stats].
+#[derive(Copy, Clone, Debug)]
+pub struct TaskStats {
+    pub id: super::TaskId,
+    /// CPU which currently owns the task
+    pub cpu: crate::mp::CpuIndex,
+    /// Location the task was spawned from, tasks do not have names so this identifies the task.
+    pub origin: &'static core::panic::Location<'static>,
+    /// Number of times the task has been polled
+    pub polls: u64,
+    /// Nanoseconds spent polling the task
+    pub run_time: u64,
+    /// Number of times the task's address space was loaded to poll it, see [crate::mem::addr_space].
+    pub as_switches: u64,
+    pub resources: super::rlimit::Usage,
+    pub pgid: super::TaskId,
+    pub state: super::job::State,
+    pub sched: Sched,
+    pub affinity: super::affinity::CpuSet,
+    /// What the task waited for when it was last polled, see [super::wait].
+    pub wait: super::wait::WaitChain,
+}
+
+/// Returns accounting information for all tasks which have not exited.
+pub fn task_stats() -> alloc::vec::Vec<TaskStats> {
+    GLOBAL_TASK_CACHE.cache.read().values().map(|t| TaskStats {
+        id: t.id,
+        cpu: t.owner.load(atomic::Ordering::Relaxed).num(),
+        origin: t.origin,
+        polls: t.polls.load(atomic::Ordering::Relaxed),
+        run_time: t.run_time.load(atomic::Ordering::Relaxed),
+        as_switches: t.as_switches.load(atomic::Ordering::Relaxed),
+        resources: t.resources.snapshot(),
+        pgid: t.process.group().id(),
+        state: t.process.state(),
+        sched: t.sched.load(atomic::Ordering::Relaxed),
+        affinity: t.process.affinity(),
+        wait: *t.wait.lock(),
+    }).collect()
+}
+
+/// Returns the CPU a task with affinity `set` should be placed on.
+///
+/// This is the calling CPU when it is in `set`, otherwise the CPU in `set` with the shortest run queue.
+pub(super) fn select_cpu(set: &super::affinity::CpuSet) -> Option<crate::mp::CpuIndex> {
+    if set.contains(crate::who_am_i()) {
+        return Some(crate::who_am_i());
+    }
+    super::SYS_EXECUTOR.read().iter().filter(|(cpu, _)| set.contains(**cpu)).min_by_key(|(_, e)| e.run_queue.len()).map(|(cpu, _)| *cpu)
+}
+
+/// Sets the affinity of the task `id`, see [super::affinity::set_affinity].
+pub(super) fn set_affinity(id: super::TaskId, set: super::affinity::CpuSet) -> Result<(), crate::fs::IoError> {
+    let task = GLOBAL_TASK_CACHE.fetch(id).ok_or(crate::fs::IoError::NotPresent)?;
+    let cpu = select_cpu(&set).ok_or(crate::fs::IoError::InvalidData)?;
+    task.process.set_affinity(set);
+    if !set.contains(task.owner.load(atomic::Ordering::Relaxed).num()) {
+        // Queued wakeups are forwarded to the new owner by the old owner's executor
+        task.owner.store(TaskOwner::Cpu(cpu), atomic::Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Delivers `sig` to every task in the process group `pgid`, returns the number of tasks in the group.
+pub(super) fn signal_group(pgid: super::TaskId, sig: super::job::Signal) -> usize {
+    let tasks: alloc::vec::Vec<Arc<Task>> = GLOBAL_TASK_CACHE.cache.read().values().filter(|t| t.process.group().id() == pgid).cloned().collect();
+    for t in &tasks {
+        if t.process.deliver(sig) {
+            t.waker().wake();
+        }
+    }
+    tasks.len()
+}
+
+/// Number of times an address space was loaded to poll a task.
+static SWITCHES: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of address space switches made by all executors.
+pub fn as_switches() -> u64 {
+    SWITCHES.load(atomic::Ordering::Relaxed)
+}
+
+/// Returns the number of tasks waiting in each CPU's run queue.
+pub fn run_queue_lens() -> alloc::vec::Vec<(crate::mp::CpuIndex, usize)> {
+    super::SYS_EXECUTOR.read().iter().map(|(cpu, e)| (*cpu, e.run_queue.len())).collect()
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -50,12 +169,70 @@ impl TaskOwner {
 }
 
 impl Task {
+    #[track_caller]
     pub fn new(fut: impl Future<Output = super::TaskResult> + Send + 'static) -> Self {
+        let parent = super::rlimit::current();
+        if let Some(p) = &parent {
+            p.charge(super::rlimit::Resource::PendingOps, 1);
+        }
+        let id = super::TaskId::new();
+        let resources = super::rlimit::Resources::inherit();
+        let process = super::job::Process::inherit(id, &resources);
         Self {
-            id: super::TaskId::new(),
+            id,
             inner: crate::util::mutex::MentallyUnstableMutex::new(Box::pin(fut)),
-            owner: atomic::Atomic::new(TaskOwner::Cpu(crate::who_am_i())),
+            owner: atomic::Atomic::new(TaskOwner::Cpu(select_cpu(&process.affinity()).unwrap_or_else(crate::who_am_i))),
+            // spawn places the task in a run queue
+            sched: atomic::Atomic::new(Sched::Queued),
             waker: spin::Mutex::new(Weak::new()),
+            // inherits the policy of the spawning task
+            mem_policy: atomic::Atomic::new(crate::mem::numa::task_policy()),
+            origin: core::panic::Location::caller(),
+            polls: AtomicU64::new(0),
+            run_time: AtomicU64::new(0),
+            as_switches: AtomicU64::new(0),
+            wait: spin::Mutex::new(super::wait::WaitChain::default()),
+            resources,
+            parent,
+            process,
+        }
+    }
+
+    pub fn id(&self) -> super::TaskId {
+        self.id
+    }
+
+    /// Records a wakeup, returns `true` if the task was sleeping and must be placed in a run queue.
+    fn notify(&self) -> bool {
+        let mut state = self.sched.load(atomic::Ordering::Acquire);
+        loop {
+            let next = match state {
+                Sched::Sleeping => Sched::Queued,
+                Sched::Running => Sched::Notified,
+                Sched::Queued | Sched::Notified => return false,
+            };
+            match self.sched.compare_exchange_weak(state, next, atomic::Ordering::AcqRel, atomic::Ordering::Acquire) {
+                Ok(_) => return next == Sched::Queued,
+                Err(s) => state = s,
+            }
+        }
+    }
+
+    /// Pushes the task to the run queue of the CPU which owns it.
+    fn enqueue(&self) {
+        let n = self.owner.load(atomic::Ordering::Relaxed).num();
+        super::SYS_EXECUTOR.read().get(&n).unwrap()
+            .run_queue
+            .push(self.id)
+            .expect("Run queue is full");
+        crate::time::tickless::kick(n);
+    }
+
+    /// Puts the task to sleep after it was polled, requeueing it if it was woken while running.
+    fn sleep(&self) {
+        if self.sched.compare_exchange(Sched::Running, Sched::Sleeping, atomic::Ordering::AcqRel, atomic::Ordering::Acquire).is_err() {
+            self.sched.store(Sched::Queued, atomic::Ordering::Release);
+            self.enqueue();
         }
     }
 
@@ -77,7 +254,32 @@ impl Task {
     /// Convenience function for polling the future within `self`.
     fn poll(&self, cx: &mut core::task::Context) -> Poll<super::TaskResult> {
         let ref mut t = *self.inner.lock();
-        t.as_mut().poll(cx)
+        let prev = crate::mem::numa::swap_policy(self.mem_policy.load(atomic::Ordering::Relaxed));
+        let prev_res = super::rlimit::swap_current(Arc::as_ptr(&self.resources));
+        let prev_proc = super::job::swap_current(Arc::as_ptr(&self.process));
+        if crate::mem::addr_space::switch(self.process.address_space().as_ref()) {
+            self.as_switches.fetch_add(1, atomic::Ordering::Relaxed);
+            SWITCHES.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+        let start = crate::time::get_sys_time();
+        super::wait::begin_poll();
+        crate::trace::poll(self.id);
+        let r = t.as_mut().poll(cx);
+        *self.wait.lock() = super::wait::end_poll();
+        self.run_time.fetch_add(crate::time::get_sys_time().saturating_sub(start), atomic::Ordering::Relaxed);
+        self.polls.fetch_add(1, atomic::Ordering::Relaxed);
+        super::job::swap_current(prev_proc);
+        super::rlimit::swap_current(prev_res);
+        self.mem_policy.store(crate::mem::numa::swap_policy(prev), atomic::Ordering::Relaxed);
+        r
+    }
+
+    /// Records the exit status and releases the pending operation charged to the parent task.
+    fn complete(&self, status: super::job::ExitStatus) {
+        self.process.exit(status);
+        if let Some(p) = &self.parent {
+            p.uncharge(super::rlimit::Resource::PendingOps, 1);
+        }
     }
 }
 
@@ -138,6 +340,7 @@ impl LocalExec {
     }
 
     fn run_ready(&self) {
+        crate::system::watchdog::heartbeat();
 
         // when another CPU steals a task the cache should be invalidated
         if let Ok(_) = self.invalidate.compare_exchange_weak(true,false,atomic::Ordering::Relaxed,atomic::Ordering::Relaxed) {
@@ -157,10 +360,28 @@ impl LocalExec {
             if task.owner.load(atomic::Ordering::Relaxed).num() != self.i {
                 // Some tasks may need to be woken a certain number of times
                 // This forwards wakeups to the new CPU instead of running here.
-                task.waker().wake();
+                task.enqueue();
                 continue;
             }
 
+            match task.process.state() {
+                super::job::State::Running => {}
+                // Woken again when it is continued
+                super::job::State::Stopped => {
+                    task.sched.store(Sched::Sleeping, atomic::Ordering::Release);
+                    // Don't lose a continue which arrived before the task was asleep
+                    if task.process.state() != super::job::State::Stopped && task.notify() {
+                        task.enqueue();
+                    }
+                    continue;
+                }
+                super::job::State::Killed(sig) => {
+                    log::info!("Task {id} spawned at {} was terminated by {sig:?}", task.origin);
+                    self.terminate(&task, super::job::ExitStatus::Killed(sig));
+                    continue;
+                }
+            }
+
             let waker = self
                 .cache
                 .try_lock().unwrap() // shouldn't panic
@@ -169,10 +390,12 @@ impl LocalExec {
                 .or_insert_with(|| task.waker())
                 .clone();
 
+            task.sched.store(Sched::Running, atomic::Ordering::Release);
             match task.poll(&mut core::task::Context::from_waker(&waker)) {
                 // todo impl Display for task and display more info here
                 Poll::Ready(r) => {
                     GLOBAL_TASK_CACHE.drop(id);
+                    task.complete(super::job::ExitStatus::Exited(r));
                     // todo implement Display for Task, should show a name and owned device(s)
                     match r {
                         super::TaskResult::ExitedNormally => {}
@@ -181,13 +404,21 @@ impl LocalExec {
                         super::TaskResult::Panicked => log::error!("{id:?} Panicked and was caught successfully")
                     }
                 }
-                _ => {} // ops normal
+                Poll::Pending => task.sleep(),
             }
 
 
         }
     }
 
+    /// Removes `task` without completing it.
+    fn terminate(&self, task: &Arc<Task>, status: super::job::ExitStatus) {
+        GLOBAL_TASK_CACHE.drop(task.id);
+        self.cache.lock().local_cache.remove(&task.id);
+        task.complete(status);
+        // The future is dropped with the last reference, freeing its memory
+    }
+
     fn fetch_task(&self, id: super::TaskId) -> Option<Arc<Task>> {
         let mut l = self.cache.lock();
         if let Some(w) = l.local_cache.get(&id) {
@@ -224,9 +455,15 @@ impl LocalExec {
             let b = super::SYS_EXECUTOR.read();
             if let Some(target) = b.get(&target_id) {
                 if let Some(tid) = target.run_queue.pop() {
+                    let task = GLOBAL_TASK_CACHE.fetch(tid);
+                    if task.as_ref().is_some_and(|t| !t.process.affinity().contains(crate::who_am_i())) {
+                        // Not allowed to run here, return it to the back of its queue
+                        target.run_queue.push(tid).expect("Run queue is full");
+                        continue;
+                    }
                     target.invalidate.store(true,atomic::Ordering::Relaxed);
 
-                    if let Some(i) = GLOBAL_TASK_CACHE.fetch(tid) {
+                    if let Some(i) = task {
                         i.owner.store(TaskOwner::Cpu(crate::who_am_i()), atomic::Ordering::Relaxed);
                     }
                     return Some(tid);
@@ -240,9 +477,29 @@ impl LocalExec {
     fn idle(&self) {
         use x86_64::instructions::interrupts;
 
+        crate::mem::allocator::idle_reclaim();
+        crate::system::cpufreq::update();
+
         interrupts::disable();
         if self.run_queue.is_empty() {
-            interrupts::enable_and_hlt();
+            crate::time::tickless::enter_idle();
+            // A task queued before the tick was stopped was not kicked
+            core::sync::atomic::fence(atomic::Ordering::SeqCst);
+            if !self.run_queue.is_empty() {
+                crate::time::tickless::exit_idle();
+                interrupts::enable();
+                return;
+            }
+            crate::system::watchdog::set_idle(true);
+            if crate::system::cpufreq::enabled() {
+                let start = crate::time::get_sys_time();
+                interrupts::enable_and_hlt();
+                crate::system::cpufreq::account_idle(crate::time::get_sys_time().saturating_sub(start));
+            } else {
+                interrupts::enable_and_hlt();
+            }
+            crate::time::tickless::exit_idle();
+            crate::system::watchdog::set_idle(false);
         } else {
             interrupts::enable();
         }
@@ -259,6 +516,11 @@ impl LocalExec {
         let task = Arc::new(task);
         let id = task.id;
         GLOBAL_TASK_CACHE.insert(task.clone());
+        if task.owner.load(atomic::Ordering::Relaxed).num() != self.i {
+            // Placed on another CPU by its affinity
+            task.enqueue();
+            return;
+        }
         self.cache.lock().local_cache.insert(id,task);
         self.run_queue.push(id).expect("Run queue is full");
     }
@@ -0,0 +1,200 @@
+//! Per-task resource limits.
+//!
+//! Each task has a set of [Resources] which records its usage of each [Resource] and the limit for
+//! each. A task inherits the limits of the task which spawned it, tasks spawned from outside a
+//! task use the defaults given by the `rlimit.*` tunables. A limit of `0` is unlimited.
+//!
+//! - [Resource::Heap]: Bytes allocated from the kernel heap while the task is polled. Memory freed
+//!   by a different task is uncharged from that task instead, so caches filled by one task and
+//!   emptied by another are not accounted accurately, for this reason the default is unlimited.
+//!   Allocations which would exceed the limit fail, fallible allocations such as
+//!   [alloc::vec::Vec::try_reserve] return an error and infallible allocations call the allocation
+//!   error handler.
+//! - [Resource::OpenFiles]: Files in [crate::fs::fd::FdTable]s created by the task. Inserting a
+//!   file into a table fails with [crate::fs::IoError::LimitExceeded].
+//! - [Resource::PendingOps]: Tasks spawned by the task which have not yet completed. This is
+//!   only enforced by [super::try_run_task], [super::run_task] always spawns the task.
+//!
+//! The usage and limits of every task are exported at `/rlimits`.
+
+use crate::config::Tunable;
+use crate::fs::report::ReportFile;
+use crate::fs::vfs::{DevID, MajorNum};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const FS_NAME: &str = "/rlimits";
+
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("rlimits").unwrap(););
+
+/// Default heap limit for tasks spawned outside a task.
+pub static DEFAULT_HEAP: Tunable<usize> = Tunable::new("rlimit.heap_bytes", "Default heap bytes a task may allocate, 0 is unlimited", 0);
+
+/// Default open file limit for tasks spawned outside a task.
+pub static DEFAULT_OPEN_FILES: Tunable<usize> = Tunable::new("rlimit.open_files", "Default number of files a task may hold open, 0 is unlimited", 256);
+
+/// Default pending operation limit for tasks spawned outside a task.
+pub static DEFAULT_PENDING_OPS: Tunable<usize> = Tunable::new("rlimit.pending_ops", "Default number of incomplete tasks a task may spawn, 0 is unlimited", 1024);
+
+/// Resources which are limited per task.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Resource {
+    Heap = 0,
+    OpenFiles,
+    PendingOps,
+}
+
+impl Resource {
+    pub const ALL: [Resource; 3] = [Resource::Heap, Resource::OpenFiles, Resource::PendingOps];
+}
+
+/// Resource usage and limits of a single task.
+pub struct Resources {
+    usage: [AtomicUsize; 3],
+    limits: [AtomicUsize; 3],
+}
+
+/// A snapshot of a task's [Resources].
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Usage {
+    pub usage: [usize; 3],
+    pub limits: [usize; 3],
+}
+
+impl Resources {
+    fn new(limits: [usize; 3]) -> Self {
+        Self {
+            usage: Default::default(),
+            limits: limits.map(AtomicUsize::new),
+        }
+    }
+
+    /// Constructs resources for a new task, inheriting the limits of the current task.
+    pub(super) fn inherit() -> Arc<Self> {
+        let limits = match current() {
+            Some(r) => Resource::ALL.map(|res| r.limit(res)),
+            None => [DEFAULT_HEAP.get(), DEFAULT_OPEN_FILES.get(), DEFAULT_PENDING_OPS.get()],
+        };
+        Arc::new(Self::new(limits))
+    }
+
+    pub fn usage(&self, res: Resource) -> usize {
+        self.usage[res as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn limit(&self, res: Resource) -> usize {
+        self.limits[res as usize].load(Ordering::Relaxed)
+    }
+
+    /// Sets the limit of `res`, this does not affect resources which have already been charged.
+    pub fn set_limit(&self, res: Resource, limit: usize) {
+        self.limits[res as usize].store(limit, Ordering::Relaxed)
+    }
+
+    /// Charges `n` units of `res`, returns `false` without charging anything if this would exceed the limit.
+    pub fn try_charge(&self, res: Resource, n: usize) -> bool {
+        let limit = self.limit(res);
+        self.usage[res as usize]
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |u| u.checked_add(n).filter(|u| limit == 0 || *u <= limit))
+            .is_ok()
+    }
+
+    /// Charges `n` units of `res` regardless of the limit.
+    pub fn charge(&self, res: Resource, n: usize) {
+        self.usage[res as usize].fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn uncharge(&self, res: Resource, n: usize) {
+        let _ = self.usage[res as usize].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |u| Some(u.saturating_sub(n)));
+    }
+
+    pub fn snapshot(&self) -> Usage {
+        Usage {
+            usage: Resource::ALL.map(|r| self.usage(r)),
+            limits: Resource::ALL.map(|r| self.limit(r)),
+        }
+    }
+}
+
+/// Resources of the task which is currently being polled on this CPU.
+#[thread_local]
+static CURRENT: core::cell::Cell<*const Resources> = core::cell::Cell::new(core::ptr::null());
+
+/// Sets the resources of the running task and returns the previous value. Used by the executor
+/// when switching tasks.
+pub(super) fn swap_current(res: *const Resources) -> *const Resources {
+    CURRENT.replace(res)
+}
+
+/// Returns the resources of the task which is currently running, `None` when this is not called
+/// from within a task.
+pub fn current() -> Option<Arc<Resources>> {
+    if crate::runlevel::runlevel() == crate::runlevel::Runlevel::PreInit {
+        return None;
+    }
+    let ptr = CURRENT.get();
+    if ptr.is_null() {
+        return None;
+    }
+    // SAFETY: CURRENT is only set by the executor to a pointer from Arc::as_ptr while the task,
+    // which holds a strong reference, is being polled.
+    unsafe {
+        Arc::increment_strong_count(ptr);
+        Some(Arc::from_raw(ptr))
+    }
+}
+
+/// Charges a heap allocation to the running task, returns `false` without charging anything if
+/// this would exceed the task's heap limit.
+///
+/// This is called by the global allocator so it must not allocate or log.
+pub(crate) fn charge_heap(bytes: usize) -> bool {
+    // The TLS may not be initialized yet.
+    if crate::runlevel::runlevel() == crate::runlevel::Runlevel::PreInit {
+        return true;
+    }
+    // SAFETY: See [current]
+    let Some(res) = (unsafe { CURRENT.get().as_ref() }) else { return true };
+    res.try_charge(Resource::Heap, bytes)
+}
+
+/// Uncharges a heap allocation from the running task. See [charge_heap].
+pub(crate) fn uncharge_heap(bytes: usize) {
+    if crate::runlevel::runlevel() == crate::runlevel::Runlevel::PreInit {
+        return;
+    }
+    // SAFETY: See [current]
+    if let Some(res) = unsafe { CURRENT.get().as_ref() } {
+        res.uncharge(Resource::Heap, bytes);
+    }
+}
+
+fn fmt_limit(s: &mut String, usage: usize, limit: usize) {
+    let _ = match limit {
+        0 => write!(s, " {:>21}", alloc::format!("{usage}/-")),
+        l => write!(s, " {:>21}", alloc::format!("{usage}/{l}")),
+    };
+}
+
+/// Formats the usage and limits of all tasks, one task per line.
+pub fn report() -> String {
+    let mut s = String::new();
+    let _ = writeln!(s, "{:>6} {:>21} {:>21} {:>21}  ORIGIN", "ID", "HEAP", "FILES", "OPS");
+    for t in super::mp_executor::task_stats() {
+        let _ = write!(s, "{:>6}", t.id);
+        for res in Resource::ALL {
+            fmt_limit(&mut s, t.resources.usage[res as usize], t.resources.limits[res as usize]);
+        }
+        let _ = writeln!(s, "  {}", t.origin);
+    }
+    s
+}
+
+/// Mounts the report file.
+pub fn init() {
+    crate::task::util::block_on!(crate::fs::get_vfs().mount_dev(Box::new(ReportFile::new(DevID::new(*MAJOR, 0), report)), FS_NAME))
+        .expect("Failed to mount rlimits to VFS");
+}
@@ -0,0 +1,122 @@
+//! `posix_spawn` style program creation.
+//!
+//! [spawn] starts a program as a child of the running task, passing it arguments and environment
+//! variables in [Args]. There is no ELF loader so programs can't be loaded from executable files,
+//! programs are built into the kernel and registered under a path using [register]. Spawning an
+//! unregistered path which exists in the filesystem returns [Errno::EOPNOTSUPP].
+//!
+//! The child inherits every descriptor of the parent's [FdTable] which is not marked close-on-spawn
+//! (see [FdTable::set_cloexec]), each file is duplicated using [crate::fs::file::File::clone_file].
+//! The [FileAction]s are then applied in order before the program starts. The child joins the
+//! parent's process group, see [super::job]. To collect the child's exit status the parent must
+//! call [super::job::track_children] before spawning it.
+
+use super::{TaskId, TaskResult};
+use crate::error::{Errno, ErrorContext, KernelError};
+use crate::fs::fd::{Fd, FdTable};
+use crate::fs::file::File;
+use crate::fs::IoError;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+
+/// Entry point of a program.
+pub type Entry = fn(Args) -> Pin<Box<dyn Future<Output = TaskResult> + Send>>;
+
+static PROGRAMS: spin::RwLock<BTreeMap<String, Entry>> = spin::RwLock::new(BTreeMap::new());
+
+/// Arguments passed to a program.
+#[derive(Clone, Debug, Default)]
+pub struct Args {
+    /// By convention the first argument is the path of the program.
+    pub argv: Vec<String>,
+    /// Environment variables in the form `NAME=value`.
+    pub envp: Vec<String>,
+}
+
+impl Args {
+    /// Returns the value of the environment variable `name`.
+    pub fn env(&self, name: &str) -> Option<&str> {
+        self.envp.iter().find_map(|e| e.strip_prefix(name)?.strip_prefix('='))
+    }
+}
+
+/// Changes to the child's descriptors made before it starts.
+#[derive(Clone, Debug)]
+pub enum FileAction {
+    /// Opens `path` as `fd`.
+    Open { fd: Fd, path: String },
+    /// Duplicates `from` as `to`.
+    Dup2 { from: Fd, to: Fd },
+    Close(Fd),
+}
+
+/// Registers `entry` as the program at `path`.
+pub fn register(path: &str, entry: Entry) -> Result<(), IoError> {
+    let mut l = PROGRAMS.write();
+    if l.contains_key(path) {
+        return Err(IoError::AlreadyExists);
+    }
+    l.insert(String::from(path), entry);
+    Ok(())
+}
+
+/// Returns the descriptors inherited by a child of the running task.
+fn inherited() -> BTreeMap<Fd, Box<dyn File>> {
+    let Some(proc) = super::job::current() else { return BTreeMap::new() };
+    let files = proc.files();
+    files.iter().filter(|(fd, _)| !files.is_cloexec(*fd)).map(|(fd, f)| (fd, f.clone_file())).collect()
+}
+
+/// Starts the program at `path` as a child of the running task and returns its task ID.
+#[track_caller]
+pub fn spawn<'a>(path: &'a str, args: Args, actions: &'a [FileAction]) -> impl Future<Output = Result<TaskId, KernelError>> + 'a {
+    // Recorded here because track_caller does not apply to async fns
+    let origin = core::panic::Location::caller();
+    async move {
+        let entry = PROGRAMS.read().get(path).copied();
+        let Some(entry) = entry else {
+            return match crate::fs::get_vfs().open(path).await {
+                Ok(_) => Err(KernelError::new("spawn", Errno::EOPNOTSUPP).with_context(alloc::format!("{path}: Executable files are not supported"))),
+                Err(e) => Err(KernelError::from(e).wrap("spawn", alloc::format!("{path}: Program not found"))),
+            };
+        };
+
+        let mut files = inherited();
+        for action in actions {
+            match action {
+                FileAction::Open { fd, path } => {
+                    let file = crate::fs::get_vfs().open(path).await.map_err(|e| KernelError::from(e).wrap("spawn", alloc::format!("Failed to open {path}")))?;
+                    files.insert(*fd, file);
+                }
+                FileAction::Dup2 { from, to } => {
+                    let file = files.get(from).ok_or(IoError::NotPresent).with_context("spawn", || alloc::format!("Descriptor {from} is not open"))?;
+                    let file = file.clone_file();
+                    files.insert(*to, file);
+                }
+                FileAction::Close(fd) => {
+                    files.remove(fd);
+                }
+            }
+        }
+
+        log::debug!("Spawning {path} from {origin}");
+        let program = String::from(path);
+        Ok(super::start_task(Box::pin(async move {
+            // Runs in the child, so the table is charged to the child's limits
+            if let Some(proc) = super::job::current() {
+                let mut table = proc.files();
+                for (fd, file) in files {
+                    if let Err((e, _)) = table.insert_at(fd, file) {
+                        log::error!("{program}: Failed to inherit descriptor {fd}: {e:?}");
+                        return TaskResult::Error;
+                    }
+                }
+            }
+            entry(args).await
+        })))
+    }
+}
@@ -0,0 +1,454 @@
+//! Hierarchical timer wheel for kernel timers.
+//!
+//! Timers are stored in one of [LEVELS] wheels each containing [SLOTS] slots, each level has a
+//! granularity 64 times coarser than the one below it. Inserting and cancelling a timer is O(1),
+//! timers in the upper levels are cascaded down into lower levels as their expiry approaches.
+//!
+//! The timer interrupt only checks whether the next expiry has passed and wakes the wheel task,
+//! expired timers are then processed in a batch by the task. Because of this timers have a
+//! resolution of [TICK_NS] or the period of the timer interrupt, whichever is larger.
+//!
+//! [Timeout] is a future which completes at a deadline, [Periodic] can be used for timers which
+//! fire at a fixed interval.
+
+use super::util::Duration;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use futures_util::task::AtomicWaker;
+
+/// Duration of a single tick of the lowest level in nanoseconds.
+pub const TICK_NS: u64 = 1_000_000;
+const LEVEL_BITS: u32 = 6;
+const SLOTS: usize = 1 << LEVEL_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+/// With a tick of 1ms this covers timers up to ~34 years into the future, timers further than this
+/// are placed in the last slot of the highest level.
+const LEVELS: usize = 5;
+/// Largest number of ticks a timer can be placed in the future.
+const MAX_DELTA: u64 = (1 << (LEVEL_BITS * LEVELS as u32)) - 1;
+
+static WHEEL: crate::lockdep::Mutex<TimerWheel> = crate::lockdep::Mutex::new(TimerWheel::new());
+/// Tick at which the wheel task must next run, this may be earlier than the next expiry.
+static NEXT_TICK: atomic::Atomic<u64> = atomic::Atomic::new(u64::MAX);
+static WHEEL_WAKER: AtomicWaker = AtomicWaker::new();
+
+fn current_tick() -> u64 {
+    crate::time::get_sys_time() / TICK_NS
+}
+
+/// Rounds `nanos` up to the next tick.
+fn to_tick(nanos: u64) -> u64 {
+    nanos.div_ceil(TICK_NS)
+}
+
+/// Handle to a timer inserted into the wheel, used to cancel it or update its waker.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TimerHandle {
+    index: u32,
+    generation: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Location {
+    level: u8,
+    slot: u8,
+}
+
+struct Entry {
+    /// Incremented each time the entry is freed, stale handles are detected by comparing this.
+    generation: u32,
+    expires: u64,
+    waker: Option<Waker>,
+    /// `None` when the entry is not in use.
+    location: Option<Location>,
+    prev: Option<u32>,
+    next: Option<u32>,
+}
+
+struct TimerWheel {
+    /// Next tick to be processed.
+    base: u64,
+    slots: [[Option<u32>; SLOTS]; LEVELS],
+    /// Bitmap of non-empty slots for each level.
+    occupied: [u64; LEVELS],
+    entries: Vec<Entry>,
+    free: Vec<u32>,
+    pending: usize,
+}
+
+impl TimerWheel {
+    const fn new() -> Self {
+        Self {
+            base: 0,
+            slots: [[None; SLOTS]; LEVELS],
+            occupied: [0; LEVELS],
+            entries: Vec::new(),
+            free: Vec::new(),
+            pending: 0,
+        }
+    }
+
+    fn alloc(&mut self, expires: u64, waker: Waker) -> TimerHandle {
+        let index = match self.free.pop() {
+            Some(i) => {
+                let e = &mut self.entries[i as usize];
+                e.expires = expires;
+                e.waker = Some(waker);
+                i
+            }
+            None => {
+                self.entries.push(Entry {
+                    generation: 0,
+                    expires,
+                    waker: Some(waker),
+                    location: None,
+                    prev: None,
+                    next: None,
+                });
+                (self.entries.len() - 1) as u32
+            }
+        };
+        self.pending += 1;
+        TimerHandle { index, generation: self.entries[index as usize].generation }
+    }
+
+    fn free(&mut self, index: u32) -> Option<Waker> {
+        let e = &mut self.entries[index as usize];
+        e.generation = e.generation.wrapping_add(1);
+        self.free.push(index);
+        self.pending -= 1;
+        e.waker.take()
+    }
+
+    /// Returns the entry referred to by `handle` if it is still armed.
+    fn lookup(&self, handle: TimerHandle) -> Option<&Entry> {
+        self.entries
+            .get(handle.index as usize)
+            .filter(|e| e.generation == handle.generation && e.location.is_some())
+    }
+
+    /// Returns the location where a timer expiring at `expires` must be placed.
+    fn locate(&self, expires: u64) -> Location {
+        let expires = expires.max(self.base);
+        let delta = (expires - self.base).min(MAX_DELTA);
+        let expires = self.base + delta;
+
+        let mut level = 0;
+        while delta >= 1 << (LEVEL_BITS * (level as u32 + 1)) {
+            level += 1;
+        }
+        Location {
+            level: level as u8,
+            slot: ((expires >> (LEVEL_BITS * level as u32)) & SLOT_MASK) as u8,
+        }
+    }
+
+    fn link(&mut self, index: u32) {
+        let loc = self.locate(self.entries[index as usize].expires);
+        let head = &mut self.slots[loc.level as usize][loc.slot as usize];
+        let old = head.replace(index);
+        if let Some(old) = old {
+            self.entries[old as usize].prev = Some(index);
+        }
+        let e = &mut self.entries[index as usize];
+        e.prev = None;
+        e.next = old;
+        e.location = Some(loc);
+        self.occupied[loc.level as usize] |= 1 << loc.slot;
+    }
+
+    fn unlink(&mut self, index: u32) {
+        let e = &mut self.entries[index as usize];
+        let loc = e.location.take().expect("Attempted to unlink timer which is not linked");
+        let (prev, next) = (e.prev.take(), e.next.take());
+
+        match prev {
+            Some(p) => self.entries[p as usize].next = next,
+            None => self.slots[loc.level as usize][loc.slot as usize] = next,
+        }
+        if let Some(n) = next {
+            self.entries[n as usize].prev = prev;
+        }
+        if self.slots[loc.level as usize][loc.slot as usize].is_none() {
+            self.occupied[loc.level as usize] &= !(1 << loc.slot);
+        }
+    }
+
+    /// Removes every entry from the given slot and returns their indices.
+    fn take_slot(&mut self, level: usize, slot: usize) -> Vec<u32> {
+        let mut taken = Vec::new();
+        while let Some(i) = self.slots[level][slot] {
+            self.unlink(i);
+            taken.push(i);
+        }
+        taken
+    }
+
+    /// Moves timers from the upper levels into lower levels when the level below wraps.
+    fn cascade(&mut self) {
+        for level in 1..LEVELS {
+            let slot = ((self.base >> (LEVEL_BITS * level as u32)) & SLOT_MASK) as usize;
+            for i in self.take_slot(level, slot) {
+                self.link(i);
+            }
+            // Only continue to the next level when this level has also wrapped
+            if slot != 0 {
+                break;
+            }
+        }
+    }
+
+    /// Processes all ticks up to and including `now`, wakers of expired timers are pushed into `expired`.
+    fn advance(&mut self, now: u64, expired: &mut Vec<Waker>) {
+        while self.base <= now {
+            let slot = (self.base & SLOT_MASK) as usize;
+            if slot == 0 {
+                self.cascade();
+            } else if self.occupied[0] == 0 {
+                // Nothing can expire until the next cascade, skip to it.
+                self.base = (self.base | SLOT_MASK).wrapping_add(1).min(now + 1);
+                continue;
+            }
+
+            for i in self.take_slot(0, slot) {
+                if let Some(w) = self.free(i) {
+                    expired.push(w);
+                }
+            }
+            self.base += 1;
+        }
+    }
+
+    /// Returns the tick at which the wheel must next be processed.
+    fn next_tick(&self) -> u64 {
+        if self.pending == 0 {
+            return u64::MAX;
+        }
+        let mut next = u64::MAX;
+        if self.occupied[0] != 0 {
+            let offset = self.occupied[0].rotate_right((self.base & SLOT_MASK) as u32).trailing_zeros();
+            next = self.base + offset as u64;
+        }
+        // Timers in upper levels may expire immediately after they are cascaded.
+        if self.occupied[1..].iter().any(|o| *o != 0) {
+            next = next.min((self.base | SLOT_MASK) + 1);
+        }
+        next
+    }
+
+    fn update_next(&self) {
+        NEXT_TICK.store(self.next_tick(), atomic::Ordering::Release);
+    }
+}
+
+/// Arms a timer which will wake `waker` at `deadline` nanoseconds since boot.
+///
+/// Returns `None` if the deadline has already passed, in which case `waker` is not woken.
+pub fn insert(deadline: u64, waker: Waker) -> Option<TimerHandle> {
+    let tick = to_tick(deadline);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut l = WHEEL.lock();
+        // When the wheel is empty no ticks need to be processed, so it can be moved to the current time.
+        if l.pending == 0 {
+            l.base = current_tick();
+        }
+        if tick < l.base || deadline <= crate::time::get_sys_time() {
+            return None;
+        }
+        let h = l.alloc(tick, waker);
+        l.link(h.index);
+        l.update_next();
+        Some(h)
+    })
+}
+
+/// Disarms the timer referred to by `handle`.
+///
+/// Returns `false` if the timer has already expired or been cancelled.
+pub fn cancel(handle: TimerHandle) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut l = WHEEL.lock();
+        if l.lookup(handle).is_none() {
+            return false;
+        }
+        l.unlink(handle.index);
+        l.free(handle.index);
+        l.update_next();
+        true
+    })
+}
+
+/// Replaces the waker of an armed timer. Returns `false` if the timer is no longer armed.
+pub fn set_waker(handle: TimerHandle, waker: &Waker) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut l = WHEEL.lock();
+        if l.lookup(handle).is_none() {
+            return false;
+        }
+        let e = &mut l.entries[handle.index as usize];
+        if !e.waker.as_ref().is_some_and(|w| w.will_wake(waker)) {
+            e.waker = Some(waker.clone());
+        }
+        true
+    })
+}
+
+/// Returns the time in nanoseconds since boot at which the wheel must next be processed, `None`
+/// when no timers are armed.
+pub(crate) fn next_expiry() -> Option<u64> {
+    match NEXT_TICK.load(atomic::Ordering::Acquire) {
+        u64::MAX => None,
+        t => Some(t.saturating_mul(TICK_NS)),
+    }
+}
+
+/// Called from the timer interrupt, wakes the wheel task when timers need to be processed.
+pub(crate) fn check_expired() {
+    if current_tick() >= NEXT_TICK.load(atomic::Ordering::Acquire) {
+        WHEEL_WAKER.wake();
+    }
+}
+
+/// Starts the task which processes expired timers.
+pub fn init() {
+    super::run_task(Box::pin(wheel_task()));
+}
+
+async fn wheel_task() -> super::TaskResult {
+    let mut expired = Vec::new();
+    loop {
+        WheelWait.await;
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut l = WHEEL.lock();
+            l.advance(current_tick(), &mut expired);
+            l.update_next();
+        });
+        // Wakers may take locks, so they are woken after the wheel is released.
+        for w in expired.drain(..) {
+            w.wake();
+        }
+    }
+}
+
+/// Completes when the wheel needs to be processed.
+struct WheelWait;
+
+impl core::future::Future for WheelWait {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let ready = || current_tick() >= NEXT_TICK.load(atomic::Ordering::Acquire);
+        if ready() {
+            return Poll::Ready(());
+        }
+        WHEEL_WAKER.register(cx.waker());
+        if ready() {
+            Poll::Ready(())
+        } else {
+            super::wait::note(super::wait::WaitPoint::timer("timer wheel"));
+            Poll::Pending
+        }
+    }
+}
+
+/// A future which completes at a deadline using the timer wheel.
+///
+/// The timer is cancelled when this is dropped.
+pub struct Timeout {
+    deadline: u64,
+    handle: Option<TimerHandle>,
+}
+
+impl Timeout {
+    /// Creates a timeout which completes after `duration`.
+    pub fn new(duration: Duration) -> Self {
+        Self::at(crate::time::get_sys_time() + duration.get_nanos())
+    }
+
+    /// Creates a timeout which completes at `deadline` nanoseconds since boot.
+    pub fn at(deadline: u64) -> Self {
+        Self { deadline, handle: None }
+    }
+
+    /// Returns the deadline in nanoseconds since boot.
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+}
+
+impl core::future::Future for Timeout {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if crate::time::get_sys_time() >= self.deadline {
+            if let Some(h) = self.handle.take() {
+                cancel(h);
+            }
+            return Poll::Ready(());
+        }
+        let r = match self.handle {
+            Some(h) if set_waker(h, cx.waker()) => Poll::Pending,
+            // The timer has fired but the clock was read before it expired, or the timer was never armed.
+            _ => match insert(self.deadline, cx.waker().clone()) {
+                Some(h) => {
+                    self.handle = Some(h);
+                    Poll::Pending
+                }
+                None => {
+                    self.handle = None;
+                    Poll::Ready(())
+                }
+            },
+        };
+        if r.is_pending() {
+            super::wait::note(super::wait::WaitPoint::timer("timeout"));
+        }
+        r
+    }
+}
+
+impl Drop for Timeout {
+    fn drop(&mut self) {
+        if let Some(h) = self.handle.take() {
+            cancel(h);
+        }
+    }
+}
+
+/// A timer which fires at a fixed period.
+///
+/// Each deadline is calculated from the previous deadline rather than from when the timer was
+/// awaited, so latency when handling a tick does not accumulate into drift.
+pub struct Periodic {
+    period: u64,
+    next: u64,
+}
+
+impl Periodic {
+    /// Creates a periodic timer which will first fire after `period`.
+    ///
+    /// # Panics
+    ///
+    /// `period` must not be zero.
+    pub fn new(period: Duration) -> Self {
+        assert_ne!(period.get_nanos(), 0, "Periodic timer period must not be zero");
+        Self {
+            period: period.get_nanos(),
+            next: crate::time::get_sys_time() + period.get_nanos(),
+        }
+    }
+
+    /// Waits until the next period has elapsed.
+    ///
+    /// Returns the number of periods which have elapsed since the previous call, this is greater
+    /// than 1 if ticks were missed. Missed ticks are not delivered individually.
+    pub async fn tick(&mut self) -> u64 {
+        Timeout::at(self.next).await;
+        let now = crate::time::get_sys_time();
+        let elapsed = (now - self.next) / self.period + 1;
+        self.next += elapsed * self.period;
+        elapsed
+    }
+}
@@ -0,0 +1,249 @@
+//! `top`, an interactive task monitor.
+//!
+//! Samples [mp_executor::task_stats] and the kernel heap statistics every [INTERVAL_MS] and renders
+//! a table of tasks to a virtual console, only lines which changed since the previous frame are
+//! redrawn. Tasks do not have names, they are identified by the location they were spawned from.
+//! The `S` column shows whether the task is runnable (`R`), sleeping (`S`), stopped (`T`) or
+//! killed and waiting to be removed (`X`). The `WCHAN` column shows what the task waited for when
+//! it was last polled, see [super::wait], the innermost wait is kept when it does not fit.
+//!
+//! The following keys are accepted
+//! - `c`: Sort by CPU usage during the last sample, this is the default.
+//! - `t`: Sort by total run time.
+//! - `p`: Sort by the number of times the task was polled.
+//! - `i`: Sort by task ID.
+//! - `q`: Exit.
+//!
+//! There is no shell, so [spawn] is called at boot to run `top` on the shell console.
+
+use super::mp_executor::{self, TaskStats};
+use super::TaskId;
+use crate::config::Tunable;
+use crate::fs::device::{Fifo, OpenMode};
+use crate::fs::file::{cast_file, Read as _, Write as _};
+use crate::fs::IoError;
+use crate::mem::dma::DmaGuard;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write as _;
+use futures_util::future::Either;
+
+/// Interval between samples.
+pub static INTERVAL_MS: Tunable<u64> = Tunable::new("top.interval_ms", "Milliseconds between top updates", 1000);
+
+/// Number of lines before the task table.
+const HEADER_LINES: usize = 6;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SortKey {
+    Cpu,
+    Time,
+    Polls,
+    Id,
+}
+
+impl SortKey {
+    fn from_key(key: u8) -> Option<Self> {
+        match key {
+            b'c' => Some(SortKey::Cpu),
+            b't' => Some(SortKey::Time),
+            b'p' => Some(SortKey::Polls),
+            b'i' => Some(SortKey::Id),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            SortKey::Cpu => "cpu",
+            SortKey::Time => "time",
+            SortKey::Polls => "polls",
+            SortKey::Id => "id",
+        }
+    }
+}
+
+struct Row {
+    stats: TaskStats,
+    /// Nanoseconds the task ran during the last sample
+    delta: u64,
+}
+
+fn sort(rows: &mut [Row], key: SortKey) {
+    match key {
+        SortKey::Cpu => rows.sort_by(|a, b| b.delta.cmp(&a.delta).then(a.stats.id.cmp(&b.stats.id))),
+        SortKey::Time => rows.sort_by(|a, b| b.stats.run_time.cmp(&a.stats.run_time).then(a.stats.id.cmp(&b.stats.id))),
+        SortKey::Polls => rows.sort_by(|a, b| b.stats.polls.cmp(&a.stats.polls).then(a.stats.id.cmp(&b.stats.id))),
+        SortKey::Id => rows.sort_by_key(|r| r.stats.id),
+    }
+}
+
+/// Formats a frame, each line is truncated to `width` and at most `height` lines are returned.
+/// Returns the state column of a task, in the style of `ps`.
+fn state_char(stats: &TaskStats) -> char {
+    match (stats.state, stats.sched) {
+        (super::job::State::Stopped, _) => 'T',
+        (super::job::State::Killed(_), _) => 'X',
+        (_, mp_executor::Sched::Sleeping) => 'S',
+        _ => 'R',
+    }
+}
+
+/// Width of the `WCHAN` column.
+const WCHAN_WIDTH: usize = 24;
+
+/// Formats the wait chain of a task, dropping the outermost characters when it is too long.
+fn wchan(stats: &TaskStats) -> String {
+    let s = alloc::format!("{}", stats.wait);
+    let len = s.chars().count();
+    if len <= WCHAN_WIDTH {
+        return s;
+    }
+    // Keeps the last `WCHAN_WIDTH - 1` characters after the marker
+    let (start, _) = s.char_indices().nth(len - (WCHAN_WIDTH - 1)).unwrap();
+    alloc::format!("<{}", &s[start..])
+}
+
+fn render(rows: &[Row], elapsed: u64, key: SortKey, width: usize, height: usize) -> Vec<String> {
+    let heap = crate::mem::allocator::virt_alloc_stats();
+    let mut lines = Vec::new();
+    lines.push(alloc::format!(
+        "top - up {}s, {} tasks, {} CPUs, interval {}ms",
+        crate::time::get_sys_time() / 1_000_000_000,
+        rows.len(),
+        crate::mp::num_cpus(),
+        elapsed / 1_000_000
+    ));
+    let mut queues = String::from("run queues:");
+    for (cpu, len) in mp_executor::run_queue_lens() {
+        let _ = write!(queues, " CPU{cpu} {len}");
+    }
+    let _ = write!(queues, ", {} AS switches", mp_executor::as_switches());
+    lines.push(queues);
+    lines.push(alloc::format!(
+        "heap: {} KiB allocated in {} allocations, {} KiB mapped of {} KiB",
+        heap.allocated / 1024,
+        heap.allocations,
+        crate::mem::allocator::heap_mapped() / 1024,
+        crate::mem::allocator::heap_ceiling() / 1024
+    ));
+    lines.push(alloc::format!("sort: {}, keys (c)pu (t)ime (p)olls (i)d (q)uit", key.name()));
+    lines.push(String::new());
+    lines.push(alloc::format!("{:>6} {:>4} S {:>6} {:>10} {:>10} {:<WCHAN_WIDTH$}  ORIGIN", "ID", "CPU", "%CPU", "POLLS", "TIME(ms)", "WCHAN"));
+    debug_assert_eq!(lines.len(), HEADER_LINES);
+
+    for row in rows.iter().take(height.saturating_sub(HEADER_LINES)) {
+        let permille = row.delta * 1000 / elapsed.max(1);
+        lines.push(alloc::format!(
+            "{:>6} {:>4} {} {:>4}.{} {:>10} {:>10} {:<WCHAN_WIDTH$}  {}",
+            row.stats.id,
+            row.stats.cpu,
+            state_char(&row.stats),
+            permille / 10,
+            permille % 10,
+            row.stats.polls,
+            row.stats.run_time / 1_000_000,
+            wchan(&row.stats),
+            row.stats.origin
+        ));
+    }
+
+    for line in &mut lines {
+        if let Some((i, _)) = line.char_indices().nth(width) {
+            line.truncate(i);
+        }
+    }
+    lines.truncate(height);
+    lines
+}
+
+async fn write(tty: &dyn Fifo<u8>, s: &str) -> Result<(), IoError> {
+    tty.write(0, Box::new(DmaGuard::from(Vec::from(s.as_bytes())))).await.map(|_| ()).map_err(|(e, ..)| e)
+}
+
+/// Waits up to `timeout` milliseconds for a key to be pressed.
+async fn read_key(tty: &dyn Fifo<u8>, timeout: u64) -> Result<Option<u8>, IoError> {
+    let read = tty.read(0, Box::new(DmaGuard::from(alloc::vec![0u8; 1])));
+    let sleep = Box::pin(super::util::sleep(timeout));
+    match futures_util::future::select(read, sleep).await {
+        Either::Left((Ok((mut buff, count)), _)) => {
+            // SAFETY: The read has completed
+            let buff = unsafe { &*crate::mem::dma::DmaTarget::as_mut(&mut *buff) };
+            Ok(buff.first().copied().filter(|_| count > 0))
+        }
+        Either::Left((Err((e, ..)), _)) => Err(e),
+        Either::Right(_) => Ok(None),
+    }
+}
+
+/// Runs `top` on virtual console `console` until `q` is pressed.
+pub async fn top(console: usize) -> Result<(), IoError> {
+    let path = alloc::format!("/dev/tty{console}");
+    let file = crate::fs::get_vfs().open(&path).await.map_err(|_| IoError::NotPresent)?;
+    let mut tty = cast_file!(Fifo<u8>: file).map_err(|_| IoError::InvalidData)?;
+    tty.open(OpenMode::ReadWrite)?;
+    let (width, height) = crate::graphics::basic_output::console_size(console).unwrap_or((80, 25));
+
+    let mut key = SortKey::Cpu;
+    let mut prev_frame: Vec<String> = Vec::new();
+    let mut prev_time: BTreeMap<TaskId, u64> = BTreeMap::new();
+    let mut last = crate::time::get_sys_time();
+    write(&*tty, "\x1b[2J").await?;
+
+    loop {
+        let now = crate::time::get_sys_time();
+        let elapsed = now.saturating_sub(last);
+        last = now;
+        let mut rows: Vec<Row> = mp_executor::task_stats()
+            .into_iter()
+            .map(|stats| Row {
+                delta: stats.run_time.saturating_sub(prev_time.get(&stats.id).copied().unwrap_or(stats.run_time)),
+                stats,
+            })
+            .collect();
+        prev_time = rows.iter().map(|r| (r.stats.id, r.stats.run_time)).collect();
+        let deadline = now + INTERVAL_MS.get() * 1_000_000;
+
+        // Redraws without sampling when the sort key is changed
+        loop {
+            sort(&mut rows, key);
+            let frame = render(&rows, elapsed, key, width, height);
+            let mut out = String::new();
+            for (y, line) in frame.iter().enumerate() {
+                if prev_frame.get(y) != Some(line) {
+                    let _ = write!(out, "\x1b[{};1H{line}\x1b[K", y + 1);
+                }
+            }
+            if frame.len() < prev_frame.len() {
+                let _ = write!(out, "\x1b[{};1H\x1b[J", frame.len() + 1);
+            }
+            write(&*tty, &out).await?;
+            prev_frame = frame;
+
+            let remaining = deadline.saturating_sub(crate::time::get_sys_time()) / 1_000_000;
+            match read_key(&*tty, remaining).await? {
+                Some(b'q') => {
+                    write(&*tty, "\x1b[2J\x1b[H").await?;
+                    return tty.close();
+                }
+                Some(k) => key = SortKey::from_key(k).unwrap_or(key),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Starts `top` on the shell console.
+pub fn spawn() {
+    super::run_task(Box::pin(async {
+        match top(crate::graphics::basic_output::VC_SHELL).await {
+            Ok(()) => super::TaskResult::ExitedNormally,
+            Err(e) => {
+                log::error!("top: {e:?}");
+                super::TaskResult::Error
+            }
+        }
+    }))
+}
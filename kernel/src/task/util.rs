@@ -12,6 +12,11 @@ pub(crate) fn check_slp() {
     sleep::SLEEP_QUEUE.wakeup()
 }
 
+/// Returns the time at which the next [sleep] completes, `Some(0)` if it cannot be determined.
+pub(crate) fn next_slp() -> Option<u64> {
+    sleep::SLEEP_QUEUE.next_alarm()
+}
+
 #[doc(hidden)]
 pub mod suspend {
     use core::pin::Pin;
@@ -58,15 +63,57 @@ impl alloc::task::Wake for DummyWaker {
     fn wake(self: alloc::sync::Arc<Self>) {}
 }
 
+/// Waker used by [block_on], records wakeups so the CPU can halt until the future can make progress.
+#[derive(Default)]
+pub struct ParkWaker {
+    woken: core::sync::atomic::AtomicBool,
+}
+
+impl alloc::task::Wake for ParkWaker {
+    fn wake(self: alloc::sync::Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &alloc::sync::Arc<Self>) {
+        self.woken.store(true, core::sync::atomic::Ordering::Release);
+    }
+}
+
+impl ParkWaker {
+    /// Waits until the waker is woken.
+    ///
+    /// The CPU is halted until the next interrupt, wakeups from other CPUs are noticed when this CPU
+    /// receives its next timer interrupt. When interrupts are disabled this spins instead.
+    pub fn park(&self) {
+        use x86_64::instructions::interrupts;
+        if !interrupts::are_enabled() {
+            core::hint::spin_loop();
+            return;
+        }
+        interrupts::disable();
+        if self.woken.swap(false, core::sync::atomic::Ordering::Acquire) {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
+/// Polls `task` to completion on the calling CPU.
+///
+/// Outside of interrupt handlers the CPU halts between polls, so the future must wake its waker when
+/// it can make progress.
 #[macro_export]
 macro_rules! block_on {
     ($task:expr) => {
         {
             let mut future = $task;
+            let parker = ::alloc::sync::Arc::new($crate::task::util::ParkWaker::default());
+            let waker = ::core::task::Waker::from(parker.clone());
             loop {
-                match core::future::Future::poll( ::core::pin::Pin::new(&mut future), &mut ::core::task::Context::from_waker( & ::core::task::Waker::from( ::alloc::sync::Arc::new($crate::task::util::DummyWaker )))) {
+                match core::future::Future::poll( ::core::pin::Pin::new(&mut future), &mut ::core::task::Context::from_waker(&waker)) {
                     ::core::task::Poll::Pending => {
-                        core::hint::spin_loop(); // busy-loop, but whatever
+                        parker.park();
                         continue;
                     },
                     ::core::task::Poll::Ready(t) => break t,
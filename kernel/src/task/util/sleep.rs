@@ -23,6 +23,15 @@ impl crate::util::Mutex<SleepQueue> {
         })
     }
 
+    /// Returns the alarm of the next timer. When the queue is locked this returns `Some(0)` because
+    /// the next alarm is unknown.
+    pub(crate) fn next_alarm(&self) -> Option<u64> {
+        match self.try_lock() {
+            Some(l) => l.list.front().map(|t| t.inner.alarm),
+            None => Some(0),
+        }
+    }
+
     /// Wakes all timers which can be woken
     pub(crate) fn wakeup(&self) {
         let ct = crate::time::get_sys_time();
@@ -84,6 +93,7 @@ impl core::future::Future for Timer {
                 SLEEP_QUEUE.register(&self);
             }
 
+            crate::task::wait::note(crate::task::wait::WaitPoint::timer("sleep"));
             core::task::Poll::Pending
         } else {
             core::task::Poll::Ready(())
@@ -0,0 +1,203 @@
+//! Records what each task is waiting for.
+//!
+//! While a task is polled each [Annotated] future pushes a [WaitPoint] onto a per-CPU shadow stack
+//! for the duration of its poll. When a primitive returns [Poll::Pending] it calls [note], which
+//! records the points on the stack and the primitive's own point as the task's [WaitChain]. The
+//! first chain recorded during a poll is kept, this is the innermost future which returned
+//! `Pending`. The chain from the last poll of each task is shown by [super::top].
+//!
+//! Timers, work queues and the serial and console drivers note their waits. Other futures, e.g.
+//! locks from external crates, are annotated by their callers with [Annotate::waiting_on].
+//!
+//! [Poll::Pending]: core::task::Poll::Pending
+
+use core::cell::Cell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// Maximum depth of a [WaitChain], deeper points are not recorded.
+pub const MAX_DEPTH: usize = 4;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WaitKind {
+    Device,
+    Lock,
+    Channel,
+    Timer,
+}
+
+/// A resource a task may wait for.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct WaitPoint {
+    pub kind: WaitKind,
+    pub name: &'static str,
+}
+
+impl WaitPoint {
+    pub const fn device(name: &'static str) -> Self {
+        Self { kind: WaitKind::Device, name }
+    }
+
+    pub const fn lock(name: &'static str) -> Self {
+        Self { kind: WaitKind::Lock, name }
+    }
+
+    pub const fn channel(name: &'static str) -> Self {
+        Self { kind: WaitKind::Channel, name }
+    }
+
+    pub const fn timer(name: &'static str) -> Self {
+        Self { kind: WaitKind::Timer, name }
+    }
+}
+
+impl core::fmt::Display for WaitPoint {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let kind = match self.kind {
+            WaitKind::Device => "dev",
+            WaitKind::Lock => "lock",
+            WaitKind::Channel => "chan",
+            WaitKind::Timer => "timer",
+        };
+        write!(f, "{kind}:{}", self.name)
+    }
+}
+
+/// Wait points from the outermost to the innermost.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct WaitChain {
+    points: [Option<WaitPoint>; MAX_DEPTH],
+    len: usize,
+}
+
+impl WaitChain {
+    pub fn points(&self) -> impl Iterator<Item = WaitPoint> + '_ {
+        self.points[..self.len].iter().flatten().copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the innermost point.
+    pub fn last(&self) -> Option<WaitPoint> {
+        self.len.checked_sub(1).and_then(|i| self.points[i])
+    }
+
+    fn push(&mut self, point: WaitPoint) {
+        if let Some(p) = self.points.get_mut(self.len) {
+            *p = Some(point);
+            self.len += 1;
+        }
+    }
+}
+
+impl core::fmt::Display for WaitChain {
+    /// Formats the chain as `outer>inner`, or `-` when empty.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.is_empty() {
+            return f.write_str("-");
+        }
+        for (i, p) in self.points().enumerate() {
+            if i != 0 {
+                f.write_str(">")?;
+            }
+            write!(f, "{p}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Shadow stack of the task being polled on this CPU.
+#[thread_local]
+static STACK: Cell<WaitChain> = Cell::new(WaitChain { points: [None; MAX_DEPTH], len: 0 });
+/// Annotations pushed beyond [MAX_DEPTH], these are popped without modifying [STACK].
+#[thread_local]
+static OVERFLOW: Cell<usize> = Cell::new(0);
+#[thread_local]
+static CAPTURED: Cell<Option<WaitChain>> = Cell::new(None);
+
+/// Records `point` as the resource the running task waits for. Called by primitives which return
+/// [Poll::Pending].
+pub fn note(point: WaitPoint) {
+    if crate::runlevel::runlevel() == crate::runlevel::Runlevel::PreInit || CAPTURED.get().is_some() {
+        return;
+    }
+    let mut chain = STACK.get();
+    chain.push(point);
+    CAPTURED.set(Some(chain));
+}
+
+/// Called by the executor before a task is polled.
+pub(super) fn begin_poll() {
+    STACK.set(WaitChain::default());
+    OVERFLOW.set(0);
+    CAPTURED.set(None);
+}
+
+/// Called by the executor after a task was polled, returns the chain recorded during the poll.
+pub(super) fn end_poll() -> WaitChain {
+    CAPTURED.take().unwrap_or_default()
+}
+
+fn push(point: WaitPoint) {
+    let mut s = STACK.get();
+    if s.len < MAX_DEPTH {
+        s.push(point);
+        STACK.set(s);
+    } else {
+        OVERFLOW.set(OVERFLOW.get() + 1);
+    }
+}
+
+fn pop() {
+    if OVERFLOW.get() > 0 {
+        OVERFLOW.set(OVERFLOW.get() - 1);
+        return;
+    }
+    let mut s = STACK.get();
+    if let Some(len) = s.len.checked_sub(1) {
+        s.points[len] = None;
+        s.len = len;
+        STACK.set(s);
+    }
+}
+
+/// A future which records `point` while it is polled, see [Annotate::waiting_on].
+pub struct Annotated<F> {
+    inner: F,
+    point: WaitPoint,
+}
+
+impl<F: Future> Future for Annotated<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if crate::runlevel::runlevel() == crate::runlevel::Runlevel::PreInit {
+            // SAFETY: `inner` is never moved
+            return unsafe { self.map_unchecked_mut(|s| &mut s.inner) }.poll(cx);
+        }
+        let point = self.point;
+        push(point);
+        // SAFETY: `inner` is never moved
+        let r = unsafe { self.map_unchecked_mut(|s| &mut s.inner) }.poll(cx);
+        if r.is_pending() {
+            // Futures which do not note their waits are recorded as waiting for this point
+            if CAPTURED.get().is_none() {
+                CAPTURED.set(Some(STACK.get()));
+            }
+        }
+        pop();
+        r
+    }
+}
+
+pub trait Annotate: Future + Sized {
+    /// Records that the task waits for `point` while this future is pending.
+    fn waiting_on(self, point: WaitPoint) -> Annotated<Self> {
+        Annotated { inner: self, point }
+    }
+}
+
+impl<F: Future> Annotate for F {}
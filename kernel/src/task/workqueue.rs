@@ -0,0 +1,255 @@
+//! Deferred work queues.
+//!
+//! A [WorkQueue] runs queued work items one at a time, in the order they were queued, on a worker
+//! task. This allows drivers to defer follow-up work without spawning a task for each item.
+//!
+//! Most users should use [queue_work] and [queue_delayed_work] which use a shared system queue.
+//! Subsystems which may run long work items or require ordering between their own items should
+//! create a dedicated queue using [WorkQueue::new] so they do not delay other users.
+
+use super::timer_wheel::Timeout;
+use super::util::Duration;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+
+type Work = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+static SYSTEM_QUEUE: conquer_once::spin::OnceCell<WorkQueue> = conquer_once::spin::OnceCell::uninit();
+
+/// Queues `work` onto the system work queue.
+pub fn queue_work(work: impl Future<Output = ()> + Send + 'static) {
+    system_queue().queue_work(work)
+}
+
+/// Queues `work` onto the system work queue after `delay` has elapsed.
+pub fn queue_delayed_work(delay: Duration, work: impl Future<Output = ()> + Send + 'static) -> DelayedWork {
+    system_queue().queue_delayed_work(delay, work)
+}
+
+/// Returns the shared system work queue.
+pub fn system_queue() -> &'static WorkQueue {
+    SYSTEM_QUEUE.get_or_init(|| WorkQueue::new("system"))
+}
+
+struct Inner {
+    name: &'static str,
+    queue: spin::Mutex<VecDeque<Work>>,
+    waker: AtomicWaker,
+    /// Set when the [WorkQueue] is dropped, the worker will exit once the queue is empty.
+    closed: atomic::Atomic<bool>,
+}
+
+impl Inner {
+    fn push(&self, work: Work) {
+        x86_64::instructions::interrupts::without_interrupts(|| self.queue.lock().push_back(work));
+        self.waker.wake();
+    }
+
+    fn pop(&self) -> Option<Work> {
+        x86_64::instructions::interrupts::without_interrupts(|| self.queue.lock().pop_front())
+    }
+}
+
+/// A queue of work items executed sequentially by a dedicated worker task.
+///
+/// Dropping the queue does not discard queued work, the worker exits after all queued work has
+/// completed. Delayed work which has not yet been queued when the queue is dropped will be discarded.
+pub struct WorkQueue {
+    inner: Arc<Inner>,
+}
+
+impl WorkQueue {
+    /// Creates a new queue and spawns its worker task. `name` is used for logging.
+    pub fn new(name: &'static str) -> Self {
+        let inner = Arc::new(Inner {
+            name,
+            queue: spin::Mutex::new(VecDeque::new()),
+            waker: AtomicWaker::new(),
+            closed: atomic::Atomic::new(false),
+        });
+        super::run_task(Box::pin(worker(inner.clone())));
+        Self { inner }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.inner.name
+    }
+
+    /// Queues `work` to be run after all previously queued work has completed.
+    pub fn queue_work(&self, work: impl Future<Output = ()> + Send + 'static) {
+        self.inner.push(Box::pin(work))
+    }
+
+    /// Queues `work` after `delay` has elapsed.
+    ///
+    /// The returned [DelayedWork] can be used to cancel the work before it is queued, cancelling
+    /// the work also ends the task waiting for `delay`.
+    pub fn queue_delayed_work(&self, delay: Duration, work: impl Future<Output = ()> + Send + 'static) -> DelayedWork {
+        let shared = Arc::new(DelayedShared { state: atomic::Atomic::new(DelayedState::Pending), waker: AtomicWaker::new() });
+        let inner = Arc::downgrade(&self.inner);
+        let s = shared.clone();
+        let timeout = Timeout::new(delay);
+
+        super::run_task(Box::pin(async move {
+            let cancelled = CancelFut { shared: &s };
+            futures_util::future::select(core::pin::pin!(timeout), cancelled).await;
+            if let Some(inner) = inner.upgrade() {
+                if !inner.closed.load(atomic::Ordering::Relaxed)
+                    && s.state.compare_exchange(DelayedState::Pending, DelayedState::Queued, atomic::Ordering::AcqRel, atomic::Ordering::Relaxed).is_ok()
+                {
+                    inner.push(Box::pin(work));
+                }
+            }
+            super::TaskResult::ExitedNormally
+        }));
+        DelayedWork { shared }
+    }
+
+    /// Waits until all work queued before this was called has completed.
+    pub async fn flush(&self) {
+        let flag = Arc::new(FlushFlag { done: atomic::Atomic::new(false), waker: AtomicWaker::new() });
+        let f = flag.clone();
+        self.queue_work(async move {
+            f.done.store(true, atomic::Ordering::Release);
+            f.waker.wake();
+        });
+        FlushFut { flag }.await
+    }
+}
+
+impl Drop for WorkQueue {
+    fn drop(&mut self) {
+        self.inner.closed.store(true, atomic::Ordering::Relaxed);
+        self.inner.waker.wake();
+    }
+}
+
+async fn worker(inner: Arc<Inner>) -> super::TaskResult {
+    loop {
+        let next = NextWork { inner: &inner }.await;
+        match next {
+            Some(work) => work.await,
+            None => {
+                log::trace!("Work queue {} exited", inner.name);
+                return super::TaskResult::ExitedNormally;
+            }
+        }
+    }
+}
+
+/// Completes with the next work item, or `None` when the queue is closed and empty.
+struct NextWork<'a> {
+    inner: &'a Inner,
+}
+
+impl Future for NextWork<'_> {
+    type Output = Option<Work>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(w) = self.inner.pop() {
+            return Poll::Ready(Some(w));
+        }
+        self.inner.waker.register(cx.waker());
+        if let Some(w) = self.inner.pop() {
+            Poll::Ready(Some(w))
+        } else if self.inner.closed.load(atomic::Ordering::Relaxed) {
+            Poll::Ready(None)
+        } else {
+            super::wait::note(super::wait::WaitPoint::channel("workqueue"));
+            Poll::Pending
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum DelayedState {
+    Pending,
+    Queued,
+    Cancelled,
+}
+
+struct DelayedShared {
+    state: atomic::Atomic<DelayedState>,
+    /// Woken when the work is cancelled.
+    waker: AtomicWaker,
+}
+
+/// Handle to work queued by [WorkQueue::queue_delayed_work].
+///
+/// Dropping this does not cancel the work.
+pub struct DelayedWork {
+    shared: Arc<DelayedShared>,
+}
+
+impl DelayedWork {
+    /// Cancels the work if it has not yet been queued.
+    ///
+    /// Returns `false` if the work has already been queued, in which case it will still run.
+    pub fn cancel(&self) -> bool {
+        match self.shared.state.compare_exchange(DelayedState::Pending, DelayedState::Cancelled, atomic::Ordering::AcqRel, atomic::Ordering::Relaxed) {
+            Ok(_) => {
+                self.shared.waker.wake();
+                true
+            }
+            Err(DelayedState::Cancelled) => true,
+            Err(_) => false,
+        }
+    }
+
+    /// Returns whether the work has been moved onto the queue.
+    pub fn is_queued(&self) -> bool {
+        self.shared.state.load(atomic::Ordering::Acquire) == DelayedState::Queued
+    }
+}
+
+/// Completes when the delayed work is cancelled.
+struct CancelFut<'a> {
+    shared: &'a DelayedShared,
+}
+
+impl Future for CancelFut<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.shared.state.load(atomic::Ordering::Acquire) == DelayedState::Cancelled {
+            return Poll::Ready(());
+        }
+        self.shared.waker.register(cx.waker());
+        if self.shared.state.load(atomic::Ordering::Acquire) == DelayedState::Cancelled {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+struct FlushFlag {
+    done: atomic::Atomic<bool>,
+    waker: AtomicWaker,
+}
+
+struct FlushFut {
+    flag: Arc<FlushFlag>,
+}
+
+impl Future for FlushFut {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.flag.done.load(atomic::Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+        self.flag.waker.register(cx.waker());
+        if self.flag.done.load(atomic::Ordering::Acquire) {
+            Poll::Ready(())
+        } else {
+            super::wait::note(super::wait::WaitPoint::channel("workqueue flush"));
+            Poll::Pending
+        }
+    }
+}
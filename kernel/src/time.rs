@@ -7,6 +7,12 @@
 //! these quirks
 
 pub mod acpi_pm_timer;
+pub mod clock_dev;
+pub mod kvmclock;
+pub mod rtc;
+pub mod sntp;
+pub mod vdso;
+pub mod tickless;
 pub(crate) type TimerResult = Result<(), TimerError>;
 
 static SYSTEM_TIME: SystemTime = SystemTime::new();
@@ -161,6 +167,9 @@ pub trait TimeKeeper {
     ///
     /// Implementations must account for handle rollover
     fn time_since(&self, old_time: u64) -> (u64, u64);
+
+    /// Called after the system has resumed from suspend, before the system time is updated.
+    fn resume(&self) {}
 }
 
 /// Returns the current time in nanoseconds since boot.
@@ -174,6 +183,16 @@ pub(crate) fn update_timer() {
     SYSTEM_TIME.update();
 }
 
+/// Resynchronises the system time after the system has resumed from suspend.
+///
+/// The clock count is not preserved while suspended, so the time spent suspended is not counted.
+pub(crate) fn resume_timer() {
+    if let Some(timer) = SYSTEM_TIMEKEEPER.read().as_ref() {
+        timer.resume();
+    }
+    SYSTEM_TIME.init();
+}
+
 pub fn kernel_init_timer(timer: alloc::boxed::Box<(impl TimeKeeper + Sync + Send + 'static)>) {
     // This takes ownership but does not compile without 'static. WHY?
     *SYSTEM_TIMEKEEPER.write() = Some(timer);
@@ -0,0 +1,75 @@
+//! Device files exposing the system clocks.
+//!
+//! Each clock is a character device which returns its current value as a decimal string
+//! terminated by a newline.
+//!
+//! - `/dev/clock_monotonic` nanoseconds since boot.
+//! - `/dev/clock_realtime` seconds since the Unix epoch.
+//! - `/dev/rtc` seconds since the Unix epoch, writing a decimal value to this file sets the RTC.
+
+use crate::fs::devfs::naming::DeviceClass;
+use crate::fs::report::{Report, ReportFile};
+use crate::fs::vfs::{DevID, MajorNum};
+use crate::fs::IoError;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+
+lazy_static::lazy_static!(static ref MAJOR: MajorNum = MajorNum::register("clock").unwrap(););
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Clock {
+    Monotonic,
+    Realtime,
+    Rtc,
+}
+
+impl Clock {
+    fn name(&self) -> &'static str {
+        match self {
+            Clock::Monotonic => "clock_monotonic",
+            Clock::Realtime => "clock_realtime",
+            Clock::Rtc => "rtc",
+        }
+    }
+
+    fn value(&self) -> u64 {
+        match self {
+            Clock::Monotonic => super::get_sys_time(),
+            Clock::Realtime | Clock::Rtc => super::rtc::realtime(),
+        }
+    }
+}
+
+impl Report for Clock {
+    fn report(&self) -> String {
+        alloc::format!("{}\n", self.value())
+    }
+
+    fn writable(&self) -> bool {
+        *self == Clock::Rtc
+    }
+
+    fn write<'a>(&'a self, data: &'a str) -> BoxFuture<'a, Result<(), IoError>> {
+        async move {
+            let secs = data.trim().parse::<u64>().map_err(|_| IoError::InvalidData)?;
+            super::rtc::set_realtime(secs).map_err(|()| IoError::InvalidData)?;
+            log::info!("RTC set to {}", super::rtc::DateTime::from_unix(secs));
+            Ok(())
+        }.boxed()
+    }
+}
+
+/// Initializes the RTC and mounts the clock device files.
+///
+/// The system timer must be initialized before this is called.
+pub fn init() {
+    super::rtc::init();
+    for (minor, clock) in [Clock::Monotonic, Clock::Realtime, Clock::Rtc].into_iter().enumerate() {
+        let file = ReportFile::with_report(DevID::new(*MAJOR, minor), Arc::new(clock));
+        crate::task::util::block_on!(crate::fs::devfs::register(Box::new(file), DeviceClass::Fixed(clock.name())))
+            .expect("Failed to register clock with devfs");
+    }
+}
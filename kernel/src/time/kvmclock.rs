@@ -0,0 +1,116 @@
+//! The KVM paravirtual clock.
+//!
+//! The host publishes a scale and offset which convert the TSC into nanoseconds since an
+//! arbitrary point. Reading it only requires `rdtsc` so it does not cause a VM-exit, unlike
+//! reading emulated timers such as the ACPI PM timer or the HPET.
+//!
+//! The structure is per-CPU, but when the host sets `PVCLOCK_TSC_STABLE_BIT` the structure of any
+//! CPU may be used from all CPUs. [KvmClock] is only used when this is the case, so only the
+//! structure for the BSP is registered.
+
+use crate::system::hypervisor::{kvm_feature, kvm_features};
+use crate::time::TimeKeeper;
+use alloc::boxed::Box;
+use x86_msr::Msr;
+
+/// PVCLOCK_TSC_STABLE_BIT
+const TSC_STABLE: u8 = 1;
+
+/// `pvclock_vcpu_time_info`
+#[repr(C, align(32))]
+#[derive(Default)]
+struct PvClockInfo {
+    /// Odd while the host is updating the structure.
+    version: u32,
+    _pad0: u32,
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+    _pad1: [u8; 2],
+}
+
+pub struct KvmClock {
+    info: core::ptr::NonNull<PvClockInfo>,
+    /// Physical address of `info`
+    addr: u64,
+}
+
+// SAFETY: The structure is only read, and is valid from all CPUs while the clock is stable.
+unsafe impl Send for KvmClock {}
+unsafe impl Sync for KvmClock {}
+
+impl KvmClock {
+    /// Registers the clock with the host, returns `None` if the clock is not available or is
+    /// not stable.
+    pub fn locate() -> Option<Self> {
+        let features = kvm_features();
+        if features & kvm_feature::CLOCKSOURCE2 == 0 || features & kvm_feature::CLOCKSOURCE_STABLE == 0 {
+            return None;
+        }
+
+        let info = core::ptr::NonNull::from(Box::leak(Box::new(PvClockInfo::default())));
+        let addr = crate::mem::mem_map::translate(info.as_ptr() as usize)?;
+        // SAFETY: The MSR is available, the structure is never freed.
+        unsafe { x86_msr::kvm::SystemTimeNew::write((addr | 1).into()) };
+
+        let clock = Self { info, addr };
+        if clock.snapshot().2 & TSC_STABLE == 0 {
+            log::info!("kvmclock is not stable, not using it");
+            // SAFETY: Disables the clock, the structure is leaked so the host may still write to it.
+            unsafe { x86_msr::kvm::SystemTimeNew::write(0u64.into()) };
+            return None;
+        }
+        Some(clock)
+    }
+
+    /// Returns a consistent copy of `(tsc_timestamp, system_time, flags, mul, shift)`
+    fn snapshot(&self) -> (u64, u64, u8, u32, i8) {
+        let info = self.info.as_ptr();
+        loop {
+            // SAFETY: The structure is written by the host, fields are read volatile.
+            unsafe {
+                let version = core::ptr::addr_of!((*info).version).read_volatile();
+                core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+                let r = (
+                    core::ptr::addr_of!((*info).tsc_timestamp).read_volatile(),
+                    core::ptr::addr_of!((*info).system_time).read_volatile(),
+                    core::ptr::addr_of!((*info).flags).read_volatile(),
+                    core::ptr::addr_of!((*info).tsc_to_system_mul).read_volatile(),
+                    core::ptr::addr_of!((*info).tsc_shift).read_volatile(),
+                );
+                core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+                if version & 1 == 0 && version == core::ptr::addr_of!((*info).version).read_volatile() {
+                    return r;
+                }
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Returns the current time in nanoseconds.
+    fn now(&self) -> u64 {
+        let (stamp, system_time, _, mul, shift) = self.snapshot();
+        // SAFETY: rdtsc is always available under KVM
+        let mut delta = unsafe { core::arch::x86_64::_rdtsc() }.wrapping_sub(stamp);
+        if shift < 0 {
+            delta >>= -shift as u32;
+        } else {
+            delta <<= shift as u32;
+        }
+        system_time.wrapping_add(((delta as u128 * mul as u128) >> 32) as u64)
+    }
+}
+
+impl TimeKeeper for KvmClock {
+    fn resume(&self) {
+        // SAFETY: The clock was registered by `locate`, the MSR is cleared while suspended.
+        unsafe { x86_msr::kvm::SystemTimeNew::write((self.addr | 1).into()) };
+    }
+
+    fn time_since(&self, old_time: u64) -> (u64, u64) {
+        let now = self.now();
+        (now.saturating_sub(old_time), now)
+    }
+}
@@ -0,0 +1,301 @@
+//! Driver for the CMOS real time clock.
+//!
+//! The RTC is only read once during [init] to determine the wall clock time at boot, afterwards
+//! the realtime clock is derived from the system time. This avoids polling the slow CMOS ports and
+//! keeps the realtime clock monotonic between steps.
+//!
+//! The realtime clock may be corrected gradually using [adjust_realtime] which slews the clock by
+//! at most [SLEW_PPM] so that it remains monotonic, or stepped using [step_realtime].
+//!
+//! The century register is not located via the FADT, years are assumed to be within 2000..2100.
+
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24H: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Halts updates while the clock is being set.
+const STATUS_B_SET: u8 = 1 << 7;
+/// Set in the hours register when the time is PM in 12 hour mode.
+const HOUR_PM: u8 = 1 << 7;
+
+const SECS_PER_DAY: u64 = 86400;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+/// Maximum rate at which the realtime clock is slewed in parts per million.
+pub const SLEW_PPM: u64 = 500;
+
+/// Serializes access to the CMOS index register.
+static CMOS: spin::Mutex<()> = spin::Mutex::new(());
+static WALL_CLOCK: spin::RwLock<WallClock> = spin::RwLock::new(WallClock { epoch: 0, slew_start: 0, slew: 0 });
+
+/// Offset of the realtime clock from the system time, all values are in nanoseconds.
+struct WallClock {
+    /// Unix time at which the system time was 0.
+    epoch: u64,
+    /// System time at which the current slew started.
+    slew_start: u64,
+    /// Total correction of the current slew, this is applied at [SLEW_PPM].
+    slew: i64,
+}
+
+impl WallClock {
+    /// Returns the part of the slew which has been applied at the system time `now`.
+    fn applied(&self, now: u64) -> i64 {
+        let max = now.saturating_sub(self.slew_start) / 1_000_000 * SLEW_PPM;
+        self.slew.signum() * (self.slew.unsigned_abs().min(max) as i64)
+    }
+
+    fn realtime(&self, now: u64) -> u64 {
+        self.epoch.saturating_add(now).saturating_add_signed(self.applied(now))
+    }
+
+    /// Sets the realtime clock to `nanos` at the system time `now`, cancelling any slew.
+    fn set(&mut self, nanos: u64, now: u64) {
+        self.epoch = nanos.saturating_sub(now);
+        self.slew = 0;
+    }
+}
+
+/// Calendar time as stored by the RTC.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Converts self into seconds since the Unix epoch.
+    pub fn to_unix(&self) -> u64 {
+        // Days from civil, see http://howardhinnant.github.io/date_algorithms.html
+        let y = self.year as i64 - (self.month <= 2) as i64;
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let m = self.month as i64;
+        let doy = (153 * (m + if m > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = (era * 146097 + doe - 719468) as u64;
+
+        days * SECS_PER_DAY + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    /// Converts seconds since the Unix epoch into a calendar time.
+    pub fn from_unix(secs: u64) -> Self {
+        let days = (secs / SECS_PER_DAY) as i64 + 719468;
+        let rem = secs % SECS_PER_DAY;
+        let era = days / 146097;
+        let doe = days - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+        let year = (yoe + era * 400 + (month <= 2) as i64) as u16;
+
+        Self {
+            year,
+            month,
+            day,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+fn read_reg(reg: u8) -> u8 {
+    // SAFETY: Caller must hold CMOS. Selecting a register has no side effects.
+    unsafe {
+        Port::new(INDEX_PORT).write(reg);
+        Port::new(DATA_PORT).read()
+    }
+}
+
+fn write_reg(reg: u8, value: u8) {
+    // SAFETY: Caller must hold CMOS and ensure `value` is valid for `reg`.
+    unsafe {
+        Port::new(INDEX_PORT).write(reg);
+        Port::new(DATA_PORT).write(value);
+    }
+}
+
+fn from_bcd(v: u8) -> u8 {
+    (v & 0xf) + (v >> 4) * 10
+}
+
+fn to_bcd(v: u8) -> u8 {
+    (v / 10) << 4 | v % 10
+}
+
+/// Reads the time registers, values are not converted.
+fn read_raw() -> [u8; 6] {
+    while read_reg(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    [REG_SECONDS, REG_MINUTES, REG_HOURS, REG_DAY, REG_MONTH, REG_YEAR].map(read_reg)
+}
+
+/// Reads the current time from the RTC.
+pub fn read() -> DateTime {
+    let _l = CMOS.lock();
+    without_interrupts(|| {
+        // The registers may be updated between reads, so they are read until two consecutive reads match.
+        let mut raw = read_raw();
+        loop {
+            let next = read_raw();
+            if next == raw {
+                break;
+            }
+            raw = next;
+        }
+        let status = read_reg(REG_STATUS_B);
+
+        let [mut sec, mut min, mut hour, mut day, mut month, mut year] = raw;
+        let pm = hour & HOUR_PM != 0;
+        hour &= !HOUR_PM;
+        if status & STATUS_B_BINARY == 0 {
+            for i in [&mut sec, &mut min, &mut hour, &mut day, &mut month, &mut year] {
+                *i = from_bcd(*i);
+            }
+        }
+        if status & STATUS_B_24H == 0 {
+            // 12AM is 0 and 12PM is 12
+            hour %= 12;
+            if pm {
+                hour += 12;
+            }
+        }
+
+        DateTime {
+            year: 2000 + year as u16,
+            month,
+            day,
+            hour,
+            minute: min,
+            second: sec,
+        }
+    })
+}
+
+/// Sets the RTC to `time`.
+///
+/// Returns `Err(())` if `time.year` is not within 2000..2100
+pub fn write(time: DateTime) -> Result<(), ()> {
+    if !(2000..2100).contains(&time.year) {
+        return Err(());
+    }
+    let _l = CMOS.lock();
+    without_interrupts(|| {
+        let status = read_reg(REG_STATUS_B);
+        write_reg(REG_STATUS_B, status | STATUS_B_SET);
+
+        let mut hour = time.hour;
+        let pm = hour >= 12;
+        if status & STATUS_B_24H == 0 {
+            hour %= 12;
+            if hour == 0 {
+                hour = 12;
+            }
+        }
+        let mut values = [time.second, time.minute, hour, time.day, time.month, (time.year - 2000) as u8];
+        if status & STATUS_B_BINARY == 0 {
+            values = values.map(to_bcd);
+        }
+        if status & STATUS_B_24H == 0 && pm {
+            values[2] |= HOUR_PM;
+        }
+
+        for (reg, v) in [REG_SECONDS, REG_MINUTES, REG_HOURS, REG_DAY, REG_MONTH, REG_YEAR].into_iter().zip(values) {
+            write_reg(reg, v);
+        }
+        write_reg(REG_STATUS_B, status & !STATUS_B_SET);
+    });
+    Ok(())
+}
+
+/// Reads the RTC and initializes the realtime clock.
+///
+/// The system timer must be initialized before this is called.
+pub fn init() {
+    let now = read();
+    WALL_CLOCK.write().set(now.to_unix() * NANOS_PER_SEC, super::get_sys_time());
+    log::info!("RTC time is {now}");
+}
+
+/// Returns the current Unix time in seconds.
+pub fn realtime() -> u64 {
+    realtime_nanos() / NANOS_PER_SEC
+}
+
+/// Returns the current Unix time in nanoseconds.
+pub fn realtime_nanos() -> u64 {
+    WALL_CLOCK.read().realtime(super::get_sys_time())
+}
+
+/// Gradually corrects the realtime clock by `offset` nanoseconds.
+///
+/// The clock runs up to [SLEW_PPM] faster or slower until the correction has been applied, a
+/// correction of one second takes about 33 minutes. This replaces any correction which is still
+/// in progress, the part of it which has already been applied is kept.
+pub fn adjust_realtime(offset: i64) {
+    let now = super::get_sys_time();
+    let mut l = WALL_CLOCK.write();
+    let applied = l.applied(now);
+    l.epoch = l.epoch.saturating_add_signed(applied);
+    l.slew_start = now;
+    l.slew = offset;
+}
+
+/// Returns the part of the correction requested by [adjust_realtime] which has not been applied.
+pub fn slew_remaining() -> i64 {
+    let l = WALL_CLOCK.read();
+    l.slew - l.applied(super::get_sys_time())
+}
+
+/// Immediately corrects the realtime clock by `offset` nanoseconds and updates the RTC.
+///
+/// This cancels any correction in progress. Stepping the clock backwards may cause timestamps to
+/// go backwards, [adjust_realtime] should be used for small corrections.
+pub fn step_realtime(offset: i64) {
+    let now = super::get_sys_time();
+    let nanos = {
+        let mut l = WALL_CLOCK.write();
+        let nanos = l.realtime(now).saturating_add_signed(offset);
+        l.set(nanos, now);
+        nanos
+    };
+    if write(DateTime::from_unix(nanos / NANOS_PER_SEC)).is_err() {
+        log::warn!("RTC can not represent {}", DateTime::from_unix(nanos / NANOS_PER_SEC));
+    }
+}
+
+/// Sets the realtime clock and the RTC to `secs` since the Unix epoch.
+///
+/// Returns `Err(())` if the RTC can not represent `secs`, see [write].
+pub fn set_realtime(secs: u64) -> Result<(), ()> {
+    write(DateTime::from_unix(secs))?;
+    WALL_CLOCK.write().set(secs.saturating_mul(NANOS_PER_SEC), super::get_sys_time());
+    Ok(())
+}
@@ -0,0 +1,169 @@
+//! SNTP client (RFC 4330).
+//!
+//! The client periodically queries [SERVER] and corrects the realtime clock. Each poll sends
+//! [SAMPLES] requests and uses the reply with the lowest round trip delay, which is least
+//! affected by asymmetric queuing. Offsets below [STEP_THRESHOLD] are slewed using
+//! [super::rtc::adjust_realtime], larger offsets step the clock.
+//!
+//! The client does not own a socket, it is started using [start] with a [Transport] provided by
+//! the network stack which exchanges datagrams with the server.
+
+use crate::config::Tunable;
+use crate::fs::IoError;
+use crate::net::buffer::PacketBuff;
+use alloc::boxed::Box;
+use core::net::{Ipv4Addr, SocketAddrV4};
+use futures_util::future::{BoxFuture, Either};
+
+/// Address of the NTP server, `time1.google.com` by default.
+pub static SERVER: Tunable<SocketAddrV4> = Tunable::new("time.sntp.server", "Address of the SNTP server", SocketAddrV4::new(Ipv4Addr::new(216, 239, 35, 0), 123));
+/// Seconds between polls.
+pub static POLL_INTERVAL: Tunable<u64> = Tunable::new("time.sntp.interval", "Seconds between SNTP polls", 1024);
+
+/// Number of requests sent each poll.
+pub const SAMPLES: usize = 4;
+/// Offsets larger than this step the clock, in nanoseconds.
+pub const STEP_THRESHOLD: i64 = 128_000_000;
+/// Time to wait for a reply in milliseconds.
+const TIMEOUT_MSEC: u64 = 2000;
+/// RFC 4330 requires clients to poll no more than once every 15 seconds.
+const MIN_POLL_SECS: u64 = 15;
+
+const PACKET_SIZE: usize = 48;
+/// Seconds between the NTP epoch (1900) and the Unix epoch.
+const NTP_UNIX_OFFSET: i64 = 2_208_988_800;
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+const VERSION: u8 = 4;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+/// Leap indicator value indicating that the server is not synchronized.
+const LI_ALARM: u8 = 3;
+
+const ORIGINATE_OFFSET: usize = 24;
+const RECEIVE_OFFSET: usize = 32;
+const TRANSMIT_OFFSET: usize = 40;
+
+/// Sends a datagram to a server and waits for the reply.
+pub trait Transport: Send + Sync {
+    /// Sends `request` to UDP port `server` and returns the payload of the reply.
+    ///
+    /// `request` is allocated with enough headroom for the UDP and lower headers. Replies from
+    /// other addresses must be discarded, the client applies its own timeout.
+    fn exchange(&self, server: SocketAddrV4, request: PacketBuff) -> BoxFuture<Result<PacketBuff, IoError>>;
+}
+
+/// The result of a single exchange with the server.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Sample {
+    /// Nanoseconds which must be added to the local clock to match the server.
+    pub offset: i64,
+    /// Round trip delay in nanoseconds.
+    pub delay: i64,
+    pub stratum: u8,
+}
+
+/// Converts Unix time in nanoseconds into a 64-bit NTP timestamp.
+fn to_ntp(nanos: u64) -> u64 {
+    let secs = (nanos / NANOS_PER_SEC as u64) as i64 + NTP_UNIX_OFFSET;
+    let frac = ((nanos % NANOS_PER_SEC as u64) << 32) / NANOS_PER_SEC as u64;
+    ((secs as u64) << 32) | frac
+}
+
+/// Converts a 64-bit NTP timestamp into Unix time in nanoseconds.
+///
+/// NTP timestamps wrap every 136 years, the era closest to `near` (Unix nanoseconds) is used.
+fn from_ntp(ts: u64, near: u64) -> i64 {
+    let near_secs = (near / NANOS_PER_SEC as u64) as i64 + NTP_UNIX_OFFSET;
+    let diff = ((ts >> 32) as u32).wrapping_sub(near_secs as u32) as i32 as i64;
+    let frac = (((ts & 0xffff_ffff) * NANOS_PER_SEC as u64) >> 32) as i64;
+    (near_secs + diff - NTP_UNIX_OFFSET) * NANOS_PER_SEC + frac
+}
+
+fn timestamp_at(packet: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(packet[offset..offset + 8].try_into().unwrap())
+}
+
+/// Validates the reply to a request transmitted at `t1` and received at `t4`.
+///
+/// Returns [IoError::InvalidData] if the reply is malformed or does not match the request and
+/// [IoError::NotReady] if the server is not synchronized or sent a kiss-o'-death packet.
+fn parse_reply(reply: &[u8], t1: u64, t4: u64) -> Result<Sample, IoError> {
+    if reply.len() < PACKET_SIZE {
+        return Err(IoError::InvalidData);
+    }
+    let li = reply[0] >> 6;
+    let version = (reply[0] >> 3) & 7;
+    let mode = reply[0] & 7;
+    let stratum = reply[1];
+    if mode != MODE_SERVER || !(3..=4).contains(&version) || timestamp_at(reply, ORIGINATE_OFFSET) != to_ntp(t1) {
+        return Err(IoError::InvalidData);
+    }
+    let transmit = timestamp_at(reply, TRANSMIT_OFFSET);
+    if li == LI_ALARM || stratum == 0 || stratum > 15 || transmit == 0 {
+        return Err(IoError::NotReady);
+    }
+
+    let t1 = t1 as i64;
+    let t4 = t4 as i64;
+    let t2 = from_ntp(timestamp_at(reply, RECEIVE_OFFSET), t1 as u64);
+    let t3 = from_ntp(transmit, t1 as u64);
+    Ok(Sample { offset: ((t2 - t1) + (t3 - t4)) / 2, delay: (t4 - t1) - (t3 - t2), stratum })
+}
+
+/// Sends a single request to `server`.
+pub async fn query(transport: &dyn Transport, server: SocketAddrV4) -> Result<Sample, IoError> {
+    let mut request = crate::net::buffer::alloc();
+    let packet = request.put(PACKET_SIZE).unwrap(); // the buffer is larger than a packet
+    packet.fill(0);
+    packet[0] = (VERSION << 3) | MODE_CLIENT;
+    let t1 = super::rtc::realtime_nanos();
+    packet[TRANSMIT_OFFSET..TRANSMIT_OFFSET + 8].copy_from_slice(&to_ntp(t1).to_be_bytes());
+
+    let timeout = Box::pin(crate::task::util::sleep(TIMEOUT_MSEC));
+    let reply = match futures_util::future::select(transport.exchange(server, request), timeout).await {
+        Either::Left((r, _)) => r?,
+        Either::Right(_) => return Err(IoError::NotReady),
+    };
+    parse_reply(reply.data(), t1, super::rtc::realtime_nanos())
+}
+
+/// Polls the server and corrects the realtime clock.
+///
+/// Returns the sample used to correct the clock.
+pub async fn poll(transport: &dyn Transport) -> Result<Sample, IoError> {
+    let server = SERVER.get();
+    let mut best: Option<Sample> = None;
+    let mut err = IoError::NotReady;
+    for _ in 0..SAMPLES {
+        match query(transport, server).await {
+            Ok(s) if best.is_none_or(|b| s.delay < b.delay) => best = Some(s),
+            Ok(_) => {}
+            Err(e) => err = e,
+        }
+    }
+    let sample = best.ok_or(err)?;
+
+    if sample.offset.abs() > STEP_THRESHOLD {
+        log::info!("sntp: stepping clock by {}ms", sample.offset / 1_000_000);
+        super::rtc::step_realtime(sample.offset);
+    } else {
+        log::debug!("sntp: slewing clock by {}us, delay {}us", sample.offset / 1000, sample.delay / 1000);
+        super::rtc::adjust_realtime(sample.offset);
+    }
+    Ok(sample)
+}
+
+/// Starts a task which polls the server every [POLL_INTERVAL] seconds using `transport`.
+pub fn start(transport: Box<dyn Transport>) {
+    crate::task::run_task(Box::pin(client(transport)));
+}
+
+async fn client(transport: Box<dyn Transport>) -> crate::task::TaskResult {
+    loop {
+        if let Err(e) = poll(&*transport).await {
+            log::warn!("sntp: Failed to poll {}: {e:?}", SERVER.get());
+        }
+        crate::task::util::sleep(POLL_INTERVAL.get().max(MIN_POLL_SECS) * 1000).await;
+    }
+}
@@ -0,0 +1,93 @@
+//! Tickless idle.
+//!
+//! Each CPU normally receives a periodic timer interrupt which updates the system time and wakes
+//! expired timers. When a CPU becomes idle and the next timer, from either the
+//! [crate::task::timer_wheel] or [crate::task::util::sleep], is more than two periods away the local
+//! APIC timer is switched to one-shot mode for that deadline, the periodic tick is restored when the
+//! CPU leaves idle.
+//!
+//! Without the tick an idle CPU would not notice tasks placed in its run queue by other CPUs, so
+//! [kick] sends a [reschedule](crate::mp::ipi::reschedule) IPI to CPUs which stopped their tick. The idle period is limited to
+//! [MAX_IDLE_MS] so the clock source is read before it can roll over.
+
+use crate::config::Tunable;
+use crate::mp::CpuIndex;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Whether idle CPUs may stop their periodic tick.
+pub static TICKLESS: Tunable<bool> = Tunable::new("time.tickless", "Stop the periodic timer interrupt on idle CPUs", true);
+
+/// Longest time an idle CPU may go without a timer interrupt.
+pub static MAX_IDLE_MS: Tunable<u64> = Tunable::new("time.max_idle_ms", "Maximum milliseconds an idle CPU may stop its timer interrupt for", 1000);
+
+const MAX_CPUS: usize = crate::task::affinity::MAX_CPUS;
+
+/// Bitmap of CPUs which have stopped their periodic tick.
+static STOPPED: [AtomicU64; MAX_CPUS / 64] = [const { AtomicU64::new(0) }; MAX_CPUS / 64];
+
+/// Number of times a CPU stopped its tick, and the number of ticks which were skipped.
+static IDLE_ENTRIES: AtomicU64 = AtomicU64::new(0);
+static SKIPPED_TICKS: AtomicU64 = AtomicU64::new(0);
+
+fn stopped(cpu: CpuIndex) -> Option<(&'static AtomicU64, u64)> {
+    Some((STOPPED.get(cpu as usize / 64)?, 1 << (cpu % 64)))
+}
+
+/// Returns the time of the next timer in nanoseconds since boot, `None` if no timers are armed.
+fn next_deadline() -> Option<u64> {
+    match (crate::task::timer_wheel::next_expiry(), crate::task::util::next_slp()) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Stops the periodic tick of the calling CPU if the next timer is far enough away.
+///
+/// Must be called with interrupts disabled immediately before halting.
+pub(crate) fn enter_idle() {
+    if !TICKLESS.get() || !crate::mp::ipi::is_ready() {
+        return;
+    }
+    let Some((word, bit)) = stopped(crate::who_am_i()) else { return };
+    let now = super::get_sys_time();
+    let limit = now.saturating_add(MAX_IDLE_MS.get().saturating_mul(1_000_000));
+    let delta = next_deadline().map_or(limit, |d| d.min(limit)).saturating_sub(now);
+    // Near deadlines are served by the periodic tick
+    if delta <= 2 * crate::interrupts::apic::TICK_PERIOD_NS {
+        return;
+    }
+    // Published before the timer is stopped so a concurrent kick is not lost
+    word.fetch_or(bit, Ordering::SeqCst);
+    if crate::interrupts::apic::set_timer_oneshot(delta) {
+        IDLE_ENTRIES.fetch_add(1, Ordering::Relaxed);
+        SKIPPED_TICKS.fetch_add(delta / crate::interrupts::apic::TICK_PERIOD_NS, Ordering::Relaxed);
+    } else {
+        word.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
+
+/// Restores the periodic tick of the calling CPU if it was stopped by [enter_idle].
+pub(crate) fn exit_idle() {
+    let Some((word, bit)) = stopped(crate::who_am_i()) else { return };
+    if word.fetch_and(!bit, Ordering::SeqCst) & bit != 0 {
+        crate::interrupts::apic::set_timer_periodic();
+        // The CPU may have been woken by another interrupt before the deadline
+        crate::interrupts::apic::timer_tick();
+    }
+}
+
+/// Wakes `cpu` if it has stopped its tick. This is called when a task is queued on another CPU.
+pub(crate) fn kick(cpu: CpuIndex) {
+    let Some((word, bit)) = stopped(cpu) else { return };
+    // Orders the caller's push to the run queue before the load, see the idle loop
+    core::sync::atomic::fence(Ordering::SeqCst);
+    if cpu == crate::who_am_i() || word.load(Ordering::SeqCst) & bit == 0 {
+        return;
+    }
+    let _ = crate::mp::ipi::reschedule(cpu);
+}
+
+/// Returns the number of times CPUs stopped their tick and the number of ticks which were skipped.
+pub fn stats() -> (u64, u64) {
+    (IDLE_ENTRIES.load(Ordering::Relaxed), SKIPPED_TICKS.load(Ordering::Relaxed))
+}
@@ -0,0 +1,205 @@
+//! Clock data for reading the time without entering the kernel.
+//!
+//! [VdsoData] is a page which contains no kernel pointers and is intended to be mapped read-only
+//! into user processes. It describes the monotonic and realtime clocks as a linear function of the
+//! TSC, [clock_gettime] and [getcpu] read the time and the current CPU using only the page and
+//! unprivileged instructions so they may be called from user mode.
+//!
+//! A task samples the TSC against [super::get_sys_time] and [super::rtc::realtime_nanos] every
+//! [UPDATE_MS] and republishes the data. The TSC rate is measured over the previous interval, the
+//! monotonic base never moves backwards when the rate is corrected. The data is only marked valid
+//! when the CPU has an invariant TSC, otherwise callers must fall back to the kernel clocks.
+//!
+//! [getcpu] uses `rdtscp`, which returns `IA32_TSC_AUX`. [register_cpu] sets this to the CPU's
+//! index, it must be called by each CPU.
+//!
+//! There are no user processes yet, so the page is not mapped anywhere. [data_page] returns its
+//! physical address for the process loader.
+
+use crate::config::Tunable;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use x86_msr::architecture::TscAux;
+use x86_msr::{Msr, MsrReadWrite};
+
+/// Interval between updates of the clock data.
+pub static UPDATE_MS: Tunable<u64> = Tunable::new("vdso.update_ms", "Milliseconds between updates of the user clock data", 100);
+
+/// The TSC may be used to read the clocks.
+pub const FLAG_VALID: u32 = 1;
+/// `IA32_TSC_AUX` contains the CPU index on every CPU.
+pub const FLAG_GETCPU: u32 = 1 << 1;
+
+/// Clocks which can be read from [VdsoData], the values are the same as Linux.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum ClockId {
+    Realtime = 0,
+    Monotonic = 1,
+}
+
+/// Clock data shared with user processes.
+///
+/// The fields are protected by a sequence count, writers make it odd while they update the data.
+#[repr(C, align(4096))]
+pub struct VdsoData {
+    seq: AtomicU32,
+    flags: AtomicU32,
+    /// TSC value at which the bases were sampled.
+    tsc_base: AtomicU64,
+    /// Nanoseconds since boot at `tsc_base`.
+    mono_base: AtomicU64,
+    /// Nanoseconds since the Unix epoch at `tsc_base`.
+    real_base: AtomicU64,
+    /// Nanoseconds per TSC tick as a 32.32 fixed point number.
+    mult: AtomicU64,
+}
+
+static DATA: VdsoData = VdsoData {
+    seq: AtomicU32::new(0),
+    flags: AtomicU32::new(0),
+    tsc_base: AtomicU64::new(0),
+    mono_base: AtomicU64::new(0),
+    real_base: AtomicU64::new(0),
+    mult: AtomicU64::new(0),
+};
+
+impl VdsoData {
+    /// Returns the time of `clock` at the TSC value `tsc` using the current data.
+    fn at(&self, clock: ClockId, tsc: u64) -> u64 {
+        let base = match clock {
+            ClockId::Realtime => self.real_base.load(Ordering::Relaxed),
+            ClockId::Monotonic => self.mono_base.load(Ordering::Relaxed),
+        };
+        // The TSC of another CPU may be slightly behind the CPU which sampled it.
+        let delta = tsc.saturating_sub(self.tsc_base.load(Ordering::Relaxed));
+        base + ((delta as u128 * self.mult.load(Ordering::Relaxed) as u128) >> 32) as u64
+    }
+
+    /// Calls `f` with a consistent view of the data.
+    fn read<R>(&self, f: impl Fn(&Self) -> R) -> R {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq & 1 != 0 {
+                core::hint::spin_loop();
+                continue;
+            }
+            let r = f(self);
+            core::sync::atomic::fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return r;
+            }
+        }
+    }
+
+    /// Only the update task may call this.
+    fn write(&self, f: impl FnOnce(&Self)) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        core::sync::atomic::fence(Ordering::Release);
+        f(self);
+        self.seq.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Returns the time of `clock` in nanoseconds, or `None` if the data is not valid.
+pub fn clock_gettime(data: &VdsoData, clock: ClockId) -> Option<u64> {
+    data.read(|d| {
+        if d.flags.load(Ordering::Relaxed) & FLAG_VALID == 0 {
+            return None;
+        }
+        // SAFETY: rdtsc has no side effects
+        Some(d.at(clock, unsafe { core::arch::x86_64::_rdtsc() }))
+    })
+}
+
+/// Returns the index of the current CPU, or `None` if `rdtscp` is not available.
+///
+/// The task may be moved to another CPU as soon as this returns.
+pub fn getcpu(data: &VdsoData) -> Option<u32> {
+    if data.flags.load(Ordering::Relaxed) & FLAG_GETCPU == 0 {
+        return None;
+    }
+    let mut aux = 0;
+    // SAFETY: FLAG_GETCPU is only set when rdtscp is available
+    unsafe { core::arch::x86_64::__rdtscp(&mut aux) };
+    Some(aux)
+}
+
+/// Returns the kernel's copy of the clock data.
+pub fn data() -> &'static VdsoData {
+    &DATA
+}
+
+/// Returns the physical address of the clock data page.
+pub fn data_page() -> Option<u64> {
+    crate::mem::mem_map::translate(&DATA as *const VdsoData as usize)
+}
+
+fn has_rdtscp() -> bool {
+    TscAux::availability().get_bit(0) == MsrReadWrite::Write
+}
+
+/// Sets `IA32_TSC_AUX` to the index of the calling CPU. This must be called once by each CPU.
+pub fn register_cpu() {
+    if has_rdtscp() {
+        // SAFETY: TSC_AUX is only read by rdtscp
+        unsafe { TscAux::write((crate::who_am_i() as u64).into()) };
+    }
+}
+
+struct Sample {
+    tsc: u64,
+    mono: u64,
+    real: u64,
+}
+
+fn sample() -> Sample {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // SAFETY: rdtsc has no side effects
+        let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+        Sample { tsc, mono: super::get_sys_time(), real: super::rtc::realtime_nanos() }
+    })
+}
+
+/// Publishes `now` using the TSC rate measured since `prev`.
+fn update(prev: &Sample, now: &Sample, valid: bool) {
+    let ticks = now.tsc.saturating_sub(prev.tsc);
+    if ticks == 0 {
+        return;
+    }
+    let mult = ((now.mono.saturating_sub(prev.mono) as u128) << 32) / ticks as u128;
+    // Don't let the monotonic clock go backwards when the previous rate was too fast
+    let mono = match DATA.flags.load(Ordering::Relaxed) & FLAG_VALID {
+        0 => now.mono,
+        _ => now.mono.max(DATA.at(ClockId::Monotonic, now.tsc)),
+    };
+    let mut flags = if has_rdtscp() { FLAG_GETCPU } else { 0 };
+    if valid {
+        flags |= FLAG_VALID;
+    }
+    DATA.write(|d| {
+        d.tsc_base.store(now.tsc, Ordering::Relaxed);
+        d.mono_base.store(mono, Ordering::Relaxed);
+        d.real_base.store(now.real, Ordering::Relaxed);
+        d.mult.store(mult.min(u64::MAX as u128) as u64, Ordering::Relaxed);
+        d.flags.store(flags, Ordering::Relaxed);
+    });
+}
+
+/// Starts the task which updates the clock data. The system timer and RTC must be initialized.
+pub fn init() {
+    let invariant = raw_cpuid::CpuId::new().get_advanced_power_mgmt_info().is_some_and(|i| i.has_invariant_tsc());
+    if !invariant {
+        log::info!("TSC is not invariant, user clock data is disabled");
+    }
+    crate::task::run_task(alloc::boxed::Box::pin(update_task(invariant)));
+}
+
+async fn update_task(valid: bool) -> crate::task::TaskResult {
+    let mut prev = sample();
+    loop {
+        crate::task::util::sleep(UPDATE_MS.get().max(1)).await;
+        let now = sample();
+        update(&prev, &now, valid);
+        prev = now;
+    }
+}
@@ -0,0 +1,95 @@
+//! Per-CPU trace of recent events.
+//!
+//! Each CPU records the interrupts it dispatches and the tasks it polls into a small ring which
+//! overwrites the oldest event. The ring is dumped by the NMI, machine check and double fault
+//! handlers to show what the CPU was doing before the exception. Other code may record its own
+//! events using [mark].
+//!
+//! Recording does not lock or disable interrupts, an interrupt which arrives while an event is
+//! recorded uses the next slot. Events are not recorded before the runlevel leaves `PreInit`.
+
+use core::cell::Cell;
+
+/// Number of events kept on each CPU.
+pub const LEN: usize = 32;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EventKind {
+    /// An interrupt was dispatched, the argument is its vector.
+    Irq,
+    /// A task was polled, the argument is its ID.
+    Poll,
+    Mark(&'static str),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Event {
+    /// TSC when the event was recorded.
+    pub tsc: u64,
+    pub kind: EventKind,
+    pub arg: u64,
+}
+
+impl core::fmt::Display for Event {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.kind {
+            EventKind::Irq => write!(f, "irq {:#x}", self.arg),
+            EventKind::Poll => write!(f, "poll task {}", self.arg),
+            EventKind::Mark(name) => write!(f, "{name} {:#x}", self.arg),
+        }
+    }
+}
+
+#[thread_local]
+static EVENTS: [Cell<Option<Event>>; LEN] = [const { Cell::new(None) }; LEN];
+/// Number of events recorded on this CPU.
+#[thread_local]
+static NEXT: Cell<usize> = Cell::new(0);
+
+fn tsc() -> u64 {
+    // SAFETY: rdtsc has no side effects
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+fn record(kind: EventKind, arg: u64) {
+    if crate::runlevel::runlevel() == crate::runlevel::Runlevel::PreInit {
+        return;
+    }
+    let i = NEXT.get();
+    NEXT.set(i.wrapping_add(1));
+    EVENTS[i % LEN].set(Some(Event { tsc: tsc(), kind, arg }));
+}
+
+pub(crate) fn irq(vector: u8) {
+    record(EventKind::Irq, vector as u64);
+}
+
+pub(crate) fn poll(task: crate::task::TaskId) {
+    record(EventKind::Poll, task.into());
+}
+
+/// Records `name` with an argument in this CPU's trace.
+pub fn mark(name: &'static str, arg: u64) {
+    record(EventKind::Mark(name), arg);
+}
+
+/// Returns the events recorded on this CPU, oldest first.
+pub fn recent() -> impl Iterator<Item = Event> {
+    let next = if crate::runlevel::runlevel() == crate::runlevel::Runlevel::PreInit { 0 } else { NEXT.get() };
+    (next.saturating_sub(LEN)..next).filter_map(|i| EVENTS[i % LEN].get())
+}
+
+/// Writes the events recorded on this CPU to `f`, one per line with the number of TSC cycles
+/// elapsed since each event.
+pub fn dump(f: &mut dyn core::fmt::Write) -> core::fmt::Result {
+    let now = tsc();
+    let mut any = false;
+    for e in recent() {
+        any = true;
+        writeln!(f, "  -{:>12} {e}", now.saturating_sub(e.tsc))?;
+    }
+    if !any {
+        writeln!(f, "  (none)")?;
+    }
+    Ok(())
+}
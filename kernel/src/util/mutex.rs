@@ -1,26 +1,34 @@
 use core::cell::{UnsafeCell};
 use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use crate::lockdep::{ClassKey, Kind};
 use core::sync::atomic;
 use core::sync::atomic::Ordering;
 
 pub struct Mutex<T> {
     lock: atomic::AtomicBool,
     inner: UnsafeCell<T>,
+    /// Lock dependency class, see [crate::lockdep].
+    class: &'static Location<'static>,
 }
 
 /// Native implementation of Mutex, capable of being forcibly acquired without being unlocked.
 impl<T> Mutex<T> {
+    #[track_caller]
     pub const fn new(data: T) -> Self {
         Self {
             inner: UnsafeCell::new(data),
             lock: atomic::AtomicBool::new(false),
+            class: Location::caller(),
         }
     }
 
     #[inline]
+    #[track_caller]
     pub fn lock(&self) -> MutexGuard<T> {
+        crate::lockdep::acquire(self.key(), ClassKey::Site(self.class), Location::caller(), Kind::Lock);
         loop {
-            if let Some(t) = self.try_lock() {
+            if let Some(t) = self.try_lock_raw() {
                 return t;
             } else {
                 core::hint::spin_loop()
@@ -29,7 +37,20 @@ impl<T> Mutex<T> {
     }
 
     #[inline]
+    #[track_caller]
     pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let g = self.try_lock_raw()?;
+        crate::lockdep::acquire(self.key(), ClassKey::Site(self.class), Location::caller(), Kind::TryLock);
+        Some(g)
+    }
+
+    /// Lock dependency key, this matches the address used by [MutexGuard].
+    fn key(&self) -> usize {
+        &self.lock as *const atomic::AtomicBool as usize
+    }
+
+    #[inline]
+    fn try_lock_raw(&self) -> Option<MutexGuard<T>> {
         if let Ok(_) =
             self.lock
                 .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
@@ -89,6 +110,7 @@ impl<'a, T> DerefMut for MutexGuard<'a, T> {
 
 impl<'a, T> Drop for MutexGuard<'a, T> {
     fn drop(&mut self) {
+        crate::lockdep::release(self.lock as *const atomic::AtomicBool as usize);
         self.lock.store(false, Ordering::Release);
     }
 }
@@ -110,6 +132,8 @@ pub struct ReentrantMutex<T> {
     // at the time of writing CpuIndex is 32bit so this is non-locking
     owner: ::atomic::Atomic<Option<crate::mp::CpuIndex>>,
     lock_count: atomic::AtomicUsize,
+    /// Lock dependency class, see [crate::lockdep].
+    class: &'static Location<'static>,
 }
 
 unsafe impl<T: Send> Send for ReentrantMutex<T> {}
@@ -122,18 +146,42 @@ pub struct ReentrantMutexGuard<'a, T> {
 }
 
 impl<'a, T> ReentrantMutex<T> {
+    #[track_caller]
     pub const fn new(data: T) -> Self {
         Self {
             data: UnsafeCell::new(data),
             lock_count: atomic::AtomicUsize::new(0),
             owner: ::atomic::Atomic::new(None),
             control: atomic::AtomicBool::new(false),
+            class: Location::caller(),
         }
     }
 
     /// Attempts to lock control bit returns None if control bit is locked.
     #[inline]
+    #[track_caller]
     pub fn try_lock_inner(&self) -> Option<ReentrantMutexGuard<T>> {
+        let g = self.try_lock_raw()?;
+        if self.lock_count.load(Ordering::Relaxed) == 1 {
+            crate::lockdep::acquire(self.key(), ClassKey::Site(self.class), Location::caller(), Kind::TryLock);
+        }
+        Some(g)
+    }
+
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Only the first acquisition by a CPU is checked for lock dependencies.
+    #[track_caller]
+    fn track_blocking(&self) {
+        if self.owner.load(Ordering::Relaxed) != Some(crate::who_am_i()) {
+            crate::lockdep::acquire(self.key(), ClassKey::Site(self.class), Location::caller(), Kind::Reentrant);
+        }
+    }
+
+    #[inline]
+    fn try_lock_raw(&self) -> Option<ReentrantMutexGuard<T>> {
         self.try_control(||
         {
             match self.owner.load(Ordering::Relaxed) {
@@ -176,6 +224,7 @@ impl<'a, T> ReentrantMutex<T> {
 
         // fetch sub fetches the value **before** sub, if nc is 1 then lock_count is 0
         if nc == 1 {
+            crate::lockdep::release(self.key());
             self.owner.store(None,Ordering::Release)
         }
     }
@@ -184,9 +233,11 @@ impl<'a, T> ReentrantMutex<T> {
     /// This fn is different from [Self::try_lock_inner] because it will spin while waiting for the
     /// control bit, and exits if the mutex is already locked.
     #[inline]
+    #[track_caller]
     pub fn try_lock(&self) -> Option<ReentrantMutexGuard<T>> {
+        self.track_blocking();
         loop {
-            if let Some(t) = self.try_lock_inner() {
+            if let Some(t) = self.try_lock_raw() {
                 return Some(t);
             } else {
                 core::hint::spin_loop();
@@ -195,9 +246,11 @@ impl<'a, T> ReentrantMutex<T> {
     }
 
     #[inline]
+    #[track_caller]
     pub fn lock(&self) -> ReentrantMutexGuard<T> {
+        self.track_blocking();
         loop {
-            if let Some(t) = self.try_lock() {
+            if let Some(t) = self.try_lock_raw() {
                 return t;
             } else {
                 core::hint::spin_loop();
@@ -209,6 +262,7 @@ impl<'a, T> ReentrantMutex<T> {
     /// This differs from [self.lock] because that will acquire `self` if the owner is the calling CPU.
     ///
     /// This is intended for use with interrupts. However
+    #[track_caller]
     pub fn try_lock_pedantic(&self) -> Option<ReentrantMutexGuard<T>> {
         loop {
             match self.try_control(|| {
@@ -234,7 +288,10 @@ impl<'a, T> ReentrantMutex<T> {
                     }
                 }
             }) {
-                Ok(Some(Ok(g))) => return Some(g),
+                Ok(Some(Ok(g))) => {
+                    crate::lockdep::acquire(self.key(), ClassKey::Site(self.class), Location::caller(), Kind::TryLock);
+                    return Some(g)
+                },
 
                 // This CPU is owner, break to avoid deadlock
                 Ok(Some(Err(()))) => return None,
@@ -1,11 +1,60 @@
 mod privacy {
     pub trait Sealed {}
 }
-/// This is a marker trait for checking command compatibility and may not be be implemented outside
-/// of this crate
-pub trait CheckableCommand: privacy::Sealed {}
+use crate::structures::identification::{DeviceIdentity, Features119, Features82, Features83, SupportBits};
+
+/// A command whose support can be checked using [DeviceIdentity::is_supported].
+/// This may not be implemented outside of this crate.
+pub trait CheckableCommand: privacy::Sealed {
+    /// Returns `Some(b)` where `b` indicates whether the device supports `self`, or `None` if the
+    /// device identity does not indicate support for `self`.
+    fn check_support(&self, id: &DeviceIdentity) -> Option<bool>;
+}
+
+impl CheckableCommand for AtaCommand {
+    fn check_support(&self, id: &DeviceIdentity) -> Option<bool> {
+        id.ata_support_bits().into_iter().find_map(|b| b.supports(*self))
+    }
+}
 
-impl<T> CheckableCommand for T where T: privacy::Sealed {}
+impl CheckableCommand for SanitiseSubcommand {
+    fn check_support(&self, id: &DeviceIdentity) -> Option<bool> {
+        id.sanitize_sub_cmd.supports(*self)
+    }
+}
+
+impl CheckableCommand for SetFeaturesSubcommand {
+    fn check_support(&self, id: &DeviceIdentity) -> Option<bool> {
+        use SetFeaturesSubcommand::*;
+        let ret = match self {
+            ENABLE_VOLATILE_WRITE_CACHE | DISABLE_VOLATILE_WRITE_CACHE => {
+                id.features.features_82.contains(Features82::VOLATILE_WRITE_CACHE)
+            }
+            ENABLE_READ_LOOK_AHEAD | DISABLE_READ_LOOK_AHEAD => {
+                id.features.features_82.contains(Features82::LOOK_AHEAD)
+            }
+            ENABLE_APM | DISABLE_APM => id.features.features_83.contains(Features83::APM),
+            ENABLE_FREE_FALL_CTL | DISABLE_FREE_FALL_CTL => {
+                id.features119.contains(Features119::FREE_FALL_CTL)
+            }
+            ENABLE_WRITE_READ_VERIFY | DISABLE_WRITE_READ_VERIFY => {
+                id.features119.contains(Features119::READ_WRITE_VERIFY)
+            }
+        };
+        Some(ret)
+    }
+}
+
+impl CheckableCommand for MicrocodeSubcommand {
+    fn check_support(&self, id: &DeviceIdentity) -> Option<bool> {
+        match self {
+            MicrocodeSubcommand::DOWNLOAD_SAVE => AtaCommand::DOWNLOAD_MICROCODE.check_support(id),
+            MicrocodeSubcommand::DOWNLOAD_OFFSETS_SAVE => {
+                Some(id.features119.contains(Features119::DOWNLOAD_MICRO_MODE_3))
+            }
+        }
+    }
+}
 
 #[repr(u8)]
 #[derive(
@@ -186,6 +235,8 @@ impl AtaCommand {
             AtaCommand::SECURITY_UNLOCK => true,
             AtaCommand::SECURITY_ERASE_UNIT => true,
             AtaCommand::SECURITY_DISABLE_PASSWORD => true,
+            AtaCommand::DOWNLOAD_MICROCODE => true,
+            AtaCommand::DOWNLOAD_MICROCODE_DMA => true,
             _ => false,
         }
     }
@@ -208,6 +259,46 @@ pub enum SanitiseSubcommand {
 
 impl privacy::Sealed for SanitiseSubcommand {}
 
+/// Used with the [AtaCommand::SET_FEATURES] command as the feature field.
+///
+/// Where a subcommand takes an argument it is given in the count field.
+#[repr(u8)]
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, num_enum::TryFromPrimitive, num_enum::IntoPrimitive)]
+pub enum SetFeaturesSubcommand {
+    ENABLE_VOLATILE_WRITE_CACHE = 0x02,
+    /// Count contains the APM level, 0x00 and 0xFF are reserved.
+    ENABLE_APM = 0x05,
+    /// Count contains the Write-Read-Verify mode. For mode 3 LBA (7:0) contains the number of
+    /// logical sectors to verify in units of 1024 sectors.
+    ENABLE_WRITE_READ_VERIFY = 0x0b,
+    /// Count contains the sensitivity, 0x00 selects the vendor's recommended sensitivity.
+    ENABLE_FREE_FALL_CTL = 0x41,
+    DISABLE_READ_LOOK_AHEAD = 0x55,
+    DISABLE_VOLATILE_WRITE_CACHE = 0x82,
+    DISABLE_APM = 0x85,
+    DISABLE_WRITE_READ_VERIFY = 0x8b,
+    ENABLE_READ_LOOK_AHEAD = 0xaa,
+    DISABLE_FREE_FALL_CTL = 0xc1,
+}
+
+impl privacy::Sealed for SetFeaturesSubcommand {}
+
+/// Used with the [AtaCommand::DOWNLOAD_MICROCODE] and [AtaCommand::DOWNLOAD_MICROCODE_DMA]
+/// commands as the feature field.
+#[repr(u8)]
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, num_enum::TryFromPrimitive, num_enum::IntoPrimitive)]
+pub enum MicrocodeSubcommand {
+    /// Download microcode in segments using offsets, the microcode is saved and activated once
+    /// the final segment is received.
+    DOWNLOAD_OFFSETS_SAVE = 0x03,
+    /// Download the entire microcode in a single command, save and activate it.
+    DOWNLOAD_SAVE = 0x07,
+}
+
+impl privacy::Sealed for MicrocodeSubcommand {}
+
 pub mod constructor {
     use crate::command::AtaCommand;
 
@@ -355,6 +446,130 @@ pub mod constructor {
         }
     }
 
+    /// LBA addressing used by a data transfer command.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    pub enum Addressing {
+        /// 28-bit commands such as [AtaCommand::READ_DMA]. These can address LBAs up to
+        /// `0xFFF_FFFF` and transfer up to 256 sectors.
+        Lba28,
+        /// 48-bit commands such as [AtaCommand::READ_DMA_EXT]. These can address LBAs up to
+        /// `0xFFFF_FFFF_FFFF` and transfer up to 65,536 sectors. Devices only support these when
+        /// [crate::structures::identification::Features83::LBA_48] is set.
+        Lba48,
+    }
+
+    impl Addressing {
+        /// Returns the last LBA which can be addressed.
+        pub const fn max_lba(self) -> u64 {
+            match self {
+                Addressing::Lba28 => 0xFFF_FFFF,
+                Addressing::Lba48 => 0xFFFF_FFFF_FFFF,
+            }
+        }
+
+        /// Returns the maximum number of sectors which may be transferred by a single command.
+        pub const fn max_count(self) -> u32 {
+            match self {
+                Addressing::Lba28 => 1 << 8,
+                Addressing::Lba48 => 1 << 16,
+            }
+        }
+
+        /// Returns whether a transfer of `count` sectors starting at `lba` can be issued using this addressing.
+        pub const fn fits(self, lba: u64, count: u32) -> bool {
+            count != 0 && count <= self.max_count() && lba <= self.max_lba() && lba + count as u64 - 1 <= self.max_lba()
+        }
+
+        /// Selects the addressing for a transfer of `count` sectors starting at `lba`.
+        ///
+        /// 28-bit commands are used whenever the transfer fits within them, 48-bit commands are
+        /// only used when required and `lba_48` indicates the device supports them.
+        pub fn select(lba: u64, count: u32, lba_48: bool) -> Result<Self, AddressError> {
+            if count == 0 {
+                Err(AddressError::ZeroCount)
+            } else if Addressing::Lba28.fits(lba, count) {
+                Ok(Addressing::Lba28)
+            } else if !Addressing::Lba48.fits(lba, count) {
+                Err(AddressError::OutOfRange)
+            } else if lba_48 {
+                Ok(Addressing::Lba48)
+            } else {
+                Err(AddressError::Requires48Bit)
+            }
+        }
+    }
+
+    /// Errors returned when a transfer cannot be addressed.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    pub enum AddressError {
+        /// The transfer did not contain any sectors.
+        ZeroCount,
+        /// The transfer extends beyond the range addressable by any command or transfers more
+        /// sectors than a single command can.
+        OutOfRange,
+        /// The transfer can only be addressed by 48-bit commands which the device does not support.
+        Requires48Bit,
+    }
+
+    /// Constructs a DMA read or write, either [AtaCommand::READ_DMA]/[AtaCommand::WRITE_DMA] or
+    /// [AtaCommand::READ_DMA_EXT]/[AtaCommand::WRITE_DMA_EXT] depending on the [Addressing].
+    #[derive(Copy, Clone, Debug)]
+    pub struct DmaCmd {
+        cmd: SpanningCmdType,
+        lba: u64,
+        count: u32,
+        addressing: Addressing,
+    }
+
+    impl DmaCmd {
+        /// Constructs a transfer of `count` sectors at `lba`, selecting the addressing using [Addressing::select].
+        pub fn new(cmd_type: SpanningCmdType, lba: u64, count: u32, lba_48: bool) -> Result<Self, AddressError> {
+            let addressing = Addressing::select(lba, count, lba_48)?;
+            Ok(Self { cmd: cmd_type, lba, count, addressing })
+        }
+
+        /// Constructs a transfer using the given addressing.
+        pub fn with_addressing(cmd_type: SpanningCmdType, lba: u64, count: u32, addressing: Addressing) -> Result<Self, AddressError> {
+            if count == 0 {
+                return Err(AddressError::ZeroCount);
+            }
+            if !addressing.fits(lba, count) {
+                return Err(AddressError::OutOfRange);
+            }
+            Ok(Self { cmd: cmd_type, lba, count, addressing })
+        }
+
+        pub fn addressing(&self) -> Addressing {
+            self.addressing
+        }
+    }
+
+    impl CommandConstructor for DmaCmd {
+        fn compose(self) -> ComposedCommand {
+            let command = match (self.cmd, self.addressing) {
+                (SpanningCmdType::Read, Addressing::Lba28) => AtaCommand::READ_DMA,
+                (SpanningCmdType::Write, Addressing::Lba28) => AtaCommand::WRITE_DMA,
+                (SpanningCmdType::Read, Addressing::Lba48) => AtaCommand::READ_DMA_EXT,
+                (SpanningCmdType::Write, Addressing::Lba48) => AtaCommand::WRITE_DMA_EXT,
+            };
+            let mut cmd = ComposedCommand::zeroed(command.into());
+            // A count of 0 means the maximum count
+            cmd.count = Some((self.count % self.addressing.max_count()) as u16);
+            match self.addressing {
+                Addressing::Lba28 => {
+                    // LBA bits 24..28 are given in the low nibble of the device register
+                    cmd.lba = Some(self.lba & 0xFF_FFFF);
+                    cmd.device = Some(1 << 6 | (self.lba >> 24) as u8 & 0xf);
+                }
+                Addressing::Lba48 => {
+                    cmd.lba = Some(self.lba);
+                    cmd.device = Some(1 << 6);
+                }
+            }
+            cmd
+        }
+    }
+
     #[derive(Copy, Clone, Debug)]
     #[non_exhaustive]
     pub enum SpanningCmdType {
@@ -371,17 +586,286 @@ pub mod constructor {
         }
     }
 
+    /// Constructs a [AtaCommand::SET_FEATURES] command.
+    #[derive(Copy, Clone, Debug)]
+    pub struct SetFeaturesCmd {
+        subcommand: crate::command::SetFeaturesSubcommand,
+        count: u8,
+        lba: u32,
+    }
+
+    impl SetFeaturesCmd {
+        /// `count` is the argument to the subcommand, it is ignored by subcommands which do not take one.
+        pub fn new(subcommand: crate::command::SetFeaturesSubcommand, count: u8) -> Self {
+            Self { subcommand, count, lba: 0 }
+        }
+
+        /// Sets the LBA field for subcommands which take an additional argument. Only the low 24
+        /// bits are used.
+        pub fn with_lba(mut self, lba: u32) -> Self {
+            self.lba = lba & 0xff_ffff;
+            self
+        }
+    }
+
+    impl CommandConstructor for SetFeaturesCmd {
+        fn compose(self) -> ComposedCommand {
+            let mut cmd = ComposedCommand::zeroed(AtaCommand::SET_FEATURES.into());
+            cmd.feature = Some(u8::from(self.subcommand) as u16);
+            cmd.count = Some(self.count as u16);
+            cmd.lba = Some(self.lba as u64);
+            cmd
+        }
+    }
+
+    /// Constructs a [AtaCommand::DOWNLOAD_MICROCODE] or [AtaCommand::DOWNLOAD_MICROCODE_DMA] command
+    /// transferring `blocks` 512 byte blocks to the device.
+    #[derive(Copy, Clone, Debug)]
+    pub struct DownloadMicrocodeCmd {
+        subcommand: crate::command::MicrocodeSubcommand,
+        blocks: u16,
+        offset: u16,
+        dma: bool,
+    }
+
+    impl DownloadMicrocodeCmd {
+        /// `offset` is the offset of this segment into the microcode in 512 byte blocks, it is
+        /// ignored by [crate::command::MicrocodeSubcommand::DOWNLOAD_SAVE].
+        ///
+        /// Returns `None` if `blocks` is 0.
+        pub fn new(subcommand: crate::command::MicrocodeSubcommand, blocks: u16, offset: u16, dma: bool) -> Option<Self> {
+            if blocks == 0 {
+                return None;
+            }
+            Some(Self { subcommand, blocks, offset, dma })
+        }
+    }
+
+    impl CommandConstructor for DownloadMicrocodeCmd {
+        fn compose(self) -> ComposedCommand {
+            let c = if self.dma {
+                AtaCommand::DOWNLOAD_MICROCODE_DMA
+            } else {
+                AtaCommand::DOWNLOAD_MICROCODE
+            };
+            let mut cmd = ComposedCommand::zeroed(c.into());
+            cmd.feature = Some(u8::from(self.subcommand) as u16);
+            // The block count is split between count (7:0) and LBA (7:0), the offset is in LBA (23:8)
+            cmd.count = Some(self.blocks & 0xff);
+            cmd.lba = Some((self.blocks >> 8) as u64 | (self.offset as u64) << 8);
+            cmd
+        }
+    }
+
+    /// Constructs a [AtaCommand::READ_STREAM_DMA_EXT] or [AtaCommand::WRITE_STREAM_DMA_EXT]
+    /// command, see [crate::stream].
+    #[derive(Copy, Clone, Debug)]
+    pub struct StreamCmd {
+        cmd: SpanningCmdType,
+        stream: crate::stream::StreamId,
+        lba: u64,
+        count: u32,
+        cctl: u8,
+        continuous: bool,
+        flag: bool,
+    }
+
+    impl StreamCmd {
+        /// Constructs a transfer of `count` sectors at `lba` belonging to `stream`.
+        ///
+        /// `cctl` is the time limit of the command in units of
+        /// [crate::structures::identification::Streaming::perf_granularity] microseconds, 0 uses
+        /// the default given when the stream was configured.
+        pub fn new(
+            cmd_type: SpanningCmdType,
+            stream: crate::stream::StreamId,
+            lba: u64,
+            count: u32,
+            cctl: u8,
+        ) -> Result<Self, AddressError> {
+            if count == 0 {
+                return Err(AddressError::ZeroCount);
+            }
+            if !Addressing::Lba48.fits(lba, count) {
+                return Err(AddressError::OutOfRange);
+            }
+            Ok(Self {
+                cmd: cmd_type,
+                stream,
+                lba,
+                count,
+                cctl,
+                continuous: false,
+                flag: false,
+            })
+        }
+
+        /// When set the device completes the command within the time limit even if it encountered
+        /// errors, transferring as much data as it could. Errors are recorded in the streaming
+        /// error log instead of aborting the command.
+        pub fn continuous(mut self, continuous: bool) -> Self {
+            self.continuous = continuous;
+            self
+        }
+
+        /// For reads this indicates the request is not sequential with the previous request of the
+        /// stream. For writes this requests that the data is flushed to the media before completion.
+        pub fn flag(mut self, flag: bool) -> Self {
+            self.flag = flag;
+            self
+        }
+    }
+
+    impl CommandConstructor for StreamCmd {
+        fn compose(self) -> ComposedCommand {
+            let command = match self.cmd {
+                SpanningCmdType::Read => AtaCommand::READ_STREAM_DMA_EXT,
+                SpanningCmdType::Write => AtaCommand::WRITE_STREAM_DMA_EXT,
+            };
+            let mut cmd = ComposedCommand::zeroed(command.into());
+            let mut feature = (self.cctl as u16) << 8 | self.stream.get() as u16;
+            if self.continuous {
+                feature |= 1 << 6;
+            }
+            if self.flag {
+                feature |= 1 << 5;
+            }
+            cmd.feature = Some(feature);
+            cmd.count = Some((self.count % Addressing::Lba48.max_count()) as u16);
+            cmd.lba = Some(self.lba);
+            cmd.device = Some(1 << 6);
+            cmd
+        }
+    }
+
+    /// Constructs a [AtaCommand::CONFIG_STREAM] command which adds or removes a stream.
+    #[derive(Copy, Clone, Debug)]
+    pub struct ConfigureStreamCmd {
+        stream: crate::stream::StreamId,
+        add: bool,
+        default_cctl: u8,
+        alloc_unit: u16,
+    }
+
+    impl ConfigureStreamCmd {
+        /// Adds `stream`. `default_cctl` is used by stream commands which do not specify a time
+        /// limit and `alloc_unit` is the number of sectors the device should optimize allocation for.
+        pub fn add(stream: crate::stream::StreamId, default_cctl: u8, alloc_unit: u16) -> Self {
+            Self { stream, add: true, default_cctl, alloc_unit }
+        }
+
+        pub fn remove(stream: crate::stream::StreamId) -> Self {
+            Self { stream, add: false, default_cctl: 0, alloc_unit: 0 }
+        }
+    }
+
+    impl CommandConstructor for ConfigureStreamCmd {
+        fn compose(self) -> ComposedCommand {
+            let mut cmd = ComposedCommand::zeroed(AtaCommand::CONFIG_STREAM.into());
+            let mut feature = (self.default_cctl as u16) << 8 | self.stream.get() as u16;
+            if self.add {
+                feature |= 1 << 7;
+            }
+            cmd.feature = Some(feature);
+            cmd.count = Some(self.alloc_unit);
+            cmd
+        }
+    }
+
+    /// Constructs a [AtaCommand::READ_LOG_EXT] or [AtaCommand::READ_LOG_DMA_EXT] command reading
+    /// `pages` 512 byte pages of a log starting at `page`.
+    #[derive(Copy, Clone, Debug)]
+    pub struct ReadLogCmd {
+        log: u8,
+        page: u16,
+        pages: u16,
+        dma: bool,
+    }
+
+    impl ReadLogCmd {
+        /// Returns `None` if `pages` is 0.
+        pub fn new(log: u8, page: u16, pages: u16, dma: bool) -> Option<Self> {
+            if pages == 0 {
+                return None;
+            }
+            Some(Self { log, page, pages, dma })
+        }
+    }
+
+    impl CommandConstructor for ReadLogCmd {
+        fn compose(self) -> ComposedCommand {
+            let c = if self.dma {
+                AtaCommand::READ_LOG_DMA_EXT
+            } else {
+                AtaCommand::READ_LOG_EXT
+            };
+            let mut cmd = ComposedCommand::zeroed(c.into());
+            cmd.count = Some(self.pages);
+            // The page number is split between LBA (15:8) and LBA (47:40)
+            let page_lo = (self.page & 0xff) as u64;
+            let page_hi = (self.page >> 8) as u64;
+            cmd.lba = Some(self.log as u64 | page_lo << 8 | page_hi << 40);
+            cmd
+        }
+    }
+
+    /// Constructs a [AtaCommand::WRITE_LOG_EXT] or [AtaCommand::WRITE_LOG_DMA_EXT] command writing
+    /// `pages` 512 byte pages of a log starting at `page`.
+    #[derive(Copy, Clone, Debug)]
+    pub struct WriteLogCmd {
+        log: u8,
+        page: u16,
+        pages: u16,
+        dma: bool,
+    }
+
+    impl WriteLogCmd {
+        /// Returns `None` if `pages` is 0.
+        pub fn new(log: u8, page: u16, pages: u16, dma: bool) -> Option<Self> {
+            if pages == 0 {
+                return None;
+            }
+            Some(Self { log, page, pages, dma })
+        }
+    }
+
+    impl CommandConstructor for WriteLogCmd {
+        fn compose(self) -> ComposedCommand {
+            let c = if self.dma {
+                AtaCommand::WRITE_LOG_DMA_EXT
+            } else {
+                AtaCommand::WRITE_LOG_EXT
+            };
+            let mut cmd = ComposedCommand::zeroed(c.into());
+            cmd.count = Some(self.pages);
+            // The page number is split between LBA (15:8) and LBA (47:40)
+            let page_lo = (self.page & 0xff) as u64;
+            let page_hi = (self.page >> 8) as u64;
+            cmd.lba = Some(self.log as u64 | page_lo << 8 | page_hi << 40);
+            cmd
+        }
+    }
+
     /// This enum contains command variants for commands that do not contain any arguments.
     #[derive(Copy, Clone, Debug)]
     #[non_exhaustive]
     pub enum NoArgCmd {
         IdentifyDevice,
+        /// [AtaCommand::NOP] using subcommand `0`. The device always aborts this command, if it
+        /// has queued commands outstanding they are aborted too.
+        Nop,
+        /// [AtaCommand::FLUSH_CACHE], prefer [Self::FlushCacheExt] on devices supporting 48 bit commands.
+        FlushCache,
+        FlushCacheExt,
     }
 
     impl Into<MaybeOpaqueCommand> for NoArgCmd {
         fn into(self) -> MaybeOpaqueCommand {
             match self {
                 NoArgCmd::IdentifyDevice => AtaCommand::IDENTIFY_DEVICE.into(),
+                NoArgCmd::Nop => AtaCommand::NOP.into(),
+                NoArgCmd::FlushCache => AtaCommand::FLUSH_CACHE.into(),
+                NoArgCmd::FlushCacheExt => AtaCommand::FLUSH_CACHE_EXT.into(),
             }
         }
     }
@@ -0,0 +1,246 @@
+//! Changes to device configuration made using [crate::command::AtaCommand::SET_FEATURES].
+//!
+//! A [DeviceConfig] describes the desired state of a number of device features. Features which are
+//! not set in the config are left unchanged. The driver should check the config against the
+//! current [DeviceIdentity] using [DeviceConfig::check_supported], issue each command returned by
+//! [DeviceConfig::commands] and then re-read the identity and call [DeviceConfig::verify] to
+//! ensure the device actually applied the changes.
+//!
+//! Most of these settings are volatile and are reset to their defaults after a power cycle or
+//! (depending on [crate::structures::identification::SataFeaturesEnabled::SETTINGS_PRESERVATION])
+//! a reset.
+
+use crate::command::constructor::{CommandConstructor, ComposedCommand, SetFeaturesCmd};
+use crate::command::SetFeaturesSubcommand;
+use crate::structures::identification::{DeviceIdentity, Features119, Features82, Features83, WriteReadVerify};
+
+/// Builder for a set of device feature changes.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeviceConfig {
+    write_cache: Option<bool>,
+    look_ahead: Option<bool>,
+    apm: Option<ApmSetting>,
+    free_fall: Option<FreeFallSetting>,
+    wrv: Option<WrvSetting>,
+}
+
+/// Advanced power management configuration.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ApmSetting {
+    Disabled,
+    /// Enables APM at the given level. Lower levels save more power, levels below `0x80` permit the
+    /// device to spin down. `0x00` and `0xFF` are reserved and are rejected by [DeviceConfig::apm].
+    Level(u8),
+}
+
+/// Free-fall control configuration.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FreeFallSetting {
+    Disabled,
+    /// Enables free-fall control with the given sensitivity, `0` selects the vendor's
+    /// recommended sensitivity.
+    Enabled(u8),
+}
+
+/// Write-Read-Verify configuration. While enabled the device reads back and verifies data after
+/// it is written, which reduces write performance.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WrvSetting {
+    Disabled,
+    /// Enables Write-Read-Verify in the given mode. [WriteReadVerify::CheckFromCounter] must be
+    /// set using [Self::Counter] instead.
+    Mode(WriteReadVerify),
+    /// Enables Write-Read-Verify for the first `n * 1024` logical sectors written after spin-up.
+    Counter(u8),
+}
+
+/// A feature which can be changed by [DeviceConfig].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Feature {
+    VolatileWriteCache,
+    ReadLookAhead,
+    Apm,
+    FreeFallControl,
+    WriteReadVerify,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ConfigError {
+    /// The device does not support the feature.
+    Unsupported(Feature),
+    /// The device identity does not reflect the requested state of the feature after the
+    /// commands were issued.
+    NotApplied(Feature),
+}
+
+impl DeviceConfig {
+    /// Returns a config which does not change anything.
+    pub const fn new() -> Self {
+        Self {
+            write_cache: None,
+            look_ahead: None,
+            apm: None,
+            free_fall: None,
+            wrv: None,
+        }
+    }
+
+    /// Enables or disables the volatile write cache.
+    ///
+    /// When the write cache is enabled data may be lost on power failure unless the cache is flushed.
+    pub fn write_cache(mut self, enable: bool) -> Self {
+        self.write_cache = Some(enable);
+        self
+    }
+
+    /// Enables or disables read look-ahead.
+    pub fn look_ahead(mut self, enable: bool) -> Self {
+        self.look_ahead = Some(enable);
+        self
+    }
+
+    /// Sets the advanced power management level.
+    ///
+    /// Returns `None` if the level is reserved.
+    pub fn apm(mut self, setting: ApmSetting) -> Option<Self> {
+        if let ApmSetting::Level(0 | 0xff) = setting {
+            return None;
+        }
+        self.apm = Some(setting);
+        Some(self)
+    }
+
+    /// Sets free-fall control.
+    pub fn free_fall(mut self, setting: FreeFallSetting) -> Self {
+        self.free_fall = Some(setting);
+        self
+    }
+
+    /// Sets Write-Read-Verify.
+    ///
+    /// Returns `None` if [WriteReadVerify::CheckFromCounter] is given as a [WrvSetting::Mode].
+    pub fn write_read_verify(mut self, setting: WrvSetting) -> Option<Self> {
+        if let WrvSetting::Mode(WriteReadVerify::CheckFromCounter) = setting {
+            return None;
+        }
+        self.wrv = Some(setting);
+        Some(self)
+    }
+
+    /// Returns whether this config changes nothing.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::new()
+    }
+
+    /// Checks that the device supports every feature modified by this config.
+    pub fn check_supported(&self, id: &DeviceIdentity) -> Result<(), ConfigError> {
+        let f82 = &id.features.features_82;
+        if self.write_cache.is_some() && !f82.contains(Features82::VOLATILE_WRITE_CACHE) {
+            return Err(ConfigError::Unsupported(Feature::VolatileWriteCache));
+        }
+        if self.look_ahead.is_some() && !f82.contains(Features82::LOOK_AHEAD) {
+            return Err(ConfigError::Unsupported(Feature::ReadLookAhead));
+        }
+        if self.apm.is_some() && !id.features.features_83.contains(Features83::APM) {
+            return Err(ConfigError::Unsupported(Feature::Apm));
+        }
+        if self.free_fall.is_some() && !id.features119.contains(Features119::FREE_FALL_CTL) {
+            return Err(ConfigError::Unsupported(Feature::FreeFallControl));
+        }
+        if self.wrv.is_some() && id.write_read_verify().is_none() {
+            return Err(ConfigError::Unsupported(Feature::WriteReadVerify));
+        }
+        Ok(())
+    }
+
+    /// Returns the commands which must be issued to apply this config.
+    pub fn commands(&self) -> impl Iterator<Item = ComposedCommand> {
+        use SetFeaturesSubcommand::*;
+
+        let write_cache = self.write_cache.map(|e| {
+            let sub = if e { ENABLE_VOLATILE_WRITE_CACHE } else { DISABLE_VOLATILE_WRITE_CACHE };
+            SetFeaturesCmd::new(sub, 0)
+        });
+        let look_ahead = self.look_ahead.map(|e| {
+            let sub = if e { ENABLE_READ_LOOK_AHEAD } else { DISABLE_READ_LOOK_AHEAD };
+            SetFeaturesCmd::new(sub, 0)
+        });
+        let apm = self.apm.map(|s| match s {
+            ApmSetting::Disabled => SetFeaturesCmd::new(DISABLE_APM, 0),
+            ApmSetting::Level(l) => SetFeaturesCmd::new(ENABLE_APM, l),
+        });
+        let free_fall = self.free_fall.map(|s| match s {
+            FreeFallSetting::Disabled => SetFeaturesCmd::new(DISABLE_FREE_FALL_CTL, 0),
+            FreeFallSetting::Enabled(s) => SetFeaturesCmd::new(ENABLE_FREE_FALL_CTL, s),
+        });
+        let wrv = self.wrv.map(|s| match s {
+            WrvSetting::Disabled => SetFeaturesCmd::new(DISABLE_WRITE_READ_VERIFY, 0),
+            WrvSetting::Mode(m) => SetFeaturesCmd::new(ENABLE_WRITE_READ_VERIFY, m.mode_number()),
+            WrvSetting::Counter(n) => {
+                let mode = WriteReadVerify::CheckFromCounter.mode_number();
+                SetFeaturesCmd::new(ENABLE_WRITE_READ_VERIFY, mode).with_lba(n as u32)
+            }
+        });
+
+        [write_cache, look_ahead, apm, free_fall, wrv]
+            .into_iter()
+            .flatten()
+            .map(|c| c.compose())
+    }
+
+    /// Checks that the identity read after the commands were issued reflects this config.
+    pub fn verify(&self, id: &DeviceIdentity) -> Result<(), ConfigError> {
+        // Words 85..=87 contain the enabled state of the features in words 82..=84
+        let enabled = &id.features_copy;
+        if let Some(e) = self.write_cache {
+            if enabled.features_82.contains(Features82::VOLATILE_WRITE_CACHE) != e {
+                return Err(ConfigError::NotApplied(Feature::VolatileWriteCache));
+            }
+        }
+        if let Some(e) = self.look_ahead {
+            if enabled.features_82.contains(Features82::LOOK_AHEAD) != e {
+                return Err(ConfigError::NotApplied(Feature::ReadLookAhead));
+            }
+        }
+        if let Some(s) = self.apm {
+            let ok = match s {
+                ApmSetting::Disabled => !enabled.features_83.contains(Features83::APM),
+                ApmSetting::Level(l) => {
+                    enabled.features_83.contains(Features83::APM) && id.apm_level.level() == l
+                }
+            };
+            if !ok {
+                return Err(ConfigError::NotApplied(Feature::Apm));
+            }
+        }
+        if let Some(s) = self.free_fall {
+            let en = id.features119_copy.contains(Features119::FREE_FALL_CTL);
+            let ok = match s {
+                FreeFallSetting::Disabled => !en,
+                // When 0 is given the device reports the vendor recommended value which is unknown
+                FreeFallSetting::Enabled(0) => en,
+                FreeFallSetting::Enabled(n) => en && id.free_fall_sensitivity() == n,
+            };
+            if !ok {
+                return Err(ConfigError::NotApplied(Feature::FreeFallControl));
+            }
+        }
+        if let Some(s) = self.wrv {
+            // check_supported ensures this is Some
+            let status = id.write_read_verify().ok_or(ConfigError::Unsupported(Feature::WriteReadVerify))?;
+            let ok = match s {
+                WrvSetting::Disabled => !status.enabled,
+                WrvSetting::Mode(m) => status.enabled && status.mode == Some(m),
+                WrvSetting::Counter(n) => {
+                    status.enabled
+                        && status.mode == Some(WriteReadVerify::CheckFromCounter)
+                        && status.mode_3_sectors == n as u32 * 1024
+                }
+            };
+            if !ok {
+                return Err(ConfigError::NotApplied(Feature::WriteReadVerify));
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,8 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 pub mod command;
+pub mod config;
+pub mod microcode;
+pub mod sct;
+pub mod stream;
 pub mod structures;
@@ -0,0 +1,127 @@
+//! Planning for device firmware updates using [crate::command::AtaCommand::DOWNLOAD_MICROCODE].
+//!
+//! A [MicrocodeDownload] validates a microcode image against the [DeviceIdentity] and splits it
+//! into the commands required to transfer it. When the device supports
+//! [Features119::DOWNLOAD_MICRO_MODE_3] the image is transferred in segments using
+//! [MicrocodeSubcommand::DOWNLOAD_OFFSETS_SAVE], each segment is within the limits returned by
+//! [DeviceIdentity::microcode_block_limits]. Otherwise the image is transferred in a single
+//! command using [MicrocodeSubcommand::DOWNLOAD_SAVE].
+//!
+//! The device activates the new microcode once the final command completes. The driver should
+//! re-read the device identity afterwards because it may have changed.
+
+use crate::command::constructor::{CommandConstructor, ComposedCommand, DownloadMicrocodeCmd};
+use crate::command::MicrocodeSubcommand;
+use crate::structures::identification::{AdditonalSupport, DeviceIdentity, Features119, Features83};
+use core::ops::Range;
+
+/// Size of the blocks counted by DOWNLOAD MICROCODE.
+pub const MICROCODE_BLOCK_SIZE: usize = 512;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MicrocodeError {
+    /// The device does not support DOWNLOAD MICROCODE.
+    Unsupported,
+    /// The image is not a multiple of [MICROCODE_BLOCK_SIZE].
+    Misaligned,
+    /// The image is smaller than the minimum transfer size reported by the device.
+    TooSmall,
+    /// The image is larger than can be transferred.
+    TooLarge,
+}
+
+/// The commands required to download a microcode image, see the module level documentation.
+///
+/// Iterating over this yields each command along with the range of the image which must be
+/// given as its data buffer.
+#[derive(Clone, Debug)]
+pub struct MicrocodeDownload {
+    subcommand: MicrocodeSubcommand,
+    dma: bool,
+    total: u32,
+    segments: u32,
+    next: u32,
+    offset: u32,
+}
+
+impl MicrocodeDownload {
+    /// Plans the download of an image of `len` bytes.
+    pub fn new(id: &DeviceIdentity, len: usize) -> Result<Self, MicrocodeError> {
+        if !id.features.features_83.contains(Features83::DOWNLOAD_MICROCODE) {
+            return Err(MicrocodeError::Unsupported);
+        }
+        if len == 0 || !len.is_multiple_of(MICROCODE_BLOCK_SIZE) {
+            return Err(MicrocodeError::Misaligned);
+        }
+        // The offset field is 16 bits so no image larger than this can be addressed
+        let total: u32 = (len / MICROCODE_BLOCK_SIZE).try_into().map_err(|_| MicrocodeError::TooLarge)?;
+        if total > u16::MAX as u32 {
+            return Err(MicrocodeError::TooLarge);
+        }
+
+        let dma = id
+            .get_transfer_cfg()
+            .is_some_and(|t| t.additional_supported.contains(AdditonalSupport::DOWNLOAD_MICRO_DMA));
+        let limits = id.microcode_block_limits();
+
+        let (subcommand, segments) = if id.features119.contains(Features119::DOWNLOAD_MICRO_MODE_3) {
+            let (min, max) = limits.unwrap_or((1, u16::MAX));
+            // Segments are sized evenly so that the last one is not smaller than the minimum.
+            let segments = total.div_ceil(max as u32);
+            if total / segments < min as u32 {
+                return Err(MicrocodeError::TooSmall);
+            }
+            (MicrocodeSubcommand::DOWNLOAD_OFFSETS_SAVE, segments)
+        } else {
+            if let Some((min, _)) = limits {
+                if total < min as u32 {
+                    return Err(MicrocodeError::TooSmall);
+                }
+            }
+            (MicrocodeSubcommand::DOWNLOAD_SAVE, 1)
+        };
+
+        Ok(Self {
+            subcommand,
+            dma,
+            total,
+            segments,
+            next: 0,
+            offset: 0,
+        })
+    }
+
+    /// Returns the subcommand used for the download.
+    pub fn subcommand(&self) -> MicrocodeSubcommand {
+        self.subcommand
+    }
+
+    /// Returns the number of commands required to transfer the image.
+    pub fn segments(&self) -> u32 {
+        self.segments
+    }
+}
+
+impl Iterator for MicrocodeDownload {
+    type Item = (Range<usize>, ComposedCommand);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.segments {
+            return None;
+        }
+        // The first `total % segments` segments contain one extra block.
+        let mut blocks = self.total / self.segments;
+        if self.next < self.total % self.segments {
+            blocks += 1;
+        }
+
+        // Neither of these can overflow, total fits in a u16
+        let cmd = DownloadMicrocodeCmd::new(self.subcommand, blocks as u16, self.offset as u16, self.dma)?;
+        let start = self.offset as usize * MICROCODE_BLOCK_SIZE;
+        let range = start..start + blocks as usize * MICROCODE_BLOCK_SIZE;
+
+        self.offset += blocks;
+        self.next += 1;
+        Some((range, cmd.compose()))
+    }
+}
@@ -0,0 +1,166 @@
+//! SMART Command Transport.
+//!
+//! SCT commands are issued by writing a key sector to the [SCT_COMMAND_LOG] using
+//! [crate::command::constructor::WriteLogCmd]. Reading the same log returns the [SctStatus] which
+//! reports the progress and result of the last command. Support for SCT and each of its commands
+//! is indicated by [DeviceIdentity::sct_command_trans].
+//!
+//! Only the Error Recovery Control and Write Same commands are implemented.
+
+use crate::command::constructor::{CommandConstructor, ComposedCommand, WriteLogCmd};
+use crate::structures::identification::{DeviceIdentity, Features84, SCTCommandTransport};
+
+/// Log address used to issue SCT commands and read their status.
+pub const SCT_COMMAND_LOG: u8 = 0xe0;
+/// Log address used to transfer data for SCT commands.
+pub const SCT_DATA_LOG: u8 = 0xe1;
+
+/// Size of an SCT key sector and of the SCT status in bytes.
+pub const KEY_SIZE: usize = 512;
+
+/// Extended status code reported while a command is still executing.
+const STATUS_IN_PROGRESS: u16 = 0xffff;
+
+const ACTION_WRITE_SAME: u16 = 0x0002;
+const ACTION_ERROR_RECOVERY: u16 = 0x0003;
+
+/// An SCT command transport feature.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SctFeature {
+    ErrorRecovery,
+    WriteSame,
+}
+
+impl SctFeature {
+    /// Returns whether the device supports the feature.
+    pub fn is_supported(&self, id: &DeviceIdentity) -> bool {
+        let sct = &id.sct_command_trans;
+        // Keys are written using WRITE LOG EXT
+        if !sct.contains(SCTCommandTransport::SCT_COMMAND_TRANSPORT)
+            || !id.features.features_84.contains(Features84::GPL_FEATURES)
+        {
+            return false;
+        }
+        match self {
+            SctFeature::ErrorRecovery => sct.contains(SCTCommandTransport::SCT_ERR_RECOVERY),
+            SctFeature::WriteSame => sct.contains(SCTCommandTransport::SCT_WRITE_SAME),
+        }
+    }
+}
+
+/// The timer set by an Error Recovery Control command.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RecoveryTimer {
+    Read = 1,
+    Write = 2,
+}
+
+/// The key sector for an SCT command.
+#[derive(Clone)]
+#[repr(C, align(2))]
+pub struct SctKey([u8; KEY_SIZE]);
+
+impl SctKey {
+    fn new(action: u16, function: u16) -> Self {
+        let mut key = Self([0; KEY_SIZE]);
+        key.set_word(0, action);
+        key.set_word(1, function);
+        key
+    }
+
+    fn set_word(&mut self, word: usize, value: u16) {
+        self.0[word * 2..word * 2 + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_qword(&mut self, word: usize, value: u64) {
+        self.0[word * 2..word * 2 + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Sets the time limit for `timer` in units of 100ms, `0` disables the limit.
+    ///
+    /// While a limit is set the device abandons a read or write which it cannot complete within
+    /// the limit and returns an error instead of retrying indefinitely. This allows redundant
+    /// storage to recover the data from elsewhere rather than stalling. The limit is volatile.
+    pub fn set_error_recovery(timer: RecoveryTimer, limit: u16) -> Self {
+        let mut key = Self::new(ACTION_ERROR_RECOVERY, 1);
+        key.set_word(2, timer as u16);
+        key.set_word(3, limit);
+        key
+    }
+
+    /// Writes `pattern` repeatedly to `count` logical sectors starting at `lba`. A `count` of `0`
+    /// writes until the end of the device.
+    ///
+    /// The command runs in the background, the command which issues the key completes
+    /// immediately and [SctStatus::in_progress] must be polled to determine when it finishes.
+    pub fn write_same(lba: u64, count: u64, pattern: u32) -> Self {
+        let mut key = Self::new(ACTION_WRITE_SAME, 1);
+        key.set_qword(2, lba);
+        key.set_qword(6, count);
+        key.set_word(10, pattern as u16);
+        key.set_word(11, (pattern >> 16) as u16);
+        key
+    }
+
+    /// Returns the key sector which must be given as the data for [Self::command].
+    pub fn as_bytes(&self) -> &[u8; KEY_SIZE] {
+        &self.0
+    }
+
+    /// Returns the command which issues the key to the device.
+    pub fn command(&self, dma: bool) -> ComposedCommand {
+        // Never returns None, the page count is 1
+        WriteLogCmd::new(SCT_COMMAND_LOG, 0, 1, dma)
+            .unwrap()
+            .compose()
+    }
+}
+
+impl core::fmt::Debug for SctKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SctKey")
+            .field("action", &u16::from_le_bytes([self.0[0], self.0[1]]))
+            .field("function", &u16::from_le_bytes([self.0[2], self.0[3]]))
+            .finish()
+    }
+}
+
+/// The SCT status returned by reading the [SCT_COMMAND_LOG].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SctStatus {
+    pub format_version: u16,
+    /// The device state, `5` indicates that an SCT command is executing in the background.
+    pub device_state: u8,
+    /// Result of the last SCT command, `0` indicates success.
+    pub extended_status: u16,
+    /// Action code of the last SCT command.
+    pub action: u16,
+    /// Function code of the last SCT command.
+    pub function: u16,
+    /// Current temperature in degrees celsius.
+    pub temperature: Option<i8>,
+}
+
+impl SctStatus {
+    pub fn from_raw(raw: &[u8; KEY_SIZE]) -> Self {
+        let word = |off: usize| u16::from_le_bytes([raw[off], raw[off + 1]]);
+        // 0x80 indicates that the temperature is not reported
+        let temperature = match raw[200] as i8 {
+            i8::MIN => None,
+            t => Some(t),
+        };
+        Self {
+            format_version: word(0),
+            device_state: raw[10],
+            extended_status: word(14),
+            action: word(16),
+            function: word(18),
+            temperature,
+        }
+    }
+
+    /// Returns whether the last SCT command is still executing.
+    pub fn in_progress(&self) -> bool {
+        self.extended_status == STATUS_IN_PROGRESS
+    }
+}
@@ -0,0 +1,66 @@
+//! Support for the streaming feature set.
+//!
+//! Streaming commands trade error recovery for bounded latency. Each command belongs to one of
+//! eight streams which are set up using [crate::command::AtaCommand::CONFIG_STREAM], and may
+//! specify a time limit (CCTL) which the device must complete the command within. When a command is
+//! issued with [crate::command::constructor::StreamCmd::continuous] the device completes it within
+//! the time limit even if errors occur, the data may contain errors which are recorded in the
+//! streaming error log.
+//!
+//! Devices which support streaming set [crate::structures::identification::Features84::STREAMING].
+//! Requests should be aligned using [crate::structures::identification::Streaming::align_request].
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// Identifies a stream, this is in the range `0..8`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct StreamId(u8);
+
+impl StreamId {
+    pub const COUNT: u8 = 8;
+
+    /// Returns `None` if `id` is not a valid stream ID.
+    pub const fn new(id: u8) -> Option<Self> {
+        if id < Self::COUNT {
+            Some(Self(id))
+        } else {
+            None
+        }
+    }
+
+    pub const fn get(&self) -> u8 {
+        self.0
+    }
+}
+
+/// Tracks which stream IDs of a device are in use.
+#[derive(Debug, Default)]
+pub struct StreamIds {
+    used: AtomicU8,
+}
+
+impl StreamIds {
+    pub const fn new() -> Self {
+        Self {
+            used: AtomicU8::new(0),
+        }
+    }
+
+    /// Allocates a free stream ID. Returns `None` if all streams are in use.
+    pub fn alloc(&self) -> Option<StreamId> {
+        let mut id = None;
+        self.used
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |u| {
+                let free = (!u).trailing_zeros() as u8;
+                id = StreamId::new(free);
+                id.map(|i| u | 1 << i.get())
+            })
+            .ok()?;
+        id
+    }
+
+    /// Releases `id`, it must have been returned by [Self::alloc].
+    pub fn free(&self, id: StreamId) {
+        self.used.fetch_and(!(1 << id.get()), Ordering::Release);
+    }
+}
@@ -1 +1,3 @@
+pub mod device_stats;
 pub mod identification;
+pub mod string;
@@ -0,0 +1,148 @@
+//! Decoding for the Device Statistics log.
+//!
+//! The log is read using [crate::command::constructor::ReadLogCmd] with the log address
+//! [DEVICE_STATISTICS_LOG]. Page 0 lists the pages supported by the device, the remaining pages
+//! each contain a header followed by a number of 8 byte statistics. [DeviceStatistics::apply_page]
+//! decodes the statistics which are commonly used to monitor drive health.
+
+/// Log address of the Device Statistics log.
+pub const DEVICE_STATISTICS_LOG: u8 = 0x04;
+
+pub const PAGE_SIZE: usize = 512;
+
+/// Page numbers within the Device Statistics log.
+pub mod page {
+    /// List of supported pages.
+    pub const SUPPORTED: u8 = 0x00;
+    pub const GENERAL: u8 = 0x01;
+    pub const TEMPERATURE: u8 = 0x05;
+    pub const SOLID_STATE: u8 = 0x07;
+}
+
+/// Returns the page numbers listed in the supported pages page.
+pub fn supported_pages(page: &[u8; PAGE_SIZE]) -> impl Iterator<Item = u8> + '_ {
+    // byte 8 contains the number of entries, which follow it
+    let count = page[8] as usize;
+    page[9..9 + count.min(PAGE_SIZE - 9)].iter().copied()
+}
+
+/// A single entry from a statistics page.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Statistic(u64);
+
+impl Statistic {
+    const SUPPORTED: u64 = 1 << 63;
+    const VALID: u64 = 1 << 62;
+    const NORMALIZED: u64 = 1 << 60;
+
+    /// Returns the value of the statistic if it is supported and valid.
+    ///
+    /// The width of the value depends on the statistic, up to 56 bits are returned.
+    pub fn value(&self) -> Option<u64> {
+        let flags = Self::SUPPORTED | Self::VALID;
+        (self.0 & flags == flags).then_some(self.0 & 0xff_ffff_ffff_ffff)
+    }
+
+    /// Indicates the value is normalized, i.e. a percentage rather than a raw count.
+    pub fn is_normalized(&self) -> bool {
+        self.0 & Self::NORMALIZED != 0
+    }
+}
+
+/// A page of the Device Statistics log.
+pub struct StatisticsPage<'a> {
+    data: &'a [u8; PAGE_SIZE],
+}
+
+impl<'a> StatisticsPage<'a> {
+    /// Returns `None` if the page header is not valid.
+    pub fn new(data: &'a [u8; PAGE_SIZE]) -> Option<Self> {
+        // The revision number in bytes 0..2 must be 1
+        if u16::from_le_bytes([data[0], data[1]]) != 1 {
+            return None;
+        }
+        Some(Self { data })
+    }
+
+    /// Returns the page number from the header.
+    pub fn number(&self) -> u8 {
+        self.data[2]
+    }
+
+    /// Returns the statistic at the byte offset `offset`. `offset` must be a multiple of 8.
+    pub fn get(&self, offset: usize) -> Option<Statistic> {
+        if !offset.is_multiple_of(8) || offset == 0 || offset + 8 > PAGE_SIZE {
+            return None;
+        }
+        let raw = u64::from_le_bytes(self.data[offset..offset + 8].try_into().unwrap());
+        Some(Statistic(raw))
+    }
+
+    fn value(&self, offset: usize) -> Option<u64> {
+        self.get(offset)?.value()
+    }
+
+    /// Temperatures are stored as signed bytes in degrees Celsius.
+    fn temperature(&self, offset: usize) -> Option<i8> {
+        Some(self.value(offset)? as u8 as i8)
+    }
+}
+
+/// Health related statistics from the Device Statistics log.
+///
+/// Fields are `None` when the device does not report them.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeviceStatistics {
+    pub power_on_resets: Option<u64>,
+    pub power_on_hours: Option<u64>,
+    pub logical_sectors_written: Option<u64>,
+    pub write_commands: Option<u64>,
+    pub logical_sectors_read: Option<u64>,
+    pub read_commands: Option<u64>,
+    /// Current temperature in degrees Celsius.
+    pub temperature: Option<i8>,
+    /// Highest temperature over the lifetime of the device.
+    pub highest_temperature: Option<i8>,
+    /// Lowest temperature over the lifetime of the device.
+    pub lowest_temperature: Option<i8>,
+    /// The maximum temperature the device is specified to operate at.
+    pub max_operating_temperature: Option<i8>,
+    /// Minutes spent above the maximum operating temperature.
+    pub time_over_temperature: Option<u64>,
+    /// Estimate of the percentage of the device's endurance which has been used, this may exceed 100.
+    pub endurance_used: Option<u8>,
+}
+
+impl DeviceStatistics {
+    /// Decodes the statistics in `page` and stores them in `self`. Returns `false` if the page
+    /// header is invalid.
+    ///
+    /// Pages which are not decoded are ignored.
+    pub fn apply_page(&mut self, page: &[u8; PAGE_SIZE]) -> bool {
+        let Some(p) = StatisticsPage::new(page) else {
+            return false;
+        };
+        match p.number() {
+            page::GENERAL => {
+                self.power_on_resets = p.value(0x08).map(|v| v & 0xffff_ffff);
+                self.power_on_hours = p.value(0x10).map(|v| v & 0xffff_ffff);
+                self.logical_sectors_written = p.value(0x18).map(|v| v & 0xffff_ffff_ffff);
+                self.write_commands = p.value(0x20).map(|v| v & 0xffff_ffff_ffff);
+                self.logical_sectors_read = p.value(0x28).map(|v| v & 0xffff_ffff_ffff);
+                self.read_commands = p.value(0x30).map(|v| v & 0xffff_ffff_ffff);
+            }
+            page::TEMPERATURE => {
+                self.temperature = p.temperature(0x08);
+                self.highest_temperature = p.temperature(0x20);
+                self.lowest_temperature = p.temperature(0x28);
+                self.time_over_temperature = p.value(0x50).map(|v| v & 0xffff_ffff);
+                self.max_operating_temperature = p.temperature(0x58);
+            }
+            page::SOLID_STATE => {
+                self.endurance_used = p.value(0x08).map(|v| v as u8);
+            }
+            _ => {}
+        }
+        true
+    }
+}
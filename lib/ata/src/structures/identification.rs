@@ -1,3 +1,5 @@
+use super::string::{AtaString, AtaStringError};
+use crate::command::{AtaCommand, SanitiseSubcommand};
 use core::fmt::{Debug, Formatter};
 
 const _ASSERT: () = {
@@ -5,6 +7,17 @@ const _ASSERT: () = {
     assert!(core::mem::size_of::<TransferConfig>() == 14);
 };
 
+/// A field of the [DeviceIdentity] which indicates whether commands of type `C` are supported.
+///
+/// New checks are added by implementing this for the field and adding the field to
+/// [DeviceIdentity::ata_support_bits] or the [crate::command::CheckableCommand] implementation
+/// for `C`.
+pub trait SupportBits<C> {
+    /// Returns `Some(b)` where `b` indicates whether `cmd` is supported, or `None` if this field
+    /// does not indicate support for `cmd`.
+    fn supports(&self, cmd: C) -> Option<bool>;
+}
+
 /// This struct is returned by [crate::command::AtaCommand::IDENTIFY_DEVICE]. It represents the
 /// current device configuration most contained values are static but some may be changed.
 /// This struct cannot be used configure the device.
@@ -121,20 +134,22 @@ pub struct FeaturesSet {
     pub features_84: Features84,
 }
 
-const DECODE_FAILED: &str = "Failed to decode ata string";
 
 impl DeviceIdentity {
     /// Returns true on a good checksum, otherwise returns false.
     /// This should be called before any other data is read from this struct.
     ///
     /// If this fn returns false this may indicate a hardware failure on the HBA cable or device.
+    ///
+    /// The checksum is optional, when the device does not implement it this returns true.
     pub fn checksum(&self) -> bool {
-        if !self.checksum.validity == 0xa5 {
-            return false;
+        if self.checksum.validity != 0xa5 {
+            return true;
         }
 
+        // The checksum byte is chosen so that the sum of every byte is 0
         let mut sum = 0u8;
-        let arr = unsafe { &*(self as *const _ as *const [u8; 511]) };
+        let arr = unsafe { &*(self as *const _ as *const [u8; 512]) };
         for i in arr {
             sum = sum.wrapping_add(*i)
         }
@@ -158,16 +173,58 @@ impl DeviceIdentity {
         }
     }
 
-    pub fn get_serial(&self) -> &str {
-        core::str::from_utf8(&self.serial).unwrap_or(DECODE_FAILED)
+    /// Returns the serial number of the device.
+    pub fn get_serial(&self) -> Result<AtaString<20>, AtaStringError> {
+        AtaString::decode(&self.serial)
+    }
+
+    pub fn firmware_revision(&self) -> Result<AtaString<8>, AtaStringError> {
+        AtaString::decode(&self.firmware_vers)
+    }
+
+    pub fn model_num(&self) -> Result<AtaString<40>, AtaStringError> {
+        AtaString::decode(&self.model_num)
+    }
+
+    /// Returns the additional product identifier, this is empty if it is not implemented.
+    pub fn additional_product_id(&self) -> Result<AtaString<8>, AtaStringError> {
+        AtaString::decode(&self.additional_product_id)
+    }
+
+    /// Returns the serial number of the current media. This is empty when the device does not
+    /// have removable media or the media does not have a serial number.
+    pub fn media_serial(&self) -> Result<AtaString<40>, AtaStringError> {
+        AtaString::decode(&self.current_media_serial)
+    }
+
+    /// Returns the manufacturer of the current media, see [Self::media_serial].
+    pub fn media_manufacturer(&self) -> Result<AtaString<20>, AtaStringError> {
+        AtaString::decode(&self.current_media_manufacturer)
     }
 
-    pub fn firmware_revision(&self) -> &str {
-        core::str::from_utf8(&self.firmware_vers).unwrap_or(DECODE_FAILED)
+    /// Returns the minimum and maximum number of 512 byte blocks which may be transferred by a
+    /// single DOWNLOAD MICROCODE command in segmented mode. Returns `None` if the device does not
+    /// report the limits.
+    pub fn microcode_block_limits(&self) -> Option<(u16, u16)> {
+        let valid = |n: u16| n != 0 && n != u16::MAX;
+        if valid(self.micro_blocks_min) && valid(self.micro_blocks_max) && self.micro_blocks_min <= self.micro_blocks_max {
+            Some((self.micro_blocks_min, self.micro_blocks_max))
+        } else {
+            None
+        }
     }
 
-    pub fn model_num(&self) -> &str {
-        core::str::from_utf8(&self.model_num).unwrap_or(DECODE_FAILED)
+    /// Returns the Write-Read-Verify configuration, or `None` if the feature is not supported.
+    pub fn write_read_verify(&self) -> Option<WrvStatus> {
+        if !self.features119.contains(Features119::READ_WRITE_VERIFY) {
+            return None;
+        }
+        Some(WrvStatus {
+            enabled: self.features119_copy.contains(Features119::READ_WRITE_VERIFY),
+            mode: self.wrv_mode.get_mode(),
+            mode_2_sectors: self.wrv_mode_2_count,
+            mode_3_sectors: self.wrv_mode_3_count,
+        })
     }
 
     pub fn free_fall_sensitivity(&self) -> u8 {
@@ -210,44 +267,21 @@ impl DeviceIdentity {
     /// This function returns an Option<bool>. When this fn returns `Some(b)` the support of the
     /// command is indicated by `b`. If this fn returns `None` the command has no check implemented for it.
     ///
-    /// Ths function can be used to check all command sets defined in [crate::command]   
-    // todo support checking features
-    pub fn is_supported<C: crate::command::CheckableCommand + Copy + 'static>(
-        // would not build without static idk why this is not a ref
-        &self,
-        cmd: C,
-    ) -> Option<bool> {
-        use core::any::Any;
-        // checks against concrete type and casts to it these checks are optimized out
-
-        // this function is and probably always will be a fucking mess
-        if cmd.type_id() == crate::command::AtaCommand::READ_LOG_DMA_EXT.type_id() {
-            let cmd = unsafe { *(&cmd as *const _ as *const crate::command::AtaCommand) };
-            return self.chk_ata_cmd(cmd);
-        } else if cmd.type_id() == crate::command::SanitiseSubcommand::OVERWRITE_EXT.type_id() {
-            let cmd = unsafe { *(&cmd as *const _ as *const crate::command::SanitiseSubcommand) };
-            return Some(self.sanitize_sub_cmd.is_supported(cmd));
-        }
+    /// Ths function can be used to check all command sets defined in [crate::command]
+    pub fn is_supported<C: crate::command::CheckableCommand>(&self, cmd: C) -> Option<bool> {
+        cmd.check_support(self)
+    }
 
-        None
-    }
-
-    /// Internal component for [Self::is_supported] for checking [crate::command::AtaCommand]
-    fn chk_ata_cmd(&self, cmd: crate::command::AtaCommand) -> Option<bool> {
-        if let Some(n) = self.features.features_82.is_supported(cmd) {
-            Some(n)
-        } else if let Some(n) = self.features.features_83.is_supported(cmd) {
-            Some(n)
-        } else if let Some(n) = self.features119.is_supported(cmd) {
-            Some(n)
-        } else if cmd == crate::command::AtaCommand::SANITIZE_DEVICE {
-            Some(
-                self.sanitize_sub_cmd
-                    .is_supported(crate::command::SanitiseSubcommand::SANITIZE_STATUS_EXT),
-            )
-        } else {
-            None
-        }
+    /// Returns each field which indicates support for [AtaCommand]s.
+    pub(crate) fn ata_support_bits(&self) -> [&dyn SupportBits<AtaCommand>; 6] {
+        [
+            &self.features.features_82,
+            &self.features.features_83,
+            &self.features.features_84,
+            &self.features119,
+            &self.sanitize_sub_cmd,
+            &self.data_management,
+        ]
     }
 
     pub fn world_wide_name(&self) -> Option<u64> {
@@ -290,8 +324,53 @@ impl DeviceIdentity {
         self.queue_depth.get_depth()
     }
 
+    /// Returns the SATA interface capabilities of the device.
     pub fn interface_properties(&self) -> InterfaceProperties {
-        unimplemented!()
+        if !self.sata_cap.is_sata() {
+            return InterfaceProperties::default();
+        }
+
+        let state = |supported: bool, enabled: bool| match (supported, enabled) {
+            (false, _) => FeatureState::Unsupported,
+            (true, false) => FeatureState::Disabled,
+            (true, true) => FeatureState::Enabled,
+        };
+        let sup = &self.sata_features;
+        let en = &self.sata_features_en;
+
+        let ncq = self.sata_cap.contains(SataCap::SUPPORTS_NQC).then(|| NcqProperties {
+            queue_depth: self.queue_depth(),
+            priority: self.sata_cap.contains(SataCap::SUPPORTS_NQC_PRIORIITY),
+            unload: self.sata_cap.contains(SataCap::SUPPORTS_UNLOAD_WHILE_NQC),
+            fpdma: self.sata_cap2.contains(SataCap2::FPDMA_COMMANDS),
+            non_data: self.sata_cap2.contains(SataCap2::SUPPORTS_NCQ_NON_DATA),
+            streaming: self.sata_cap2.contains(SataCap2::SUPPORTS_NCQ_STREAMING),
+            autosense: sup.contains(SataFeatures::NQC_AUTOSENSE),
+        });
+
+        InterfaceProperties {
+            sata: true,
+            max_gen: self.sata_cap.max_sata_gen(),
+            negotiated_gen: self.sata_cap2.get_sata_gen(),
+            ncq,
+            host_initiated_pm: self.sata_cap.contains(SataCap::SUPPORTS_HOST_PM_REQUESTS),
+            device_initiated_pm: state(
+                sup.contains(SataFeatures::INIT_POWER_MANAGEMENT),
+                en.contains(SataFeaturesEnabled::DEVICE_POWER_MANAGEMENT),
+            ),
+            auto_partial_to_slumber: state(
+                self.sata_cap.contains(SataCap::DEVICE_AUTO_PARTIAL_TO_SLUMBER),
+                en.contains(SataFeaturesEnabled::AUTO_PARTIAL_TO_SLUMBER),
+            ),
+            device_sleep: state(
+                sup.contains(SataFeatures::DEVICE_SLEEP),
+                en.contains(SataFeaturesEnabled::DEVICE_SLEEP),
+            ),
+            settings_preservation: state(
+                sup.contains(SataFeatures::SOFTWARE_SETTINGS_PRESERVATION),
+                en.contains(SataFeaturesEnabled::SETTINGS_PRESERVATION),
+            ),
+        }
     }
 
     pub fn get_device_geometry(&self) -> DeviceGeometry {
@@ -355,13 +434,13 @@ pub struct VersionInfo {
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 pub struct Streaming {
     /// Number of sectors that provides optimum performance in streaming environments.
     /// Starting LBAs for streaming commands should be divisable by this value.
     min_req_size: u16,
 
-    // is this in msec?
+    /// Streaming transfer time for DMA
     access_time: u16,
 
     access_latency: u16,
@@ -373,10 +452,48 @@ pub struct Streaming {
 }
 
 impl Streaming {
+    /// Returns the granularity of streaming command time limits in microseconds.
     pub fn perf_granularity(&self) -> u32 {
         let t = (self.perf_granularity_high as u32) << 16;
         t | self.perf_granularity_low as u32
     }
+
+    /// Returns the number of sectors which stream requests should be a multiple of, this is never 0.
+    pub fn min_req_size(&self) -> u16 {
+        self.min_req_size.max(1)
+    }
+
+    /// Returns the time taken to transfer [Self::min_req_size] sectors using DMA, in units of
+    /// [Self::perf_granularity].
+    pub fn transfer_time(&self) -> u16 {
+        self.access_time
+    }
+
+    /// Returns the worst case access latency in units of [Self::perf_granularity].
+    pub fn access_latency(&self) -> u16 {
+        self.access_latency
+    }
+
+    /// Extends the request of `count` sectors at `lba` so that it starts and ends on a multiple
+    /// of [Self::min_req_size]. Returns the new start LBA and count.
+    pub fn align_request(&self, lba: u64, count: u32) -> (u64, u32) {
+        let size = self.min_req_size() as u64;
+        let start = lba - lba % size;
+        let end = (lba + count as u64).next_multiple_of(size);
+        (start, (end - start) as u32)
+    }
+
+    /// Converts a time limit in microseconds into a Command Completion Time Limit (CCTL) for
+    /// stream commands. The result is rounded down so the limit is not exceeded, limits shorter
+    /// than one unit use the shortest limit of 1.
+    ///
+    /// Returns `None` if the device does not report [Self::perf_granularity].
+    pub fn cctl(&self, limit_us: u32) -> Option<u8> {
+        match self.perf_granularity() {
+            0 => None,
+            g => Some((limit_us / g).clamp(1, u8::MAX as u32) as u8),
+        }
+    }
 }
 
 /// Contains the device geometry.
@@ -465,7 +582,83 @@ impl TrustedComputing {
     }
 }
 
-pub struct InterfaceProperties {}
+/// SATA interface properties of a device, returned by [DeviceIdentity::interface_properties].
+///
+/// All fields are `None`/unsupported for devices which are not SATA devices.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct InterfaceProperties {
+    /// Whether the device reported SATA capabilities.
+    pub sata: bool,
+    /// The fastest signalling speed supported by the device.
+    pub max_gen: Option<SataGen>,
+    /// The currently negotiated signalling speed, `None` if this is not reported.
+    pub negotiated_gen: Option<SataGen>,
+    /// Native command queueing capabilities, `None` if NCQ is not supported.
+    pub ncq: Option<NcqProperties>,
+    /// Whether the device accepts host initiated power management requests.
+    pub host_initiated_pm: bool,
+    /// Device initiated interface power management.
+    pub device_initiated_pm: FeatureState,
+    /// Whether the device may transition from Partial to Slumber automatically.
+    pub auto_partial_to_slumber: FeatureState,
+    /// DevSleep interface power state.
+    pub device_sleep: FeatureState,
+    /// Whether settings are preserved across COMRESET.
+    pub settings_preservation: FeatureState,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct NcqProperties {
+    /// Maximum number of queued commands.
+    pub queue_depth: u8,
+    pub priority: bool,
+    /// Supports unload while NCQ commands are outstanding.
+    pub unload: bool,
+    /// Supports [crate::command::AtaCommand::SEND_FPDMA_QUEUED] and
+    /// [crate::command::AtaCommand::RECEIVE_FPDMA_QUEUED].
+    pub fpdma: bool,
+    /// Supports [crate::command::AtaCommand::NCQ_NON_DATA].
+    pub non_data: bool,
+    pub streaming: bool,
+    pub autosense: bool,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum FeatureState {
+    #[default]
+    Unsupported,
+    Disabled,
+    Enabled,
+}
+
+/// SATA signalling generation.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum SataGen {
+    /// 1.5 Gb/s
+    Gen1,
+    /// 3.0 Gb/s
+    Gen2,
+    /// 6.0 Gb/s
+    Gen3,
+}
+
+impl SataGen {
+    /// Returns the signalling rate in Mb/s.
+    pub fn rate_mbps(&self) -> u32 {
+        match self {
+            SataGen::Gen1 => 1500,
+            SataGen::Gen2 => 3000,
+            SataGen::Gen3 => 6000,
+        }
+    }
+}
+
+impl core::fmt::Display for SataGen {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let r = self.rate_mbps();
+        write!(f, "{}.{} Gb/s", r / 1000, r % 1000 / 100)
+    }
+}
 
 bitflags::bitflags! {
     // TODO see ata spec 7.12.6.17
@@ -504,12 +697,17 @@ impl SanitizeSubcommands {
     /// Returns whether or not the sanitize subcommand is supported.
     /// Subcommands that return None are not checked by this field.
     ///
-    /// [crate::command::SanitiseSubcommand::SANITIZE_STATUS_EXT] and [crate::command::SanitiseSubcommand::SANITIZE_FREEZE_LOCK_EXT]
-    /// return the same value as [crate::command::AtaCommand::SANITIZE_DEVICE]. This should
-    /// be used to check whether the sanitize command set is available by [DeviceIdentity::is_supported]
-    fn is_supported(&self, cmd: super::super::command::SanitiseSubcommand) -> bool {
-        use super::super::command::SanitiseSubcommand;
-        if self.0 & (1 << 12) != 0 {
+    /// Sanitize commands conform to the ACS-2 standard other it conforms to the ACS-4 standard
+    pub fn is_acs2(&self) -> bool {
+        self.0 & (1 << 11) == 0
+    }
+}
+
+impl SupportBits<SanitiseSubcommand> for SanitizeSubcommands {
+    /// [SanitiseSubcommand::SANITIZE_STATUS_EXT] and [SanitiseSubcommand::SANITIZE_FREEZE_LOCK_EXT]
+    /// return the same value as [AtaCommand::SANITIZE_DEVICE].
+    fn supports(&self, cmd: SanitiseSubcommand) -> Option<bool> {
+        let ret = if self.0 & (1 << 12) != 0 {
             match cmd {
                 SanitiseSubcommand::CRYPTO_SCRAMBLE_EXT => self.0 & (1 << 13) != 0,
                 SanitiseSubcommand::BLOCK_ERASE_EXT => self.0 & (1 << 15) != 0,
@@ -519,12 +717,17 @@ impl SanitizeSubcommands {
             }
         } else {
             false
-        }
+        };
+        Some(ret)
     }
+}
 
-    /// Sanitize commands conform to the ACS-2 standard other it conforms to the ACS-4 standard
-    pub fn is_acs2(&self) -> bool {
-        self.0 & (1 << 11) == 0
+impl SupportBits<AtaCommand> for SanitizeSubcommands {
+    fn supports(&self, cmd: AtaCommand) -> Option<bool> {
+        match cmd {
+            AtaCommand::SANITIZE_DEVICE => self.supports(SanitiseSubcommand::SANITIZE_STATUS_EXT),
+            _ => None,
+        }
     }
 }
 
@@ -674,13 +877,40 @@ bitflags::bitflags! {
     }
 }
 
+impl SataCap {
+    /// Word 76 is either all zeros or all ones for devices which are not SATA devices.
+    fn is_sata(&self) -> bool {
+        !(self.bits() == 0 || self.bits() == u16::MAX)
+    }
+
+    /// Returns the fastest SATA generation supported by the device.
+    pub fn max_sata_gen(&self) -> Option<SataGen> {
+        if !self.is_sata() {
+            None
+        } else if self.contains(Self::SUPPORTS_SATA_GEN3) {
+            Some(SataGen::Gen3)
+        } else if self.contains(Self::SUPPORTS_SATA_GEN2) {
+            Some(SataGen::Gen2)
+        } else if self.contains(Self::SUPPORTS_SATA_GEN1) {
+            Some(SataGen::Gen1)
+        } else {
+            None
+        }
+    }
+}
+
 impl SataCap2 {
     // spec gives wrong section it's actually 9.11.10.3.1
-    // todo add to InterfaceProperties
-    pub fn get_sata_gen(&self) -> u8 {
-        let t = (self.bits() & 7) as u8;
-        assert!(t < 4, "Invalid SATA speed reported");
-        t
+    /// Returns the currently negotiated SATA generation. Returns `None` if the device does not
+    /// report it or reports a reserved value.
+    pub fn get_sata_gen(&self) -> Option<SataGen> {
+        // bits 1..=3
+        match (self.bits() >> 1) & 7 {
+            1 => Some(SataGen::Gen1),
+            2 => Some(SataGen::Gen2),
+            3 => Some(SataGen::Gen3),
+            _ => None,
+        }
     }
 }
 
@@ -721,12 +951,8 @@ bitflags::bitflags! {
     }
 }
 
-impl Features82 {
-    /// Checks if the given command is supported.
-    /// Returns `Some(true)` if the command is supported and `Some(false)` if it is not.
-    /// If this fn returns `None` the command cannot be checked against this.
-    fn is_supported(&self, cmd: super::super::command::AtaCommand) -> Option<bool> {
-        use super::super::command::AtaCommand;
+impl SupportBits<AtaCommand> for Features82 {
+    fn supports(&self, cmd: AtaCommand) -> Option<bool> {
         match cmd {
             AtaCommand::NOP => return self.contains(Self::NOP).into(),
             AtaCommand::READ_BUFFER => return self.contains(Self::READ_BUFFER).into(),
@@ -752,9 +978,8 @@ bitflags::bitflags! {
     }
 }
 
-impl Features83 {
-    fn is_supported(&self, cmd: super::super::command::AtaCommand) -> Option<bool> {
-        use super::super::command::AtaCommand;
+impl SupportBits<AtaCommand> for Features83 {
+    fn supports(&self, cmd: AtaCommand) -> Option<bool> {
         match cmd {
             AtaCommand::FLUSH_CACHE_EXT => return self.contains(Self::FLUSH_CACHE_EXT).into(),
             AtaCommand::FLUSH_CACHE => return self.contains(Self::FLUSH_CACHE).into(),
@@ -780,6 +1005,23 @@ bitflags::bitflags! {
     }
 }
 
+impl SupportBits<AtaCommand> for Features84 {
+    fn supports(&self, cmd: AtaCommand) -> Option<bool> {
+        match cmd {
+            AtaCommand::WRITE_DMA_FUA_EXT => Some(self.contains(Self::WRITE_DMA_FUA_EXT)),
+            AtaCommand::READ_LOG_EXT | AtaCommand::WRITE_LOG_EXT => {
+                Some(self.contains(Self::GPL_FEATURES))
+            }
+            AtaCommand::READ_STREAM_EXT
+            | AtaCommand::READ_STREAM_DMA_EXT
+            | AtaCommand::WRITE_STREAM_EXT
+            | AtaCommand::WRITE_STREAM_DMA_EXT
+            | AtaCommand::CONFIG_STREAM => Some(self.contains(Self::STREAMING)),
+            _ => None,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct UltraDma {
@@ -862,6 +1104,11 @@ pub enum ApmLevel {
 }
 
 impl AdvancedPowerManagement {
+    /// Returns the raw APM level. This is only valid while APM is enabled.
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
     pub fn get_mode(&self) -> ApmLevel {
         match self.level {
             1 => ApmLevel::MinimumStandby,
@@ -929,10 +1176,8 @@ bitflags::bitflags! {
     }
 }
 
-impl Features119 {
-    fn is_supported(&self, cmd: super::super::command::AtaCommand) -> Option<bool> {
-        use super::super::command::AtaCommand;
-
+impl SupportBits<AtaCommand> for Features119 {
+    fn supports(&self, cmd: AtaCommand) -> Option<bool> {
         match cmd {
             AtaCommand::READ_LOG_DMA_EXT => Some(self.contains(Self::LOG_DMA_EXT)),
             AtaCommand::WRITE_LOG_DMA_EXT => Some(self.contains(Self::LOG_DMA_EXT)),
@@ -1011,6 +1256,16 @@ impl DataManagement {
     }
 }
 
+impl SupportBits<AtaCommand> for DataManagement {
+    /// TRIM is the only DATA SET MANAGEMENT function reported by this field.
+    fn supports(&self, cmd: AtaCommand) -> Option<bool> {
+        match cmd {
+            AtaCommand::DATA_SET_MANAGEMENT => Some(self.trim_support()),
+            _ => None,
+        }
+    }
+}
+
 bitflags::bitflags! {
     #[repr(transparent)]
     #[derive(Debug)]
@@ -1066,6 +1321,7 @@ impl RotationRateField {
 #[derive(Debug)]
 pub struct WriteReadVerifyMode(u16);
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum WriteReadVerify {
     /// Always write read verify regardless of command,
     Always,
@@ -1074,14 +1330,39 @@ pub enum WriteReadVerify {
     /// Vendor specific definition
     VendorSpecific,
     /// Checks first number of logical sectors defined at runtime.
-    /// The the number of sectors checked is `count * 1024`
-    // todo defined how?
+    /// The the number of sectors checked is `count * 1024` where `count` is given when the
+    /// feature is enabled, see [crate::config::WrvSetting::Counter].
     CheckFromCounter,
 }
 
+impl WriteReadVerify {
+    /// Returns the mode number used by SET FEATURES.
+    pub fn mode_number(&self) -> u8 {
+        match self {
+            WriteReadVerify::Always => 0,
+            WriteReadVerify::Check64K => 1,
+            WriteReadVerify::VendorSpecific => 2,
+            WriteReadVerify::CheckFromCounter => 3,
+        }
+    }
+}
+
+/// The Write-Read-Verify configuration of a device, see [DeviceIdentity::write_read_verify].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct WrvStatus {
+    pub enabled: bool,
+    /// The current mode, this is only meaningful while WRV is enabled.
+    pub mode: Option<WriteReadVerify>,
+    /// Number of logical sectors verified in [WriteReadVerify::VendorSpecific] mode.
+    pub mode_2_sectors: u32,
+    /// Number of logical sectors verified in [WriteReadVerify::CheckFromCounter] mode.
+    pub mode_3_sectors: u32,
+}
+
 impl WriteReadVerifyMode {
     pub fn get_mode(&self) -> Option<WriteReadVerify> {
-        match self.0 {
+        // bits 8..16 are reserved
+        match self.0 & 0xff {
             0 => Some(WriteReadVerify::Always),
             1 => Some(WriteReadVerify::Check64K),
             2 => Some(WriteReadVerify::VendorSpecific),
@@ -1122,18 +1403,22 @@ pub enum ParallelVersion {
 impl TryFrom<u16> for ParallelVersion {
     type Error = u16;
 
+    /// Bits 11:0 indicate each supported version, the newest is returned.
     fn try_from(value: u16) -> Result<Self, Self::Error> {
         let t = value & 0xfff;
-        match t {
-            0 => Ok(Self::Ata8),
-            1 => Ok(Self::Ata7),
-            e => Err(e),
+        match t.checked_ilog2() {
+            Some(0) => Ok(Self::Ata7),
+            Some(1) => Ok(Self::Ata8),
+            _ => Err(t),
         }
     }
 }
 
 #[derive(Copy, Clone, Debug)]
 pub enum SerialVersion {
+    Sata3_5,
+    Sata3_4,
+    Sata3_3,
     Sata3_2,
     Sata3_1,
     Sata3_0,
@@ -1147,25 +1432,32 @@ pub enum SerialVersion {
 impl TryFrom<u16> for SerialVersion {
     type Error = u16;
 
+    /// Bits 11:0 indicate each supported version, the newest is returned.
     fn try_from(value: u16) -> Result<Self, Self::Error> {
         let t = value & 0xfff;
 
-        match t {
-            0 => Ok(Self::Ata8),
-            1 => Ok(Self::Sata1),
-            2 => Ok(Self::Sata2),
-            3 => Ok(Self::Sata2_5),
-            4 => Ok(Self::Sata2_6),
-            5 => Ok(Self::Sata3_0),
-            6 => Ok(Self::Sata3_1),
-            7 => Ok(Self::Sata3_2),
-            e => Err(e),
+        match t.checked_ilog2() {
+            Some(0) => Ok(Self::Ata8),
+            Some(1) => Ok(Self::Sata1),
+            Some(2) => Ok(Self::Sata2),
+            Some(3) => Ok(Self::Sata2_5),
+            Some(4) => Ok(Self::Sata2_6),
+            Some(5) => Ok(Self::Sata3_0),
+            Some(6) => Ok(Self::Sata3_1),
+            Some(7) => Ok(Self::Sata3_2),
+            Some(8) => Ok(Self::Sata3_3),
+            Some(9) => Ok(Self::Sata3_4),
+            Some(10) => Ok(Self::Sata3_5),
+            _ => Err(t),
         }
     }
 }
 
 impl TransportMajorVersion {
     fn get_version(&self) -> Option<TransportIf> {
+        if self.0 == 0 || self.0 == u16::MAX {
+            return None;
+        }
         let t = (self.0 >> 12) & 0xf;
         match t {
             0 => Some(TransportIf::Parallel(self.0.try_into().ok()?)),
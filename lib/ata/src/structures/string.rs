@@ -0,0 +1,77 @@
+//! Decoding for ATA string fields.
+//!
+//! ATA strings are stored as ASCII with two characters per word, the first character of each pair
+//! is in the high byte of the word so reading the field as bytes gives each pair swapped. Strings
+//! are padded to the length of the field with spaces, some devices also pad with NUL bytes or
+//! right-justify the string.
+
+use core::fmt::{Debug, Display, Formatter};
+
+/// A string decoded from an ATA string field of `N` bytes.
+///
+/// Leading and trailing padding is removed.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct AtaString<const N: usize> {
+    buff: [u8; N],
+    len: usize,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AtaStringError {
+    /// The character at `offset` (after the byte pairs were swapped) is not printable ASCII.
+    NotAscii { offset: usize, byte: u8 },
+}
+
+impl<const N: usize> AtaString<N> {
+    /// Decodes the raw contents of a field as it is laid out in memory.
+    pub fn decode(raw: &[u8; N]) -> Result<Self, AtaStringError> {
+        const { assert!(N.is_multiple_of(2), "ATA strings contain whole words") };
+        let mut swapped = [0u8; N];
+        for (dst, src) in swapped.chunks_exact_mut(2).zip(raw.chunks_exact(2)) {
+            dst[0] = src[1];
+            dst[1] = src[0];
+        }
+
+        let is_pad = |b: &u8| *b == b' ' || *b == 0;
+        let start = swapped.iter().position(|b| !is_pad(b)).unwrap_or(N);
+        let end = swapped.iter().rposition(|b| !is_pad(b)).map_or(start, |i| i + 1);
+        if let Some(i) = swapped[start..end].iter().position(|b| !(0x20..0x7f).contains(b)) {
+            return Err(AtaStringError::NotAscii { offset: start + i, byte: swapped[start + i] });
+        }
+
+        let mut buff = [0u8; N];
+        buff[..end - start].copy_from_slice(&swapped[start..end]);
+        Ok(Self { buff, len: end - start })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // SAFETY: Only printable ASCII is copied into self.buff
+        unsafe { core::str::from_utf8_unchecked(&self.buff[..self.len]) }
+    }
+
+    /// Returns `true` when the field contained only padding, devices use this to indicate that a
+    /// field is not implemented.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<const N: usize> core::ops::Deref for AtaString<N> {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> Display for AtaString<N> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<const N: usize> Debug for AtaString<N> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        Debug::fmt(self.as_str(), f)
+    }
+}
@@ -0,0 +1,173 @@
+//! Decodes IDENTIFY DEVICE data recorded from a number of devices.
+//!
+//! Run on the host with `cargo test -p ata --features std`, otherwise these are run by the kernel
+//! test harness.
+//!
+//! - `qemu_harddisk.bin`: QEMU `ide-hd` attached to AHCI with a 64MiB image. QEMU does not
+//!   implement the integrity word.
+//! - `ssd_512n.bin`: 500GB SATA SSD using extended sector addresses.
+//! - `hdd_512e.bin`: 1TB HDD with 4KiB physical sectors and 512 byte logical sectors.
+//! - `hdd_4kn.bin`: 4TB HDD with 4KiB logical sectors.
+//!
+//! Serial numbers and world wide names have been replaced.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(not(feature = "std"), no_main)]
+#![cfg_attr(not(feature = "std"), feature(custom_test_frameworks))]
+#![cfg_attr(not(feature = "std"), test_runner(hootux::test_runner))]
+#![cfg_attr(not(feature = "std"), reexport_test_harness_main = "test_main")]
+
+use ata::command::{AtaCommand, MicrocodeSubcommand, SanitiseSubcommand, SetFeaturesSubcommand};
+use ata::structures::identification::{
+    DeviceIdentity, MajorVersion, SerialVersion, TransportIf, TransportMinorVersion,
+};
+
+#[cfg(not(feature = "std"))]
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+    loop {}
+}
+
+#[cfg(not(feature = "std"))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    hootux::test_panic(info);
+}
+
+// DeviceIdentity must be aligned, the fixture is copied into this before it is used
+#[repr(C, align(8))]
+struct Fixture([u8; 512]);
+
+impl Fixture {
+    fn new(raw: &[u8; 512]) -> Self {
+        Self(*raw)
+    }
+
+    fn id(&self) -> &DeviceIdentity {
+        // SAFETY: DeviceIdentity is 512 bytes, every bit pattern is valid and self is aligned
+        unsafe { &*(self.0.as_ptr() as *const DeviceIdentity) }
+    }
+}
+
+const QEMU: &[u8; 512] = include_bytes!("fixtures/qemu_harddisk.bin");
+const SSD_512N: &[u8; 512] = include_bytes!("fixtures/ssd_512n.bin");
+const HDD_512E: &[u8; 512] = include_bytes!("fixtures/hdd_512e.bin");
+const HDD_4KN: &[u8; 512] = include_bytes!("fixtures/hdd_4kn.bin");
+
+const ALL: [&[u8; 512]; 4] = [QEMU, SSD_512N, HDD_512E, HDD_4KN];
+
+#[cfg_attr(feature = "std", test)]
+#[cfg_attr(not(feature = "std"), test_case)]
+fn checksum() {
+    for raw in ALL {
+        assert!(Fixture::new(raw).id().checksum());
+    }
+
+    // Corrupting any byte must be detected when the device implements the checksum
+    let mut bad = Fixture::new(SSD_512N);
+    bad.0[54] ^= 0x20;
+    assert!(!bad.id().checksum());
+}
+
+#[cfg_attr(feature = "std", test)]
+#[cfg_attr(not(feature = "std"), test_case)]
+fn strings() {
+    let f = Fixture::new(QEMU);
+    assert_eq!(f.id().model_num().unwrap().as_str(), "QEMU HARDDISK");
+    assert_eq!(f.id().firmware_revision().unwrap().as_str(), "2.5+");
+    assert_eq!(f.id().get_serial().unwrap().as_str(), "QM00001");
+
+    let f = Fixture::new(HDD_4KN);
+    assert_eq!(f.id().model_num().unwrap().as_str(), "HGST HUS726T4TALN6L4");
+    assert!(f.id().additional_product_id().unwrap().is_empty());
+}
+
+#[cfg_attr(feature = "std", test)]
+#[cfg_attr(not(feature = "std"), test_case)]
+fn device_geometry() {
+    // (fixture, sectors, logical size, physical size)
+    let expected = [
+        (QEMU, 131072, 512, 512),
+        (SSD_512N, 976773168, 512, 512),
+        (HDD_512E, 1953525168, 512, 4096),
+        (HDD_4KN, 976754646, 4096, 4096),
+    ];
+
+    for (raw, sectors, logical, phys) in expected {
+        let f = Fixture::new(raw);
+        let geom = f.id().get_device_geometry();
+        assert_eq!(geom.lba_count(), sectors);
+        assert_eq!(geom.logical_sec_size(), logical);
+        assert_eq!(geom.phys_sec_size(), phys);
+        assert_eq!(geom.get_alignment(), 0);
+    }
+}
+
+#[cfg_attr(feature = "std", test)]
+#[cfg_attr(not(feature = "std"), test_case)]
+fn version_info() {
+    let f = Fixture::new(QEMU);
+    let v = f.id().version_info();
+    let major = v.major_vers.unwrap();
+    assert!(major.contains(MajorVersion::ATA_7));
+    assert!(!major.contains(MajorVersion::ATA_8));
+    assert!(v.transport_major.is_none());
+    assert!(matches!(v.transport_minor, Some(TransportMinorVersion::NotReported)));
+
+    let f = Fixture::new(SSD_512N);
+    let v = f.id().version_info();
+    assert!(v.major_vers.unwrap().contains(MajorVersion::ACS_4));
+    assert_eq!(v.minor_vers, 0x39);
+    assert!(matches!(v.transport_major, Some(TransportIf::Serial(SerialVersion::Sata3_1))));
+
+    let f = Fixture::new(HDD_512E);
+    let v = f.id().version_info();
+    let major = v.major_vers.unwrap();
+    assert!(major.contains(MajorVersion::ACS_2));
+    assert!(!major.contains(MajorVersion::ACS_3));
+    assert!(matches!(v.transport_major, Some(TransportIf::Serial(SerialVersion::Sata3_0))));
+
+    let f = Fixture::new(HDD_4KN);
+    let v = f.id().version_info();
+    assert!(matches!(v.transport_major, Some(TransportIf::Serial(SerialVersion::Sata3_2))));
+}
+
+#[cfg_attr(feature = "std", test)]
+#[cfg_attr(not(feature = "std"), test_case)]
+fn is_supported() {
+    let f = Fixture::new(QEMU);
+    let id = f.id();
+    assert_eq!(id.is_supported(AtaCommand::NOP), Some(true));
+    assert_eq!(id.is_supported(AtaCommand::FLUSH_CACHE_EXT), Some(true));
+    assert_eq!(id.is_supported(AtaCommand::DOWNLOAD_MICROCODE), Some(false));
+    assert_eq!(id.is_supported(AtaCommand::READ_LOG_DMA_EXT), Some(false));
+    assert_eq!(id.is_supported(AtaCommand::SANITIZE_DEVICE), Some(false));
+    assert_eq!(id.is_supported(AtaCommand::DATA_SET_MANAGEMENT), Some(false));
+    assert_eq!(id.is_supported(SetFeaturesSubcommand::ENABLE_VOLATILE_WRITE_CACHE), Some(true));
+    assert_eq!(id.is_supported(SetFeaturesSubcommand::ENABLE_APM), Some(false));
+    // No check exists for this
+    assert_eq!(id.is_supported(AtaCommand::READ_DMA_EXT), None);
+
+    let f = Fixture::new(SSD_512N);
+    let id = f.id();
+    assert_eq!(id.is_supported(AtaCommand::DOWNLOAD_MICROCODE), Some(true));
+    assert_eq!(id.is_supported(AtaCommand::READ_LOG_DMA_EXT), Some(true));
+    assert_eq!(id.is_supported(AtaCommand::SANITIZE_DEVICE), Some(true));
+    assert_eq!(id.is_supported(SanitiseSubcommand::CRYPTO_SCRAMBLE_EXT), Some(true));
+    assert_eq!(id.is_supported(SanitiseSubcommand::BLOCK_ERASE_EXT), Some(true));
+    assert_eq!(id.is_supported(SanitiseSubcommand::OVERWRITE_EXT), Some(false));
+    assert_eq!(id.is_supported(AtaCommand::READ_LOG_EXT), Some(true));
+    assert_eq!(id.is_supported(AtaCommand::DATA_SET_MANAGEMENT), Some(true));
+    assert_eq!(id.is_supported(MicrocodeSubcommand::DOWNLOAD_OFFSETS_SAVE), Some(true));
+
+    let f = Fixture::new(HDD_512E);
+    let id = f.id();
+    assert_eq!(id.is_supported(AtaCommand::SANITIZE_DEVICE), Some(false));
+    assert_eq!(id.is_supported(SanitiseSubcommand::OVERWRITE_EXT), Some(false));
+
+    let f = Fixture::new(HDD_4KN);
+    let id = f.id();
+    assert_eq!(id.is_supported(SanitiseSubcommand::CRYPTO_SCRAMBLE_EXT), Some(false));
+    assert_eq!(id.is_supported(SanitiseSubcommand::OVERWRITE_EXT), Some(true));
+}
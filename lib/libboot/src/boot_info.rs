@@ -63,6 +63,11 @@ impl BootInfo {
         }
     }
 
+    /// Returns the command line given by the bootloader
+    pub fn cmdline(&self) -> Option<&str> {
+        self.optionals.mb2_info.as_ref()?.command_line_tag()?.cmdline().ok()
+    }
+
     /// Returns the RDP address
     pub fn rsdp_ptr(&self) -> Option<Rsdp> {
         // We get the actual pointer to the RSDP, we must increment the ptr by 8 because there is an MBI header there
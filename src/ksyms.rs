@@ -0,0 +1,157 @@
+//! Embeds the kernel symbol map into the kernel binary.
+//!
+//! The kernel reserves the `.ksyms` section, which begins with [MAGIC] followed by the length of
+//! the map as a little endian `u64`. The map is generated from the ELF symbol table and written
+//! into the section after the kernel is linked. See `kernel/src/ksyms.rs` for the format.
+
+use std::fmt::Write as _;
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"HOOTKSYM";
+const SECTION: &str = ".ksyms";
+const HEADER_LEN: usize = 16;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+struct Section {
+    name: u32,
+    kind: u32,
+    offset: usize,
+    size: usize,
+    link: u32,
+}
+
+fn u16_at(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes(b[off..off + 2].try_into().unwrap())
+}
+
+fn u32_at(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+fn u64_at(b: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(b[off..off + 8].try_into().unwrap())
+}
+
+fn c_str(b: &[u8], off: usize) -> &str {
+    let end = b[off..].iter().position(|c| *c == 0).map_or(b.len(), |e| off + e);
+    std::str::from_utf8(&b[off..end]).unwrap_or("")
+}
+
+fn sections(elf: &[u8]) -> Result<Vec<Section>, String> {
+    if elf.len() < 64 || &elf[..4] != b"\x7fELF" || elf[4] != 2 || elf[5] != 1 {
+        return Err("Not a little endian ELF64 file".into());
+    }
+    let shoff = u64_at(elf, 0x28) as usize;
+    let shentsize = u16_at(elf, 0x3a) as usize;
+    let shnum = u16_at(elf, 0x3c) as usize;
+    (0..shnum)
+        .map(|i| {
+            let sh = shoff + i * shentsize;
+            if sh + 64 > elf.len() {
+                return Err("Section header out of bounds".into());
+            }
+            Ok(Section {
+                name: u32_at(elf, sh),
+                kind: u32_at(elf, sh + 4),
+                offset: u64_at(elf, sh + 0x18) as usize,
+                size: u64_at(elf, sh + 0x20) as usize,
+                link: u32_at(elf, sh + 0x28),
+            })
+        })
+        .collect()
+}
+
+/// Converts a legacy mangled Rust symbol into its path, other symbols including v0 mangled
+/// symbols are returned unchanged.
+fn demangle(name: &str) -> String {
+    let Some(mut rest) = name.strip_prefix("_ZN") else {
+        return name.to_string();
+    };
+    let mut path = Vec::new();
+    while let Some(len_end) = rest.find(|c: char| !c.is_ascii_digit()).filter(|i| *i > 0) {
+        let Ok(len) = rest[..len_end].parse::<usize>() else { break };
+        let Some(seg) = rest.get(len_end..len_end + len) else { break };
+        // Segments beginning with an escape are prefixed with an underscore
+        path.push(seg.strip_prefix('_').filter(|s| s.starts_with('$')).unwrap_or(seg));
+        rest = &rest[len_end + len..];
+    }
+    if rest != "E" || path.is_empty() {
+        return name.to_string();
+    }
+    // The last segment is a hash
+    if path.len() > 1 && path.last().is_some_and(|h| h.len() == 17 && h.starts_with('h')) {
+        path.pop();
+    }
+    let path = path.join("::");
+    [("$LT$", "<"), ("$GT$", ">"), ("$RF$", "&"), ("$BP$", "*"), ("$u20$", " "), ("$u27$", "'"), ("$u5b$", "["), ("$u5d$", "]"), ("$u7b$", "{"), ("$u7d$", "}"), ("$C$", ","), ("..", "::")]
+        .iter()
+        .fold(path, |p, (from, to)| p.replace(from, to))
+}
+
+/// Formats the symbol table of `elf`, one symbol per line sorted by address.
+fn symbol_map(elf: &[u8], sections: &[Section]) -> Result<String, String> {
+    let symtab = sections.iter().find(|s| s.kind == SHT_SYMTAB).ok_or("No symbol table, was the kernel stripped?")?;
+    let strtab = sections.get(symtab.link as usize).ok_or("Symbol table has no string table")?;
+    let strings = &elf[strtab.offset..strtab.offset + strtab.size];
+
+    let mut syms = Vec::new();
+    for sym in elf[symtab.offset..symtab.offset + symtab.size].chunks_exact(24) {
+        let kind = match sym[4] & 0xf {
+            STT_FUNC => 'T',
+            STT_OBJECT => 'D',
+            _ => continue,
+        };
+        let addr = u64_at(sym, 8);
+        if addr == 0 {
+            continue;
+        }
+        let name = demangle(c_str(strings, u32_at(sym, 0) as usize));
+        let module = name.split("::").next().filter(|m| *m != name).unwrap_or("-").trim_start_matches('<').to_string();
+        syms.push((addr, u64_at(sym, 16), kind, module, name));
+    }
+    syms.sort_by_key(|s| s.0);
+    syms.dedup_by_key(|s| s.0);
+
+    let mut map = String::new();
+    for (addr, size, kind, module, name) in syms {
+        let _ = writeln!(map, "{addr:016x} {size:08x} {kind} {module} {name}");
+    }
+    Ok(map)
+}
+
+/// Generates the symbol map of the kernel at `kernel` and writes it into the `.ksyms` section.
+///
+/// If the map does not fit in the section it is truncated, symbols at the highest addresses are
+/// omitted.
+pub fn embed(kernel: &Path) -> Result<(), String> {
+    let mut elf = std::fs::read(kernel).map_err(|e| format!("Failed to read {}: {e}", kernel.display()))?;
+    let sections = sections(&elf)?;
+    let shstrndx = u16_at(&elf, 0x3e) as usize;
+    let shstrtab = sections.get(shstrndx).ok_or("Invalid section name table index")?;
+    let ksyms = sections
+        .iter()
+        .find(|s| c_str(&elf, shstrtab.offset + s.name as usize) == SECTION)
+        .ok_or("Kernel does not contain a .ksyms section")?;
+    if ksyms.size < HEADER_LEN || &elf[ksyms.offset..ksyms.offset + MAGIC.len()] != MAGIC {
+        return Err(".ksyms section is malformed".into());
+    }
+
+    let map = symbol_map(&elf, &sections)?;
+    let capacity = ksyms.size - HEADER_LEN;
+    let len = if map.len() > capacity {
+        let len = map.as_bytes()[..capacity].iter().rposition(|c| *c == b'\n').map_or(0, |i| i + 1);
+        eprintln!("Warning: Symbol map is {} bytes but .ksyms can only contain {capacity}, the map was truncated", map.len());
+        len
+    } else {
+        map.len()
+    };
+
+    let data = ksyms.offset + HEADER_LEN;
+    elf[ksyms.offset + MAGIC.len()..data].copy_from_slice(&(len as u64).to_le_bytes());
+    elf[data..data + len].copy_from_slice(&map.as_bytes()[..len]);
+    elf[data + len..data + capacity].fill(0);
+    std::fs::write(kernel, elf).map_err(|e| format!("Failed to write {}: {e}", kernel.display()))
+}
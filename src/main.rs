@@ -3,6 +3,8 @@ use std::io::{Read, Write};
 use std::process::{Command, Stdio};
 use toml::value::Value;
 
+mod ksyms;
+
 const QEMU: &str = "qemu-system-x86_64";
 
 static BRIEF: &str = r#"\
@@ -42,6 +44,9 @@ fn main() {
         eprintln!("Failed to get path to kernel");
         std::process::exit(0x26)
     };
+    if let Err(e) = ksyms::embed(&kernel) {
+        eprintln!("Warning: Failed to embed kernel symbols: {e}");
+    }
     let img = opts.build_grub_img(&kernel);
 
     let mut qemu = if let Some(q) = opts.build_exec(&img, &toml) {
@@ -328,3 +328,133 @@ impl Msr<PatData> for Pat {
         }
     }
 }
+
+/// Contains the raw value of a register, used for registers where fields are interpreted by the caller.
+#[derive(Copy, Clone, Debug)]
+pub struct RawValue {
+    pub value: u64,
+}
+
+impl MsrFlags for RawValue {
+    fn bits(&self) -> (u32, u32) {
+        Self::split_bits(self.value)
+    }
+
+    fn new(high: u32, low: u32) -> Self {
+        Self {
+            value: Self::bitfield(high, low),
+        }
+    }
+}
+
+impl From<u64> for RawValue {
+    fn from(value: u64) -> Self {
+        Self { value }
+    }
+}
+
+fn availability_if(available: bool) -> MsrAvailability {
+    if available {
+        MsrAvailability::new(MsrReadWrite::Write)
+    } else {
+        MsrAvailability::new(Reserved)
+    }
+}
+
+/// IA32_PERF_CTL, bits 15:8 contain the target performance ratio when Enhanced Intel SpeedStep is enabled.
+pub struct PerfCtl;
+
+impl Msr<RawValue> for PerfCtl {
+    const MSR_ADDR: u32 = 0x199;
+
+    fn availability() -> MsrAvailability {
+        availability_if(cpuid_lookup_bit(CpuidRegister::ecx, 1, 0, 7))
+    }
+}
+
+/// IA32_MISC_ENABLE, bit 16 indicates whether Enhanced Intel SpeedStep is enabled.
+pub struct MiscEnable;
+
+impl Msr<RawValue> for MiscEnable {
+    const MSR_ADDR: u32 = 0x1a0;
+
+    fn availability() -> MsrAvailability {
+        availability_if(cpuid_lookup_reg(CpuidRegister::eax, 0, 0) >= 1)
+    }
+}
+
+/// IA32_PM_ENABLE, setting bit 0 enables hardware controlled performance states (HWP).
+/// HWP can not be disabled once enabled.
+pub struct PmEnable;
+
+impl Msr<RawValue> for PmEnable {
+    const MSR_ADDR: u32 = 0x770;
+
+    fn availability() -> MsrAvailability {
+        availability_if(cpuid_lookup_bit(CpuidRegister::eax, 6, 0, 7))
+    }
+}
+
+/// IA32_HWP_CAPABILITIES contains the highest, guaranteed, most efficient and lowest performance
+/// levels in bytes 0 to 3.
+pub struct HwpCapabilities;
+
+impl Msr<RawValue> for HwpCapabilities {
+    const MSR_ADDR: u32 = 0x771;
+
+    fn availability() -> MsrAvailability {
+        let mut a = MsrAvailability::new(Reserved);
+        if cpuid_lookup_bit(CpuidRegister::eax, 6, 0, 7) {
+            for i in 0..32 {
+                a.set_bit(i, MsrReadWrite::Read);
+            }
+        }
+        a
+    }
+}
+
+/// IA32_HWP_REQUEST contains the minimum, maximum and desired performance levels and the energy
+/// performance preference in bytes 0 to 3.
+pub struct HwpRequest;
+
+impl Msr<RawValue> for HwpRequest {
+    const MSR_ADDR: u32 = 0x774;
+
+    fn availability() -> MsrAvailability {
+        availability_if(cpuid_lookup_bit(CpuidRegister::eax, 6, 0, 7))
+    }
+}
+
+/// IA32_THERM_STATUS, bits 22:16 contain the core temperature in degrees below TjMax, which is
+/// valid while bit 31 is set.
+pub struct ThermStatus;
+
+impl Msr<RawValue> for ThermStatus {
+    const MSR_ADDR: u32 = 0x19c;
+
+    fn availability() -> MsrAvailability {
+        availability_if(cpuid_lookup_bit(CpuidRegister::eax, 6, 0, 0))
+    }
+}
+
+/// IA32_PACKAGE_THERM_STATUS, bits 22:16 contain the package temperature in degrees below TjMax.
+pub struct PackageThermStatus;
+
+impl Msr<RawValue> for PackageThermStatus {
+    const MSR_ADDR: u32 = 0x1b1;
+
+    fn availability() -> MsrAvailability {
+        availability_if(cpuid_lookup_bit(CpuidRegister::eax, 6, 0, 6))
+    }
+}
+
+/// IA32_TSC_AUX, the low 32 bits are returned in `ecx` by `rdtscp`.
+pub struct TscAux;
+
+impl Msr<RawValue> for TscAux {
+    const MSR_ADDR: u32 = 0xc000_0103;
+
+    fn availability() -> MsrAvailability {
+        availability_if(cpuid_lookup_bit(CpuidRegister::edx, 0x8000_0001, 0, 27))
+    }
+}
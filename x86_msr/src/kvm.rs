@@ -0,0 +1,69 @@
+//! This module contains the paravirtual MSRs provided by KVM, see <https://docs.kernel.org/virt/kvm/x86/msr.html>
+//!
+//! These registers are only present when running under KVM and their availability is reported by
+//! the KVM feature leaf. All registers in this module take a guest physical address with the enable
+//! flag in bit 0.
+
+use crate::*;
+use architecture::BaseAddr;
+
+/// KVM_FEATURE_CLOCKSOURCE2
+const FEATURE_CLOCKSOURCE2: u8 = 3;
+/// KVM_FEATURE_PV_EOI
+const FEATURE_PV_EOI: u8 = 6;
+
+/// Returns the KVM CPUID base leaf if KVM is present.
+///
+/// When Hyper-V enlightenments are enabled KVM reports itself after the Hyper-V leaves.
+pub fn kvm_cpuid_base() -> Option<u32> {
+    // hypervisor present bit
+    if !cpuid_lookup_bit(CpuidRegister::ecx, 1, 0, 31) {
+        return None;
+    }
+    (0x4000_0000..0x4001_0000).step_by(0x100).find(|&base| {
+        cpuid_lookup_reg(CpuidRegister::ebx, base, 0) == u32::from_le_bytes(*b"KVMK")
+            && cpuid_lookup_reg(CpuidRegister::ecx, base, 0) == u32::from_le_bytes(*b"VMKV")
+            && cpuid_lookup_reg(CpuidRegister::edx, base, 0) == u32::from_le_bytes(*b"M\0\0\0")
+    })
+}
+
+/// Returns the KVM feature bits, these are `0` when KVM is not present.
+pub fn kvm_features() -> u32 {
+    kvm_cpuid_base().map_or(0, |base| cpuid_lookup_reg(CpuidRegister::eax, base + 1, 0))
+}
+
+fn kvm_availability(feature: u8) -> MsrAvailability {
+    if kvm_features() & (1 << feature) != 0 {
+        MsrAvailability::new(MsrReadWrite::Write)
+    } else {
+        MsrAvailability::new(MsrReadWrite::Reserved)
+    }
+}
+
+/// MSR_KVM_SYSTEM_TIME_NEW
+///
+/// Contains the address of the `pvclock_vcpu_time_info` structure for this CPU.
+/// The address must be 4-byte aligned.
+pub struct SystemTimeNew;
+
+impl Msr<BaseAddr> for SystemTimeNew {
+    const MSR_ADDR: u32 = 0x4b56_4d01;
+
+    fn availability() -> MsrAvailability {
+        kvm_availability(FEATURE_CLOCKSOURCE2)
+    }
+}
+
+/// MSR_KVM_PV_EOI_EN
+///
+/// Contains the address of a 4-byte aligned word, bit 0 of the word is set by the host when the
+/// guest may skip signalling EOI to the local APIC.
+pub struct PvEoiEn;
+
+impl Msr<BaseAddr> for PvEoiEn {
+    const MSR_ADDR: u32 = 0x4b56_4d04;
+
+    fn availability() -> MsrAvailability {
+        kvm_availability(FEATURE_PV_EOI)
+    }
+}
@@ -6,6 +6,7 @@
 //! The traits in this module are pub for custom implementations
 
 pub mod architecture;
+pub mod kvm;
 use lazy_static::lazy_static;
 
 lazy_static! {